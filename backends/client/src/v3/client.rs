@@ -168,6 +168,7 @@ impl Client {
                 slots: vec![],
                 cache_len: 0,
                 chunk_len: None,
+                input_ids: Vec::new(),
                 // Set sampling parameters to also take these ops into account in the max memory
                 parameters: Some(NextTokenChooserParameters {
                     temperature: 0.9,
@@ -181,6 +182,8 @@ impl Client {
                     watermark: true,
                     grammar: String::new(),
                     grammar_type: GrammarType::None as i32,
+                    allowed_token_ids: vec![],
+                    banned_token_ids: vec![],
                 }),
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens,
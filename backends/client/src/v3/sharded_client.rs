@@ -244,6 +244,8 @@ impl Health for ShardedClient {
                 watermark: false,
                 grammar: String::new(),
                 grammar_type: GrammarType::None as i32,
+                allowed_token_ids: vec![],
+                banned_token_ids: vec![],
             }),
             stopping_parameters: Some(StoppingCriteriaParameters {
                 max_new_tokens: 1,
@@ -256,6 +258,7 @@ impl Health for ShardedClient {
             slots: (0..16).collect(),
             cache_len: 0,
             chunk_len: None,
+            input_ids: Vec::new(),
             adapter_id: None,
         };
         let batch = Batch {
@@ -0,0 +1,281 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use text_generation_router::infer::{
+    Backend, BackendCapabilities, GeneratedText, GenerationStream, InferError, InferStreamResponse,
+};
+use text_generation_router::validation::{ChunksToString, ValidGenerateRequest};
+use text_generation_router::{BackendMetadata, FinishReason, LoadAdapterRequest, Token};
+use tokenizers::models::wordlevel::WordLevel;
+use tokenizers::pre_tokenizers::whitespace::Whitespace;
+use tokenizers::Tokenizer;
+use tokio::sync::{mpsc, TryAcquireError};
+use tokio::time::{sleep, Instant};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{instrument, Instrument};
+
+/// Failure injected in the requests whose inputs contain its trigger
+#[derive(Clone, Debug, PartialEq)]
+pub enum Failure {
+    /// The request is rejected when scheduled, as by a backend with a full queue
+    Overloaded,
+    /// The generation fails after `after_tokens` tokens
+    Generation { after_tokens: u32, message: String },
+    /// The stream ends after `after_tokens` tokens without its last message
+    Incomplete { after_tokens: u32 },
+}
+
+/// Generated tokens, latencies and injected failures of a [`MockBackend`]
+#[derive(Clone, Debug)]
+pub struct MockConfig {
+    /// Texts of the generated tokens, repeated until the generation stops
+    pub tokens: Vec<String>,
+    /// Generations end with the end of sequence token after this many tokens, unless the
+    /// request ignores it. They otherwise stop at `max_new_tokens` or on a stop sequence.
+    pub eos_after: Option<u32>,
+    pub time_to_first_token: Duration,
+    pub inter_token_latency: Duration,
+    /// Failures of the requests whose inputs contain the trigger, the first match applies
+    pub failures: Vec<(String, Failure)>,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            tokens: ["Hello", " world", "!"].map(String::from).to_vec(),
+            eos_after: None,
+            time_to_first_token: Duration::ZERO,
+            inter_token_latency: Duration::ZERO,
+            failures: Vec::new(),
+        }
+    }
+}
+
+/// Backend generating the same tokens for every request, without model
+///
+/// Clones share their state, so a test can keep one to check the running generations or
+/// toggle the health of the backend served by the router.
+#[derive(Clone)]
+pub struct MockBackend {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: MockConfig,
+    healthy: AtomicBool,
+    running: AtomicUsize,
+    /// Adapters loaded with `POST /admin/adapters`, they do not change the generated tokens
+    adapters: Mutex<Vec<String>>,
+}
+
+impl MockBackend {
+    pub fn new(config: MockConfig) -> Self {
+        assert!(
+            !config.tokens.is_empty(),
+            "the mock needs at least one token"
+        );
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                healthy: AtomicBool::new(true),
+                running: AtomicUsize::new(0),
+                adapters: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Word level tokenizer of the generated tokens, the other words are one unknown token each
+    ///
+    /// The ids of the generated tokens are their indices in [`MockConfig::tokens`].
+    pub fn tokenizer(&self) -> Tokenizer {
+        let tokens = &self.inner.config.tokens;
+        let mut vocab: HashMap<String, u32> = HashMap::new();
+        for (id, text) in tokens.iter().enumerate() {
+            vocab.entry(text.trim().to_string()).or_insert(id as u32);
+        }
+        vocab.insert("<unk>".to_string(), tokens.len() as u32);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .expect("valid word level vocabulary");
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer
+    }
+
+    /// Number of generations not finished yet, cancelled ones stop at their next token
+    pub fn running(&self) -> usize {
+        self.inner.running.load(Ordering::SeqCst)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.inner.healthy.store(healthy, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl Backend for MockBackend {
+    #[instrument(skip_all)]
+    fn schedule(&self, request: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
+        let inputs = request.inputs.chunks_to_string();
+        let failure = self
+            .inner
+            .config
+            .failures
+            .iter()
+            .find(|(trigger, _)| inputs.contains(trigger.as_str()))
+            .map(|(_, failure)| failure.clone());
+        if failure == Some(Failure::Overloaded) {
+            return Err(InferError::Overloaded(TryAcquireError::NoPermits));
+        }
+
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        let running = Running::new(self.inner.clone());
+        tokio::spawn(generate(running, request, failure, response_tx).in_current_span());
+        Ok(Box::pin(UnboundedReceiverStream::new(response_rx)))
+    }
+
+    async fn health(&self, _current_health: bool) -> bool {
+        self.inner.healthy.load(Ordering::SeqCst)
+    }
+
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities {
+            supports_grammar: false,
+            supports_images: false,
+            // The token texts are sent as configured
+            supports_detokenization: false,
+            supports_prefix_pinning: false,
+            supports_prefill_logits: false,
+            supports_embeddings: false,
+            supports_token_lists: false,
+            supports_extended_sampling: true,
+        }
+    }
+
+    fn metadata(&self) -> BackendMetadata {
+        BackendMetadata {
+            adapters: self.inner.adapters.lock().unwrap().clone(),
+            ..Default::default()
+        }
+    }
+
+    async fn load_adapter(&self, request: LoadAdapterRequest) -> Result<(), InferError> {
+        let mut adapters = self.inner.adapters.lock().unwrap();
+        if !adapters.contains(&request.adapter_id) {
+            adapters.push(request.adapter_id);
+        }
+        Ok(())
+    }
+}
+
+/// Counts a generation as running until dropped
+struct Running(Arc<Inner>);
+
+impl Running {
+    fn new(inner: Arc<Inner>) -> Self {
+        inner.running.fetch_add(1, Ordering::SeqCst);
+        Self(inner)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Send the tokens of `request` until it stops, fails or its stream is dropped
+async fn generate(
+    running: Running,
+    request: ValidGenerateRequest,
+    failure: Option<Failure>,
+    response_tx: mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
+) {
+    let config = &running.0.config;
+    let stopping = &request.stopping_parameters;
+    let eos_after = config.eos_after.filter(|_| !stopping.ignore_eos_token);
+    let seed = request
+        .parameters
+        .do_sample
+        .then_some(request.parameters.seed);
+
+    let queued = Instant::now();
+    sleep(config.time_to_first_token).await;
+    let start = Instant::now();
+    let mut text = String::new();
+
+    for index in 0..stopping.max_new_tokens {
+        if index > 0 {
+            sleep(config.inter_token_latency).await;
+        }
+        // Stop generating once the client went away
+        if response_tx.is_closed() {
+            return;
+        }
+        match &failure {
+            Some(Failure::Generation {
+                after_tokens,
+                message,
+            }) if index == *after_tokens => {
+                let _ = response_tx.send(Err(InferError::GenerationError(message.clone())));
+                return;
+            }
+            Some(Failure::Incomplete { after_tokens }) if index == *after_tokens => return,
+            _ => {}
+        }
+
+        let id = index as usize % config.tokens.len();
+        let token = Token {
+            id: id as u32,
+            text: config.tokens[id].clone(),
+            logprob: 0.0,
+            special: false,
+            bytes: None,
+        };
+        text.push_str(&token.text);
+        let generated_tokens = index + 1;
+        let finish_reason = if stopping
+            .stop_sequences
+            .iter()
+            .any(|stop| text.ends_with(stop))
+        {
+            FinishReason::StopSequence
+        } else if eos_after == Some(generated_tokens) {
+            FinishReason::EndOfSequenceToken
+        } else if generated_tokens == stopping.max_new_tokens {
+            FinishReason::Length
+        } else {
+            let _ = response_tx.send(Ok(InferStreamResponse::Intermediate {
+                token,
+                top_tokens: vec![],
+            }));
+            continue;
+        };
+
+        let generated_text = GeneratedText {
+            text,
+            generated_tokens,
+            finish_reason,
+            seed,
+            backend: None,
+            // One token per step, the mock does not speculate
+            steps: Some(generated_tokens),
+        };
+        let _ = response_tx.send(Ok(InferStreamResponse::End {
+            token,
+            top_tokens: vec![],
+            generated_text,
+            start,
+            queued,
+        }));
+        return;
+    }
+}
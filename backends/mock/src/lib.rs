@@ -0,0 +1,8 @@
+/// Deterministic backend for the end-to-end tests of the router
+///
+/// The mock generates configured tokens with configured latencies and injects failures in
+/// the requests matching a trigger, so the HTTP behaviour of streaming, cancellation and
+/// error statuses can be tested without model weights.
+mod backend;
+
+pub use backend::{Failure, MockBackend, MockConfig};
@@ -0,0 +1,140 @@
+use clap::Parser;
+use std::time::Duration;
+use text_generation_router::router_config::{RouterConfig, RouterConfigError};
+use text_generation_router::{server, HubTokenizerConfig};
+use text_generation_router_mock::{MockBackend, MockConfig};
+use thiserror::Error;
+
+/// App Configuration
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(default_value = "128", long, env)]
+    max_concurrent_requests: usize,
+    #[clap(default_value = "2", long, env)]
+    max_best_of: usize,
+    #[clap(default_value = "4", long, env)]
+    max_stop_sequences: usize,
+    #[clap(default_value = "4095", long, env)]
+    max_input_tokens: usize,
+    #[clap(default_value = "4096", long, env)]
+    max_total_tokens: usize,
+    #[clap(default_value = "0.0.0.0", long, env)]
+    hostname: String,
+    #[clap(default_value = "3000", long, short, env)]
+    port: u16,
+    /// Texts of the generated tokens, repeated until the generation stops
+    #[clap(default_values_t = ["Hello".to_string(), " world".to_string(), "!".to_string()], long, env, value_delimiter = ',')]
+    tokens: Vec<String>,
+    /// Generations end with the end of sequence token after this many tokens
+    #[clap(long, env)]
+    eos_after: Option<u32>,
+    #[clap(default_value = "0", long, env)]
+    time_to_first_token_ms: u64,
+    #[clap(default_value = "0", long, env)]
+    inter_token_latency_ms: u64,
+    /// Model id reported in `/info`
+    #[clap(default_value = "mock", long, env)]
+    model_id: String,
+    /// JSON file with operator settings such as parameter presets and prompt templates
+    #[clap(long, env)]
+    router_config_path: Option<String>,
+    #[clap(default_value = "2", long, env)]
+    validation_workers: usize,
+    #[clap(long, env)]
+    api_key: Option<String>,
+    #[clap(long, env)]
+    json_output: bool,
+    #[clap(default_value = "4", long, env)]
+    max_client_batch_size: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), RouterError> {
+    // Get args
+    let args = Args::parse();
+    // Pattern match configuration
+    let Args {
+        max_concurrent_requests,
+        max_best_of,
+        max_stop_sequences,
+        max_input_tokens,
+        max_total_tokens,
+        hostname,
+        port,
+        tokens,
+        eos_after,
+        time_to_first_token_ms,
+        inter_token_latency_ms,
+        model_id,
+        router_config_path,
+        validation_workers,
+        api_key,
+        json_output,
+        max_client_batch_size,
+    } = args;
+
+    text_generation_router::logging::init_logging(None, String::new(), 1.0, json_output);
+
+    // Validate args
+    if tokens.is_empty() {
+        return Err(RouterError::ArgumentValidation(
+            "`tokens` must not be empty".to_string(),
+        ));
+    }
+    if validation_workers == 0 {
+        return Err(RouterError::ArgumentValidation(
+            "`validation_workers` must be > 0".to_string(),
+        ));
+    }
+    if max_input_tokens >= max_total_tokens {
+        return Err(RouterError::ArgumentValidation(
+            "`max_input_tokens` must be < `max_total_tokens`".to_string(),
+        ));
+    }
+
+    let backend = MockBackend::new(MockConfig {
+        tokens,
+        eos_after,
+        time_to_first_token: Duration::from_millis(time_to_first_token_ms),
+        inter_token_latency: Duration::from_millis(inter_token_latency_ms),
+        failures: Vec::new(),
+    });
+    let tokenizer = backend.tokenizer();
+    let router_config = match router_config_path {
+        Some(path) => RouterConfig::from_file(path)?,
+        None => RouterConfig::default(),
+    };
+
+    // Run server
+    server::run_with_tokenizer(
+        backend,
+        max_concurrent_requests,
+        max_best_of,
+        max_stop_sequences,
+        // The mock logprobs are all 0
+        0,
+        max_input_tokens,
+        max_total_tokens,
+        validation_workers,
+        api_key,
+        model_id,
+        (tokenizer, HubTokenizerConfig::default()),
+        router_config,
+        hostname,
+        port,
+        max_client_batch_size,
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+enum RouterError {
+    #[error("Argument validation error: {0}")]
+    ArgumentValidation(String),
+    #[error("Router config error: {0}")]
+    RouterConfig(#[from] RouterConfigError),
+    #[error("WebServer error: {0}")]
+    WebServer(#[from] server::WebServerError),
+}
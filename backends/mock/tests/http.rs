@@ -0,0 +1,328 @@
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+use std::time::Duration;
+use text_generation_router::router_config::{AdminConfig, RouterConfig};
+use text_generation_router::{server, HubTokenizerConfig};
+use text_generation_router_mock::{Failure, MockBackend, MockConfig};
+
+const ADMIN_KEY: &str = "admin-key";
+
+struct Server {
+    url: String,
+    backend: MockBackend,
+}
+
+/// Router serving the mock backend, shared by the tests since the metrics recorder is global
+fn server() -> &'static Server {
+    static SERVER: OnceLock<Server> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let backend = MockBackend::new(MockConfig {
+            time_to_first_token: Duration::from_millis(10),
+            inter_token_latency: Duration::from_millis(10),
+            failures: vec![
+                ("overloaded".to_string(), Failure::Overloaded),
+                (
+                    "failing".to_string(),
+                    Failure::Generation {
+                        after_tokens: 2,
+                        message: "CUDA OOM".to_string(),
+                    },
+                ),
+                (
+                    "truncated".to_string(),
+                    Failure::Incomplete { after_tokens: 2 },
+                ),
+            ],
+            ..Default::default()
+        });
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let served = backend.clone();
+        std::thread::spawn(move || {
+            let tokenizer = served.tokenizer();
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(server::run_with_tokenizer(
+                    served,
+                    16,
+                    2,
+                    4,
+                    0,
+                    1024,
+                    2048,
+                    1,
+                    None,
+                    "mock".to_string(),
+                    (tokenizer, HubTokenizerConfig::default()),
+                    RouterConfig {
+                        admin: AdminConfig {
+                            api_key: Some(ADMIN_KEY.to_string()),
+                        },
+                        ..Default::default()
+                    },
+                    "127.0.0.1".to_string(),
+                    port,
+                    4,
+                ))
+                .unwrap();
+        });
+        Server {
+            url: format!("http://127.0.0.1:{port}"),
+            backend,
+        }
+    })
+}
+
+/// Send `body` once the server answers
+async fn post(path: &str, body: Value) -> reqwest::Response {
+    let url = format!("{}{path}", server().url);
+    for _ in 0..100 {
+        match reqwest::Client::new().post(&url).json(&body).send().await {
+            Ok(response) => return response,
+            Err(err) if err.is_connect() => tokio::time::sleep(Duration::from_millis(50)).await,
+            Err(err) => panic!("{err}"),
+        }
+    }
+    panic!("the server did not start");
+}
+
+/// Data of the server-sent events of `response`
+async fn events(response: reqwest::Response) -> Vec<Value> {
+    let body = response.text().await.unwrap();
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| serde_json::from_str(data.trim()).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_generate() {
+    let response = post(
+        "/generate",
+        json!({"inputs": "Say hello", "parameters": {"max_new_tokens": 5, "details": true}}),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["generated_text"], "Hello world!Hello world");
+    assert_eq!(body["details"]["finish_reason"], "length");
+    assert_eq!(body["details"]["generated_tokens"], 5);
+    assert_eq!(body["details"]["steps"], 5);
+    assert_eq!(body["details"]["speculative_tokens_accepted"], 0);
+
+    let response = post(
+        "/generate",
+        json!({"inputs": "Say hello", "parameters": {"max_new_tokens": 5, "stop": ["!"], "details": true}}),
+    )
+    .await;
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["generated_text"], "Hello world!");
+    assert_eq!(body["details"]["finish_reason"], "stop_sequence");
+}
+
+#[tokio::test]
+async fn test_generate_upload() {
+    // Waits for the server to start
+    post("/generate", json!({"inputs": "Say hello"})).await;
+    let body = "--xyz\r\n\
+        Content-Disposition: form-data; name=\"request\"\r\n\r\n\
+        {\"parameters\": {\"max_new_tokens\": 3}}\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"inputs\"; filename=\"prompt.txt\"\r\n\r\n\
+        Say hello\r\n--xyz--\r\n";
+    let response = reqwest::Client::new()
+        .post(format!("{}/generate", server().url))
+        .header("content-type", "multipart/form-data; boundary=xyz")
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["generated_text"], "Hello world!");
+}
+
+#[tokio::test]
+async fn test_rag() {
+    let response = post(
+        "/rag",
+        json!({
+            "question": "Say hello",
+            "documents": ["Hello is a greeting", "Hi"],
+            "max_document_tokens": 2,
+            "parameters": {"max_new_tokens": 2}
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["generated_text"], "Hello world");
+    assert_eq!(
+        body["documents"],
+        json!([
+            {"index": 0, "tokens": 2, "truncated": true},
+            {"index": 1, "tokens": 1, "truncated": false}
+        ])
+    );
+}
+
+#[tokio::test]
+async fn test_generate_stream() {
+    let response = post(
+        "/generate_stream",
+        json!({"inputs": "Say hello", "parameters": {"max_new_tokens": 4, "details": true}}),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let events = events(response).await;
+    let texts: Vec<_> = events.iter().map(|event| &event["token"]["text"]).collect();
+    assert_eq!(texts, ["Hello", " world", "!", "Hello"]);
+    assert!(events[..3]
+        .iter()
+        .all(|event| event["generated_text"].is_null()));
+    assert_eq!(events[3]["generated_text"], "Hello world!Hello");
+    assert_eq!(events[3]["details"]["finish_reason"], "length");
+}
+
+#[tokio::test]
+async fn test_stream_cancellation() {
+    let backend = &server().backend;
+    let response = post(
+        "/generate_stream",
+        json!({"inputs": "Count", "parameters": {"max_new_tokens": 1000}}),
+    )
+    .await;
+    let mut stream = response.bytes_stream();
+    stream.next().await.unwrap().unwrap();
+    assert!(backend.running() > 0);
+
+    // The generation would last 10s without the cancellation
+    drop(stream);
+    for _ in 0..100 {
+        if backend.running() == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the generation did not stop after the client disconnected");
+}
+
+#[tokio::test]
+async fn test_error_status() {
+    let response = post(
+        "/generate",
+        json!({"inputs": "Say hello", "parameters": {"max_new_tokens": 0}}),
+    )
+    .await;
+    assert_eq!(response.status(), 422);
+
+    // The mock backend does not enforce token lists
+    let response = post(
+        "/generate",
+        json!({"inputs": "Say hello", "parameters": {"banned_tokens": [0]}}),
+    )
+    .await;
+    assert_eq!(response.status(), 422);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "token_list_not_supported");
+    assert_eq!(body["error"]["param"], "banned_tokens");
+
+    let response = post("/generate", json!({"inputs": "overloaded"})).await;
+    assert_eq!(response.status(), 429);
+
+    let response = post("/generate", json!({"inputs": "failing"})).await;
+    assert_eq!(response.status(), 502);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "generation");
+
+    let response = post("/generate", json!({"inputs": "truncated"})).await;
+    assert_eq!(response.status(), 502);
+
+    // Streams report the failures after their first tokens as error events
+    let response = post(
+        "/generate_stream",
+        json!({"inputs": "failing", "parameters": {"max_new_tokens": 5}}),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let events = events(response).await;
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[1]["token"]["text"], " world");
+    assert_eq!(events[2]["error"]["type"], "generation");
+}
+
+#[tokio::test]
+async fn test_health() {
+    // Waits for the server to start
+    post("/generate", json!({"inputs": "Say hello"})).await;
+    let url = format!("{}/health", server().url);
+    let response = reqwest::get(&url).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    server().backend.set_healthy(false);
+    let response = reqwest::get(&url).await.unwrap();
+    server().backend.set_healthy(true);
+    assert_eq!(response.status(), 503);
+}
+
+#[tokio::test]
+async fn test_info() {
+    // Waits for the server to start
+    post("/generate", json!({"inputs": "Say hello"})).await;
+    let info: Value = reqwest::get(format!("{}/info", server().url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(info["model_id"], "mock");
+    assert_eq!(info["backend"], "mock");
+    // The other tests load adapters
+    assert!(info["backend_metadata"]["adapters"].is_array());
+    assert!(info["backend_metadata"]["batching"].is_null());
+}
+
+#[tokio::test]
+async fn test_load_adapter() {
+    let body = json!({"adapter_id": "customer-support"});
+    // Only served to the admin key
+    let response = post("/admin/adapters", body.clone()).await;
+    assert_eq!(response.status(), 401);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/adapters", server().url))
+        .bearer_auth(ADMIN_KEY)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert!(body["adapters"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("customer-support")));
+
+    let info: Value = reqwest::get(format!("{}/info", server().url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(info["backend_metadata"]["adapters"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("customer-support")));
+
+    // Selected by the `model` of the OpenAI requests
+    let response = post(
+        "/v1/completions",
+        json!({"model": "mock:customer-support", "prompt": "Say hello", "max_tokens": 3}),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+}
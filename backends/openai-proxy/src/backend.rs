@@ -0,0 +1,413 @@
+use crate::ProxyError;
+use async_trait::async_trait;
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde::{Deserialize, Serialize};
+use text_generation_router::infer::{
+    Backend, BackendCapabilities, GeneratedText, GenerationStream, InferError, InferStreamResponse,
+};
+use text_generation_router::validation::{ChunksToString, ValidGenerateRequest};
+use text_generation_router::{FinishReason, Token};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{instrument, Instrument};
+
+/// Backend forwarding validated requests to an OpenAI-compatible `/v1/completions` endpoint
+pub struct OpenAiProxyBackend {
+    client: reqwest::Client,
+    completions_url: String,
+    models_url: String,
+    model: String,
+}
+
+impl OpenAiProxyBackend {
+    pub fn new(
+        upstream_url: &str,
+        model: String,
+        api_key: Option<String>,
+    ) -> Result<Self, ProxyError> {
+        let mut headers = HeaderMap::new();
+        if let Some(api_key) = api_key {
+            let mut value = HeaderValue::from_str(&format!("Bearer {api_key}"))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .map_err(ProxyError::Client)?;
+
+        let base = upstream_url.trim_end_matches('/');
+        Ok(Self {
+            client,
+            completions_url: format!("{base}/v1/completions"),
+            models_url: format!("{base}/v1/models"),
+            model,
+        })
+    }
+
+    /// List the upstream models, used to check that the upstream is reachable
+    pub(crate) async fn models(&self) -> Result<(), reqwest::Error> {
+        self.client
+            .get(&self.models_url)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Backend for OpenAiProxyBackend {
+    #[instrument(skip_all)]
+    fn schedule(&self, request: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        let body = CompletionRequest::new(&self.model, &request);
+        let sent = self.client.post(&self.completions_url).json(&body).send();
+        let seed = request
+            .parameters
+            .do_sample
+            .then_some(request.parameters.seed);
+        let has_stop = !request.stopping_parameters.stop_sequences.is_empty();
+
+        tokio::spawn(
+            async move {
+                let queued = Instant::now();
+                let result = match sent.await {
+                    Ok(response) => forward(response, &response_tx, queued, seed, has_stop).await,
+                    Err(err) => Err(InferError::GenerationError(err.to_string())),
+                };
+                if let Err(err) = result {
+                    metrics::counter!("tgi_request_failure", "err" => "upstream").increment(1);
+                    tracing::error!("{err}");
+                    let _ = response_tx.send(Err(err));
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(Box::pin(UnboundedReceiverStream::new(response_rx)))
+    }
+
+    async fn health(&self, _current_health: bool) -> bool {
+        self.models().await.is_ok()
+    }
+
+    fn name(&self) -> &'static str {
+        "openai-proxy"
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities {
+            supports_grammar: false,
+            supports_images: false,
+            // The upstream only returns texts
+            supports_detokenization: false,
+            supports_prefix_pinning: false,
+            supports_prefill_logits: false,
+            supports_embeddings: false,
+            // The token ids of the router tokenizer may not be those of the upstream
+            supports_token_lists: false,
+            // The OpenAI completions API has no `top_k`, `typical_p` or `repetition_penalty`
+            supports_extended_sampling: false,
+        }
+    }
+}
+
+/// Read the upstream server-sent events and forward them as `InferStreamResponse`s
+async fn forward(
+    response: reqwest::Response,
+    response_tx: &mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
+    queued: Instant,
+    seed: Option<u64>,
+    has_stop: bool,
+) -> Result<(), InferError> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(InferError::GenerationError(format!(
+            "upstream returned {status}: {body}"
+        )));
+    }
+    forward_events(response.bytes_stream(), response_tx, queued, seed, has_stop).await
+}
+
+/// Forward the events of the body of a successful upstream response
+///
+/// The upstream does not expose token ids, so every streamed chunk is reported as one token.
+/// The stream ends on the first finish reason, or on `[DONE]` for the upstreams that do not
+/// send one.
+async fn forward_events<B: AsRef<[u8]>, E: std::fmt::Display>(
+    mut stream: impl futures::Stream<Item = Result<B, E>> + Unpin,
+    response_tx: &mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
+    queued: Instant,
+    seed: Option<u64>,
+    has_stop: bool,
+) -> Result<(), InferError> {
+    let start = Instant::now();
+    let mut buffer = Vec::new();
+    let mut text = String::new();
+    let mut generated_tokens = 0;
+
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes.map_err(|err| InferError::GenerationError(err.to_string()))?;
+        buffer.extend_from_slice(bytes.as_ref());
+
+        while let Some(event) = next_event(&mut buffer)? {
+            // Stop reading from upstream if the client went away
+            if response_tx.is_closed() {
+                metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
+                return Ok(());
+            }
+            let (text_chunk, finish_reason) = match event {
+                Some(chunk) => match chunk.choices.into_iter().next() {
+                    Some(choice) => (
+                        choice.text,
+                        choice
+                            .finish_reason
+                            .map(|reason| parse_finish_reason(&reason, has_stop)),
+                    ),
+                    // Chunks without choices, such as the usage chunk of `stream_options`
+                    None => continue,
+                },
+                None => (String::new(), Some(FinishReason::EndOfSequenceToken)),
+            };
+
+            let token = Token {
+                id: 0,
+                text: text_chunk,
+                logprob: f32::NAN,
+                special: false,
+                bytes: None,
+            };
+            match finish_reason {
+                None if token.text.is_empty() => {}
+                None => {
+                    generated_tokens += 1;
+                    text.push_str(&token.text);
+                    let _ = response_tx.send(Ok(InferStreamResponse::Intermediate {
+                        token,
+                        top_tokens: vec![],
+                    }));
+                }
+                Some(finish_reason) => {
+                    if !token.text.is_empty() {
+                        generated_tokens += 1;
+                        text.push_str(&token.text);
+                    }
+                    let generated_text = GeneratedText {
+                        text,
+                        generated_tokens,
+                        finish_reason,
+                        seed,
+                        backend: None,
+                        steps: None,
+                    };
+                    let _ = response_tx.send(Ok(InferStreamResponse::End {
+                        token,
+                        top_tokens: vec![],
+                        generated_text,
+                        start,
+                        queued,
+                    }));
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Err(InferError::IncompleteGenerationStream)
+}
+
+/// Pop the next complete `data:` line from `buffer`
+///
+/// Returns `Ok(Some(None))` for the `[DONE]` sentinel and `Ok(None)` when more bytes are needed.
+fn next_event(buffer: &mut Vec<u8>) -> Result<Option<Option<CompletionChunk>>, InferError> {
+    while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = buffer.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&line);
+        let Some(data) = line.trim().strip_prefix("data:") else {
+            // Comments, `event:` lines and event separators
+            continue;
+        };
+        let data = data.trim();
+        if data == "[DONE]" {
+            return Ok(Some(None));
+        }
+        return serde_json::from_str(data)
+            .map(|chunk| Some(Some(chunk)))
+            .map_err(|err| InferError::GenerationError(format!("invalid upstream event: {err}")));
+    }
+    Ok(None)
+}
+
+/// The upstream reports `stop` for both its end of sequence token and the stop sequences,
+/// a request with stop sequences is assumed to have matched one
+fn parse_finish_reason(finish_reason: &str, has_stop: bool) -> FinishReason {
+    match finish_reason {
+        "length" => FinishReason::Length,
+        "stop" if has_stop => FinishReason::StopSequence,
+        _ => FinishReason::EndOfSequenceToken,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    prompt: String,
+    max_tokens: u32,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    stream: bool,
+}
+
+impl<'a> CompletionRequest<'a> {
+    fn new(model: &'a str, request: &ValidGenerateRequest) -> Self {
+        let parameters = &request.parameters;
+        let do_sample = parameters.do_sample;
+        Self {
+            model,
+            prompt: request.inputs.chunks_to_string(),
+            max_tokens: request.stopping_parameters.max_new_tokens,
+            // Greedy decoding is expressed as temperature 0 in the OpenAI API
+            temperature: if do_sample {
+                parameters.temperature
+            } else {
+                0.0
+            },
+            top_p: (do_sample && parameters.top_p < 1.0).then_some(parameters.top_p),
+            frequency_penalty: (parameters.frequency_penalty != 0.0)
+                .then_some(parameters.frequency_penalty),
+            seed: do_sample.then_some(parameters.seed),
+            stop: request.stopping_parameters.stop_sequences.clone(),
+            stream: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChunk {
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionChoice {
+    text: String,
+    finish_reason: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_event() {
+        let mut buffer = b": ping\n\ndata: {\"choices\":[{\"text\":\"He".to_vec();
+        assert!(next_event(&mut buffer).unwrap().is_none());
+
+        buffer.extend_from_slice(b"llo\",\"finish_reason\":null}]}\n\ndata: [DONE]\n\n");
+        let chunk = next_event(&mut buffer).unwrap().unwrap().unwrap();
+        assert_eq!(chunk.choices[0].text, "Hello");
+        assert!(chunk.choices[0].finish_reason.is_none());
+
+        assert!(next_event(&mut buffer).unwrap().unwrap().is_none());
+        assert!(next_event(&mut buffer).unwrap().is_none());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_next_event_invalid() {
+        let mut buffer = b"data: {not json}\n".to_vec();
+        assert!(matches!(
+            next_event(&mut buffer),
+            Err(InferError::GenerationError(_))
+        ));
+    }
+
+    /// Responses forwarded from the upstream events
+    fn forward_body(body: &str, has_stop: bool) -> Vec<Result<InferStreamResponse, InferError>> {
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let stream = futures::stream::iter([Ok::<_, std::convert::Infallible>(body.to_string())]);
+        let result = futures::executor::block_on(forward_events(
+            stream,
+            &response_tx,
+            Instant::now(),
+            None,
+            has_stop,
+        ));
+        let mut responses = Vec::new();
+        while let Ok(response) = response_rx.try_recv() {
+            responses.push(response);
+        }
+        if let Err(err) = result {
+            responses.push(Err(err));
+        }
+        responses
+    }
+
+    #[test]
+    fn test_forward_events() {
+        // The usage chunk has no choices, and `[DONE]` ends a stream without finish reason
+        let responses = forward_body(
+            "data: {\"choices\":[{\"text\":\"Hi\",\"finish_reason\":null}]}\n\n\
+            data: {\"choices\":[]}\n\n\
+            data: [DONE]\n\n",
+            false,
+        );
+        assert_eq!(responses.len(), 2);
+        match &responses[1] {
+            Ok(InferStreamResponse::End { generated_text, .. }) => {
+                assert_eq!(generated_text.text, "Hi");
+                assert_eq!(generated_text.generated_tokens, 1);
+                assert!(matches!(
+                    generated_text.finish_reason,
+                    FinishReason::EndOfSequenceToken
+                ));
+            }
+            _ => panic!("Unexpected response without end"),
+        }
+
+        let responses = forward_body(
+            "data: {\"choices\":[{\"text\":\"Hi\",\"finish_reason\":\"stop\"}]}\n\n",
+            true,
+        );
+        assert!(matches!(
+            &responses[..],
+            [Ok(InferStreamResponse::End { generated_text, .. })]
+                if matches!(generated_text.finish_reason, FinishReason::StopSequence)
+        ));
+
+        // The body ends without `[DONE]` or a finish reason
+        let responses = forward_body(
+            "data: {\"choices\":[{\"text\":\"Hi\",\"finish_reason\":null}]}\n\n",
+            false,
+        );
+        assert!(matches!(
+            responses.last(),
+            Some(Err(InferError::IncompleteGenerationStream))
+        ));
+    }
+
+    #[test]
+    fn test_parse_finish_reason() {
+        assert!(matches!(
+            parse_finish_reason("length", true),
+            FinishReason::Length
+        ));
+        assert!(matches!(
+            parse_finish_reason("stop", false),
+            FinishReason::EndOfSequenceToken
+        ));
+        assert!(matches!(
+            parse_finish_reason("stop", true),
+            FinishReason::StopSequence
+        ));
+    }
+}
@@ -0,0 +1,27 @@
+mod backend;
+
+pub use backend::OpenAiProxyBackend;
+use reqwest::header::InvalidHeaderValue;
+use thiserror::Error;
+
+/// Create the proxy backend and check that the upstream server answers
+pub async fn connect_backend(
+    upstream_url: String,
+    upstream_model: String,
+    upstream_api_key: Option<String>,
+) -> Result<OpenAiProxyBackend, ProxyError> {
+    let backend = OpenAiProxyBackend::new(&upstream_url, upstream_model, upstream_api_key)?;
+    backend.models().await.map_err(ProxyError::Connection)?;
+    tracing::info!("Connected to upstream {upstream_url}");
+    Ok(backend)
+}
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("Invalid upstream API key: {0}")]
+    ApiKey(#[from] InvalidHeaderValue),
+    #[error("Unable to create HTTP client: {0}")]
+    Client(reqwest::Error),
+    #[error("Unable to reach upstream server: {0}")]
+    Connection(reqwest::Error),
+}
@@ -0,0 +1,189 @@
+use clap::{Parser, Subcommand};
+use text_generation_router::{server, usage_stats};
+use text_generation_router_openai_proxy::{connect_backend, ProxyError};
+use thiserror::Error;
+
+/// App Configuration
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[clap(default_value = "128", long, env)]
+    max_concurrent_requests: usize,
+    #[clap(default_value = "2", long, env)]
+    max_best_of: usize,
+    #[clap(default_value = "4", long, env)]
+    max_stop_sequences: usize,
+    #[clap(default_value = "4095", long, env)]
+    max_input_tokens: usize,
+    #[clap(default_value = "4096", long, env)]
+    max_total_tokens: usize,
+    #[clap(default_value = "0.0.0.0", long, env)]
+    hostname: String,
+    #[clap(default_value = "3000", long, short, env)]
+    port: u16,
+    /// Base URL of the upstream OpenAI-compatible server, e.g. `https://api.example.com`
+    #[clap(long, env)]
+    upstream_url: String,
+    /// Model name sent to the upstream server
+    #[clap(long, env)]
+    upstream_model: String,
+    /// Bearer token sent to the upstream server
+    #[clap(long, env)]
+    upstream_api_key: Option<String>,
+    /// Tokenizer used to validate input lengths, should match the upstream model
+    #[clap(default_value = "bigscience/bloom", long, env)]
+    tokenizer_name: String,
+    #[clap(long, env)]
+    tokenizer_config_path: Option<String>,
+    /// JSON file with operator settings such as parameter presets and prompt templates
+    #[clap(long, env)]
+    router_config_path: Option<String>,
+    #[clap(long, env)]
+    revision: Option<String>,
+    #[clap(long, env, value_enum)]
+    trust_remote_code: bool,
+    #[clap(default_value = "2", long, env)]
+    validation_workers: usize,
+    #[clap(long, env)]
+    api_key: Option<String>,
+    #[clap(long, env)]
+    json_output: bool,
+    #[clap(long, env)]
+    otlp_endpoint: Option<String>,
+    #[clap(default_value = "text-generation-inference.router", long, env)]
+    otlp_service_name: String,
+    /// Share of the traces exported to the Open Telemetry collector, between 0 and 1
+    #[clap(default_value = "1.0", long, env)]
+    otlp_sampling_ratio: f64,
+    #[clap(long, env)]
+    cors_allow_origin: Option<Vec<String>>,
+    #[clap(long, env)]
+    ngrok: bool,
+    #[clap(long, env)]
+    ngrok_authtoken: Option<String>,
+    #[clap(long, env)]
+    ngrok_edge: Option<String>,
+    #[clap(default_value = "4", long, env)]
+    max_client_batch_size: usize,
+    #[clap(default_value = "on", long, env)]
+    usage_stats: usage_stats::UsageStatsLevel,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    PrintSchema,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), RouterError> {
+    // Get args
+    let args = Args::parse();
+    // Pattern match configuration
+    let Args {
+        command,
+        max_concurrent_requests,
+        max_best_of,
+        max_stop_sequences,
+        max_input_tokens,
+        max_total_tokens,
+        hostname,
+        port,
+        upstream_url,
+        upstream_model,
+        upstream_api_key,
+        tokenizer_name,
+        tokenizer_config_path,
+        router_config_path,
+        revision,
+        trust_remote_code,
+        validation_workers,
+        api_key,
+        json_output,
+        otlp_endpoint,
+        otlp_service_name,
+        otlp_sampling_ratio,
+        cors_allow_origin,
+        ngrok,
+        ngrok_authtoken,
+        ngrok_edge,
+        max_client_batch_size,
+        usage_stats,
+    } = args;
+
+    if let Some(Commands::PrintSchema) = command {
+        use utoipa::OpenApi;
+        let api_doc = text_generation_router::server::ApiDoc::openapi();
+        let api_doc = serde_json::to_string_pretty(&api_doc).unwrap();
+        println!("{}", api_doc);
+        std::process::exit(0);
+    };
+    text_generation_router::logging::init_logging(
+        otlp_endpoint,
+        otlp_service_name,
+        otlp_sampling_ratio,
+        json_output,
+    );
+
+    // Validate args
+    if !(0.0..=1.0).contains(&otlp_sampling_ratio) {
+        return Err(RouterError::ArgumentValidation(
+            "`otlp_sampling_ratio` must be between 0 and 1".to_string(),
+        ));
+    }
+    if validation_workers == 0 {
+        return Err(RouterError::ArgumentValidation(
+            "`validation_workers` must be > 0".to_string(),
+        ));
+    }
+    if max_input_tokens >= max_total_tokens {
+        return Err(RouterError::ArgumentValidation(
+            "`max_input_tokens` must be < `max_total_tokens`".to_string(),
+        ));
+    }
+
+    let backend = connect_backend(upstream_url, upstream_model, upstream_api_key).await?;
+
+    // Run server
+    server::run(
+        backend,
+        max_concurrent_requests,
+        max_best_of,
+        max_stop_sequences,
+        // The upstream does not return token level logprobs
+        0,
+        max_input_tokens,
+        max_total_tokens,
+        validation_workers,
+        api_key,
+        tokenizer_name,
+        tokenizer_config_path,
+        router_config_path,
+        revision,
+        trust_remote_code,
+        hostname,
+        port,
+        cors_allow_origin,
+        ngrok,
+        ngrok_authtoken,
+        ngrok_edge,
+        // Grammars cannot be forwarded to the upstream
+        true,
+        max_client_batch_size,
+        usage_stats,
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+enum RouterError {
+    #[error("Argument validation error: {0}")]
+    ArgumentValidation(String),
+    #[error("Backend failed: {0}")]
+    Backend(#[from] ProxyError),
+    #[error("WebServer error: {0}")]
+    WebServer(#[from] server::WebServerError),
+}
@@ -14,7 +14,9 @@ use tokio_stream::wrappers::UnboundedReceiverStream;
 use tracing::{debug, error, warn};
 
 use text_generation_router::infer::InferError::{GenerationError, ValidationError};
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
+use text_generation_router::infer::{
+    Backend, BackendCapabilities, GeneratedText, GenerationStream, InferError, InferStreamResponse,
+};
 use text_generation_router::validation::ValidationError::{
     EmptyInput, Grammar, TopNTokensDisabled, UnsupportedModality,
 };
@@ -193,6 +195,7 @@ fn post_processor_looper<const MAX_NUM_TOKENS: usize>(
                                 text,
                                 logprob: ctx.token.log_prob,
                                 special: is_special,
+                                bytes: None,
                             };
 
                             let out = if !ctx.token.is_final {
@@ -208,6 +211,8 @@ fn post_processor_looper<const MAX_NUM_TOKENS: usize>(
                                     generated_tokens: tokens.len() as u32,
                                     finish_reason: FinishReason::EndOfSequenceToken,
                                     seed: None,
+                                    backend: None,
+                                    steps: None,
                                 };
 
                                 InferStreamResponse::End {
@@ -352,10 +357,7 @@ impl TensorRtLlmBackendV2 {
 
 #[async_trait]
 impl Backend for TensorRtLlmBackendV2 {
-    fn schedule(
-        &self,
-        inner: ValidGenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+    fn schedule(&self, inner: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
         Self::validate(&inner)?;
 
         // Open-up the stream to send tokens
@@ -369,7 +371,7 @@ impl Backend for TensorRtLlmBackendV2 {
             queued,
             streamer,
         }) {
-            Ok(_) => Ok(UnboundedReceiverStream::new(receiver)),
+            Ok(_) => Ok(Box::pin(UnboundedReceiverStream::new(receiver))),
             Err(_) => Err(GenerationError(
                 "Failed to submit request to the backend".into(),
             )),
@@ -379,4 +381,21 @@ impl Backend for TensorRtLlmBackendV2 {
     async fn health(&self, _: bool) -> bool {
         !self.executor_looper.is_finished() & !self.post_processor_looper.is_finished()
     }
+
+    fn name(&self) -> &'static str {
+        "trtllm"
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities {
+            supports_grammar: false,
+            supports_images: false,
+            supports_detokenization: true,
+            supports_prefix_pinning: false,
+            supports_prefill_logits: false,
+            supports_embeddings: false,
+            supports_token_lists: false,
+            supports_extended_sampling: true,
+        }
+    }
 }
@@ -41,6 +41,8 @@ struct Args {
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
     #[clap(long, env)]
+    router_config_path: Option<String>,
+    #[clap(long, env)]
     revision: Option<String>,
     #[clap(long, env)]
     model_id: String,
@@ -52,6 +54,9 @@ struct Args {
     otlp_endpoint: Option<String>,
     #[clap(default_value = "text-generation-inference.router", long, env)]
     otlp_service_name: String,
+    /// Share of the traces exported to the Open Telemetry collector, between 0 and 1
+    #[clap(default_value = "1.0", long, env)]
+    otlp_sampling_ratio: f64,
     #[clap(long, env)]
     cors_allow_origin: Option<Vec<String>>,
     #[clap(default_value = "4", long, env)]
@@ -206,12 +211,14 @@ async fn main() -> Result<(), TensorRtLlmBackendError> {
         port,
         tokenizer_name,
         tokenizer_config_path,
+        router_config_path,
         revision,
         model_id,
         validation_workers,
         json_output,
         otlp_endpoint,
         otlp_service_name,
+        otlp_sampling_ratio,
         cors_allow_origin,
         max_client_batch_size,
         auth_token,
@@ -220,9 +227,19 @@ async fn main() -> Result<(), TensorRtLlmBackendError> {
     } = args;
 
     // Launch Tokio runtime
-    text_generation_router::logging::init_logging(otlp_endpoint, otlp_service_name, json_output);
+    text_generation_router::logging::init_logging(
+        otlp_endpoint,
+        otlp_service_name,
+        otlp_sampling_ratio,
+        json_output,
+    );
 
     // Validate args
+    if !(0.0..=1.0).contains(&otlp_sampling_ratio) {
+        return Err(TensorRtLlmBackendError::ArgumentValidation(
+            "`otlp_sampling_ratio` must be between 0 and 1".to_string(),
+        ));
+    }
     if max_input_tokens >= max_total_tokens {
         return Err(TensorRtLlmBackendError::ArgumentValidation(
             "`max_input_tokens` must be < `max_total_tokens`".to_string(),
@@ -286,6 +303,7 @@ async fn main() -> Result<(), TensorRtLlmBackendError> {
         auth_token,
         tokenizer_name,
         tokenizer_config_path,
+        router_config_path,
         revision,
         hostname,
         port,
@@ -4,9 +4,13 @@ use crate::queue::{Entry, Queue};
 use async_trait::async_trait;
 use nohash_hasher::IntMap;
 use std::sync::Arc;
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
+use text_generation_router::infer::utf8::{token_bytes, Utf8Decoder};
+use text_generation_router::infer::{
+    Backend, BackendCapabilities, GeneratedText, GenerationStream, InferError, InferStreamResponse,
+};
+use text_generation_router::kv_cache::BlockManager;
 use text_generation_router::validation::ValidGenerateRequest;
-use text_generation_router::{FinishReason, PrefillToken, Token};
+use text_generation_router::{BackendMetadata, BatchingLimits, FinishReason, PrefillToken, Token};
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::{mpsc, Notify};
 use tokio::time::Instant;
@@ -20,6 +24,8 @@ pub struct BackendV2 {
     batching_task_notifier: Arc<Notify>,
     /// Client clone, used for health checks to skip the queue
     client: ShardedClient,
+    /// Reported in `/info`
+    batching: BatchingLimits,
 }
 
 impl BackendV2 {
@@ -44,7 +50,10 @@ impl BackendV2 {
             _ => unreachable!(),
         };
 
-        let queue = Queue::new(requires_padding, block_size, window_size, speculate);
+        // The shards report the tokens that fit in their KV cache during warmup
+        let blocks = (!requires_padding)
+            .then(|| BlockManager::new(block_size, max_batch_total_tokens / block_size));
+        let queue = Queue::new(requires_padding, block_size, window_size, speculate, blocks);
         let batching_task_notifier = Arc::new(Notify::new());
 
         // Spawn batching background task that contains all the inference logic
@@ -63,6 +72,13 @@ impl BackendV2 {
             queue,
             batching_task_notifier,
             client,
+            batching: BatchingLimits {
+                max_batch_prefill_tokens,
+                max_batch_total_tokens,
+                max_batch_size,
+                max_waiting_tokens,
+                waiting_served_ratio,
+            },
         }
     }
 }
@@ -70,10 +86,7 @@ impl BackendV2 {
 #[async_trait]
 impl Backend for BackendV2 {
     #[instrument(skip_all)]
-    fn schedule(
-        &self,
-        request: ValidGenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+    fn schedule(&self, request: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
         // MPSC channel to communicate with the background batching task
         let (response_tx, response_rx) = mpsc::unbounded_channel();
 
@@ -85,6 +98,9 @@ impl Backend for BackendV2 {
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
+            decoder: Utf8Decoder::default(),
+            steps: 0,
+            blocks: None,
         });
 
         // Notify the background task that we have a new entry in the queue that needs
@@ -92,7 +108,7 @@ impl Backend for BackendV2 {
         self.batching_task_notifier.notify_one();
 
         // Return stream
-        Ok(UnboundedReceiverStream::new(response_rx))
+        Ok(Box::pin(UnboundedReceiverStream::new(response_rx)))
     }
 
     async fn health(&self, current_health: bool) -> bool {
@@ -104,6 +120,25 @@ impl Backend for BackendV2 {
         }
         .is_ok()
     }
+
+    fn name(&self) -> &'static str {
+        "v2"
+    }
+
+    fn metadata(&self) -> BackendMetadata {
+        BackendMetadata {
+            batching: Some(self.batching.clone()),
+            ..Default::default()
+        }
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities {
+            // The shards return the prefill logprobs with `decoder_input_details`
+            supports_prefill_logits: true,
+            ..Default::default()
+        }
+    }
 }
 
 /// Batching logic
@@ -356,7 +391,7 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
         // Get entry
         // We can `expect` here as the request id should always be in the entries
         let entry = entries
-            .get(&id)
+            .get_mut(&id)
             .expect("ID not found in entries. This is a bug.");
 
         // Create and enter a span to link this function back to the entry
@@ -377,7 +412,7 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
 /// Send responses through the `entry` response channel
 fn send_responses(
     generation: Generation,
-    entry: &Entry,
+    entry: &mut Entry,
 ) -> Result<bool, Box<SendError<Result<InferStreamResponse, InferError>>>> {
     // Return directly if the channel is disconnected
     if entry.response_tx.is_closed() {
@@ -395,7 +430,13 @@ fn send_responses(
             .into_iter()
             .zip(prefill_tokens.logprobs)
             .zip(prefill_tokens.texts)
-            .map(|((id, logprob), text)| PrefillToken { id, text, logprob })
+            .map(|((id, logprob), text)| PrefillToken {
+                id,
+                text,
+                logprob,
+                start: None,
+                stop: None,
+            })
             .collect();
 
         // Send message
@@ -408,52 +449,77 @@ fn send_responses(
     let tokens_ = generation.tokens.expect("Non empty tokens in generation");
     let n = tokens_.ids.len();
     metrics::histogram!("tgi_request_skipped_tokens").record((n - 1) as f64);
+    entry.steps += 1;
+    let mut generated_text = generation.generated_text;
+    // Top tokens are moved out of the generation instead of being copied
+    let mut top_tokens_iter = generation.top_tokens.into_iter();
     let mut iterator = tokens_
         .ids
         .into_iter()
         .zip(tokens_.logprobs)
         .zip(tokens_.texts)
         .zip(tokens_.is_special)
-        .enumerate()
         .peekable();
-    while let Some((i, (((id, logprob), text), special))) = iterator.next() {
+    while let Some((((id, logprob), text), special)) = iterator.next() {
+        // The generated text is sent with the last token
+        let generated_text = match iterator.peek() {
+            None => generated_text.take(),
+            Some(_) => None,
+        };
+        let end = generated_text.is_some();
+        let (text, bytes) = if entry.request.raw_bytes {
+            let bytes = token_bytes(&text);
+            (text, Some(bytes))
+        } else {
+            // Only send complete characters
+            let mut text = entry.decoder.push(text);
+            if end {
+                text.push_str(&entry.decoder.flush());
+            }
+            (text, None)
+        };
         let token = Token {
             id,
             text,
             logprob,
             special,
+            bytes,
         };
-        let top_tokens = if let Some(top_tokens_) = generation.top_tokens.get(i) {
-            top_tokens_
+        let top_tokens = match top_tokens_iter.next() {
+            // Skip the top tokens entirely if they were not requested
+            Some(top_tokens_) if entry.request.top_n_tokens > 0 => top_tokens_
                 .ids
-                .iter()
-                .zip(top_tokens_.logprobs.iter())
-                .zip(top_tokens_.texts.iter())
-                .zip(top_tokens_.is_special.iter())
-                .map(|(((&id, &logprob), text), &special)| Token {
+                .into_iter()
+                .zip(top_tokens_.logprobs)
+                .zip(top_tokens_.texts)
+                .zip(top_tokens_.is_special)
+                .map(|(((id, logprob), text), special)| Token {
                     id,
-                    text: text.to_string(),
+                    bytes: entry.request.raw_bytes.then(|| token_bytes(&text)),
+                    text,
                     logprob,
                     special,
                 })
-                .collect()
-        } else {
-            vec![]
+                .collect(),
+            _ => Vec::new(),
         };
-        match (&generation.generated_text, iterator.peek()) {
-            (Some(generated_text), None) => {
+        match generated_text {
+            Some(generated_text) => {
                 // Generation has ended
                 stopped = true;
                 // Send message
                 entry.response_tx.send(Ok(InferStreamResponse::End {
                     token,
                     top_tokens,
-                    generated_text: GeneratedText::from(generated_text.clone()),
+                    generated_text: GeneratedText {
+                        steps: Some(entry.steps),
+                        ..GeneratedText::from(generated_text)
+                    },
                     queued: entry.queue_time,
                     start: entry.batch_time.unwrap(),
                 }))?;
             }
-            _ => {
+            None => {
                 // Send message
                 entry
                     .response_tx
@@ -497,6 +563,8 @@ impl From<crate::client::GeneratedText> for GeneratedText {
             generated_tokens: value.generated_tokens,
             finish_reason,
             seed: value.seed,
+            backend: None,
+            steps: None,
         }
     }
 }
@@ -1,5 +1,6 @@
 use clap::{Parser, Subcommand};
-use text_generation_router::{server, usage_stats};
+use std::path::PathBuf;
+use text_generation_router::{replay, server, usage_stats};
 use text_generation_router_v2::{connect_backend, V2Error};
 use thiserror::Error;
 
@@ -42,6 +43,9 @@ struct Args {
     tokenizer_name: String,
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
+    /// JSON file with operator settings such as parameter presets and prompt templates
+    #[clap(long, env)]
+    router_config_path: Option<String>,
     #[clap(long, env)]
     revision: Option<String>,
     #[clap(long, env, value_enum)]
@@ -56,6 +60,9 @@ struct Args {
     otlp_endpoint: Option<String>,
     #[clap(default_value = "text-generation-inference.router", long, env)]
     otlp_service_name: String,
+    /// Share of the traces exported to the Open Telemetry collector, between 0 and 1
+    #[clap(default_value = "1.0", long, env)]
+    otlp_sampling_ratio: f64,
     #[clap(long, env)]
     cors_allow_origin: Option<Vec<String>>,
     #[clap(long, env)]
@@ -75,6 +82,19 @@ struct Args {
 #[derive(Debug, Subcommand)]
 enum Commands {
     PrintSchema,
+    /// Re-send the requests of a request log against a running server
+    Replay {
+        /// JSON lines file with the `timestamp`, `path` and `body` of a request per line
+        log_path: PathBuf,
+        #[clap(default_value = "http://localhost:3000", long)]
+        url: String,
+        /// Pacing of the requests relative to the log, 2 sends them twice as fast and 0 all at
+        /// once
+        #[clap(default_value = "1.0", long)]
+        speed: f64,
+        #[clap(long)]
+        api_key: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -100,6 +120,7 @@ async fn main() -> Result<(), RouterError> {
         master_shard_uds_path,
         tokenizer_name,
         tokenizer_config_path,
+        router_config_path,
         revision,
         trust_remote_code,
         validation_workers,
@@ -107,6 +128,7 @@ async fn main() -> Result<(), RouterError> {
         json_output,
         otlp_endpoint,
         otlp_service_name,
+        otlp_sampling_ratio,
         cors_allow_origin,
         ngrok,
         ngrok_authtoken,
@@ -116,16 +138,44 @@ async fn main() -> Result<(), RouterError> {
         usage_stats,
     } = args;
 
-    if let Some(Commands::PrintSchema) = command {
-        use utoipa::OpenApi;
-        let api_doc = text_generation_router::server::ApiDoc::openapi();
-        let api_doc = serde_json::to_string_pretty(&api_doc).unwrap();
-        println!("{}", api_doc);
-        std::process::exit(0);
+    match command {
+        Some(Commands::PrintSchema) => {
+            use utoipa::OpenApi;
+            let api_doc = text_generation_router::server::ApiDoc::openapi();
+            let api_doc = serde_json::to_string_pretty(&api_doc).unwrap();
+            println!("{}", api_doc);
+            std::process::exit(0);
+        }
+        Some(Commands::Replay {
+            log_path,
+            url,
+            speed,
+            api_key,
+        }) => {
+            if !(speed.is_finite() && speed >= 0.0) {
+                return Err(RouterError::ArgumentValidation(
+                    "`speed` must be >= 0".to_string(),
+                ));
+            }
+            let summary = replay::replay(&log_path, &url, speed, api_key).await?;
+            println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+            std::process::exit(0);
+        }
+        None => {}
     };
-    text_generation_router::logging::init_logging(otlp_endpoint, otlp_service_name, json_output);
+    text_generation_router::logging::init_logging(
+        otlp_endpoint,
+        otlp_service_name,
+        otlp_sampling_ratio,
+        json_output,
+    );
 
     // Validate args
+    if !(0.0..=1.0).contains(&otlp_sampling_ratio) {
+        return Err(RouterError::ArgumentValidation(
+            "`otlp_sampling_ratio` must be between 0 and 1".to_string(),
+        ));
+    }
     if max_input_tokens >= max_total_tokens {
         return Err(RouterError::ArgumentValidation(
             "`max_input_tokens` must be < `max_total_tokens`".to_string(),
@@ -183,6 +233,7 @@ async fn main() -> Result<(), RouterError> {
         api_key,
         tokenizer_name,
         tokenizer_config_path,
+        router_config_path,
         revision,
         trust_remote_code,
         hostname,
@@ -207,6 +258,8 @@ enum RouterError {
     Backend(#[from] V2Error),
     #[error("WebServer error: {0}")]
     WebServer(#[from] server::WebServerError),
+    #[error("Replay failed: {0}")]
+    Replay(#[from] replay::ReplayError),
     #[error("Tokio runtime failed to start: {0}")]
     Tokio(#[from] std::io::Error),
 }
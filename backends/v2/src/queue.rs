@@ -4,8 +4,10 @@ use crate::client::{
 use nohash_hasher::{BuildNoHashHasher, IntMap};
 use std::cmp::min;
 use std::collections::VecDeque;
+use text_generation_router::infer::utf8::Utf8Decoder;
 use text_generation_router::infer::InferError;
 use text_generation_router::infer::InferStreamResponse;
+use text_generation_router::kv_cache::{BlockManager, BlockReservation};
 use text_generation_router::validation::{
     ChunksToString, ValidGenerateRequest, ValidGrammar, ValidParameters, ValidStoppingParameters,
 };
@@ -28,6 +30,13 @@ pub(crate) struct Entry {
     pub queue_time: Instant,
     /// Instant when this entry was added to a batch
     pub batch_time: Option<Instant>,
+    /// Characters split across the streamed token texts of the shards, which are the response
+    /// texts when the router does not detokenize the request
+    pub decoder: Utf8Decoder,
+    /// Forward passes that generated tokens for this entry
+    pub steps: u32,
+    /// KV cache blocks of the whole generation, freed when the entry is dropped
+    pub blocks: Option<BlockReservation>,
 }
 
 /// Request Queue
@@ -43,6 +52,7 @@ impl Queue {
         block_size: u32,
         window_size: Option<u32>,
         speculate: u32,
+        blocks: Option<BlockManager>,
     ) -> Self {
         // Create channel
         let (queue_sender, queue_receiver) = mpsc::unbounded_channel();
@@ -53,6 +63,7 @@ impl Queue {
             block_size,
             window_size,
             speculate,
+            blocks,
             queue_receiver,
         ));
 
@@ -103,9 +114,10 @@ async fn queue_task(
     block_size: u32,
     window_size: Option<u32>,
     speculate: u32,
+    blocks: Option<BlockManager>,
     mut receiver: mpsc::UnboundedReceiver<QueueCommand>,
 ) {
-    let mut state = State::new(requires_padding, block_size, window_size, speculate);
+    let mut state = State::new(requires_padding, block_size, window_size, speculate, blocks);
 
     while let Some(cmd) = receiver.recv().await {
         match cmd {
@@ -153,6 +165,10 @@ struct State {
 
     /// Speculation amount
     speculate: u32,
+
+    /// Free blocks of the KV cache, when the shards use paged attention
+    /// Without it, the memory of the batch is estimated from the token budget
+    blocks: Option<BlockManager>,
 }
 
 impl State {
@@ -161,6 +177,7 @@ impl State {
         block_size: u32,
         window_size: Option<u32>,
         speculate: u32,
+        blocks: Option<BlockManager>,
     ) -> Self {
         Self {
             entries: VecDeque::with_capacity(128),
@@ -170,6 +187,7 @@ impl State {
             block_size,
             window_size,
             speculate,
+            blocks,
         }
     }
 
@@ -220,9 +238,16 @@ impl State {
         let next_batch_span = info_span!(parent: None, "batch", batch_size = tracing::field::Empty);
         next_batch_span.follows_from(Span::current());
 
-        let mut batch_requests = Vec::with_capacity(self.entries.len());
+        // Every entry prefills at least one token, so the batch size is bounded by the budget
+        // and not by the length of the queue
+        let capacity = self
+            .entries
+            .len()
+            .min(max_size.unwrap_or(usize::MAX))
+            .min(prefill_token_budget as usize);
+        let mut batch_requests = Vec::with_capacity(capacity);
         let mut batch_entries =
-            IntMap::with_capacity_and_hasher(self.entries.len(), BuildNoHashHasher::default());
+            IntMap::with_capacity_and_hasher(capacity, BuildNoHashHasher::default());
 
         let mut max_input_length = 0;
         let mut prefill_tokens: u32 = 0;
@@ -250,25 +275,26 @@ impl State {
                     * self.block_size;
             }
 
+            let max_new_tokens = match self.window_size {
+                None => entry.request.stopping_parameters.max_new_tokens,
+                Some(window_size) => min(
+                    window_size.saturating_sub(entry.request.input_length),
+                    entry.request.stopping_parameters.max_new_tokens,
+                ),
+            };
             if self.requires_padding {
                 decode_tokens += entry.request.stopping_parameters.max_new_tokens;
             } else {
-                let max_new_tokens = match self.window_size {
-                    None => entry.request.stopping_parameters.max_new_tokens,
-                    Some(window_size) => min(
-                        window_size.saturating_sub(entry.request.input_length),
-                        entry.request.stopping_parameters.max_new_tokens,
-                    ),
-                };
-
                 // pad to block size
                 decode_tokens +=
                     ((max_new_tokens + self.block_size - 1) / self.block_size) * self.block_size;
             }
 
-            if prefill_tokens > prefill_token_budget
-                || (prefill_tokens + decode_tokens + self.speculate) > token_budget
-            {
+            let over_budget = match &self.blocks {
+                None => (prefill_tokens + decode_tokens + self.speculate) > token_budget,
+                Some(_) => false,
+            };
+            if prefill_tokens > prefill_token_budget || over_budget {
                 // Entry is over budget
                 // Add it back to the front
                 tracing::debug!("Over budget: prefill_tokens={prefill_tokens} > {prefill_token_budget} || {prefill_tokens} + {decode_tokens} + {} > {token_budget}", self.speculate);
@@ -276,6 +302,22 @@ impl State {
                 break;
             }
 
+            if let Some(blocks) = &self.blocks {
+                let tokens = entry.request.input_length + max_new_tokens + self.speculate;
+                match blocks.allocate(tokens) {
+                    Some(reservation) => entry.blocks = Some(reservation),
+                    None => {
+                        tracing::debug!(
+                            "Not enough free blocks: {} > {}",
+                            blocks.blocks(tokens),
+                            blocks.free_blocks()
+                        );
+                        self.entries.push_front((id, entry));
+                        break;
+                    }
+                }
+            }
+
             tracing::debug!("Accepting entry");
             // Create a new span to link the batch back to this entry
             let entry_batch_span = info_span!(parent: &entry.span, "infer");
@@ -417,8 +459,10 @@ mod tests {
             request: ValidGenerateRequest {
                 inputs: vec![],
                 input_ids: Some(Arc::new(vec![])),
+                input_offsets: None,
                 input_length: 0,
                 add_special_tokens: true,
+                pre_tokenized: false,
                 truncate: 0,
                 decoder_input_details: false,
                 parameters: ValidParameters {
@@ -432,6 +476,8 @@ mod tests {
                     frequency_penalty: 0.0,
                     watermark: false,
                     grammar: None,
+                    allowed_token_ids: vec![],
+                    banned_token_ids: vec![],
                 },
                 stopping_parameters: ValidStoppingParameters {
                     ignore_eos_token: false,
@@ -440,19 +486,27 @@ mod tests {
                 },
                 top_n_tokens: 0,
                 adapter_id: None,
+                raw_bytes: false,
+                stop_on_repetition: None,
+                stop_on_json: false,
+                stop_token_ids: vec![],
+                pin_prefix: None,
             },
             response_tx,
             span: info_span!("entry"),
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
+            decoder: Utf8Decoder::default(),
+            steps: 0,
+            blocks: None,
         };
         (entry, receiver_tx)
     }
 
     #[test]
     fn test_append() {
-        let mut state = State::new(false, 1, None, 0);
+        let mut state = State::new(false, 1, None, 0, None);
         let (entry, _guard) = default_entry();
 
         assert_eq!(state.next_id, 0);
@@ -468,7 +522,7 @@ mod tests {
 
     #[test]
     fn test_next_batch_empty() {
-        let mut state = State::new(false, 1, None, 0);
+        let mut state = State::new(false, 1, None, 0, None);
 
         assert!(state.next_batch(None, None, 1, 1).is_none());
         assert!(state.next_batch(Some(1), None, 1, 1).is_none());
@@ -476,7 +530,7 @@ mod tests {
 
     #[test]
     fn test_next_batch_min_size() {
-        let mut state = State::new(false, 1, None, 0);
+        let mut state = State::new(false, 1, None, 0, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
@@ -508,7 +562,7 @@ mod tests {
 
     #[test]
     fn test_next_batch_max_size() {
-        let mut state = State::new(false, 1, None, 0);
+        let mut state = State::new(false, 1, None, 0, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
@@ -526,9 +580,33 @@ mod tests {
         assert_eq!(state.next_batch_id, 1);
     }
 
+    #[test]
+    fn test_next_batch_free_blocks() {
+        let blocks = BlockManager::new(1, 1);
+        let mut state = State::new(false, 1, None, 0, Some(blocks.clone()));
+        let (entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+
+        // The token budget is not used when the blocks are tracked
+        let (entries, batch, _) = state.next_batch(None, None, 2, 0).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key(&0));
+        assert_eq!(batch.size, 1);
+        assert_eq!(blocks.free_blocks(), 0);
+        assert!(state.next_batch(None, None, 2, 0).is_none());
+
+        // Finished entries free their blocks
+        drop(entries);
+        assert_eq!(blocks.free_blocks(), 1);
+        let (entries, _, _) = state.next_batch(None, None, 2, 0).unwrap();
+        assert!(entries.contains_key(&1));
+    }
+
     #[test]
     fn test_next_batch_token_budget() {
-        let mut state = State::new(false, 1, None, 0);
+        let mut state = State::new(false, 1, None, 0, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
@@ -561,14 +639,14 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_append() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry, _guard) = default_entry();
         queue.append(entry);
     }
 
     #[tokio::test]
     async fn test_queue_next_batch_empty() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
 
         assert!(queue.next_batch(None, None, 1, 1).await.is_none());
         assert!(queue.next_batch(Some(1), None, 1, 1).await.is_none());
@@ -576,7 +654,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_min_size() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -609,7 +687,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_max_size() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -625,7 +703,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_token_budget() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -650,7 +728,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_token_speculate() {
-        let queue = Queue::new(false, 1, None, 2);
+        let queue = Queue::new(false, 1, None, 2, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -669,7 +747,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_dropped_receiver() {
-        let queue = Queue::new(false, 1, None, 0);
+        let queue = Queue::new(false, 1, None, 0, None);
         let (entry, _) = default_entry();
         queue.append(entry);
 
@@ -0,0 +1,21 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::runtime::Runtime;
+
+use text_generation_router_v3::bench::QueuedRequests;
+
+const MAX_BATCH_SIZE: usize = 32;
+
+fn next_batch_benchmark(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+
+    for queued in [100, 10_000, 50_000] {
+        let mut requests = runtime.block_on(async { QueuedRequests::new(queued) });
+        c.bench_function(
+            &format!("next_batch queued={queued} max_batch_size={MAX_BATCH_SIZE}"),
+            |b| b.iter(|| runtime.block_on(requests.next_batch(MAX_BATCH_SIZE))),
+        );
+    }
+}
+
+criterion_group!(benches, next_batch_benchmark);
+criterion_main!(benches);
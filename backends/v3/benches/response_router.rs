@@ -0,0 +1,85 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::{Stream, StreamExt};
+use text_generation_router::infer::{InferError, InferStreamResponse};
+use text_generation_router::Token;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use text_generation_router_v3::response::ResponseRouter;
+
+const BATCH_SIZE: usize = 128;
+const TOKENS: usize = 64;
+
+fn response() -> Result<InferStreamResponse, InferError> {
+    Ok(InferStreamResponse::Intermediate {
+        token: Token {
+            id: 0,
+            text: " token".to_string(),
+            logprob: -0.5,
+            special: false,
+            bytes: None,
+        },
+        top_tokens: vec![],
+    })
+}
+
+/// Batch of requests streaming `TOKENS` responses each, read by one task per request
+async fn run<S, R>(streams: Vec<S>, senders: Vec<R>, send: fn(&R))
+where
+    S: Stream + Send + Unpin + 'static,
+{
+    let readers: Vec<_> = streams
+        .into_iter()
+        .map(|mut stream| tokio::spawn(async move { while stream.next().await.is_some() {} }))
+        .collect();
+    for _ in 0..TOKENS {
+        for sender in senders.iter() {
+            send(sender);
+        }
+        // Let the readers run between decode steps, like the batching task awaiting the shards
+        tokio::task::yield_now().await;
+    }
+    drop(senders);
+    for reader in readers {
+        reader.await.unwrap();
+    }
+}
+
+fn response_router_benchmark(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let responses = ResponseRouter::default();
+
+    c.bench_function(
+        &format!("unbounded channels batch_size={BATCH_SIZE} tokens={TOKENS}"),
+        |b| {
+            b.iter(|| {
+                let (senders, streams): (Vec<_>, Vec<_>) = (0..BATCH_SIZE)
+                    .map(|_| {
+                        let (sender, receiver) = mpsc::unbounded_channel();
+                        (sender, UnboundedReceiverStream::new(receiver))
+                    })
+                    .unzip();
+                runtime.block_on(run(streams, senders, |sender| {
+                    sender.send(response()).unwrap()
+                }))
+            })
+        },
+    );
+
+    c.bench_function(
+        &format!("response router batch_size={BATCH_SIZE} tokens={TOKENS}"),
+        |b| {
+            b.iter(|| {
+                let (senders, streams): (Vec<_>, Vec<_>) =
+                    (0..BATCH_SIZE).map(|_| responses.channel()).unzip();
+                runtime.block_on(run(streams, senders, |sender| {
+                    sender.send(response()).unwrap()
+                }))
+            })
+        },
+    );
+}
+
+criterion_group!(benches, response_router_benchmark);
+criterion_main!(benches);
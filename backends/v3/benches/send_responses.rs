@@ -0,0 +1,57 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use text_generation_router_v3::bench::DecodeStep;
+
+/// Allocator counting the allocations, to report the allocation rate of `send_responses`
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const BATCH_SIZE: usize = 128;
+
+fn send_responses_benchmark(c: &mut Criterion) {
+    for top_n_tokens in [0, 5] {
+        let mut step = DecodeStep::new(BATCH_SIZE, top_n_tokens);
+
+        let generations = step.generations();
+        let step_generations = generations.clone();
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        step.send(generations);
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        println!(
+            "send_responses: {:.1} allocations per sequence with top_n_tokens={top_n_tokens}",
+            allocations as f64 / BATCH_SIZE as f64
+        );
+
+        c.bench_function(
+            &format!("send_responses batch_size={BATCH_SIZE} top_n_tokens={top_n_tokens}"),
+            |b| {
+                b.iter_batched(
+                    || step_generations.clone(),
+                    |generations| step.send(generations),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+}
+
+criterion_group!(benches, send_responses_benchmark);
+criterion_main!(benches);
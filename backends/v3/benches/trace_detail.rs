@@ -0,0 +1,37 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use opentelemetry::sdk::trace::TracerProvider;
+use opentelemetry::trace::TracerProvider as _;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use text_generation_router::logging::TraceDetail;
+use text_generation_router_v3::bench::DecodeStep;
+
+const BATCH_SIZE: usize = 128;
+
+fn trace_detail_benchmark(c: &mut Criterion) {
+    // Spans are built as for an OTLP export, without exporting them
+    let tracer = TracerProvider::builder().build().tracer("bench");
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .init();
+
+    for trace_detail in [TraceDetail::Token, TraceDetail::Request] {
+        let mut step = DecodeStep::new(BATCH_SIZE, 0);
+        let generations = step.generations();
+
+        c.bench_function(
+            &format!("decode_step batch_size={BATCH_SIZE} trace_detail={trace_detail:?}"),
+            |b| {
+                b.iter_batched(
+                    || generations.clone(),
+                    |generations| step.decode(generations, trace_detail),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+}
+
+criterion_group!(benches, trace_detail_benchmark);
+criterion_main!(benches);
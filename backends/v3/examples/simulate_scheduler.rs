@@ -0,0 +1,31 @@
+//! Compare the scheduling policies on the same synthetic trace
+//!
+//! cargo run -p text-generation-router-v3 --features simulator --example simulate_scheduler
+use text_generation_router_v3::simulator::{poisson_trace, simulate, CostModel, SchedulerConfig};
+use text_generation_router_v3::{PriorityAging, SchedulingPolicy};
+
+fn main() {
+    let trace = poisson_trace(0, 500, 2.5, 16..=2048, 16..=512);
+    let cost = CostModel::default();
+
+    for (scheduling_policy, priority_aging) in [
+        (SchedulingPolicy::Fifo, None),
+        (SchedulingPolicy::LongestWaitFirst, None),
+        (SchedulingPolicy::ShortestPrefillFirst, None),
+        (
+            SchedulingPolicy::ShortestPrefillFirst,
+            Some(PriorityAging {
+                rate: 100.0,
+                max_boost: 4096,
+            }),
+        ),
+    ] {
+        let scheduler = SchedulerConfig {
+            scheduling_policy,
+            priority_aging,
+            ..Default::default()
+        };
+        println!("{scheduling_policy:?}, priority aging {priority_aging:?}");
+        println!("{}", simulate(&trace, &scheduler, &cost));
+    }
+}
@@ -0,0 +1,14 @@
+//! Check the invariants of the queue admission on arbitrary scenarios
+//!
+//! cargo +nightly fuzz run next_batch
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use text_generation_router_v3::admission;
+
+fuzz_target!(|data: &[u8]| {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(admission::check(data));
+});
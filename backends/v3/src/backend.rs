@@ -1,20 +1,38 @@
 /// Batching and inference logic
 use crate::client::{
-    Batch, CachedBatch, ClientError, Generation, Health, InfoResponse, ShardedClient,
+    Batch, BatchClient, CacheStats, CachedBatch, ClientError, Generation, Health, InfoResponse,
+    ShardedClient,
 };
-use crate::queue::{Entry, Queue};
+use crate::grammar::GrammarCompiler;
+use crate::lifecycle::TrackedClient;
+use crate::queue::{Entry, PriorityAging, Queue, QueuedAt, SchedulingPolicy};
+use crate::response::ResponseRouter;
+use crate::slo::{SloAction, TtftSlo};
 use async_trait::async_trait;
 use nohash_hasher::IntMap;
+use std::future::Future;
 use std::sync::Arc;
-use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
+use std::time::Duration;
+use text_generation_router::infer::utf8::{token_bytes, Utf8Decoder};
+use text_generation_router::infer::{
+    Backend, BackendCapabilities, GeneratedText, GenerationStream, InferError, InferStreamResponse,
+};
+use text_generation_router::logging::TraceDetail;
+use text_generation_router::scheduler_events::{self, SchedulerEvent};
 use text_generation_router::validation::ValidGenerateRequest;
-use text_generation_router::{FinishReason, PrefillToken, Token};
+use text_generation_router::{
+    BackendMetadata, FinishReason, LoadAdapterRequest, PrefillToken, ShardInfo, Token,
+};
 use tokio::sync::mpsc::error::SendError;
-use tokio::sync::{mpsc, Notify};
+use tokio::sync::Notify;
 use tokio::time::Instant;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 use tracing::{info_span, instrument, Instrument, Span};
 
+/// Interval between the calls for the KV cache occupancy of the shards, while batches run
+const CACHE_STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval between the reconciliations of the shards cache, while no batch runs
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
 pub struct BackendV3 {
     /// Request queue
     queue: Queue,
@@ -22,6 +40,24 @@ pub struct BackendV3 {
     batching_task_notifier: Arc<Notify>,
     /// Client clone, used for health checks to skip the queue
     client: ShardedClient,
+    /// Grammars of the queued requests compiled by the shards
+    grammars: GrammarCompiler,
+    /// Response buffers of the queued and running requests
+    responses: ResponseRouter,
+    /// Model shards, in rank order
+    shards: Vec<ShardInfo>,
+    /// The prompts are kept in the prefix cache of the block allocator
+    prefix_caching: bool,
+    /// Reported in `/info`
+    metadata: BackendMetadata,
+    /// Adapters of the metadata, with the ones loaded since the startup
+    adapters: std::sync::RwLock<Vec<String>>,
+    /// One adapter is loaded at a time, the shards number them in loading order
+    adapter_loading: tokio::sync::Mutex<()>,
+    /// Objective on the estimated time to first token of the new requests
+    ttft_slo: Option<TtftSlo>,
+    /// Prompt tokens prefilled per batch, for the time to first token estimates
+    max_batch_prefill_tokens: u32,
 }
 
 impl BackendV3 {
@@ -33,13 +69,22 @@ impl BackendV3 {
         max_batch_total_tokens: u32,
         max_waiting_tokens: usize,
         max_batch_size: Option<usize>,
+        scheduling_policy: SchedulingPolicy,
+        priority_aging: Option<PriorityAging>,
+        eager_admission: bool,
+        ttft_slo: Option<TtftSlo>,
+        batch_timeout: Option<Duration>,
+        trace_detail: TraceDetail,
         shard_info: InfoResponse,
+        shards: Vec<ShardInfo>,
+        metadata: BackendMetadata,
     ) -> Self {
         if shard_info.support_chunking {
             tracing::warn!("Model supports prefill chunking. `waiting_served_ratio` and `max_waiting_tokens` will be ignored.");
         }
 
         let block_size = shard_info.block_size;
+        let prefix_caching = shard_info.use_prefix_caching && !shard_info.requires_padding;
 
         let queue = Queue::new(
             shard_info.requires_padding,
@@ -49,6 +94,8 @@ impl BackendV3 {
             shard_info.speculate,
             max_batch_total_tokens,
             shard_info.support_chunking,
+            scheduling_policy,
+            priority_aging,
         );
         let batching_task_notifier = Arc::new(Notify::new());
 
@@ -60,15 +107,54 @@ impl BackendV3 {
             max_batch_total_tokens,
             max_waiting_tokens,
             max_batch_size,
+            eager_admission,
+            batch_timeout,
+            trace_detail,
             shard_info.support_chunking,
             queue.clone(),
             batching_task_notifier.clone(),
         ));
 
+        let grammars = GrammarCompiler::new(client.clone(), batching_task_notifier.clone());
+
         Self {
             queue,
             batching_task_notifier,
             client,
+            grammars,
+            responses: ResponseRouter::default(),
+            shards,
+            prefix_caching,
+            adapters: std::sync::RwLock::new(metadata.adapters.clone()),
+            adapter_loading: tokio::sync::Mutex::default(),
+            metadata,
+            ttft_slo,
+            max_batch_prefill_tokens,
+        }
+    }
+
+    /// Whether `request` is downgraded, or its rejection, when its estimated time to first
+    /// token is over the objective
+    fn check_ttft_slo(&self, request: &ValidGenerateRequest) -> Result<bool, InferError> {
+        let Some(slo) = self.ttft_slo else {
+            return Ok(false);
+        };
+        let estimate = self
+            .queue
+            .ttft()
+            .estimate(request.input_length, self.max_batch_prefill_tokens);
+        let Some(estimate) = estimate.filter(|estimate| *estimate > slo.objective()) else {
+            return Ok(false);
+        };
+        match slo.action {
+            SloAction::Reject => {
+                metrics::counter!("tgi_request_failure", "err" => "ttft_slo").increment(1);
+                Err(InferError::TtftSlo(estimate.as_millis() as u64, slo.slo_ms))
+            }
+            SloAction::Downgrade => {
+                metrics::counter!("tgi_queue_downgraded").increment(1);
+                Ok(true)
+            }
         }
     }
 }
@@ -76,12 +162,18 @@ impl BackendV3 {
 #[async_trait]
 impl Backend for BackendV3 {
     #[instrument(skip_all)]
-    fn schedule(
-        &self,
-        request: ValidGenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
-        // MPSC channel to communicate with the background batching task
-        let (response_tx, response_rx) = mpsc::unbounded_channel();
+    fn schedule(&self, request: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
+        let downgraded = self.check_ttft_slo(&request)?;
+
+        // Slot to communicate with the background batching task
+        let (response_tx, response_rx) = self.responses.channel();
+
+        // Compile the grammar while the request is queued
+        let grammar = request
+            .parameters
+            .grammar
+            .as_ref()
+            .map(|grammar| self.grammars.compile(grammar));
 
         // Append the request to the queue
         self.queue.append(Entry {
@@ -91,7 +183,12 @@ impl Backend for BackendV3 {
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
+            decoder: Utf8Decoder::default(),
+            steps: 0,
             block_allocation: None,
+            queued_at: QueuedAt::default(),
+            grammar,
+            downgraded,
         });
 
         // Notify the background task that we have a new entry in the queue that needs
@@ -99,7 +196,7 @@ impl Backend for BackendV3 {
         self.batching_task_notifier.notify_one();
 
         // Return stream
-        Ok(UnboundedReceiverStream::new(response_rx))
+        Ok(Box::pin(response_rx))
     }
 
     async fn health(&self, current_health: bool) -> bool {
@@ -111,6 +208,50 @@ impl Backend for BackendV3 {
         }
         .is_ok()
     }
+
+    fn name(&self) -> &'static str {
+        "v3"
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities {
+            supports_prefix_pinning: self.prefix_caching,
+            // The shards return the prefill logprobs with `decoder_input_details`
+            supports_prefill_logits: true,
+            supports_token_lists: true,
+            ..Default::default()
+        }
+    }
+
+    fn shards(&self) -> Vec<ShardInfo> {
+        self.shards.clone()
+    }
+
+    fn metadata(&self) -> BackendMetadata {
+        BackendMetadata {
+            adapters: self.adapters.read().unwrap().clone(),
+            ..self.metadata.clone()
+        }
+    }
+
+    fn unpin_prefix(&self, id: u64) {
+        self.queue.unpin_prefix(id);
+    }
+
+    async fn load_adapter(&self, request: LoadAdapterRequest) -> Result<(), InferError> {
+        let _loading = self.adapter_loading.lock().await;
+        if self.adapters.read().unwrap().contains(&request.adapter_id) {
+            return Ok(());
+        }
+        // The batches keep running while the shards download the weights
+        self.client
+            .clone()
+            .load_adapter(request.adapter_id.clone(), request.revision)
+            .await
+            .map_err(|err| InferError::AdapterLoading(err.to_string()))?;
+        self.adapters.write().unwrap().push(request.adapter_id);
+        Ok(())
+    }
 }
 
 /// Batching logic
@@ -119,20 +260,36 @@ impl Backend for BackendV3 {
 /// Batches requests and sends them to the inference server
 #[allow(clippy::too_many_arguments)]
 pub(crate) async fn batching_task(
-    mut client: ShardedClient,
+    client: impl BatchClient,
     waiting_served_ratio: f32,
     max_batch_prefill_tokens: u32,
     max_batch_total_tokens: u32,
     max_waiting_tokens: usize,
     max_batch_size: Option<usize>,
+    eager_admission: bool,
+    batch_timeout: Option<Duration>,
+    trace_detail: TraceDetail,
     support_chunking: bool,
     queue: Queue,
     notifier: Arc<Notify>,
 ) {
+    let mut client = TrackedClient::new(client);
+    // Last call for the KV cache occupancy, during the running batches
+    let mut cache_stats_time: Option<Instant> = None;
     // Infinite loop
     loop {
         // Wait for a notification from the Infer struct
-        notifier.notified().await;
+        // The shards cache is reconciled while idle, in case batches were left behind
+        if tokio::time::timeout(RECONCILE_INTERVAL, notifier.notified())
+            .await
+            .is_err()
+        {
+            match watchdog(client.cache_stats(), batch_timeout, "cache_stats").await {
+                Ok(cache_stats) => reconcile(&mut client, &cache_stats, batch_timeout).await,
+                Err(err) => tracing::warn!("Unable to reconcile the shards cache: {err}"),
+            }
+            continue;
+        }
 
         // Get the next batch from the queue
         // This batch might be smaller than the maximum batch size if there are not enough requests
@@ -146,14 +303,41 @@ pub(crate) async fn batching_task(
             )
             .await
         {
-            let mut cached_batch = prefill(&mut client, batch, None, &mut entries)
-                .instrument(span)
-                .await;
+            let batch_prefill_tokens = prefill_tokens(&batch, &entries);
+            let start_time = Instant::now();
+            let mut cached_batch = prefill(
+                &mut client,
+                batch,
+                None,
+                &mut entries,
+                batch_timeout,
+                trace_detail,
+            )
+            .instrument(span)
+            .await;
+            queue
+                .ttft()
+                .record_prefill(batch_prefill_tokens, start_time.elapsed());
             let mut waiting_tokens = 1;
+            // Whether sequences of the running batch finished during the last decode step
+            let mut sequences_finished = false;
 
             // We loop until we do not receive any cached batch from the inference server (== until
             // all requests have met their stopping criteria)
             while let Some(batch) = cached_batch {
+                // The queue budgets the new entries with the blocks actually free on the shards
+                if cache_stats_time.map_or(true, |time| time.elapsed() >= CACHE_STATS_INTERVAL) {
+                    cache_stats_time = Some(Instant::now());
+                    match watchdog(client.cache_stats(), batch_timeout, "cache_stats").await {
+                        Ok(cache_stats) => {
+                            record_cache_stats(&cache_stats);
+                            reconcile(&mut client, &cache_stats, batch_timeout).await;
+                            queue.set_cache_stats(Some(cache_stats));
+                        }
+                        Err(err) => tracing::warn!("Unable to get the KV cache stats: {err}"),
+                    }
+                }
+
                 // Get current batch info
                 let batch_size = batch.size;
                 let batch_max_tokens = batch.max_tokens;
@@ -179,6 +363,10 @@ pub(crate) async fn batching_task(
                         // If we didn't onboard any new requests since >= max_waiting_tokens, we try
                         // to add a new batch even though its size might be small
                         None
+                    } else if eager_admission && sequences_finished {
+                        // Finished sequences freed the budget they were projected to use until
+                        // `max_new_tokens`, use it right away
+                        None
                     } else {
                         // Minimum batch size
                         // TODO: temporarily disable to avoid incorrect deallocation +
@@ -198,41 +386,58 @@ pub(crate) async fn batching_task(
                     .await
                 {
                     // Tracking metrics
-                    if min_size.is_some() {
-                        metrics::counter!("tgi_batch_concat", "reason" => "backpressure")
-                            .increment(1);
+                    let reason = if min_size.is_some() {
+                        "backpressure"
+                    } else if support_chunking {
+                        "chunking"
+                    } else if waiting_tokens >= max_waiting_tokens {
+                        "wait_exceeded"
                     } else {
-                        let counter = if support_chunking {
-                            metrics::counter!("tgi_batch_concat", "reason" => "chunking")
-                        } else {
-                            metrics::counter!("tgi_batch_concat", "reason" => "wait_exceeded")
-                        };
-                        counter.increment(1);
-                    }
+                        "finished"
+                    };
+                    metrics::counter!("tgi_batch_concat", "reason" => reason).increment(1);
+                    scheduler_events::emit(SchedulerEvent::Concat {
+                        reason,
+                        new_requests: new_entries.len(),
+                        running_requests: batch_size,
+                    });
                     let cached_batch = if support_chunking {
                         // Concat current batch to the new one
                         batches.pop()
                     } else {
                         // Request are waiting only if we don't support chunking
-                        entries.iter_mut().for_each(|(_, entry)| {
-                            // Create a new span to add the info that this entry is waiting
-                            // because a new batch is being computed
-                            let entry_waiting_span = info_span!(parent: &entry.span, "waiting");
-                            // Add relationships
-                            span.follows_from(&entry_waiting_span);
-                            entry_waiting_span.follows_from(&span);
-                            // Update entry
-                            entry.temp_span = Some(entry_waiting_span);
-                        });
+                        if trace_detail == TraceDetail::Token {
+                            entries.iter_mut().for_each(|(_, entry)| {
+                                // Create a new span to add the info that this entry is waiting
+                                // because a new batch is being computed
+                                let entry_waiting_span = info_span!(parent: &entry.span, "waiting");
+                                // Add relationships
+                                span.follows_from(&entry_waiting_span);
+                                entry_waiting_span.follows_from(&span);
+                                // Update entry
+                                entry.temp_span = Some(entry_waiting_span);
+                            });
+                        }
                         None
                     };
                     entries.extend(new_entries);
 
                     // Generate one token for this new batch to have the attention past in cache
-                    let new_cached_batch =
-                        prefill(&mut client, new_batch, cached_batch, &mut entries)
-                            .instrument(span)
-                            .await;
+                    let batch_prefill_tokens = prefill_tokens(&new_batch, &entries);
+                    let start_time = Instant::now();
+                    let new_cached_batch = prefill(
+                        &mut client,
+                        new_batch,
+                        cached_batch,
+                        &mut entries,
+                        batch_timeout,
+                        trace_detail,
+                    )
+                    .instrument(span)
+                    .await;
+                    queue
+                        .ttft()
+                        .record_prefill(batch_prefill_tokens, start_time.elapsed());
                     // Reset waiting counter
                     waiting_tokens = 1;
                     // Extend current batch with the new batch
@@ -244,50 +449,97 @@ pub(crate) async fn batching_task(
                     }
                 }
 
-                // Create span for this batch to add context to inference calls
                 let next_batch_size = entries.len();
-                let next_batch_span =
-                    info_span!(parent: None, "batch", batch_size = next_batch_size);
-                entries.iter_mut().for_each(|(_, entry)| {
-                    // Create a new span to link the batch back to this entry
-                    let entry_batch_span = info_span!(parent: &entry.span, "infer");
-                    // Add relationships
-                    next_batch_span.follows_from(&entry_batch_span);
-                    entry_batch_span.follows_from(&next_batch_span);
-                    // Update entry
-                    entry.temp_span = Some(entry_batch_span);
-                });
-
-                cached_batch = decode(&mut client, batches, &mut entries)
-                    .instrument(next_batch_span)
-                    .await;
+                let next_batch_span = decode_span(&mut entries, trace_detail);
+
+                let start_time = Instant::now();
+                cached_batch = decode(
+                    &mut client,
+                    batches,
+                    &mut entries,
+                    batch_timeout,
+                    trace_detail,
+                )
+                .instrument(next_batch_span)
+                .await;
+                queue.ttft().record_decode(start_time.elapsed());
+                sequences_finished = entries.len() < next_batch_size;
                 waiting_tokens += 1;
             }
+            queue.ttft().idle();
+            // The cache is empty without running batches
+            queue.set_cache_stats(None);
+            cache_stats_time = None;
+            metrics::gauge!("tgi_kv_cache_used_blocks").set(0.0);
+            metrics::gauge!("tgi_kv_cache_used_bytes").set(0.0);
             metrics::gauge!("tgi_batch_current_size").set(0.0);
             metrics::gauge!("tgi_batch_current_max_tokens").set(0.0);
         }
     }
 }
 
+/// Clear the batches cached by the shards that the batching task does not use
+///
+/// A batch whose clear fails is left to the next reconciliation.
+async fn reconcile(
+    client: &mut TrackedClient<impl BatchClient>,
+    cache_stats: &CacheStats,
+    batch_timeout: Option<Duration>,
+) {
+    for id in client.reconcile(&cache_stats.batch_ids) {
+        tracing::warn!("Clearing orphaned batch {id} from the shards cache");
+        metrics::counter!("tgi_batch_orphaned").increment(1);
+        if let Err(err) = watchdog(client.clear_cache(Some(id)), batch_timeout, "clear").await {
+            tracing::error!("Clearing orphaned batch {id} failed: {err}");
+        }
+    }
+}
+
+fn record_cache_stats(cache_stats: &CacheStats) {
+    metrics::gauge!("tgi_kv_cache_used_blocks").set(cache_stats.used_blocks as f64);
+    metrics::gauge!("tgi_kv_cache_total_blocks").set(cache_stats.total_blocks as f64);
+    metrics::gauge!("tgi_kv_cache_used_bytes").set(cache_stats.used_bytes as f64);
+    metrics::gauge!("tgi_kv_cache_total_bytes").set(cache_stats.total_bytes as f64);
+}
+
+/// Prompt tokens computed by the prefill of `batch`, without the cached prefixes
+fn prefill_tokens(batch: &Batch, entries: &IntMap<u64, Entry>) -> u32 {
+    batch
+        .requests
+        .iter()
+        .map(|request| {
+            request.chunk_len.unwrap_or_else(|| {
+                entries[&request.id]
+                    .request
+                    .input_length
+                    .saturating_sub(request.cache_len)
+            })
+        })
+        .sum()
+}
+
 #[instrument(skip_all)]
 async fn prefill(
-    client: &mut ShardedClient,
+    client: &mut impl BatchClient,
     batch: Batch,
     cached_batch: Option<CachedBatch>,
     entries: &mut IntMap<u64, Entry>,
+    batch_timeout: Option<Duration>,
+    trace_detail: TraceDetail,
 ) -> Option<CachedBatch> {
     let start_time = Instant::now();
     let batch_id = batch.id;
     metrics::counter!("tgi_batch_inference_count", "method" => "prefill").increment(1);
 
-    match client.prefill(batch, cached_batch).await {
+    let call = client.prefill(batch, cached_batch);
+    match watchdog(call, batch_timeout, "prefill").await {
         Ok((generations, next_batch, timings)) => {
             let start_filtering_time = Instant::now();
             // Send generated tokens and filter stopped entries
-            filter_send_generations(generations, entries);
+            filter_send_generations(generations, entries, trace_detail);
 
             // Filter next batch and remove requests that were stopped
-            let next_batch = filter_batch(client, next_batch, entries).await;
+            let next_batch = filter_batch(client, next_batch, entries, batch_timeout).await;
 
             if let Some(concat_duration) = timings.concat {
                 metrics::histogram!("tgi_batch_concat_duration", "method" => "decode")
@@ -306,7 +558,7 @@ async fn prefill(
         }
         // If we have an error, we discard the whole batch
         Err(err) => {
-            let _ = client.clear_cache(Some(batch_id)).await;
+            let _ = watchdog(client.clear_cache(Some(batch_id)), batch_timeout, "clear").await;
             send_errors(err, entries);
             metrics::counter!("tgi_batch_inference_failure", "method" => "prefill").increment(1);
             None
@@ -316,22 +568,25 @@ async fn prefill(
 
 #[instrument(skip_all)]
 async fn decode(
-    client: &mut ShardedClient,
+    client: &mut impl BatchClient,
     batches: Vec<CachedBatch>,
     entries: &mut IntMap<u64, Entry>,
+    batch_timeout: Option<Duration>,
+    trace_detail: TraceDetail,
 ) -> Option<CachedBatch> {
     let start_time = Instant::now();
     let batch_ids: Vec<u64> = batches.iter().map(|b| b.id).collect();
     metrics::counter!("tgi_batch_inference_count", "method" => "decode").increment(1);
 
-    match client.decode(batches).await {
+    let call = client.decode(batches);
+    match watchdog(call, batch_timeout, "decode").await {
         Ok((generations, next_batch, timings)) => {
             let start_filtering_time = Instant::now();
             // Send generated tokens and filter stopped entries
-            filter_send_generations(generations, entries);
+            filter_send_generations(generations, entries, trace_detail);
 
             // Filter next batch and remove requests that were stopped
-            let next_batch = filter_batch(client, next_batch, entries).await;
+            let next_batch = filter_batch(client, next_batch, entries, batch_timeout).await;
 
             if let Some(concat_duration) = timings.concat {
                 metrics::histogram!("tgi_batch_concat_duration", "method" => "decode")
@@ -351,7 +606,7 @@ async fn decode(
         // If we have an error, we discard the whole batch
         Err(err) => {
             for id in batch_ids {
-                let _ = client.clear_cache(Some(id)).await;
+                let _ = watchdog(client.clear_cache(Some(id)), batch_timeout, "clear").await;
             }
             send_errors(err, entries);
             metrics::counter!("tgi_batch_inference_failure", "method" => "decode").increment(1);
@@ -360,12 +615,60 @@ async fn decode(
     }
 }
 
+/// Fail a call to the shards that did not return within `batch_timeout`
+///
+/// A hung shard never answers, which would stall the batching task and all the requests behind
+/// it. The requests of the batch are failed instead, which marks the backend unhealthy in the
+/// router, and the batching task moves on to the next batch.
+async fn watchdog<T>(
+    call: impl Future<Output = Result<T, ClientError>>,
+    batch_timeout: Option<Duration>,
+    method: &'static str,
+) -> Result<T, ClientError> {
+    let Some(batch_timeout) = batch_timeout else {
+        return call.await;
+    };
+    match tokio::time::timeout(batch_timeout, call).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::error!("Shards did not answer the {method} call within {batch_timeout:?}");
+            metrics::counter!("tgi_batch_timeout", "method" => method).increment(1);
+            Err(ClientError::Timeout(batch_timeout))
+        }
+    }
+}
+
+/// Create the span of a decode step, and with `TraceDetail::Token` a span per entry linked to it
+fn decode_span(entries: &mut IntMap<u64, Entry>, trace_detail: TraceDetail) -> Span {
+    // Create span for this batch to add context to inference calls
+    let next_batch_span = info_span!(parent: None, "batch", batch_size = entries.len());
+    if trace_detail == TraceDetail::Token {
+        entries.iter_mut().for_each(|(_, entry)| {
+            // Create a new span to link the batch back to this entry
+            let entry_batch_span = info_span!(parent: &entry.span, "infer");
+            // Add relationships
+            next_batch_span.follows_from(&entry_batch_span);
+            entry_batch_span.follows_from(&next_batch_span);
+            // Update entry
+            entry.temp_span = Some(entry_batch_span);
+        });
+    }
+    next_batch_span
+}
+
+/// Attempts at filtering a batch before it is dropped
+const FILTER_ATTEMPTS: usize = 2;
+
 /// Filter a `batch` and remove all requests not present in `entries`
+///
+/// A failed call is retried once. If it fails again the batch is dropped: its requests are
+/// failed and it is cleared from the shards cache, the batching task keeps serving the queue.
 #[instrument(skip_all)]
 async fn filter_batch(
-    client: &mut ShardedClient,
+    client: &mut impl BatchClient,
     next_batch: Option<CachedBatch>,
-    entries: &IntMap<u64, Entry>,
+    entries: &mut IntMap<u64, Entry>,
+    batch_timeout: Option<Duration>,
 ) -> Option<CachedBatch> {
     let mut batch = next_batch?;
 
@@ -379,34 +682,59 @@ async fn filter_batch(
     // Retain only requests that are still in entries
     batch.request_ids.retain(|id| entries.contains_key(id));
 
-    if batch.request_ids.is_empty() {
-        // All requests have been filtered out
-        // Next batch is now empty
-        // Clear it from the Python shards cache
-        // We unwrap here as we need to panic since we cannot recover if this method fails
-        client.clear_cache(Some(id)).await.unwrap();
-        None
-    } else {
-        // Filter Python shard cache
-        // We unwrap here as we need to panic since we cannot recover if this method fails
-        client.filter_batch(id, batch.request_ids).await.unwrap()
+    for attempt in 1..=FILTER_ATTEMPTS {
+        let result = if batch.request_ids.is_empty() {
+            // All requests have been filtered out
+            // Next batch is now empty
+            // Clear it from the Python shards cache
+            let call = client.clear_cache(Some(id));
+            watchdog(call, batch_timeout, "clear").await.map(|()| None)
+        } else {
+            // Filter Python shard cache
+            let call = client.filter_batch(id, batch.request_ids.clone());
+            watchdog(call, batch_timeout, "filter").await
+        };
+        let err = match result {
+            Ok(next_batch) => return next_batch,
+            Err(err) => err,
+        };
+        if attempt < FILTER_ATTEMPTS {
+            tracing::warn!("Filtering batch {id} failed, retrying: {err}");
+            continue;
+        }
+        if batch.request_ids.is_empty() {
+            // No request is left to fail
+            tracing::error!("Clearing batch {id} failed: {err}");
+        } else {
+            tracing::error!("Filtering batch {id} failed, dropping it: {err}");
+            let _ = watchdog(client.clear_cache(Some(id)), batch_timeout, "clear").await;
+            send_errors(err, entries);
+            metrics::counter!("tgi_batch_inference_failure", "method" => "filter").increment(1);
+        }
     }
+    None
 }
 
 /// Send one or multiple `InferStreamResponse` to Infer for all `entries`
 /// and filter entries
 #[instrument(skip_all)]
-fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u64, Entry>) {
+fn filter_send_generations(
+    generations: Vec<Generation>,
+    entries: &mut IntMap<u64, Entry>,
+    trace_detail: TraceDetail,
+) {
     generations.into_iter().for_each(|generation| {
         let id = generation.request_id;
         // Get entry
         // We can `expect` here as the request id should always be in the entries
         let entry = entries
-            .get(&id)
+            .get_mut(&id)
             .expect("ID not found in entries. This is a bug.");
 
         // Create and enter a span to link this function back to the entry
-        let _span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_generation", generation = ?generation).entered();
+        let _span = (trace_detail == TraceDetail::Token).then(|| {
+            info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_generation", generation = ?generation).entered()
+        });
         // Send generation responses back to the infer task
         // If the receive an error from the Flume channel, it means that the client dropped the
         // request and we need to stop generating hence why we unwrap_or(true)
@@ -423,7 +751,7 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
 /// Send responses through the `entry` response channel
 fn send_responses(
     generation: Generation,
-    entry: &Entry,
+    entry: &mut Entry,
 ) -> Result<bool, Box<SendError<Result<InferStreamResponse, InferError>>>> {
     // Return directly if the channel is disconnected
     if entry.response_tx.is_closed() {
@@ -441,7 +769,13 @@ fn send_responses(
             .into_iter()
             .zip(prefill_tokens.logprobs)
             .zip(prefill_tokens.texts)
-            .map(|((id, logprob), text)| PrefillToken { id, text, logprob })
+            .map(|((id, logprob), text)| PrefillToken {
+                id,
+                text,
+                logprob,
+                start: None,
+                stop: None,
+            })
             .collect();
 
         // Send message
@@ -454,52 +788,77 @@ fn send_responses(
     let tokens_ = generation.tokens.expect("Non empty tokens in generation");
     let n = tokens_.ids.len();
     metrics::histogram!("tgi_request_skipped_tokens").record((n - 1) as f64);
+    entry.steps += 1;
+    let mut generated_text = generation.generated_text;
+    // Top tokens are moved out of the generation instead of being copied
+    let mut top_tokens_iter = generation.top_tokens.into_iter();
     let mut iterator = tokens_
         .ids
         .into_iter()
         .zip(tokens_.logprobs)
         .zip(tokens_.texts)
         .zip(tokens_.is_special)
-        .enumerate()
         .peekable();
-    while let Some((i, (((id, logprob), text), special))) = iterator.next() {
+    while let Some((((id, logprob), text), special)) = iterator.next() {
+        // The generated text is sent with the last token
+        let generated_text = match iterator.peek() {
+            None => generated_text.take(),
+            Some(_) => None,
+        };
+        let end = generated_text.is_some();
+        let (text, bytes) = if entry.request.raw_bytes {
+            let bytes = token_bytes(&text);
+            (text, Some(bytes))
+        } else {
+            // Only send complete characters
+            let mut text = entry.decoder.push(text);
+            if end {
+                text.push_str(&entry.decoder.flush());
+            }
+            (text, None)
+        };
         let token = Token {
             id,
             text,
             logprob,
             special,
+            bytes,
         };
-        let top_tokens = if let Some(top_tokens_) = generation.top_tokens.get(i) {
-            top_tokens_
+        let top_tokens = match top_tokens_iter.next() {
+            // Skip the top tokens entirely if they were not requested
+            Some(top_tokens_) if entry.request.top_n_tokens > 0 => top_tokens_
                 .ids
-                .iter()
-                .zip(top_tokens_.logprobs.iter())
-                .zip(top_tokens_.texts.iter())
-                .zip(top_tokens_.is_special.iter())
-                .map(|(((&id, &logprob), text), &special)| Token {
+                .into_iter()
+                .zip(top_tokens_.logprobs)
+                .zip(top_tokens_.texts)
+                .zip(top_tokens_.is_special)
+                .map(|(((id, logprob), text), special)| Token {
                     id,
-                    text: text.to_string(),
+                    bytes: entry.request.raw_bytes.then(|| token_bytes(&text)),
+                    text,
                     logprob,
                     special,
                 })
-                .collect()
-        } else {
-            vec![]
+                .collect(),
+            _ => Vec::new(),
         };
-        match (&generation.generated_text, iterator.peek()) {
-            (Some(generated_text), None) => {
+        match generated_text {
+            Some(generated_text) => {
                 // Generation has ended
                 stopped = true;
                 // Send message
                 entry.response_tx.send(Ok(InferStreamResponse::End {
                     token,
                     top_tokens,
-                    generated_text: GeneratedText::from(generated_text.clone()),
+                    generated_text: GeneratedText {
+                        steps: Some(entry.steps),
+                        ..GeneratedText::from(generated_text)
+                    },
                     queued: entry.queue_time,
                     start: entry.batch_time.unwrap(),
                 }))?;
             }
-            _ => {
+            None => {
                 // Send message
                 entry
                     .response_tx
@@ -517,8 +876,12 @@ fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
     entries.drain().for_each(|(_, entry)| {
         // Create and enter a span to link this function back to the entry
         let _send_error_span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_error").entered();
-        let err = InferError::GenerationError(error.to_string());
-        metrics::counter!("tgi_request_failure", "err" => "generation").increment(1);
+        let (err, label) = match error {
+            // The shards did not answer within `--batch-timeout-secs`
+            ClientError::Timeout(_) => (InferError::GenerationTimeout(error.to_string()), "timeout"),
+            _ => (InferError::GenerationError(error.to_string()), "generation"),
+        };
+        metrics::counter!("tgi_request_failure", "err" => label).increment(1);
         tracing::error!("{err}");
 
         // unwrap_or is valid here as we don't care if the receiver is gone.
@@ -543,6 +906,366 @@ impl From<crate::client::GeneratedText> for GeneratedText {
             generated_tokens: value.generated_tokens,
             finish_reason,
             seed: value.seed,
+            backend: None,
+            steps: None,
+        }
+    }
+}
+
+/// Hooks for the `send_responses` and `next_batch` benchmarks, and the scheduler simulator
+#[doc(hidden)]
+pub mod bench {
+    use super::*;
+    use crate::client::Tokens;
+    use crate::response::{ResponseSender, ResponseStream};
+    use futures::{FutureExt, StreamExt};
+    use std::collections::VecDeque;
+    use text_generation_router::infer::utf8::Utf8Decoder;
+    use text_generation_router::validation::{ValidParameters, ValidStoppingParameters};
+
+    /// One decode step of a batch
+    pub struct DecodeStep {
+        entries: IntMap<u64, Entry>,
+        receivers: Vec<ResponseStream>,
+        top_n_tokens: u32,
+    }
+
+    impl DecodeStep {
+        pub fn new(batch_size: usize, top_n_tokens: u32) -> Self {
+            let responses = ResponseRouter::default();
+            let (entries, receivers) = (0..batch_size as u64)
+                .map(|id| {
+                    let (response_tx, response_rx) = responses.channel();
+                    let entry = entry(top_n_tokens, response_tx);
+                    ((id, entry), response_rx)
+                })
+                .unzip();
+            Self {
+                entries,
+                receivers,
+                top_n_tokens,
+            }
+        }
+
+        /// Generations returned by the shards for this step
+        pub fn generations(&self) -> Vec<Generation> {
+            let tokens = |n: usize| Tokens {
+                ids: (0..n as u32).collect(),
+                logprobs: vec![-0.5; n],
+                texts: vec![" token".to_string(); n],
+                is_special: vec![false; n],
+            };
+            (0..self.entries.len() as u64)
+                .map(|request_id| Generation {
+                    request_id,
+                    prefill_tokens: None,
+                    tokens: Some(tokens(1)),
+                    generated_text: None,
+                    top_tokens: match self.top_n_tokens {
+                        0 => vec![],
+                        n => vec![tokens(n as usize)],
+                    },
+                })
+                .collect()
+        }
+
+        /// Send the generations and consume the responses
+        pub fn send(&mut self, generations: Vec<Generation>) {
+            for generation in generations {
+                let entry = self.entries.get_mut(&generation.request_id).unwrap();
+                send_responses(generation, entry).unwrap();
+            }
+            self.receive();
+        }
+
+        /// Send the generations with the spans recorded by the batching task for
+        /// `trace_detail`, and consume the responses
+        pub fn decode(&mut self, generations: Vec<Generation>, trace_detail: TraceDetail) {
+            let span = decode_span(&mut self.entries, trace_detail);
+            span.in_scope(|| filter_send_generations(generations, &mut self.entries, trace_detail));
+            self.receive();
+        }
+
+        fn receive(&mut self) {
+            for receiver in self.receivers.iter_mut() {
+                while receiver.next().now_or_never().flatten().is_some() {}
+            }
+        }
+    }
+
+    /// Queue of waiting requests, refilled after every batch so that its length stays constant
+    pub struct QueuedRequests {
+        queue: Queue,
+        responses: ResponseRouter,
+        streams: VecDeque<ResponseStream>,
+    }
+
+    impl QueuedRequests {
+        /// Must be called from a Tokio runtime, which runs the queue task
+        pub fn new(queued: usize) -> Self {
+            let mut requests = Self {
+                queue: Queue::new(
+                    false,
+                    16,
+                    false,
+                    None,
+                    0,
+                    16 * 4096,
+                    false,
+                    SchedulingPolicy::Fifo,
+                    None,
+                ),
+                responses: ResponseRouter::default(),
+                streams: VecDeque::new(),
+            };
+            requests.append(queued);
+            requests
+        }
+
+        fn append(&mut self, n: usize) {
+            for _ in 0..n {
+                let (response_tx, response_rx) = self.responses.channel();
+                let mut entry = entry(0, response_tx);
+                entry.request.input_length = 8;
+                entry.request.stopping_parameters.max_new_tokens = 8;
+                self.queue.append(entry);
+                self.streams.push_back(response_rx);
+            }
+        }
+
+        /// Batch up to `max_size` requests and queue as many new ones
+        pub async fn next_batch(&mut self, max_size: usize) {
+            let (entries, _, _) = self
+                .queue
+                .next_batch(None, Some(max_size), 4096, 16 * 4096)
+                .await
+                .unwrap();
+            self.append(entries.len());
+            // The streams of the batched requests are not needed anymore
+            self.streams.drain(..entries.len());
+        }
+    }
+
+    pub(crate) fn entry(top_n_tokens: u32, response_tx: ResponseSender) -> Entry {
+        Entry {
+            request: ValidGenerateRequest {
+                inputs: vec![],
+                input_ids: None,
+                input_offsets: None,
+                input_length: 1,
+                add_special_tokens: true,
+                pre_tokenized: false,
+                truncate: 0,
+                decoder_input_details: false,
+                parameters: ValidParameters {
+                    temperature: 1.0,
+                    top_k: 0,
+                    top_p: 1.0,
+                    typical_p: 1.0,
+                    do_sample: false,
+                    seed: 0,
+                    repetition_penalty: 1.0,
+                    frequency_penalty: 0.0,
+                    watermark: false,
+                    grammar: None,
+                    allowed_token_ids: vec![],
+                    banned_token_ids: vec![],
+                },
+                stopping_parameters: ValidStoppingParameters {
+                    ignore_eos_token: false,
+                    max_new_tokens: u32::MAX,
+                    stop_sequences: vec![],
+                },
+                top_n_tokens,
+                adapter_id: None,
+                raw_bytes: false,
+                stop_on_repetition: None,
+                stop_on_json: false,
+                stop_token_ids: vec![],
+                pin_prefix: None,
+            },
+            response_tx,
+            span: info_span!("schedule"),
+            temp_span: None,
+            queue_time: Instant::now(),
+            batch_time: Some(Instant::now()),
+            decoder: Utf8Decoder::default(),
+            steps: 0,
+            block_allocation: None,
+            queued_at: QueuedAt::default(),
+            grammar: None,
+            downgraded: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{DecodeTimings, PrefillTimings};
+    use crate::response::ResponseRouter;
+    use futures::StreamExt;
+
+    /// Shards whose next `failures` filter calls fail
+    #[derive(Default)]
+    struct FlakyShards {
+        failures: usize,
+        filtered: usize,
+        cleared: Vec<Option<u64>>,
+    }
+
+    #[async_trait::async_trait]
+    impl BatchClient for FlakyShards {
+        async fn prefill(
+            &mut self,
+            _batch: Batch,
+            _cached_batch: Option<CachedBatch>,
+        ) -> Result<(Vec<Generation>, Option<CachedBatch>, PrefillTimings), ClientError> {
+            unreachable!()
+        }
+
+        async fn decode(
+            &mut self,
+            _batches: Vec<CachedBatch>,
+        ) -> Result<(Vec<Generation>, Option<CachedBatch>, DecodeTimings), ClientError> {
+            unreachable!()
+        }
+
+        async fn filter_batch(
+            &mut self,
+            batch_id: u64,
+            request_ids: Vec<u64>,
+        ) -> Result<Option<CachedBatch>, ClientError> {
+            self.filtered += 1;
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(ClientError::Generation("filter failed".to_string()));
+            }
+            Ok(Some(CachedBatch {
+                id: batch_id,
+                size: request_ids.len() as u32,
+                max_tokens: 0,
+                current_tokens: 0,
+                request_ids,
+            }))
+        }
+
+        async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<(), ClientError> {
+            self.cleared.push(batch_id);
+            Ok(())
+        }
+
+        async fn cache_stats(&mut self) -> Result<CacheStats, ClientError> {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_batch_failure() {
+        let responses = ResponseRouter::default();
+        let (response_tx, mut response_rx) = responses.channel();
+        let mut entry = bench::entry(0, response_tx);
+        entry.temp_span = Some(info_span!("infer"));
+        let mut entries = IntMap::from_iter([(1, entry)]);
+        let batch = || {
+            Some(CachedBatch {
+                id: 7,
+                request_ids: vec![0, 1],
+                size: 2,
+                max_tokens: 0,
+                current_tokens: 0,
+            })
+        };
+
+        // A failed filter is retried
+        let mut client = FlakyShards {
+            failures: 1,
+            ..Default::default()
+        };
+        let next_batch = filter_batch(&mut client, batch(), &mut entries, None).await;
+        assert_eq!(next_batch.unwrap().request_ids, [1]);
+        assert_eq!(client.filtered, 2);
+        assert_eq!(entries.len(), 1);
+
+        // The batch is dropped and its requests are failed when the retry fails too
+        let mut client = FlakyShards {
+            failures: 2,
+            ..Default::default()
+        };
+        let next_batch = filter_batch(&mut client, batch(), &mut entries, None).await;
+        assert!(next_batch.is_none());
+        assert_eq!(client.cleared, [Some(7)]);
+        assert!(entries.is_empty());
+        assert!(matches!(
+            response_rx.next().await,
+            Some(Err(InferError::GenerationError(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_errors_timeout() {
+        let responses = ResponseRouter::default();
+        let (response_tx, mut response_rx) = responses.channel();
+        let mut entry = bench::entry(0, response_tx);
+        entry.temp_span = Some(info_span!("infer"));
+        let mut entries = IntMap::from_iter([(0, entry)]);
+
+        // Timeouts of the watchdog are told apart from the failures of the shards
+        send_errors(ClientError::Timeout(Duration::from_secs(1)), &mut entries);
+        assert!(matches!(
+            response_rx.next().await,
+            Some(Err(InferError::GenerationTimeout(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_responses_steps() {
+        let responses = ResponseRouter::default();
+        let (response_tx, mut response_rx) = responses.channel();
+        let mut entry = bench::entry(0, response_tx);
+        let generation = |n: usize, generated_text| Generation {
+            request_id: 0,
+            prefill_tokens: None,
+            tokens: Some(crate::client::Tokens {
+                ids: (0..n as u32).collect(),
+                logprobs: vec![-0.5; n],
+                texts: vec![" token".to_string(); n],
+                is_special: vec![false; n],
+            }),
+            generated_text,
+            top_tokens: vec![],
+        };
+
+        // The prefill generates one token, the decode accepts two speculated ones
+        assert!(!send_responses(generation(1, None), &mut entry).unwrap());
+        let generated_text = crate::client::GeneratedText {
+            text: " token".repeat(4),
+            generated_tokens: 4,
+            finish_reason: crate::client::FinishReason::Length.into(),
+            seed: None,
+        };
+        assert!(send_responses(generation(3, Some(generated_text)), &mut entry).unwrap());
+        drop(entry);
+        let mut end = None;
+        while let Some(response) = response_rx.next().await {
+            if let Ok(InferStreamResponse::End { generated_text, .. }) = response {
+                end = Some(generated_text);
+            }
+        }
+        let generated_text = end.unwrap();
+        assert_eq!(generated_text.steps, Some(2));
+        assert_eq!(generated_text.generated_tokens, 4);
+    }
+
+    #[tokio::test]
+    async fn test_watchdog() {
+        let timeout = Some(Duration::from_millis(10));
+        let hung = std::future::pending::<Result<(), ClientError>>();
+        assert!(matches!(
+            watchdog(hung, timeout, "decode").await,
+            Err(ClientError::Timeout(_))
+        ));
+        assert!(watchdog(async { Ok(()) }, timeout, "decode").await.is_ok());
+        assert!(watchdog(async { Ok(()) }, None, "decode").await.is_ok());
+    }
+}
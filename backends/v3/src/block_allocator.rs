@@ -54,16 +54,20 @@ impl BlockAllocator {
         }
     }
 
+    /// Allocate the blocks of `tokens`, the cache of `prefill_tokens` is kept once they are
+    /// freed until `pin_prefix` is unpinned
     pub(crate) async fn allocate(
         &self,
         tokens: u32,
         prefill_tokens: Option<Arc<Vec<u32>>>,
+        pin_prefix: Option<u64>,
     ) -> Option<BlockAllocation> {
         let (response_sender, response_receiver) = oneshot::channel();
         self.block_allocator
             .send(BlockAllocatorCommand::Allocate {
                 tokens,
                 prefill_tokens,
+                pin_prefix,
                 response_sender,
             })
             .unwrap();
@@ -82,6 +86,12 @@ impl BlockAllocator {
             })
             .unwrap();
     }
+
+    pub(crate) fn unpin(&self, pin_prefix: u64) {
+        self.block_allocator
+            .send(BlockAllocatorCommand::Unpin { pin_prefix })
+            .unwrap();
+    }
 }
 
 async fn block_allocator_task(
@@ -105,12 +115,16 @@ async fn block_allocator_task(
             BlockAllocatorCommand::Allocate {
                 tokens,
                 prefill_tokens,
+                pin_prefix,
                 response_sender,
             } => {
-                response_sender
-                    .send(allocator.allocate(tokens, prefill_tokens))
-                    .unwrap();
+                let allocation = allocator.allocate(tokens, prefill_tokens);
+                if let (Some(allocation), Some(pin_prefix)) = (&allocation, pin_prefix) {
+                    allocator.pin(allocation.allocation_id, pin_prefix);
+                }
+                response_sender.send(allocation).unwrap();
             }
+            BlockAllocatorCommand::Unpin { pin_prefix } => allocator.unpin(pin_prefix),
         }
     }
 }
@@ -124,8 +138,12 @@ enum BlockAllocatorCommand {
     Allocate {
         tokens: u32,
         prefill_tokens: Option<Arc<Vec<u32>>>,
+        pin_prefix: Option<u64>,
         response_sender: oneshot::Sender<Option<BlockAllocation>>,
     },
+    Unpin {
+        pin_prefix: u64,
+    },
 }
 
 pub trait Allocator {
@@ -136,6 +154,12 @@ pub trait Allocator {
     ) -> Option<BlockAllocation>;
 
     fn free(&mut self, blocks: Vec<u32>, allocation_id: u64);
+
+    /// Keep the cache of the prefill tokens of the allocation once it is freed, until `unpin`.
+    /// Allocators without prefix caching have nothing to keep.
+    fn pin(&mut self, _allocation_id: u64, _pin_prefix: u64) {}
+
+    fn unpin(&mut self, _pin_prefix: u64) {}
 }
 pub struct SimpleAllocator {
     free_blocks: Vec<u32>,
@@ -168,6 +168,7 @@ impl Client {
                 slots: vec![],
                 cache_len: 0,
                 chunk_len: None,
+                input_ids: Vec::new(),
                 // Set sampling parameters to also take these ops into account in the max memory
                 parameters: Some(NextTokenChooserParameters {
                     temperature: 0.9,
@@ -181,6 +182,8 @@ impl Client {
                     watermark: true,
                     grammar: String::new(),
                     grammar_type: GrammarType::None as i32,
+                    allowed_token_ids: vec![],
+                    banned_token_ids: vec![],
                 }),
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens,
@@ -222,6 +225,44 @@ impl Client {
         ))
     }
 
+    /// Occupancy of the KV cache
+    #[instrument(skip(self))]
+    pub async fn cache_stats(&mut self) -> Result<CacheStats> {
+        let request = tonic::Request::new(CacheStatsRequest {}).inject_context();
+        let response = self.stub.cache_stats(request).await?.into_inner();
+        Ok(CacheStats {
+            used_blocks: response.used_blocks,
+            total_blocks: response.total_blocks,
+            used_bytes: response.used_bytes,
+            total_bytes: response.total_bytes,
+            batch_ids: response.batch_ids,
+        })
+    }
+
+    /// Load a LoRA adapter, from `path` or from the hub
+    #[instrument(skip(self))]
+    pub async fn load_adapter(&mut self, id: String, revision: Option<String>) -> Result<()> {
+        let request = tonic::Request::new(LoadAdapterRequest { id, revision }).inject_context();
+        self.stub.load_adapter(request).await?;
+        Ok(())
+    }
+
+    /// Compile the finite-state machine of a grammar ahead of the batches using it
+    #[instrument(skip_all)]
+    pub async fn compile_grammar(
+        &mut self,
+        grammar: String,
+        grammar_type: GrammarType,
+    ) -> Result<()> {
+        let request = tonic::Request::new(CompileGrammarRequest {
+            grammar,
+            grammar_type: grammar_type.into(),
+        })
+        .inject_context();
+        self.stub.compile_grammar(request).await?;
+        Ok(())
+    }
+
     /// Generate one token for each request in the given batch
     ///
     /// Returns Generation for each request in batch
@@ -274,6 +315,23 @@ impl Client {
     }
 }
 
+/// Occupancy of the KV cache of the shards
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub used_blocks: u32,
+    pub total_blocks: u32,
+    pub used_bytes: u64,
+    pub total_bytes: u64,
+    /// Ids of the batches cached by the shards
+    pub batch_ids: Vec<u64>,
+}
+
+impl CacheStats {
+    pub fn free_blocks(&self) -> u32 {
+        self.total_blocks.saturating_sub(self.used_blocks)
+    }
+}
+
 pub struct PrefillTimings {
     pub concat: Option<Duration>,
     pub forward: Duration,
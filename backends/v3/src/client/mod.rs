@@ -1,6 +1,7 @@
 //! Text Generation gRPC client library
 
 use async_trait::async_trait;
+use std::time::Duration;
 use thiserror::Error;
 use tonic::transport;
 use tonic::Status;
@@ -11,11 +12,11 @@ mod pb;
 mod grpc_client;
 mod sharded_client;
 
-pub use grpc_client::Client;
+pub use grpc_client::{CacheStats, Client, DecodeTimings, PrefillTimings};
 pub use pb::generate::v3::{
     input_chunk::Chunk, Batch, CachedBatch, FinishReason, GeneratedText, Generation, GrammarType,
     HealthResponse, Image, InfoResponse, Input, InputChunk, NextTokenChooserParameters, Request,
-    StoppingCriteriaParameters,
+    StoppingCriteriaParameters, Tokens,
 };
 pub use sharded_client::ShardedClient;
 
@@ -29,6 +30,37 @@ pub trait Health {
     async fn model_health(&self) -> Result<()>;
 }
 
+/// Calls of the batching task to the shards, also implemented by the mock shards of the
+/// scheduler simulator
+#[async_trait]
+pub trait BatchClient: Send + 'static {
+    /// Generate one token for each request of `batch`, concatenated to `cached_batch`
+    async fn prefill(
+        &mut self,
+        batch: Batch,
+        cached_batch: Option<CachedBatch>,
+    ) -> Result<(Vec<Generation>, Option<CachedBatch>, PrefillTimings)>;
+
+    /// Generate one token for each request of `batches`, concatenated together
+    async fn decode(
+        &mut self,
+        batches: Vec<CachedBatch>,
+    ) -> Result<(Vec<Generation>, Option<CachedBatch>, DecodeTimings)>;
+
+    /// Keep only `request_ids` in a cached batch
+    async fn filter_batch(
+        &mut self,
+        batch_id: u64,
+        request_ids: Vec<u64>,
+    ) -> Result<Option<CachedBatch>>;
+
+    /// Clear a cached batch, or all of them
+    async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()>;
+
+    /// Occupancy of the KV cache by the cached batches
+    async fn cache_stats(&mut self) -> Result<CacheStats>;
+}
+
 #[derive(Error, Debug, Clone)]
 pub enum ClientError {
     #[error("Could not connect to Text Generation server: {0}")]
@@ -37,6 +69,8 @@ pub enum ClientError {
     Generation(String),
     #[error("Sharded results are empty")]
     EmptyResults,
+    #[error("Shards did not answer within {0:?}")]
+    Timeout(Duration),
 }
 
 impl From<Status> for ClientError {
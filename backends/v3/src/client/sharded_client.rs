@@ -1,8 +1,8 @@
-use crate::client::Health;
+use crate::client::{BatchClient, Health};
 /// Multi shard Client
 use crate::client::{ClientError, Result};
 
-use crate::client::grpc_client::{DecodeTimings, PrefillTimings};
+use crate::client::grpc_client::{CacheStats, DecodeTimings, PrefillTimings};
 use crate::client::{
     Batch, CachedBatch, Client, Generation, GrammarType, HealthResponse,
     NextTokenChooserParameters, Request, StoppingCriteriaParameters,
@@ -47,15 +47,15 @@ impl ShardedClient {
         Self::from_master_client(master_client).await
     }
 
-    /// Get the model info
+    /// Get the model info of every shard, in rank order
     #[instrument(skip(self))]
-    pub async fn info(&mut self) -> Result<InfoResponse> {
+    pub async fn info(&mut self) -> Result<Vec<InfoResponse>> {
         let futures: Vec<_> = self
             .clients
             .iter_mut()
             .map(|client| client.info())
             .collect();
-        join_all(futures).await.pop().unwrap()
+        join_all(futures).await.into_iter().collect()
     }
 
     /// GRPC health check
@@ -134,6 +134,63 @@ impl ShardedClient {
         Ok(*min)
     }
 
+    /// Occupancy of the KV cache of the shards
+    ///
+    /// Every shard holds the same blocks, with a slice of the heads of each, so the blocks are
+    /// those of the fullest shard and the bytes are summed. The batches are those cached by any
+    /// shard.
+    #[instrument(skip(self))]
+    pub async fn cache_stats(&mut self) -> Result<CacheStats> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.cache_stats())
+            .collect();
+        let results: Result<Vec<CacheStats>> = join_all(futures).await.into_iter().collect();
+        let mut stats = results?
+            .into_iter()
+            .reduce(|mut total, stats| {
+                total.batch_ids.extend(stats.batch_ids);
+                CacheStats {
+                    used_blocks: total.used_blocks.max(stats.used_blocks),
+                    total_blocks: total.total_blocks.min(stats.total_blocks),
+                    used_bytes: total.used_bytes + stats.used_bytes,
+                    total_bytes: total.total_bytes + stats.total_bytes,
+                    batch_ids: total.batch_ids,
+                }
+            })
+            .ok_or(ClientError::EmptyResults)?;
+        stats.batch_ids.sort_unstable();
+        stats.batch_ids.dedup();
+        Ok(stats)
+    }
+
+    /// Load a LoRA adapter on every shard
+    #[instrument(skip(self))]
+    pub async fn load_adapter(&mut self, id: String, revision: Option<String>) -> Result<()> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.load_adapter(id.clone(), revision.clone())))
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
+    /// Compile the finite-state machine of a grammar on every shard
+    #[instrument(skip_all)]
+    pub async fn compile_grammar(
+        &mut self,
+        grammar: String,
+        grammar_type: GrammarType,
+    ) -> Result<()> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.compile_grammar(grammar.clone(), grammar_type)))
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
     /// Generate one token for each request in the given batch
     ///
     /// Returns Generation for each request in batch
@@ -154,6 +211,12 @@ impl ShardedClient {
             join_all(futures).await.into_iter().collect();
         let mut results = results?;
 
+        // Per shard latencies, to show the skew between ranks
+        for (rank, (_, _, shard_timings)) in results.iter().enumerate() {
+            metrics::histogram!("tgi_shard_forward_duration", "method" => "prefill", "rank" => rank.to_string())
+                .record(shard_timings.forward.as_secs_f64());
+        }
+
         let (mut generations, next_batch, mut timings) =
             results.pop().ok_or(ClientError::EmptyResults)?;
 
@@ -187,6 +250,12 @@ impl ShardedClient {
             join_all(futures).await.into_iter().collect();
         let mut results = results?;
 
+        // Per shard latencies, to show the skew between ranks
+        for (rank, (_, _, shard_timings)) in results.iter().enumerate() {
+            metrics::histogram!("tgi_shard_forward_duration", "method" => "decode", "rank" => rank.to_string())
+                .record(shard_timings.forward.as_secs_f64());
+        }
+
         let (mut generations, next_batch, mut timings) =
             results.pop().ok_or(ClientError::EmptyResults)?;
 
@@ -202,6 +271,40 @@ impl ShardedClient {
     }
 }
 
+#[async_trait]
+impl BatchClient for ShardedClient {
+    async fn prefill(
+        &mut self,
+        batch: Batch,
+        cached_batch: Option<CachedBatch>,
+    ) -> Result<(Vec<Generation>, Option<CachedBatch>, PrefillTimings)> {
+        ShardedClient::prefill(self, batch, cached_batch).await
+    }
+
+    async fn decode(
+        &mut self,
+        batches: Vec<CachedBatch>,
+    ) -> Result<(Vec<Generation>, Option<CachedBatch>, DecodeTimings)> {
+        ShardedClient::decode(self, batches).await
+    }
+
+    async fn filter_batch(
+        &mut self,
+        batch_id: u64,
+        request_ids: Vec<u64>,
+    ) -> Result<Option<CachedBatch>> {
+        ShardedClient::filter_batch(self, batch_id, request_ids).await
+    }
+
+    async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
+        ShardedClient::clear_cache(self, batch_id).await
+    }
+
+    async fn cache_stats(&mut self) -> Result<CacheStats> {
+        ShardedClient::cache_stats(self).await
+    }
+}
+
 #[async_trait]
 impl Health for ShardedClient {
     async fn device_health(&self) -> Result<()> {
@@ -232,6 +335,8 @@ impl Health for ShardedClient {
                 watermark: false,
                 grammar: String::new(),
                 grammar_type: GrammarType::None as i32,
+                allowed_token_ids: vec![],
+                banned_token_ids: vec![],
             }),
             stopping_parameters: Some(StoppingCriteriaParameters {
                 max_new_tokens: 1,
@@ -245,6 +350,7 @@ impl Health for ShardedClient {
             cache_len: 0,
             adapter_id: None,
             chunk_len: None,
+            input_ids: Vec::new(),
         };
         let batch = Batch {
             id: u64::MAX,
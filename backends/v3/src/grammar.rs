@@ -0,0 +1,114 @@
+/// Compilation of the grammars of the constrained requests ahead of their batches
+///
+/// The shards compile the finite-state machine of a grammar the first time a batch uses it,
+/// which stalls the forward of the whole batch, unconstrained requests included. The grammars
+/// are instead compiled by the shards as soon as their requests are queued, and the queue only
+/// batches a constrained request once its grammar is compiled.
+use crate::client::{GrammarType, ShardedClient};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use text_generation_router::validation::ValidGrammar;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Number of compiled grammars kept by the shards, the size of their `_cached_compile_fsm` cache
+const MAX_COMPILED_GRAMMARS: usize = 32;
+
+/// Compilation of the grammar of a queued request
+#[derive(Debug, Default)]
+pub(crate) struct GrammarState {
+    compiled: AtomicBool,
+}
+
+impl GrammarState {
+    /// The request can be batched. A grammar the shards failed to compile ahead of time is
+    /// compiled in the prefill, as without this compilation
+    pub(crate) fn is_compiled(&self) -> bool {
+        self.compiled.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_compiled(&self) {
+        self.compiled.store(true, Ordering::Release);
+    }
+}
+
+#[derive(Default)]
+struct Grammars {
+    /// Grammars by hash, with the clock of their last use
+    states: HashMap<u64, (Arc<GrammarState>, u64)>,
+    clock: u64,
+}
+
+/// Grammars compiled by the shards
+#[derive(Clone)]
+pub(crate) struct GrammarCompiler {
+    client: ShardedClient,
+    grammars: Arc<Mutex<Grammars>>,
+    /// Wakes the batching task up when a grammar is compiled
+    notifier: Arc<Notify>,
+}
+
+impl GrammarCompiler {
+    pub(crate) fn new(client: ShardedClient, notifier: Arc<Notify>) -> Self {
+        Self {
+            client,
+            grammars: Arc::new(Mutex::new(Grammars::default())),
+            notifier,
+        }
+    }
+
+    /// Compilation of `grammar`, started in the background if the shards do not have it
+    pub(crate) fn compile(&self, grammar: &ValidGrammar) -> Arc<GrammarState> {
+        let (grammar, grammar_type) = match grammar {
+            ValidGrammar::Json(grammar) => (grammar, GrammarType::Json),
+            ValidGrammar::Regex(grammar) => (grammar, GrammarType::Regex),
+        };
+        let mut hasher = DefaultHasher::new();
+        grammar_type.hash(&mut hasher);
+        grammar.hash(&mut hasher);
+        let key = hasher.finish();
+
+        let mut grammars = self.grammars.lock().unwrap();
+        grammars.clock += 1;
+        let clock = grammars.clock;
+        if let Some((state, last_used)) = grammars.states.get_mut(&key) {
+            metrics::counter!("tgi_grammar_cache_hit").increment(1);
+            *last_used = clock;
+            return state.clone();
+        }
+        metrics::counter!("tgi_grammar_cache_miss").increment(1);
+        if grammars.states.len() >= MAX_COMPILED_GRAMMARS {
+            // The shards evicted the least recently used grammar as well
+            if let Some(evicted) = grammars
+                .states
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(&key, _)| key)
+            {
+                grammars.states.remove(&evicted);
+            }
+        }
+        let state = Arc::new(GrammarState::default());
+        grammars.states.insert(key, (state.clone(), clock));
+        drop(grammars);
+
+        let mut client = self.client.clone();
+        let grammar = grammar.clone();
+        let notifier = self.notifier.clone();
+        let compiled = state.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            if let Err(err) = client.compile_grammar(grammar, grammar_type).await {
+                tracing::warn!("Could not compile the grammar ahead of its batch: {err}");
+            }
+            metrics::histogram!("tgi_grammar_compile_duration")
+                .record(start.elapsed().as_secs_f64());
+            compiled.set_compiled();
+            notifier.notify_one();
+        });
+        state
+    }
+}
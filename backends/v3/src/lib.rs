@@ -1,12 +1,27 @@
 mod backend;
 pub mod block_allocator;
 mod client;
+mod grammar;
+mod lifecycle;
 mod queue;
 pub mod radix;
+pub mod response;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+mod slo;
 
 use crate::client::{ClientError, ShardedClient};
+#[doc(hidden)]
+pub use backend::bench;
 pub(crate) use backend::BackendV3;
+#[doc(hidden)]
+pub use queue::admission;
+pub use queue::{PriorityAging, SchedulingPolicy};
 use serde::Serialize;
+pub use slo::{SloAction, TtftSlo};
+use std::time::Duration;
+use text_generation_router::logging::TraceDetail;
+use text_generation_router::{BackendMetadata, BatchingLimits, ShardInfo};
 use thiserror::Error;
 use utoipa::ToSchema;
 
@@ -31,6 +46,13 @@ pub struct BackendInfo {
     pub max_batch_size: Option<usize>,
     #[schema(example = "false")]
     pub support_chunking: bool,
+    pub scheduling_policy: SchedulingPolicy,
+    #[schema(nullable = true)]
+    pub priority_aging: Option<PriorityAging>,
+    #[schema(example = "false")]
+    pub eager_admission: bool,
+    #[schema(nullable = true)]
+    pub ttft_slo: Option<TtftSlo>,
     #[schema(example = "false")]
     pub prefix_caching: bool,
     #[schema(example = "flashinfer")]
@@ -54,6 +76,14 @@ pub async fn connect_backend(
     max_batch_total_tokens: Option<u32>,
     max_waiting_tokens: usize,
     max_batch_size: Option<usize>,
+    scheduling_policy: SchedulingPolicy,
+    priority_aging: Option<PriorityAging>,
+    eager_admission: bool,
+    ttft_slo: Option<TtftSlo>,
+    batch_timeout: Option<Duration>,
+    trace_detail: TraceDetail,
+    quantize: Option<String>,
+    lora_adapters: Vec<String>,
 ) -> Result<(BackendV3, BackendInfo), V3Error> {
     // Helper function
     let check_max_batch_total_tokens = |(
@@ -118,8 +148,20 @@ pub async fn connect_backend(
         .clear_cache(None)
         .await
         .map_err(V3Error::Cache)?;
-    // Get info from the shard
-    let shard_info = sharded_client.info().await.map_err(V3Error::Info)?;
+    // Get info from the shards
+    let shards_info = sharded_client.info().await.map_err(V3Error::Info)?;
+    let shards = shards_info
+        .iter()
+        .map(|info| ShardInfo {
+            rank: info.rank,
+            device_type: info.device_type.clone(),
+            device_memory: info.device_memory,
+        })
+        .collect();
+    let shard_info = shards_info
+        .into_iter()
+        .next_back()
+        .ok_or(V3Error::Info(ClientError::EmptyResults))?;
 
     // Warmup model
     tracing::info!("Warming up model");
@@ -144,6 +186,10 @@ pub async fn connect_backend(
         max_total_tokens,
         max_waiting_tokens,
         max_batch_size,
+        scheduling_policy,
+        priority_aging,
+        eager_admission,
+        ttft_slo,
         model_device_type: shard_info.device_type.clone(),
         model_dtype: shard_info.dtype.clone(),
         speculate: shard_info.speculate as usize,
@@ -153,6 +199,31 @@ pub async fn connect_backend(
         block_size: shard_info.block_size,
     };
 
+    let metadata = BackendMetadata {
+        // The shards are served by the Python server of the same release
+        version: None,
+        quantization: quantize,
+        // Formatted as `adapter_id=path@revision` for the shards
+        adapters: lora_adapters
+            .iter()
+            .map(|adapter| {
+                let adapter = adapter.trim();
+                adapter
+                    .split(['=', '@'])
+                    .next()
+                    .unwrap_or(adapter)
+                    .to_string()
+            })
+            .collect(),
+        batching: Some(BatchingLimits {
+            max_batch_prefill_tokens,
+            max_batch_total_tokens,
+            max_batch_size,
+            max_waiting_tokens,
+            waiting_served_ratio,
+        }),
+    };
+
     let backend = BackendV3::new(
         sharded_client,
         waiting_served_ratio,
@@ -160,7 +231,15 @@ pub async fn connect_backend(
         max_batch_total_tokens,
         max_waiting_tokens,
         max_batch_size,
+        scheduling_policy,
+        priority_aging,
+        eager_admission,
+        ttft_slo,
+        batch_timeout,
+        trace_detail,
         shard_info,
+        shards,
+        metadata,
     );
 
     tracing::info!("Using backend V3");
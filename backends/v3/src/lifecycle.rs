@@ -0,0 +1,266 @@
+/// Lifecycle of the batches cached by the shards
+///
+/// A prefill creates a batch, which is active while the batching task decodes and filters it.
+/// It is finished once its requests are done, it is concatenated into another batch or a call
+/// on it fails, and cleared once the shards dropped it. A finished batch can stay in the shards
+/// cache when its clear failed or was cancelled, or when the calls of a previous router process
+/// outlived it. The reconciliation compares the batches cached by the shards with the active
+/// ones and returns the orphans to clear.
+use crate::client::{
+    Batch, BatchClient, CacheStats, CachedBatch, DecodeTimings, Generation, PrefillTimings, Result,
+};
+use async_trait::async_trait;
+use nohash_hasher::IntMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BatchState {
+    /// Sent to the shards by a prefill still running
+    Created,
+    /// Returned by the shards and used by the batching task
+    Active,
+    /// No longer used by the batching task, possibly still cached by the shards
+    Finished,
+}
+
+/// Shards client recording the state of the batches of its calls
+///
+/// The batches of a call are finished before it is sent, so that a call cancelled by the
+/// watchdog leaves them to the reconciliation.
+pub(crate) struct TrackedClient<C> {
+    client: C,
+    /// Batches not cleared yet
+    states: IntMap<u64, BatchState>,
+}
+
+impl<C: BatchClient> TrackedClient<C> {
+    pub(crate) fn new(client: C) -> Self {
+        Self {
+            client,
+            states: IntMap::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn state(&self, batch_id: u64) -> Option<BatchState> {
+        self.states.get(&batch_id).copied()
+    }
+
+    /// Batches among `cached_ids`, cached by the shards, that are not active
+    ///
+    /// The batches no longer cached by the shards are cleared. This must not run during a call,
+    /// as the batches it creates are not active yet.
+    pub(crate) fn reconcile(&mut self, cached_ids: &[u64]) -> Vec<u64> {
+        self.states
+            .retain(|id, state| *state == BatchState::Active || cached_ids.contains(id));
+        cached_ids
+            .iter()
+            .filter(|id| self.states.get(id) != Some(&BatchState::Active))
+            .copied()
+            .collect()
+    }
+
+    fn finish(&mut self, batch_id: u64) {
+        self.states.insert(batch_id, BatchState::Finished);
+    }
+
+    fn activate(&mut self, next_batch: &Option<CachedBatch>) {
+        if let Some(batch) = next_batch {
+            self.states.insert(batch.id, BatchState::Active);
+        }
+    }
+}
+
+#[async_trait]
+impl<C: BatchClient> BatchClient for TrackedClient<C> {
+    async fn prefill(
+        &mut self,
+        batch: Batch,
+        cached_batch: Option<CachedBatch>,
+    ) -> Result<(Vec<Generation>, Option<CachedBatch>, PrefillTimings)> {
+        let batch_id = batch.id;
+        self.states.insert(batch_id, BatchState::Created);
+        if let Some(cached_batch) = &cached_batch {
+            self.finish(cached_batch.id);
+        }
+        let result = self.client.prefill(batch, cached_batch).await;
+        self.finish(batch_id);
+        if let Ok((_, next_batch, _)) = &result {
+            self.activate(next_batch);
+        }
+        result
+    }
+
+    async fn decode(
+        &mut self,
+        batches: Vec<CachedBatch>,
+    ) -> Result<(Vec<Generation>, Option<CachedBatch>, DecodeTimings)> {
+        for batch in &batches {
+            self.finish(batch.id);
+        }
+        let result = self.client.decode(batches).await;
+        if let Ok((_, next_batch, _)) = &result {
+            self.activate(next_batch);
+        }
+        result
+    }
+
+    async fn filter_batch(
+        &mut self,
+        batch_id: u64,
+        request_ids: Vec<u64>,
+    ) -> Result<Option<CachedBatch>> {
+        self.finish(batch_id);
+        let next_batch = self.client.filter_batch(batch_id, request_ids).await?;
+        self.activate(&next_batch);
+        Ok(next_batch)
+    }
+
+    async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
+        if let Some(batch_id) = batch_id {
+            self.finish(batch_id);
+        }
+        self.client.clear_cache(batch_id).await?;
+        match batch_id {
+            Some(batch_id) => {
+                self.states.remove(&batch_id);
+            }
+            None => self.states.clear(),
+        }
+        Ok(())
+    }
+
+    async fn cache_stats(&mut self) -> Result<CacheStats> {
+        self.client.cache_stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientError;
+
+    /// Shards caching the prefilled batches, and failing the clears when `clear_fails`
+    #[derive(Default)]
+    struct Shards {
+        cached: Vec<u64>,
+        clear_fails: bool,
+    }
+
+    fn cached_batch(id: u64) -> CachedBatch {
+        CachedBatch {
+            id,
+            request_ids: vec![id],
+            size: 1,
+            max_tokens: 1,
+            current_tokens: 1,
+        }
+    }
+
+    #[async_trait]
+    impl BatchClient for Shards {
+        async fn prefill(
+            &mut self,
+            batch: Batch,
+            cached_batch: Option<CachedBatch>,
+        ) -> Result<(Vec<Generation>, Option<CachedBatch>, PrefillTimings)> {
+            // The cached batch is concatenated into the new one
+            if let Some(cached_batch) = cached_batch {
+                self.cached.retain(|id| *id != cached_batch.id);
+            }
+            self.cached.push(batch.id);
+            let timings = PrefillTimings {
+                concat: None,
+                forward: Default::default(),
+                decode: Default::default(),
+                total: Default::default(),
+            };
+            Ok((vec![], Some(self::cached_batch(batch.id)), timings))
+        }
+
+        async fn decode(
+            &mut self,
+            _batches: Vec<CachedBatch>,
+        ) -> Result<(Vec<Generation>, Option<CachedBatch>, DecodeTimings)> {
+            unreachable!()
+        }
+
+        async fn filter_batch(
+            &mut self,
+            batch_id: u64,
+            _request_ids: Vec<u64>,
+        ) -> Result<Option<CachedBatch>> {
+            Ok(Some(cached_batch(batch_id)))
+        }
+
+        async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
+            if self.clear_fails {
+                return Err(ClientError::Generation("clear failed".to_string()));
+            }
+            self.cached
+                .retain(|id| batch_id.is_some_and(|batch_id| *id != batch_id));
+            Ok(())
+        }
+
+        async fn cache_stats(&mut self) -> Result<CacheStats> {
+            Ok(CacheStats {
+                batch_ids: self.cached.clone(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn batch(id: u64) -> Batch {
+        Batch {
+            id,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let mut client = TrackedClient::new(Shards::default());
+        client.prefill(batch(0), None).await.unwrap();
+        assert_eq!(client.state(0), Some(BatchState::Active));
+        client.filter_batch(0, vec![0]).await.unwrap();
+        assert_eq!(client.state(0), Some(BatchState::Active));
+
+        // The running batch is concatenated into the new one
+        client
+            .prefill(batch(1), Some(cached_batch(0)))
+            .await
+            .unwrap();
+        assert_eq!(client.state(0), Some(BatchState::Finished));
+        assert_eq!(client.state(1), Some(BatchState::Active));
+
+        // Clearing twice is a no-op
+        client.clear_cache(Some(1)).await.unwrap();
+        client.clear_cache(Some(1)).await.unwrap();
+        assert_eq!(client.state(1), None);
+        let cache_stats = client.cache_stats().await.unwrap();
+        assert!(client.reconcile(&cache_stats.batch_ids).is_empty());
+        // The shards dropped the concatenated batch
+        assert_eq!(client.state(0), None);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        // Batch 7 was left by a previous router process
+        let mut client = TrackedClient::new(Shards {
+            cached: vec![7],
+            ..Default::default()
+        });
+        client.prefill(batch(0), None).await.unwrap();
+        client.prefill(batch(1), None).await.unwrap();
+
+        // The clear of batch 1 fails, and batch 2 is abandoned during its prefill
+        client.client.clear_fails = true;
+        assert!(client.clear_cache(Some(1)).await.is_err());
+        assert_eq!(client.state(1), Some(BatchState::Finished));
+        client.client.cached.push(2);
+        client.states.insert(2, BatchState::Created);
+
+        let cache_stats = client.cache_stats().await.unwrap();
+        assert_eq!(client.reconcile(&cache_stats.batch_ids), [7, 1, 2]);
+        assert_eq!(client.state(0), Some(BatchState::Active));
+    }
+}
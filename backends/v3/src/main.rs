@@ -1,6 +1,15 @@
 use clap::{Parser, Subcommand};
-use text_generation_router::{server, usage_stats};
-use text_generation_router_v3::{connect_backend, V3Error};
+use std::path::PathBuf;
+use std::time::Duration;
+use text_generation_router::infer::experiment::{ExperimentBackend, ExperimentMode};
+use text_generation_router::infer::failover::FailoverBackend;
+use text_generation_router::infer::Backend;
+use text_generation_router::logging::TraceDetail;
+use text_generation_router::{replay, server, usage_stats};
+use text_generation_router_openai_proxy::ProxyError;
+use text_generation_router_v3::{
+    connect_backend, PriorityAging, SchedulingPolicy, SloAction, TtftSlo, V3Error,
+};
 use thiserror::Error;
 
 /// App Configuration
@@ -32,6 +41,26 @@ struct Args {
     max_waiting_tokens: usize,
     #[clap(long, env)]
     max_batch_size: Option<usize>,
+    #[clap(default_value = "fifo", long, env, value_enum)]
+    scheduling_policy: SchedulingPolicy,
+    /// Prompt tokens taken off the queue position of a waiting request per second, with
+    /// the `shortest-prefill-first` policy
+    #[clap(long, env)]
+    priority_aging_rate: Option<f32>,
+    #[clap(default_value = "4096", long, env)]
+    priority_max_boost: u32,
+    #[clap(long, env)]
+    eager_admission: bool,
+    /// Objective on the estimated time to first token of the new requests, in milliseconds
+    #[clap(long, env)]
+    ttft_slo_ms: Option<u64>,
+    /// What happens to the requests whose estimated time to first token is over
+    /// `ttft_slo_ms`
+    #[clap(default_value = "reject", long, env, value_enum)]
+    ttft_slo_action: SloAction,
+    /// Fail the batches whose forward took longer than this, 0 disables the timeout
+    #[clap(default_value = "120", long, env)]
+    batch_timeout_secs: u64,
     #[clap(default_value = "0.0.0.0", long, env)]
     hostname: String,
     #[clap(default_value = "3000", long, short, env)]
@@ -42,10 +71,19 @@ struct Args {
     tokenizer_name: String,
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
+    /// JSON file with operator settings such as parameter presets and prompt templates
+    #[clap(long, env)]
+    router_config_path: Option<String>,
     #[clap(long, env)]
     revision: Option<String>,
     #[clap(long, env, value_enum)]
     trust_remote_code: bool,
+    /// Quantization of the model, only reported in `/info`
+    #[clap(long, env)]
+    quantize: Option<String>,
+    /// Adapters loaded by the shards, only reported in `/info`
+    #[clap(long, env, value_delimiter = ',')]
+    lora_adapters: Vec<String>,
     #[clap(default_value = "2", long, env)]
     validation_workers: usize,
     #[clap(long, env)]
@@ -56,6 +94,11 @@ struct Args {
     otlp_endpoint: Option<String>,
     #[clap(default_value = "text-generation-inference.router", long, env)]
     otlp_service_name: String,
+    /// Share of the traces exported to the Open Telemetry collector, between 0 and 1
+    #[clap(default_value = "1.0", long, env)]
+    otlp_sampling_ratio: f64,
+    #[clap(default_value = "token", long, env, value_enum)]
+    trace_detail: TraceDetail,
     #[clap(long, env)]
     cors_allow_origin: Option<Vec<String>>,
     #[clap(long, env)]
@@ -70,11 +113,48 @@ struct Args {
     max_client_batch_size: usize,
     #[clap(default_value = "on", long, env)]
     usage_stats: usage_stats::UsageStatsLevel,
+    /// OpenAI-compatible server used when the shards are unhealthy or overloaded
+    #[clap(long, env, requires = "fallback_model")]
+    fallback_url: Option<String>,
+    #[clap(long, env)]
+    fallback_model: Option<String>,
+    #[clap(long, env)]
+    fallback_api_key: Option<String>,
+    /// Send new requests to the fallback once a request waited that long for its first token
+    #[clap(default_value = "5000", long, env)]
+    fallback_max_queue_time_ms: u64,
+    /// OpenAI-compatible server receiving a share of the traffic for shadow or A/B testing
+    #[clap(long, env, requires = "experiment_model")]
+    experiment_url: Option<String>,
+    #[clap(long, env)]
+    experiment_model: Option<String>,
+    #[clap(long, env)]
+    experiment_api_key: Option<String>,
+    #[clap(default_value = "candidate", long, env)]
+    experiment_name: String,
+    #[clap(default_value = "shadow", long, env, value_enum)]
+    experiment_mode: ExperimentMode,
+    /// Percentage of the requests sent to the experiment server
+    #[clap(default_value = "10", long, env)]
+    experiment_percentage: f64,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     PrintSchema,
+    /// Re-send the requests of a request log against a running server
+    Replay {
+        /// JSON lines file with the `timestamp`, `path` and `body` of a request per line
+        log_path: PathBuf,
+        #[clap(default_value = "http://localhost:3000", long)]
+        url: String,
+        /// Pacing of the requests relative to the log, 2 sends them twice as fast and 0 all at
+        /// once
+        #[clap(default_value = "1.0", long)]
+        speed: f64,
+        #[clap(long)]
+        api_key: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -95,18 +175,30 @@ async fn main() -> Result<(), RouterError> {
         max_batch_total_tokens,
         max_waiting_tokens,
         max_batch_size,
+        scheduling_policy,
+        priority_aging_rate,
+        priority_max_boost,
+        eager_admission,
+        ttft_slo_ms,
+        ttft_slo_action,
+        batch_timeout_secs,
         hostname,
         port,
         master_shard_uds_path,
         tokenizer_name,
         tokenizer_config_path,
+        router_config_path,
         revision,
         trust_remote_code,
+        quantize,
+        lora_adapters,
         validation_workers,
         api_key,
         json_output,
         otlp_endpoint,
         otlp_service_name,
+        otlp_sampling_ratio,
+        trace_detail,
         cors_allow_origin,
         ngrok,
         ngrok_authtoken,
@@ -114,23 +206,66 @@ async fn main() -> Result<(), RouterError> {
         disable_grammar_support,
         max_client_batch_size,
         usage_stats,
+        fallback_url,
+        fallback_model,
+        fallback_api_key,
+        fallback_max_queue_time_ms,
+        experiment_url,
+        experiment_model,
+        experiment_api_key,
+        experiment_name,
+        experiment_mode,
+        experiment_percentage,
     } = args;
 
-    if let Some(Commands::PrintSchema) = command {
-        use utoipa::OpenApi;
-        let api_doc = text_generation_router::server::ApiDoc::openapi();
-        let api_doc = serde_json::to_string_pretty(&api_doc).unwrap();
-        println!("{}", api_doc);
-        std::process::exit(0);
+    match command {
+        Some(Commands::PrintSchema) => {
+            use utoipa::OpenApi;
+            let api_doc = text_generation_router::server::ApiDoc::openapi();
+            let api_doc = serde_json::to_string_pretty(&api_doc).unwrap();
+            println!("{}", api_doc);
+            std::process::exit(0);
+        }
+        Some(Commands::Replay {
+            log_path,
+            url,
+            speed,
+            api_key,
+        }) => {
+            if !(speed.is_finite() && speed >= 0.0) {
+                return Err(RouterError::ArgumentValidation(
+                    "`speed` must be >= 0".to_string(),
+                ));
+            }
+            let summary = replay::replay(&log_path, &url, speed, api_key).await?;
+            println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+            std::process::exit(0);
+        }
+        None => {}
     };
-    text_generation_router::logging::init_logging(otlp_endpoint, otlp_service_name, json_output);
+    text_generation_router::logging::init_logging(
+        otlp_endpoint,
+        otlp_service_name,
+        otlp_sampling_ratio,
+        json_output,
+    );
 
     // Validate args
+    if !(0.0..=1.0).contains(&otlp_sampling_ratio) {
+        return Err(RouterError::ArgumentValidation(
+            "`otlp_sampling_ratio` must be between 0 and 1".to_string(),
+        ));
+    }
     if validation_workers == 0 {
         return Err(RouterError::ArgumentValidation(
             "`validation_workers` must be > 0".to_string(),
         ));
     }
+    if !(0.0..=100.0).contains(&experiment_percentage) {
+        return Err(RouterError::ArgumentValidation(
+            "`experiment_percentage` must be between 0 and 100".to_string(),
+        ));
+    }
     if let Some(max_batch_size) = max_batch_size {
         if max_batch_size == 0 {
             return Err(RouterError::ArgumentValidation(
@@ -138,6 +273,26 @@ async fn main() -> Result<(), RouterError> {
             ));
         }
     }
+    if let Some(rate) = priority_aging_rate {
+        if !(rate.is_finite() && rate > 0.0) {
+            return Err(RouterError::ArgumentValidation(
+                "`priority_aging_rate` must be > 0".to_string(),
+            ));
+        }
+    }
+    let priority_aging = priority_aging_rate.map(|rate| PriorityAging {
+        rate,
+        max_boost: priority_max_boost,
+    });
+    if ttft_slo_ms == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`ttft_slo_ms` must be > 0".to_string(),
+        ));
+    }
+    let ttft_slo = ttft_slo_ms.map(|slo_ms| TtftSlo {
+        slo_ms,
+        action: ttft_slo_action,
+    });
 
     let (backend, backend_info) = connect_backend(
         max_input_tokens,
@@ -148,6 +303,14 @@ async fn main() -> Result<(), RouterError> {
         max_batch_total_tokens,
         max_waiting_tokens,
         max_batch_size,
+        scheduling_policy,
+        priority_aging,
+        eager_admission,
+        ttft_slo,
+        (batch_timeout_secs > 0).then(|| Duration::from_secs(batch_timeout_secs)),
+        trace_detail,
+        quantize,
+        lora_adapters,
     )
     .await?;
 
@@ -186,6 +349,44 @@ async fn main() -> Result<(), RouterError> {
         return Err(RouterError::ArgumentValidation(format!("`max_total_tokens` must be <= `max_batch_total_tokens`. Given: {max_total_tokens} and {max_batch_total_tokens}")));
     }
 
+    let backend: Box<dyn Backend + Send + Sync> = match (fallback_url, fallback_model) {
+        (Some(fallback_url), Some(fallback_model)) => {
+            let fallback = text_generation_router_openai_proxy::connect_backend(
+                fallback_url,
+                fallback_model,
+                fallback_api_key,
+            )
+            .await
+            .map_err(RouterError::Fallback)?;
+            Box::new(FailoverBackend::new(
+                backend,
+                fallback,
+                Duration::from_millis(fallback_max_queue_time_ms),
+            ))
+        }
+        _ => Box::new(backend),
+    };
+
+    let backend: Box<dyn Backend + Send + Sync> = match (experiment_url, experiment_model) {
+        (Some(experiment_url), Some(experiment_model)) => {
+            let candidate = text_generation_router_openai_proxy::connect_backend(
+                experiment_url,
+                experiment_model,
+                experiment_api_key,
+            )
+            .await
+            .map_err(RouterError::Experiment)?;
+            Box::new(ExperimentBackend::new(
+                experiment_name,
+                backend,
+                candidate,
+                experiment_mode,
+                experiment_percentage / 100.0,
+            ))
+        }
+        _ => backend,
+    };
+
     // Run server
     server::run(
         backend,
@@ -199,6 +400,7 @@ async fn main() -> Result<(), RouterError> {
         api_key,
         tokenizer_name,
         tokenizer_config_path,
+        router_config_path,
         revision,
         trust_remote_code,
         hostname,
@@ -221,8 +423,14 @@ enum RouterError {
     ArgumentValidation(String),
     #[error("Backend failed: {0}")]
     Backend(#[from] V3Error),
+    #[error("Fallback backend failed: {0}")]
+    Fallback(ProxyError),
+    #[error("Experiment backend failed: {0}")]
+    Experiment(ProxyError),
     #[error("WebServer error: {0}")]
     WebServer(#[from] server::WebServerError),
+    #[error("Replay failed: {0}")]
+    Replay(#[from] replay::ReplayError),
     #[error("Tokio runtime failed to start: {0}")]
     Tokio(#[from] std::io::Error),
 }
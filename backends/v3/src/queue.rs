@@ -1,20 +1,98 @@
 use crate::block_allocator::{BlockAllocation, BlockAllocator};
 use crate::client;
 use crate::client::{
-    Batch, GrammarType, NextTokenChooserParameters, Request, StoppingCriteriaParameters,
+    Batch, CacheStats, GrammarType, NextTokenChooserParameters, Request, StoppingCriteriaParameters,
 };
+use crate::grammar::GrammarState;
+use crate::response::ResponseSender;
+use crate::slo::TtftEstimator;
+use clap::ValueEnum;
 use nohash_hasher::{BuildNoHashHasher, IntMap};
+use serde::Serialize;
 use std::cmp::max;
 use std::collections::VecDeque;
-use text_generation_router::infer::InferError;
+use std::sync::Arc;
+use std::time::Duration;
+use text_generation_router::infer::utf8::Utf8Decoder;
 use text_generation_router::infer::InferStreamResponse;
+use text_generation_router::scheduler_events::{self, SchedulerEvent, SkipReason};
 use text_generation_router::validation::{
     Chunk, ChunksToString, ValidGenerateRequest, ValidGrammar, ValidParameters,
     ValidStoppingParameters,
 };
 use tokio::sync::{mpsc, oneshot};
-use tokio::time::Instant;
+use tokio::time::{Instant, MissedTickBehavior};
 use tracing::{info_span, instrument, Instrument, Span};
+use utoipa::ToSchema;
+
+/// Number of requests that can be skipped per batch with [`SchedulingPolicy::LongestWaitFirst`],
+/// which bounds the time spent looking for requests that fit the budget
+const MAX_SKIPPED_ENTRIES: usize = 64;
+
+/// Interval between the positions sent to the waiting entries
+const QUEUE_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Order in which the queued requests are added to the batches
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, ToSchema, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedulingPolicy {
+    /// Arrival order. A request that does not fit the budget blocks the requests behind it
+    #[default]
+    Fifo,
+    /// Shortest prompts first, so that long prompts do not delay the short ones. A long prompt
+    /// can wait for as long as shorter requests keep arriving
+    ShortestPrefillFirst,
+    /// Arrival order, but requests that do not fit the budget are skipped so that the next
+    /// ones can be batched. Skipped requests keep their place in the queue
+    LongestWaitFirst,
+}
+
+impl SchedulingPolicy {
+    /// Position of an entry in the queue at `now`, the queue is sorted by this key
+    ///
+    /// The downgraded entries are behind all the others, in the order of the policy.
+    fn key(
+        &self,
+        id: u64,
+        entry: &Entry,
+        aging: Option<PriorityAging>,
+        now: Instant,
+    ) -> (bool, u32, u64) {
+        match self {
+            SchedulingPolicy::ShortestPrefillFirst => {
+                let boost = aging.map_or(0, |aging| {
+                    aging.boost(now.saturating_duration_since(entry.queue_time))
+                });
+                (
+                    entry.downgraded,
+                    entry.request.input_length.saturating_sub(boost),
+                    id,
+                )
+            }
+            SchedulingPolicy::Fifo | SchedulingPolicy::LongestWaitFirst => {
+                (entry.downgraded, 0, id)
+            }
+        }
+    }
+}
+
+/// Raise the priority of the requests with their waiting time, so that the long prompts are
+/// not starved by [`SchedulingPolicy::ShortestPrefillFirst`]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct PriorityAging {
+    /// Prompt tokens taken off the sort key of a request per second of waiting
+    #[schema(example = "100")]
+    pub rate: f32,
+    /// Maximum number of prompt tokens taken off the sort key
+    #[schema(example = "4096")]
+    pub max_boost: u32,
+}
+
+impl PriorityAging {
+    fn boost(&self, waited: Duration) -> u32 {
+        (waited.as_secs_f32() * self.rate).min(self.max_boost as f32) as u32
+    }
+}
 
 /// Queue entry
 #[derive(Debug)]
@@ -22,7 +100,7 @@ pub(crate) struct Entry {
     /// Request
     pub request: ValidGenerateRequest,
     /// Response sender to communicate between the Infer struct and the batching_task
-    pub response_tx: mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
+    pub response_tx: ResponseSender,
     /// Span that will live as long as entry
     pub span: Span,
     /// Temporary span used as a guard when logging inference, wait times...
@@ -31,8 +109,28 @@ pub(crate) struct Entry {
     pub queue_time: Instant,
     /// Instant when this entry was added to a batch
     pub batch_time: Option<Instant>,
+    /// Characters split across the streamed token texts of the shards, which are the response
+    /// texts when the router does not detokenize the request
+    pub decoder: Utf8Decoder,
+    /// Forward passes that generated tokens for this entry
+    pub steps: u32,
     /// Block Allocation
     pub block_allocation: Option<BlockAllocation>,
+    /// Scheduler state when this entry was queued, set by the queue
+    pub queued_at: QueuedAt,
+    /// Compilation of the grammar of a constrained request
+    pub grammar: Option<Arc<GrammarState>>,
+    /// Queued behind the requests within the time to first token objective
+    pub downgraded: bool,
+}
+
+/// Scheduler state when an entry was queued, to measure how long it was held back by the others
+#[derive(Debug, Default)]
+pub(crate) struct QueuedAt {
+    /// Id of the next batch
+    batch_id: u64,
+    /// Time the batches had been blocked by entries over their budget
+    blocked: Duration,
 }
 
 /// Request Queue
@@ -40,9 +138,12 @@ pub(crate) struct Entry {
 pub(crate) struct Queue {
     /// Channel to communicate with the background queue task
     queue_sender: mpsc::UnboundedSender<QueueCommand>,
+    /// Time to first token of the new entries
+    ttft: Arc<TtftEstimator>,
 }
 
 impl Queue {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         requires_padding: bool,
         block_size: u32,
@@ -51,9 +152,12 @@ impl Queue {
         speculate: u32,
         max_batch_total_tokens: u32,
         support_chunking: bool,
+        scheduling_policy: SchedulingPolicy,
+        priority_aging: Option<PriorityAging>,
     ) -> Self {
         // Create channel
         let (queue_sender, queue_receiver) = mpsc::unbounded_channel();
+        let ttft = Arc::new(TtftEstimator::default());
 
         // Launch background queue task
         tokio::spawn(queue_task(
@@ -64,15 +168,25 @@ impl Queue {
             speculate,
             max_batch_total_tokens,
             support_chunking,
+            scheduling_policy,
+            priority_aging,
+            ttft.clone(),
             queue_receiver,
         ));
 
-        Self { queue_sender }
+        Self { queue_sender, ttft }
+    }
+
+    pub(crate) fn ttft(&self) -> &TtftEstimator {
+        &self.ttft
     }
 
     /// Append an entry to the queue
     #[instrument(skip_all)]
     pub(crate) fn append(&self, entry: Entry) {
+        if !entry.downgraded {
+            self.ttft.queue(entry.request.input_length as u64);
+        }
         // Send append command to the background task managing the state
         // Unwrap is safe here
         self.queue_sender
@@ -111,6 +225,20 @@ impl Queue {
         // Unwrap is safe here
         response_receiver.await.unwrap()
     }
+
+    /// Occupancy of the KV cache reported by the shards, none once the running batches finished
+    pub(crate) fn set_cache_stats(&self, cache_stats: Option<CacheStats>) {
+        self.queue_sender
+            .send(QueueCommand::CacheStats(cache_stats))
+            .unwrap();
+    }
+
+    /// Release the KV cache pinned by a request
+    pub(crate) fn unpin_prefix(&self, pin_prefix: u64) {
+        self.queue_sender
+            .send(QueueCommand::UnpinPrefix(pin_prefix))
+            .unwrap();
+    }
 }
 
 // Background task responsible of the queue state
@@ -123,6 +251,9 @@ async fn queue_task(
     speculate: u32,
     max_batch_total_tokens: u32,
     support_chunking: bool,
+    scheduling_policy: SchedulingPolicy,
+    priority_aging: Option<PriorityAging>,
+    ttft: Arc<TtftEstimator>,
     mut receiver: mpsc::UnboundedReceiver<QueueCommand>,
 ) {
     let mut state = State::new(
@@ -133,9 +264,26 @@ async fn queue_task(
         speculate,
         max_batch_total_tokens,
         support_chunking,
-    );
-
-    while let Some(cmd) = receiver.recv().await {
+        scheduling_policy,
+    )
+    .with_priority_aging(priority_aging);
+    // Budget of the last batch, the estimates of the waiting entries assume the next ones
+    // have the same
+    let mut last_prefill_token_budget = None;
+    let mut queue_updates = tokio::time::interval(QUEUE_UPDATE_INTERVAL);
+    queue_updates.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let cmd = tokio::select! {
+            cmd = receiver.recv() => match cmd {
+                Some(cmd) => cmd,
+                None => break,
+            },
+            _ = queue_updates.tick() => {
+                state.send_positions(&ttft, last_prefill_token_budget);
+                continue;
+            }
+        };
         match cmd {
             QueueCommand::Append(entry, span) => {
                 span.in_scope(|| state.append(*entry));
@@ -149,13 +297,23 @@ async fn queue_task(
                 response_sender,
                 span,
             } => {
+                last_prefill_token_budget = Some(prefill_token_budget);
+                let queued_tokens = state.queued_tokens();
                 let next_batch = state
                     .next_batch(min_size, max_size, prefill_token_budget, token_budget)
                     .instrument(span)
                     .await;
+                // Batched and dropped entries do not delay the new ones anymore
+                ttft.dequeue(queued_tokens - state.queued_tokens());
                 response_sender.send(next_batch).unwrap();
                 metrics::gauge!("tgi_queue_size").set(state.entries.len() as f64);
             }
+            QueueCommand::CacheStats(cache_stats) => state.cache_stats = cache_stats,
+            QueueCommand::UnpinPrefix(pin_prefix) => {
+                if let Some(block_allocator) = &state.block_allocator {
+                    block_allocator.unpin(pin_prefix);
+                }
+            }
         }
     }
 }
@@ -183,11 +341,28 @@ struct State {
     /// token budget
     support_chunking: bool,
 
+    /// Order of the queue entries
+    scheduling_policy: SchedulingPolicy,
+
+    /// Raise the priority of the waiting entries, the order then changes over time
+    priority_aging: Option<PriorityAging>,
+
+    /// Time the batches were blocked by an entry over their budget, the entries behind it
+    /// waiting
+    blocked: Duration,
+
+    /// Start of the current blocking, if the last batch stopped at an entry over its budget
+    blocked_since: Option<Instant>,
+
     /// Paged Attention Block Allocation
     block_allocator: Option<BlockAllocator>,
+
+    /// Occupancy of the KV cache by the running batches, reported by the shards
+    cache_stats: Option<CacheStats>,
 }
 
 impl State {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         requires_padding: bool,
         block_size: u32,
@@ -196,6 +371,7 @@ impl State {
         speculate: u32,
         max_batch_total_tokens: u32,
         support_chunking: bool,
+        scheduling_policy: SchedulingPolicy,
     ) -> Self {
         let block_allocator = (!requires_padding).then(|| {
             BlockAllocator::new(
@@ -213,19 +389,91 @@ impl State {
             block_size,
             speculate,
             support_chunking,
+            scheduling_policy,
+            priority_aging: None,
+            blocked: Duration::ZERO,
+            blocked_since: None,
             block_allocator,
+            cache_stats: None,
         }
     }
 
+    fn with_priority_aging(mut self, priority_aging: Option<PriorityAging>) -> Self {
+        self.priority_aging = priority_aging;
+        self
+    }
+
     /// Append an entry to the queue
     fn append(&mut self, mut entry: Entry) {
         // Create a span that will live as long as the entry is in the queue waiting to be batched
         let queue_span = info_span!(parent: &entry.span, "queued");
         entry.temp_span = Some(queue_span);
+        entry.queued_at = QueuedAt {
+            batch_id: self.next_batch_id,
+            blocked: self.blocked_at(Instant::now()),
+        };
 
         // Push entry in the queue
-        self.entries.push_back((self.next_id, entry));
+        let position = self.insert(self.next_id, entry);
         self.next_id += 1;
+        metrics::histogram!("tgi_queue_position").record(position as f64);
+    }
+
+    /// Insert an entry at its position in the queue, and return this position
+    fn insert(&mut self, id: u64, entry: Entry) -> usize {
+        let (policy, aging, now) = (self.scheduling_policy, self.priority_aging, Instant::now());
+        let key = policy.key(id, &entry, aging, now);
+        let position = self
+            .entries
+            .partition_point(|(id, entry)| policy.key(*id, entry, aging, now) < key);
+        self.entries.insert(position, (id, entry));
+        position
+    }
+
+    /// Prompt tokens of the entries that are not downgraded
+    fn queued_tokens(&self) -> u64 {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.downgraded)
+            .map(|(_, entry)| entry.request.input_length as u64)
+            .sum()
+    }
+
+    /// Send their position and their estimated time to first token to the waiting entries
+    ///
+    /// The estimate of an entry counts the prompt tokens of the entries ahead of it, none before
+    /// the first batch.
+    fn send_positions(&self, ttft: &TtftEstimator, prefill_token_budget: Option<u32>) {
+        let mut tokens = 0;
+        for (index, (_, entry)) in self.entries.iter().enumerate() {
+            tokens += entry.request.input_length as u64;
+            let eta = prefill_token_budget.and_then(|prefill_token_budget| {
+                ttft.estimate_tokens(tokens, prefill_token_budget)
+            });
+            // Dropped entries are filtered out by the next batch
+            let _ = entry.response_tx.send(Ok(InferStreamResponse::Queued {
+                position: index + 1,
+                eta,
+            }));
+        }
+    }
+
+    /// Time the batches were blocked by an entry over their budget until `now`
+    fn blocked_at(&self, now: Instant) -> Duration {
+        self.blocked
+            + self
+                .blocked_since
+                .map_or(Duration::ZERO, |since| now.saturating_duration_since(since))
+    }
+
+    /// Sort the entries by their priority at `now`
+    fn age(&mut self, now: Instant) {
+        let (policy, aging) = (self.scheduling_policy, self.priority_aging);
+        if aging.is_some() && policy == SchedulingPolicy::ShortestPrefillFirst {
+            self.entries
+                .make_contiguous()
+                .sort_by_cached_key(|(id, entry)| policy.key(*id, entry, aging, now));
+        }
     }
 
     // Get the next batch
@@ -236,6 +484,12 @@ impl State {
         prefill_token_budget: u32,
         token_budget: u32,
     ) -> Option<NextBatch> {
+        // The previous batch tells if the entries were blocked until now, this one if they
+        // still are
+        let now = Instant::now();
+        self.blocked = self.blocked_at(now);
+        self.blocked_since = None;
+
         if self.entries.is_empty() {
             tracing::debug!("No queue");
             return None;
@@ -256,6 +510,8 @@ impl State {
             }
         }
 
+        self.age(now);
+
         // Pad prefill_token_budget to be a multiple of block size
         let prefill_token_budget =
             ((prefill_token_budget + self.block_size - 1) / self.block_size) * self.block_size;
@@ -264,11 +520,27 @@ impl State {
         let next_batch_span = info_span!(parent: None, "batch", batch_size = tracing::field::Empty);
         next_batch_span.follows_from(Span::current());
 
-        let mut batch = Vec::with_capacity(self.entries.len());
+        // Every entry prefills at least one token, so the batch size is bounded by the budget
+        // and not by the length of the queue
+        let capacity = self
+            .entries
+            .len()
+            .min(max_size.unwrap_or(usize::MAX))
+            .min(prefill_token_budget as usize);
+        let mut batch = Vec::with_capacity(capacity);
+        let mut skipped = Vec::new();
+        let skip_over_budget = self.scheduling_policy == SchedulingPolicy::LongestWaitFirst;
         let mut max_input_length = 0;
         let mut prefill_tokens: u32 = 0;
         let mut decode_tokens: u32 = 0;
         let mut max_blocks = 0;
+        // Blocks the shards hold free. The allocator projects the blocks of the running requests
+        // from their token counts, the shards may be fuller
+        let mut free_blocks = self
+            .cache_stats
+            .as_ref()
+            .filter(|cache_stats| cache_stats.total_blocks > 0)
+            .map(|cache_stats| cache_stats.free_blocks());
 
         // Pop entries starting from the front of the queue
         'entry_loop: while let Some((id, entry)) = self.entries.pop_front() {
@@ -280,23 +552,64 @@ impl State {
                 continue;
             }
 
+            if entry
+                .grammar
+                .as_ref()
+                .is_some_and(|grammar| !grammar.is_compiled())
+            {
+                // The next entries are batched while the grammar compiles
+                tracing::debug!("Grammar not compiled");
+                let skipped_over = skipped.len() < MAX_SKIPPED_ENTRIES;
+                scheduler_events::emit(SchedulerEvent::Skipped {
+                    id,
+                    reason: SkipReason::GrammarCompiling,
+                    skipped_over,
+                });
+                if skipped_over {
+                    skipped.push((id, entry));
+                    continue 'entry_loop;
+                }
+                self.entries.push_front((id, entry));
+                break 'entry_loop;
+            }
+
             let block_allocation = match &self.block_allocator {
                 None => {
                     // We pad to max input length in the Python shards
                     // We need to take these padding tokens into the equation
-                    max_input_length = max_input_length.max(entry.request.input_length);
-                    prefill_tokens = (batch.len() + 1) as u32 * max_input_length;
+                    let padded_length = max_input_length.max(entry.request.input_length);
+                    let padded_prefill_tokens = (batch.len() + 1) as u32 * padded_length;
 
-                    decode_tokens += entry.request.stopping_parameters.max_new_tokens;
-                    let total_tokens = prefill_tokens + decode_tokens + self.speculate;
+                    let entry_decode_tokens =
+                        decode_tokens + entry.request.stopping_parameters.max_new_tokens;
+                    let total_tokens = padded_prefill_tokens + entry_decode_tokens + self.speculate;
 
-                    if prefill_tokens > prefill_token_budget || total_tokens > token_budget {
+                    if padded_prefill_tokens > prefill_token_budget || total_tokens > token_budget {
                         // Entry is over budget
-                        // Add it back to the front
-                        tracing::debug!("Over budget: prefill_tokens={prefill_tokens} > {prefill_token_budget} || {prefill_tokens} + {decode_tokens} + {} > {token_budget}", self.speculate);
+                        tracing::debug!("Over budget: prefill_tokens={padded_prefill_tokens} > {prefill_token_budget} || {padded_prefill_tokens} + {entry_decode_tokens} + {} > {token_budget}", self.speculate);
+                        let reason = if padded_prefill_tokens > prefill_token_budget {
+                            SkipReason::PrefillBudget
+                        } else {
+                            SkipReason::TokenBudget
+                        };
+                        let skipped_over = skip_over_budget && skipped.len() < MAX_SKIPPED_ENTRIES;
+                        scheduler_events::emit(SchedulerEvent::Skipped {
+                            id,
+                            reason,
+                            skipped_over,
+                        });
+                        if skipped_over {
+                            skipped.push((id, entry));
+                            continue 'entry_loop;
+                        }
+                        // Add it back to the front, the entries behind it wait for it
+                        self.blocked_since = Some(now);
                         self.entries.push_front((id, entry));
                         break 'entry_loop;
                     }
+                    max_input_length = padded_length;
+                    prefill_tokens = padded_prefill_tokens;
+                    decode_tokens = entry_decode_tokens;
                     None
                 }
                 Some(block_allocator) => {
@@ -314,11 +627,28 @@ impl State {
                         - 1;
                     tracing::debug!("Allocating {tokens} with {input_ids:?}");
 
-                    let block_allocation = match block_allocator.allocate(tokens, input_ids).await {
+                    let block_allocation = match block_allocator
+                        .allocate(tokens, input_ids, entry.request.pin_prefix)
+                        .await
+                        .filter(|allocation| {
+                            take_free_blocks(&mut free_blocks, allocation, self.block_size)
+                        }) {
                         None => {
                             // Entry is over budget
-                            // Add it back to the front
                             tracing::debug!("Over budget: not enough free blocks");
+                            let skipped_over =
+                                skip_over_budget && skipped.len() < MAX_SKIPPED_ENTRIES;
+                            scheduler_events::emit(SchedulerEvent::Skipped {
+                                id,
+                                reason: SkipReason::KvCacheBlocks,
+                                skipped_over,
+                            });
+                            if skipped_over {
+                                skipped.push((id, entry));
+                                continue 'entry_loop;
+                            }
+                            // Add it back to the front, the entries behind it wait for it
+                            self.blocked_since = Some(now);
                             self.entries.push_front((id, entry));
                             break 'entry_loop;
                         }
@@ -350,6 +680,11 @@ impl State {
                             } else {
                                 // We cannot prefill even one token for this entry
                                 // Add it back to the queue
+                                scheduler_events::emit(SchedulerEvent::Skipped {
+                                    id,
+                                    reason: SkipReason::PrefillBudget,
+                                    skipped_over: false,
+                                });
                                 self.entries.push_front((id, entry));
                             }
                             tracing::debug!(
@@ -359,11 +694,23 @@ impl State {
                             break 'entry_loop;
                         } else {
                             // We don't support chunking, this entry needs to go back to the buffer
-                            // Add it back to the front
                             tracing::debug!(
                                 "Over budget: prefill_tokens={} > {prefill_token_budget}",
                                 prefill_tokens + postfix_len
                             );
+                            let skipped_over =
+                                skip_over_budget && skipped.len() < MAX_SKIPPED_ENTRIES;
+                            scheduler_events::emit(SchedulerEvent::Skipped {
+                                id,
+                                reason: SkipReason::PrefillBudget,
+                                skipped_over,
+                            });
+                            if skipped_over {
+                                skipped.push((id, entry));
+                                continue 'entry_loop;
+                            }
+                            // Add it back to the front, the entries behind it wait for it
+                            self.blocked_since = Some(now);
                             self.entries.push_front((id, entry));
                             break 'entry_loop;
                         }
@@ -376,10 +723,22 @@ impl State {
             };
             batch.push((id, entry, block_allocation, None));
             if Some(batch.len()) == max_size {
+                if let Some((id, _)) = self.entries.front() {
+                    scheduler_events::emit(SchedulerEvent::Skipped {
+                        id: *id,
+                        reason: SkipReason::BatchSize,
+                        skipped_over: false,
+                    });
+                }
                 break;
             }
         }
 
+        // Skipped entries were in front of the remaining ones
+        for entry in skipped.into_iter().rev() {
+            self.entries.push_front(entry);
+        }
+
         // Empty batch
         if batch.is_empty() {
             tracing::debug!("Filterered out all entries");
@@ -392,16 +751,21 @@ impl State {
             // Batch is too small
             if batch.len() < min_size {
                 // Add back entries to the queue in the correct order
-                for (id, entry, _, _) in batch.into_iter().rev() {
-                    self.entries.push_front((id, entry));
+                for (id, entry, _, _) in batch {
+                    scheduler_events::emit(SchedulerEvent::Skipped {
+                        id,
+                        reason: SkipReason::MinBatchSize,
+                        skipped_over: false,
+                    });
+                    self.insert(id, entry);
                 }
                 return None;
             }
         }
 
-        let mut batch_requests = Vec::with_capacity(self.entries.len());
+        let mut batch_requests = Vec::with_capacity(batch.len());
         let mut batch_entries =
-            IntMap::with_capacity_and_hasher(self.entries.len(), BuildNoHashHasher::default());
+            IntMap::with_capacity_and_hasher(batch.len(), BuildNoHashHasher::default());
 
         for (id, mut entry, block_allocation, chunk_len) in batch {
             // Create a new span to link the batch back to this entry
@@ -458,9 +822,20 @@ impl State {
                 cache_len: prefix_len,
                 adapter_id: entry.request.adapter_id.clone(),
                 chunk_len,
+                input_ids: match &entry.request.input_ids {
+                    Some(input_ids) if entry.request.pre_tokenized => input_ids.to_vec(),
+                    _ => Vec::new(),
+                },
             });
             // Set batch_time
             entry.batch_time = Some(Instant::now());
+            metrics::histogram!("tgi_queue_skipped_batches")
+                .record((self.next_batch_id - entry.queued_at.batch_id) as f64);
+            metrics::histogram!("tgi_queue_blocked_duration").record(
+                self.blocked_at(now)
+                    .saturating_sub(entry.queued_at.blocked)
+                    .as_secs_f64(),
+            );
             // Insert in batch_entries IntMap
             batch_entries.insert(id, entry);
         }
@@ -485,6 +860,26 @@ impl State {
     }
 }
 
+/// Take the new blocks of `allocation` from `free_blocks`, returns false if there are not enough
+///
+/// The blocks of the cached prefix are already used, they are not taken again.
+fn take_free_blocks(
+    free_blocks: &mut Option<u32>,
+    allocation: &BlockAllocation,
+    block_size: u32,
+) -> bool {
+    let Some(free_blocks) = free_blocks else {
+        return true;
+    };
+    let new_blocks =
+        (allocation.blocks.len() as u32).saturating_sub(allocation.prefix_len / block_size);
+    if new_blocks > *free_blocks {
+        return false;
+    }
+    *free_blocks -= new_blocks;
+    true
+}
+
 type NextBatch = (IntMap<u64, Entry>, Batch, Span);
 
 #[derive(Debug)]
@@ -498,6 +893,8 @@ enum QueueCommand {
         response_sender: oneshot::Sender<Option<NextBatch>>,
         span: Span,
     },
+    CacheStats(Option<CacheStats>),
+    UnpinPrefix(u64),
 }
 
 impl From<ValidParameters> for NextTokenChooserParameters {
@@ -523,6 +920,8 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             watermark: value.watermark,
             grammar,
             grammar_type: grammar_type.into(),
+            allowed_token_ids: value.allowed_token_ids,
+            banned_token_ids: value.banned_token_ids,
         }
     }
 }
@@ -537,25 +936,206 @@ impl From<ValidStoppingParameters> for StoppingCriteriaParameters {
     }
 }
 
+/// Admission scenarios decoded from arbitrary bytes, shared by the property test and the fuzz
+/// target of `State::next_batch`
+#[doc(hidden)]
+pub mod admission {
+    use super::*;
+    use crate::backend::bench;
+    use crate::response::{ResponseRouter, ResponseStream};
+    use std::collections::{HashMap, HashSet};
+
+    /// Bytes of a scenario, read as zeros once exhausted
+    struct Bytes<'a>(std::slice::Iter<'a, u8>);
+
+    impl Bytes<'_> {
+        fn next(&mut self) -> u8 {
+            self.0.next().copied().unwrap_or(0)
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.as_slice().is_empty()
+        }
+    }
+
+    /// Run the appends, cancellations, batches and batch completions encoded by `data`, and
+    /// panic when `State::next_batch`:
+    /// - returns a batch over its size, prefill or token budget
+    /// - loses, duplicates or batches again an entry
+    /// - allocates a KV cache block to two running entries
+    /// - keeps a cancelled entry once the queue is drained
+    ///
+    /// Must be called from a Tokio runtime, which runs the block allocator.
+    pub async fn check(data: &[u8]) {
+        let mut bytes = Bytes(data.iter());
+        let requires_padding = bytes.next() % 2 == 0;
+        let block_size = 1 << (bytes.next() % 5);
+        let speculate = (bytes.next() % 3) as u32;
+        let support_chunking = !requires_padding && bytes.next() % 2 == 0;
+        let scheduling_policy = match bytes.next() % 3 {
+            0 => SchedulingPolicy::Fifo,
+            1 => SchedulingPolicy::ShortestPrefillFirst,
+            _ => SchedulingPolicy::LongestWaitFirst,
+        };
+        // Every entry fits an empty batch on its own, so that the queue can be drained
+        let max_batch_total_tokens = 256 + 16 * bytes.next() as u32;
+        let mut state = State::new(
+            requires_padding,
+            block_size,
+            false,
+            None,
+            speculate,
+            max_batch_total_tokens,
+            support_chunking,
+            scheduling_policy,
+        );
+
+        let responses = ResponseRouter::default();
+        // Streams of the queued entries, dropped to cancel them
+        let mut streams: HashMap<u64, ResponseStream> = HashMap::new();
+        let mut cancelled = HashSet::new();
+        let mut batched = HashSet::new();
+        // Entries of the running batches, their blocks are freed when they finish
+        let mut running: VecDeque<IntMap<u64, Entry>> = VecDeque::new();
+
+        while !bytes.is_empty() {
+            match bytes.next() % 4 {
+                0 => {
+                    let (response_tx, stream) = responses.channel();
+                    let mut entry = bench::entry(0, response_tx);
+                    entry.request.input_length = 1 + (bytes.next() % 64) as u32;
+                    entry.request.stopping_parameters.max_new_tokens =
+                        1 + (bytes.next() % 64) as u32;
+                    entry.batch_time = None;
+                    streams.insert(state.next_id, stream);
+                    state.append(entry);
+                }
+                1 => {
+                    let mut ids: Vec<u64> = streams.keys().copied().collect();
+                    if !ids.is_empty() {
+                        ids.sort();
+                        let id = ids[bytes.next() as usize % ids.len()];
+                        streams.remove(&id);
+                        cancelled.insert(id);
+                    }
+                }
+                2 => {
+                    let min_size = (bytes.next() % 4 == 0).then(|| 1 + bytes.next() as usize % 4);
+                    let max_size = (bytes.next() % 4 == 0).then(|| bytes.next() as usize % 8);
+                    let prefill_token_budget = 1 + 2 * bytes.next() as u32;
+                    let token_budget = 1 + 4 * bytes.next() as u32;
+                    let queued: HashSet<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+
+                    let Some((entries, batch, _)) = state
+                        .next_batch(min_size, max_size, prefill_token_budget, token_budget)
+                        .await
+                    else {
+                        continue;
+                    };
+                    assert!(!entries.is_empty());
+                    assert_eq!(batch.size as usize, entries.len());
+                    assert_eq!(batch.requests.len(), entries.len());
+                    assert!(max_size.map_or(true, |max_size| entries.len() <= max_size));
+                    assert!(min_size.map_or(true, |min_size| entries.len() >= min_size));
+
+                    let input_lengths = batch
+                        .requests
+                        .iter()
+                        .map(|request| (request, entries[&request.id].request.input_length));
+                    let prefill_tokens: u32 = if requires_padding {
+                        entries.len() as u32 * input_lengths.map(|(_, len)| len).max().unwrap()
+                    } else {
+                        input_lengths
+                            .map(|(request, len)| request.chunk_len.unwrap_or(len))
+                            .sum()
+                    };
+                    assert!(
+                        prefill_tokens <= prefill_token_budget.div_ceil(block_size) * block_size
+                    );
+                    if requires_padding {
+                        assert!(batch.max_tokens + speculate <= token_budget);
+                    }
+
+                    for id in entries.keys() {
+                        assert!(queued.contains(id), "entry {id} was not queued");
+                        assert!(!cancelled.contains(id), "cancelled entry {id} was batched");
+                        assert!(batched.insert(*id), "entry {id} was batched twice");
+                        streams.remove(id);
+                    }
+                    running.push_back(entries);
+                    let mut blocks = HashSet::new();
+                    for entry in running.iter().flat_map(|entries| entries.values()) {
+                        for block in entry.block_allocation.iter().flat_map(|a| &a.blocks) {
+                            assert!(blocks.insert(*block), "block {block} allocated twice");
+                        }
+                    }
+                }
+                _ => {
+                    running.pop_front();
+                }
+            }
+            check_entries(&state, state.next_id, &cancelled, &batched);
+        }
+
+        // Finish the running batches and batch the whole queue
+        running.clear();
+        while let Some((entries, _, _)) = state
+            .next_batch(None, None, max_batch_total_tokens, max_batch_total_tokens)
+            .await
+        {
+            for id in entries.keys() {
+                assert!(!cancelled.contains(id), "cancelled entry {id} was batched");
+                assert!(batched.insert(*id), "entry {id} was batched twice");
+            }
+        }
+        assert!(
+            state.entries.is_empty(),
+            "entries left in the drained queue"
+        );
+        check_entries(&state, state.next_id, &cancelled, &batched);
+    }
+
+    /// Every appended entry is queued once, batched once, or cancelled
+    fn check_entries(
+        state: &State,
+        appended: u64,
+        cancelled: &HashSet<u64>,
+        batched: &HashSet<u64>,
+    ) {
+        let queued: HashSet<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+        assert_eq!(queued.len(), state.entries.len(), "entry queued twice");
+        for id in 0..appended {
+            assert!(!(queued.contains(&id) && batched.contains(&id)));
+            assert!(
+                queued.contains(&id) || batched.contains(&id) || cancelled.contains(&id),
+                "entry {id} was lost"
+            );
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
 
     use super::*;
+    use crate::response::{ResponseRouter, ResponseStream};
+    use futures::StreamExt;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
     use tracing::info_span;
 
-    fn default_entry() -> (
-        Entry,
-        mpsc::UnboundedReceiver<Result<InferStreamResponse, InferError>>,
-    ) {
-        let (response_tx, receiver_tx) = mpsc::unbounded_channel();
+    fn default_entry() -> (Entry, ResponseStream) {
+        let (response_tx, receiver_tx) = ResponseRouter::default().channel();
 
         let entry = Entry {
             request: ValidGenerateRequest {
                 inputs: vec![],
                 input_ids: Some(Arc::new(vec![])),
+                input_offsets: None,
                 input_length: 1,
                 add_special_tokens: true,
+                pre_tokenized: false,
                 truncate: 0,
                 decoder_input_details: false,
                 parameters: ValidParameters {
@@ -569,6 +1149,8 @@ mod tests {
                     frequency_penalty: 0.0,
                     watermark: false,
                     grammar: None,
+                    allowed_token_ids: vec![],
+                    banned_token_ids: vec![],
                 },
                 stopping_parameters: ValidStoppingParameters {
                     ignore_eos_token: false,
@@ -577,20 +1159,30 @@ mod tests {
                 },
                 top_n_tokens: 0,
                 adapter_id: None,
+                raw_bytes: false,
+                stop_on_repetition: None,
+                stop_on_json: false,
+                stop_token_ids: vec![],
+                pin_prefix: None,
             },
             response_tx,
             span: info_span!("entry"),
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
+            decoder: Utf8Decoder::default(),
+            steps: 0,
             block_allocation: None,
+            queued_at: QueuedAt::default(),
+            grammar: None,
+            downgraded: false,
         };
         (entry, receiver_tx)
     }
 
     #[tokio::test]
     async fn test_append() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(false, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
         let (entry, _guard) = default_entry();
 
         assert_eq!(state.next_id, 0);
@@ -606,7 +1198,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_next_batch_empty() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(false, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
 
         assert!(state.next_batch(None, None, 1, 1).await.is_none());
         assert!(state.next_batch(Some(1), None, 1, 1).await.is_none());
@@ -614,7 +1206,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_next_batch_min_size() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(false, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
@@ -644,9 +1236,37 @@ mod tests {
         assert_eq!(id, 2);
     }
 
+    #[tokio::test]
+    async fn test_next_batch_cache_stats() {
+        let mut state = State::new(false, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
+        let mut guards = vec![];
+        for _ in 0..3 {
+            let (entry, guard) = default_entry();
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // The allocator has 16 free blocks, the shards only 2
+        state.cache_stats = Some(CacheStats {
+            used_blocks: 14,
+            total_blocks: 16,
+            used_bytes: 14 << 20,
+            total_bytes: 16 << 20,
+            batch_ids: vec![],
+        });
+        let (entries, _, _) = state.next_batch(None, None, 16, 16).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(state.entries.len(), 1);
+
+        // Once the running batches finished, the allocator budget applies
+        state.cache_stats = None;
+        let (entries, _, _) = state.next_batch(None, None, 16, 16).await.unwrap();
+        assert!(entries.contains_key(&2));
+    }
+
     #[tokio::test]
     async fn test_next_batch_max_size() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(false, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
@@ -664,9 +1284,232 @@ mod tests {
         assert_eq!(state.next_batch_id, 1);
     }
 
+    #[tokio::test]
+    async fn test_next_batch_large_queue() {
+        let mut state = State::new(true, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
+        let mut guards = vec![];
+        for _ in 0..20_000 {
+            let (entry, guard) = default_entry();
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // The batch is bounded by the prefill budget and only pops the entries it batches
+        let (entries, batch, _) = state.next_batch(None, None, 4, 1 << 20).await.unwrap();
+        assert_eq!(batch.size, 4);
+        assert!((0..4).all(|id| entries.contains_key(&id)));
+        assert_eq!(state.entries.len(), 19_996);
+        assert_eq!(state.entries.front().unwrap().0, 4);
+    }
+
+    fn entry_with_length(input_length: u32) -> (Entry, ResponseStream) {
+        let (mut entry, guard) = default_entry();
+        entry.request.input_length = input_length;
+        (entry, guard)
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_shortest_prefill_first() {
+        let mut state = State::new(
+            true,
+            1,
+            false,
+            None,
+            0,
+            16,
+            false,
+            SchedulingPolicy::ShortestPrefillFirst,
+        );
+        let mut guards = vec![];
+        for input_length in [3, 1, 2] {
+            let (entry, guard) = entry_with_length(input_length);
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        let (entries, _, _) = state.next_batch(None, Some(1), 16, 16).await.unwrap();
+        assert!(entries.contains_key(&1));
+        let (entries, _, _) = state.next_batch(None, Some(1), 16, 16).await.unwrap();
+        assert!(entries.contains_key(&2));
+        assert_eq!(state.entries.front().unwrap().0, 0);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_downgraded_last() {
+        for policy in [
+            SchedulingPolicy::Fifo,
+            SchedulingPolicy::ShortestPrefillFirst,
+        ] {
+            let mut state = State::new(true, 1, false, None, 0, 16, false, policy);
+            let mut guards = vec![];
+            for (input_length, downgraded) in [(1, true), (3, false), (2, false)] {
+                let (mut entry, guard) = entry_with_length(input_length);
+                entry.downgraded = downgraded;
+                state.append(entry);
+                guards.push(guard);
+            }
+            assert_eq!(state.queued_tokens(), 5);
+
+            for _ in 0..2 {
+                let (entries, _, _) = state.next_batch(None, Some(1), 16, 16).await.unwrap();
+                assert!(!entries.contains_key(&0));
+            }
+            assert_eq!(state.queued_tokens(), 0);
+            let (entries, _, _) = state.next_batch(None, Some(1), 16, 16).await.unwrap();
+            assert!(entries.contains_key(&0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_positions() {
+        let mut state = State::new(true, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
+        let mut streams = vec![];
+        for input_length in [300, 200] {
+            let (entry, stream) = entry_with_length(input_length);
+            state.append(entry);
+            streams.push(stream);
+        }
+        let ttft = TtftEstimator::default();
+        ttft.record_prefill(1000, Duration::from_millis(100));
+
+        // No estimate before the first batch
+        state.send_positions(&ttft, None);
+        state.send_positions(&ttft, Some(1000));
+        for (position, (stream, eta_ms)) in streams.iter_mut().zip([30, 50]).enumerate() {
+            match stream.next().await {
+                Some(Ok(InferStreamResponse::Queued { eta: None, .. })) => {}
+                _ => panic!("Missing the position without estimate"),
+            }
+            match stream.next().await {
+                Some(Ok(InferStreamResponse::Queued {
+                    position: queued,
+                    eta: Some(eta),
+                })) => {
+                    assert_eq!(queued, position + 1);
+                    assert_eq!((eta.as_secs_f64() * 1000.0).round() as u64, eta_ms);
+                }
+                _ => panic!("Missing the position with estimate"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_priority_aging() {
+        // Boost of the prompt that waited 10 seconds, and id of the first batched entry
+        for (max_boost, first) in [(0, 1), (5, 1), (10, 0)] {
+            let mut state = State::new(
+                true,
+                1,
+                false,
+                None,
+                0,
+                16,
+                false,
+                SchedulingPolicy::ShortestPrefillFirst,
+            )
+            .with_priority_aging(Some(PriorityAging {
+                rate: 1.0,
+                max_boost,
+            }));
+            let (mut long, _long_guard) = entry_with_length(10);
+            long.queue_time -= Duration::from_secs(10);
+            state.append(long);
+            let (short, _short_guard) = entry_with_length(1);
+            state.append(short);
+
+            let (entries, _, _) = state.next_batch(None, Some(1), 16, 16).await.unwrap();
+            assert!(entries.contains_key(&first));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_longest_wait_first() {
+        for (policy, batched, queued) in [
+            (SchedulingPolicy::Fifo, vec![0], vec![1, 2]),
+            (SchedulingPolicy::LongestWaitFirst, vec![0, 2], vec![1]),
+        ] {
+            let mut state = State::new(true, 1, false, None, 0, 16, false, policy);
+            let mut guards = vec![];
+            for input_length in [1, 10, 1] {
+                let (entry, guard) = entry_with_length(input_length);
+                state.append(entry);
+                guards.push(guard);
+            }
+
+            // The long prompt does not fit the prefill budget
+            let (entries, _, _) = state.next_batch(None, None, 4, 16).await.unwrap();
+            let mut ids: Vec<u64> = entries.keys().copied().collect();
+            ids.sort();
+            assert_eq!(ids, batched);
+            let ids: Vec<u64> = state.entries.iter().map(|(id, _)| *id).collect();
+            assert_eq!(ids, queued);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_blocked() {
+        let mut state = State::new(true, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
+        let (long, _long_guard) = entry_with_length(10);
+        let (short, _short_guard) = entry_with_length(1);
+        state.append(long);
+        state.append(short);
+
+        // The long prompt does not fit the prefill budget, and blocks the short one
+        assert!(state.next_batch(None, None, 4, 16).await.is_none());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (entries, _, _) = state.next_batch(None, Some(1), 16, 16).await.unwrap();
+        assert!(entries.contains_key(&0));
+        assert!(state.blocked >= Duration::from_millis(10));
+        assert_eq!(state.blocked_since, None);
+
+        // The entries queued afterwards are not held back by this blocking
+        let (entry, _guard) = default_entry();
+        state.append(entry);
+        let (_, entry) = state.entries.back().unwrap();
+        assert_eq!(entry.queued_at.blocked, state.blocked);
+        assert_eq!(entry.queued_at.batch_id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_grammar_compiling() {
+        let mut state = State::new(true, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
+        let grammar = Arc::new(GrammarState::default());
+        let (mut entry1, _guard1) = default_entry();
+        entry1.grammar = Some(grammar.clone());
+        let (entry2, _guard2) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+
+        // The unconstrained request is not held back by the compilation
+        let (entries, _, _) = state.next_batch(None, None, 16, 16).await.unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(state.entries.len(), 1);
+
+        grammar.set_compiled();
+        let (entries, _, _) = state.next_batch(None, None, 16, 16).await.unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), [&0]);
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_pre_tokenized() {
+        let mut state = State::new(false, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
+        let (mut entry1, _guard1) = default_entry();
+        entry1.request.input_ids = Some(Arc::new(vec![5, 6]));
+        entry1.request.pre_tokenized = true;
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.input_ids = Some(Arc::new(vec![7, 8]));
+        state.append(entry1);
+        state.append(entry2);
+
+        // Only the ids sent by the client are forwarded, the shards tokenize the others
+        let (_, batch, _) = state.next_batch(None, None, 16, 16).await.unwrap();
+        assert_eq!(batch.requests[0].input_ids, [5, 6]);
+        assert!(batch.requests[1].input_ids.is_empty());
+    }
+
     #[tokio::test]
     async fn test_next_batch_token_budget() {
-        let mut state = State::new(false, 1, false, None, 0, 16, false);
+        let mut state = State::new(false, 1, false, None, 0, 16, false, SchedulingPolicy::Fifo);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
@@ -699,14 +1542,34 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_append() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(
+            false,
+            1,
+            false,
+            None,
+            0,
+            16,
+            false,
+            SchedulingPolicy::Fifo,
+            None,
+        );
         let (entry, _guard) = default_entry();
         queue.append(entry);
     }
 
     #[tokio::test]
     async fn test_queue_next_batch_empty() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(
+            false,
+            1,
+            false,
+            None,
+            0,
+            16,
+            false,
+            SchedulingPolicy::Fifo,
+            None,
+        );
 
         assert!(queue.next_batch(None, None, 1, 1).await.is_none());
         assert!(queue.next_batch(Some(1), None, 1, 1).await.is_none());
@@ -714,7 +1577,17 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_min_size() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(
+            false,
+            1,
+            false,
+            None,
+            0,
+            16,
+            false,
+            SchedulingPolicy::Fifo,
+            None,
+        );
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -747,7 +1620,17 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_max_size() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(
+            false,
+            1,
+            false,
+            None,
+            0,
+            16,
+            false,
+            SchedulingPolicy::Fifo,
+            None,
+        );
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -763,7 +1646,17 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_token_budget() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(
+            false,
+            1,
+            false,
+            None,
+            0,
+            16,
+            false,
+            SchedulingPolicy::Fifo,
+            None,
+        );
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -788,7 +1681,17 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_token_speculate() {
-        let queue = Queue::new(true, 1, false, None, 2, 16, false);
+        let queue = Queue::new(
+            true,
+            1,
+            false,
+            None,
+            2,
+            16,
+            false,
+            SchedulingPolicy::Fifo,
+            None,
+        );
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
@@ -805,9 +1708,30 @@ mod tests {
         assert_eq!(batch.size, 2);
     }
 
+    #[tokio::test]
+    async fn test_next_batch_invariants() {
+        // Random scenarios, like the inputs of the `next_batch` fuzz target
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..500 {
+            let len = rng.gen_range(0..512);
+            let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            admission::check(&data).await;
+        }
+    }
+
     #[tokio::test]
     async fn test_queue_next_batch_dropped_receiver() {
-        let queue = Queue::new(false, 1, false, None, 0, 16, false);
+        let queue = Queue::new(
+            false,
+            1,
+            false,
+            None,
+            0,
+            16,
+            false,
+            SchedulingPolicy::Fifo,
+            None,
+        );
         let (entry, _) = default_entry();
         queue.append(entry);
 
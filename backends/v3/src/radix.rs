@@ -24,6 +24,9 @@ pub struct RadixAllocator {
 
     cache_blocks: RadixTrie,
 
+    /// Trie nodes of the pinned prefixes, referenced until they are unpinned.
+    pins: HashMap<u64, NodeId>,
+
     /// Blocks that are immediately available for allocation.
     free_blocks: Vec<u32>,
 
@@ -41,6 +44,7 @@ impl RadixAllocator {
             allocation_id: 0,
             allocations: HashMap::new(),
             cache_blocks: RadixTrie::new(block_size as usize),
+            pins: HashMap::new(),
 
             // Block 0 is reserved for health checks.
             free_blocks: (1..n_blocks).collect(),
@@ -140,6 +144,7 @@ impl Allocator for RadixAllocator {
             prefix_node,
             cached_prefix_len: prefix_len,
             prefill_tokens: prefill_tokens.clone(),
+            pin_prefix: None,
         };
 
         self.allocation_id += 1;
@@ -202,16 +207,44 @@ impl Allocator for RadixAllocator {
             // Free non-prefill blocks.
             self.free_blocks
                 .extend(&blocks[prefill_tokens.len() / self.block_size as usize..]);
+
+            // Keep the prefill blocks that are now in the trie until the prefix is unpinned.
+            if let Some(pin_prefix) = allocation.pin_prefix {
+                let aligned =
+                    (prefill_tokens.len() / self.block_size as usize) * self.block_size as usize;
+                if let Some(node_id) = self.cache_blocks.find_node(&prefill_tokens[..aligned]) {
+                    self.cache_blocks
+                        .incref(node_id)
+                        .expect("Failed to increment refcount");
+                    self.pins.insert(pin_prefix, node_id);
+                }
+            }
         } else {
             self.free_blocks.extend(blocks);
         }
     }
+
+    fn pin(&mut self, allocation_id: u64, pin_prefix: u64) {
+        if let Some(allocation) = self.allocations.get_mut(&allocation_id) {
+            allocation.pin_prefix = Some(pin_prefix);
+        }
+    }
+
+    fn unpin(&mut self, pin_prefix: u64) {
+        // The prefix may have been unpinned before its allocation was freed.
+        if let Some(node_id) = self.pins.remove(&pin_prefix) {
+            self.cache_blocks
+                .decref(node_id)
+                .expect("Failed to decrement refcount");
+        }
+    }
 }
 
 struct RadixAllocation {
     prefix_node: NodeId,
     cached_prefix_len: usize,
     prefill_tokens: Option<Arc<Vec<u32>>>,
+    pin_prefix: Option<u64>,
 }
 
 // Radix trie that is heavily inspired by radix attention from sglang.
@@ -305,6 +338,27 @@ impl RadixTrie {
         node_id
     }
 
+    /// Find the node that holds the end of the given block-aligned tokens.
+    ///
+    /// In contrast to `find`, the returned node contains the whole prefix, so
+    /// that referencing it keeps all of its blocks. Returns `None` when the
+    /// trie does not contain all the tokens, and the root for empty tokens.
+    pub fn find_node(&self, mut key: &[u32]) -> Option<NodeId> {
+        let mut node_id = self.root;
+        while !key.is_empty() {
+            let node_key = hash(&key[..self.block_size]);
+            let child_id = *self.nodes[node_id].children.get(&node_key)?;
+            let child = &self.nodes[child_id];
+            let shared_prefix_len = shared_prefix(&child.key, key, self.block_size);
+            if shared_prefix_len < key.len() && shared_prefix_len < child.key.len() {
+                return None;
+            }
+            key = &key[shared_prefix_len..];
+            node_id = child_id;
+        }
+        Some(node_id)
+    }
+
     /// Decrease the reference count of a node.
     pub fn decref(&mut self, node_id: NodeId) -> Result<(), TrieError> {
         // We don't care about refcounting for root, since it will never
@@ -663,6 +717,30 @@ mod tests {
         assert_eq!(allocation.prefix_len, 2);
     }
 
+    #[test]
+    fn allocator_keeps_pinned_prefixes() {
+        let mut cache = RadixAllocator::new(1, 12, None);
+        let allocation = cache.allocate(4, Some(Arc::new(vec![0, 1, 2, 3]))).unwrap();
+        cache.pin(allocation.allocation_id, 7);
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+
+        // The pinned prefix is not evicted to allocate other blocks.
+        assert!(cache.allocate(11, None).is_none());
+        let allocation = cache.allocate(7, None).unwrap();
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+
+        let allocation = cache
+            .allocate(6, Some(Arc::new(vec![0, 1, 2, 3, 4, 5])))
+            .unwrap();
+        assert_eq!(allocation.prefix_len, 4);
+        cache.free(allocation.blocks.clone(), allocation.allocation_id);
+
+        // Unpinned, it can be evicted again.
+        cache.unpin(7);
+        let allocation = cache.allocate(11, None).unwrap();
+        assert_eq!(allocation.blocks.len(), 11);
+    }
+
     #[test]
     fn allocator_reuses_prefixes() {
         let mut cache = RadixAllocator::new(1, 12, None);
@@ -0,0 +1,507 @@
+/// Deterministic simulation of the continuous batching scheduler
+///
+/// Request traces are replayed through the real `Queue` and `batching_task`, against mock shards
+/// whose forwards take the time given by a [`CostModel`]. The simulation runs on a paused Tokio
+/// clock, so a trace always produces the same [`Report`], in a fraction of the simulated time.
+use crate::backend::{batching_task, bench};
+use crate::client::{
+    Batch, BatchClient, CacheStats, CachedBatch, DecodeTimings, FinishReason, GeneratedText,
+    Generation, PrefillTimings, Result, Tokens,
+};
+use crate::queue::{PriorityAging, Queue, SchedulingPolicy};
+use crate::response::{ResponseRouter, ResponseStream};
+use async_trait::async_trait;
+use futures::StreamExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use text_generation_router::infer::InferStreamResponse;
+use text_generation_router::logging::TraceDetail;
+use text_generation_router::validation::Chunk;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Request of a trace
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRequest {
+    /// Time of arrival, since the start of the trace
+    pub arrival: Duration,
+    pub input_length: u32,
+    /// Every request generates exactly `max_new_tokens`
+    pub max_new_tokens: u32,
+}
+
+/// Trace of `requests` arriving as a Poisson process of `rate` requests per second, with
+/// uniformly distributed lengths
+pub fn poisson_trace(
+    seed: u64,
+    requests: usize,
+    rate: f64,
+    input_length: RangeInclusive<u32>,
+    max_new_tokens: RangeInclusive<u32>,
+) -> Vec<TraceRequest> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut arrival = 0.0;
+    (0..requests)
+        .map(|_| {
+            arrival += -(1.0 - rng.gen::<f64>()).ln() / rate;
+            TraceRequest {
+                arrival: Duration::from_secs_f64(arrival),
+                input_length: rng.gen_range(input_length.clone()),
+                max_new_tokens: rng.gen_range(max_new_tokens.clone()),
+            }
+        })
+        .collect()
+}
+
+/// Scheduler parameters, with the defaults of the launcher
+#[derive(Clone, Debug)]
+pub struct SchedulerConfig {
+    pub waiting_served_ratio: f32,
+    pub max_batch_prefill_tokens: u32,
+    pub max_batch_total_tokens: u32,
+    pub max_waiting_tokens: usize,
+    pub max_batch_size: Option<usize>,
+    pub scheduling_policy: SchedulingPolicy,
+    pub priority_aging: Option<PriorityAging>,
+    pub eager_admission: bool,
+    pub block_size: u32,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            waiting_served_ratio: 0.3,
+            max_batch_prefill_tokens: 4096,
+            max_batch_total_tokens: 16384,
+            max_waiting_tokens: 20,
+            max_batch_size: None,
+            scheduling_policy: SchedulingPolicy::Fifo,
+            priority_aging: None,
+            eager_admission: false,
+            block_size: 16,
+        }
+    }
+}
+
+/// Duration of the forwards of the mock shards
+#[derive(Clone, Debug)]
+pub struct CostModel {
+    /// Fixed cost of every forward
+    pub forward: Duration,
+    /// Cost of every prefilled token
+    pub prefill_token: Duration,
+    /// Cost of every sequence decoded by a forward
+    pub decode_sequence: Duration,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            forward: Duration::from_millis(10),
+            prefill_token: Duration::from_micros(50),
+            decode_sequence: Duration::from_micros(200),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Latencies {
+    pub average: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Latencies {
+    fn new(mut latencies: Vec<Duration>) -> Self {
+        latencies.sort();
+        let percentile = |p: f64| {
+            latencies
+                .get(((latencies.len().saturating_sub(1)) as f64 * p).round() as usize)
+                .copied()
+                .unwrap_or_default()
+        };
+        Self {
+            average: latencies.iter().sum::<Duration>() / latencies.len().max(1) as u32,
+            p50: percentile(0.5),
+            p99: percentile(0.99),
+            max: percentile(1.0),
+        }
+    }
+}
+
+/// Results of a simulation, in simulated time
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub requests: usize,
+    /// From the start of the trace to the end of the last request
+    pub duration: Duration,
+    /// Share of `duration` spent in forwards
+    pub utilization: f64,
+    pub forwards: u64,
+    /// Average number of sequences of the forwards
+    pub average_batch_size: f64,
+    /// Generated tokens per second
+    pub throughput: f64,
+    /// From the arrival of a request to its first token
+    pub time_to_first_token: Latencies,
+    /// From the arrival of a request to its last token
+    pub latency: Latencies,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests in {:.2?}, {} forwards of {:.1} sequences on average",
+            self.requests, self.duration, self.forwards, self.average_batch_size
+        )?;
+        writeln!(
+            f,
+            "utilization {:.1}%, throughput {:.1} tokens/s",
+            self.utilization * 100.0,
+            self.throughput
+        )?;
+        for (name, latencies) in [
+            ("time to first token", &self.time_to_first_token),
+            ("latency", &self.latency),
+        ] {
+            writeln!(
+                f,
+                "{name}: average {:.2?}, p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+                latencies.average, latencies.p50, latencies.p99, latencies.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Replay `trace` through the scheduler configured by `scheduler`
+///
+/// Every request must fit `max_batch_total_tokens` and, without chunking,
+/// `max_batch_prefill_tokens`, or it is never scheduled.
+pub fn simulate(trace: &[TraceRequest], scheduler: &SchedulerConfig, cost: &CostModel) -> Report {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .expect("Unable to build the simulation runtime")
+        .block_on(run(trace, scheduler, cost))
+}
+
+async fn run(trace: &[TraceRequest], scheduler: &SchedulerConfig, cost: &CostModel) -> Report {
+    let stats = Arc::new(Mutex::new(ShardStats::default()));
+    let shards = MockShards {
+        cost: cost.clone(),
+        stats: stats.clone(),
+        batches: HashMap::new(),
+        block_size: scheduler.block_size,
+        total_blocks: scheduler.max_batch_total_tokens / scheduler.block_size,
+    };
+    let queue = Queue::new(
+        false,
+        scheduler.block_size,
+        false,
+        None,
+        0,
+        scheduler.max_batch_total_tokens,
+        false,
+        scheduler.scheduling_policy,
+        scheduler.priority_aging,
+    );
+    let notifier = Arc::new(Notify::new());
+    let batching = tokio::spawn(batching_task(
+        shards,
+        scheduler.waiting_served_ratio,
+        scheduler.max_batch_prefill_tokens,
+        scheduler.max_batch_total_tokens,
+        scheduler.max_waiting_tokens,
+        scheduler.max_batch_size,
+        scheduler.eager_admission,
+        None,
+        TraceDetail::Request,
+        false,
+        queue.clone(),
+        notifier.clone(),
+    ));
+
+    let responses = ResponseRouter::default();
+    let start = Instant::now();
+    let mut requests = Vec::with_capacity(trace.len());
+    for request in trace {
+        tokio::time::sleep_until(start + request.arrival).await;
+        let (response_tx, response_rx) = responses.channel();
+        let mut entry = bench::entry(0, response_tx);
+        // The mock shards count one token per character
+        entry.request.inputs = vec![Chunk::Text("a".repeat(request.input_length as usize))];
+        entry.request.input_length = request.input_length;
+        entry.request.stopping_parameters.max_new_tokens = request.max_new_tokens;
+        entry.queue_time = Instant::now();
+        entry.batch_time = None;
+        queue.append(entry);
+        notifier.notify_one();
+        requests.push(tokio::spawn(receive(response_rx)));
+    }
+
+    let mut time_to_first_token = Vec::with_capacity(trace.len());
+    let mut latency = Vec::with_capacity(trace.len());
+    for request in requests {
+        let (first_token, last_token) = request.await.expect("Simulated request panicked");
+        time_to_first_token.push(first_token);
+        latency.push(last_token);
+    }
+    let duration = start.elapsed();
+    batching.abort();
+
+    let stats = stats.lock().unwrap();
+    let seconds = duration.as_secs_f64();
+    Report {
+        requests: trace.len(),
+        duration,
+        utilization: stats.busy.as_secs_f64() / seconds,
+        forwards: stats.forwards,
+        average_batch_size: stats.sequences as f64 / stats.forwards.max(1) as f64,
+        throughput: stats.sequences as f64 / seconds,
+        time_to_first_token: Latencies::new(time_to_first_token),
+        latency: Latencies::new(latency),
+    }
+}
+
+/// Time to the first and to the last token of a request
+async fn receive(mut stream: ResponseStream) -> (Duration, Duration) {
+    let queued = Instant::now();
+    let mut first_token = None;
+    while let Some(response) = stream.next().await {
+        match response.expect("Mock shards do not fail") {
+            InferStreamResponse::Queued { .. } | InferStreamResponse::Prefill(_) => {}
+            InferStreamResponse::Intermediate { .. } => {
+                first_token.get_or_insert(queued.elapsed());
+            }
+            InferStreamResponse::End { .. } => {
+                let last_token = queued.elapsed();
+                return (first_token.unwrap_or(last_token), last_token);
+            }
+        }
+    }
+    panic!("Simulated request ended without its last token")
+}
+
+#[derive(Debug, Default)]
+struct ShardStats {
+    busy: Duration,
+    forwards: u64,
+    /// Sequences of all the forwards, each generating one token
+    sequences: u64,
+}
+
+struct Sequence {
+    id: u64,
+    input_length: u32,
+    max_new_tokens: u32,
+    generated: u32,
+}
+
+/// Shards generating one token per sequence and forward, until `max_new_tokens`
+struct MockShards {
+    cost: CostModel,
+    stats: Arc<Mutex<ShardStats>>,
+    batches: HashMap<u64, Vec<Sequence>>,
+    block_size: u32,
+    total_blocks: u32,
+}
+
+impl MockShards {
+    /// Run a forward of `cost`, and cache the unfinished sequences as batch `id`
+    async fn forward(
+        &mut self,
+        id: u64,
+        mut sequences: Vec<Sequence>,
+        cost: Duration,
+    ) -> (Vec<Generation>, Option<CachedBatch>) {
+        tokio::time::sleep(cost).await;
+        let mut stats = self.stats.lock().unwrap();
+        stats.busy += cost;
+        stats.forwards += 1;
+        stats.sequences += sequences.len() as u64;
+        drop(stats);
+
+        let generations = sequences
+            .iter_mut()
+            .map(|sequence| {
+                sequence.generated += 1;
+                Generation {
+                    request_id: sequence.id,
+                    prefill_tokens: None,
+                    tokens: Some(Tokens {
+                        ids: vec![0],
+                        logprobs: vec![0.0],
+                        texts: vec!["a".to_string()],
+                        is_special: vec![false],
+                    }),
+                    generated_text: (sequence.generated == sequence.max_new_tokens).then(|| {
+                        GeneratedText {
+                            text: "a".repeat(sequence.generated as usize),
+                            generated_tokens: sequence.generated,
+                            finish_reason: FinishReason::Length as i32,
+                            seed: None,
+                        }
+                    }),
+                    top_tokens: vec![],
+                }
+            })
+            .collect();
+
+        sequences.retain(|sequence| sequence.generated < sequence.max_new_tokens);
+        if sequences.is_empty() {
+            return (generations, None);
+        }
+        let batch = cached_batch(id, &sequences);
+        self.batches.insert(id, sequences);
+        (generations, Some(batch))
+    }
+
+    fn take(&mut self, batch: &CachedBatch) -> Vec<Sequence> {
+        self.batches.remove(&batch.id).unwrap_or_default()
+    }
+}
+
+fn cached_batch(id: u64, sequences: &[Sequence]) -> CachedBatch {
+    CachedBatch {
+        id,
+        request_ids: sequences.iter().map(|sequence| sequence.id).collect(),
+        size: sequences.len() as u32,
+        max_tokens: sequences
+            .iter()
+            .map(|sequence| sequence.input_length + sequence.max_new_tokens)
+            .sum(),
+        current_tokens: sequences.len() as u32,
+    }
+}
+
+#[async_trait]
+impl BatchClient for MockShards {
+    async fn prefill(
+        &mut self,
+        batch: Batch,
+        cached_batch: Option<CachedBatch>,
+    ) -> Result<(Vec<Generation>, Option<CachedBatch>, PrefillTimings)> {
+        let prefill_tokens: u32 = batch
+            .requests
+            .iter()
+            .map(|request| request.inputs.len() as u32)
+            .sum();
+        let mut sequences = cached_batch
+            .as_ref()
+            .map(|cached_batch| self.take(cached_batch))
+            .unwrap_or_default();
+        let cost = self.cost.forward
+            + self.cost.prefill_token * prefill_tokens
+            + self.cost.decode_sequence * sequences.len() as u32;
+        sequences.extend(batch.requests.into_iter().map(|request| {
+            Sequence {
+                id: request.id,
+                input_length: request.inputs.len() as u32,
+                max_new_tokens: request
+                    .stopping_parameters
+                    .map(|parameters| parameters.max_new_tokens)
+                    .unwrap_or(1),
+                generated: 0,
+            }
+        }));
+
+        let (generations, next_batch) = self.forward(batch.id, sequences, cost).await;
+        let timings = PrefillTimings {
+            concat: None,
+            forward: cost,
+            decode: Duration::ZERO,
+            total: cost,
+        };
+        Ok((generations, next_batch, timings))
+    }
+
+    async fn decode(
+        &mut self,
+        batches: Vec<CachedBatch>,
+    ) -> Result<(Vec<Generation>, Option<CachedBatch>, DecodeTimings)> {
+        let sequences: Vec<Sequence> = batches.iter().flat_map(|batch| self.take(batch)).collect();
+        let cost = self.cost.forward + self.cost.decode_sequence * sequences.len() as u32;
+
+        // Batches are concatenated into the first one
+        let (generations, next_batch) = self.forward(batches[0].id, sequences, cost).await;
+        let timings = DecodeTimings {
+            concat: None,
+            forward: cost,
+            decode: Duration::ZERO,
+            total: cost,
+        };
+        Ok((generations, next_batch, timings))
+    }
+
+    async fn filter_batch(
+        &mut self,
+        batch_id: u64,
+        request_ids: Vec<u64>,
+    ) -> Result<Option<CachedBatch>> {
+        let mut sequences = self.batches.remove(&batch_id).unwrap_or_default();
+        sequences.retain(|sequence| request_ids.contains(&sequence.id));
+        let batch = cached_batch(batch_id, &sequences);
+        self.batches.insert(batch_id, sequences);
+        Ok(Some(batch))
+    }
+
+    async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
+        match batch_id {
+            Some(batch_id) => {
+                self.batches.remove(&batch_id);
+            }
+            None => self.batches.clear(),
+        }
+        Ok(())
+    }
+
+    async fn cache_stats(&mut self) -> Result<CacheStats> {
+        let used_blocks = self
+            .batches
+            .values()
+            .flatten()
+            .map(|sequence| (sequence.input_length + sequence.generated).div_ceil(self.block_size))
+            .sum();
+        // The mock shards have no device memory
+        Ok(CacheStats {
+            used_blocks,
+            total_blocks: self.total_blocks,
+            used_bytes: 0,
+            total_bytes: 0,
+            batch_ids: self.batches.keys().copied().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulate() {
+        let trace = poisson_trace(0, 64, 20.0, 16..=512, 8..=64);
+        let report = simulate(&trace, &SchedulerConfig::default(), &CostModel::default());
+        assert_eq!(report.requests, 64);
+        assert!(report.utilization > 0.0 && report.utilization <= 1.0);
+        assert!(report.average_batch_size > 1.0);
+        assert!(report.time_to_first_token.p50 <= report.latency.p50);
+        let generated_tokens: u32 = trace.iter().map(|request| request.max_new_tokens).sum();
+        assert!(
+            (report.throughput * report.duration.as_secs_f64() - generated_tokens as f64).abs()
+                < 1e-6
+        );
+
+        // Replays are deterministic
+        let replay = simulate(&trace, &SchedulerConfig::default(), &CostModel::default());
+        assert_eq!(report, replay);
+    }
+}
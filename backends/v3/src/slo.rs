@@ -0,0 +1,158 @@
+/// Time to first token objective of the admission
+///
+/// The estimator measures the prefill throughput and the decode step duration of the batching
+/// task, and counts the prompt tokens queued ahead of a new request, to tell how long this
+/// request would wait for its first token.
+use clap::ValueEnum;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Weight of the last measurement in the moving averages of the estimator
+const SMOOTHING: f64 = 0.2;
+
+/// Requests whose estimated time to first token is over `slo_ms` are not admitted as others
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct TtftSlo {
+    /// Objective on the time to first token, in milliseconds
+    #[schema(example = "1000")]
+    pub slo_ms: u64,
+    pub action: SloAction,
+}
+
+/// What happens to the requests over the time to first token objective
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, ToSchema, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SloAction {
+    /// The request is rejected with a 429 reporting the estimate
+    #[default]
+    Reject,
+    /// The request is queued behind all the requests within the objective, it is batched when
+    /// there is spare capacity
+    Downgrade,
+}
+
+impl TtftSlo {
+    pub(crate) fn objective(&self) -> Duration {
+        Duration::from_millis(self.slo_ms)
+    }
+}
+
+/// Time to first token of the new requests, from the queued tokens and the batch latencies
+#[derive(Debug, Default)]
+pub(crate) struct TtftEstimator {
+    /// Prompt tokens of the queued requests, except the downgraded ones which do not delay
+    /// the others
+    queued_tokens: AtomicU64,
+    latencies: Mutex<Latencies>,
+}
+
+#[derive(Debug, Default)]
+struct Latencies {
+    /// Prefill duration per prompt token, in seconds
+    prefill_per_token: Option<f64>,
+    /// Duration of a decode step of the running batch, in seconds, none without running batch
+    decode_step: Option<f64>,
+}
+
+impl TtftEstimator {
+    pub(crate) fn queue(&self, tokens: u64) {
+        self.queued_tokens.fetch_add(tokens, Ordering::SeqCst);
+    }
+
+    pub(crate) fn dequeue(&self, tokens: u64) {
+        // Saturates so that a miscount cannot wrap around and reject every request
+        let _ = self
+            .queued_tokens
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                Some(queued.saturating_sub(tokens))
+            });
+    }
+
+    pub(crate) fn record_prefill(&self, tokens: u32, duration: Duration) {
+        if tokens == 0 {
+            return;
+        }
+        let per_token = duration.as_secs_f64() / tokens as f64;
+        let mut latencies = self.latencies.lock().unwrap();
+        latencies.prefill_per_token = Some(smooth(latencies.prefill_per_token, per_token));
+    }
+
+    pub(crate) fn record_decode(&self, duration: Duration) {
+        let mut latencies = self.latencies.lock().unwrap();
+        latencies.decode_step = Some(smooth(latencies.decode_step, duration.as_secs_f64()));
+    }
+
+    /// The running batch finished, the prefills are not interleaved with decode steps anymore
+    pub(crate) fn idle(&self) {
+        self.latencies.lock().unwrap().decode_step = None;
+    }
+
+    /// Time to first token of a request of `input_length` tokens queued now, none until a
+    /// prefill was measured
+    ///
+    /// The queued prompts are prefilled in batches of `prefill_token_budget` tokens, each
+    /// followed by a decode step of the running batch.
+    pub(crate) fn estimate(
+        &self,
+        input_length: u32,
+        prefill_token_budget: u32,
+    ) -> Option<Duration> {
+        let tokens = self.queued_tokens.load(Ordering::SeqCst) + input_length as u64;
+        self.estimate_tokens(tokens, prefill_token_budget)
+    }
+
+    /// Time to first token of a queued request, once the `tokens` prompt tokens of the requests
+    /// ahead of it and of its own prompt are prefilled
+    pub(crate) fn estimate_tokens(
+        &self,
+        tokens: u64,
+        prefill_token_budget: u32,
+    ) -> Option<Duration> {
+        let latencies = self.latencies.lock().unwrap();
+        let prefill_per_token = latencies.prefill_per_token?;
+        let prefills = tokens.div_ceil(prefill_token_budget.max(1) as u64);
+        let decode_steps = latencies.decode_step.unwrap_or(0.0) * prefills as f64;
+        Some(Duration::from_secs_f64(
+            tokens as f64 * prefill_per_token + decode_steps,
+        ))
+    }
+}
+
+fn smooth(average: Option<f64>, value: f64) -> f64 {
+    average.map_or(value, |average| average + SMOOTHING * (value - average))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let estimator = TtftEstimator::default();
+        let estimate_ms = |input_length| {
+            estimator
+                .estimate(input_length, 1000)
+                .map(|estimate| (estimate.as_secs_f64() * 1000.0).round() as u64)
+        };
+        // Admitted until the latencies are measured
+        assert_eq!(estimate_ms(100), None);
+
+        estimator.record_prefill(1000, Duration::from_millis(100));
+        assert_eq!(estimate_ms(500), Some(50));
+
+        // The queued prompts are prefilled first, between the decode steps of the running batch
+        estimator.queue(1500);
+        estimator.record_decode(Duration::from_millis(20));
+        assert_eq!(estimate_ms(500), Some(200 + 2 * 20));
+
+        estimator.dequeue(1000);
+        estimator.idle();
+        assert_eq!(estimate_ms(500), Some(100));
+
+        estimator.record_prefill(1000, Duration::from_millis(200));
+        assert_eq!(estimate_ms(0), Some(60));
+    }
+}
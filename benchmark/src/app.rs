@@ -1,67 +1,117 @@
 /// Inspired by https://github.com/hatoo/oha/blob/bb989ea3cd77727e7743e7daa60a19894bb5e901/src/monitor.rs
-use crate::generation::{Decode, Message, Prefill};
+use crate::generation::{Decode, DecodeMode, Message, Prefill};
+use crate::memory::MemorySample;
 use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
-use ratatui::layout::{Alignment, Constraint, Direction, Layout};
+use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
 use ratatui::style::{Color, Modifier, Style};
 use ratatui::text::{Line, Span};
 use ratatui::widgets::{
-    Axis, BarChart, Block, Borders, Chart, Dataset, Gauge, GraphType, Paragraph, Tabs,
+    Axis, BarChart, Block, Borders, Cell, Chart, Dataset, Gauge, GraphType, Paragraph, Row, Table,
+    Tabs,
 };
 use ratatui::{symbols, Frame};
 use text_generation_client::ClientError;
 use tokio::sync::mpsc;
 
-/// TUI powered App
-pub(crate) struct App {
-    pub(crate) running: bool,
+/// Benchmarked server, driven by its own generation task
+pub(crate) struct Target {
+    pub(crate) name: String,
     pub(crate) data: Data,
     completed_runs: Vec<usize>,
     completed_batch: usize,
     current_batch: usize,
+    pub(crate) is_error: bool,
+    receiver: mpsc::Receiver<Result<Message, ClientError>>,
+}
+
+impl Target {
+    pub(crate) fn new(
+        name: String,
+        receiver: mpsc::Receiver<Result<Message, ClientError>>,
+        n_run: usize,
+        batch_size: Vec<u32>,
+    ) -> Self {
+        Self {
+            name,
+            completed_runs: vec![0; batch_size.len()],
+            data: Data::new(n_run, batch_size),
+            completed_batch: 0,
+            current_batch: 0,
+            is_error: false,
+            receiver,
+        }
+    }
+
+    /// Whether all the batch sizes ran or the benchmark failed
+    pub(crate) fn finished(&self) -> bool {
+        self.is_error || self.completed_batch == self.data.batch_size.len()
+    }
+
+    /// Get all pending messages from the generation task
+    fn tick(&mut self) {
+        while let Ok(message) = self.receiver.try_recv() {
+            match message {
+                Ok(message) => match message {
+                    Message::Prefill(step) => self.data.push_prefill(step, self.current_batch),
+                    Message::Decode(step) => self.data.push_decode(step, self.current_batch),
+                    Message::Memory(sample) => self.data.push_memory(sample, self.current_batch),
+                    Message::EndRun => {
+                        self.completed_runs[self.current_batch] += 1;
+                    }
+                    Message::EndBatch => {
+                        self.data.end_batch(self.current_batch);
+                        self.completed_batch += 1;
+
+                        if self.current_batch < self.data.batch_size.len() - 1 {
+                            self.current_batch += 1;
+                        }
+                    }
+                    Message::Warmup => {}
+                },
+                Err(_) => self.is_error = true,
+            }
+        }
+    }
+}
+
+/// TUI powered App
+pub(crate) struct App {
+    pub(crate) running: bool,
+    /// The benchmarked server, then the one it is compared with if any
+    pub(crate) targets: Vec<Target>,
     current_tab: usize,
     touched_tab: bool,
     zoom: bool,
-    is_error: bool,
     tokenizer_name: String,
     sequence_length: u32,
     decode_length: u32,
+    decode_mode: DecodeMode,
     n_run: usize,
-    receiver: mpsc::Receiver<Result<Message, ClientError>>,
+    batch_size: Vec<u32>,
 }
 
 impl App {
     pub(crate) fn new(
-        receiver: mpsc::Receiver<Result<Message, ClientError>>,
+        targets: Vec<Target>,
         tokenizer_name: String,
         sequence_length: u32,
         decode_length: u32,
+        decode_mode: DecodeMode,
         n_run: usize,
         batch_size: Vec<u32>,
     ) -> Self {
-        let current_tab = 0;
-
-        let completed_runs: Vec<usize> = (0..batch_size.len()).map(|_| 0).collect();
-        let completed_batch = 0;
-        let current_batch = 0;
-        let is_error = false;
-
-        let data = Data::new(n_run, batch_size);
-
         Self {
             running: true,
-            data,
-            completed_runs,
-            completed_batch,
-            current_batch,
-            current_tab,
+            targets,
+            current_tab: 0,
             touched_tab: false,
             zoom: false,
-            is_error,
             tokenizer_name,
             sequence_length,
             decode_length,
+            decode_mode,
             n_run,
-            receiver,
+            batch_size,
         }
     }
 
@@ -77,7 +127,7 @@ impl App {
                 code: KeyCode::Tab, ..
             } => {
                 self.touched_tab = true;
-                self.current_tab = (self.current_tab + 1) % self.data.batch_size.len();
+                self.current_tab = (self.current_tab + 1) % self.batch_size.len();
             }
             // Decrease and wrap tab
             KeyEvent {
@@ -88,7 +138,7 @@ impl App {
                 if self.current_tab > 0 {
                     self.current_tab -= 1;
                 } else {
-                    self.current_tab = self.data.batch_size.len() - 1;
+                    self.current_tab = self.batch_size.len() - 1;
                 }
             }
             // Zoom on throughput/latency fig
@@ -121,43 +171,25 @@ impl App {
         }
     }
 
-    /// Get all pending messages from generation task
+    /// Get all pending messages from generation tasks
     pub(crate) fn tick(&mut self) {
-        while let Ok(message) = self.receiver.try_recv() {
-            match message {
-                Ok(message) => match message {
-                    Message::Prefill(step) => self.data.push_prefill(step, self.current_batch),
-                    Message::Decode(step) => self.data.push_decode(step, self.current_batch),
-                    Message::EndRun => {
-                        self.completed_runs[self.current_batch] += 1;
-                    }
-                    Message::EndBatch => {
-                        self.data.end_batch(self.current_batch);
-                        self.completed_batch += 1;
-
-                        if self.current_batch < self.data.batch_size.len() - 1 {
-                            // Only go to next tab if the user never touched the tab keys
-                            if !self.touched_tab {
-                                self.current_tab += 1;
-                            }
-
-                            self.current_batch += 1;
-                        }
-                    }
-                    Message::Warmup => {}
-                },
-                Err(_) => self.is_error = true,
-            }
+        for target in self.targets.iter_mut() {
+            target.tick();
+        }
+        // Only go to next tab if the user never touched the tab keys, the tab follows the
+        // slowest target
+        if !self.touched_tab {
+            self.current_tab = self
+                .targets
+                .iter()
+                .map(|target| target.current_batch)
+                .min()
+                .unwrap_or(0);
         }
     }
 
     /// Render frame
     pub fn render(&mut self, f: &mut Frame) {
-        let batch_progress =
-            (self.completed_batch as f64 / self.data.batch_size.len() as f64).clamp(0.0, 1.0);
-        let run_progress =
-            (self.completed_runs[self.current_batch] as f64 / self.n_run as f64).clamp(0.0, 1.0);
-
         // Vertical layout
         let row5 = Layout::default()
             .direction(Direction::Vertical)
@@ -179,36 +211,6 @@ impl App {
             .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
             .split(row5[2]);
 
-        // Mid row horizontal layout
-        let mid = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints(
-                [
-                    Constraint::Percentage(25),
-                    Constraint::Percentage(25),
-                    Constraint::Percentage(25),
-                    Constraint::Percentage(25),
-                ]
-                .as_ref(),
-            )
-            .split(row5[3]);
-
-        // Left mid row vertical layout
-        let prefill_text = Layout::default()
-            .direction(Direction::Vertical)
-            .constraints([Constraint::Length(8), Constraint::Length(5)].as_ref())
-            .split(mid[0]);
-
-        // Right mid row vertical layout
-        let decode_text = Layout::default()
-            .direction(Direction::Vertical)
-            .constraints([Constraint::Length(8), Constraint::Length(5)].as_ref())
-            .split(mid[2]);
-        let decode_text_latency = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-            .split(decode_text[0]);
-
         // Bottom row horizontal layout
         let bottom = Layout::default()
             .direction(Direction::Horizontal)
@@ -219,8 +221,8 @@ impl App {
         let title = Block::default()
             .borders(Borders::NONE)
             .title(format!(
-                "Model: {} | Sequence Length: {} | Decode Length: {}",
-                self.tokenizer_name, self.sequence_length, self.decode_length
+                "Model: {} | Sequence Length: {} | Decode Length: {} ({:?})",
+                self.tokenizer_name, self.sequence_length, self.decode_length, self.decode_mode
             ))
             .style(
                 Style::default()
@@ -239,7 +241,6 @@ impl App {
 
         // Batch tabs
         let titles: Vec<Line> = self
-            .data
             .batch_size
             .iter()
             .map(|b| {
@@ -260,109 +261,203 @@ impl App {
             );
         f.render_widget(tabs, row5[1]);
 
-        // Total progress bar
-        let color = if self.is_error {
-            Color::Red
-        } else {
-            Color::LightGreen
-        };
-        let batch_gauge = progress_gauge(
-            "Total Progress",
-            format!("{} / {}", self.completed_batch, self.data.batch_size.len()),
-            batch_progress,
-            color,
-        );
-        f.render_widget(batch_gauge, top[0]);
-
-        // Batch progress Bar
-        let color = if self.is_error {
-            Color::Red
-        } else {
-            Color::LightBlue
-        };
-        let run_gauge = progress_gauge(
-            "Batch Progress",
-            format!(
-                "{} / {}",
-                self.completed_runs[self.current_batch], self.n_run
-            ),
-            run_progress,
-            color,
-        );
-        f.render_widget(run_gauge, top[1]);
-
-        // Prefill text infos
-        let prefill_latency_block = latency_paragraph(
-            &mut self.data.prefill_latencies[self.current_tab],
-            "Prefill",
-        );
-        let prefill_throughput_block =
-            throughput_paragraph(&self.data.prefill_throughputs[self.current_tab], "Prefill");
-
-        f.render_widget(prefill_latency_block, prefill_text[0]);
-        f.render_widget(prefill_throughput_block, prefill_text[1]);
-
-        // Prefill latency histogram
-        let histo_width = 7;
-        let bins = if mid[1].width < 2 {
-            0
-        } else {
-            (mid[1].width as usize - 2) / (histo_width + 1)
+        match self.targets.as_mut_slice() {
+            [target] => {
+                render_target(f, target, self.current_tab, self.n_run, &top, row5[3]);
+
+                // Prefill latency/throughput chart
+                let prefill_latency_throughput_chart = latency_throughput_chart(
+                    batch_series(
+                        &target.data.prefill_batch_latency_throughput,
+                        &self.batch_size,
+                    ),
+                    self.zoom,
+                    "Prefill",
+                );
+                f.render_widget(prefill_latency_throughput_chart, bottom[0]);
+
+                // Decode latency/throughput chart
+                let decode_latency_throughput_chart = latency_throughput_chart(
+                    batch_series(
+                        &target.data.decode_batch_latency_throughput,
+                        &self.batch_size,
+                    ),
+                    self.zoom,
+                    "Decode",
+                );
+                f.render_widget(decode_latency_throughput_chart, bottom[1]);
+            }
+            targets => {
+                let colors = [Color::LightCyan, Color::LightMagenta];
+                for (i, target) in targets.iter().enumerate() {
+                    let gauge = target_gauge(target, self.n_run, colors[i % colors.len()]);
+                    f.render_widget(gauge, top[i % top.len()]);
+                }
+
+                if let [baseline, compared, ..] = &*targets {
+                    let table = comparison_table(baseline, compared, self.current_tab);
+                    f.render_widget(table, row5[3]);
+                }
+
+                // Latency/throughput charts, one series per target
+                let series = |select: fn(&Data) -> &[(f64, f64)]| -> Vec<Series> {
+                    targets
+                        .iter()
+                        .enumerate()
+                        .map(|(i, target)| {
+                            (
+                                target.name.clone(),
+                                colors[i % colors.len()],
+                                select(&target.data),
+                            )
+                        })
+                        .collect()
+                };
+                let prefill_latency_throughput_chart = latency_throughput_chart(
+                    series(|data| &data.prefill_batch_latency_throughput),
+                    self.zoom,
+                    "Prefill",
+                );
+                f.render_widget(prefill_latency_throughput_chart, bottom[0]);
+                let decode_latency_throughput_chart = latency_throughput_chart(
+                    series(|data| &data.decode_batch_latency_throughput),
+                    self.zoom,
+                    "Decode",
+                );
+                f.render_widget(decode_latency_throughput_chart, bottom[1]);
+            }
         }
-        .max(2);
-
-        let histo_data =
-            latency_histogram_data(&self.data.prefill_latencies[self.current_tab], bins);
-        let histo_data_str: Vec<(&str, u64)> =
-            histo_data.iter().map(|(l, v)| (l.as_str(), *v)).collect();
-        let prefill_histogram =
-            latency_histogram(&histo_data_str, "Prefill").bar_width(histo_width as u16);
-        f.render_widget(prefill_histogram, mid[1]);
-
-        // Decode text info
-        let decode_latency_block = latency_paragraph(
-            &mut self.data.decode_latencies[self.current_tab],
-            "Decode Total",
-        );
-        let decode_token_latency_block = latency_paragraph(
-            &mut self.data.decode_token_latencies[self.current_tab],
-            "Decode Token",
-        );
-        let decode_throughput_block =
-            throughput_paragraph(&self.data.decode_throughputs[self.current_tab], "Decode");
-        f.render_widget(decode_latency_block, decode_text_latency[0]);
-        f.render_widget(decode_token_latency_block, decode_text_latency[1]);
-        f.render_widget(decode_throughput_block, decode_text[1]);
-
-        // Decode latency histogram
-        let histo_data =
-            latency_histogram_data(&self.data.decode_latencies[self.current_tab], bins);
-        let histo_data_str: Vec<(&str, u64)> =
-            histo_data.iter().map(|(l, v)| (l.as_str(), *v)).collect();
-        let decode_histogram =
-            latency_histogram(&histo_data_str, "Decode").bar_width(histo_width as u16);
-        f.render_widget(decode_histogram, mid[3]);
-
-        // Prefill latency/throughput chart
-        let prefill_latency_throughput_chart = latency_throughput_chart(
-            &self.data.prefill_batch_latency_throughput,
-            &self.data.batch_size,
-            self.zoom,
-            "Prefill",
-        );
-        f.render_widget(prefill_latency_throughput_chart, bottom[0]);
-
-        // Decode latency/throughput chart
-        let decode_latency_throughput_chart = latency_throughput_chart(
-            &self.data.decode_batch_latency_throughput,
-            &self.data.batch_size,
-            self.zoom,
-            "Decode",
-        );
-        f.render_widget(decode_latency_throughput_chart, bottom[1]);
     }
 }
 
+/// Progress bars and statistics of a single target
+fn render_target(
+    f: &mut Frame,
+    target: &mut Target,
+    current_tab: usize,
+    n_run: usize,
+    top: &[Rect],
+    mid_area: Rect,
+) {
+    let batch_progress =
+        (target.completed_batch as f64 / target.data.batch_size.len() as f64).clamp(0.0, 1.0);
+    let run_progress =
+        (target.completed_runs[target.current_batch] as f64 / n_run as f64).clamp(0.0, 1.0);
+
+    // Mid row horizontal layout
+    let mid = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage(25),
+                Constraint::Percentage(25),
+                Constraint::Percentage(25),
+                Constraint::Percentage(25),
+            ]
+            .as_ref(),
+        )
+        .split(mid_area);
+
+    // Left mid row vertical layout
+    let prefill_text = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(8), Constraint::Length(5)].as_ref())
+        .split(mid[0]);
+
+    // Right mid row vertical layout
+    let decode_text = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(8), Constraint::Length(5)].as_ref())
+        .split(mid[2]);
+    let decode_text_latency = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(decode_text[0]);
+
+    // Total progress bar
+    let color = if target.is_error {
+        Color::Red
+    } else {
+        Color::LightGreen
+    };
+    let batch_gauge = progress_gauge(
+        "Total Progress",
+        format!(
+            "{} / {}",
+            target.completed_batch,
+            target.data.batch_size.len()
+        ),
+        batch_progress,
+        color,
+    );
+    f.render_widget(batch_gauge, top[0]);
+
+    // Batch progress Bar
+    let color = if target.is_error {
+        Color::Red
+    } else {
+        Color::LightBlue
+    };
+    let run_gauge = progress_gauge(
+        "Batch Progress",
+        format!(
+            "{} / {}",
+            target.completed_runs[target.current_batch], n_run
+        ),
+        run_progress,
+        color,
+    );
+    f.render_widget(run_gauge, top[1]);
+
+    let data = &mut target.data;
+
+    // Prefill text infos
+    let prefill_latency_block =
+        latency_paragraph(&mut data.prefill_latencies[current_tab], "Prefill");
+    let prefill_throughput_block =
+        throughput_paragraph(&data.prefill_throughputs[current_tab], "Prefill");
+
+    f.render_widget(prefill_latency_block, prefill_text[0]);
+    f.render_widget(prefill_throughput_block, prefill_text[1]);
+
+    // Prefill latency histogram
+    let histo_width = 7;
+    let bins = if mid[1].width < 2 {
+        0
+    } else {
+        (mid[1].width as usize - 2) / (histo_width + 1)
+    }
+    .max(2);
+
+    let histo_data = latency_histogram_data(&data.prefill_latencies[current_tab], bins);
+    let histo_data_str: Vec<(&str, u64)> =
+        histo_data.iter().map(|(l, v)| (l.as_str(), *v)).collect();
+    let prefill_histogram =
+        latency_histogram(&histo_data_str, "Prefill").bar_width(histo_width as u16);
+    f.render_widget(prefill_histogram, mid[1]);
+
+    // Decode text info
+    let decode_latency_block =
+        latency_paragraph(&mut data.decode_latencies[current_tab], "Decode Total");
+    let decode_token_latency_block = latency_paragraph(
+        &mut data.decode_token_latencies[current_tab],
+        "Decode Token",
+    );
+    let decode_throughput_block =
+        throughput_paragraph(&data.decode_throughputs[current_tab], "Decode");
+    f.render_widget(decode_latency_block, decode_text_latency[0]);
+    f.render_widget(decode_token_latency_block, decode_text_latency[1]);
+    f.render_widget(decode_throughput_block, decode_text[1]);
+
+    // Decode latency histogram
+    let histo_data = latency_histogram_data(&data.decode_latencies[current_tab], bins);
+    let histo_data_str: Vec<(&str, u64)> =
+        histo_data.iter().map(|(l, v)| (l.as_str(), *v)).collect();
+    let decode_histogram =
+        latency_histogram(&histo_data_str, "Decode").bar_width(histo_width as u16);
+    f.render_widget(decode_histogram, mid[3]);
+}
+
 /// App internal data struct
 pub(crate) struct Data {
     pub(crate) batch_size: Vec<u32>,
@@ -373,6 +468,9 @@ pub(crate) struct Data {
     pub(crate) decode_throughputs: Vec<Vec<f64>>,
     pub(crate) prefill_batch_latency_throughput: Vec<(f64, f64)>,
     pub(crate) decode_batch_latency_throughput: Vec<(f64, f64)>,
+    /// Resident and GPU memory of the server in MiB, sampled during the runs
+    pub(crate) rss: Vec<Vec<f64>>,
+    pub(crate) vram: Vec<Vec<f64>>,
 }
 
 impl Data {
@@ -386,6 +484,9 @@ impl Data {
         let decode_token_latencies: Vec<Vec<f64>> = decode_latencies.clone();
         let decode_throughputs: Vec<Vec<f64>> = prefill_throughputs.clone();
 
+        let rss: Vec<Vec<f64>> = (0..batch_size.len()).map(|_| Vec::new()).collect();
+        let vram: Vec<Vec<f64>> = rss.clone();
+
         let prefill_batch_latency_throughput: Vec<(f64, f64)> =
             Vec::with_capacity(batch_size.len());
         let decode_batch_latency_throughput: Vec<(f64, f64)> =
@@ -400,6 +501,8 @@ impl Data {
             decode_throughputs,
             prefill_batch_latency_throughput,
             decode_batch_latency_throughput,
+            rss,
+            vram,
         }
     }
 
@@ -417,6 +520,37 @@ impl Data {
         self.decode_throughputs[batch_idx].push(decode.throughput);
     }
 
+    fn push_memory(&mut self, sample: MemorySample, batch_idx: usize) {
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        self.rss[batch_idx].push(mib(sample.rss));
+        if let Some(vram) = sample.vram {
+            self.vram[batch_idx].push(mib(vram));
+        }
+    }
+
+    /// Values of [`COMPARED_STATS`] for a batch size
+    pub(crate) fn compared_stats(&self, batch_idx: usize) -> [f64; 9] {
+        let average = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+        let percentile = |values: &[f64], p: usize| {
+            let mut values = values.to_vec();
+            float_ord::sort(&mut values);
+            *values.get(p * values.len() / 100).unwrap_or(&f64::NAN)
+        };
+        let prefill_latencies = &self.prefill_latencies[batch_idx];
+        let decode_token_latencies = &self.decode_token_latencies[batch_idx];
+        [
+            average(prefill_latencies),
+            percentile(prefill_latencies, 50),
+            percentile(prefill_latencies, 99),
+            average(&self.prefill_throughputs[batch_idx]),
+            average(decode_token_latencies),
+            percentile(decode_token_latencies, 50),
+            percentile(decode_token_latencies, 99),
+            average(&self.decode_latencies[batch_idx]),
+            average(&self.decode_throughputs[batch_idx]),
+        ]
+    }
+
     fn end_batch(&mut self, batch_idx: usize) {
         self.prefill_batch_latency_throughput.push((
             self.prefill_latencies[batch_idx].iter().sum::<f64>()
@@ -433,10 +567,98 @@ impl Data {
     }
 }
 
+/// Statistics compared between two targets, with their unit and whether higher is better
+pub(crate) const COMPARED_STATS: [(&str, &str, bool); 9] = [
+    ("Prefill Latency Average", "ms", false),
+    ("Prefill Latency p50", "ms", false),
+    ("Prefill Latency p99", "ms", false),
+    ("Prefill Throughput Average", "tokens/secs", true),
+    ("Decode Token Latency Average", "ms", false),
+    ("Decode Token Latency p50", "ms", false),
+    ("Decode Token Latency p99", "ms", false),
+    ("Decode Total Latency Average", "ms", false),
+    ("Decode Throughput Average", "tokens/secs", true),
+];
+
+/// Relative difference of `compared` to `baseline`, in percents
+pub(crate) fn delta(baseline: f64, compared: f64) -> f64 {
+    (compared - baseline) / baseline * 100.0
+}
+
+/// Progress bar of a target when comparing targets
+fn target_gauge(target: &Target, n_run: usize, color: Color) -> Gauge {
+    let progress =
+        (target.completed_batch as f64 / target.data.batch_size.len() as f64).clamp(0.0, 1.0);
+    let color = if target.is_error { Color::Red } else { color };
+    progress_gauge(
+        &target.name,
+        format!(
+            "Batch {} / {} | Run {} / {}",
+            target.completed_batch,
+            target.data.batch_size.len(),
+            target.completed_runs[target.current_batch],
+            n_run
+        ),
+        progress,
+        color,
+    )
+}
+
+/// Statistics of two targets side by side for a batch size, with the relative difference of
+/// the second one
+fn comparison_table<'a>(baseline: &Target, compared: &Target, batch_idx: usize) -> Table<'a> {
+    let baseline_stats = baseline.data.compared_stats(batch_idx);
+    let compared_stats = compared.data.compared_stats(batch_idx);
+    let rows = COMPARED_STATS
+        .iter()
+        .enumerate()
+        .map(|(i, (name, unit, higher_is_better))| {
+            let delta = delta(baseline_stats[i], compared_stats[i]);
+            let delta = if delta.is_finite() {
+                let color = if (delta > 0.0) == *higher_is_better {
+                    Color::LightGreen
+                } else {
+                    Color::LightRed
+                };
+                Cell::from(format!("{delta:+.1} %")).style(Style::default().fg(color))
+            } else {
+                Cell::from("-")
+            };
+            Row::new(vec![
+                Cell::from(*name),
+                Cell::from(format!("{:.2} {unit}", baseline_stats[i])),
+                Cell::from(format!("{:.2} {unit}", compared_stats[i])),
+                delta,
+            ])
+        });
+    let header = Row::new(vec![
+        "Statistic".to_string(),
+        baseline.name.clone(),
+        compared.name.clone(),
+        "Delta".to_string(),
+    ])
+    .style(Style::default().add_modifier(Modifier::BOLD));
+    Table::new(
+        rows,
+        [
+            Constraint::Percentage(30),
+            Constraint::Percentage(27),
+            Constraint::Percentage(27),
+            Constraint::Percentage(16),
+        ],
+    )
+    .header(header)
+    .block(Block::default().title("Comparison").borders(Borders::ALL))
+}
+
 /// Progress bar
-fn progress_gauge(title: &str, label: String, progress: f64, color: Color) -> Gauge {
+fn progress_gauge<'a>(title: &str, label: String, progress: f64, color: Color) -> Gauge<'a> {
     Gauge::default()
-        .block(Block::default().title(title).borders(Borders::ALL))
+        .block(
+            Block::default()
+                .title(title.to_string())
+                .borders(Borders::ALL),
+        )
         .gauge_style(Style::default().fg(color))
         .label(Span::raw(label))
         .ratio(progress)
@@ -540,15 +762,32 @@ fn latency_histogram<'a>(
         .data(histo_data_str.as_slice())
 }
 
+/// Named points of a latency/throughput chart
+type Series<'a> = (String, Color, &'a [(f64, f64)]);
+
+/// One series per batch size
+fn batch_series<'a>(latency_throughput: &'a [(f64, f64)], batch_sizes: &[u32]) -> Vec<Series<'a>> {
+    let colors = color_vec();
+    (0..latency_throughput.len())
+        .map(|i| {
+            (
+                batch_sizes[i].to_string(),
+                colors[i % colors.len()],
+                &latency_throughput[i..(i + 1)],
+            )
+        })
+        .collect()
+}
+
 /// Latency/Throughput chart
 fn latency_throughput_chart<'a>(
-    latency_throughput: &'a [(f64, f64)],
-    batch_sizes: &'a [u32],
+    series: Vec<Series<'a>>,
     zoom: bool,
     name: &'static str,
 ) -> Chart<'a> {
-    let latency_iter = latency_throughput.iter().map(|(l, _)| l);
-    let throughput_iter = latency_throughput.iter().map(|(_, t)| t);
+    let points = series.iter().flat_map(|(_, _, points)| points.iter());
+    let latency_iter = points.clone().map(|(l, _)| l);
+    let throughput_iter = points.map(|(_, t)| t);
 
     // Get extreme values
     let min_latency: f64 = *latency_iter
@@ -629,17 +868,15 @@ fn latency_throughput_chart<'a>(
     ));
 
     // Chart dataset
-    let colors = color_vec();
-    let datasets: Vec<Dataset> = (0..latency_throughput.len())
-        .map(|i| {
-            let color_idx = i % colors.len();
-
+    let datasets: Vec<Dataset> = series
+        .into_iter()
+        .map(|(name, color, points)| {
             Dataset::default()
-                .name(batch_sizes[i].to_string())
+                .name(name)
                 .marker(symbols::Marker::Block)
-                .style(Style::default().fg(colors[color_idx]))
+                .style(Style::default().fg(color))
                 .graph_type(GraphType::Scatter)
-                .data(&latency_throughput[i..(i + 1)])
+                .data(points)
         })
         .collect();
 
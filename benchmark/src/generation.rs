@@ -1,12 +1,17 @@
+use crate::memory::{MemorySample, MemorySource};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::time::{Duration, Instant};
 use text_generation_client::v3::{
-    Batch, CachedBatch, NextTokenChooserParameters, Request, ShardedClient,
+    Batch, CachedBatch, Generation, NextTokenChooserParameters, Request, ShardedClient,
     StoppingCriteriaParameters,
 };
 use text_generation_client::{Chunk, ClientError, Input};
 use tokenizers::{Tokenizer, TruncationDirection};
 use tokio::sync::{broadcast, mpsc};
 
+const MEMORY_SAMPLING_INTERVAL: Duration = Duration::from_millis(250);
+
 const LOREM_IPSUM: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat. Duis aute irure dolor in reprehenderit in voluptate velit esse cillum dolore eu fugiat nulla pariatur. Excepteur sint occaecat cupidatat non proident, sunt in culpa qui officia deserunt mollit anim id est laborum.";
 
 #[derive(Debug, Clone)]
@@ -22,11 +27,25 @@ pub(crate) struct Decode {
     pub(crate) throughput: f64,
 }
 
+/// How the generation of each request of a batch ends
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum DecodeMode {
+    /// Generate exactly `decode_length` tokens for every request
+    Fixed,
+    /// Stop at the EOS token of the model, or after `decode_length` tokens
+    Eos,
+    /// Stop after a number of tokens drawn from a geometric distribution of mean
+    /// `mean_decode_length`, as if each token was a stop token with the same probability,
+    /// between 2 and `decode_length` tokens
+    Sampled,
+}
+
 #[derive(Debug)]
 pub(crate) enum Message {
     Warmup,
     Prefill(Prefill),
     Decode(Decode),
+    Memory(MemorySample),
     EndRun,
     EndBatch,
 }
@@ -38,11 +57,14 @@ pub(crate) async fn generation_task(
     batch_size: Vec<u32>,
     sequence_length: u32,
     decode_length: u32,
+    decode_mode: DecodeMode,
+    mean_decode_length: u32,
     top_n_tokens: Option<u32>,
     n_runs: usize,
     warmups: usize,
     parameters: NextTokenChooserParameters,
     client: ShardedClient,
+    memory_source: Option<MemorySource>,
     run_sender: mpsc::Sender<Result<Message, ClientError>>,
     mut shutdown_receiver: broadcast::Receiver<()>,
     _shutdown_guard_sender: mpsc::Sender<()>,
@@ -50,11 +72,13 @@ pub(crate) async fn generation_task(
     // End task if a message is received on shutdown_receiver
     // _shutdown_guard_sender will be dropped once the task is finished
     tokio::select! {
-        res = generate_runs(tokenizer, batch_size, sequence_length, decode_length, top_n_tokens, n_runs, warmups, parameters, client, run_sender.clone())  => {
+        res = generate_runs(tokenizer, batch_size, sequence_length, decode_length, decode_mode, mean_decode_length, top_n_tokens, n_runs, warmups, parameters, client, run_sender.clone())  => {
             if let Err(err) = res {
                 run_sender.send(Err(err)).await.unwrap_or(());
             }
         },
+        // Never ends, sampling stops with the runs
+        _ = sample_memory(memory_source, run_sender.clone()) => {},
         _ = shutdown_receiver.recv() => {}
     }
 }
@@ -66,6 +90,8 @@ async fn generate_runs(
     batch_size: Vec<u32>,
     sequence_length: u32,
     decode_length: u32,
+    decode_mode: DecodeMode,
+    mean_decode_length: u32,
     top_n_tokens: Option<u32>,
     n_runs: usize,
     warmups: usize,
@@ -75,6 +101,8 @@ async fn generate_runs(
 ) -> Result<(), ClientError> {
     // Create a dummy sequence
     let sequence = create_sequence(sequence_length, tokenizer);
+    // Seeded so that compared servers get the same decode lengths
+    let mut rng = StdRng::seed_from_u64(0);
 
     for b in batch_size {
         // Warmups on batch size
@@ -84,6 +112,8 @@ async fn generate_runs(
                 sequence_length,
                 b,
                 decode_length,
+                decode_lengths(&mut rng, decode_mode, b, decode_length, mean_decode_length),
+                decode_mode == DecodeMode::Eos,
                 parameters.clone(),
                 top_n_tokens,
                 &mut client,
@@ -100,6 +130,8 @@ async fn generate_runs(
                 sequence_length,
                 b,
                 decode_length,
+                decode_lengths(&mut rng, decode_mode, b, decode_length, mean_decode_length),
+                decode_mode == DecodeMode::Eos,
                 parameters.clone(),
                 top_n_tokens,
                 &mut client,
@@ -128,20 +160,81 @@ async fn generate_runs(
     Ok(())
 }
 
+/// Maximum number of new tokens of each request of a batch
+fn decode_lengths(
+    rng: &mut StdRng,
+    decode_mode: DecodeMode,
+    batch_size: u32,
+    decode_length: u32,
+    mean_decode_length: u32,
+) -> Vec<u32> {
+    (0..batch_size)
+        .map(|_| match decode_mode {
+            DecodeMode::Fixed | DecodeMode::Eos => decode_length,
+            DecodeMode::Sampled => {
+                // Number of tokens until the first stop token, with a stop probability of
+                // 1 / mean_decode_length per token
+                let stop_probability = 1.0 / mean_decode_length.max(1) as f64;
+                let length = if stop_probability < 1.0 {
+                    let u = 1.0 - rng.gen::<f64>();
+                    1 + (u.ln() / (1.0 - stop_probability).ln()) as u32
+                } else {
+                    1
+                };
+                // The first token is generated by the prefill, keep at least one decode step
+                length.clamp(2, decode_length.max(2))
+            }
+        })
+        .collect()
+}
+
+/// Send the memory usage of the server every `MEMORY_SAMPLING_INTERVAL`
+async fn sample_memory(
+    memory_source: Option<MemorySource>,
+    run_sender: mpsc::Sender<Result<Message, ClientError>>,
+) {
+    let Some(memory_source) = memory_source else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(MEMORY_SAMPLING_INTERVAL);
+    loop {
+        interval.tick().await;
+        let memory_source = memory_source.clone();
+        // Reading /proc and running nvidia-smi block
+        let sample = tokio::task::spawn_blocking(move || memory_source.sample())
+            .await
+            .ok()
+            .flatten();
+        match sample {
+            Some(sample) => run_sender
+                .send(Ok(Message::Memory(sample)))
+                .await
+                .unwrap_or(()),
+            // The server exited
+            None => return std::future::pending().await,
+        }
+    }
+}
+
 // Run a prefill step
+#[allow(clippy::too_many_arguments)]
 async fn prefill(
     sequence: String,
     sequence_length: u32,
     batch_size: u32,
     decode_length: u32,
+    decode_lengths: Vec<u32>,
+    stop_at_eos: bool,
     parameters: NextTokenChooserParameters,
     top_n_tokens: Option<u32>,
     client: &mut ShardedClient,
 ) -> Result<(Prefill, CachedBatch), ClientError> {
     // Create requests
-    let requests = (0..batch_size)
-        .map(|id| Request {
-            id: id.into(),
+    let requests = decode_lengths
+        .into_iter()
+        .enumerate()
+        .map(|(id, max_new_tokens)| Request {
+            id: id as u64,
             prefill_logprobs: false,
             input_chunks: Some(Input {
                 chunks: vec![Chunk::Text(sequence.clone()).into()],
@@ -151,15 +244,17 @@ async fn prefill(
             add_special_tokens: true,
             parameters: Some(parameters.clone()),
             stopping_parameters: Some(StoppingCriteriaParameters {
-                max_new_tokens: decode_length,
+                max_new_tokens,
                 stop_sequences: vec![],
-                ignore_eos_token: true, // Will not stop even if a eos token is generated
+                // Will not stop even if a eos token is generated, unless asked to
+                ignore_eos_token: !stop_at_eos,
             }),
             top_n_tokens: top_n_tokens.unwrap_or(0),
             blocks: vec![],
             slots: vec![],
             cache_len: 0,
             chunk_len: None,
+            input_ids: Vec::new(),
             adapter_id: None,
         })
         .collect();
@@ -174,7 +269,7 @@ async fn prefill(
 
     // Run prefill
     let start_time = Instant::now();
-    let (_, decode_batch, _) = client.prefill(batch.clone(), None).await?;
+    let (generations, decode_batch, _) = client.prefill(batch.clone(), None).await?;
 
     // Get latency
     let latency = start_time.elapsed();
@@ -182,8 +277,11 @@ async fn prefill(
     // Compute throughput from latency and batch size
     let throughput = batch_size as f64 / latency.as_secs_f64();
 
-    // Decode batch cannot be empty
-    let decode_batch = decode_batch.expect("decode_batch is None. This is a bug.");
+    // Decode batch cannot be empty, requests generate at least two tokens unless they stop at
+    // the EOS token
+    let decode_batch = filter_finished(&generations, decode_batch, client)
+        .await?
+        .expect("decode_batch is None. All the requests stopped at the first token.");
 
     let step = Prefill {
         latency,
@@ -196,24 +294,26 @@ async fn prefill(
 /// Run a full decode
 async fn decode(batch: CachedBatch, client: &mut ShardedClient) -> Result<Decode, ClientError> {
     let mut decode_length = 0;
-    let batch_size = batch.size;
+    let mut generated_tokens = 0;
 
     let start_time = Instant::now();
 
-    // Full decode over decode length
+    // Full decode until all the requests are finished
     let mut next_batch = Some(batch);
     while let Some(batch) = next_batch {
-        let result = client.decode(vec![batch]).await?;
-        next_batch = result.1;
+        let (generations, batch, _) = client.decode(vec![batch]).await?;
         decode_length += 1;
+        generated_tokens += generations.len() as u32;
+
+        next_batch = filter_finished(&generations, batch, client).await?;
     }
 
     // Get latency
     let latency = start_time.elapsed();
     let token_latency = latency / decode_length;
 
-    // Compute throughput from latency, batch size and decode length
-    let throughput = (batch_size * decode_length) as f64 / latency.as_secs_f64();
+    // Compute throughput from latency and generated tokens
+    let throughput = generated_tokens as f64 / latency.as_secs_f64();
 
     let step = Decode {
         latency,
@@ -223,6 +323,30 @@ async fn decode(batch: CachedBatch, client: &mut ShardedClient) -> Result<Decode
     Ok(step)
 }
 
+/// Remove the finished requests from the batch, as the router does
+async fn filter_finished(
+    generations: &[Generation],
+    batch: Option<CachedBatch>,
+    client: &mut ShardedClient,
+) -> Result<Option<CachedBatch>, ClientError> {
+    let finished: Vec<u64> = generations
+        .iter()
+        .filter(|g| g.generated_text.is_some())
+        .map(|g| g.request_id)
+        .collect();
+    match batch {
+        Some(batch) if !finished.is_empty() => {
+            let request_ids = batch
+                .request_ids
+                .into_iter()
+                .filter(|id| !finished.contains(id))
+                .collect();
+            client.filter_batch(batch.id, request_ids).await
+        }
+        batch => Ok(batch),
+    }
+}
+
 /// Create a dummy sequence of the correct length
 fn create_sequence(sequence_length: u32, tokenizer: Tokenizer) -> String {
     let lorem_ipsum_length = tokenizer.encode(LOREM_IPSUM, true).unwrap().len();
@@ -1,20 +1,33 @@
 mod app;
 mod event;
 mod generation;
+mod memory;
+mod report;
 mod table;
 mod utils;
 
-use crate::app::App;
+use crate::app::{App, Target};
 use crate::event::Event;
+pub use crate::generation::DecodeMode;
+use crate::memory::MemorySource;
+pub use crate::report::{BatchReport, Report, TargetReport, Thresholds};
 use ratatui::backend::CrosstermBackend;
 use ratatui::crossterm::ExecutableCommand;
 use ratatui::Terminal;
 use std::io;
+use std::time::Duration;
 use text_generation_client::v3::{GrammarType, NextTokenChooserParameters, ShardedClient};
 use tokenizers::Tokenizer;
 use tokio::sync::{broadcast, mpsc};
 
 /// Run benchmarking app
+///
+/// With several `clients`, the same workload runs on all of them at the same time, and the
+/// statistics of the second one are compared to the first one. Clients are named by the path
+/// of their unix socket, used to sample the memory of the server when it runs on this machine.
+///
+/// When `headless`, the benchmark runs without the TUI until all the batch sizes ran. The
+/// results are printed as tables and returned.
 #[allow(clippy::too_many_arguments)]
 pub async fn run(
     tokenizer_name: String,
@@ -22,6 +35,8 @@ pub async fn run(
     batch_size: Vec<u32>,
     sequence_length: u32,
     decode_length: u32,
+    decode_mode: DecodeMode,
+    mean_decode_length: u32,
     top_n_tokens: Option<u32>,
     n_runs: usize,
     warmups: usize,
@@ -33,8 +48,9 @@ pub async fn run(
     frequency_penalty: Option<f32>,
     watermark: bool,
     do_sample: bool,
-    client: ShardedClient,
-) -> Result<(), std::io::Error> {
+    clients: Vec<(String, ShardedClient)>,
+    headless: bool,
+) -> Result<Report, std::io::Error> {
     let parameters = NextTokenChooserParameters {
         temperature: temperature.unwrap_or(1.0),
         top_k: top_k.unwrap_or(0),
@@ -47,21 +63,23 @@ pub async fn run(
         watermark,
         grammar: String::new(),
         grammar_type: GrammarType::None as i32,
+        allowed_token_ids: vec![],
+        banned_token_ids: vec![],
     };
 
-    // Initialize terminal properties
-    ratatui::crossterm::terminal::enable_raw_mode()?;
-    io::stdout().execute(ratatui::crossterm::terminal::EnterAlternateScreen)?;
-    io::stdout().execute(ratatui::crossterm::cursor::Hide)?;
-
     // Initialize terminal
-    let mut terminal = {
+    let mut terminal = if headless {
+        None
+    } else {
+        // Initialize terminal properties
+        ratatui::crossterm::terminal::enable_raw_mode()?;
+        io::stdout().execute(ratatui::crossterm::terminal::EnterAlternateScreen)?;
+        io::stdout().execute(ratatui::crossterm::cursor::Hide)?;
+
         let backend = CrosstermBackend::new(io::stdout());
-        Terminal::new(backend)?
+        Some(Terminal::new(backend)?)
     };
 
-    // Create message channel between generation_task and app
-    let (run_sender, run_receiver) = mpsc::channel(8);
     // Crossterm event channel
     let (event_sender, mut event_receiver) = mpsc::channel(8);
     // Shutdown channel to terminate tasks
@@ -69,56 +87,90 @@ pub async fn run(
     // Channel to check if tasks terminated
     let (shutdown_guard_sender, mut shutdown_guard_receiver) = mpsc::channel(1);
 
-    // Create generation task
-    tokio::spawn(generation::generation_task(
-        tokenizer,
-        batch_size.clone(),
-        sequence_length,
-        decode_length,
-        top_n_tokens,
-        n_runs,
-        warmups,
-        parameters,
-        client,
-        run_sender,
-        shutdown_sender.subscribe(),
-        shutdown_guard_sender.clone(),
-    ));
+    // Create a generation task per client
+    let targets = clients
+        .into_iter()
+        .map(|(name, client)| {
+            // Create message channel between generation_task and app
+            let (run_sender, run_receiver) = mpsc::channel(8);
+            let memory_source = MemorySource::find(&name);
+            if memory_source.is_none() {
+                tracing::warn!(
+                    "Could not find the server process of {name}, memory will not be sampled"
+                );
+            }
+            tokio::spawn(generation::generation_task(
+                tokenizer.clone(),
+                batch_size.clone(),
+                sequence_length,
+                decode_length,
+                decode_mode,
+                mean_decode_length,
+                top_n_tokens,
+                n_runs,
+                warmups,
+                parameters.clone(),
+                client,
+                memory_source,
+                run_sender,
+                shutdown_sender.subscribe(),
+                shutdown_guard_sender.clone(),
+            ));
+            Target::new(name, run_receiver, n_runs, batch_size.clone())
+        })
+        .collect();
 
     // Create event task
-    tokio::spawn(event::terminal_event_task(
-        250,
-        event_sender,
-        shutdown_sender.subscribe(),
-        shutdown_guard_sender.clone(),
-    ));
+    if !headless {
+        tokio::spawn(event::terminal_event_task(
+            250,
+            event_sender,
+            shutdown_sender.subscribe(),
+            shutdown_guard_sender.clone(),
+        ));
+    }
 
     // Drop our end of shutdown sender
     drop(shutdown_guard_sender);
 
     // Create App
     let mut app = App::new(
-        run_receiver,
+        targets,
         tokenizer_name.clone(),
         sequence_length,
         decode_length,
+        decode_mode,
         n_runs,
         batch_size,
     );
 
-    while app.running {
-        // Draw frame
-        terminal.draw(|frame| app.render(frame))?;
-
-        // Await a new event from event handling task
-        match event_receiver.recv().await {
-            None => break,
-            // Update app state
-            Some(event) => match event {
-                Event::Tick => app.tick(),
-                Event::Key(key_event) => app.handle_key_event(key_event),
-                _ => {}
-            },
+    match terminal.as_mut() {
+        Some(terminal) => {
+            while app.running {
+                // Draw frame
+                terminal.draw(|frame| app.render(frame))?;
+
+                // Await a new event from event handling task
+                match event_receiver.recv().await {
+                    None => break,
+                    // Update app state
+                    Some(event) => match event {
+                        Event::Tick => app.tick(),
+                        Event::Key(key_event) => app.handle_key_event(key_event),
+                        _ => {}
+                    },
+                }
+            }
+        }
+        None => {
+            // Run until all targets finished or Ctrl-C
+            let mut interval = tokio::time::interval(Duration::from_millis(250));
+            while !app.targets.iter().all(|target| target.finished()) {
+                tokio::select! {
+                    _ = interval.tick() => app.tick(),
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
         }
     }
 
@@ -127,15 +179,19 @@ pub async fn run(
     // Wait for tasks to shutdown
     let _ = shutdown_guard_receiver.recv().await;
 
-    // Revert terminal to original view
-    io::stdout().execute(ratatui::crossterm::terminal::LeaveAlternateScreen)?;
-    ratatui::crossterm::terminal::disable_raw_mode()?;
-    io::stdout().execute(ratatui::crossterm::cursor::Show)?;
+    if terminal.is_some() {
+        // Revert terminal to original view
+        io::stdout().execute(ratatui::crossterm::terminal::LeaveAlternateScreen)?;
+        ratatui::crossterm::terminal::disable_raw_mode()?;
+        io::stdout().execute(ratatui::crossterm::cursor::Show)?;
+    }
 
     let parameters_table = table::parameters_table(
         tokenizer_name,
         sequence_length,
         decode_length,
+        decode_mode,
+        mean_decode_length,
         top_n_tokens,
         n_runs,
         warmups,
@@ -150,11 +206,31 @@ pub async fn run(
     );
     println!("\n{parameters_table}\n");
 
-    let latency_table = table::latency_table(&app.data);
-    println!("\n{latency_table}\n");
+    let comparing = app.targets.len() > 1;
+    for target in &app.targets {
+        if comparing {
+            println!("\n{}\n", target.name);
+        }
+
+        let latency_table = table::latency_table(&target.data);
+        println!("\n{latency_table}\n");
 
-    let throughput_table = table::throughput_table(&app.data);
-    println!("\n{throughput_table}\n");
+        let throughput_table = table::throughput_table(&target.data);
+        println!("\n{throughput_table}\n");
+
+        let memory_table = table::memory_table(&target.data);
+        println!("\n{memory_table}\n");
+    }
+
+    if let [baseline, compared, ..] = app.targets.as_slice() {
+        let comparison_table = table::comparison_table(
+            &baseline.name,
+            &baseline.data,
+            &compared.name,
+            &compared.data,
+        );
+        println!("\n{comparison_table}\n");
+    }
 
-    Ok(())
+    Ok(Report::new(&app.targets))
 }
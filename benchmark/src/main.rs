@@ -4,6 +4,7 @@
 /// and: https://github.com/orhun/rust-tui-template
 use clap::Parser;
 use std::path::Path;
+use text_generation_benchmark::{DecodeMode, Thresholds};
 use text_generation_client::v3::ShardedClient;
 use tokenizers::{FromPretrainedParameters, Tokenizer};
 use tracing_subscriber::layer::SubscriberExt;
@@ -46,6 +47,18 @@ struct Args {
     #[clap(default_value = "8", short, long, env)]
     decode_length: u32,
 
+    /// How the generation of the requests ends. `fixed` generates `decode_length` tokens for
+    /// every request, `eos` stops at the EOS token of the model, and `sampled` stops after a
+    /// random number of tokens, to match the finish behavior of production traffic rather than
+    /// always hitting `max_new_tokens`
+    #[clap(default_value = "fixed", long, env, value_enum)]
+    decode_mode: DecodeMode,
+
+    /// The mean number of generated tokens of the `sampled` decode mode, half of
+    /// `decode_length` by default
+    #[clap(long, env)]
+    mean_decode_length: Option<u32>,
+
     ///How many runs should we average from
     #[clap(default_value = "10", short, long, env)]
     runs: usize,
@@ -59,6 +72,12 @@ struct Args {
     #[clap(default_value = "/tmp/text-generation-server-0", short, long, env)]
     master_shard_uds_path: String,
 
+    /// The location of the grpc socket of a second server to compare with the first one.
+    /// Both servers are benchmarked at the same time with the same workload, and their
+    /// statistics are displayed side by side
+    #[clap(long, env)]
+    compare_shard_uds_path: Option<String>,
+
     /// Generation parameter in case you want to specifically test/debug particular
     /// decoding strategies, for full doc refer to the `text-generation-server`
     #[clap(long, env)]
@@ -103,6 +122,31 @@ struct Args {
     /// decoding strategies, for full doc refer to the `text-generation-server`
     #[clap(long, env)]
     top_n_tokens: Option<u32>,
+
+    /// Run without the TUI until all the batch sizes ran, and print the results. Combined with
+    /// the thresholds below, the exit code tells whether the results are acceptable, to run the
+    /// benchmark in automation
+    #[clap(long, env)]
+    headless: bool,
+
+    /// Write the results as JSON to this path
+    #[clap(long, env)]
+    report_path: Option<String>,
+
+    /// Exit with an error if the p99 prefill latency of a batch size is above this value,
+    /// in milliseconds
+    #[clap(long, env)]
+    max_prefill_latency: Option<f64>,
+
+    /// Exit with an error if the p99 decode token latency of a batch size is above this value,
+    /// in milliseconds
+    #[clap(long, env)]
+    max_decode_token_latency: Option<f64>,
+
+    /// Exit with an error if the average decode throughput of a batch size is below this value,
+    /// in tokens per second
+    #[clap(long, env)]
+    min_decode_throughput: Option<f64>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -117,6 +161,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         batch_size,
         sequence_length,
         decode_length,
+        decode_mode,
+        mean_decode_length,
         runs,
         warmups,
         temperature,
@@ -128,8 +174,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         watermark,
         do_sample,
         master_shard_uds_path,
+        compare_shard_uds_path,
         top_n_tokens,
+        headless,
+        report_path,
+        max_prefill_latency,
+        max_decode_token_latency,
+        min_decode_throughput,
     } = args;
+    let thresholds = Thresholds {
+        max_prefill_latency,
+        max_decode_token_latency,
+        min_decode_throughput,
+    };
 
     let batch_size = batch_size.unwrap_or(vec![1, 2, 4, 8, 16, 32]);
 
@@ -163,21 +220,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing::info!("Tokenizer loaded");
 
     // Launch Tokio runtime
-    tokio::runtime::Builder::new_multi_thread()
+    let report = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async {
-            // Instantiate sharded client from the master unix socket
-            tracing::info!("Connect to model server");
-            let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path)
-                .await
-                .expect("Could not connect to server");
-            // Clear the cache; useful if the webserver rebooted
-            sharded_client
-                .clear_cache(None)
-                .await
-                .expect("Unable to clear cache");
+            let mut clients = Vec::new();
+            for uds_path in std::iter::once(master_shard_uds_path).chain(compare_shard_uds_path) {
+                // Instantiate sharded client from the master unix socket
+                tracing::info!("Connect to model server at {uds_path}");
+                let mut sharded_client = ShardedClient::connect_uds(uds_path.clone())
+                    .await
+                    .expect("Could not connect to server");
+                // Clear the cache; useful if the webserver rebooted
+                sharded_client
+                    .clear_cache(None)
+                    .await
+                    .expect("Unable to clear cache");
+                clients.push((uds_path, sharded_client));
+            }
 
             tracing::info!("Connected");
 
@@ -188,6 +249,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 batch_size,
                 sequence_length,
                 decode_length,
+                decode_mode,
+                mean_decode_length.unwrap_or(decode_length / 2),
                 top_n_tokens,
                 runs,
                 warmups,
@@ -199,11 +262,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 frequency_penalty,
                 watermark,
                 do_sample,
-                sharded_client,
+                clients,
+                headless,
             )
             .await
-            .unwrap();
+            .unwrap()
         });
+
+    if let Some(report_path) = report_path {
+        std::fs::write(report_path, serde_json::to_string_pretty(&report)?)?;
+    }
+
+    let violations = thresholds.violations(&report);
+    if !violations.is_empty() {
+        for violation in violations {
+            eprintln!("{violation}");
+        }
+        std::process::exit(1);
+    }
     Ok(())
 }
 
@@ -0,0 +1,92 @@
+/// Memory usage of the benchmarked server, sampled from outside of it
+///
+/// The server is found from its unix socket in `/proc`, so the memory is the one of the
+/// master shard process, on the same machine as the benchmark.
+use std::path::PathBuf;
+use std::process::Command;
+
+#[derive(Debug, Clone)]
+pub(crate) struct MemorySample {
+    /// Resident memory of the process in bytes
+    pub(crate) rss: u64,
+    /// GPU memory of the process in bytes, if `nvidia-smi` is available
+    pub(crate) vram: Option<u64>,
+}
+
+/// Process listening on a unix socket
+#[derive(Debug, Clone)]
+pub(crate) struct MemorySource {
+    pid: u32,
+}
+
+impl MemorySource {
+    /// Find the process listening on `uds_path`, if it runs on this machine and is visible
+    pub(crate) fn find(uds_path: &str) -> Option<Self> {
+        let path = std::fs::canonicalize(uds_path).unwrap_or_else(|_| PathBuf::from(uds_path));
+        let path = path.to_string_lossy();
+
+        // Inode of the listening socket, the last column of /proc/net/unix is its path
+        let sockets = std::fs::read_to_string("/proc/net/unix").ok()?;
+        let inode = sockets.lines().skip(1).find_map(|line| {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            match columns.as_slice() {
+                [.., inode, socket_path] if *socket_path == path => Some(inode.to_string()),
+                _ => None,
+            }
+        })?;
+        let target = format!("socket:[{inode}]");
+
+        // Process holding a file descriptor on this socket
+        std::fs::read_dir("/proc")
+            .ok()?
+            .flatten()
+            .find_map(|entry| {
+                let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+                std::fs::read_dir(entry.path().join("fd"))
+                    .ok()?
+                    .flatten()
+                    .any(|fd| {
+                        std::fs::read_link(fd.path())
+                            .is_ok_and(|link| link.to_string_lossy() == target)
+                    })
+                    .then_some(Self { pid })
+            })
+    }
+
+    /// Current memory usage of the process, `None` once it exited
+    pub(crate) fn sample(&self) -> Option<MemorySample> {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.pid)).ok()?;
+        let rss = status.lines().find_map(|line| {
+            let kb = line.strip_prefix("VmRSS:")?.trim().strip_suffix("kB")?;
+            kb.trim().parse::<u64>().ok()
+        })? * 1024;
+        Some(MemorySample {
+            rss,
+            vram: self.vram(),
+        })
+    }
+
+    fn vram(&self) -> Option<u64> {
+        let output = Command::new("nvidia-smi")
+            .args([
+                "--query-compute-apps=pid,used_memory",
+                "--format=csv,noheader,nounits",
+            ])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        // Memory of the process in MiB, summed over the GPUs it uses
+        let mut used = None;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((pid, memory)) = line.split_once(',') {
+                if pid.trim().parse() == Ok(self.pid) {
+                    let memory: u64 = memory.trim().parse().ok()?;
+                    *used.get_or_insert(0) += memory * 1024 * 1024;
+                }
+            }
+        }
+        used
+    }
+}
@@ -0,0 +1,135 @@
+/// Machine readable results of a benchmark, and the thresholds they are checked against
+use crate::app::{Data, Target};
+use serde::Serialize;
+
+/// Results of every benchmarked server
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub targets: Vec<TargetReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TargetReport {
+    pub name: String,
+    /// Whether the benchmark of this server failed
+    pub error: bool,
+    pub batches: Vec<BatchReport>,
+}
+
+/// Latencies in milliseconds, throughputs in tokens per second, and memory in MiB
+#[derive(Debug, Serialize)]
+pub struct BatchReport {
+    pub batch_size: u32,
+    pub runs: usize,
+    pub prefill_latency_average: f64,
+    pub prefill_latency_p50: f64,
+    pub prefill_latency_p99: f64,
+    pub prefill_throughput_average: f64,
+    pub decode_token_latency_average: f64,
+    pub decode_token_latency_p50: f64,
+    pub decode_token_latency_p99: f64,
+    pub decode_latency_average: f64,
+    pub decode_throughput_average: f64,
+    pub rss_peak: Option<f64>,
+    pub vram_peak: Option<f64>,
+}
+
+impl Report {
+    pub(crate) fn new(targets: &[Target]) -> Self {
+        Self {
+            targets: targets
+                .iter()
+                .map(|target| TargetReport {
+                    name: target.name.clone(),
+                    error: target.is_error,
+                    batches: batch_reports(&target.data),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn batch_reports(data: &Data) -> Vec<BatchReport> {
+    let peak = |samples: &[f64]| samples.iter().copied().reduce(f64::max);
+    data.batch_size
+        .iter()
+        .enumerate()
+        // Batch sizes that did not run
+        .filter(|(i, _)| !data.decode_latencies[*i].is_empty())
+        .map(|(i, batch_size)| {
+            // In the order of `COMPARED_STATS`
+            let stats = data.compared_stats(i);
+            BatchReport {
+                batch_size: *batch_size,
+                runs: data.decode_latencies[i].len(),
+                prefill_latency_average: stats[0],
+                prefill_latency_p50: stats[1],
+                prefill_latency_p99: stats[2],
+                prefill_throughput_average: stats[3],
+                decode_token_latency_average: stats[4],
+                decode_token_latency_p50: stats[5],
+                decode_token_latency_p99: stats[6],
+                decode_latency_average: stats[7],
+                decode_throughput_average: stats[8],
+                rss_peak: peak(&data.rss[i]),
+                vram_peak: peak(&data.vram[i]),
+            }
+        })
+        .collect()
+}
+
+/// Limits a benchmark must respect for every batch size, unset limits are not checked
+#[derive(Debug, Default, Clone)]
+pub struct Thresholds {
+    /// Maximum p99 prefill latency, in milliseconds
+    pub max_prefill_latency: Option<f64>,
+    /// Maximum p99 decode token latency, in milliseconds
+    pub max_decode_token_latency: Option<f64>,
+    /// Minimum average decode throughput, in tokens per second
+    pub min_decode_throughput: Option<f64>,
+}
+
+impl Thresholds {
+    /// Description of every violated threshold, including failed benchmarks
+    pub fn violations(&self, report: &Report) -> Vec<String> {
+        let mut violations = Vec::new();
+        for target in &report.targets {
+            if target.error {
+                violations.push(format!("{}: benchmark failed", target.name));
+            }
+            for batch in &target.batches {
+                let mut check = |name: &str, value: f64, limit: Option<f64>, max: bool| {
+                    if let Some(limit) = limit {
+                        if (max && value > limit) || (!max && value < limit) {
+                            violations.push(format!(
+                                "{}: {name} of batch size {} is {value:.2}, {} {limit:.2}",
+                                target.name,
+                                batch.batch_size,
+                                if max { "above" } else { "below" }
+                            ));
+                        }
+                    }
+                };
+                check(
+                    "prefill latency p99",
+                    batch.prefill_latency_p99,
+                    self.max_prefill_latency,
+                    true,
+                );
+                check(
+                    "decode token latency p99",
+                    batch.decode_token_latency_p99,
+                    self.max_decode_token_latency,
+                    true,
+                );
+                check(
+                    "decode throughput average",
+                    batch.decode_throughput_average,
+                    self.min_decode_throughput,
+                    false,
+                );
+            }
+        }
+        violations
+    }
+}
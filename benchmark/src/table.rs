@@ -1,4 +1,5 @@
-use crate::app::Data;
+use crate::app::{delta, Data, COMPARED_STATS};
+use crate::generation::DecodeMode;
 use tabled::settings::Merge;
 use tabled::{builder::Builder, settings::Style, Table};
 
@@ -7,6 +8,8 @@ pub(crate) fn parameters_table(
     tokenizer_name: String,
     sequence_length: u32,
     decode_length: u32,
+    decode_mode: DecodeMode,
+    mean_decode_length: u32,
     top_n_tokens: Option<u32>,
     n_runs: usize,
     warmups: usize,
@@ -26,6 +29,10 @@ pub(crate) fn parameters_table(
     builder.push_record(["Model", &tokenizer_name]);
     builder.push_record(["Sequence Length", &sequence_length.to_string()]);
     builder.push_record(["Decode Length", &decode_length.to_string()]);
+    builder.push_record(["Decode Mode", &format!("{decode_mode:?}")]);
+    if decode_mode == DecodeMode::Sampled {
+        builder.push_record(["Mean Decode Length", &mean_decode_length.to_string()]);
+    }
     builder.push_record(["Top N Tokens", &format!("{top_n_tokens:?}")]);
     builder.push_record(["N Runs", &n_runs.to_string()]);
     builder.push_record(["Warmups", &warmups.to_string()]);
@@ -104,6 +111,71 @@ pub(crate) fn throughput_table(data: &Data) -> Table {
     table
 }
 
+/// Average and peak memory of the server during the runs of each batch size
+pub(crate) fn memory_table(data: &Data) -> Table {
+    let mut builder = Builder::default();
+
+    builder.set_header(["Memory", "Batch Size", "Average", "Peak"]);
+
+    for (name, samples) in [("RSS", &data.rss), ("VRAM", &data.vram)] {
+        // VRAM is only sampled with nvidia-smi
+        if samples.iter().all(|samples| samples.is_empty()) {
+            continue;
+        }
+        for (samples, b) in samples.iter().zip(&data.batch_size) {
+            let (avg, _, peak) = avg_min_max(samples);
+            let row = [
+                name.to_string(),
+                b.to_string(),
+                format_value(avg, "MiB"),
+                format_value(peak, "MiB"),
+            ];
+            builder.push_record(row);
+        }
+    }
+
+    let mut table = builder.build();
+    table.with(Style::markdown()).with(Merge::vertical());
+    table
+}
+
+/// Statistics of two targets side by side, with the relative difference of the second one
+pub(crate) fn comparison_table(
+    baseline_name: &str,
+    baseline: &Data,
+    compared_name: &str,
+    compared: &Data,
+) -> Table {
+    let mut builder = Builder::default();
+
+    builder.set_header([
+        "Statistic",
+        "Batch Size",
+        baseline_name,
+        compared_name,
+        "Delta",
+    ]);
+
+    for (i, (name, unit, _)) in COMPARED_STATS.iter().enumerate() {
+        for (batch_idx, b) in baseline.batch_size.iter().enumerate() {
+            let baseline_value = baseline.compared_stats(batch_idx)[i];
+            let compared_value = compared.compared_stats(batch_idx)[i];
+            let row = [
+                name.to_string(),
+                b.to_string(),
+                format_value(baseline_value, unit),
+                format_value(compared_value, unit),
+                format!("{:+.1} %", delta(baseline_value, compared_value)),
+            ];
+            builder.push_record(row);
+        }
+    }
+
+    let mut table = builder.build();
+    table.with(Style::markdown()).with(Merge::vertical());
+    table
+}
+
 fn add_latencies(
     builder: &mut Builder,
     step: &'static str,
@@ -26,6 +26,8 @@ def test_incomplete_generation_error():
 def test_overloaded_error():
     payload = {"error_type": "overloaded", "error": "test"}
     assert isinstance(parse_error(400, payload), OverloadedError)
+    payload = {"error_type": "overloaded_user", "error": "test"}
+    assert isinstance(parse_error(429, payload), OverloadedError)
 
 
 def test_validation_error():
@@ -33,6 +35,20 @@ def test_validation_error():
     assert isinstance(parse_error(400, payload), ValidationError)
 
 
+def test_nested_validation_error():
+    payload = {
+        "error": {
+            "code": "input_too_long",
+            "type": "validation",
+            "message": "test",
+            "param": "inputs",
+        }
+    }
+    error = parse_error(422, payload)
+    assert isinstance(error, ValidationError)
+    assert str(error) == "test"
+
+
 def test_bad_request_error():
     payload = {"error": "test"}
     assert isinstance(parse_error(400, payload), BadRequestError)
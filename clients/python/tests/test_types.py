@@ -49,8 +49,7 @@ def test_parameters_validation():
         Parameters(top_p=0)
     with pytest.raises(ValidationError):
         Parameters(top_p=-1)
-    with pytest.raises(ValidationError):
-        Parameters(top_p=1)
+    Parameters(top_p=1)
 
     # Test truncate
     Parameters(truncate=1)
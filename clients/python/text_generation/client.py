@@ -179,6 +179,7 @@ class Client:
         tools: Optional[List[Tool]] = None,
         tool_prompt: Optional[str] = None,
         tool_choice: Optional[str] = None,
+        parallel_tool_calls: bool = False,
         stop: Optional[List[str]] = None,
     ):
         """
@@ -222,6 +223,8 @@ class Client:
                 A prompt to be appended before the tools
             tool_choice (`str`):
                 The tool to use
+            parallel_tool_calls (`bool`):
+                Allow the model to call several tools in one turn
             stop (`List[str]`):
                 Stop generating tokens if a member of `stop` is generated
 
@@ -244,6 +247,7 @@ class Client:
             tools=tools,
             tool_prompt=tool_prompt,
             tool_choice=tool_choice,
+            parallel_tool_calls=parallel_tool_calls,
             stop=stop,
         )
         if not stream:
@@ -661,6 +665,7 @@ class AsyncClient:
         tools: Optional[List[Tool]] = None,
         tool_prompt: Optional[str] = None,
         tool_choice: Optional[str] = None,
+        parallel_tool_calls: bool = False,
         stop: Optional[List[str]] = None,
     ) -> Union[ChatComplete, AsyncIterator[ChatCompletionChunk]]:
         """
@@ -704,6 +709,8 @@ class AsyncClient:
                 A prompt to be appended before the tools
             tool_choice (`str`):
                 The tool to use
+            parallel_tool_calls (`bool`):
+                Allow the model to call several tools in one turn
             stop (`List[str]`):
                 Stop generating tokens if a member of `stop` is generated
 
@@ -726,6 +733,7 @@ class AsyncClient:
             tools=tools,
             tool_prompt=tool_prompt,
             tool_choice=tool_choice,
+            parallel_tool_calls=parallel_tool_calls,
             stop=stop,
         )
         if not stream:
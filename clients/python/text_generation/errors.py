@@ -77,6 +77,13 @@ def parse_error(status_code: int, payload: Dict[str, str]) -> Exception:
         Exception: parsed exception
 
     """
+    # Unwrap the `{"error": {"code", "type", "message", "param"}}` payloads
+    if isinstance(payload.get("error"), dict):
+        payload = {
+            "error": payload["error"]["message"],
+            "error_type": payload["error"]["type"],
+        }
+
     # Try to parse a Text Generation Inference error
     message = payload["error"]
     if "error_type" in payload:
@@ -85,7 +92,8 @@ def parse_error(status_code: int, payload: Dict[str, str]) -> Exception:
             return GenerationError(message)
         if error_type == "incomplete_generation":
             return IncompleteGenerationError(message)
-        if error_type == "overloaded":
+        # `overloaded_user` when the caller is over its own concurrency limit
+        if error_type in ("overloaded", "overloaded_server", "overloaded_user"):
             return OverloadedError(message)
         if error_type == "validation":
             return ValidationError(message)
@@ -1,6 +1,6 @@
 from enum import Enum
 from pydantic import BaseModel, field_validator, ConfigDict
-from typing import Optional, List, Union, Any
+from typing import Dict, Optional, List, Union, Any
 
 from text_generation.errors import ValidationError
 
@@ -43,6 +43,8 @@ class Message(BaseModel):
     name: Optional[str] = None
     # Tool calls associated with the chat completion
     tool_calls: Optional[Any] = None
+    # Continue this assistant message, it must be the last message
+    prefix: bool = False
 
 
 class Tool(BaseModel):
@@ -53,21 +55,21 @@ class Tool(BaseModel):
 
 
 class Function(BaseModel):
-    name: Optional[str]
+    name: Optional[str] = None
     arguments: str
 
 
 class ChoiceDeltaToolCall(BaseModel):
     index: int
-    id: str
-    type: str
+    id: Optional[str] = None
+    type: Optional[str] = None
     function: Function
 
 
 class ChoiceDelta(BaseModel):
     role: str
     content: Optional[str] = None
-    tool_calls: Optional[ChoiceDeltaToolCall] = None
+    tool_calls: Optional[List[ChoiceDeltaToolCall]] = None
 
 
 class Choice(BaseModel):
@@ -144,6 +146,8 @@ class ChatRequest(BaseModel):
     top_logprobs: Optional[int] = None
     # Maximum number of tokens to generate
     max_tokens: Optional[int] = None
+    # Maximum number of tokens to generate, takes precedence over `max_tokens`
+    max_completion_tokens: Optional[int] = None
     # Number of chat completion choices to generate
     n: Optional[int] = None
     # Penalty for presence of new tokens
@@ -162,8 +166,16 @@ class ChatRequest(BaseModel):
     tool_prompt: Optional[str] = None
     # Choice of tool to be used
     tool_choice: Optional[str] = None
+    # Allow the model to call several tools in one turn
+    parallel_tool_calls: bool = False
     # Stop generating tokens if a member of `stop` is generated
     stop: Optional[List[str]] = None
+    # Keep the completed conversation for `GET /v1/chat/completions/{id}`
+    store: bool = False
+    # Key-value pairs recorded in the logs and traces of the request
+    metadata: Optional[Dict[str, str]] = None
+    # Identifier of the end user
+    user: Optional[str] = None
 
 
 class ChatCompletionComplete(BaseModel):
@@ -294,8 +306,8 @@ class Parameters(BaseModel):
 
     @field_validator("top_p")
     def valid_top_p(cls, v):
-        if v is not None and (v <= 0 or v >= 1.0):
-            raise ValidationError("`top_p` must be > 0.0 and < 1.0")
+        if v is not None and v <= 0:
+            raise ValidationError("`top_p` must be > 0.0")
         return v
 
     @field_validator("truncate")
@@ -364,6 +376,9 @@ class InputToken(BaseModel):
     # Logprob
     # Optional since the logprob of the first token cannot be computed
     logprob: Optional[float] = None
+    # Character offsets of the token in the inputs, if the inputs are text
+    start: Optional[int] = None
+    stop: Optional[int] = None
 
 
 # Generated tokens
@@ -387,6 +402,10 @@ class FinishReason(str, Enum):
     EndOfSequenceToken = "eos_token"
     # the model generated a text included in `stop_sequences`
     StopSequence = "stop_sequence"
+    # the generation failed after these tokens, with `partial_on_error`
+    Error = "error"
+    # the generation was stuck in a loop, with `stop_on_repetition`
+    Repetition = "repetition"
 
 
 # Additional sequences when using the `best_of` parameter
@@ -408,6 +427,35 @@ class BestOfSequence(BaseModel):
 
 
 # `generate` details
+# Sampling parameters the generation ran with, after validation
+class EffectiveParameters(BaseModel):
+    # The logits are sampled, False for a greedy generation
+    do_sample: bool
+    # 1.0 when the logits are not scaled
+    temperature: float
+    # None when the logits are not restricted to the k most likely tokens
+    top_k: Optional[int] = None
+    # 1.0 when nucleus sampling is disabled
+    top_p: float
+    # 1.0 when typical decoding is disabled
+    typical_p: float
+    repetition_penalty: float
+    frequency_penalty: float
+
+
+class PromptCompression(BaseModel):
+    # Prompt tokens before the compression
+    input_tokens: int
+    # Prompt tokens after the compression
+    compressed_tokens: int
+    # Strategies that changed the prompt, in order
+    strategies: List[str]
+    # Few-shot examples and chat turns removed as duplicates
+    removed_examples: int
+    # Chat messages replaced by a summary
+    summarized_turns: int
+
+
 class Details(BaseModel):
     # Generation finish reason
     finish_reason: FinishReason
@@ -423,6 +471,14 @@ class Details(BaseModel):
     top_tokens: Optional[List[List[Token]]] = None
     # Additional sequences when using the `best_of` parameter
     best_of_sequences: Optional[List[BestOfSequence]] = None
+    # Forward passes of the generation, the prefill included
+    steps: Optional[int] = None
+    # Generated tokens over one per step, accepted from the speculation
+    speculative_tokens_accepted: Optional[int] = None
+    # Sampling parameters the generation ran with
+    effective_parameters: Optional[EffectiveParameters] = None
+    # Compression of the prompt, when it was over the threshold
+    compression: Optional[PromptCompression] = None
 
 
 # `generate` return value
@@ -431,6 +487,8 @@ class Response(BaseModel):
     generated_text: str
     # Generation details
     details: Details
+    # Id of the pinned prompt, with `pin`
+    prefix_id: Optional[str] = None
 
 
 # `generate_stream` details
@@ -441,6 +499,14 @@ class StreamDetails(BaseModel):
     generated_tokens: int
     # Sampling seed if sampling was activated
     seed: Optional[int] = None
+    # Forward passes of the generation, the prefill included
+    steps: Optional[int] = None
+    # Generated tokens over one per step, accepted from the speculation
+    speculative_tokens_accepted: Optional[int] = None
+    # Sampling parameters the generation ran with
+    effective_parameters: Optional[EffectiveParameters] = None
+    # Compression of the prompt, when it was over the threshold
+    compression: Optional[PromptCompression] = None
 
 
 # `generate_stream` return value
@@ -455,6 +521,9 @@ class StreamResponse(BaseModel):
     # Generation details
     # Only available when the generation is finished
     details: Optional[StreamDetails] = None
+    # Id of the pinned prompt, with `pin`
+    # Only available when the generation is finished
+    prefix_id: Optional[str] = None
 
 
 # Inference API currently deployed model
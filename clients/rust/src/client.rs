@@ -0,0 +1,317 @@
+use crate::types::{
+    ChatCompletion, ChatCompletionChunk, ChatRequest, ErrorResponse, GenerateRequest,
+    GenerateResponse, SimpleToken, StreamResponse,
+};
+use crate::ClientError;
+use futures::{Stream, StreamExt};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// Events of a streamed response, ending after the last one
+pub type EventStream<T> = Pin<Box<dyn Stream<Item = Result<T, ClientError>> + Send>>;
+
+/// Client of a Text Generation Inference server
+///
+/// Requests failing to connect or rejected because the server is overloaded are retried
+/// `max_retries` times, with a delay doubling after every attempt. Streamed requests are only
+/// retried until the response starts.
+#[derive(Clone, Debug)]
+pub struct Client {
+    client: reqwest::Client,
+    base_url: String,
+    max_retries: u32,
+    retry_delay: Duration,
+}
+
+impl Client {
+    pub fn new(base_url: &str, api_key: Option<String>) -> Result<Self, ClientError> {
+        let mut headers = HeaderMap::new();
+        if let Some(api_key) = api_key {
+            let mut value = HeaderValue::from_str(&format!("Bearer {api_key}"))?;
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()?;
+        Ok(Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            max_retries: 2,
+            retry_delay: Duration::from_millis(500),
+        })
+    }
+
+    /// Retry the failed requests `max_retries` times, waiting `retry_delay` before the first
+    /// retry
+    pub fn with_retries(mut self, max_retries: u32, retry_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Generate the completion of `request.inputs`
+    pub async fn generate(
+        &self,
+        request: &GenerateRequest,
+    ) -> Result<GenerateResponse, ClientError> {
+        self.post("/generate", request)
+            .await?
+            .json()
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Generate the completion of `request.inputs`, token by token
+    pub async fn generate_stream(
+        &self,
+        request: &GenerateRequest,
+    ) -> Result<EventStream<StreamResponse>, ClientError> {
+        Ok(events(self.post("/generate_stream", request).await?))
+    }
+
+    /// Generate the next message of `request.messages`
+    pub async fn chat(&self, request: &ChatRequest) -> Result<ChatCompletion, ClientError> {
+        let request = ChatRequest {
+            stream: false,
+            ..request.clone()
+        };
+        self.post("/v1/chat/completions", &request)
+            .await?
+            .json()
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Generate the next message of `request.messages`, token by token
+    pub async fn chat_stream(
+        &self,
+        request: &ChatRequest,
+    ) -> Result<EventStream<ChatCompletionChunk>, ClientError> {
+        let request = ChatRequest {
+            stream: true,
+            ..request.clone()
+        };
+        Ok(events(self.post("/v1/chat/completions", &request).await?))
+    }
+
+    /// Tokens of `request.inputs`
+    pub async fn tokenize(
+        &self,
+        request: &GenerateRequest,
+    ) -> Result<Vec<SimpleToken>, ClientError> {
+        self.post("/tokenize", request)
+            .await?
+            .json()
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Send `body` to `path`, retrying when the server is unreachable or overloaded
+    async fn post(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<reqwest::Response, ClientError> {
+        let url = format!("{}{path}", self.base_url);
+        let mut attempt = 0;
+        loop {
+            let result = self.client.post(&url).json(body).send().await;
+            let retryable = match &result {
+                Ok(response) => is_retryable(response.status()),
+                Err(err) => err.is_connect() || err.is_timeout(),
+            };
+            if retryable && attempt < self.max_retries {
+                tokio::time::sleep(self.retry_delay * 2u32.pow(attempt)).await;
+                attempt += 1;
+                continue;
+            }
+
+            let response = result?;
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let body = response.bytes().await?;
+            return Err(match serde_json::from_slice::<ErrorResponse>(&body) {
+                Ok(error) => ClientError::Server {
+                    status: Some(status.as_u16()),
+                    error: error.error,
+                },
+                Err(_) => ClientError::Status {
+                    status: status.as_u16(),
+                    body: String::from_utf8_lossy(&body).into_owned(),
+                },
+            });
+        }
+    }
+}
+
+/// Statuses of the requests the server did not start to handle
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Parse the server-sent events of `response`
+fn events<T: DeserializeOwned + Send + 'static>(response: reqwest::Response) -> EventStream<T> {
+    let state = (response.bytes_stream(), Vec::new(), false);
+    Box::pin(futures::stream::unfold(
+        state,
+        |(mut stream, mut buffer, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                match next_event(&mut buffer) {
+                    Ok(Some(Some(event))) => return Some((Ok(event), (stream, buffer, false))),
+                    // `[DONE]` sentinel
+                    Ok(Some(None)) => return None,
+                    Ok(None) => {}
+                    Err(err) => return Some((Err(err), (stream, buffer, true))),
+                }
+                match stream.next().await {
+                    Some(Ok(bytes)) => buffer.extend_from_slice(&bytes),
+                    Some(Err(err)) => return Some((Err(err.into()), (stream, buffer, true))),
+                    // The server closes the stream after the last event
+                    None => return None,
+                }
+            }
+        },
+    ))
+}
+
+/// Pop the next complete `data:` line from `buffer`
+///
+/// Returns `Ok(Some(None))` for the `[DONE]` sentinel and `Ok(None)` when more bytes are needed.
+/// Error events are returned as [`ClientError::Server`].
+fn next_event<T: DeserializeOwned>(buffer: &mut Vec<u8>) -> Result<Option<Option<T>>, ClientError> {
+    while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = buffer.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&line);
+        let Some(data) = line.trim().strip_prefix("data:") else {
+            // Comments, `event:` lines and event separators
+            continue;
+        };
+        let data = data.trim();
+        if data == "[DONE]" {
+            return Ok(Some(None));
+        }
+        if let Ok(error) = serde_json::from_str::<ErrorResponse>(data) {
+            return Err(ClientError::Server {
+                status: None,
+                error: error.error,
+            });
+        }
+        return serde_json::from_str(data)
+            .map(|event| Some(Some(event)))
+            .map_err(ClientError::InvalidEvent);
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::FinishReason;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_next_event() {
+        let mut buffer = b": ping\n\ndata:{\"index\":1,\"token\":{\"id\":3,\"text\":\"He".to_vec();
+        assert!(next_event::<StreamResponse>(&mut buffer).unwrap().is_none());
+
+        buffer.extend_from_slice(b"llo\",\"logprob\":-0.5,\"special\":false},\"generated_text\":\"Hello\",\"details\":{\"finish_reason\":\"eos_token\",\"generated_tokens\":1,\"seed\":null,\"input_length\":2}}\n\n");
+        let event = next_event::<StreamResponse>(&mut buffer)
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.token.text, "Hello");
+        assert_eq!(
+            event.details.unwrap().finish_reason,
+            FinishReason::EndOfSequenceToken
+        );
+        assert!(next_event::<StreamResponse>(&mut buffer).unwrap().is_none());
+        assert!(buffer.is_empty());
+
+        let mut buffer = b"data: [DONE]\n".to_vec();
+        assert!(next_event::<StreamResponse>(&mut buffer)
+            .unwrap()
+            .unwrap()
+            .is_none());
+
+        let mut buffer = b"data:{\"error\":{\"code\":\"generation_failed\",\"type\":\"generation\",\"message\":\"CUDA OOM\",\"param\":null}}\n".to_vec();
+        assert!(matches!(
+            next_event::<StreamResponse>(&mut buffer),
+            Err(ClientError::Server { status: None, error }) if error.code == "generation_failed"
+        ));
+
+        let mut buffer = b"data: {not json}\n".to_vec();
+        assert!(matches!(
+            next_event::<StreamResponse>(&mut buffer),
+            Err(ClientError::InvalidEvent(_))
+        ));
+    }
+
+    fn response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Answer the requests with the given responses, in order
+    async fn serve(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 4096];
+                let _ = socket.read(&mut request).await.unwrap();
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_client_retries() {
+        let overloaded = response(
+            "429 Too Many Requests",
+            r#"{"error":{"code":"overloaded","type":"overloaded","message":"Model is overloaded"}}"#,
+        );
+        let tokens = response("200 OK", r#"[{"id":1,"text":"Hi","start":0,"stop":2}]"#);
+
+        let url = serve(vec![overloaded.clone(), tokens]).await;
+        let client = Client::new(&url, None)
+            .unwrap()
+            .with_retries(1, Duration::from_millis(1));
+        let tokens = client.tokenize(&GenerateRequest::new("Hi")).await.unwrap();
+        assert_eq!(tokens[0].text, "Hi");
+
+        // Errors are returned once the retries are exhausted
+        let url = serve(vec![overloaded.clone(), overloaded]).await;
+        let client = Client::new(&url, None)
+            .unwrap()
+            .with_retries(1, Duration::from_millis(1));
+        let err = client
+            .tokenize(&GenerateRequest::new("Hi"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ClientError::Server { status: Some(429), error } if error.code == "overloaded"
+        ));
+    }
+}
@@ -0,0 +1,39 @@
+//! Async client of the Text Generation Inference HTTP API
+//!
+//! ```no_run
+//! use text_generation_router_client::{ChatRequest, Client, Message};
+//!
+//! # async fn run() -> Result<(), text_generation_router_client::ClientError> {
+//! let client = Client::new("http://localhost:3000", None)?;
+//! let request = ChatRequest::new(vec![Message::new("user", "What is deep learning?")]);
+//! let completion = client.chat(&request).await?;
+//! println!("{:?}", completion.choices[0].message.content);
+//! # Ok(())
+//! # }
+//! ```
+mod client;
+mod types;
+
+pub use client::{Client, EventStream};
+pub use types::*;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("invalid API key: {0}")]
+    ApiKey(#[from] reqwest::header::InvalidHeaderValue),
+    /// Error returned by the server, `status` is not set for the errors of streams
+    #[error("server error {}: {}", .error.code, .error.message)]
+    Server {
+        status: Option<u16>,
+        error: ErrorDetails,
+    },
+    /// Error response without a JSON body, from a proxy in front of the server for instance
+    #[error("server returned {status}: {body}")]
+    Status { status: u16, body: String },
+    #[error("invalid event: {0}")]
+    InvalidEvent(serde_json::Error),
+}
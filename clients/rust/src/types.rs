@@ -0,0 +1,341 @@
+/// Requests and responses of the HTTP API
+///
+/// Fields the server may add in later versions are ignored, and unset optional request fields
+/// are not sent, so that the server defaults apply.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Body of `/generate`, `/generate_stream` and `/tokenize`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GenerateRequest {
+    pub inputs: String,
+    pub parameters: GenerateParameters,
+}
+
+impl GenerateRequest {
+    pub fn new(inputs: impl Into<String>) -> Self {
+        Self {
+            inputs: inputs.into(),
+            parameters: GenerateParameters::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GenerateParameters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typical_p: Option<f32>,
+    pub do_sample: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_new_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_full_text: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncate: Option<usize>,
+    pub watermark: bool,
+    pub details: bool,
+    pub decoder_input_details: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_n_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grammar: Option<GrammarType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapter_id: Option<String>,
+    /// Named set of default parameters configured on the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum GrammarType {
+    /// JSON schema the generated text must follow
+    Json(serde_json::Value),
+    Regex(String),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Length,
+    #[serde(rename = "eos_token")]
+    EndOfSequenceToken,
+    StopSequence,
+    Error,
+    Repetition,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Token {
+    pub id: u32,
+    pub text: String,
+    /// Not set by backends that do not compute it
+    pub logprob: Option<f32>,
+    pub special: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PrefillToken {
+    pub id: u32,
+    pub text: String,
+    /// Not set for the first token
+    pub logprob: Option<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Details {
+    pub finish_reason: FinishReason,
+    pub generated_tokens: u32,
+    pub seed: Option<u64>,
+    pub prefill: Vec<PrefillToken>,
+    pub tokens: Vec<Token>,
+    #[serde(default)]
+    pub top_tokens: Vec<Vec<Token>>,
+}
+
+/// Response of `/generate`
+#[derive(Clone, Debug, Deserialize)]
+pub struct GenerateResponse {
+    pub generated_text: String,
+    pub details: Option<Details>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StreamDetails {
+    pub finish_reason: FinishReason,
+    pub generated_tokens: u32,
+    pub seed: Option<u64>,
+    pub input_length: u32,
+}
+
+/// Event of `/generate_stream`, the last one has the `generated_text` and `details`
+#[derive(Clone, Debug, Deserialize)]
+pub struct StreamResponse {
+    pub index: u32,
+    pub token: Token,
+    #[serde(default)]
+    pub top_tokens: Vec<Token>,
+    pub generated_text: Option<String>,
+    pub details: Option<StreamDetails>,
+}
+
+/// Token of `/tokenize`, with its byte offsets in the inputs
+#[derive(Clone, Debug, Deserialize)]
+pub struct SimpleToken {
+    pub id: u32,
+    pub text: String,
+    pub start: usize,
+    pub stop: usize,
+}
+
+/// Body of `/v1/chat/completions`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChatRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+    /// Tools and tool choice as defined by the OpenAI API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<GrammarType>,
+    /// Set by [`Client::chat_stream`](crate::Client::chat_stream)
+    pub stream: bool,
+    /// Keep the completion on the server, to retrieve it by its request id
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub store: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl ChatRequest {
+    pub fn new(messages: Vec<Message>) -> Self {
+        Self {
+            messages,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub role: String,
+    pub content: MessageContent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Message {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: MessageContent::SingleText(content.into()),
+            name: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    SingleText(String),
+    MultipleChunks(Vec<MessageChunk>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageChunk {
+    Text { text: String },
+    ImageUrl { image_url: Url },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Url {
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// Arguments of the call, as a JSON value
+    pub arguments: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub r#type: String,
+    pub function: FunctionCall,
+}
+
+/// Message of a chat completion, with either a `content` or `tool_calls`
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatCompletionChoice {
+    pub index: u32,
+    pub message: ChatMessage,
+    pub finish_reason: String,
+}
+
+/// Response of `/v1/chat/completions`
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatCompletion {
+    pub id: String,
+    pub created: u64,
+    pub model: String,
+    pub system_fingerprint: String,
+    pub choices: Vec<ChatCompletionChoice>,
+    pub usage: Usage,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeltaFunctionCall {
+    /// Set in the first delta of the call
+    pub name: Option<String>,
+    /// Part of the JSON arguments of the call
+    pub arguments: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeltaToolCall {
+    pub index: u32,
+    /// Set in the first delta of the call
+    pub id: Option<String>,
+    pub function: DeltaFunctionCall,
+}
+
+/// Part of the message of a chat completion, with either a `content` or `tool_calls`
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatCompletionDelta {
+    pub role: String,
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<DeltaToolCall>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatCompletionChunkChoice {
+    pub index: u32,
+    pub delta: ChatCompletionDelta,
+    pub finish_reason: Option<String>,
+}
+
+/// Event of a streamed `/v1/chat/completions`
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub created: u64,
+    pub model: String,
+    pub system_fingerprint: String,
+    pub choices: Vec<ChatCompletionChunkChoice>,
+    pub usage: Option<Usage>,
+}
+
+/// Error returned by the server, as the body of an error response or as a stream event
+#[derive(Clone, Debug, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorDetails,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ErrorDetails {
+    /// Stable machine-readable code of the error
+    pub code: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub message: String,
+    /// Request parameter that failed validation
+    pub param: Option<String>,
+}
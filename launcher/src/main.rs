@@ -347,6 +347,77 @@ impl std::fmt::Display for RopeScaling {
     }
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SchedulingPolicy {
+    /// Arrival order. A request that does not fit the batch blocks the requests behind it
+    Fifo,
+    /// Shortest prompts first. Long prompts wait for as long as shorter requests keep arriving
+    ShortestPrefillFirst,
+    /// Arrival order, but requests that do not fit the batch are skipped for the next ones
+    LongestWaitFirst,
+}
+
+impl std::fmt::Display for SchedulingPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // To keep in track with `router`.
+        match self {
+            SchedulingPolicy::Fifo => {
+                write!(f, "fifo")
+            }
+            SchedulingPolicy::ShortestPrefillFirst => {
+                write!(f, "shortest-prefill-first")
+            }
+            SchedulingPolicy::LongestWaitFirst => {
+                write!(f, "longest-wait-first")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SloAction {
+    /// Reject the query with a 429 reporting the estimated time to first token
+    Reject,
+    /// Queue the query behind all the queries within the objective
+    Downgrade,
+}
+
+impl std::fmt::Display for SloAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // To keep in track with `router`.
+        match self {
+            SloAction::Reject => {
+                write!(f, "reject")
+            }
+            SloAction::Downgrade => {
+                write!(f, "downgrade")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TraceDetail {
+    /// Spans of the request, its time in the queue and the batches it joins
+    Request,
+    /// Also a span per request for every decode step and every generated token
+    Token,
+}
+
+impl std::fmt::Display for TraceDetail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // To keep in track with `router`.
+        match self {
+            TraceDetail::Request => {
+                write!(f, "request")
+            }
+            TraceDetail::Token => {
+                write!(f, "token")
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, ValueEnum)]
 pub enum UsageStatsLevel {
     /// Default option, usage statistics are collected anonymously
@@ -448,7 +519,8 @@ struct Args {
 
     /// This is the maximum allowed value for clients to set `best_of`.
     /// Best of makes `n` generations at the same time, and return the best
-    /// in terms of overall log probability over the entire generated sequence
+    /// in terms of overall log probability over the entire generated sequence.
+    /// Each of the `n` generations counts as one of the `max_concurrent_requests`.
     #[clap(default_value = "2", long, env)]
     max_best_of: usize,
 
@@ -557,6 +629,61 @@ struct Args {
     #[clap(long, env)]
     max_batch_size: Option<usize>,
 
+    /// Order in which the waiting queries are added to the batch.
+    ///
+    /// With `fifo`, a long prompt that does not fit the remaining budget delays all the
+    /// queries behind it. `shortest-prefill-first` batches the shortest prompts first and
+    /// `longest-wait-first` skips the queries that do not fit, which both reduce this
+    /// head-of-line blocking on mixed workloads.
+    #[clap(default_value = "fifo", long, env, value_enum)]
+    scheduling_policy: SchedulingPolicy,
+
+    /// Raise the priority of the waiting queries with `shortest-prefill-first`, by taking
+    /// this number of prompt tokens per second of waiting off their position in the queue.
+    ///
+    /// Without aging, a long prompt waits for as long as shorter queries keep arriving.
+    #[clap(long, env)]
+    priority_aging_rate: Option<f32>,
+
+    /// The maximum number of prompt tokens taken off the position of a waiting query by
+    /// `priority_aging_rate`. Long prompts reach the front of the queue only if it is at
+    /// least their length.
+    #[clap(default_value = "4096", long, env)]
+    priority_max_boost: u32,
+
+    /// Admit waiting queries as soon as sequences of the running batch finish.
+    ///
+    /// The budget of a running query is projected up to its `max_new_tokens`. Queries
+    /// stopping early on a stop sequence or the end of sequence token free this budget, but
+    /// the waiting queries only join the batch once `waiting_served_ratio` or
+    /// `max_waiting_tokens` allow it. With this flag, they are admitted on the next step,
+    /// which improves the utilization on bursty traffic with many early stops.
+    ///
+    /// Has no effect on models supporting prefill chunking, which admit queries on every step.
+    #[clap(long, env)]
+    eager_admission: bool,
+
+    /// Objective on the time to first token of the new queries, in milliseconds.
+    ///
+    /// The time to first token of a query is estimated from the prompt tokens queued ahead of
+    /// it and the measured prefill and decode latencies. The queries over the objective are
+    /// handled according to `ttft_slo_action` instead of waiting in a queue they would not
+    /// leave in time.
+    #[clap(long, env)]
+    ttft_slo_ms: Option<u64>,
+
+    /// What happens to the queries whose estimated time to first token is over `ttft_slo_ms`.
+    #[clap(default_value = "reject", long, env, value_enum)]
+    ttft_slo_action: SloAction,
+
+    /// Fail the batches whose forward on the shards took longer than this number of seconds.
+    ///
+    /// A hung shard never answers the router, which would stall all the queries. Their
+    /// batches are instead failed after this timeout, the router reports itself unhealthy and
+    /// moves on to the next batch. Use "0" to disable.
+    #[clap(default_value = "120", long, env)]
+    batch_timeout_secs: u64,
+
     /// Specify the batch sizes to compute cuda graphs for.
     /// Use "0" to disable.
     /// Default = "1,2,4,8,16,32"
@@ -636,6 +763,19 @@ struct Args {
     #[clap(default_value = "text-generation-inference.router", long, env)]
     otlp_service_name: String,
 
+    /// Share of the router traces exported to the Open Telemetry collector, between 0 and 1.
+    /// The decision is taken per trace id.
+    #[clap(default_value = "1.0", long, env)]
+    otlp_sampling_ratio: f64,
+
+    /// Spans recorded by the router for the requests being generated.
+    ///
+    /// `token` records spans per request for every generated token, which costs CPU in the
+    /// batching loop at high request rates. `request` only keeps the spans of the request,
+    /// its time in the queue and the batches it joins.
+    #[clap(default_value = "token", long, env, value_enum)]
+    trace_detail: TraceDetail,
+
     #[clap(long, env)]
     cors_allow_origin: Vec<String>,
 
@@ -664,6 +804,34 @@ struct Args {
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
 
+    /// The path to a JSON file with router settings, such as named generation parameter
+    /// presets selectable with the `preset` request parameter, default generation
+    /// parameters and stop tokens per model or adapter under `default_parameters`,
+    /// prompt templates selectable with the `template` field of the generate endpoints,
+    /// token quotas per API key under `quotas`, the buffering of streamed events to let
+    /// clients resume streams under `stream_resume`, the stripping or rejection of
+    /// special tokens in user inputs under `special_tokens`, the rerank endpoint of the
+    /// `best_of` sequences under `best_of`, the fill-in-the-middle tokens of the model
+    /// under `fim`, the SentencePiece or tiktoken tokenizer of the models without a
+    /// `tokenizer.json` under `tokenizer`, the limits of the tokenization cache of the
+    /// prompt prefixes under `tokenizer_cache`, the stream of the scheduler decisions
+    /// on `/admin/events` under `scheduler_events`, the system prompts enforced per API
+    /// key under `system_prompt`, the concurrent requests per API key or client IP
+    /// under `concurrency`, the limit of the concurrent requests adjusted to the time
+    /// to first token under `adaptive_concurrency`, the capacity and lifetime of the
+    /// pinned prompt prefixes under `prefix_pinning`, the chat completions stored for
+    /// `GET /v1/chat/completions/{id}` under `chat_store`, the port of the gRPC API of
+    /// routers built with the `grpc` feature under `grpc`, the certificate and key to
+    /// serve HTTPS with under `tls`, the pending checks and timeout of the validation
+    /// workers under `validation`, the limits on the input tokens of the generate, chat
+    /// and embeddings endpoints under `input_limits`, the retries of the chat
+    /// completions whose tool calls fail the schemas of their tools under `tool_calls`,
+    /// the compression of the prompts over a number of tokens under
+    /// `prompt_compression`, or the key of the `/admin` endpoints loading adapters,
+    /// changing the log filter and streaming the scheduler events under `admin`.
+    #[clap(long, env)]
+    router_config_path: Option<String>,
+
     /// Disable outlines grammar constrained generation.
     /// This is a feature that allows you to generate text that follows a specific grammar.
     #[clap(long, env)]
@@ -1435,12 +1603,14 @@ fn compute_type(num_shard: usize) -> Option<String> {
     Some(compute_type)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn spawn_webserver(
     num_shard: usize,
     args: Args,
     max_input_tokens: Option<usize>,
     max_total_tokens: Option<usize>,
     max_batch_prefill_tokens: u32,
+    quantize: Option<Quantization>,
     shutdown: Arc<AtomicBool>,
     shutdown_receiver: &mpsc::Receiver<()>,
 ) -> Result<Child, LauncherError> {
@@ -1464,6 +1634,8 @@ fn spawn_webserver(
         args.waiting_served_ratio.to_string(),
         "--max-waiting-tokens".to_string(),
         args.max_waiting_tokens.to_string(),
+        "--scheduling-policy".to_string(),
+        args.scheduling_policy.to_string(),
         "--validation-workers".to_string(),
         args.validation_workers.to_string(),
         "--hostname".to_string(),
@@ -1497,12 +1669,41 @@ fn spawn_webserver(
         router_args.push("--disable-grammar-support".to_string());
     }
 
+    if args.eager_admission {
+        router_args.push("--eager-admission".to_string());
+    }
+
+    // Time to first token objective
+    if let Some(ttft_slo_ms) = args.ttft_slo_ms {
+        router_args.push("--ttft-slo-ms".to_string());
+        router_args.push(ttft_slo_ms.to_string());
+        router_args.push("--ttft-slo-action".to_string());
+        router_args.push(args.ttft_slo_action.to_string());
+    }
+
+    router_args.push("--batch-timeout-secs".to_string());
+    router_args.push(args.batch_timeout_secs.to_string());
+
+    // Priority aging
+    if let Some(priority_aging_rate) = args.priority_aging_rate {
+        router_args.push("--priority-aging-rate".to_string());
+        router_args.push(priority_aging_rate.to_string());
+        router_args.push("--priority-max-boost".to_string());
+        router_args.push(args.priority_max_boost.to_string());
+    }
+
     // Tokenizer config path
     if let Some(ref tokenizer_config_path) = args.tokenizer_config_path {
         router_args.push("--tokenizer-config-path".to_string());
         router_args.push(tokenizer_config_path.to_string());
     }
 
+    // Router config path
+    if let Some(ref router_config_path) = args.router_config_path {
+        router_args.push("--router-config-path".to_string());
+        router_args.push(router_config_path.to_string());
+    }
+
     // Model optional max batch total tokens
     if let Some(max_batch_total_tokens) = args.max_batch_total_tokens {
         router_args.push("--max-batch-total-tokens".to_string());
@@ -1515,6 +1716,16 @@ fn spawn_webserver(
         router_args.push(max_batch_size.to_string());
     }
 
+    // Reported in `/info`
+    if let Some(quantize) = quantize {
+        router_args.push("--quantize".to_string());
+        router_args.push(quantize.to_string());
+    }
+    if let Some(ref lora_adapters) = args.lora_adapters {
+        router_args.push("--lora-adapters".to_string());
+        router_args.push(lora_adapters.to_string());
+    }
+
     // Model optional revision
     if let Some(ref revision) = args.revision {
         router_args.push("--revision".to_string());
@@ -1539,6 +1750,10 @@ fn spawn_webserver(
     let otlp_service_name = args.otlp_service_name;
     router_args.push("--otlp-service-name".to_string());
     router_args.push(otlp_service_name);
+    router_args.push("--otlp-sampling-ratio".to_string());
+    router_args.push(args.otlp_sampling_ratio.to_string());
+    router_args.push("--trace-detail".to_string());
+    router_args.push(args.trace_detail.to_string());
 
     // CORS origins
     for origin in args.cors_allow_origin.into_iter() {
@@ -1723,6 +1938,14 @@ fn main() -> Result<(), LauncherError> {
         }
     };
     let max_total_tokens = args.max_total_tokens;
+    if let (Some(max_total_tokens), Some(trained)) = (
+        max_total_tokens,
+        config.as_ref().and_then(|c| c.max_position_embeddings),
+    ) {
+        if max_total_tokens > trained {
+            tracing::warn!("`max_total_tokens` ({max_total_tokens}) exceeds the context the model was trained with ({trained}). Quality will likely degrade past that point.");
+        }
+    }
     let max_batch_prefill_tokens = {
         match args.max_batch_prefill_tokens {
             Some(max_batch_prefill_tokens) => max_batch_prefill_tokens,
@@ -1921,6 +2144,7 @@ fn main() -> Result<(), LauncherError> {
         max_input_tokens,
         max_total_tokens,
         max_batch_prefill_tokens,
+        quantize,
         shutdown.clone(),
         &shutdown_receiver,
     )
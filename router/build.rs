@@ -22,5 +22,26 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("cargo:rustc-env=DOCKER_LABEL={label}");
     }
 
+    #[cfg(feature = "grpc")]
+    compile_protos();
+
     Ok(())
 }
+
+/// Front-door gRPC API, served with the `grpc` feature
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    println!("cargo:rerun-if-changed=../proto/router/");
+
+    std::fs::create_dir_all("src/grpc/pb").unwrap_or(());
+    let mut config = prost_build::Config::new();
+    config.protoc_arg("--experimental_allow_proto3_optional");
+
+    tonic_build::configure()
+        .build_client(false)
+        .build_server(true)
+        .out_dir("src/grpc/pb")
+        .include_file("mod.rs")
+        .compile_with_config(config, &["../proto/router/v1/router.proto"], &["../proto"])
+        .unwrap_or_else(|e| panic!("protobuf compilation failed: {e}"));
+}
@@ -0,0 +1,287 @@
+/// Completed chat conversations of the requests with `store`, for debugging consoles
+use crate::requests::{request_id, request_owner};
+use crate::router_config::ChatStoreConfig;
+use crate::usage::bearer_token;
+use crate::{ChatCompletion, ErrorResponse, Message};
+use axum::extract::{Extension, Path};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+#[derive(Clone, Serialize, ToSchema)]
+pub(crate) struct StoredChatCompletion {
+    #[serde(flatten)]
+    pub completion: ChatCompletion,
+    /// Messages of the request
+    pub messages: Vec<Message>,
+    #[schema(example = json!({"session": "4f2a9c"}))]
+    pub metadata: HashMap<String, String>,
+    #[schema(nullable = true, example = "user-1234")]
+    pub user: Option<String>,
+}
+
+/// Id of a stored completion, and the API key of its caller
+///
+/// The ids are chosen by the clients, so a completion is only readable by the caller that
+/// stored it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct StoredId {
+    id: String,
+    owner: Option<String>,
+}
+
+#[derive(Default)]
+struct StoredCompletions {
+    completions: HashMap<StoredId, StoredChatCompletion>,
+    /// Ids of the completions with the time they were stored, oldest first
+    order: VecDeque<(Instant, StoredId)>,
+}
+
+/// Chat completions kept for `max_completions` completions or `ttl_secs`, whichever is shorter
+#[derive(Clone)]
+pub(crate) struct ChatStore {
+    config: ChatStoreConfig,
+    state: Arc<Mutex<StoredCompletions>>,
+}
+
+impl ChatStore {
+    pub(crate) fn new(config: ChatStoreConfig) -> Self {
+        Self {
+            config,
+            state: Arc::new(Mutex::new(StoredCompletions::default())),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.config.max_completions > 0
+    }
+
+    /// Conversation of the request being handled, if it has `store` and the store is enabled
+    ///
+    /// Must be called from the request handler, streams are polled outside of it.
+    pub(crate) fn pending(
+        &self,
+        store: bool,
+        messages: Vec<Message>,
+        metadata: Option<HashMap<String, String>>,
+        user: Option<String>,
+    ) -> Option<PendingChat> {
+        if !store || !self.enabled() {
+            return None;
+        }
+        Some(PendingChat {
+            store: self.clone(),
+            id: request_id()?,
+            owner: request_owner(),
+            messages,
+            metadata: metadata.unwrap_or_default(),
+            user,
+        })
+    }
+
+    /// Keep the completion of the request `id` of the caller with the API key `owner`,
+    /// replacing a previous one of the same caller with the same id
+    fn store(&self, id: &str, owner: Option<&str>, mut completion: StoredChatCompletion) {
+        if !self.enabled() {
+            return;
+        }
+        completion.completion.id = id.to_string();
+        let key = StoredId {
+            id: id.to_string(),
+            owner: owner.map(str::to_string),
+        };
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state.completions.insert(key.clone(), completion).is_some() {
+            state.order.retain(|(_, stored)| *stored != key);
+        }
+        state.order.push_back((now, key));
+        self.expire(&mut state, now);
+    }
+
+    /// Completion of the request `id` of the caller with the API key `owner`
+    pub(crate) fn get(&self, id: &str, owner: Option<&str>) -> Option<StoredChatCompletion> {
+        let key = StoredId {
+            id: id.to_string(),
+            owner: owner.map(str::to_string),
+        };
+        let mut state = self.state.lock().unwrap();
+        self.expire(&mut state, Instant::now());
+        state.completions.get(&key).cloned()
+    }
+
+    /// Drop the completions older than the TTL or over the maximum
+    fn expire(&self, state: &mut StoredCompletions, now: Instant) {
+        let ttl = Duration::from_secs(self.config.ttl_secs);
+        while let Some((stored_at, id)) = state.order.front() {
+            if state.order.len() <= self.config.max_completions
+                && now.duration_since(*stored_at) < ttl
+            {
+                break;
+            }
+            state.completions.remove(id);
+            state.order.pop_front();
+        }
+    }
+}
+
+/// Conversation of a request with `store`, stored once its completion is known
+pub(crate) struct PendingChat {
+    store: ChatStore,
+    id: Arc<str>,
+    owner: Option<Arc<str>>,
+    messages: Vec<Message>,
+    metadata: HashMap<String, String>,
+    user: Option<String>,
+}
+
+impl PendingChat {
+    pub(crate) fn complete(self, completion: ChatCompletion) {
+        self.store.store(
+            &self.id,
+            self.owner.as_deref(),
+            StoredChatCompletion {
+                completion,
+                messages: self.messages,
+                metadata: self.metadata,
+                user: self.user,
+            },
+        );
+    }
+}
+
+/// Stored chat completion of a request with `store`
+///
+/// Only the completions of the requests sent with the same API key are returned.
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/chat/completions/{id}",
+params(
+("id" = String, Path, description = "Value of the `x-request-id` header of the completion"),
+),
+responses(
+(status = 200, description = "Stored chat completion", body = StoredChatCompletion),
+(status = 404, description = "Unknown or expired completion of the caller", body = ErrorResponse,
+example = json ! ({"error": {"code": "completion_not_found", "type": "not_found", "message": "No stored completion with this id"}})),
+)
+)]
+pub(crate) async fn get_chat_completion(
+    Extension(store): Extension<ChatStore>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<StoredChatCompletion>, (StatusCode, Json<ErrorResponse>)> {
+    store
+        .get(&id, bearer_token(&headers))
+        .map(Json)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse::new(
+                    "completion_not_found",
+                    "not_found",
+                    "No stored completion with this id",
+                )),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatCompletionComplete, OutputMessage, TextMessage, Usage};
+
+    fn completion(content: &str) -> StoredChatCompletion {
+        StoredChatCompletion {
+            completion: ChatCompletion {
+                id: String::new(),
+                created: 0,
+                model: "model".to_string(),
+                system_fingerprint: String::new(),
+                choices: vec![ChatCompletionComplete {
+                    index: 0,
+                    message: OutputMessage::ChatMessage(TextMessage {
+                        role: "assistant".to_string(),
+                        content: content.to_string(),
+                    }),
+                    logprobs: None,
+                    finish_reason: "stop".to_string(),
+                }],
+                usage: Usage {
+                    prompt_tokens: 1,
+                    completion_tokens: 1,
+                    total_tokens: 2,
+                },
+                compression: None,
+            },
+            messages: vec![],
+            metadata: HashMap::from([("session".to_string(), "1".to_string())]),
+            user: None,
+        }
+    }
+
+    #[test]
+    fn test_chat_store() {
+        let store = ChatStore::new(ChatStoreConfig {
+            max_completions: 2,
+            ttl_secs: 60,
+        });
+        store.store("a", Some("key"), completion("a"));
+        store.store("b", Some("key"), completion("b"));
+        let stored = store.get("a", Some("key")).unwrap();
+        assert_eq!(stored.completion.id, "a");
+        assert_eq!(stored.metadata["session"], "1");
+
+        // Storing the same id again replaces the completion
+        store.store("a", Some("key"), completion("c"));
+        assert!(matches!(
+            &store.get("a", Some("key")).unwrap().completion.choices[0].message,
+            OutputMessage::ChatMessage(message) if message.content == "c"
+        ));
+        // The oldest completions are dropped first
+        store.store("d", Some("key"), completion("d"));
+        assert!(store.get("b", Some("key")).is_none());
+        assert!(store.get("a", Some("key")).is_some());
+
+        // And expire after the TTL
+        let store = ChatStore::new(ChatStoreConfig {
+            max_completions: 2,
+            ttl_secs: 0,
+        });
+        store.store("a", None, completion("a"));
+        assert!(store.get("a", None).is_none());
+
+        let disabled = ChatStore::new(ChatStoreConfig::default());
+        disabled.store("a", None, completion("a"));
+        assert!(disabled.get("a", None).is_none());
+    }
+
+    #[test]
+    fn test_chat_store_owner() {
+        let store = ChatStore::new(ChatStoreConfig {
+            max_completions: 4,
+            ttl_secs: 60,
+        });
+        store.store("a", Some("key"), completion("a"));
+
+        // Another caller cannot read the completion
+        assert!(store.get("a", Some("other")).is_none());
+        assert!(store.get("a", None).is_none());
+
+        // Nor replace it
+        store.store("a", Some("other"), completion("b"));
+        store.store("a", None, completion("c"));
+        assert!(matches!(
+            &store.get("a", Some("key")).unwrap().completion.choices[0].message,
+            OutputMessage::ChatMessage(message) if message.content == "a"
+        ));
+        assert!(matches!(
+            &store.get("a", Some("other")).unwrap().completion.choices[0].message,
+            OutputMessage::ChatMessage(message) if message.content == "b"
+        ));
+    }
+}
@@ -0,0 +1,280 @@
+/// Concurrent generation limits per API key or client IP, and adaptive limit of the server
+use crate::infer::InferError;
+use crate::router_config::{AdaptiveConcurrencyConfig, CallerIdentity, ConcurrencyConfig};
+use crate::usage::bearer_token;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+tokio::task_local! {
+    /// Concurrency limit of the caller of the request being handled
+    static CALLER_LIMIT: CallerLimit;
+}
+
+/// Concurrent generations left to a caller
+#[derive(Clone)]
+pub(crate) struct CallerLimit {
+    semaphore: Arc<Semaphore>,
+    limit: usize,
+}
+
+impl CallerLimit {
+    /// Limit of the caller of the request being handled, if it has one
+    ///
+    /// Must be called from the request handler, streams are polled outside of it.
+    pub(crate) fn current() -> Option<Self> {
+        CALLER_LIMIT.try_with(Clone::clone).ok()
+    }
+
+    /// Run `future` with its generations limited by `limit`
+    pub(crate) async fn scope<F: Future>(limit: Option<Self>, future: F) -> F::Output {
+        match limit {
+            Some(limit) => CALLER_LIMIT.scope(limit, future).await,
+            None => future.await,
+        }
+    }
+
+    /// Maximum number of concurrent generations of the caller
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Permit of a generation, held until the generation ends
+    pub(crate) fn try_acquire(&self) -> Result<OwnedSemaphorePermit, InferError> {
+        self.semaphore
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| InferError::CallerOverloaded(self.limit))
+    }
+}
+
+/// Concurrency limits of the callers, their semaphores are created on their first request
+#[derive(Clone)]
+pub(crate) struct ConcurrencyLimits {
+    config: Arc<ConcurrencyConfig>,
+    semaphores: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl ConcurrencyLimits {
+    /// `None` if no limit is configured
+    pub(crate) fn new(config: ConcurrencyConfig) -> Option<Self> {
+        if config.is_empty() {
+            return None;
+        }
+        Some(Self {
+            config: Arc::new(config),
+            semaphores: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    fn limit(&self, caller: &str) -> Option<CallerLimit> {
+        let limit = *self
+            .config
+            .keys
+            .get(caller)
+            .or(self.config.default.as_ref())?;
+        let mut semaphores = self.semaphores.lock().unwrap();
+        let semaphore = match semaphores.get(caller) {
+            Some(semaphore) => semaphore.clone(),
+            None => {
+                // Forget the callers without generation in flight, their permits and limits
+                // hold the only other references to their semaphores
+                semaphores.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+                let semaphore = Arc::new(Semaphore::new(limit));
+                semaphores.insert(caller.to_string(), semaphore.clone());
+                semaphore
+            }
+        };
+        Some(CallerLimit { semaphore, limit })
+    }
+}
+
+/// Limit of the concurrent generations of the server, increased by one after a window of times
+/// to first token under the target if the limit was reached, and decreased by `backoff` after a
+/// window over the target
+pub(crate) struct AdaptiveLimit {
+    target_ttft: Duration,
+    window: usize,
+    backoff: f64,
+    min_limit: usize,
+    max_limit: usize,
+    state: Mutex<AdaptiveState>,
+}
+
+struct AdaptiveState {
+    limit: f64,
+    in_flight: usize,
+    /// The limit was reached during the window
+    saturated: bool,
+    /// Times to first token of the window
+    samples: Vec<Duration>,
+}
+
+impl AdaptiveLimit {
+    /// `None` if no target is configured
+    pub(crate) fn new(config: AdaptiveConcurrencyConfig, max_limit: usize) -> Option<Arc<Self>> {
+        let target_ttft = Duration::from_millis(config.target_ttft_ms?);
+        let min_limit = config.min_limit.clamp(1, max_limit);
+        let limit = config
+            .initial_limit
+            .unwrap_or(max_limit)
+            .clamp(min_limit, max_limit);
+        metrics::gauge!("tgi_adaptive_concurrency_limit").set(limit as f64);
+        Some(Arc::new(Self {
+            target_ttft,
+            window: config.window.max(1),
+            backoff: config.backoff.clamp(0.0, 1.0),
+            min_limit,
+            max_limit,
+            state: Mutex::new(AdaptiveState {
+                limit: limit as f64,
+                in_flight: 0,
+                saturated: false,
+                samples: Vec::with_capacity(config.window),
+            }),
+        }))
+    }
+
+    /// Permit of a generation, held until the generation ends
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Result<AdaptivePermit, InferError> {
+        let mut state = self.state.lock().unwrap();
+        let limit = state.limit as usize;
+        if state.in_flight >= limit {
+            state.saturated = true;
+            return Err(InferError::LoadShed(limit));
+        }
+        state.in_flight += 1;
+        if state.in_flight == limit {
+            state.saturated = true;
+        }
+        Ok(AdaptivePermit {
+            limit: self.clone(),
+        })
+    }
+
+    /// Record the time to first token of a generation, and adjust the limit at the end of
+    /// the window
+    pub(crate) fn record(&self, ttft: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.samples.push(ttft);
+        if state.samples.len() < self.window {
+            return;
+        }
+        let mut samples = std::mem::take(&mut state.samples);
+        samples.sort_unstable();
+        let p99 = samples[(samples.len() * 99).div_ceil(100) - 1];
+        if p99 > self.target_ttft {
+            state.limit = (state.limit * self.backoff).max(self.min_limit as f64);
+        } else if state.saturated {
+            state.limit = (state.limit + 1.0).min(self.max_limit as f64);
+        }
+        state.saturated = state.in_flight >= state.limit as usize;
+        samples.clear();
+        state.samples = samples;
+        metrics::gauge!("tgi_adaptive_concurrency_ttft_p99").set(p99.as_secs_f64());
+        metrics::gauge!("tgi_adaptive_concurrency_limit").set(state.limit.floor());
+    }
+}
+
+/// Generation counted by the adaptive limit until it is dropped
+pub(crate) struct AdaptivePermit {
+    limit: Arc<AdaptiveLimit>,
+}
+
+impl Drop for AdaptivePermit {
+    fn drop(&mut self) {
+        self.limit.state.lock().unwrap().in_flight -= 1;
+    }
+}
+
+/// Select the concurrency limit of the API key or IP address of the request
+pub(crate) async fn identify_caller_limit(
+    State(limits): State<ConcurrencyLimits>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let caller = match limits.config.by {
+        CallerIdentity::ApiKey => bearer_token(request.headers()).map(String::from),
+        CallerIdentity::Ip => request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string()),
+    };
+    let limit = caller.and_then(|caller| limits.limit(&caller));
+    CallerLimit::scope(limit, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caller_limit() {
+        let limits = ConcurrencyLimits::new(ConcurrencyConfig {
+            by: CallerIdentity::ApiKey,
+            default: Some(1),
+            keys: HashMap::from([("key".to_string(), 2)]),
+        })
+        .unwrap();
+
+        let limit = limits.limit("key").unwrap();
+        let _first = limit.try_acquire().unwrap();
+        let _second = limits.limit("key").unwrap().try_acquire().unwrap();
+        assert!(matches!(
+            limit.try_acquire(),
+            Err(InferError::CallerOverloaded(2))
+        ));
+
+        // The permits of a caller are released with its generations
+        let other = limits.limit("other").unwrap().try_acquire().unwrap();
+        assert!(limits.limit("other").unwrap().try_acquire().is_err());
+        drop(other);
+        assert!(limits.limit("other").unwrap().try_acquire().is_ok());
+
+        assert!(ConcurrencyLimits::new(ConcurrencyConfig::default()).is_none());
+    }
+
+    fn current(limit: &AdaptiveLimit) -> usize {
+        limit.state.lock().unwrap().limit as usize
+    }
+
+    #[test]
+    fn test_adaptive_limit() {
+        let config = AdaptiveConcurrencyConfig {
+            target_ttft_ms: Some(100),
+            window: 2,
+            initial_limit: Some(2),
+            ..Default::default()
+        };
+        let limit = AdaptiveLimit::new(config, 4).unwrap();
+
+        // The limit increases after a window under the target where it was reached
+        let permits = [limit.try_acquire().unwrap(), limit.try_acquire().unwrap()];
+        assert!(matches!(limit.try_acquire(), Err(InferError::LoadShed(2))));
+        limit.record(Duration::from_millis(50));
+        limit.record(Duration::from_millis(60));
+        assert_eq!(current(&limit), 3);
+        drop(permits);
+
+        // But not after a window where it was not reached
+        limit.record(Duration::from_millis(50));
+        limit.record(Duration::from_millis(60));
+        assert_eq!(current(&limit), 3);
+
+        // It decreases after a window over the target, down to `min_limit`
+        for _ in 0..20 {
+            limit.record(Duration::from_millis(500));
+        }
+        assert_eq!(current(&limit), 1);
+        let _permit = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_err());
+
+        assert!(AdaptiveLimit::new(AdaptiveConcurrencyConfig::default(), 4).is_none());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::infer::Infer;
+use crate::json_body::JsonBody;
+use crate::requests::RequestScope;
+use crate::router_config::GenerateBatchConfig;
+use crate::server::{generate_internal, ComputeType};
+use crate::{
+    default_parameters, Endpoint, ErrorDetails, ErrorResponse, GenerateParameters, GenerateRequest,
+    GenerateResponse,
+};
+use axum::body::Body;
+use axum::extract::Extension;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use tracing::instrument;
+use utoipa::ToSchema;
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct GenerateBatchRequest {
+    #[schema(example = json!(["My name is Olivier and I", "The capital of France is"]))]
+    pub inputs: Vec<String>,
+    /// Parameters shared by all the prompts of the batch
+    #[serde(default = "default_parameters")]
+    pub parameters: GenerateParameters,
+}
+
+/// Result of one prompt of the batch, sent as a single line as soon as it completes
+#[derive(Serialize, ToSchema)]
+pub(crate) struct GenerateBatchResponse {
+    /// Position of the prompt in `inputs`
+    #[schema(example = 0)]
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<GenerateResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorDetails>,
+}
+
+/// Generate tokens for a batch of prompts
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/generate_batch",
+request_body = GenerateBatchRequest,
+responses(
+(status = 200, description = "Generated Text, one JSON line per prompt in completion order",
+content_type = "application/x-ndjson", body = GenerateBatchResponse),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": {"code": "too_many_prompts", "type": "validation", "message": "Number of prompts exceeds the maximum of 1024", "param": "inputs"}})),
+)
+)]
+#[instrument(skip_all, fields(prompts = req.inputs.len()))]
+pub(crate) async fn generate_batch(
+    Extension(infer): Extension<Infer>,
+    Extension(compute_type): Extension<ComputeType>,
+    Extension(config): Extension<GenerateBatchConfig>,
+    JsonBody(req): JsonBody<GenerateBatchRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    if req.inputs.is_empty() || req.inputs.len() > config.max_prompts {
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(
+                ErrorResponse::new(
+                    "too_many_prompts",
+                    "validation",
+                    format!(
+                        "Number of prompts must be between 1 and {}. Given: {}",
+                        config.max_prompts,
+                        req.inputs.len()
+                    ),
+                )
+                .with_param(Some("inputs")),
+            ),
+        ));
+    }
+
+    let span = tracing::Span::current();
+    // The prompts are generated while the response body is streamed, outside of the handler
+    let scope = RequestScope::current();
+    let parameters = req.parameters;
+    // Only `max_concurrent` prompts of the batch are queued at the same time, so that a large
+    // batch does not exhaust the concurrent requests of the router
+    let lines = futures::stream::iter(req.inputs.into_iter().enumerate())
+        .map(move |(index, inputs)| {
+            let request = GenerateRequest {
+                inputs,
+                parameters: parameters.clone(),
+                add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
+            };
+            let infer = infer.clone();
+            let compute_type = compute_type.clone();
+            let span = span.clone();
+            let scope = scope.clone();
+            async move {
+                let generation =
+                    generate_internal(Extension(infer), compute_type, Json(request), span);
+                let line = match scope.run(generation).await {
+                    Ok((_, Json(response))) => GenerateBatchResponse {
+                        index,
+                        response: Some(response),
+                        error: None,
+                    },
+                    Err((_, Json(error))) => GenerateBatchResponse {
+                        index,
+                        response: None,
+                        error: Some(error.error),
+                    },
+                };
+                let mut line = serde_json::to_vec(&line).unwrap();
+                line.push(b'\n');
+                Ok::<_, Infallible>(line)
+            }
+        })
+        .buffer_unordered(config.max_concurrent.max(1));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    Ok((headers, Body::from_stream(lines)).into_response())
+}
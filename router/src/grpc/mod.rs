@@ -0,0 +1,491 @@
+/// gRPC front-door API, mirroring the generate and chat endpoints of the HTTP API
+///
+/// The requests go through the same validation and inference as the HTTP ones, the
+/// errors are returned with the gRPC code closest to the HTTP status and the error code
+/// of the HTTP API in the `error-code` metadata.
+use crate::infer::{Infer, InferError};
+use crate::server::{generate_internal, generate_stream_internal, ComputeType, StreamEvent};
+use crate::{
+    default_parameters, ChatRequest, Endpoint, ErrorResponse, FinishReason, GenerateParameters,
+    GenerateRequest, GenerateResponse, GrammarType, Message, MessageContent, PrefillToken,
+    StreamResponse, Token, ToolChoice,
+};
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::Json;
+use futures::{Stream, StreamExt};
+use pb::router::v1 as proto;
+use pb::router::v1::text_generation_server::{TextGeneration, TextGenerationServer};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tonic::{Code, Request, Response, Status};
+use tracing::instrument;
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+mod pb;
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+struct GrpcService {
+    infer: Infer,
+    compute_type: ComputeType,
+}
+
+/// Serve the gRPC API on `addr` until `shutdown` completes
+///
+/// When `api_key` is set, the requests must have the `authorization: Bearer <api_key>`
+/// metadata, as the HTTP requests.
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    infer: Infer,
+    compute_type: ComputeType,
+    api_key: Option<String>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    let service = TextGenerationServer::with_interceptor(
+        GrpcService {
+            infer,
+            compute_type,
+        },
+        move |request: Request<()>| authorize(request, api_key.as_deref()),
+    );
+    tracing::info!("Serving the gRPC API on {addr}");
+    tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_shutdown(addr, shutdown)
+        .await
+}
+
+fn authorize(request: Request<()>, api_key: Option<&str>) -> Result<Request<()>, Status> {
+    let Some(api_key) = api_key else {
+        return Ok(request);
+    };
+    match request
+        .metadata()
+        .get("authorization")
+        .and_then(|token| token.to_str().ok())
+    {
+        Some(token) if token.eq_ignore_ascii_case(&format!("Bearer {api_key}")) => Ok(request),
+        _ => Err(Status::unauthenticated("Invalid API key")),
+    }
+}
+
+#[tonic::async_trait]
+impl TextGeneration for GrpcService {
+    #[instrument(
+        skip_all,
+        fields(
+            total_time,
+            validation_time,
+            queue_time,
+            inference_time,
+            time_per_token,
+            seed,
+        )
+    )]
+    async fn generate(
+        &self,
+        request: Request<proto::GenerateRequest>,
+    ) -> Result<Response<proto::GenerateResponse>, Status> {
+        let request = generate_request(request.into_inner())?;
+        let (_, Json(response)) = generate_internal(
+            Extension(self.infer.clone()),
+            self.compute_type.clone(),
+            Json(request),
+            tracing::Span::current(),
+        )
+        .await
+        .map_err(status)?;
+        Ok(Response::new(response.into()))
+    }
+
+    type GenerateStreamStream = ResponseStream<proto::StreamResponse>;
+
+    #[instrument(
+        skip_all,
+        fields(
+            total_time,
+            validation_time,
+            queue_time,
+            inference_time,
+            time_per_token,
+            seed,
+        )
+    )]
+    async fn generate_stream(
+        &self,
+        request: Request<proto::GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let request = generate_request(request.into_inner())?;
+        let (_, stream) = generate_stream_internal(
+            self.infer.clone(),
+            self.compute_type.clone(),
+            Json(request),
+            tracing::Span::current(),
+        )
+        .await;
+        let stream = stream
+            .filter_map(|response| async { tokens(response) })
+            .map(|response| response.map(Into::into).map_err(infer_status));
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    #[instrument(
+        skip_all,
+        fields(
+            total_time,
+            validation_time,
+            queue_time,
+            inference_time,
+            time_per_token,
+            seed,
+        )
+    )]
+    async fn chat(
+        &self,
+        request: Request<proto::ChatRequest>,
+    ) -> Result<Response<proto::ChatResponse>, Status> {
+        let (request, _) = chat_request(request.into_inner())?
+            .try_into_generate(&self.infer)
+            .map_err(infer_status)?;
+        let (_, Json(response)) = generate_internal(
+            Extension(self.infer.clone()),
+            self.compute_type.clone(),
+            Json(request),
+            tracing::Span::current(),
+        )
+        .await
+        .map_err(status)?;
+        // Chat requests always ask for the details
+        let details = response
+            .details
+            .ok_or_else(|| Status::internal("Missing generation details"))?;
+        let prompt_tokens = details.prefill.len() as u32;
+        Ok(Response::new(proto::ChatResponse {
+            content: response.generated_text,
+            finish_reason: proto::FinishReason::from(details.finish_reason).into(),
+            usage: Some(usage(prompt_tokens, details.generated_tokens)),
+        }))
+    }
+
+    type ChatStreamStream = ResponseStream<proto::ChatStreamResponse>;
+
+    #[instrument(
+        skip_all,
+        fields(
+            total_time,
+            validation_time,
+            queue_time,
+            inference_time,
+            time_per_token,
+            seed,
+        )
+    )]
+    async fn chat_stream(
+        &self,
+        request: Request<proto::ChatRequest>,
+    ) -> Result<Response<Self::ChatStreamStream>, Status> {
+        let (request, _) = chat_request(request.into_inner())?
+            .try_into_generate(&self.infer)
+            .map_err(infer_status)?;
+        let (_, stream) = generate_stream_internal(
+            self.infer.clone(),
+            self.compute_type.clone(),
+            Json(request),
+            tracing::Span::current(),
+        )
+        .await;
+        let stream = stream.filter_map(|response| async { tokens(response) });
+        let stream = stream.map(|response| {
+            let StreamResponse { token, details, .. } = response.map_err(infer_status)?;
+            let token_usage = details
+                .as_ref()
+                .map(|details| usage(details.input_length, details.generated_tokens));
+            Ok(proto::ChatStreamResponse {
+                // Special tokens are not part of the message, as in the HTTP API
+                content: if token.special {
+                    String::new()
+                } else {
+                    token.text
+                },
+                finish_reason: details
+                    .map(|details| proto::FinishReason::from(details.finish_reason).into()),
+                usage: token_usage,
+            })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+fn generate_request(request: proto::GenerateRequest) -> Result<GenerateRequest, Status> {
+    // Unset parameters take the defaults of the HTTP API, for the whole object and per field
+    let parameters = match request.parameters {
+        None => default_parameters(),
+        Some(parameters) => GenerateParameters {
+            best_of: parameters.best_of.map(|best_of| best_of as usize),
+            temperature: parameters.temperature,
+            repetition_penalty: parameters.repetition_penalty,
+            frequency_penalty: parameters.frequency_penalty,
+            top_k: parameters.top_k,
+            top_p: parameters.top_p,
+            typical_p: parameters.typical_p,
+            do_sample: parameters.do_sample,
+            max_new_tokens: parameters.max_new_tokens,
+            return_full_text: parameters.return_full_text,
+            stop: parameters.stop,
+            truncate: parameters.truncate.map(|truncate| truncate as usize),
+            watermark: parameters.watermark,
+            details: parameters.details,
+            decoder_input_details: parameters.decoder_input_details,
+            seed: parameters.seed,
+            top_n_tokens: parameters.top_n_tokens,
+            grammar: parameters.grammar.map(grammar).transpose()?,
+            adapter_id: parameters.adapter_id,
+            preset: parameters.preset,
+            ..GenerateParameters::default()
+        },
+    };
+    Ok(GenerateRequest {
+        inputs: request.inputs,
+        parameters,
+        template: None,
+        variables: None,
+        inputs_ids: None,
+        suffix: None,
+        add_special_tokens: true,
+        endpoint: Endpoint::Generate,
+    })
+}
+
+fn chat_request(request: proto::ChatRequest) -> Result<ChatRequest, Status> {
+    Ok(ChatRequest {
+        model: None,
+        messages: request
+            .messages
+            .into_iter()
+            .map(|message| Message {
+                role: message.role,
+                content: MessageContent::SingleText(message.content),
+                name: None,
+                prefix: false,
+            })
+            .collect(),
+        frequency_penalty: request.frequency_penalty,
+        logit_bias: None,
+        logprobs: None,
+        top_logprobs: None,
+        max_tokens: None,
+        max_completion_tokens: request.max_completion_tokens,
+        n: None,
+        presence_penalty: request.presence_penalty,
+        stop: (!request.stop.is_empty()).then_some(request.stop),
+        stream: false,
+        seed: request.seed,
+        temperature: request.temperature,
+        top_p: request.top_p,
+        tools: None,
+        tool_prompt: None,
+        tool_choice: ToolChoice::default(),
+        parallel_tool_calls: false,
+        response_format: request.response_format.map(grammar).transpose()?,
+        guideline: None,
+        stream_options: None,
+        store: false,
+        metadata: None,
+        user: None,
+        adapter_id: None,
+    })
+}
+
+fn grammar(grammar: proto::Grammar) -> Result<GrammarType, Status> {
+    match grammar.r#type() {
+        proto::GrammarType::Json => serde_json::from_str(&grammar.value)
+            .map(GrammarType::Json)
+            .map_err(|err| Status::invalid_argument(format!("Invalid JSON schema: {err}"))),
+        proto::GrammarType::Regex => Ok(GrammarType::Regex(grammar.value)),
+    }
+}
+
+fn usage(prompt_tokens: u32, completion_tokens: u32) -> proto::Usage {
+    proto::Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    }
+}
+
+/// Tokens of a generation stream, the queue positions are not sent over gRPC
+fn tokens(event: Result<StreamEvent, InferError>) -> Option<Result<StreamResponse, InferError>> {
+    match event {
+        Ok(StreamEvent::Token(response)) => Some(Ok(response)),
+        Ok(StreamEvent::Queued(_)) => None,
+        Err(err) => Some(Err(err)),
+    }
+}
+
+/// gRPC status of an error response of the HTTP API
+fn status((status_code, Json(response)): (StatusCode, Json<ErrorResponse>)) -> Status {
+    let code = match status_code {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        StatusCode::NOT_IMPLEMENTED => Code::Unimplemented,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    let mut status = Status::new(code, response.error.message);
+    if let Ok(error_code) = response.error.code.parse() {
+        status.metadata_mut().insert("error-code", error_code);
+    }
+    status
+}
+
+fn infer_status(err: InferError) -> Status {
+    status(err.into())
+}
+
+impl From<FinishReason> for proto::FinishReason {
+    fn from(finish_reason: FinishReason) -> Self {
+        match finish_reason {
+            FinishReason::Length => Self::Length,
+            FinishReason::EndOfSequenceToken => Self::EosToken,
+            FinishReason::StopSequence => Self::StopSequence,
+            FinishReason::Error => Self::Error,
+            FinishReason::Repetition => Self::Repetition,
+        }
+    }
+}
+
+impl From<Token> for proto::Token {
+    fn from(token: Token) -> Self {
+        Self {
+            id: token.id,
+            text: token.text,
+            logprob: token.logprob,
+            special: token.special,
+        }
+    }
+}
+
+impl From<PrefillToken> for proto::PrefillToken {
+    fn from(token: PrefillToken) -> Self {
+        Self {
+            id: token.id,
+            text: token.text,
+            logprob: token.logprob,
+        }
+    }
+}
+
+impl From<GenerateResponse> for proto::GenerateResponse {
+    fn from(response: GenerateResponse) -> Self {
+        Self {
+            generated_text: response.generated_text,
+            details: response.details.map(|details| proto::Details {
+                finish_reason: proto::FinishReason::from(details.finish_reason).into(),
+                generated_tokens: details.generated_tokens,
+                seed: details.seed,
+                prefill: details.prefill.into_iter().map(Into::into).collect(),
+                tokens: details.tokens.into_iter().map(Into::into).collect(),
+            }),
+        }
+    }
+}
+
+impl From<StreamResponse> for proto::StreamResponse {
+    fn from(response: StreamResponse) -> Self {
+        Self {
+            index: response.index,
+            token: Some(response.token.into()),
+            generated_text: response.generated_text,
+            details: response.details.map(|details| proto::StreamDetails {
+                finish_reason: proto::FinishReason::from(details.finish_reason).into(),
+                generated_tokens: details.generated_tokens,
+                seed: details.seed,
+                input_length: details.input_length,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grpc_generate_request() {
+        // Unset parameters take the defaults of the HTTP API
+        let request = generate_request(proto::GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: None,
+        })
+        .unwrap();
+        assert_eq!(request.parameters, default_parameters());
+
+        let request = generate_request(proto::GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: Some(proto::GenerateParameters {
+                temperature: Some(0.5),
+                truncate: Some(10),
+                grammar: Some(proto::Grammar {
+                    r#type: proto::GrammarType::Json.into(),
+                    value: r#"{"type": "object"}"#.to_string(),
+                }),
+                ..Default::default()
+            }),
+        })
+        .unwrap();
+        assert_eq!(request.parameters.temperature, Some(0.5));
+        assert_eq!(request.parameters.truncate, Some(10));
+        assert_eq!(request.parameters.max_new_tokens, None);
+        assert!(!request.parameters.do_sample);
+        assert_eq!(
+            request.parameters.grammar,
+            Some(GrammarType::Json(serde_json::json!({"type": "object"})))
+        );
+
+        let err = generate_request(proto::GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: Some(proto::GenerateParameters {
+                grammar: Some(proto::Grammar {
+                    r#type: proto::GrammarType::Json.into(),
+                    value: "{".to_string(),
+                }),
+                ..Default::default()
+            }),
+        })
+        .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_grpc_status() {
+        let err = status((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse::new(
+                "input_too_long",
+                "validation",
+                "Too long",
+            )),
+        ));
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert_eq!(err.message(), "Too long");
+        assert_eq!(err.metadata().get("error-code").unwrap(), "input_too_long");
+
+        let request = Request::new(());
+        assert!(authorize(request, None).is_ok());
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", "bearer secret".parse().unwrap());
+        assert!(authorize(request, Some("secret")).is_ok());
+        let request = Request::new(());
+        assert_eq!(
+            authorize(request, Some("secret")).unwrap_err().code(),
+            Code::Unauthenticated
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use crate::infer::{InferError, InferResponse};
+use crate::router_config::BestOfConfig;
+use crate::BestOfStrategy;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Client of a rerank endpoint, with the API of the `/rerank` route of Text Embeddings Inference
+#[derive(Clone)]
+pub(crate) struct Reranker {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct RerankRequest<'a> {
+    query: &'a str,
+    texts: Vec<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Rank {
+    index: usize,
+    score: f32,
+}
+
+impl Reranker {
+    pub(crate) fn new(config: BestOfConfig) -> Option<Self> {
+        let url = config.rerank_url?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.rerank_timeout_secs))
+            .build()
+            .expect("the rerank client has a valid configuration");
+        Some(Self { client, url })
+    }
+
+    /// Score of every text as an answer to `query`
+    async fn scores(&self, query: &str, texts: Vec<&str>) -> Result<Vec<f32>, InferError> {
+        let mut scores = vec![f32::MIN; texts.len()];
+        let body = serde_json::to_vec(&RerankRequest { query, texts })
+            .map_err(|err| InferError::RerankError(err.to_string()))?;
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| InferError::RerankError(err.to_string()))?;
+        let body = response
+            .bytes()
+            .await
+            .map_err(|err| InferError::RerankError(err.to_string()))?;
+        let ranks: Vec<Rank> = serde_json::from_slice(&body)
+            .map_err(|err| InferError::RerankError(err.to_string()))?;
+        for rank in ranks {
+            if let Some(score) = scores.get_mut(rank.index) {
+                *score = rank.score;
+            }
+        }
+        Ok(scores)
+    }
+}
+
+/// Index of the best of `responses`, the first one with the highest score
+pub(crate) async fn best_index(
+    strategy: BestOfStrategy,
+    reranker: Option<&Reranker>,
+    inputs: &str,
+    responses: &[InferResponse],
+) -> Result<usize, InferError> {
+    let scores = match (strategy, reranker) {
+        (BestOfStrategy::Rerank, Some(reranker)) => {
+            let texts = responses
+                .iter()
+                .map(|response| response.generated_text.text.as_str())
+                .collect();
+            reranker.scores(inputs, texts).await?
+        }
+        _ => responses
+            .iter()
+            .map(|response| score(strategy, response))
+            .collect(),
+    };
+
+    let mut max_index = 0;
+    let mut max_score = f32::MIN;
+    for (i, score) in scores.into_iter().enumerate() {
+        if score > max_score {
+            max_index = i;
+            max_score = score;
+        }
+    }
+    Ok(max_index)
+}
+
+/// Score of a sequence from the logprobs of its generated tokens
+fn score(strategy: BestOfStrategy, response: &InferResponse) -> f32 {
+    let sum = response
+        .tokens
+        .iter()
+        .map(|token| token.logprob)
+        .sum::<f32>();
+    let length = response.tokens.len() as f32;
+    match strategy {
+        BestOfStrategy::SumLogprob => sum,
+        BestOfStrategy::LengthPenalty(alpha) => sum / length.powf(alpha),
+        BestOfStrategy::MeanLogprob | BestOfStrategy::Rerank => sum / length,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::GeneratedText;
+    use crate::{EffectiveParameters, FinishReason, Token};
+    use tokio::time::Instant;
+
+    fn response(logprobs: &[f32]) -> InferResponse {
+        let tokens = logprobs
+            .iter()
+            .map(|&logprob| Token {
+                id: 0,
+                text: String::new(),
+                logprob,
+                special: false,
+                bytes: None,
+            })
+            .collect();
+        InferResponse {
+            _input_length: 1,
+            prefill: vec![],
+            tokens,
+            generated_text: GeneratedText {
+                text: String::new(),
+                generated_tokens: logprobs.len() as u32,
+                finish_reason: FinishReason::Length,
+                seed: None,
+                backend: None,
+                steps: None,
+            },
+            queued: Instant::now(),
+            start: Instant::now(),
+            parameters: EffectiveParameters {
+                do_sample: true,
+                temperature: 1.0,
+                top_k: None,
+                top_p: 1.0,
+                typical_p: 1.0,
+                repetition_penalty: 1.0,
+                frequency_penalty: 0.0,
+            },
+            prefix_id: None,
+            top_tokens: vec![],
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_best_index() {
+        let responses = [response(&[-0.5]), response(&[-0.2, -0.2, -0.2, -0.2])];
+        let best = |strategy| best_index(strategy, None, "", &responses);
+        // Mean -0.5 and -0.2, sum -0.5 and -0.8
+        assert_eq!(best(BestOfStrategy::MeanLogprob).await.unwrap(), 1);
+        assert_eq!(best(BestOfStrategy::SumLogprob).await.unwrap(), 0);
+        assert_eq!(best(BestOfStrategy::LengthPenalty(0.0)).await.unwrap(), 0);
+        assert_eq!(best(BestOfStrategy::LengthPenalty(1.0)).await.unwrap(), 1);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::infer::InferError;
+use crate::validation::ValidationError;
 use crate::{ChatTemplateInputs, Message, MessageChunk, TextMessage, TokenizerConfigToken, Tool};
 use minijinja::{Environment, ErrorKind, Template};
 use minijinja_contrib::pycompat;
@@ -51,17 +52,33 @@ impl ChatTemplate {
         }
     }
 
-    pub(crate) fn apply(
+    /// Render the messages, ending with the prompt of the assistant turn if
+    /// `add_generation_prompt` is set
+    pub(crate) fn render(
         &self,
         guideline: Option<&str>,
         mut messages: Vec<Message>,
         tools_and_prompt: Option<(Vec<Tool>, String)>,
+        add_generation_prompt: bool,
     ) -> Result<String, InferError> {
         // check if guideline is expected but not provided
         if self.variables.contains("guideline") && guideline.is_none() {
             return Err(InferError::MissingTemplateVariable("guideline".to_string()));
         }
 
+        // The prefix is continued after the generation prompt
+        let prefix = match messages.iter().position(|message| message.prefix) {
+            Some(position)
+                if position + 1 == messages.len() && messages[position].role == "assistant" =>
+            {
+                messages
+                    .pop()
+                    .map(|message| TextMessage::from(message).content)
+            }
+            Some(_) => return Err(ValidationError::PrefixMessage.into()),
+            None => None,
+        };
+
         let tools = match tools_and_prompt {
             Some((tools, tool_prompt)) => {
                 // check if the `tools` variable is used in the template
@@ -91,9 +108,10 @@ impl ChatTemplate {
                 messages,
                 bos_token: self.bos_token.as_deref(),
                 eos_token: self.eos_token.as_deref(),
-                add_generation_prompt: true,
+                add_generation_prompt,
                 tools,
             })
+            .map(|prompt| prompt + prefix.as_deref().unwrap_or_default())
             .map_err(InferError::TemplateError)
     }
 }
@@ -102,7 +120,8 @@ impl ChatTemplate {
 #[cfg(test)]
 mod tests {
     use crate::infer::chat_template::raise_exception;
-    use crate::infer::ChatTemplate;
+    use crate::infer::{ChatTemplate, InferError};
+    use crate::validation::ValidationError;
     use crate::{
         ChatTemplateInputs, Message, MessageContent, TextMessage, TokenizerConfigToken, Tool,
     };
@@ -810,6 +829,7 @@ mod tests {
                 content: MessageContent::SingleText(
                     "I'd like to show off how chat templating works!".to_string(),
                 ),
+                prefix: false,
             },
             Message {
                 name: None,
@@ -817,15 +837,17 @@ mod tests {
                 content: MessageContent::SingleText(
                     "I'm doing great. How can I help you today?".to_string(),
                 ),
+                prefix: false,
             },
             Message {
                 name: None,
                 role: "user".to_string(),
                 content: MessageContent::SingleText("Hello, how are you?".to_string()),
+                prefix: false,
             },
         ];
 
-        let result = ct.apply(None, msgs, None);
+        let result = ct.render(None, msgs, None, true);
 
         match result {
             Ok(_) => panic!("Should have failed since no guideline is provided"),
@@ -835,6 +857,48 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_chat_template_prefix() {
+        let ct = ChatTemplate::new(
+            "{% for message in messages %}{{ '<|' + message['role'] + '|>' + message['content'] + '</s>' }}{% endfor %}{% if add_generation_prompt %}{{ '<|assistant|>' }}{% endif %}".to_string(),
+            None,
+            Some(TokenizerConfigToken::String("</s>".to_string())),
+        );
+        let message = |role: &str, content: &str, prefix| Message {
+            name: None,
+            role: role.to_string(),
+            content: MessageContent::SingleText(content.to_string()),
+            prefix,
+        };
+
+        let msgs = vec![
+            message("user", "Name a color", false),
+            message("assistant", "The color is", true),
+        ];
+        assert_eq!(
+            ct.render(None, msgs, None, true).unwrap(),
+            "<|user|>Name a color</s><|assistant|>The color is"
+        );
+
+        let msgs = vec![message("user", "Name a color", false)];
+        assert_eq!(
+            ct.render(None, msgs, None, false).unwrap(),
+            "<|user|>Name a color</s>"
+        );
+
+        // Only the last assistant message can be a prefix
+        let msgs = vec![message("user", "Name a color", true)];
+        assert!(matches!(
+            ct.render(None, msgs, None, true),
+            Err(InferError::ValidationError(ValidationError::PrefixMessage))
+        ));
+        let msgs = vec![
+            message("assistant", "The color is", true),
+            message("user", "Name a color", false),
+        ];
+        assert!(ct.render(None, msgs, None, true).is_err());
+    }
+
     #[test]
     fn test_chat_template_with_default_tool_template() {
         let ct = ChatTemplate::new(
@@ -851,23 +915,26 @@ mod tests {
                 content: MessageContent::SingleText(
                     "I'd like to show off how chat templating works!".to_string(),
                 ),
+                prefix: false,
             },
             Message {
                 name: None,
                 role: "assistant".to_string(),
                 content: MessageContent::SingleText("Great! How can I help you today?".to_string()),
+                prefix: false,
             },
             Message {
                 name: None,
                 role: "user".to_string(),
                 content: MessageContent::SingleText("Just testing".to_string()),
+                prefix: false,
             },
         ];
         let tools_string = r#"[{"type": "function","function": {"name": "get_current_weather","description": "Get the current weather","parameters": {"type": "object","properties": {"location": {"type": "string","description": "The city and state, e.g. San Francisco, CA"},"format": {"type": "string","enum": ["celsius", "fahrenheit"],"description": "The temperature unit to use. Infer this from the users location."}},"required": ["location", "format"]}}}]"#.to_string();
         let tools: Vec<Tool> = serde_json::from_str(&tools_string).unwrap();
         let tool_prompt = "This default prompt will be used".to_string();
         let tools_and_prompt = Some((tools, tool_prompt));
-        let result = ct.apply(None, msgs, tools_and_prompt);
+        let result = ct.render(None, msgs, tools_and_prompt, true);
         let expected = "<s>[INST] I'd like to show off how chat templating works! [/INST]Great! How can I help you today?</s> [INST] Just testing\n---\n[{\"type\":\"function\",\"function\":{\"description\":\"Get the current weather\",\"name\":\"get_current_weather\",\"arguments\":{\"properties\":{\"format\":{\"description\":\"The temperature unit to use. Infer this from the users location.\",\"enum\":[\"celsius\",\"fahrenheit\"],\"type\":\"string\"},\"location\":{\"description\":\"The city and state, e.g. San Francisco, CA\",\"type\":\"string\"}},\"required\":[\"location\",\"format\"],\"type\":\"object\"}}}]\nThis default prompt will be used [/INST]".to_string();
         assert_eq!(result.unwrap(), expected);
     }
@@ -888,6 +955,7 @@ mod tests {
                     "Youre a helpful assistant! Answer the users question best you can."
                         .to_string(),
                 ),
+                prefix: false,
             },
             Message {
                 name: None,
@@ -895,13 +963,14 @@ mod tests {
                 content: MessageContent::SingleText(
                     "What is the weather like in Brooklyn, New York?".to_string(),
                 ),
+                prefix: false,
             },
         ];
         let tools_string = r#"[{"type": "function","function": {"name": "get_current_weather","description": "Get the current weather","parameters": {"type": "object","properties": {"location": {"type": "string","description": "The city and state, e.g. San Francisco, CA"},"format": {"type": "string","enum": ["celsius", "fahrenheit"],"description": "The temperature unit to use. Infer this from the users location."}},"required": ["location", "format"]}}}]"#.to_string();
         let tools: Vec<Tool> = serde_json::from_str(&tools_string).unwrap();
         let tool_prompt = "This default prompt will be used".to_string();
         let tools_and_prompt = Some((tools, tool_prompt));
-        let result = ct.apply(None, msgs, tools_and_prompt);
+        let result = ct.render(None, msgs, tools_and_prompt, true);
         let expected = "<s><|start_header_id|>system<|end_header_id|>\n\nEnvironment: ipython\nCutting Knowledge Date: December 2023\nToday Date: 26 Jul 2024\n\nYoure a helpful assistant! Answer the users question best you can.<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nGiven the following functions, please respond with a JSON for a function call with its proper arguments that best answers the given prompt.\n\nRespond in the format {\"name\": function name, \"parameters\": dictionary of argument name and its value}.Do not use variables.\n\n{\n    \"function\": {\n        \"arguments\": {\n            \"properties\": {\n                \"format\": {\n                    \"description\": \"The temperature unit to use. Infer this from the users location.\",\n                    \"enum\": [\n                        \"celsius\",\n                        \"fahrenheit\"\n                    ],\n                    \"type\": \"string\"\n                },\n                \"location\": {\n                    \"description\": \"The city and state, e.g. San Francisco, CA\",\n                    \"type\": \"string\"\n                }\n            },\n            \"required\": [\n                \"location\",\n                \"format\"\n            ],\n            \"type\": \"object\"\n        },\n        \"description\": \"Get the current weather\",\n        \"name\": \"get_current_weather\"\n    },\n    \"type\": \"function\"\n}\n\nWhat is the weather like in Brooklyn, New York?\n---\nThis default prompt will be used<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n".to_string();
         assert_eq!(result.unwrap(), expected);
     }
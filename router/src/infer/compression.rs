@@ -0,0 +1,698 @@
+use crate::infer::{Infer, InferError};
+use crate::router_config::{CompressionStrategy, PromptCompressionConfig};
+use crate::{
+    default_parameters, ChatRequest, Endpoint, GenerateParameters, GenerateRequest, Message,
+    MessageChunk, MessageContent, PromptCompression, TextMessage,
+};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Blocks of text shorter than this are separators or labels rather than few-shot examples
+const MIN_EXAMPLE_LENGTH: usize = 16;
+
+/// Compresses the prompts over the token threshold of the `prompt_compression` config, with its
+/// strategies in order until the prompt is under the threshold
+///
+/// The prompt is tokenized again after every strategy that changed it.
+#[derive(Clone)]
+pub(crate) struct PromptCompressor {
+    threshold: usize,
+    config: Arc<PromptCompressionConfig>,
+}
+
+impl PromptCompressor {
+    /// Disabled without a threshold or strategies
+    pub(crate) fn new(config: PromptCompressionConfig) -> Option<Self> {
+        let threshold = config.threshold_tokens?;
+        (!config.strategies.is_empty()).then(|| Self {
+            threshold,
+            config: Arc::new(config),
+        })
+    }
+
+    /// Compress the inputs of a request of the generate endpoints
+    pub(crate) async fn compress_inputs(
+        &self,
+        infer: &Infer,
+        request: &mut GenerateRequest,
+    ) -> Result<Option<PromptCompression>, InferError> {
+        let add_special_tokens = request.add_special_tokens;
+        let count = |inputs: String| infer.count_tokens(inputs, add_special_tokens);
+        let mut tokens = count(request.inputs.clone()).await?;
+        if tokens <= self.threshold {
+            return Ok(None);
+        }
+        let mut compression = PromptCompression {
+            input_tokens: tokens as u32,
+            compressed_tokens: tokens as u32,
+            ..Default::default()
+        };
+        for &strategy in &self.config.strategies {
+            if tokens <= self.threshold {
+                break;
+            }
+            let changed = match strategy {
+                CompressionStrategy::Whitespace => strip_whitespace(&mut request.inputs),
+                CompressionStrategy::DedupExamples => {
+                    let removed = dedup_examples(&mut request.inputs);
+                    compression.removed_examples += removed;
+                    removed > 0
+                }
+                // The inputs have no chat turns
+                CompressionStrategy::SummarizeTurns => false,
+            };
+            if changed {
+                tokens = count(request.inputs.clone()).await?;
+                compression.applied(strategy, tokens);
+            }
+        }
+        Ok(Some(compression))
+    }
+
+    /// Compress the messages of a chat request, before the chat template renders them
+    pub(crate) async fn compress_chat(
+        &self,
+        infer: &Infer,
+        chat: &mut ChatRequest,
+    ) -> Result<Option<PromptCompression>, InferError> {
+        let count = |chat: ChatRequest| async move {
+            let (request, _) = chat.try_into_generate(infer)?;
+            infer
+                .count_tokens(request.inputs, request.add_special_tokens)
+                .await
+        };
+        let mut tokens = count(chat.clone()).await?;
+        if tokens <= self.threshold {
+            return Ok(None);
+        }
+        let mut compression = PromptCompression {
+            input_tokens: tokens as u32,
+            compressed_tokens: tokens as u32,
+            ..Default::default()
+        };
+        for &strategy in &self.config.strategies {
+            if tokens <= self.threshold {
+                break;
+            }
+            let changed = match strategy {
+                CompressionStrategy::Whitespace => {
+                    // Every text is stripped
+                    texts(&mut chat.messages)
+                        .fold(false, |changed, text| strip_whitespace(text) || changed)
+                }
+                CompressionStrategy::DedupExamples => {
+                    let removed = texts(&mut chat.messages).map(dedup_examples).sum::<u32>()
+                        + dedup_turns(&mut chat.messages);
+                    compression.removed_examples += removed;
+                    removed > 0
+                }
+                CompressionStrategy::SummarizeTurns => {
+                    let summarized = self
+                        .summarize_turns(infer, &mut chat.messages, &mut compression)
+                        .await?;
+                    compression.summarized_turns += summarized;
+                    summarized > 0
+                }
+            };
+            if changed {
+                tokens = count(chat.clone()).await?;
+                compression.applied(strategy, tokens);
+            }
+        }
+        Ok(Some(compression))
+    }
+
+    /// Replace the older messages by a summary generated by the model, added to the system
+    /// prompt, returns the number of messages summarized
+    ///
+    /// The oldest messages that do not fit in the prompt of the summary are dropped.
+    async fn summarize_turns(
+        &self,
+        infer: &Infer,
+        messages: &mut Vec<Message>,
+        compression: &mut PromptCompression,
+    ) -> Result<u32, InferError> {
+        let older = older_turns(messages, self.config.keep_turns);
+        if older.is_empty() {
+            return Ok(0);
+        }
+        let turns: Vec<String> = older
+            .iter()
+            .map(|&index| {
+                let message = TextMessage::from(messages[index].clone());
+                format!("{}: {}", message.role, message.content)
+            })
+            .collect();
+        let Some((inputs, input_tokens)) =
+            summary_prompt(infer, &turns, self.config.summary_max_tokens).await?
+        else {
+            return Ok(0);
+        };
+        let request = GenerateRequest {
+            inputs,
+            parameters: GenerateParameters {
+                do_sample: false,
+                max_new_tokens: Some(self.config.summary_max_tokens),
+                ..default_parameters()
+            },
+            template: None,
+            variables: None,
+            inputs_ids: None,
+            suffix: None,
+            add_special_tokens: false,
+            endpoint: Endpoint::Chat,
+        };
+        let response = infer.generate(request).await?;
+        // Accounted to the caller by the generation, reported in the usage of the response
+        compression.summary_prompt_tokens += input_tokens as u32;
+        compression.summary_completion_tokens += response.generated_text.generated_tokens;
+        let summary = format!(
+            "Summary of the earlier conversation: {}",
+            response.generated_text.text.trim()
+        );
+
+        let mut index = 0;
+        messages.retain(|_| {
+            index += 1;
+            older.binary_search(&(index - 1)).is_err()
+        });
+        match messages.first_mut() {
+            Some(message) if message.role == "system" => message.content.push(MessageChunk::Text {
+                text: format!("\n\n{summary}"),
+            }),
+            _ => messages.insert(
+                0,
+                Message {
+                    role: "system".to_string(),
+                    content: MessageContent::SingleText(summary),
+                    name: None,
+                    prefix: false,
+                },
+            ),
+        }
+        Ok(older.len() as u32)
+    }
+}
+
+/// Prompt summarizing the newest `turns` that fit in the input tokens of the chat endpoint,
+/// along with the `max_new_tokens` of the summary, with its number of tokens, `None` when not
+/// even the newest turn fits
+///
+/// The oldest turns are left out rather than truncating the templated prompt, whose leading
+/// tokens are the ones of the template.
+async fn summary_prompt(
+    infer: &Infer,
+    turns: &[String],
+    max_new_tokens: u32,
+) -> Result<Option<(String, usize)>, InferError> {
+    let prompt = |turns: &[String]| {
+        // The instruction comes after the transcript, as the question of the conversation
+        let prompt = format!(
+            "{}\n\nSummarize the conversation above in a few sentences, keeping the facts, \
+            names and decisions needed to continue it.",
+            turns.join("\n\n")
+        );
+        infer.apply_chat_template(
+            None,
+            vec![Message {
+                role: "user".to_string(),
+                content: MessageContent::SingleText(prompt),
+                name: None,
+                prefix: false,
+            }],
+            None,
+        )
+    };
+    let (max_input_length, _) = infer.validation.max_input_length(Endpoint::Chat);
+    let max_input_length = max_input_length.min(
+        infer
+            .validation
+            .max_total_tokens()
+            .saturating_sub(max_new_tokens as usize),
+    );
+
+    // Estimated from the tokens of every turn, then checked on the whole prompt
+    let mut budget =
+        max_input_length.saturating_sub(infer.count_tokens(prompt(&[])?, false).await?);
+    let mut first = turns.len();
+    while first > 0 {
+        let tokens = infer.count_tokens(turns[first - 1].clone(), false).await? + 1;
+        if tokens > budget {
+            break;
+        }
+        budget -= tokens;
+        first -= 1;
+    }
+    while first < turns.len() {
+        let inputs = prompt(&turns[first..])?;
+        let tokens = infer.count_tokens(inputs.clone(), false).await?;
+        if tokens <= max_input_length {
+            return Ok(Some((inputs, tokens)));
+        }
+        first += 1;
+    }
+    Ok(None)
+}
+
+impl PromptCompression {
+    fn applied(&mut self, strategy: CompressionStrategy, tokens: usize) {
+        let label = match strategy {
+            CompressionStrategy::Whitespace => "whitespace",
+            CompressionStrategy::DedupExamples => "dedup_examples",
+            CompressionStrategy::SummarizeTurns => "summarize_turns",
+        };
+        metrics::counter!("tgi_request_prompt_compression", "strategy" => label).increment(1);
+        self.strategies.push(strategy);
+        self.compressed_tokens = tokens as u32;
+    }
+}
+
+/// Texts of the messages, without their image chunks
+fn texts(messages: &mut [Message]) -> impl Iterator<Item = &mut String> {
+    messages
+        .iter_mut()
+        .flat_map(|message| message.content.texts_mut())
+}
+
+/// Collapse the runs of spaces and tabs after the indentation of the lines, the trailing
+/// spaces of the lines and the blank lines after the first one, returns whether the text
+/// changed
+///
+/// The lines of the fenced code blocks are kept as is, their spaces can be significant. The end
+/// of the text is kept as is, its trailing space can be part of the next token.
+fn strip_whitespace(text: &mut String) -> bool {
+    let mut output = String::with_capacity(text.len());
+    let mut lines = text.split('\n').peekable();
+    let mut blank = false;
+    let mut fence: Option<&str> = None;
+    while let Some(line) = lines.next() {
+        let last = lines.peek().is_none();
+        let marker = ["```", "~~~"]
+            .into_iter()
+            .find(|marker| line.trim_start().starts_with(marker));
+        if fence.is_some() || marker.is_some() {
+            // A fence is closed by the marker that opened it
+            fence = match (fence, marker) {
+                (None, marker) => marker,
+                (Some(open), Some(marker)) if open == marker => None,
+                (open, _) => open,
+            };
+            blank = false;
+            output.push_str(line);
+            if !last {
+                output.push('\n');
+            }
+            continue;
+        }
+        let line = if last { line } else { line.trim_end() };
+        if line.trim().is_empty() && !last {
+            if blank {
+                continue;
+            }
+            blank = true;
+        } else {
+            blank = false;
+        }
+        let content = line.trim_start();
+        output.push_str(&line[..line.len() - content.len()]);
+        let mut space = false;
+        for c in content.chars() {
+            if c == ' ' || c == '\t' {
+                space = true;
+                continue;
+            }
+            if space {
+                output.push(' ');
+                space = false;
+            }
+            output.push(c);
+        }
+        if space {
+            output.push(' ');
+        }
+        if !last {
+            output.push('\n');
+        }
+    }
+    if output == *text {
+        return false;
+    }
+    *text = output;
+    true
+}
+
+/// Remove the blocks of text, separated by blank lines, repeated from an earlier block, returns
+/// the number of blocks removed
+///
+/// The last block is kept, it usually is the question of the prompt.
+fn dedup_examples(text: &mut String) -> u32 {
+    let blocks: Vec<&str> = text.split("\n\n").collect();
+    let last = blocks.len() - 1;
+    let mut seen = HashSet::new();
+    let mut removed = 0;
+    let kept: Vec<&str> = blocks
+        .iter()
+        .enumerate()
+        .filter(|&(index, block)| {
+            let example = block.trim();
+            if index == last || example.len() < MIN_EXAMPLE_LENGTH || seen.insert(example) {
+                true
+            } else {
+                removed += 1;
+                false
+            }
+        })
+        .map(|(_, block)| *block)
+        .collect();
+    if removed > 0 {
+        *text = kept.join("\n\n");
+    }
+    removed
+}
+
+/// Remove the pairs of user and assistant messages repeated from an earlier pair, returns the
+/// number of pairs removed
+///
+/// The last message is kept, it is the one being answered.
+fn dedup_turns(messages: &mut Vec<Message>) -> u32 {
+    let mut pairs: Vec<usize> = Vec::new();
+    let mut removed = 0;
+    let mut index = 0;
+    while index + 2 < messages.len() {
+        if messages[index].role != "user" || messages[index + 1].role != "assistant" {
+            index += 1;
+            continue;
+        }
+        let repeated = pairs.iter().any(|&pair| {
+            messages[pair] == messages[index] && messages[pair + 1] == messages[index + 1]
+        });
+        if repeated {
+            messages.drain(index..index + 2);
+            removed += 1;
+        } else {
+            pairs.push(index);
+            index += 2;
+        }
+    }
+    removed
+}
+
+/// Sorted indices of the messages to summarize: the messages before the last `keep_turns`
+/// ones, but the system messages
+///
+/// The kept messages start with a user message, as most chat templates expect.
+fn older_turns(messages: &[Message], keep_turns: usize) -> Vec<usize> {
+    let turns: Vec<usize> = (0..messages.len())
+        .filter(|&index| messages[index].role != "system")
+        .collect();
+    let mut split = turns.len().saturating_sub(keep_turns.max(1));
+    while split > 0 && messages[turns[split]].role != "user" {
+        split -= 1;
+    }
+    let mut older = turns;
+    older.truncate(split);
+    older
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::{Backend, GeneratedText, GenerationStream, InferStreamResponse};
+    use crate::router_config::PrefixPinningConfig;
+    use crate::tests::get_word_level_tokenizer;
+    use crate::validation::{Chunk, ValidGenerateRequest, Validation};
+    use crate::{
+        ChatTemplateVersions, FinishReason, HubProcessorConfig, HubTokenizerConfig, Token,
+    };
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tokio::time::Instant;
+
+    /// Backend generating the same 4 tokens summary, keeping the inputs it received
+    #[derive(Clone, Default)]
+    struct SummaryBackend {
+        inputs: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Backend for SummaryBackend {
+        fn schedule(&self, request: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
+            let inputs = request
+                .inputs
+                .iter()
+                .map(|chunk| match chunk {
+                    Chunk::Text(text) => text.as_str(),
+                    Chunk::Image(_) => "",
+                })
+                .collect();
+            self.inputs.lock().unwrap().push(inputs);
+            let end = InferStreamResponse::End {
+                token: Token {
+                    id: 0,
+                    text: "sunny".to_string(),
+                    logprob: 0.0,
+                    special: false,
+                    bytes: None,
+                },
+                top_tokens: vec![],
+                generated_text: GeneratedText {
+                    text: "It is sunny".to_string(),
+                    generated_tokens: 4,
+                    finish_reason: FinishReason::EndOfSequenceToken,
+                    seed: None,
+                    backend: None,
+                    steps: None,
+                },
+                start: Instant::now(),
+                queued: Instant::now(),
+            };
+            Ok(Box::pin(tokio_stream::iter([Ok(end)])))
+        }
+
+        async fn health(&self, _: bool) -> bool {
+            true
+        }
+    }
+
+    /// Every word and punctuation is one token, and the chat template renders `role: content`
+    /// lines
+    fn infer(
+        backend: SummaryBackend,
+        max_input_length: usize,
+        config: PromptCompressionConfig,
+    ) -> Infer {
+        let validation = Validation::new(
+            1,
+            get_word_level_tokenizer(&[]),
+            None,
+            None,
+            1,
+            4,
+            1,
+            max_input_length,
+            max_input_length + 64,
+            true,
+            HashMap::new(),
+            HashMap::new(),
+            None,
+        );
+        let tokenizer_config = HubTokenizerConfig {
+            chat_template: Some(ChatTemplateVersions::Single(
+                "{% for message in messages %}{{ message.role }}: {{ message.content }}\n{% endfor %}"
+                    .to_string(),
+            )),
+            ..Default::default()
+        };
+        Infer::new(
+            backend,
+            validation,
+            4,
+            tokenizer_config,
+            HubProcessorConfig::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            None,
+            None,
+            None,
+            PrefixPinningConfig::default(),
+            config,
+        )
+    }
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: MessageContent::SingleText(content.to_string()),
+            name: None,
+            prefix: false,
+        }
+    }
+
+    #[test]
+    fn test_strip_whitespace() {
+        let mut text = "def f():\n    return  1   +\t2  \n\n\n\nAnswer:  ".to_string();
+        assert!(strip_whitespace(&mut text));
+        assert_eq!(text, "def f():\n    return 1 + 2\n\nAnswer: ");
+        assert!(!strip_whitespace(&mut text));
+
+        // The fenced code blocks are kept as is
+        let code = "```python\nx  =  [1,   2]  \n\n\n~~~\n```";
+        let mut text = format!("Fix  this:\n{code}\nThanks  ");
+        assert!(strip_whitespace(&mut text));
+        assert_eq!(text, format!("Fix this:\n{code}\nThanks "));
+    }
+
+    #[test]
+    fn test_dedup_examples() {
+        let example = "Q: What is 2 + 2?\nA: 4";
+        let mut text =
+            format!("{example}\n\n###\n\nQ: What is 3 + 3?\nA: 6\n\n###\n\n{example}\n\n{example}");
+        assert_eq!(dedup_examples(&mut text), 1);
+        // The separators are too short to be examples, the last block is the question
+        assert_eq!(
+            text,
+            format!("{example}\n\n###\n\nQ: What is 3 + 3?\nA: 6\n\n###\n\n{example}")
+        );
+        assert_eq!(dedup_examples(&mut text), 0);
+    }
+
+    #[test]
+    fn test_dedup_turns() {
+        let mut messages = vec![
+            message("system", "You are a calculator"),
+            message("user", "2 + 2"),
+            message("assistant", "4"),
+            message("user", "3 + 3"),
+            message("assistant", "6"),
+            message("user", "2 + 2"),
+            message("assistant", "4"),
+            message("user", "2 + 2"),
+        ];
+        assert_eq!(dedup_turns(&mut messages), 1);
+        assert_eq!(messages.len(), 6);
+        assert_eq!(messages[5], message("user", "2 + 2"));
+    }
+
+    #[test]
+    fn test_older_turns() {
+        let messages = vec![
+            message("system", "Be brief"),
+            message("user", "Hi"),
+            message("assistant", "Hello"),
+            message("user", "Weather?"),
+            message("assistant", "Sunny"),
+            message("user", "Tomorrow?"),
+        ];
+        // The kept messages start with the user message before the last 2
+        assert_eq!(older_turns(&messages, 2), [1, 2]);
+        assert_eq!(older_turns(&messages, 3), [1, 2]);
+        assert!(older_turns(&messages, 5).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_compress_inputs() {
+        let config = PromptCompressionConfig {
+            threshold_tokens: Some(24),
+            strategies: vec![
+                CompressionStrategy::SummarizeTurns,
+                CompressionStrategy::DedupExamples,
+            ],
+            ..Default::default()
+        };
+        let infer = infer(SummaryBackend::default(), 64, config);
+        let example = "Q: What is 2 + 2?\nA: 4";
+        let mut request = GenerateRequest {
+            inputs: format!("{example}\n\n{example}\n\nQ: What is 3 + 3?"),
+            parameters: default_parameters(),
+            template: None,
+            variables: None,
+            inputs_ids: None,
+            suffix: None,
+            add_special_tokens: false,
+            endpoint: Endpoint::Generate,
+        };
+        let compression = infer.compress_prompt(&mut request).await.unwrap().unwrap();
+        assert_eq!(request.inputs, format!("{example}\n\nQ: What is 3 + 3?"));
+        assert_eq!(compression.input_tokens, 30);
+        assert_eq!(compression.compressed_tokens, 19);
+        // The inputs have no turns to summarize
+        assert_eq!(compression.strategies, [CompressionStrategy::DedupExamples]);
+        assert_eq!(compression.removed_examples, 1);
+
+        // Under the threshold
+        assert!(infer.compress_prompt(&mut request).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_compress_chat() {
+        let config = PromptCompressionConfig {
+            threshold_tokens: Some(16),
+            strategies: vec![CompressionStrategy::SummarizeTurns],
+            keep_turns: 1,
+            summary_max_tokens: 16,
+        };
+        let backend = SummaryBackend::default();
+        // The summary prompt only fits the last 3 older turns
+        let infer = infer(backend.clone(), 40, config);
+        let mut chat = ChatRequest {
+            messages: vec![
+                message("system", "Be brief"),
+                message("user", "Hi there, I am planning a trip to Paris next week"),
+                message("assistant", "Hello"),
+                message("user", "What about the weather?"),
+                message("assistant", "Sunny"),
+                message("user", "Tomorrow?"),
+            ],
+            ..Default::default()
+        };
+        let compression = infer.compress_chat(&mut chat).await.unwrap().unwrap();
+        assert_eq!(
+            compression.strategies,
+            [CompressionStrategy::SummarizeTurns]
+        );
+        assert_eq!(compression.summarized_turns, 4);
+        assert_eq!(compression.summary_completion_tokens, 4);
+        assert_eq!(
+            chat.messages,
+            [
+                Message {
+                    role: "system".to_string(),
+                    content: MessageContent::MultipleChunks(vec![
+                        MessageChunk::Text {
+                            text: "Be brief".to_string()
+                        },
+                        MessageChunk::Text {
+                            text: "\n\nSummary of the earlier conversation: It is sunny"
+                                .to_string()
+                        },
+                    ]),
+                    name: None,
+                    prefix: false,
+                },
+                message("user", "Tomorrow?"),
+            ]
+        );
+
+        // The oldest turns are left out, the template of the prompt is not truncated
+        let inputs = backend.inputs.lock().unwrap();
+        assert!(
+            inputs[0].starts_with("user: assistant: Hello\n\nuser: What about the weather?"),
+            "{}",
+            inputs[0]
+        );
+        assert_eq!(compression.summary_prompt_tokens, 36);
+    }
+
+    #[test]
+    fn test_prompt_compressor_disabled() {
+        assert!(PromptCompressor::new(PromptCompressionConfig::default()).is_none());
+        let config = PromptCompressionConfig {
+            threshold_tokens: Some(1024),
+            strategies: vec![],
+            ..Default::default()
+        };
+        assert!(PromptCompressor::new(config).is_none());
+    }
+}
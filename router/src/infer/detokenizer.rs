@@ -0,0 +1,180 @@
+use crate::infer::InferStreamResponse;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+/// Number of prompt tokens used as context to decode the first generated tokens
+const PREFIX_TOKENS: usize = 5;
+
+/// Incremental detokenization of the tokens generated for one request
+///
+/// Every token is decoded together with the previous ones, so that tokenizers depending on
+/// the context, like sentencepiece with its prefix spaces, give the same text as decoding the
+/// full sequence. Text ending with an incomplete character is held back until it is complete.
+///
+/// This replaces the token texts sent by the backend, it does not remove them: the shards
+/// still decode every token, for their stop sequences, `raw_bytes` requests and routers
+/// without a fast tokenizer.
+pub(crate) struct Detokenizer {
+    tokenizer: Arc<Tokenizer>,
+    ids: Vec<u32>,
+    prefix_offset: usize,
+    read_offset: usize,
+}
+
+impl Detokenizer {
+    pub(crate) fn new(tokenizer: Arc<Tokenizer>, input_ids: &[u32]) -> Self {
+        let ids = input_ids[input_ids.len().saturating_sub(PREFIX_TOKENS)..].to_vec();
+        Self {
+            tokenizer,
+            prefix_offset: 0,
+            read_offset: ids.len(),
+            ids,
+        }
+    }
+
+    /// Replace the token texts of `response` with the router decoding
+    pub(crate) fn apply(&mut self, response: &mut InferStreamResponse) {
+        let (token, end) = match response {
+            InferStreamResponse::Queued { .. } | InferStreamResponse::Prefill(_) => return,
+            InferStreamResponse::Intermediate { token, .. } => (token, false),
+            InferStreamResponse::End { token, .. } => (token, true),
+        };
+        let text = self.next(token.id, token.special).and_then(|mut text| {
+            if end {
+                text.push_str(&self.flush()?);
+            }
+            Some(text)
+        });
+        // Keep the backend text if the tokenizer cannot decode the token
+        if let Some(text) = text {
+            token.text = text;
+        }
+    }
+
+    /// Text added by the next generated token
+    fn next(&mut self, id: u32, special: bool) -> Option<String> {
+        if special {
+            // Special tokens are sent as is and are not used as context for the next tokens
+            let mut text = self.flush()?;
+            text.push_str(&self.tokenizer.decode(&[id], false).ok()?);
+            self.prefix_offset = self.ids.len();
+            return Some(text);
+        }
+
+        self.ids.push(id);
+        let prefix_text = self.decode(self.read_offset)?;
+        let new_text = self.decode(self.ids.len())?;
+        if new_text.len() > prefix_text.len() && !new_text.ends_with('\u{FFFD}') {
+            Some(self.advance(&prefix_text, new_text))
+        } else {
+            Some(String::new())
+        }
+    }
+
+    /// Text of the tokens held back, replacing incomplete characters
+    fn flush(&mut self) -> Option<String> {
+        if self.read_offset == self.ids.len() {
+            return Some(String::new());
+        }
+        let prefix_text = self.decode(self.read_offset)?;
+        let new_text = self.decode(self.ids.len())?;
+        Some(self.advance(&prefix_text, new_text))
+    }
+
+    fn advance(&mut self, prefix_text: &str, new_text: String) -> String {
+        let text = new_text
+            .strip_prefix(prefix_text)
+            .or_else(|| new_text.get(prefix_text.len()..))
+            .unwrap_or(&new_text)
+            .to_string();
+        // Only the last decoded tokens are needed as context
+        self.ids.drain(..self.read_offset);
+        self.prefix_offset = 0;
+        self.read_offset = self.ids.len();
+        text
+    }
+
+    /// Decode the tokens from the prefix offset up to `end`
+    fn decode(&self, end: usize) -> Option<String> {
+        self.tokenizer
+            .decode(&self.ids[self.prefix_offset..end], false)
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Token;
+    use std::str::FromStr;
+
+    /// Sentencepiece style tokenizer with byte fallback, like Llama
+    fn tokenizer() -> Arc<Tokenizer> {
+        let json = r#"{
+            "version": "1.0",
+            "added_tokens": [
+                {"id": 6, "content": "</s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}
+            ],
+            "model": {
+                "type": "BPE",
+                "unk_token": "<unk>",
+                "byte_fallback": true,
+                "fuse_unk": true,
+                "vocab": {"<unk>": 0, "▁Hello": 1, "▁world": 2, "<0xE2>": 3, "<0x82>": 4, "<0xAC>": 5, "</s>": 6},
+                "merges": []
+            },
+            "decoder": {
+                "type": "Sequence",
+                "decoders": [
+                    {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+                    {"type": "ByteFallback"},
+                    {"type": "Fuse"},
+                    {"type": "Strip", "content": " ", "start": 1, "stop": 0}
+                ]
+            }
+        }"#;
+        Arc::new(Tokenizer::from_str(json).unwrap())
+    }
+
+    fn intermediate(id: u32, special: bool) -> InferStreamResponse {
+        InferStreamResponse::Intermediate {
+            token: Token {
+                id,
+                text: "backend".to_string(),
+                logprob: 0.0,
+                special,
+                bytes: None,
+            },
+            top_tokens: vec![],
+        }
+    }
+
+    fn text(response: InferStreamResponse) -> String {
+        match response {
+            InferStreamResponse::Intermediate { token, .. } => token.text,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_detokenizer() {
+        let mut detokenizer = Detokenizer::new(tokenizer(), &[1]);
+        let mut texts = vec![];
+        for (id, special) in [(2, false), (3, false), (4, false), (5, false), (6, true)] {
+            let mut response = intermediate(id, special);
+            detokenizer.apply(&mut response);
+            texts.push(text(response));
+        }
+        // The prefix space is kept and "€" is only sent once complete
+        assert_eq!(texts, vec![" world", "", "", "€", "</s>"]);
+    }
+
+    #[test]
+    fn test_detokenizer_flush() {
+        let mut detokenizer = Detokenizer::new(tokenizer(), &[]);
+        let mut response = intermediate(3, false);
+        detokenizer.apply(&mut response);
+        assert_eq!(text(response), "");
+        assert_eq!(detokenizer.flush().unwrap(), "\u{FFFD}");
+    }
+}
@@ -0,0 +1,214 @@
+use crate::infer::{
+    Backend, BackendCapabilities, GenerationStream, InferError, InferStreamResponse,
+};
+use crate::validation::ValidGenerateRequest;
+use crate::{BackendMetadata, LoadAdapterRequest, PrefillToken, ShardInfo};
+use async_trait::async_trait;
+use clap::ValueEnum;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::{instrument, Instrument};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExperimentMode {
+    /// Mirror a share of the requests to the candidate and only log its responses
+    Shadow,
+    /// Serve a share of the requests from the candidate
+    Split,
+}
+
+/// Backend sending a share of the traffic to a candidate backend, for shadow or A/B testing
+///
+/// Every request is labeled with the experiment name and the arm (`control`, `candidate` or
+/// `shadow`) in the `tgi_experiment_request_count` metric and in the logs.
+pub struct ExperimentBackend {
+    name: String,
+    control: Arc<dyn Backend + Send + Sync>,
+    candidate: Arc<dyn Backend + Send + Sync>,
+    mode: ExperimentMode,
+    /// Share of the requests sent to the candidate, between 0 and 1
+    ratio: f64,
+}
+
+impl ExperimentBackend {
+    pub fn new(
+        name: String,
+        control: impl Backend + Send + Sync + 'static,
+        candidate: impl Backend + Send + Sync + 'static,
+        mode: ExperimentMode,
+        ratio: f64,
+    ) -> Self {
+        Self {
+            name,
+            control: Arc::new(control),
+            candidate: Arc::new(candidate),
+            mode,
+            ratio: ratio.clamp(0.0, 1.0),
+        }
+    }
+
+    fn count(&self, arm: &'static str) {
+        metrics::counter!("tgi_experiment_request_count", "experiment" => self.name.clone(), "arm" => arm)
+            .increment(1);
+    }
+}
+
+#[async_trait]
+impl Backend for ExperimentBackend {
+    #[instrument(skip_all, fields(experiment = %self.name))]
+    fn schedule(&self, request: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
+        let selected = selected(self.ratio, rand::random());
+
+        match (self.mode, selected) {
+            (ExperimentMode::Split, true) => {
+                self.count("candidate");
+                let stream = self.candidate.schedule(request)?;
+                Ok(label(stream, self.candidate.name(), "candidate"))
+            }
+            (ExperimentMode::Shadow, true) => {
+                // Shadow failures must never impact the served request
+                match self.candidate.schedule(request.clone()) {
+                    Ok(stream) => {
+                        self.count("shadow");
+                        tokio::spawn(drain_shadow(stream).instrument(tracing::info_span!(
+                            "shadow",
+                            experiment = %self.name
+                        )));
+                    }
+                    Err(err) => tracing::warn!("Could not schedule shadow request: {err}"),
+                }
+                self.count("control");
+                let stream = self.control.schedule(request)?;
+                Ok(label(stream, self.control.name(), "control"))
+            }
+            (_, false) => {
+                self.count("control");
+                let stream = self.control.schedule(request)?;
+                Ok(label(stream, self.control.name(), "control"))
+            }
+        }
+    }
+
+    async fn health(&self, current_health: bool) -> bool {
+        let control = self.control.health(current_health).await;
+        match self.mode {
+            ExperimentMode::Shadow => control,
+            ExperimentMode::Split => control && self.candidate.health(current_health).await,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.control.name()
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        let control = self.control.capabilities();
+        match self.mode {
+            ExperimentMode::Shadow => control,
+            // Requests must be servable by both arms
+            ExperimentMode::Split => control.intersect(self.candidate.capabilities()),
+        }
+    }
+
+    fn shards(&self) -> Vec<ShardInfo> {
+        self.control.shards()
+    }
+
+    fn metadata(&self) -> BackendMetadata {
+        self.control.metadata()
+    }
+
+    fn unpin_prefix(&self, id: u64) {
+        self.control.unpin_prefix(id);
+        self.candidate.unpin_prefix(id);
+    }
+
+    // Requests without generation are not part of the experiment
+    async fn prefill_logits(
+        &self,
+        request: ValidGenerateRequest,
+    ) -> Result<Vec<PrefillToken>, InferError> {
+        self.control.prefill_logits(request).await
+    }
+
+    async fn embed(&self, request: ValidGenerateRequest) -> Result<Vec<f32>, InferError> {
+        self.control.embed(request).await
+    }
+
+    // Both backends serve the requests selecting the adapter
+    async fn load_adapter(&self, request: LoadAdapterRequest) -> Result<(), InferError> {
+        self.control.load_adapter(request.clone()).await?;
+        self.candidate.load_adapter(request).await
+    }
+}
+
+/// Whether a request with the uniform `draw` in [0, 1) goes to the candidate
+fn selected(ratio: f64, draw: f64) -> bool {
+    draw < ratio
+}
+
+/// Forward `stream`, recording the backend that served the request and logging the arm
+fn label(
+    mut stream: GenerationStream,
+    backend: &'static str,
+    arm: &'static str,
+) -> GenerationStream {
+    let (response_tx, response_rx) = mpsc::unbounded_channel();
+    tokio::spawn(
+        async move {
+            while let Some(mut response) = stream.next().await {
+                if let Ok(InferStreamResponse::End { generated_text, .. }) = &mut response {
+                    generated_text.backend = Some(backend);
+                    tracing::debug!(arm, "Served by {backend}");
+                }
+                if response_tx.send(response).is_err() {
+                    // Client dropped the stream
+                    break;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    Box::pin(UnboundedReceiverStream::new(response_rx))
+}
+
+/// Consume a shadow request and log its result for offline comparison
+async fn drain_shadow(mut stream: GenerationStream) {
+    let start = Instant::now();
+    while let Some(response) = stream.next().await {
+        match response {
+            Ok(InferStreamResponse::End { generated_text, .. }) => {
+                tracing::info!(
+                    generated_tokens = generated_text.generated_tokens,
+                    finish_reason = %generated_text.finish_reason,
+                    total_time = ?start.elapsed(),
+                    output = generated_text.text,
+                    "Shadow request finished"
+                );
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                metrics::counter!("tgi_experiment_shadow_failure").increment(1);
+                tracing::warn!("Shadow request failed: {err}");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selected() {
+        assert!(!selected(0.0, 0.0));
+        assert!(selected(0.1, 0.05));
+        assert!(!selected(0.1, 0.1));
+        assert!(selected(1.0, 0.999));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::infer::{
+    Backend, BackendCapabilities, GenerationStream, InferError, InferStreamResponse,
+};
+use crate::validation::ValidGenerateRequest;
+use crate::{BackendMetadata, LoadAdapterRequest, PrefillToken, ShardInfo};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::{instrument, Instrument};
+
+/// Interval between health checks of an unhealthy primary backend
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Backend sending requests to a primary backend, and to a fallback backend when the primary
+/// is unhealthy or its queue is too long
+///
+/// The queue is considered too long when a request sent to the primary has been waiting for
+/// its first token for more than `max_queue_time`.
+pub struct FailoverBackend {
+    primary: Arc<dyn Backend + Send + Sync>,
+    fallback: Arc<dyn Backend + Send + Sync>,
+    primary_health: Arc<AtomicBool>,
+    primary_waiting: Arc<Waiting>,
+    max_queue_time: Duration,
+}
+
+impl FailoverBackend {
+    pub fn new(
+        primary: impl Backend + Send + Sync + 'static,
+        fallback: impl Backend + Send + Sync + 'static,
+        max_queue_time: Duration,
+    ) -> Self {
+        let primary: Arc<dyn Backend + Send + Sync> = Arc::new(primary);
+        let primary_health = Arc::new(AtomicBool::new(true));
+
+        // Recover the primary once it is healthy again, even if nobody calls `/health`
+        tokio::spawn(health_task(primary.clone(), primary_health.clone()));
+
+        Self {
+            primary,
+            fallback: Arc::new(fallback),
+            primary_health,
+            primary_waiting: Arc::new(Waiting::default()),
+            max_queue_time,
+        }
+    }
+
+    /// Whether the next request should be sent to the fallback backend
+    fn use_fallback(&self) -> bool {
+        !self.primary_health.load(Ordering::SeqCst)
+            || self
+                .primary_waiting
+                .oldest()
+                .is_some_and(|queued| queued.elapsed() > self.max_queue_time)
+    }
+}
+
+#[async_trait]
+impl Backend for FailoverBackend {
+    #[instrument(skip_all)]
+    fn schedule(&self, request: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
+        if self.use_fallback() {
+            let name = self.fallback.name();
+            metrics::counter!("tgi_failover_request_count", "backend" => name).increment(1);
+            let stream = self.fallback.schedule(request)?;
+            return Ok(forward(stream, name, None));
+        }
+
+        let name = self.primary.name();
+        metrics::counter!("tgi_failover_request_count", "backend" => name).increment(1);
+        let stream = self.primary.schedule(request).inspect_err(|err| {
+            if err.is_backend_failure() {
+                self.primary_health.store(false, Ordering::SeqCst);
+            }
+        })?;
+        let waiting = WaitingGuard::new(self.primary_waiting.clone());
+        Ok(forward(
+            stream,
+            name,
+            Some((waiting, self.primary_health.clone())),
+        ))
+    }
+
+    async fn health(&self, current_health: bool) -> bool {
+        let primary = self.primary.health(current_health).await;
+        self.primary_health.store(primary, Ordering::SeqCst);
+        primary || self.fallback.health(current_health).await
+    }
+
+    fn name(&self) -> &'static str {
+        "failover"
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        // Requests must be servable by both backends
+        self.primary
+            .capabilities()
+            .intersect(self.fallback.capabilities())
+    }
+
+    fn shards(&self) -> Vec<ShardInfo> {
+        self.primary.shards()
+    }
+
+    fn metadata(&self) -> BackendMetadata {
+        self.primary.metadata()
+    }
+
+    fn unpin_prefix(&self, id: u64) {
+        self.primary.unpin_prefix(id);
+        self.fallback.unpin_prefix(id);
+    }
+
+    async fn prefill_logits(
+        &self,
+        request: ValidGenerateRequest,
+    ) -> Result<Vec<PrefillToken>, InferError> {
+        if self.use_fallback() {
+            return self.fallback.prefill_logits(request).await;
+        }
+        self.primary.prefill_logits(request).await
+    }
+
+    async fn embed(&self, request: ValidGenerateRequest) -> Result<Vec<f32>, InferError> {
+        if self.use_fallback() {
+            return self.fallback.embed(request).await;
+        }
+        self.primary.embed(request).await
+    }
+
+    // The fallback serves the base model of a degraded primary
+    async fn load_adapter(&self, request: LoadAdapterRequest) -> Result<(), InferError> {
+        self.primary.load_adapter(request).await
+    }
+}
+
+/// Forward `stream` to a new stream, recording which backend served the request
+///
+/// For the primary backend, the request is removed from the waiting set on its first response,
+/// and the primary is marked unhealthy if it fails. The errors of the request itself, such as
+/// its validation or cancellation, leave it healthy.
+fn forward(
+    mut stream: GenerationStream,
+    name: &'static str,
+    mut primary: Option<(WaitingGuard, Arc<AtomicBool>)>,
+) -> GenerationStream {
+    let (response_tx, response_rx) = mpsc::unbounded_channel();
+    tokio::spawn(
+        async move {
+            while let Some(mut response) = stream.next().await {
+                if let Some((waiting, health)) = primary.as_mut() {
+                    waiting.done();
+                    if matches!(&response, Err(err) if err.is_backend_failure()) {
+                        health.store(false, Ordering::SeqCst);
+                    }
+                }
+                if let Ok(InferStreamResponse::End { generated_text, .. }) = &mut response {
+                    generated_text.backend = Some(name);
+                }
+                if response_tx.send(response).is_err() {
+                    // Client dropped the stream
+                    break;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    Box::pin(UnboundedReceiverStream::new(response_rx))
+}
+
+async fn health_task(primary: Arc<dyn Backend + Send + Sync>, primary_health: Arc<AtomicBool>) {
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if !primary_health.load(Ordering::SeqCst) && primary.health(false).await {
+            tracing::info!("Primary backend `{}` is healthy again", primary.name());
+            primary_health.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Requests sent to the primary backend that did not receive their first response yet
+#[derive(Default)]
+struct Waiting {
+    next_id: AtomicU64,
+    /// Ids are increasing, so the first entry is the oldest request
+    entries: Mutex<BTreeMap<u64, Instant>>,
+}
+
+impl Waiting {
+    fn oldest(&self) -> Option<Instant> {
+        let entries = self.entries.lock().unwrap();
+        entries.first_key_value().map(|(_, queued)| *queued)
+    }
+}
+
+/// Remove a request from the waiting set on its first response or when dropped
+struct WaitingGuard {
+    waiting: Arc<Waiting>,
+    id: Option<u64>,
+}
+
+impl WaitingGuard {
+    fn new(waiting: Arc<Waiting>) -> Self {
+        let id = waiting.next_id.fetch_add(1, Ordering::SeqCst);
+        waiting.entries.lock().unwrap().insert(id, Instant::now());
+        Self {
+            waiting,
+            id: Some(id),
+        }
+    }
+
+    fn done(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.waiting.entries.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+impl Drop for WaitingGuard {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::ValidationError;
+
+    #[test]
+    fn test_waiting_oldest() {
+        let waiting = Arc::new(Waiting::default());
+        assert!(waiting.oldest().is_none());
+
+        let mut first = WaitingGuard::new(waiting.clone());
+        let expected = waiting.oldest().unwrap();
+        let second = WaitingGuard::new(waiting.clone());
+        assert_eq!(waiting.oldest(), Some(expected));
+
+        first.done();
+        assert!(waiting.oldest().unwrap() >= expected);
+
+        drop(second);
+        assert!(waiting.oldest().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_forward_primary_health() {
+        let forward_error = |err| async {
+            let health = Arc::new(AtomicBool::new(true));
+            let waiting = WaitingGuard::new(Arc::new(Waiting::default()));
+            let stream: GenerationStream = Box::pin(tokio_stream::iter([Err(err)]));
+            let mut stream = forward(stream, "primary", Some((waiting, health.clone())));
+            assert!(stream.next().await.unwrap().is_err());
+            health.load(Ordering::SeqCst)
+        };
+
+        // Errors of the request do not fail the primary over
+        assert!(forward_error(InferError::Cancelled).await);
+        assert!(forward_error(InferError::ValidationError(ValidationError::BestOfSeed)).await);
+        assert!(!forward_error(InferError::GenerationError("shard down".to_string())).await);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::router_config::FimConfig;
+use std::collections::HashSet;
+
+/// Fill-in-the-middle tokens of the known code models, in prefix, suffix, middle order
+const KNOWN_TOKENS: [[&str; 3]; 3] = [
+    // StarCoder, SantaCoder
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    // Qwen2.5-Coder, CodeGemma
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    // DeepSeek-Coder
+    ["<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"],
+];
+
+/// Builds the prompts of fill-in-the-middle requests, in the prefix-suffix-middle format
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FimTemplate {
+    prefix: String,
+    suffix: String,
+    middle: String,
+}
+
+impl FimTemplate {
+    /// Use the tokens of the router config, or the known tokens found in the tokenizer
+    pub(crate) fn new(
+        config: Option<FimConfig>,
+        tokenizer: Option<&tokenizers::Tokenizer>,
+    ) -> Option<Self> {
+        if let Some(config) = config {
+            return Some(Self {
+                prefix: config.prefix,
+                suffix: config.suffix,
+                middle: config.middle,
+            });
+        }
+        let added_tokens: HashSet<String> = tokenizer?
+            .get_added_tokens_decoder()
+            .into_values()
+            .map(|token| token.content)
+            .collect();
+        Self::from_added_tokens(&added_tokens)
+    }
+
+    fn from_added_tokens(added_tokens: &HashSet<String>) -> Option<Self> {
+        let [prefix, suffix, middle] = KNOWN_TOKENS
+            .iter()
+            .find(|tokens| tokens.iter().all(|token| added_tokens.contains(*token)))?;
+        Some(Self {
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+            middle: middle.to_string(),
+        })
+    }
+
+    /// Prompt of the text between `prefix` and `suffix`
+    pub(crate) fn render(&self, prefix: &str, suffix: &str) -> String {
+        format!(
+            "{}{prefix}{}{suffix}{}",
+            self.prefix, self.suffix, self.middle
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fim_template() {
+        let added_tokens = HashSet::from([
+            "<|endoftext|>".to_string(),
+            "<fim_prefix>".to_string(),
+            "<fim_middle>".to_string(),
+            "<fim_suffix>".to_string(),
+        ]);
+        let template = FimTemplate::from_added_tokens(&added_tokens).unwrap();
+        assert_eq!(
+            template.render("def add(a, b):\n", "\n\nprint(add(1, 2))"),
+            "<fim_prefix>def add(a, b):\n<fim_suffix>\n\nprint(add(1, 2))<fim_middle>"
+        );
+
+        let added_tokens = HashSet::from(["<|endoftext|>".to_string()]);
+        assert_eq!(FimTemplate::from_added_tokens(&added_tokens), None);
+    }
+}
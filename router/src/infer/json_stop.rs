@@ -0,0 +1,107 @@
+use crate::infer::{GeneratedText, InferStreamResponse};
+use crate::FinishReason;
+use tokio::time::Instant;
+
+/// Detects the end of the JSON value generated for a `json_object` response format, from the
+/// texts of its generated tokens
+///
+/// The generation is stopped on the token closing the top-level object or array, and the text
+/// this token generated after it is dropped.
+#[derive(Default)]
+pub(crate) struct JsonMonitor {
+    /// Number of open objects and arrays
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    generated_tokens: u32,
+    /// Text of the generated tokens, returned when the generation is stopped
+    text: String,
+}
+
+impl JsonMonitor {
+    /// Turn the response into the last one of the generation if its token closes the JSON value
+    pub(crate) fn check(
+        &mut self,
+        response: InferStreamResponse,
+        seed: Option<u64>,
+        start: Instant,
+        queued: Instant,
+    ) -> InferStreamResponse {
+        let InferStreamResponse::Intermediate {
+            mut token,
+            top_tokens,
+        } = response
+        else {
+            return response;
+        };
+        self.generated_tokens += 1;
+        if token.special {
+            return InferStreamResponse::Intermediate { token, top_tokens };
+        }
+        let Some(end) = self.push(&token.text) else {
+            self.text.push_str(&token.text);
+            return InferStreamResponse::Intermediate { token, top_tokens };
+        };
+        token.text.truncate(end);
+        self.text.push_str(&token.text);
+        InferStreamResponse::End {
+            token,
+            top_tokens,
+            generated_text: GeneratedText {
+                text: std::mem::take(&mut self.text),
+                generated_tokens: self.generated_tokens,
+                finish_reason: FinishReason::EndOfSequenceToken,
+                seed,
+                backend: None,
+                steps: None,
+            },
+            start,
+            queued,
+        }
+    }
+
+    /// Record the text of a generated token, returns the end of the JSON value in the text if
+    /// it closes it
+    fn push(&mut self, text: &str) -> Option<usize> {
+        for (i, c) in text.char_indices() {
+            if self.in_string {
+                match c {
+                    _ if self.escaped => self.escaped = false,
+                    '\\' => self.escaped = true,
+                    '"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '{' | '[' => self.depth += 1,
+                '}' | ']' if self.depth > 0 => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return Some(i + c.len_utf8());
+                    }
+                }
+                // Strings are only tracked inside the value, the text before it is ignored
+                '"' if self.depth > 0 => self.in_string = true,
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_monitor() {
+        let mut monitor = JsonMonitor::default();
+        assert_eq!(monitor.push(" {\"a\": [1, {"), None);
+        assert_eq!(monitor.push("}], \"b\": \"}\\\"]"), None);
+        assert_eq!(monitor.push("\"}\n\nThe"), Some(2));
+
+        let mut monitor = JsonMonitor::default();
+        assert_eq!(monitor.push("[]"), Some(2));
+    }
+}
@@ -1,36 +1,286 @@
 // pub(crate) mod v2;
+pub(crate) mod best_of;
 mod chat_template;
+mod compression;
+mod detokenizer;
+pub mod experiment;
+pub mod failover;
+pub(crate) mod fim;
+mod json_stop;
+pub(crate) mod prompt_template;
+mod repetition;
+pub mod sampling;
+pub(crate) mod special_tokens;
+mod stop_tokens;
+pub(crate) mod system_prompt;
+pub(crate) mod tool_calls;
 pub mod tool_grammar;
+pub mod utf8;
 
-use crate::validation::{ValidGenerateRequest, Validation, ValidationError};
+use crate::concurrency::{AdaptiveLimit, AdaptivePermit, CallerLimit};
+use crate::prefix_pins::{PrefixPins, PrefixPinsResponse};
+use crate::requests::Requests;
+use crate::router_config::{PrefixPinningConfig, PromptCompressionConfig};
+use crate::usage::UsageKey;
+use crate::validation::{Chunk, ValidGenerateRequest, Validation, ValidationError};
 use crate::Tool;
 use crate::{
-    ChatTemplateVersions, FinishReason, GenerateRequest, HubProcessorConfig, HubTokenizerConfig,
-    Message, PrefillToken, Token,
+    BackendMetadata, BestOfStrategy, ChatRequest, ChatTemplateVersions, EffectiveParameters,
+    Endpoint, FinishReason, GenerateRequest, HubProcessorConfig, HubTokenizerConfig,
+    LoadAdapterRequest, Message, PrefillToken, PromptCompression, ShardInfo, Token,
 };
 use async_stream::stream;
 use async_trait::async_trait;
+use best_of::Reranker;
 use chat_template::ChatTemplate;
+use compression::PromptCompressor;
+use detokenizer::Detokenizer;
+use fim::FimTemplate;
 use futures::future::try_join_all;
 use futures::Stream;
+use json_stop::JsonMonitor;
 use minijinja::ErrorKind;
+use prompt_template::PromptTemplates;
+use repetition::RepetitionMonitor;
+use serde::Serialize;
+use special_tokens::SpecialTokenGuard;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use stop_tokens::StopTokenMonitor;
 use thiserror::Error;
 use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
 use tokio::time::Instant;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_stream::StreamExt;
 use tracing::instrument;
+use utoipa::ToSchema;
 
+/// Stream of the generation events of one request, returned by [`Backend::schedule`]
+pub type GenerationStream =
+    Pin<Box<dyn Stream<Item = Result<InferStreamResponse, InferError>> + Send>>;
+
+/// Extension point between the router and an inference engine.
+///
+/// The router owns the HTTP layer, validation, chat templating and concurrency limits.
+/// A backend receives fully validated requests and owns everything after that: queueing,
+/// batching, and the prefill/decode loop. Backends report results through the returned
+/// stream, which must end with either an [`InferStreamResponse::End`] or an error.
+///
+/// There are no separate prefill and decode methods: continuous batching merges the prefill
+/// of new requests with the decode steps of running ones, so only the backend knows when
+/// each step runs. The stream reports both phases: the optional
+/// [`InferStreamResponse::Prefill`], then one token per decode step, the last one in
+/// [`InferStreamResponse::End`].
+///
+/// `/info` is built from [`Backend::shards`], [`Backend::metadata`] and
+/// [`Backend::capabilities`]. Backends record their own metrics through the global
+/// [`metrics`] recorder, which the router installs and serves on `/metrics`.
+///
+/// Backends that do not implement every feature should override [`Backend::capabilities`]
+/// so the router can reject unsupported requests before they are scheduled.
 #[async_trait]
 pub trait Backend {
-    fn schedule(
+    /// Schedule a validated request and return the stream of its generation events.
+    fn schedule(&self, request: ValidGenerateRequest) -> Result<GenerationStream, InferError>;
+
+    /// Check the backend health.
+    ///
+    /// `current_health` is the health last observed by the router. Backends can use it to
+    /// skip an expensive check when the last generation succeeded.
+    async fn health(&self, current_health: bool) -> bool;
+
+    /// Short backend name, used in logs and metric labels.
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// Features supported by this backend.
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities::default()
+    }
+
+    /// Model shards served by this backend, shown in `/info`.
+    fn shards(&self) -> Vec<ShardInfo> {
+        Vec::new()
+    }
+
+    /// Version, quantization, adapters and batching limits of this backend, shown in `/info`.
+    fn metadata(&self) -> BackendMetadata {
+        BackendMetadata::default()
+    }
+
+    /// Release the KV cache pinned by the request scheduled with this `pin_prefix`.
+    ///
+    /// Only called by the router on backends that support prefix pinning.
+    fn unpin_prefix(&self, _id: u64) {}
+
+    /// Log probabilities of the prompt tokens of `request`, which has `decoder_input_details`.
+    ///
+    /// Only called by the router on backends that support prefill logits. The default
+    /// schedules the request and returns its prefill tokens, without waiting for the
+    /// generated token.
+    async fn prefill_logits(
         &self,
         request: ValidGenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError>;
+    ) -> Result<Vec<PrefillToken>, InferError> {
+        let mut stream = self.schedule(request)?;
+        while let Some(response) = stream.next().await {
+            if let InferStreamResponse::Prefill(tokens) = response? {
+                return Ok(tokens);
+            }
+        }
+        Err(InferError::IncompleteGeneration)
+    }
 
-    async fn health(&self, current_health: bool) -> bool;
+    /// Embedding of the inputs of `request`.
+    ///
+    /// Only called by the router on backends that support embeddings.
+    async fn embed(&self, _request: ValidGenerateRequest) -> Result<Vec<f32>, InferError> {
+        Err(InferError::Unsupported("embeddings"))
+    }
+
+    /// Load the adapter of `request`, listed in [`Backend::metadata`] once the requests can
+    /// select it with their `adapter_id`.
+    async fn load_adapter(&self, _request: LoadAdapterRequest) -> Result<(), InferError> {
+        Err(InferError::Unsupported("adapter loading"))
+    }
+}
+
+#[async_trait]
+impl<B: Backend + Send + Sync + ?Sized> Backend for Box<B> {
+    fn schedule(&self, request: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
+        (**self).schedule(request)
+    }
+
+    async fn health(&self, current_health: bool) -> bool {
+        (**self).health(current_health).await
+    }
+
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        (**self).capabilities()
+    }
+
+    fn shards(&self) -> Vec<ShardInfo> {
+        (**self).shards()
+    }
+
+    fn metadata(&self) -> BackendMetadata {
+        (**self).metadata()
+    }
+
+    fn unpin_prefix(&self, id: u64) {
+        (**self).unpin_prefix(id)
+    }
+
+    async fn prefill_logits(
+        &self,
+        request: ValidGenerateRequest,
+    ) -> Result<Vec<PrefillToken>, InferError> {
+        (**self).prefill_logits(request).await
+    }
+
+    async fn embed(&self, request: ValidGenerateRequest) -> Result<Vec<f32>, InferError> {
+        (**self).embed(request).await
+    }
+
+    async fn load_adapter(&self, request: LoadAdapterRequest) -> Result<(), InferError> {
+        (**self).load_adapter(request).await
+    }
+}
+
+/// Optional features a [`Backend`] may support.
+///
+/// Defaults to everything being supported, which matches the behaviour of the router
+/// before capabilities existed, except the prefix pinning, prefill logits, embeddings and
+/// token lists added since.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub struct BackendCapabilities {
+    /// Grammar constrained generation (`grammar`, `response_format`, tools)
+    pub supports_grammar: bool,
+    /// Image chunks in the inputs
+    pub supports_images: bool,
+    /// Generated tokens carry the model token ids, so the router can detokenize them
+    pub supports_detokenization: bool,
+    /// The KV cache of the prompts of the requests with a `pin_prefix` is kept until it is
+    /// unpinned
+    pub supports_prefix_pinning: bool,
+    /// Log probabilities of the prompt tokens without generation, for `/score`
+    pub supports_prefill_logits: bool,
+    /// Embeddings of the inputs, for `/v1/embeddings`
+    pub supports_embeddings: bool,
+    /// Generation restricted to the `allowed_tokens` and without the `banned_tokens`
+    pub supports_token_lists: bool,
+    /// Sampling with `top_k`, `typical_p` and `repetition_penalty`, beyond the parameters of
+    /// the OpenAI API
+    pub supports_extended_sampling: bool,
+}
+
+impl Default for BackendCapabilities {
+    fn default() -> Self {
+        Self {
+            supports_grammar: true,
+            supports_images: true,
+            supports_detokenization: true,
+            supports_prefix_pinning: false,
+            supports_prefill_logits: false,
+            supports_embeddings: false,
+            supports_token_lists: false,
+            supports_extended_sampling: true,
+        }
+    }
+}
+
+impl BackendCapabilities {
+    /// Capabilities of both backends, for the requests that either of them can serve
+    pub(crate) fn intersect(self, other: Self) -> Self {
+        Self {
+            supports_grammar: self.supports_grammar && other.supports_grammar,
+            supports_images: self.supports_images && other.supports_images,
+            supports_detokenization: self.supports_detokenization && other.supports_detokenization,
+            supports_prefix_pinning: self.supports_prefix_pinning && other.supports_prefix_pinning,
+            supports_prefill_logits: self.supports_prefill_logits && other.supports_prefill_logits,
+            supports_embeddings: self.supports_embeddings && other.supports_embeddings,
+            supports_token_lists: self.supports_token_lists && other.supports_token_lists,
+            supports_extended_sampling: self.supports_extended_sampling
+                && other.supports_extended_sampling,
+        }
+    }
+
+    /// Reject requests that use a feature this backend does not support
+    pub(crate) fn check(&self, request: &ValidGenerateRequest) -> Result<(), ValidationError> {
+        if !self.supports_grammar && request.parameters.grammar.is_some() {
+            return Err(ValidationError::Grammar);
+        }
+        if !self.supports_images && request.inputs.iter().any(|c| matches!(c, Chunk::Image(_))) {
+            return Err(ValidationError::UnsupportedModality("image"));
+        }
+        if !self.supports_token_lists {
+            if !request.parameters.allowed_token_ids.is_empty() {
+                return Err(ValidationError::TokenListUnsupported("allowed_tokens"));
+            }
+            if !request.parameters.banned_token_ids.is_empty() {
+                return Err(ValidationError::TokenListUnsupported("banned_tokens"));
+            }
+        }
+        if !self.supports_extended_sampling {
+            let parameters = &request.parameters;
+            if parameters.top_k != 0 {
+                return Err(ValidationError::SamplingUnsupported("top_k"));
+            }
+            if parameters.typical_p != 1.0 {
+                return Err(ValidationError::SamplingUnsupported("typical_p"));
+            }
+            if parameters.repetition_penalty != 1.0 {
+                return Err(ValidationError::SamplingUnsupported("repetition_penalty"));
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Inference struct
@@ -42,10 +292,29 @@ pub struct Infer {
     backend: Arc<dyn Backend + Send + Sync>,
     /// Chat template
     chat_template: Option<ChatTemplate>,
+    /// Prompt templates for the generate endpoints
+    prompt_templates: PromptTemplates,
+    /// Fill-in-the-middle prompt of the requests with a suffix
+    fim_template: Option<FimTemplate>,
+    /// Special tokens allowed in the user inputs
+    special_tokens: SpecialTokenGuard,
+    /// Tokenizer used to detokenize the generated tokens
+    tokenizer: Option<Arc<tokenizers::Tokenizer>>,
     /// Inference limit
     limit_concurrent_requests: Arc<Semaphore>,
+    max_concurrent_requests: usize,
+    /// Lower inference limit adjusted to the time to first token
+    adaptive_limit: Option<Arc<AdaptiveLimit>>,
     /// Backend health
     backend_health: Arc<AtomicBool>,
+    /// Cancellation signals of the requests in flight
+    requests: Requests,
+    /// Rerank endpoint of the `rerank` best_of strategy
+    reranker: Option<Reranker>,
+    /// Prompts pinned in the KV cache of the backend
+    prefix_pins: Option<Arc<PrefixPins>>,
+    /// Compression of the prompts over a number of tokens
+    prompt_compressor: Option<PromptCompressor>,
 }
 
 impl Infer {
@@ -56,6 +325,14 @@ impl Infer {
         max_concurrent_requests: usize,
         tokenizer_config: HubTokenizerConfig,
         processor_config: HubProcessorConfig,
+        prompt_templates: PromptTemplates,
+        fim_template: Option<FimTemplate>,
+        special_tokens: SpecialTokenGuard,
+        tokenizer: Option<tokenizers::Tokenizer>,
+        reranker: Option<Reranker>,
+        adaptive_limit: Option<Arc<AdaptiveLimit>>,
+        prefix_pinning: PrefixPinningConfig,
+        prompt_compression: PromptCompressionConfig,
     ) -> Self {
         let chat_template = tokenizer_config
             .chat_template
@@ -75,59 +352,408 @@ impl Infer {
         // Backend health
         let backend_health = Arc::new(AtomicBool::new(false));
 
+        tracing::info!(
+            "Using backend `{}` with {:?}",
+            backend.name(),
+            backend.capabilities()
+        );
+
+        let backend: Arc<dyn Backend + Send + Sync> = Arc::new(backend);
+        let prefix_pins = PrefixPins::new(prefix_pinning, backend.clone());
+
         Self {
             validation,
-            backend: Arc::new(backend),
+            backend,
             chat_template,
+            prompt_templates,
+            fim_template,
+            special_tokens,
+            tokenizer: tokenizer.map(Arc::new),
             limit_concurrent_requests: semaphore,
+            max_concurrent_requests,
+            adaptive_limit,
             backend_health,
+            requests: Requests::default(),
+            reranker,
+            prefix_pins,
+            prompt_compressor: PromptCompressor::new(prompt_compression),
         }
     }
 
+    /// Permits of `count` generations, all of them or none
+    ///
+    /// Each generation holds one permit of the caller, server and adaptive limits until it
+    /// ends.
+    fn acquire_permits(&self, count: usize) -> Result<Vec<GenerationPermit>, InferError> {
+        let caller_limit = CallerLimit::current();
+        // The permits acquired before a failure are released when dropped
+        (0..count)
+            .map(|_| self.acquire_permit(caller_limit.as_ref()))
+            .collect()
+    }
+
+    fn acquire_permit(
+        &self,
+        caller_limit: Option<&CallerLimit>,
+    ) -> Result<GenerationPermit, InferError> {
+        // Limit the concurrent requests of the caller, then of the server, by acquiring
+        // permits from their semaphores
+        let caller_permit = caller_limit
+            .map(|limit| limit.try_acquire())
+            .transpose()
+            .map_err(|err| {
+                metrics::counter!("tgi_request_failure", "err" => "overloaded_user").increment(1);
+                tracing::error!("{err}");
+                err
+            })?;
+        let server_permit = self
+            .clone()
+            .limit_concurrent_requests
+            .try_acquire_owned()
+            .map_err(|err| {
+                metrics::counter!("tgi_request_failure", "err" => "overloaded_server").increment(1);
+                tracing::error!("{err}");
+                err
+            })?;
+        let adaptive_permit = self
+            .adaptive_limit
+            .as_ref()
+            .map(AdaptiveLimit::try_acquire)
+            .transpose()
+            .map_err(|err| {
+                metrics::counter!("tgi_request_failure", "err" => "overloaded_server").increment(1);
+                tracing::error!("{err}");
+                err
+            })?;
+        Ok(GenerationPermit {
+            _caller: caller_permit,
+            _server: server_permit,
+            _adaptive: adaptive_permit,
+        })
+    }
+
     /// Add a new request to the queue and return a stream of InferStreamResponse
     #[instrument(skip_all)]
+    #[allow(clippy::type_complexity)]
     pub(crate) async fn generate_stream<'a>(
         &'a self,
         request: GenerateRequest,
     ) -> Result<
         (
-            OwnedSemaphorePermit,
-            u32, // input_length
+            GenerationPermit,
+            u32,         // input_length
+            u32,         // max_new_tokens
+            Option<u64>, // seed, for sampled generations
+            EffectiveParameters,
+            Option<String>, // prefix_id, for pinned prompts
             impl Stream<Item = Result<InferStreamResponse, InferError>> + 'a,
         ),
         InferError,
     > {
-        // Limit concurrent requests by acquiring a permit from the semaphore
-        let permit = self
-            .clone()
-            .limit_concurrent_requests
-            .try_acquire_owned()
+        let permit = self.acquire_permit(CallerLimit::current().as_ref())?;
+        self.generate_stream_with_permit(request, permit).await
+    }
+
+    /// [`Infer::generate_stream`] of a request which already holds its concurrency permit
+    #[allow(clippy::type_complexity)]
+    async fn generate_stream_with_permit(
+        &self,
+        request: GenerateRequest,
+        permit: GenerationPermit,
+    ) -> Result<
+        (
+            GenerationPermit,
+            u32,         // input_length
+            u32,         // max_new_tokens
+            Option<u64>, // seed, for sampled generations
+            EffectiveParameters,
+            Option<String>, // prefix_id, for pinned prompts
+            impl Stream<Item = Result<InferStreamResponse, InferError>> + '_,
+        ),
+        InferError,
+    > {
+        // Validate request
+        let pin = request.parameters.pin;
+        let prefix_id = request.parameters.prefix_id.clone();
+        let mut valid_request = self
+            .validation
+            .validate(request)
+            .await
+            .and_then(|request| {
+                self.backend.capabilities().check(&request)?;
+                Ok(request)
+            })
             .map_err(|err| {
-                metrics::counter!("tgi_request_failure", "err" => "overloaded").increment(1);
+                metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+                tracing::error!("{err}");
+                err
+            })?;
+        let prefix_id = self
+            .pin_prefix(&mut valid_request, pin, prefix_id.as_deref())
+            .map_err(|err| {
+                let label = match err {
+                    InferError::PinCapacity(_) => "pin_capacity",
+                    _ => "validation",
+                };
+                metrics::counter!("tgi_request_failure", "err" => label).increment(1);
                 tracing::error!("{err}");
                 err
             })?;
-
-        // Validate request
-        let valid_request = self.validation.validate(request).await.map_err(|err| {
-            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
-            tracing::error!("{err}");
-            err
-        })?;
 
         let input_length = valid_request.input_length;
+        let max_new_tokens = valid_request.stopping_parameters.max_new_tokens;
+        let adapter = self.adapter_label(valid_request.adapter_id.as_deref());
+        metrics::counter!("tgi_adapter_request_count", "adapter" => adapter.clone()).increment(1);
+        metrics::counter!("tgi_adapter_input_tokens", "adapter" => adapter.clone())
+            .increment(input_length as u64);
+        // Validation assigns a seed to every request, the backend samples with it
+        let seed = valid_request
+            .parameters
+            .do_sample
+            .then_some(valid_request.parameters.seed);
+        let parameters = EffectiveParameters::from(&valid_request.parameters);
+        let mut detokenizer = self.detokenizer(&valid_request);
+        let mut repetition = valid_request.stop_on_repetition.map(RepetitionMonitor::new);
+        let mut json = valid_request.stop_on_json.then(JsonMonitor::default);
+        let mut stop_tokens = (!valid_request.stop_token_ids.is_empty())
+            .then(|| StopTokenMonitor::new(valid_request.stop_token_ids.clone()));
+        let input_offsets = valid_request.input_offsets.clone();
+        let usage_key = UsageKey::current();
+        let mut cancellation = self.requests.register();
+        let queued = Instant::now();
         let mut generation_stream = self.backend.schedule(valid_request)?;
 
         // Wrap generation stream to update the backend health if the stream contains an error
+        let mut start = None;
+        let mut first_token = true;
         let final_stream = stream! {
-            while let Some(response) = generation_stream.next().await {
+            loop {
+                let response = tokio::select! {
+                    response = generation_stream.next() => response,
+                    _ = cancellation.cancelled() => None,
+                };
+                let Some(mut response) = response else {
+                    break;
+                };
+                // Positions in the queue are not tokens, they skip the processing below
+                if let Ok(InferStreamResponse::Queued { .. }) = &response {
+                    yield response;
+                    continue;
+                }
+                if let (Some(detokenizer), Ok(response)) = (&mut detokenizer, &mut response) {
+                    detokenizer.apply(response);
+                }
+                if let (Some(offsets), Ok(InferStreamResponse::Prefill(tokens))) =
+                    (&input_offsets, &mut response)
+                {
+                    add_offsets(tokens, offsets);
+                }
+                if let (
+                    Some(adaptive_limit),
+                    Ok(InferStreamResponse::Intermediate { .. } | InferStreamResponse::End { .. }),
+                ) = (&self.adaptive_limit, &response)
+                {
+                    if std::mem::take(&mut first_token) {
+                        adaptive_limit.record(queued.elapsed());
+                    }
+                }
+                if let Some(stop_tokens) = &mut stop_tokens {
+                    // The backend only reports the timings of complete generations
+                    let start = *start.get_or_insert_with(Instant::now);
+                    response = response
+                        .map(|response| stop_tokens.check(response, seed, start, queued));
+                }
+                if let Some(json) = &mut json {
+                    let start = *start.get_or_insert_with(Instant::now);
+                    response = response.map(|response| json.check(response, seed, start, queued));
+                }
+                if let Some(repetition) = &mut repetition {
+                    let start = *start.get_or_insert_with(Instant::now);
+                    response = response
+                        .map(|response| repetition.check(response, seed, start, queued));
+                }
+                let end = matches!(response, Ok(InferStreamResponse::End { .. }));
+                if let (Some(usage_key), Ok(InferStreamResponse::End { generated_text, .. })) =
+                    (&usage_key, &response)
+                {
+                    usage_key.record(input_length, generated_text.generated_tokens);
+                }
+                if let Ok(InferStreamResponse::End { generated_text, .. }) = &response {
+                    metrics::counter!("tgi_adapter_generated_tokens", "adapter" => adapter.clone())
+                        .increment(generated_text.generated_tokens as u64);
+                }
                 yield response.inspect_err(|_err| {
                     self.backend_health.store(false, Ordering::SeqCst);
-                })
+                });
+                if end {
+                    // A generation stopped by the router is removed from the backend when the
+                    // generation stream is dropped
+                    break;
+                }
+            }
+            if cancellation.is_cancelled() {
+                // Dropping the generation stream removes the request from the backend
+                yield Err(InferError::Cancelled);
             }
         };
 
-        Ok((permit, input_length, final_stream))
+        Ok((
+            permit,
+            input_length,
+            max_new_tokens,
+            seed,
+            parameters,
+            prefix_id,
+            final_stream,
+        ))
+    }
+
+    /// Check the pinned prompt the request starts with and pin its own prompt, see
+    /// [`PrefixPins::apply`]
+    fn pin_prefix(
+        &self,
+        request: &mut ValidGenerateRequest,
+        pin: bool,
+        prefix_id: Option<&str>,
+    ) -> Result<Option<String>, InferError> {
+        match (&self.prefix_pins, prefix_id) {
+            (Some(prefix_pins), _) => prefix_pins.apply(request, pin, prefix_id),
+            (None, Some(prefix_id)) => {
+                Err(ValidationError::UnknownPrefix(prefix_id.to_string()).into())
+            }
+            (None, None) if pin => Err(ValidationError::PinUnsupported.into()),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Release a pinned prompt, returns false if it is unknown or expired
+    pub(crate) fn unpin_prefix(&self, prefix_id: &str) -> bool {
+        self.prefix_pins
+            .as_ref()
+            .is_some_and(|prefix_pins| prefix_pins.unpin(prefix_id))
+    }
+
+    pub(crate) fn prefix_pins_usage(&self) -> PrefixPinsResponse {
+        match &self.prefix_pins {
+            Some(prefix_pins) => prefix_pins.usage(),
+            None => PrefixPinsResponse {
+                pinned_prefixes: 0,
+                pinned_tokens: 0,
+                max_pinned_tokens: 0,
+            },
+        }
+    }
+
+    /// Cancel the generations of a request of the caller with the API key `owner`, returns
+    /// false if it has none in flight
+    pub(crate) fn cancel(&self, request_id: &str, owner: Option<&str>) -> bool {
+        self.requests.cancel(request_id, owner)
+    }
+
+    /// Detokenize the generated tokens in the router, so that the token texts do not depend
+    /// on the backend
+    fn detokenizer(&self, request: &ValidGenerateRequest) -> Option<Detokenizer> {
+        if request.raw_bytes || !self.backend.capabilities().supports_detokenization {
+            return None;
+        }
+        let input_ids = request.input_ids.as_deref().map_or(&[][..], Vec::as_slice);
+        Some(Detokenizer::new(self.tokenizer.clone()?, input_ids))
+    }
+
+    /// Strip or reject the special tokens of the inputs, the suffix and the template variables
+    pub(crate) fn check_special_tokens(
+        &self,
+        request: &mut GenerateRequest,
+    ) -> Result<(), InferError> {
+        // The inputs were rendered by the chat template, its messages were checked before
+        if !request.add_special_tokens {
+            return Ok(());
+        }
+        self.special_tokens.check(&mut request.inputs)?;
+        if let Some(suffix) = &mut request.suffix {
+            self.special_tokens.check(suffix)?;
+        }
+        for value in request.variables.iter_mut().flat_map(|v| v.values_mut()) {
+            let values = match value {
+                serde_json::Value::Array(values) => values.iter_mut().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                if let serde_json::Value::String(value) = value {
+                    self.special_tokens.check(value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the prompt template selected by the request into its inputs, then the
+    /// fill-in-the-middle prompt if the request has a suffix
+    #[instrument(skip_all)]
+    pub(crate) fn apply_prompt_template(
+        &self,
+        request: &mut GenerateRequest,
+    ) -> Result<(), InferError> {
+        if let Some(name) = request.template.take() {
+            request.inputs = self
+                .prompt_templates
+                .render(&name, &request.inputs, request.variables.take())
+                .map_err(|e| {
+                    metrics::counter!("tgi_request_failure", "err" => "template").increment(1);
+                    tracing::error!("{e}");
+                    e
+                })?;
+        }
+        if let Some(suffix) = request.suffix.take() {
+            let fim_template = self.fim_template.as_ref().ok_or_else(|| {
+                metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+                ValidationError::UnsupportedSuffix
+            })?;
+            request.inputs = fim_template.render(&request.inputs, &suffix);
+        }
+        Ok(())
+    }
+
+    /// Compress the inputs of a request of the generate endpoints over the compression
+    /// threshold, the chat requests are compressed before their template is rendered
+    #[instrument(skip_all)]
+    pub(crate) async fn compress_prompt(
+        &self,
+        request: &mut GenerateRequest,
+    ) -> Result<Option<PromptCompression>, InferError> {
+        match &self.prompt_compressor {
+            Some(compressor)
+                if request.endpoint == Endpoint::Generate && request.inputs_ids.is_none() =>
+            {
+                compressor.compress_inputs(self, request).await
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Compress the messages of a chat request over the compression threshold
+    #[instrument(skip_all)]
+    pub(crate) async fn compress_chat(
+        &self,
+        chat: &mut ChatRequest,
+    ) -> Result<Option<PromptCompression>, InferError> {
+        match &self.prompt_compressor {
+            Some(compressor) => compressor.compress_chat(self, chat).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Number of tokens of the inputs, without truncation
+    async fn count_tokens(
+        &self,
+        inputs: String,
+        add_special_tokens: bool,
+    ) -> Result<usize, InferError> {
+        let (encoding, _) = self
+            .validation
+            .tokenize(inputs, add_special_tokens, None)
+            .await?;
+        Ok(encoding.len())
     }
 
     /// Tokenizer the input
@@ -161,10 +787,36 @@ impl Infer {
         messages: Vec<Message>,
         tools_and_prompt: Option<(Vec<Tool>, String)>,
     ) -> Result<String, InferError> {
+        self.render_chat_template(guideline, messages, tools_and_prompt, true)
+    }
+
+    /// Apply the chat template to the messages, with or without the generation prompt
+    pub(crate) fn render_chat_template(
+        &self,
+        mut guideline: Option<String>,
+        mut messages: Vec<Message>,
+        tools_and_prompt: Option<(Vec<Tool>, String)>,
+        add_generation_prompt: bool,
+    ) -> Result<String, InferError> {
+        let texts = messages
+            .iter_mut()
+            .flat_map(|message| message.content.texts_mut());
+        for text in guideline.iter_mut().chain(texts) {
+            self.special_tokens.check(text)?;
+        }
+        // The system prompts of the policy are trusted, they are added after the check
+        if let Some(policy) = system_prompt::caller_policy() {
+            policy.apply(&mut messages);
+        }
         self.chat_template
             .as_ref()
             .ok_or_else(|| InferError::TemplateError(ErrorKind::TemplateNotFound.into()))?
-            .apply(guideline.as_deref(), messages, tools_and_prompt)
+            .render(
+                guideline.as_deref(),
+                messages,
+                tools_and_prompt,
+                add_generation_prompt,
+            )
             .map_err(|e| {
                 metrics::counter!("tgi_request_failure", "err" => "template").increment(1);
                 tracing::error!("{e}");
@@ -177,25 +829,72 @@ impl Infer {
     pub(crate) async fn generate(
         &self,
         request: GenerateRequest,
+    ) -> Result<InferResponse, InferError> {
+        let permit = self.acquire_permit(CallerLimit::current().as_ref())?;
+        self.generate_with_permit(request, permit).await
+    }
+
+    /// [`Infer::generate`] of a request which already holds its concurrency permit
+    async fn generate_with_permit(
+        &self,
+        request: GenerateRequest,
+        permit: GenerationPermit,
     ) -> Result<InferResponse, InferError> {
         let use_top_tokens = request.parameters.top_n_tokens.is_some_and(|x| x > 0);
+        let partial_on_error = request.parameters.partial_on_error;
 
         // Create stream and keep semaphore permit as long as generate lives
-        let (_permit, _input_length, stream) = self.generate_stream(request).await?;
+        let (_permit, _input_length, _max_new_tokens, seed, parameters, prefix_id, stream) =
+            self.generate_stream_with_permit(request, permit).await?;
+        let scheduled = Instant::now();
 
         // Return values
         let mut result_prefill = Vec::new();
-        let mut result_tokens = Vec::new();
+        let mut result_tokens: Vec<Token> = Vec::new();
         let mut result_top_tokens = Vec::new();
         let mut result_generated_text = None;
         let mut result_start = None;
         let mut result_queued = None;
+        let mut result_error = None;
+        let mut first_response = None;
 
         let mut stream = Box::pin(stream);
 
         // Iterate on stream
         while let Some(response) = stream.next().await {
-            match response? {
+            let response = match response {
+                Ok(response) => response,
+                // Keep the tokens generated before the backend failed
+                Err(err @ (InferError::GenerationError(_) | InferError::GenerationTimeout(_)))
+                    if partial_on_error && !result_tokens.is_empty() =>
+                {
+                    result_generated_text = Some(GeneratedText {
+                        text: result_tokens
+                            .iter()
+                            .filter(|token| !token.special)
+                            .map(|token| token.text.as_str())
+                            .collect(),
+                        generated_tokens: result_tokens.len() as u32,
+                        finish_reason: FinishReason::Error,
+                        seed,
+                        backend: None,
+                        steps: None,
+                    });
+                    // The backend only reports the timings of complete generations
+                    result_queued = Some(scheduled);
+                    result_start = first_response;
+                    result_error = Some(err);
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            // The request is still waiting for its batch
+            if let InferStreamResponse::Queued { .. } = response {
+                continue;
+            }
+            first_response.get_or_insert_with(Instant::now);
+            match response {
+                InferStreamResponse::Queued { .. } => {}
                 // Add prefill tokens
                 InferStreamResponse::Prefill(prefill_tokens) => {
                     result_prefill = prefill_tokens;
@@ -234,11 +933,14 @@ impl Infer {
                 generated_text,
                 queued,
                 start,
+                parameters,
+                prefix_id,
                 top_tokens: if use_top_tokens {
                     result_top_tokens
                 } else {
                     Vec::new()
                 },
+                error: result_error,
             })
         } else {
             let err = InferError::IncompleteGeneration;
@@ -257,34 +959,134 @@ impl Infer {
     ) -> Result<(InferResponse, Vec<InferResponse>), InferError> {
         // validate  best_of parameter separately
         let best_of = self.validation.validate_best_of(best_of)?;
+        if request.parameters.pin {
+            return Err(ValidationError::PinConflict("best_of").into());
+        }
+        let strategy = request.parameters.best_of_strategy.unwrap_or_default();
+        if strategy == BestOfStrategy::Rerank && self.reranker.is_none() {
+            return Err(ValidationError::RerankDisabled.into());
+        }
+        // Every sequence is a generation holding its own concurrency permit
+        let caller_limit = CallerLimit::current();
+        let limit = caller_limit
+            .as_ref()
+            .map_or(self.max_concurrent_requests, |caller_limit| {
+                caller_limit.limit().min(self.max_concurrent_requests)
+            });
+        if best_of > limit {
+            return Err(ValidationError::BestOfConcurrency(limit, best_of).into());
+        }
+        let permits = self.acquire_permits(best_of)?;
+        let inputs = request.inputs.clone();
 
         // create multiple generate requests
-        let mut infer_responses: Vec<InferResponse> =
-            try_join_all((0..best_of).map(|_| self.generate(request.clone()))).await?;
-
-        // get the sequence with the highest log probability per token
-        let mut max_index = 0;
-        let mut max_logprob: f32 = f32::MIN;
-
-        for (i, response) in infer_responses.iter().enumerate() {
-            // mean logprobs of the generated tokens
-            let sequence_logprob = response
-                .tokens
-                .iter()
-                .map(|token| token.logprob)
-                .sum::<f32>()
-                / response.tokens.len() as f32;
-
-            // set best sequence
-            if sequence_logprob > max_logprob {
-                max_index = i;
-                max_logprob = sequence_logprob;
-            }
-        }
+        let mut infer_responses: Vec<InferResponse> = try_join_all(
+            permits
+                .into_iter()
+                .map(|permit| self.generate_with_permit(request.clone(), permit)),
+        )
+        .await?;
+
+        let max_index =
+            best_of::best_index(strategy, self.reranker.as_ref(), &inputs, &infer_responses)
+                .await?;
         let best_response = infer_responses.remove(max_index);
         Ok((best_response, infer_responses))
     }
 
+    /// Log probabilities of the prompt tokens of `request`, without generating
+    #[instrument(skip_all)]
+    pub(crate) async fn score(
+        &self,
+        request: GenerateRequest,
+    ) -> Result<Vec<PrefillToken>, InferError> {
+        let supported = self.backend.capabilities().supports_prefill_logits;
+        let (_permit, request) = self.prefill_request(request, "scoring", supported).await?;
+        let input_offsets = request.input_offsets.clone();
+        let mut tokens = self.backend.prefill_logits(request).await?;
+        if let Some(offsets) = &input_offsets {
+            add_offsets(&mut tokens, offsets);
+        }
+        Ok(tokens)
+    }
+
+    /// Embedding of the inputs of `request`, with their number of tokens
+    #[instrument(skip_all)]
+    pub(crate) async fn embed(
+        &self,
+        mut request: GenerateRequest,
+    ) -> Result<(Vec<f32>, u32), InferError> {
+        // Embedding models have their own limit on the inputs
+        request.endpoint = Endpoint::Embeddings;
+        let supported = self.backend.capabilities().supports_embeddings;
+        let (_permit, request) = self
+            .prefill_request(request, "embeddings", supported)
+            .await?;
+        let input_length = request.input_length;
+        Ok((self.backend.embed(request).await?, input_length))
+    }
+
+    /// Validate a request served without generation by the backend, if it supports `feature`
+    async fn prefill_request(
+        &self,
+        request: GenerateRequest,
+        feature: &'static str,
+        supported: bool,
+    ) -> Result<(OwnedSemaphorePermit, ValidGenerateRequest), InferError> {
+        if !supported {
+            metrics::counter!("tgi_request_failure", "err" => "not_supported").increment(1);
+            return Err(InferError::Unsupported(feature));
+        }
+        let permit = self
+            .limit_concurrent_requests
+            .clone()
+            .try_acquire_owned()
+            .map_err(|err| {
+                metrics::counter!("tgi_request_failure", "err" => "overloaded_server").increment(1);
+                tracing::error!("{err}");
+                err
+            })?;
+        let request = self
+            .validation
+            .validate(request)
+            .await
+            .and_then(|request| {
+                self.backend.capabilities().check(&request)?;
+                Ok(request)
+            })
+            .map_err(|err| {
+                metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+                tracing::error!("{err}");
+                err
+            })?;
+        Ok((permit, request))
+    }
+
+    /// Label of the adapter metrics: the requests with an adapter unknown to the backend are
+    /// counted with the base model, which also bounds the cardinality of the label
+    fn adapter_label(&self, adapter_id: Option<&str>) -> String {
+        adapter_id
+            .filter(|adapter_id| {
+                self.backend
+                    .metadata()
+                    .adapters
+                    .iter()
+                    .any(|adapter| adapter == adapter_id)
+            })
+            .unwrap_or("base")
+            .to_string()
+    }
+
+    pub(crate) fn backend_metadata(&self) -> BackendMetadata {
+        self.backend.metadata()
+    }
+
+    /// Load an adapter on the backend, see [`Backend::load_adapter`]
+    #[instrument(skip(self))]
+    pub(crate) async fn load_adapter(&self, request: LoadAdapterRequest) -> Result<(), InferError> {
+        self.backend.load_adapter(request).await
+    }
+
     #[instrument(skip(self))]
     pub(crate) async fn health(&self) -> bool {
         let health = self
@@ -302,10 +1104,41 @@ pub struct GeneratedText {
     pub generated_tokens: u32,
     pub finish_reason: FinishReason,
     pub seed: Option<u64>,
+    /// Backend that served the request, set when several backends are available
+    pub backend: Option<&'static str>,
+    /// Forward passes that generated the tokens, the prefill included, when the backend
+    /// counts them
+    pub steps: Option<u32>,
+}
+
+impl GeneratedText {
+    /// Tokens accepted from the speculation, each step generating one token without it
+    pub(crate) fn speculative_tokens_accepted(&self) -> Option<u32> {
+        self.steps
+            .map(|steps| self.generated_tokens.saturating_sub(steps))
+    }
+}
+
+/// Set the character offsets of the prefill tokens, the backend may skip the first input tokens
+fn add_offsets(tokens: &mut [PrefillToken], offsets: &[(usize, usize)]) {
+    let Some(skipped) = offsets.len().checked_sub(tokens.len()) else {
+        return;
+    };
+    for (token, &(start, stop)) in tokens.iter_mut().zip(&offsets[skipped..]) {
+        token.start = Some(start);
+        token.stop = Some(stop);
+    }
 }
 
 #[derive(Debug)]
 pub enum InferStreamResponse {
+    // Periodic messages while the request waits in the queue of the backend
+    Queued {
+        /// Position in the queue, 1 for the next request batched
+        position: usize,
+        /// Estimated time before the first token, none until the backend measured its latencies
+        eta: Option<Duration>,
+    },
     // Optional first message
     Prefill(Vec<PrefillToken>),
     // Intermediate messages
@@ -334,15 +1167,35 @@ pub(crate) struct InferResponse {
     pub(crate) generated_text: GeneratedText,
     pub(crate) queued: Instant,
     pub(crate) start: Instant,
+    pub(crate) parameters: EffectiveParameters,
+    /// Id of the pinned prompt of the request
+    pub(crate) prefix_id: Option<String>,
     pub(crate) top_tokens: Vec<Vec<Token>>,
+    /// Error that interrupted the generation, the response then only holds its first tokens
+    pub(crate) error: Option<InferError>,
+}
+
+/// Concurrency permits of a generation, released when it ends
+pub(crate) struct GenerationPermit {
+    _caller: Option<OwnedSemaphorePermit>,
+    _server: OwnedSemaphorePermit,
+    _adaptive: Option<AdaptivePermit>,
 }
 
 #[derive(Debug, Error)]
 pub enum InferError {
     #[error("Request failed during generation: {0}")]
     GenerationError(String),
+    #[error("Request timed out during generation: {0}")]
+    GenerationTimeout(String),
     #[error("Model is overloaded")]
     Overloaded(#[from] TryAcquireError),
+    #[error("Too many concurrent requests, the limit of this caller is {0}")]
+    CallerOverloaded(usize),
+    #[error("Model is overloaded, its time to first token limits it to {0} concurrent requests")]
+    LoadShed(usize),
+    #[error("Model is overloaded, the estimated time to first token of {0}ms is over the {1}ms objective")]
+    TtftSlo(u64, u64),
     #[error("Input validation error: {0}")]
     ValidationError(#[from] ValidationError),
     #[error("Incomplete generation")]
@@ -355,22 +1208,302 @@ pub enum InferError {
     MissingTemplateVariable(String),
     #[error("Tool error: {0}")]
     ToolError(String),
+    #[error("Arguments of the call of the `{0}` tool do not match its schema: {1}")]
+    InvalidToolArguments(String, String),
     #[error("Stream event serialization error")]
     StreamSerializationError(String),
+    #[error("Request cancelled")]
+    Cancelled,
+    #[error("Rerank of the best_of sequences failed: {0}")]
+    RerankError(String),
+    #[error("Pinned prompts are limited to {0} tokens, unpin or wait for the expiry of others")]
+    PinCapacity(u64),
+    #[error("The backend does not support {0}")]
+    Unsupported(&'static str),
+    #[error("Adapter loading failed: {0}")]
+    AdapterLoading(String),
 }
 
 impl InferError {
     pub(crate) fn error_type(&self) -> &str {
         match self {
             InferError::GenerationError(_) => "generation",
-            InferError::Overloaded(_) => "overloaded",
+            InferError::GenerationTimeout(_) => "generation",
+            InferError::Overloaded(_) => "overloaded_server",
+            InferError::CallerOverloaded(_) => "overloaded_user",
+            InferError::LoadShed(_) => "overloaded_server",
+            InferError::TtftSlo(_, _) => "overloaded_server",
+            InferError::ValidationError(ValidationError::Overloaded(_)) => "overloaded_server",
             InferError::ValidationError(_) => "validation",
             InferError::IncompleteGeneration => "incomplete_generation",
             InferError::IncompleteGenerationStream => "incomplete_generation_stream",
             InferError::TemplateError(_) => "template_error",
             InferError::MissingTemplateVariable(_) => "missing_template_variable",
             InferError::ToolError(_) => "tool_error",
+            InferError::InvalidToolArguments(_, _) => "tool_error",
             InferError::StreamSerializationError(_) => "stream_serialization_error",
+            InferError::Cancelled => "cancelled",
+            InferError::RerankError(_) => "rerank_error",
+            InferError::PinCapacity(_) => "overloaded_server",
+            InferError::Unsupported(_) => "not_supported",
+            InferError::AdapterLoading(_) => "adapter_loading",
         }
     }
+
+    /// Stable code of the error, more specific than its type
+    pub(crate) fn code(&self) -> &str {
+        match self {
+            InferError::GenerationError(_) => "generation_failed",
+            InferError::GenerationTimeout(_) => "generation_timeout",
+            InferError::Overloaded(_) => "queue_full",
+            InferError::CallerOverloaded(_) => "concurrency_limit_exceeded",
+            InferError::LoadShed(_) => "load_shed",
+            InferError::TtftSlo(_, _) => "ttft_slo_exceeded",
+            InferError::PinCapacity(_) => "pin_capacity_exceeded",
+            InferError::AdapterLoading(_) => "adapter_load_failed",
+            InferError::InvalidToolArguments(_, _) => "invalid_tool_arguments",
+            InferError::ValidationError(err) => err.code(),
+            InferError::IncompleteGeneration
+            | InferError::IncompleteGenerationStream
+            | InferError::TemplateError(_)
+            | InferError::MissingTemplateVariable(_)
+            | InferError::ToolError(_)
+            | InferError::StreamSerializationError(_)
+            | InferError::Cancelled
+            | InferError::RerankError(_)
+            | InferError::Unsupported(_) => self.error_type(),
+        }
+    }
+
+    /// Request parameter that failed validation
+    pub(crate) fn param(&self) -> Option<&str> {
+        match self {
+            InferError::ValidationError(err) => err.param(),
+            InferError::MissingTemplateVariable(_) => Some("variables"),
+            InferError::PinCapacity(_) => Some("pin"),
+            InferError::AdapterLoading(_) => Some("adapter_id"),
+            InferError::InvalidToolArguments(_, _) => Some("tools"),
+            _ => None,
+        }
+    }
+
+    /// Estimated time to first token in milliseconds of a request rejected for its latency
+    pub(crate) fn estimated_ttft_ms(&self) -> Option<u64> {
+        match self {
+            InferError::TtftSlo(estimated, _) => Some(*estimated),
+            _ => None,
+        }
+    }
+
+    /// Whether the backend failed, rather than the request being rejected or cancelled
+    pub(crate) fn is_backend_failure(&self) -> bool {
+        matches!(
+            self,
+            InferError::GenerationError(_)
+                | InferError::GenerationTimeout(_)
+                | InferError::IncompleteGeneration
+                | InferError::IncompleteGenerationStream
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{ValidParameters, ValidStoppingParameters};
+    use crate::{default_parameters, GenerateParameters, Tokenizer};
+
+    /// Backend generating a single token after the prefill tokens
+    struct PrefillBackend;
+
+    #[async_trait]
+    impl Backend for PrefillBackend {
+        fn schedule(&self, _: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
+            let token = |id, logprob| PrefillToken {
+                id,
+                text: String::new(),
+                logprob,
+                start: None,
+                stop: None,
+            };
+            let prefill = InferStreamResponse::Prefill(vec![token(1, f32::NAN), token(2, -0.5)]);
+            Ok(Box::pin(tokio_stream::iter([Ok(prefill)])))
+        }
+
+        async fn health(&self, _: bool) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_backend_capabilities_intersect() {
+        let capabilities = BackendCapabilities {
+            supports_embeddings: true,
+            ..Default::default()
+        };
+        let other = BackendCapabilities {
+            supports_grammar: false,
+            supports_embeddings: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            capabilities.intersect(other),
+            BackendCapabilities {
+                supports_grammar: false,
+                supports_embeddings: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            capabilities.intersect(BackendCapabilities::default()),
+            BackendCapabilities::default()
+        );
+    }
+
+    /// Greedy request of one token after two input tokens
+    fn valid_request() -> ValidGenerateRequest {
+        ValidGenerateRequest {
+            inputs: vec![],
+            input_ids: None,
+            input_offsets: None,
+            input_length: 2,
+            truncate: 0,
+            add_special_tokens: true,
+            pre_tokenized: false,
+            decoder_input_details: true,
+            parameters: ValidParameters {
+                temperature: 1.0,
+                top_k: 0,
+                top_p: 1.0,
+                typical_p: 1.0,
+                do_sample: false,
+                seed: 0,
+                repetition_penalty: 1.0,
+                frequency_penalty: 0.0,
+                watermark: false,
+                grammar: None,
+                allowed_token_ids: vec![],
+                banned_token_ids: vec![],
+            },
+            stopping_parameters: ValidStoppingParameters {
+                max_new_tokens: 1,
+                stop_sequences: vec![],
+                ignore_eos_token: false,
+            },
+            top_n_tokens: 0,
+            adapter_id: None,
+            raw_bytes: false,
+            stop_on_repetition: None,
+            stop_on_json: false,
+            stop_token_ids: vec![],
+            pin_prefix: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefill_logits() {
+        let request = valid_request();
+
+        // The prefill tokens are returned without waiting for the generated token
+        let tokens = PrefillBackend
+            .prefill_logits(request.clone())
+            .await
+            .unwrap();
+        assert_eq!(tokens.iter().map(|t| t.id).collect::<Vec<_>>(), [1, 2]);
+        assert!(matches!(
+            PrefillBackend.embed(request).await,
+            Err(InferError::Unsupported("embeddings"))
+        ));
+        assert!(!PrefillBackend.capabilities().supports_prefill_logits);
+    }
+
+    #[test]
+    fn test_backend_capabilities_check() {
+        let capabilities = BackendCapabilities {
+            supports_extended_sampling: false,
+            ..Default::default()
+        };
+        assert!(capabilities.check(&valid_request()).is_ok());
+
+        let mut request = valid_request();
+        request.parameters.repetition_penalty = 1.2;
+        assert!(matches!(
+            capabilities.check(&request),
+            Err(ValidationError::SamplingUnsupported("repetition_penalty"))
+        ));
+        assert!(BackendCapabilities::default().check(&request).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_best_of_permits() {
+        let vocab = [("[UNK]".to_string(), 0)].into_iter().collect();
+        let model = tokenizers::models::wordlevel::WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let validation = Validation::new(
+            1,
+            Tokenizer::Rust(tokenizers::Tokenizer::new(model)),
+            None,
+            None,
+            4,
+            1,
+            1,
+            8,
+            16,
+            false,
+            Default::default(),
+            Default::default(),
+            None,
+        );
+        let infer = Infer::new(
+            PrefillBackend,
+            validation,
+            2,
+            HubTokenizerConfig::default(),
+            HubProcessorConfig::default(),
+            PromptTemplates::default(),
+            None,
+            SpecialTokenGuard::default(),
+            None,
+            None,
+            None,
+            PrefixPinningConfig::default(),
+            PromptCompressionConfig::default(),
+        );
+
+        // Every sequence needs one of the 2 concurrent requests
+        let request = GenerateRequest {
+            inputs: "hello".to_string(),
+            parameters: GenerateParameters {
+                do_sample: true,
+                ..default_parameters()
+            },
+            template: None,
+            variables: None,
+            inputs_ids: None,
+            suffix: None,
+            add_special_tokens: true,
+            endpoint: Endpoint::Generate,
+        };
+        let err = infer.generate_best_of(request, 3).await.unwrap_err();
+        assert!(matches!(
+            err,
+            InferError::ValidationError(ValidationError::BestOfConcurrency(2, 3))
+        ));
+
+        // The permits are acquired together, a failure releases them
+        let permits = infer.acquire_permits(2).unwrap();
+        assert!(matches!(
+            infer.acquire_permits(1),
+            Err(InferError::Overloaded(_))
+        ));
+        drop(permits);
+        let permit = infer.acquire_permits(1).unwrap();
+        assert!(infer.acquire_permits(2).is_err());
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 1);
+        drop(permit);
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 2);
+    }
 }
@@ -0,0 +1,85 @@
+use crate::infer::InferError;
+use minijinja::{context, Environment, UndefinedBehavior, Value};
+use minijinja_contrib::pycompat;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Server-side prompt templates, selected by name on the generate endpoints
+///
+/// Templates are rendered with the request `variables` and the request `inputs` as `inputs`.
+/// Undefined variables are an error, so a client cannot silently send an incomplete prompt.
+#[derive(Clone, Default)]
+pub(crate) struct PromptTemplates {
+    env: Arc<Environment<'static>>,
+}
+
+impl PromptTemplates {
+    pub(crate) fn new(templates: HashMap<String, String>) -> Result<Self, minijinja::Error> {
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        // enable things like .strip() or .capitalize()
+        env.set_unknown_method_callback(pycompat::unknown_method_callback);
+        // leaking the templates as read-only, static resources, like the chat template.
+        for (name, source) in templates {
+            env.add_template(
+                Box::leak(name.into_boxed_str()),
+                Box::leak(source.into_boxed_str()),
+            )?;
+        }
+        Ok(Self { env: Arc::new(env) })
+    }
+
+    pub(crate) fn render(
+        &self,
+        name: &str,
+        inputs: &str,
+        variables: Option<HashMap<String, serde_json::Value>>,
+    ) -> Result<String, InferError> {
+        let template = self.env.get_template(name)?;
+        let variables = Value::from_serialize(variables.unwrap_or_default());
+        Ok(template.render(context! { inputs, ..variables })?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn templates() -> PromptTemplates {
+        PromptTemplates::new(HashMap::from([(
+            "summarize@v1".to_string(),
+            "Summarize in {{ words }} words:\n{{ inputs.strip() }}".to_string(),
+        )]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_render_prompt_template() {
+        let variables = HashMap::from([("words".to_string(), json!(10))]);
+        let prompt = templates()
+            .render("summarize@v1", " Some text ", Some(variables))
+            .unwrap();
+        assert_eq!(prompt, "Summarize in 10 words:\nSome text");
+    }
+
+    #[test]
+    fn test_render_prompt_template_errors() {
+        let templates = templates();
+        assert!(matches!(
+            templates.render("unknown", "", None),
+            Err(InferError::TemplateError(_))
+        ));
+        // `words` is missing
+        assert!(matches!(
+            templates.render("summarize@v1", "Some text", None),
+            Err(InferError::TemplateError(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_prompt_template() {
+        let templates = HashMap::from([("broken".to_string(), "{{ inputs".to_string())]);
+        assert!(PromptTemplates::new(templates).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::infer::{GeneratedText, InferStreamResponse};
+use crate::{FinishReason, RepetitionStop};
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+/// Detects a generation stuck in a loop, from the ids of its generated tokens
+///
+/// The generation is stopped when the same window of tokens was generated `repeats` times,
+/// without overlap, e.g. `a b a b a b` for a window of 2 tokens repeated 3 times.
+pub(crate) struct RepetitionMonitor {
+    window: usize,
+    repeats: u32,
+    ids: Vec<u32>,
+    /// End of the last occurrence and number of occurrences of every window
+    occurrences: HashMap<Vec<u32>, (usize, u32)>,
+    /// Text of the generated tokens, returned when the generation is stopped
+    text: String,
+}
+
+impl RepetitionMonitor {
+    pub(crate) fn new(stop: RepetitionStop) -> Self {
+        Self {
+            window: stop.window as usize,
+            repeats: stop.repeats,
+            ids: Vec::new(),
+            occurrences: HashMap::new(),
+            text: String::new(),
+        }
+    }
+
+    /// Turn the response into the last one of the generation if its token closes a loop
+    pub(crate) fn check(
+        &mut self,
+        response: InferStreamResponse,
+        seed: Option<u64>,
+        start: Instant,
+        queued: Instant,
+    ) -> InferStreamResponse {
+        let InferStreamResponse::Intermediate { token, top_tokens } = response else {
+            return response;
+        };
+        if !token.special {
+            self.text.push_str(&token.text);
+        }
+        if !self.push(token.id) {
+            return InferStreamResponse::Intermediate { token, top_tokens };
+        }
+        InferStreamResponse::End {
+            token,
+            top_tokens,
+            generated_text: GeneratedText {
+                text: std::mem::take(&mut self.text),
+                generated_tokens: self.ids.len() as u32,
+                finish_reason: FinishReason::Repetition,
+                seed,
+                backend: None,
+                steps: None,
+            },
+            start,
+            queued,
+        }
+    }
+
+    /// Record a generated token, returns true once its window was generated `repeats` times
+    fn push(&mut self, id: u32) -> bool {
+        self.ids.push(id);
+        let end = self.ids.len();
+        if end < self.window {
+            return false;
+        }
+        let window = self.ids[end - self.window..].to_vec();
+        let (last_end, count) = self.occurrences.entry(window).or_insert((0, 0));
+        if *count == 0 || end - *last_end >= self.window {
+            *last_end = end;
+            *count += 1;
+        }
+        *count >= self.repeats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repetition_monitor() {
+        let mut monitor = RepetitionMonitor::new(RepetitionStop {
+            window: 2,
+            repeats: 3,
+        });
+        // Overlapping occurrences of `7 7` are counted once
+        assert!(![7, 7, 7, 1, 2].into_iter().any(|id| monitor.push(id)));
+        assert!(!monitor.push(1));
+        assert!(!monitor.push(2));
+        assert!(!monitor.push(1));
+        assert!(monitor.push(2));
+
+        let mut monitor = RepetitionMonitor::new(RepetitionStop {
+            window: 1,
+            repeats: 3,
+        });
+        assert!(!monitor.push(7));
+        assert!(!monitor.push(7));
+        assert!(monitor.push(7));
+    }
+}
@@ -0,0 +1,388 @@
+/// Next token choice of the backends computing the logits of the model, but not the sampling
+///
+/// The penalties and warpers are applied in the order of the Python shards: repetition and
+/// frequency penalties, the allowed and banned tokens, then temperature, top-k, top-p and
+/// typical-p. A request samples from
+/// the same distribution whichever backend serves it, the draws seeded with its `seed` are not
+/// those of the shards though.
+use crate::validation::ValidParameters;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+/// Penalties, warpers and seeded draws of the tokens of one request
+#[derive(Debug)]
+pub struct Sampler {
+    temperature: f32,
+    top_k: u32,
+    top_p: f32,
+    typical_p: f32,
+    do_sample: bool,
+    repetition_penalty: f32,
+    frequency_penalty: f32,
+    /// Sorted, all the tokens are allowed if empty
+    allowed_token_ids: Vec<u32>,
+    banned_token_ids: Vec<u32>,
+    rng: StdRng,
+    /// Occurrences of the prompt and generated tokens, for the penalties
+    counts: HashMap<u32, u32>,
+    /// Number of prompt and generated tokens
+    length: u32,
+}
+
+/// Token chosen by [`Sampler::next_token`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sampled {
+    pub id: u32,
+    /// Log probability of the token after the penalties and warpers, as reported by the shards
+    pub logprob: f32,
+}
+
+impl Sampler {
+    /// Sampler of a request with these `parameters`, whose prompt is `input_ids`
+    ///
+    /// The grammar and the watermark of the parameters are not applied.
+    pub fn new(parameters: &ValidParameters, input_ids: &[u32]) -> Self {
+        let mut sampler = Self {
+            temperature: parameters.temperature,
+            top_k: parameters.top_k,
+            top_p: parameters.top_p,
+            typical_p: parameters.typical_p,
+            do_sample: parameters.do_sample,
+            repetition_penalty: parameters.repetition_penalty,
+            frequency_penalty: parameters.frequency_penalty,
+            allowed_token_ids: parameters.allowed_token_ids.clone(),
+            banned_token_ids: parameters.banned_token_ids.clone(),
+            rng: StdRng::seed_from_u64(parameters.seed),
+            counts: HashMap::new(),
+            length: 0,
+        };
+        for id in input_ids {
+            sampler.record(*id);
+        }
+        sampler
+    }
+
+    /// Choose the next token from the `logits` of the vocabulary, processed in place, and
+    /// count it for the penalties of the following tokens
+    pub fn next_token(&mut self, logits: &mut [f32]) -> Sampled {
+        assert!(!logits.is_empty(), "the vocabulary is empty");
+        self.apply_penalties(logits);
+        self.apply_token_lists(logits);
+        self.apply_warpers(logits);
+        let logprobs = log_softmax(logits);
+        let id = if self.do_sample {
+            self.draw(&logprobs)
+        } else {
+            argmax(logits)
+        };
+        self.record(id as u32);
+        Sampled {
+            id: id as u32,
+            logprob: logprobs[id],
+        }
+    }
+
+    fn record(&mut self, id: u32) {
+        *self.counts.entry(id).or_default() += 1;
+        self.length += 1;
+    }
+
+    fn apply_penalties(&self, logits: &mut [f32]) {
+        if self.repetition_penalty == 1.0 && self.frequency_penalty == 0.0 {
+            return;
+        }
+        for (id, count) in &self.counts {
+            let Some(logit) = logits.get_mut(*id as usize) else {
+                continue;
+            };
+            if *logit < 0.0 {
+                *logit *= self.repetition_penalty;
+            } else {
+                *logit /= self.repetition_penalty;
+            }
+            // Penalized by the frequency of the token in the sequence, not by its count
+            *logit -= *count as f32 / self.length as f32 * self.frequency_penalty;
+        }
+    }
+
+    fn apply_token_lists(&self, logits: &mut [f32]) {
+        if !self.allowed_token_ids.is_empty() {
+            logits
+                .iter_mut()
+                .enumerate()
+                .filter(|(id, _)| self.allowed_token_ids.binary_search(&(*id as u32)).is_err())
+                .for_each(|(_, logit)| *logit = f32::NEG_INFINITY);
+        }
+        for id in &self.banned_token_ids {
+            if let Some(logit) = logits.get_mut(*id as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    fn apply_warpers(&self, logits: &mut [f32]) {
+        if self.temperature != 1.0 {
+            logits
+                .iter_mut()
+                .for_each(|logit| *logit /= self.temperature);
+        }
+        if self.top_k > 0 && (self.top_k as usize) < logits.len() {
+            top_k(logits, self.top_k as usize);
+        }
+        if self.top_p < 1.0 {
+            top_p(logits, self.top_p);
+        }
+        if self.typical_p < 1.0 {
+            typical_p(logits, self.typical_p);
+        }
+    }
+
+    fn draw(&mut self, logprobs: &[f32]) -> usize {
+        let mut draw: f32 = self.rng.gen();
+        for (id, logprob) in logprobs.iter().enumerate() {
+            let probability = logprob.exp();
+            if draw < probability {
+                return id;
+            }
+            draw -= probability;
+        }
+        // The probabilities sum to slightly less than 1
+        argmax(logprobs)
+    }
+}
+
+/// Remove the tokens less likely than the `k`-th most likely one
+fn top_k(logits: &mut [f32], k: usize) {
+    let mut sorted = logits.to_vec();
+    let (_, kth, _) = sorted.select_nth_unstable_by(k - 1, |a, b| b.total_cmp(a));
+    let kth = *kth;
+    logits
+        .iter_mut()
+        .filter(|logit| **logit < kth)
+        .for_each(|logit| *logit = f32::NEG_INFINITY);
+}
+
+/// Remove the least likely tokens whose cumulative probability is at most `1 - top_p`, the
+/// most likely token is always kept
+fn top_p(logits: &mut [f32], top_p: f32) {
+    let probabilities = softmax(logits);
+    let mut ascending: Vec<usize> = (0..logits.len()).collect();
+    ascending.sort_by(|a, b| logits[*a].total_cmp(&logits[*b]));
+    let mut cumulative = 0.0;
+    for id in &ascending[..ascending.len() - 1] {
+        cumulative += probabilities[*id];
+        if cumulative > 1.0 - top_p {
+            break;
+        }
+        logits[*id] = f32::NEG_INFINITY;
+    }
+}
+
+/// Keep the tokens whose information content is the closest to the entropy of the
+/// distribution, up to a cumulative probability of `mass`
+fn typical_p(logits: &mut [f32], mass: f32) {
+    let logprobs = log_softmax(logits);
+    let entropy: f32 = -logprobs
+        .iter()
+        .filter(|logprob| logprob.is_finite())
+        .map(|logprob| logprob.exp() * logprob)
+        .sum::<f32>();
+    let shifted: Vec<f32> = logprobs
+        .iter()
+        .map(|logprob| (-logprob - entropy).abs())
+        .collect();
+    let mut typical: Vec<usize> = (0..logits.len()).collect();
+    typical.sort_by(|a, b| shifted[*a].total_cmp(&shifted[*b]));
+
+    let mut cumulative = 0.0;
+    let mut last = 0;
+    for id in &typical {
+        cumulative += logprobs[*id].exp();
+        if cumulative >= mass {
+            break;
+        }
+        last += 1;
+    }
+    let threshold = shifted[typical[last.min(typical.len() - 1)]];
+    logits
+        .iter_mut()
+        .zip(&shifted)
+        .filter(|(_, shifted)| **shifted > threshold)
+        .for_each(|(logit, _)| *logit = f32::NEG_INFINITY);
+}
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    log_softmax(logits).into_iter().map(f32::exp).collect()
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|logit| (logit - max).exp()).sum();
+    let log_sum = max + sum.ln();
+    logits.iter().map(|logit| logit - log_sum).collect()
+}
+
+fn argmax(values: &[f32]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(id, _)| id)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampling_parameters() -> ValidParameters {
+        ValidParameters {
+            temperature: 1.0,
+            top_k: 0,
+            top_p: 1.0,
+            typical_p: 1.0,
+            do_sample: true,
+            seed: 42,
+            repetition_penalty: 1.0,
+            frequency_penalty: 0.0,
+            watermark: false,
+            grammar: None,
+            allowed_token_ids: vec![],
+            banned_token_ids: vec![],
+        }
+    }
+
+    /// Logits of a distribution over the tokens with these `probabilities`
+    fn logits(probabilities: &[f32]) -> Vec<f32> {
+        probabilities.iter().map(|p| p.ln()).collect()
+    }
+
+    #[test]
+    fn test_greedy() {
+        let parameters = ValidParameters {
+            do_sample: false,
+            ..sampling_parameters()
+        };
+        let mut sampler = Sampler::new(&parameters, &[]);
+        let sampled = sampler.next_token(&mut logits(&[0.2, 0.5, 0.3]));
+        assert_eq!(sampled.id, 1);
+        assert!((sampled.logprob - 0.5f32.ln()).abs() < 1e-6);
+
+        // The repeated tokens are penalized, the prompt included
+        let parameters = ValidParameters {
+            do_sample: false,
+            repetition_penalty: 2.0,
+            ..sampling_parameters()
+        };
+        let mut sampler = Sampler::new(&parameters, &[1]);
+        assert_eq!(sampler.next_token(&mut [1.0, 1.5, 0.5]).id, 0);
+        assert_eq!(sampler.next_token(&mut [1.0, 1.5, 0.5]).id, 1);
+        // Both were generated
+        assert_eq!(sampler.next_token(&mut [1.0, 1.5, 0.9]).id, 2);
+    }
+
+    #[test]
+    fn test_frequency_penalty() {
+        let parameters = ValidParameters {
+            do_sample: false,
+            frequency_penalty: 1.0,
+            ..sampling_parameters()
+        };
+        let mut sampler = Sampler::new(&parameters, &[0, 0, 0, 1]);
+        let mut logits = vec![1.0, 1.0, 1.0];
+        sampler.next_token(&mut logits);
+        assert_eq!(logits, [0.25, 0.75, 1.0]);
+    }
+
+    #[test]
+    fn test_token_lists() {
+        let parameters = ValidParameters {
+            do_sample: false,
+            allowed_token_ids: vec![0, 2, 3],
+            banned_token_ids: vec![3],
+            ..sampling_parameters()
+        };
+        let mut sampler = Sampler::new(&parameters, &[]);
+        let mut logits = logits(&[0.1, 0.5, 0.15, 0.25]);
+        let sampled = sampler.next_token(&mut logits);
+        assert_eq!(sampled.id, 2);
+        // The remaining tokens share the probability mass
+        assert!((sampled.logprob - 0.6f32.ln()).abs() < 1e-6);
+        assert_eq!(
+            logits.iter().map(|l| l.is_finite()).collect::<Vec<_>>(),
+            [true, false, true, false]
+        );
+    }
+
+    #[test]
+    fn test_warpers() {
+        let probabilities = [0.05, 0.5, 0.15, 0.3];
+        let kept = |parameters: ValidParameters| {
+            let mut logits = logits(&probabilities);
+            Sampler::new(&parameters, &[]).next_token(&mut logits);
+            logits
+                .iter()
+                .map(|logit| logit.is_finite())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kept(ValidParameters {
+                top_k: 2,
+                ..sampling_parameters()
+            }),
+            [false, true, false, true]
+        );
+        assert_eq!(
+            kept(ValidParameters {
+                top_p: 0.7,
+                ..sampling_parameters()
+            }),
+            [false, true, false, true]
+        );
+        assert_eq!(
+            kept(ValidParameters {
+                top_p: 0.9,
+                ..sampling_parameters()
+            }),
+            [false, true, true, true]
+        );
+        // The token closest to the entropy comes first, up to the mass
+        assert_eq!(
+            kept(ValidParameters {
+                typical_p: 0.2,
+                ..sampling_parameters()
+            }),
+            [false, false, false, true]
+        );
+
+        // The temperature flattens the distribution
+        let parameters = ValidParameters {
+            temperature: 2.0,
+            ..sampling_parameters()
+        };
+        let sampled = Sampler::new(&parameters, &[]).next_token(&mut logits(&[0.2, 0.8]));
+        let expected = (0.2f32.sqrt() + 0.8f32.sqrt()).ln();
+        let logprob = if sampled.id == 0 { 0.2f32 } else { 0.8f32 }.sqrt().ln() - expected;
+        assert!((sampled.logprob - logprob).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_seeded_draws() {
+        let draws = |seed| {
+            let parameters = ValidParameters {
+                seed,
+                ..sampling_parameters()
+            };
+            let mut sampler = Sampler::new(&parameters, &[]);
+            (0..100)
+                .map(|_| sampler.next_token(&mut logits(&[0.25, 0.25, 0.5])).id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(42), draws(42));
+        assert_ne!(draws(42), draws(43));
+        // The tokens are drawn with their probabilities
+        let twos = draws(42).iter().filter(|id| **id == 2).count();
+        assert!((35..65).contains(&twos), "{twos}");
+    }
+}
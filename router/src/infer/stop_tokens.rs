@@ -0,0 +1,91 @@
+use crate::infer::{GeneratedText, InferStreamResponse};
+use crate::FinishReason;
+use tokio::time::Instant;
+
+/// Ends the generation on the stop tokens configured for the model, which the backend does not
+/// know about
+pub(crate) struct StopTokenMonitor {
+    /// Sorted ids of the stop tokens
+    ids: Vec<u32>,
+    generated_tokens: u32,
+    /// Text of the generated tokens, returned when the generation is stopped
+    text: String,
+}
+
+impl StopTokenMonitor {
+    pub(crate) fn new(ids: Vec<u32>) -> Self {
+        Self {
+            ids,
+            generated_tokens: 0,
+            text: String::new(),
+        }
+    }
+
+    /// Turn the response into the last one of the generation if its token is a stop token
+    pub(crate) fn check(
+        &mut self,
+        response: InferStreamResponse,
+        seed: Option<u64>,
+        start: Instant,
+        queued: Instant,
+    ) -> InferStreamResponse {
+        let InferStreamResponse::Intermediate { token, top_tokens } = response else {
+            return response;
+        };
+        self.generated_tokens += 1;
+        if !token.special {
+            self.text.push_str(&token.text);
+        }
+        if self.ids.binary_search(&token.id).is_err() {
+            return InferStreamResponse::Intermediate { token, top_tokens };
+        }
+        InferStreamResponse::End {
+            token,
+            top_tokens,
+            generated_text: GeneratedText {
+                text: std::mem::take(&mut self.text),
+                generated_tokens: self.generated_tokens,
+                finish_reason: FinishReason::EndOfSequenceToken,
+                seed,
+                backend: None,
+                steps: None,
+            },
+            start,
+            queued,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Token;
+
+    fn token(id: u32, text: &str, special: bool) -> InferStreamResponse {
+        InferStreamResponse::Intermediate {
+            token: Token {
+                id,
+                text: text.to_string(),
+                logprob: 0.0,
+                special,
+                bytes: None,
+            },
+            top_tokens: vec![],
+        }
+    }
+
+    #[test]
+    fn test_stop_token_monitor() {
+        let mut monitor = StopTokenMonitor::new(vec![7, 9]);
+        let now = Instant::now();
+        let response = monitor.check(token(1, "Hello", false), None, now, now);
+        assert!(matches!(response, InferStreamResponse::Intermediate { .. }));
+        match monitor.check(token(9, "<|eot_id|>", true), None, now, now) {
+            InferStreamResponse::End { generated_text, .. } => {
+                assert_eq!(generated_text.text, "Hello");
+                assert_eq!(generated_text.generated_tokens, 2);
+            }
+            _ => panic!("The stop token did not end the generation"),
+        }
+    }
+}
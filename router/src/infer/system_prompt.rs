@@ -0,0 +1,177 @@
+use crate::router_config::{SystemPromptConfig, SystemPromptPolicy};
+use crate::usage::bearer_token;
+use crate::{Message, MessageChunk, MessageContent};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+tokio::task_local! {
+    /// System prompt policy of the caller of the request being handled
+    static POLICY: Option<Arc<SystemPromptPolicy>>;
+}
+
+/// System prompt policies of the API keys, so that a deployment controls the behavior of the
+/// model whatever the clients send
+#[derive(Clone, Default)]
+pub(crate) struct SystemPrompts {
+    default: Option<Arc<SystemPromptPolicy>>,
+    keys: Arc<HashMap<String, Arc<SystemPromptPolicy>>>,
+}
+
+impl SystemPrompts {
+    /// `None` if no policy is configured
+    pub(crate) fn new(config: SystemPromptConfig) -> Option<Self> {
+        if config.is_empty() {
+            return None;
+        }
+        Some(Self {
+            default: config.default.map(Arc::new),
+            keys: Arc::new(
+                config
+                    .keys
+                    .into_iter()
+                    .map(|(key, policy)| (key, Arc::new(policy)))
+                    .collect(),
+            ),
+        })
+    }
+
+    fn policy(&self, key: Option<&str>) -> Option<Arc<SystemPromptPolicy>> {
+        key.and_then(|key| self.keys.get(key))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+impl SystemPromptPolicy {
+    /// Cap, replace and prepend the system messages of `messages`
+    pub(crate) fn apply(&self, messages: &mut Vec<Message>) {
+        if let Some(max_length) = self.max_length {
+            for message in messages
+                .iter_mut()
+                .filter(|message| message.role == "system")
+            {
+                let mut remaining = max_length;
+                for text in message.content.texts_mut() {
+                    if let Some((end, _)) = text.char_indices().nth(remaining) {
+                        text.truncate(end);
+                    }
+                    remaining = remaining.saturating_sub(text.chars().count());
+                }
+            }
+        }
+        if let Some(replace) = &self.replace {
+            messages.retain(|message| message.role != "system");
+            messages.insert(0, system_message(replace.clone()));
+        }
+        if let Some(prepend) = &self.prepend {
+            match messages.first_mut() {
+                Some(message) if message.role == "system" => match &mut message.content {
+                    MessageContent::SingleText(text) => {
+                        text.insert_str(0, &format!("{prepend}\n\n"))
+                    }
+                    MessageContent::MultipleChunks(chunks) => chunks.insert(
+                        0,
+                        MessageChunk::Text {
+                            text: format!("{prepend}\n\n"),
+                        },
+                    ),
+                },
+                _ => messages.insert(0, system_message(prepend.clone())),
+            }
+        }
+    }
+}
+
+fn system_message(content: String) -> Message {
+    Message {
+        role: "system".to_string(),
+        content: MessageContent::SingleText(content),
+        name: None,
+        prefix: false,
+    }
+}
+
+/// Policy of the caller of the request being handled
+pub(crate) fn caller_policy() -> Option<Arc<SystemPromptPolicy>> {
+    POLICY.try_with(|policy| policy.clone()).ok().flatten()
+}
+
+/// Select the system prompt policy of the key of the request
+pub(crate) async fn identify_system_prompt(
+    State(system_prompts): State<SystemPrompts>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let policy = system_prompts.policy(bearer_token(request.headers()));
+    POLICY.scope(policy, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: MessageContent::SingleText(content.to_string()),
+            name: None,
+            prefix: false,
+        }
+    }
+
+    fn contents(messages: Vec<Message>) -> Vec<(String, String)> {
+        messages
+            .into_iter()
+            .map(|message| {
+                let message: crate::TextMessage = message.into();
+                (message.role, message.content)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_system_prompt_policy() {
+        let messages = vec![
+            message("system", "Talk like a pirate"),
+            message("user", "Hi"),
+        ];
+
+        let mut capped = messages.clone();
+        let policy = SystemPromptPolicy {
+            max_length: Some(4),
+            prepend: Some("Be safe".to_string()),
+            ..Default::default()
+        };
+        policy.apply(&mut capped);
+        assert_eq!(
+            contents(capped),
+            [
+                ("system".to_string(), "Be safe\n\nTalk".to_string()),
+                ("user".to_string(), "Hi".to_string())
+            ]
+        );
+
+        let mut replaced = messages.clone();
+        let policy = SystemPromptPolicy {
+            replace: Some("Be helpful".to_string()),
+            ..Default::default()
+        };
+        policy.apply(&mut replaced);
+        assert_eq!(contents(replaced)[0].1, "Be helpful");
+
+        // The prepended prompt is added as a system message when the request has none
+        let mut added = vec![message("user", "Hi")];
+        let policy = SystemPromptPolicy {
+            prepend: Some("Be safe".to_string()),
+            ..Default::default()
+        };
+        policy.apply(&mut added);
+        assert_eq!(
+            contents(added)[0],
+            ("system".to_string(), "Be safe".to_string())
+        );
+    }
+}
@@ -0,0 +1,577 @@
+use crate::infer::InferError;
+use crate::{
+    ChatRequest, DeltaToolCall, Function, FunctionDefinition, Message, MessageContent, Tool,
+    ToolCall,
+};
+use jsonschema::{Draft, JSONSchema};
+use serde_json::Value;
+
+/// Function of the responses without tool call, its `content` is the text of the response
+const NO_TOOL: &str = "no_tool";
+
+/// Tool calls, or content of a response without tool call, generated with the tool grammar
+pub(crate) fn parse_tool_calls(
+    generated_text: &str,
+) -> Result<(Option<Vec<ToolCall>>, Option<String>), InferError> {
+    let value: Value = serde_json::from_str(generated_text).map_err(|e| {
+        InferError::ToolError(format!(
+            "Failed to parse generated text: {} {:?}",
+            e, generated_text
+        ))
+    })?;
+    let function = value.get("function").ok_or(InferError::ToolError(
+        "No function found in generated text".to_string(),
+    ))?;
+    let calls = match function {
+        Value::Array(calls) => calls.as_slice(),
+        call => std::slice::from_ref(call),
+    };
+
+    let mut tool_calls = Vec::with_capacity(calls.len());
+    for (index, call) in calls.iter().enumerate() {
+        let name = call
+            .get("_name")
+            .and_then(Value::as_str)
+            .ok_or(InferError::ToolError(
+                "No _name found in generated text".to_string(),
+            ))?
+            .to_string();
+        let mut arguments = call.clone();
+        if let Value::Object(ref mut props) = arguments {
+            props.remove("_name");
+        }
+        if name == NO_TOOL {
+            let content = arguments
+                .get("content")
+                .and_then(Value::as_str)
+                .ok_or_else(|| {
+                    InferError::ToolError("No `content` found in generated text".to_string())
+                })?
+                .to_string();
+            return Ok((None, Some(content)));
+        }
+        tool_calls.push(ToolCall {
+            id: index.to_string(),
+            r#type: "function".to_string(),
+            function: FunctionDefinition {
+                description: None,
+                name,
+                arguments,
+            },
+        });
+    }
+    Ok((Some(tool_calls), None))
+}
+
+/// JSON schemas of the arguments of the tools of a request, compiled once for all the checks
+/// of its tool calls
+///
+/// The tools whose schema does not compile are not checked.
+pub(crate) struct ToolSchemas(Vec<(String, JSONSchema)>);
+
+impl ToolSchemas {
+    pub(crate) fn new(tools: &[Tool]) -> Self {
+        let schemas = tools
+            .iter()
+            .filter_map(|tool| {
+                let schema = JSONSchema::options()
+                    .with_draft(Draft::Draft202012)
+                    .compile(&tool.function.arguments)
+                    .ok()?;
+                Some((tool.function.name.clone(), schema))
+            })
+            .collect();
+        Self(schemas)
+    }
+
+    /// Check the arguments of the tool calls against the schemas of their tools, the grammar
+    /// does not enforce every keyword of the schemas
+    ///
+    /// The calls of unknown tools are not checked.
+    pub(crate) fn check(&self, tool_calls: &[ToolCall]) -> Result<(), InferError> {
+        for call in tool_calls {
+            let Some((_, schema)) = self.0.iter().find(|(name, _)| *name == call.function.name)
+            else {
+                continue;
+            };
+            let errors: Vec<String> = match schema.validate(&call.function.arguments) {
+                Ok(()) => continue,
+                Err(errors) => errors
+                    .map(|error| match error.instance_path.to_string() {
+                        path if path.is_empty() => error.to_string(),
+                        path => format!("{path}: {error}"),
+                    })
+                    .collect(),
+            };
+            return Err(InferError::InvalidToolArguments(
+                call.function.name.clone(),
+                errors.join(", "),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Retries of a chat request whose tool calls have arguments failing the schemas of their
+/// tools
+pub(crate) struct ToolCallRetries {
+    request: ChatRequest,
+    schemas: ToolSchemas,
+    left: u32,
+}
+
+impl ToolCallRetries {
+    pub(crate) fn new(request: ChatRequest, retries: u32) -> Self {
+        let schemas = ToolSchemas::new(request.tools.as_deref().unwrap_or_default());
+        Self {
+            request,
+            schemas,
+            left: retries,
+        }
+    }
+
+    /// Request generating the tool calls again, with the generated calls and why they were
+    /// rejected appended to the conversation
+    ///
+    /// `None` when the calls match their schemas, or when no retry is left: the last calls are
+    /// then returned as generated, the client is not at fault.
+    pub(crate) fn next(
+        &mut self,
+        generated_text: &str,
+        tool_calls: &[ToolCall],
+    ) -> Option<ChatRequest> {
+        let err = self.schemas.check(tool_calls).err()?;
+        if self.left == 0 {
+            tracing::warn!("Returning the tool calls without retry left: {err}");
+            return None;
+        }
+        self.left -= 1;
+        metrics::counter!("tgi_tool_call_retries").increment(1);
+        tracing::debug!("Retrying the tool calls: {err}");
+        // The model sees its calls and why they were rejected
+        self.request.messages.push(Message {
+            role: "assistant".to_string(),
+            content: MessageContent::SingleText(generated_text.to_string()),
+            name: None,
+            prefix: false,
+        });
+        self.request.messages.push(Message {
+            role: "user".to_string(),
+            content: MessageContent::SingleText(format!(
+                "{err}. Call the tool again with arguments matching its schema."
+            )),
+            name: None,
+            prefix: false,
+        });
+        Some(self.request.clone())
+    }
+}
+
+/// Part of a response streamed with the tool grammar
+#[derive(Debug, PartialEq)]
+pub(crate) enum ToolStreamDelta {
+    /// Text of a response without tool call
+    Content(String),
+    ToolCalls(Vec<DeltaToolCall>),
+}
+
+/// Incremental parser of the responses generated with the tool grammar, `{"function": call}`
+/// or `{"function": [call, ...]}` where a call is an object with the name of the function in
+/// `_name` and its arguments in the other members
+///
+/// The arguments of a call are streamed once its name is known, without the whitespace
+/// outside of the strings.
+#[derive(Default)]
+pub(crate) struct ToolCallStream {
+    /// Nesting of the objects and arrays
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// The value of `function` is a list of calls
+    in_list: bool,
+    /// Number of calls started
+    calls: usize,
+    call: Option<Call>,
+}
+
+#[derive(Default)]
+struct Call {
+    index: usize,
+    /// Nesting of the members of the call object
+    depth: usize,
+    name: Option<String>,
+    /// Arguments generated before the name
+    pending: String,
+    /// Number of arguments
+    arguments: usize,
+    member: Member,
+    /// Escape sequence of the content being decoded
+    escape: String,
+}
+
+#[derive(Default)]
+enum Member {
+    #[default]
+    None,
+    /// JSON string of the key
+    Key(String),
+    /// JSON string of the name of the function
+    Name(String),
+    /// Content of a response without tool call
+    Content,
+    Argument,
+}
+
+#[derive(Default)]
+struct Output {
+    content: String,
+    tool_calls: Vec<DeltaToolCall>,
+}
+
+impl Output {
+    fn start(&mut self, index: usize, name: &str) {
+        self.tool_calls.push(DeltaToolCall {
+            index: index as u32,
+            id: Some(index.to_string()),
+            r#type: Some("function".to_string()),
+            function: Function {
+                name: Some(name.to_string()),
+                arguments: String::new(),
+            },
+        });
+    }
+
+    fn arguments(&mut self, index: usize, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        match self.tool_calls.last_mut() {
+            Some(call) if call.index == index as u32 => call.function.arguments.push_str(text),
+            _ => self.tool_calls.push(DeltaToolCall {
+                index: index as u32,
+                id: None,
+                r#type: None,
+                function: Function {
+                    name: None,
+                    arguments: text.to_string(),
+                },
+            }),
+        }
+    }
+}
+
+impl ToolCallStream {
+    /// Parse the next generated text, returns the parts of the response it completes
+    pub(crate) fn push(&mut self, text: &str) -> Option<ToolStreamDelta> {
+        let mut output = Output::default();
+        for c in text.chars() {
+            self.next(c, &mut output);
+        }
+        if !output.tool_calls.is_empty() {
+            Some(ToolStreamDelta::ToolCalls(output.tool_calls))
+        } else if !output.content.is_empty() {
+            Some(ToolStreamDelta::Content(output.content))
+        } else {
+            None
+        }
+    }
+
+    fn next(&mut self, c: char, output: &mut Output) {
+        let in_string = self.in_string;
+        if in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if c == '\\' {
+                self.escaped = true;
+            } else if c == '"' {
+                self.in_string = false;
+            }
+        } else if c.is_whitespace() {
+            return;
+        } else if c == '"' {
+            self.in_string = true;
+        }
+        // Character of the JSON structure or of a number or literal, not of a string
+        let structural = !in_string && c != '"';
+        let depth = self.depth;
+        if structural {
+            match c {
+                '{' | '[' => self.depth += 1,
+                '}' | ']' => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+
+        let Some(call) = &mut self.call else {
+            match (c, depth) {
+                ('[', 1) if structural => self.in_list = true,
+                ('{', 1) | ('{', 2) if structural && (depth == 1 || self.in_list) => {
+                    self.call = Some(Call {
+                        index: self.calls,
+                        depth: self.depth,
+                        pending: "{".to_string(),
+                        ..Default::default()
+                    });
+                    self.calls += 1;
+                }
+                _ => {}
+            }
+            return;
+        };
+
+        if structural && depth == call.depth {
+            match c {
+                ':' => call.end_key(output),
+                ',' => call.end_member(output),
+                '}' => {
+                    call.end_member(output);
+                    call.write("}", output);
+                    self.call = None;
+                }
+                _ => call.value(c, output),
+            }
+        } else {
+            call.value(c, output);
+        }
+    }
+}
+
+impl Call {
+    fn value(&mut self, c: char, output: &mut Output) {
+        match &mut self.member {
+            Member::None => {
+                if c == '"' {
+                    self.member = Member::Key(c.to_string());
+                }
+            }
+            Member::Key(raw) | Member::Name(raw) => raw.push(c),
+            Member::Content => self.decode(c, output),
+            Member::Argument => self.write(c.encode_utf8(&mut [0; 4]), output),
+        }
+    }
+
+    fn end_key(&mut self, output: &mut Output) {
+        let Member::Key(raw) = std::mem::take(&mut self.member) else {
+            return;
+        };
+        let key: String = serde_json::from_str(&raw).unwrap_or_default();
+        self.member = if key == "_name" {
+            Member::Name(String::new())
+        } else if key == "content" && self.name.as_deref() == Some(NO_TOOL) {
+            Member::Content
+        } else {
+            let separator = if self.arguments > 0 { "," } else { "" };
+            self.arguments += 1;
+            self.write(&format!("{separator}{raw}:"), output);
+            Member::Argument
+        };
+    }
+
+    fn end_member(&mut self, output: &mut Output) {
+        if let Member::Name(raw) = std::mem::take(&mut self.member) {
+            let name: String = serde_json::from_str(&raw).unwrap_or_default();
+            let pending = std::mem::take(&mut self.pending);
+            if name == NO_TOOL {
+                // The content was generated before the name
+                let arguments: Option<Value> = serde_json::from_str(&(pending + "}")).ok();
+                if let Some(content) = arguments
+                    .as_ref()
+                    .and_then(|arguments| arguments.get("content"))
+                    .and_then(Value::as_str)
+                {
+                    output.content.push_str(content);
+                }
+            } else {
+                output.start(self.index, &name);
+                output.arguments(self.index, &pending);
+            }
+            self.name = Some(name);
+        }
+    }
+
+    fn write(&mut self, text: &str, output: &mut Output) {
+        match self.name.as_deref() {
+            None => self.pending.push_str(text),
+            Some(NO_TOOL) => {}
+            Some(_) => output.arguments(self.index, text),
+        }
+    }
+
+    /// Decode the next character of the JSON string of the content
+    fn decode(&mut self, c: char, output: &mut Output) {
+        if !self.escape.is_empty() || c == '\\' {
+            self.escape.push(c);
+            if let Some(text) = decode_escape(&self.escape) {
+                output.content.push_str(&text);
+                self.escape.clear();
+            }
+        } else if c != '"' {
+            output.content.push(c);
+        }
+    }
+}
+
+/// Text of an escape sequence of a JSON string, `None` if the sequence is incomplete
+fn decode_escape(escape: &str) -> Option<String> {
+    let complete = match escape.as_bytes() {
+        [b'\\', b'u', ..] => escape.len() == 6 || escape.len() == 12,
+        [b'\\', _] => true,
+        _ => false,
+    };
+    if !complete {
+        return None;
+    }
+    match serde_json::from_str(&format!("\"{escape}\"")) {
+        Ok(text) => Some(text),
+        // A high surrogate is followed by the escape of the low one
+        Err(_) if escape.len() == 6 => None,
+        Err(_) => Some(char::REPLACEMENT_CHARACTER.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stream `text` one character at a time
+    fn stream(text: &str) -> Vec<ToolStreamDelta> {
+        let mut parser = ToolCallStream::default();
+        text.chars()
+            .filter_map(|c| parser.push(c.encode_utf8(&mut [0; 4])))
+            .collect()
+    }
+
+    /// Arguments of every call of the streamed deltas
+    fn arguments(deltas: &[ToolStreamDelta]) -> Vec<(String, String)> {
+        let mut calls: Vec<(String, String)> = vec![];
+        for delta in deltas {
+            let ToolStreamDelta::ToolCalls(tool_calls) = delta else {
+                panic!("Unexpected content");
+            };
+            for call in tool_calls {
+                let index = call.index as usize;
+                if let Some(name) = &call.function.name {
+                    assert_eq!(index, calls.len());
+                    calls.push((name.clone(), String::new()));
+                }
+                calls[index].1.push_str(&call.function.arguments);
+            }
+        }
+        calls
+    }
+
+    #[test]
+    fn test_parse_tool_calls() {
+        let text = r#"{"function": [{"_name": "get_weather", "location": "Paris"}, {"_name": "get_time", "zone": "CET"}]}"#;
+        let (tool_calls, content) = parse_tool_calls(text).unwrap();
+        let tool_calls = tool_calls.unwrap();
+        assert_eq!(content, None);
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[1].id, "1");
+        assert_eq!(tool_calls[1].function.name, "get_time");
+        assert_eq!(
+            tool_calls[1].function.arguments,
+            serde_json::json!({"zone": "CET"})
+        );
+
+        let text = r#"{"function": {"_name": "no_tool", "content": "Hello"}}"#;
+        assert_eq!(
+            parse_tool_calls(text).unwrap(),
+            (None, Some("Hello".to_string()))
+        );
+    }
+
+    fn weather_tools() -> serde_json::Value {
+        serde_json::json!([{
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "parameters": {
+                    "type": "object",
+                    "properties": {"days": {"type": "integer", "minimum": 1, "maximum": 7}},
+                    "required": ["days"]
+                }
+            }
+        }])
+    }
+
+    #[test]
+    fn test_check_tool_arguments() {
+        let tools: Vec<Tool> = serde_json::from_value(weather_tools()).unwrap();
+        let schemas = ToolSchemas::new(&tools);
+        let text = r#"{"function": {"_name": "get_weather", "days": 3}}"#;
+        let (tool_calls, _) = parse_tool_calls(text).unwrap();
+        assert!(schemas.check(&tool_calls.unwrap()).is_ok());
+
+        let text = r#"{"function": [{"_name": "get_time"}, {"_name": "get_weather", "days": 10}]}"#;
+        let (tool_calls, _) = parse_tool_calls(text).unwrap();
+        match schemas.check(&tool_calls.unwrap()) {
+            Err(InferError::InvalidToolArguments(name, error)) => {
+                assert_eq!(name, "get_weather");
+                assert!(error.starts_with("/days: "), "{error}");
+            }
+            result => panic!("Unexpected check result {result:?}"),
+        }
+    }
+
+    #[test]
+    fn test_tool_call_retries() {
+        let request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "messages": [{"role": "user", "content": "Weather for the next 10 days?"}],
+            "tools": weather_tools(),
+        }))
+        .unwrap();
+        let mut retries = ToolCallRetries::new(request, 2);
+        let invalid = r#"{"function": {"_name": "get_weather", "days": 10}}"#;
+        let (tool_calls, _) = parse_tool_calls(invalid).unwrap();
+        let tool_calls = tool_calls.unwrap();
+
+        // Generated again with the calls and the error in the conversation
+        let retry = retries.next(invalid, &tool_calls).unwrap();
+        assert_eq!(retry.messages.len(), 3);
+        assert_eq!(retry.messages[1].role, "assistant");
+        match &retry.messages[2].content {
+            MessageContent::SingleText(text) => assert!(
+                text.starts_with("Arguments of the call of the `get_weather` tool"),
+                "{text}"
+            ),
+            content => panic!("Unexpected content {content:?}"),
+        }
+        assert_eq!(
+            retries.next(invalid, &tool_calls).unwrap().messages.len(),
+            5
+        );
+        // Out of retries, the last calls are kept
+        assert!(retries.next(invalid, &tool_calls).is_none());
+
+        let mut retries = ToolCallRetries::new(retry, 2);
+        let valid = r#"{"function": {"_name": "get_weather", "days": 7}}"#;
+        let (tool_calls, _) = parse_tool_calls(valid).unwrap();
+        assert!(retries.next(valid, &tool_calls.unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_tool_call_stream() {
+        let text = r#"{"function": [{"_name": "get_weather", "location": {"city": "Paris, FR"}, "days": 3}, {"unit": "C", "_name": "get_time"}]}"#;
+        assert_eq!(
+            arguments(&stream(text)),
+            [
+                (
+                    "get_weather".to_string(),
+                    r#"{"location":{"city":"Paris, FR"},"days":3}"#.to_string()
+                ),
+                ("get_time".to_string(), r#"{"unit":"C"}"#.to_string())
+            ]
+        );
+
+        let text = r#"{"function": {"_name": "no_tool", "content": "Say \"hi\"\n😀"}}"#;
+        let content: String = stream(text)
+            .into_iter()
+            .map(|delta| match delta {
+                ToolStreamDelta::Content(content) => content,
+                ToolStreamDelta::ToolCalls(_) => panic!("Unexpected tool call"),
+            })
+            .collect();
+        assert_eq!(content, "Say \"hi\"\n😀");
+    }
+}
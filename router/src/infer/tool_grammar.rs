@@ -21,6 +21,7 @@ impl ToolGrammar {
     pub fn apply(
         tools: Vec<Tool>,
         tool_choice: ToolChoice,
+        parallel_tool_calls: bool,
     ) -> Result<(Vec<Tool>, Option<JsonSchemaTool>), InferError> {
         // if no tools are provided, we return None
         if tools.is_empty() {
@@ -106,16 +107,27 @@ impl ToolGrammar {
             })
             .collect();
 
+        let function_ref = |tool: &Tool| FunctionRef::Ref {
+            ref_path: format!("#/$functions/{}", tool.function.name.clone()),
+        };
+        let mut function: Vec<FunctionRef> = tools_to_use.iter().map(function_ref).collect();
+        // The calls of a turn are either a single call or a list of calls of the tools
+        let calls: Vec<FunctionRef> = tools_to_use
+            .iter()
+            .filter(|tool| tool.function.name != "no_tool")
+            .map(function_ref)
+            .collect();
+        if parallel_tool_calls && !calls.is_empty() {
+            function.push(FunctionRef::Array {
+                kind: "array".to_string(),
+                items: json!({ "anyOf": calls }),
+                min_items: 1,
+            });
+        }
+
         let tool_schema = JsonSchemaTool {
             functions_map: FunctionsMap { functions },
-            properties: Properties {
-                function: tools_to_use
-                    .iter()
-                    .map(|tool| FunctionRef {
-                        ref_path: format!("#/$functions/{}", tool.function.name.clone()),
-                    })
-                    .collect(),
-            },
+            properties: Properties { function },
         };
 
         Ok((tools, Some(tool_schema)))
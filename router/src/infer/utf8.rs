@@ -0,0 +1,96 @@
+/// Reassemble UTF-8 characters split across streamed tokens
+///
+/// Byte-fallback tokenizers emit characters that are not in the vocabulary as one `<0xXX>`
+/// token per byte. Streaming the tokens one by one would send invalid UTF-8 to the clients,
+/// so incomplete sequences are buffered until the character is complete.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Push the text of the next token and return the complete characters
+    pub fn push(&mut self, text: String) -> String {
+        match fallback_byte(&text) {
+            // Nothing is buffered and `text` is valid UTF-8, so it is returned as is
+            None if self.pending.is_empty() => return text,
+            None => self.pending.extend_from_slice(text.as_bytes()),
+            Some(byte) => self.pending.push(byte),
+        }
+
+        let complete = self.pending.len() - incomplete_suffix(&self.pending);
+        let pending = self.pending.split_off(complete);
+        let complete = std::mem::replace(&mut self.pending, pending);
+        String::from_utf8_lossy(&complete).into_owned()
+    }
+
+    /// Return the buffered bytes, replacing incomplete characters
+    pub fn flush(&mut self) -> String {
+        let pending = std::mem::take(&mut self.pending);
+        String::from_utf8_lossy(&pending).into_owned()
+    }
+}
+
+/// Length of the trailing bytes that start a character without completing it
+fn incomplete_suffix(bytes: &[u8]) -> usize {
+    for i in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - i];
+        // Skip continuation bytes until the leading byte of the last character
+        if byte & 0xC0 != 0x80 {
+            let width = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if width > i { i } else { 0 };
+        }
+    }
+    0
+}
+
+/// Raw bytes of a token text, reading byte-fallback tokens such as `<0xE2>` as a single byte
+pub fn token_bytes(text: &str) -> Vec<u8> {
+    match fallback_byte(text) {
+        Some(byte) => vec![byte],
+        None => text.as_bytes().to_vec(),
+    }
+}
+
+fn fallback_byte(text: &str) -> Option<u8> {
+    text.strip_prefix("<0x")
+        .and_then(|hex| hex.strip_suffix('>'))
+        .filter(|hex| hex.len() == 2)
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bytes() {
+        assert_eq!(token_bytes("<0xE2>"), vec![0xE2]);
+        assert_eq!(token_bytes("<0x0A>"), vec![b'\n']);
+        assert_eq!(token_bytes("<0xZZ>"), b"<0xZZ>".to_vec());
+        assert_eq!(token_bytes("hello"), b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_utf8_decoder() {
+        let mut decoder = Utf8Decoder::default();
+        // "€" is 0xE2 0x82 0xAC
+        assert_eq!(decoder.push(" a".into()), " a");
+        assert_eq!(decoder.push("<0xE2>".into()), "");
+        assert_eq!(decoder.push("<0x82>".into()), "");
+        assert_eq!(decoder.push("<0xAC>".into()), "€");
+        assert_eq!(decoder.push("<0xE2>".into()), "");
+        assert_eq!(decoder.push("<0x82>".into()), "");
+        assert_eq!(decoder.push(" b".into()), "\u{FFFD} b");
+        // Invalid bytes do not swallow the start of the next character
+        assert_eq!(decoder.push("<0xFF>".into()), "\u{FFFD}");
+        assert_eq!(decoder.push("<0xE2>".into()), "");
+        assert_eq!(decoder.flush(), "\u{FFFD}");
+        assert_eq!(decoder.flush(), "");
+    }
+}
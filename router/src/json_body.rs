@@ -0,0 +1,142 @@
+/// JSON request bodies, with the location of the invalid values
+///
+/// axum rejects the malformed bodies with a plain text "Failed to deserialize the JSON body"
+/// message. The bodies are deserialized here with the path of the invalid value, which is
+/// returned as a JSON pointer in the `param` of the error, and the message names the expected
+/// type and the received value.
+use crate::ErrorResponse;
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Request};
+use axum::http::{header, StatusCode};
+use axum::Json;
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+use serde_path_to_error::{Path, Segment};
+
+/// Deserialize the JSON body of the request
+pub(crate) struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<ErrorResponse>);
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let json_content_type = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|mime| mime.trim().to_ascii_lowercase())
+            .is_some_and(|mime| {
+                mime == "application/json"
+                    || (mime.starts_with("application/") && mime.ends_with("+json"))
+            });
+        if !json_content_type {
+            return Err(rejection(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                "Expected request with `Content-Type: application/json`".to_string(),
+                None,
+            ));
+        }
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(|err| rejection(err.status(), "invalid_body", err.body_text(), None))?;
+        parse(&body).map(JsonBody)
+    }
+}
+
+pub(crate) fn parse<T: DeserializeOwned>(
+    body: &[u8],
+) -> Result<T, (StatusCode, Json<ErrorResponse>)> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|err| {
+        let pointer = pointer(err.path());
+        let err = err.into_inner();
+        let (status_code, code) = match err.classify() {
+            Category::Data => (StatusCode::UNPROCESSABLE_ENTITY, "invalid_body"),
+            Category::Syntax | Category::Eof | Category::Io => {
+                (StatusCode::BAD_REQUEST, "invalid_json")
+            }
+        };
+        let message = if pointer.is_empty() {
+            format!("Failed to deserialize the JSON body: {err}")
+        } else {
+            format!("Failed to deserialize the JSON body at `{pointer}`: {err}")
+        };
+        rejection(status_code, code, message, Some(pointer))
+    })?;
+    deserializer.end().map_err(|err| {
+        rejection(
+            StatusCode::BAD_REQUEST,
+            "invalid_json",
+            format!("Failed to parse the JSON body: {err}"),
+            None,
+        )
+    })?;
+    Ok(value)
+}
+
+/// JSON pointer (RFC 6901) of a deserialization path
+fn pointer(path: &Path) -> String {
+    let mut pointer = String::new();
+    for segment in path.iter() {
+        match segment {
+            Segment::Seq { index } => pointer.push_str(&format!("/{index}")),
+            Segment::Map { key } | Segment::Enum { variant: key } => {
+                pointer.push('/');
+                pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+            }
+            Segment::Unknown => {}
+        }
+    }
+    pointer
+}
+
+pub(crate) fn rejection(
+    status_code: StatusCode,
+    code: &str,
+    message: String,
+    pointer: Option<String>,
+) -> (StatusCode, Json<ErrorResponse>) {
+    let error = ErrorResponse::new(code, "validation", message)
+        .with_param(pointer.as_deref().filter(|pointer| !pointer.is_empty()));
+    (status_code, Json(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GenerateRequest;
+
+    fn error(body: &str) -> (StatusCode, String, Option<String>) {
+        let (status_code, Json(error)) =
+            parse::<GenerateRequest>(body.as_bytes()).expect_err("Invalid body");
+        (status_code, error.error.message, error.error.param)
+    }
+
+    #[test]
+    fn test_json_body_errors() {
+        let (status_code, message, param) =
+            error(r#"{"inputs": "Hi", "parameters": {"stop": ["a", 1]}}"#);
+        assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(param.as_deref(), Some("/parameters/stop/1"));
+        assert!(message.starts_with(
+            "Failed to deserialize the JSON body at `/parameters/stop/1`: invalid type: integer `1`, expected a string"
+        ));
+
+        let (status_code, message, param) =
+            error(r#"{"inputs": "Hi", "parameters": {"grammar": {"type": "json"}}}"#);
+        assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(param.as_deref(), Some("/parameters/grammar"));
+        assert!(message.contains("missing field `value`"));
+
+        let (status_code, _, _) = error(r#"{"inputs": "Hi""#);
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::infer::Infer;
+use crate::json_body::JsonBody;
 use crate::{
     default_parameters,
     server::{generate_internal, ComputeType},
@@ -71,7 +72,7 @@ pub struct MetadataServerResponse {
     responses(
         (status = 200, description = "Service is live", body = LiveReponse),
         (status = 404, description = "Service not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"code": "not_found", "type": "not_found", "message": "No response"}}))
     )
 )]
 pub async fn kserve_health_live() -> Json<LiveResponse> {
@@ -86,7 +87,7 @@ pub async fn kserve_health_live() -> Json<LiveResponse> {
     responses(
         (status = 200, description = "Service is ready", body = ReadyResponse),
         (status = 404, description = "Service not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"code": "not_found", "type": "not_found", "message": "No response"}}))
     )
 )]
 pub async fn kserve_health_ready() -> Json<ReadyResponse> {
@@ -101,7 +102,7 @@ pub async fn kserve_health_ready() -> Json<ReadyResponse> {
     responses(
         (status = 200, description = "Metadata retrieved", body = MetadataServerResponse),
         (status = 404, description = "Service not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"code": "not_found", "type": "not_found", "message": "No response"}}))
     )
 )]
 pub async fn kerve_server_metadata() -> Json<MetadataServerResponse> {
@@ -124,7 +125,7 @@ pub async fn kerve_server_metadata() -> Json<MetadataServerResponse> {
     responses(
         (status = 200, description = "Model version metadata retrieved", body = MetadataServerResponse),
         (status = 404, description = "Model or version not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"code": "not_found", "type": "not_found", "message": "No response"}}))
     )
 )]
 pub async fn kserve_model_metadata(
@@ -145,7 +146,7 @@ pub async fn kserve_model_metadata(
     responses(
         (status = 200, description = "Model version is ready", body = ReadyResponse),
         (status = 404, description = "Model or version not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"code": "not_found", "type": "not_found", "message": "No response"}}))
     )
 )]
 pub async fn kserve_model_metadata_ready(
@@ -163,13 +164,13 @@ pub async fn kserve_model_metadata_ready(
     responses(
         (status = 200, description = "Inference executed successfully", body = InferenceOutput),
         (status = 404, description = "Model or version not found", body = ErrorResponse,
-            example = json!({"error": "No response"}))
+            example = json!({"error": {"code": "not_found", "type": "not_found", "message": "No response"}}))
     )
 )]
 pub async fn kserve_model_infer(
     infer: Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
-    Json(payload): Json<InferenceRequest>,
+    JsonBody(payload): JsonBody<InferenceRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
     let id = payload.id.clone();
     let str_inputs = payload
@@ -179,10 +180,10 @@ pub async fn kserve_model_infer(
             std::str::from_utf8(&input.data).map_err(|e| {
                 (
                     StatusCode::UNPROCESSABLE_ENTITY,
-                    Json(ErrorResponse {
-                        error: e.to_string(),
-                        error_type: "utf8".to_string(),
-                    }),
+                    Json(
+                        ErrorResponse::new("invalid_utf8", "validation", e.to_string())
+                            .with_param(Some("inputs")),
+                    ),
                 )
             })
         })
@@ -191,10 +192,14 @@ pub async fn kserve_model_infer(
     if str_inputs.len() != payload.outputs.len() {
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: "Inputs and outputs length mismatch".to_string(),
-                error_type: "length mismatch".to_string(),
-            }),
+            Json(
+                ErrorResponse::new(
+                    "length_mismatch",
+                    "validation",
+                    "Inputs and outputs length mismatch",
+                )
+                .with_param(Some("outputs")),
+            ),
         ));
     }
 
@@ -205,6 +210,10 @@ pub async fn kserve_model_infer(
             let generate_request = GenerateRequest {
                 inputs: str_input.to_string(),
                 parameters: payload.parameters.clone(),
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
             };
             let infer = infer.clone();
             let compute_type = compute_type.clone();
@@ -221,15 +230,6 @@ pub async fn kserve_model_infer(
                             data: generation_as_bytes,
                         }
                     })
-                    .map_err(|_| {
-                        (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            Json(ErrorResponse {
-                                error: "Incomplete generation".into(),
-                                error_type: "Incomplete generation".into(),
-                            }),
-                        )
-                    })
             }
         })
         .collect::<FuturesUnordered<_>>()
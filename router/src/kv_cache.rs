@@ -0,0 +1,220 @@
+/// Block accounting of the paged KV cache of a backend
+///
+/// The backend reports its block size and the number of blocks of its cache, the scheduler
+/// then admits a request only if the blocks of its whole generation can be reserved, instead
+/// of estimating the memory from token counts.
+use crate::scheduler_events::{self, SchedulerEvent};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Frees KV cache blocks of running requests when a new request does not fit
+///
+/// Both hooks receive the number of missing blocks and return the number of blocks they
+/// freed, by dropping the [`BlockReservation`]s of the preempted requests.
+pub trait Preemption: Send + Sync {
+    /// Move the KV cache of running requests out of the device memory, the requests are
+    /// resumed later without recomputing it
+    fn swap_out(&self, _blocks: u32) -> u32 {
+        0
+    }
+
+    /// Stop running requests, their KV cache is recomputed when they are scheduled again
+    fn evict(&self, _blocks: u32) -> u32 {
+        0
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    free_blocks: u32,
+}
+
+#[derive(Clone)]
+pub struct BlockManager {
+    block_size: u32,
+    total_blocks: u32,
+    state: Arc<Mutex<State>>,
+    preemption: Option<Arc<dyn Preemption>>,
+}
+
+impl fmt::Debug for BlockManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockManager")
+            .field("block_size", &self.block_size)
+            .field("total_blocks", &self.total_blocks)
+            .field("free_blocks", &self.free_blocks())
+            .finish()
+    }
+}
+
+impl BlockManager {
+    pub fn new(block_size: u32, total_blocks: u32) -> Self {
+        metrics::gauge!("tgi_kv_cache_free_blocks").set(total_blocks as f64);
+        Self {
+            block_size: block_size.max(1),
+            total_blocks,
+            state: Arc::new(Mutex::new(State {
+                free_blocks: total_blocks,
+            })),
+            preemption: None,
+        }
+    }
+
+    /// Call `preemption` when a request does not fit in the free blocks
+    pub fn with_preemption(mut self, preemption: Arc<dyn Preemption>) -> Self {
+        self.preemption = Some(preemption);
+        self
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    pub fn total_blocks(&self) -> u32 {
+        self.total_blocks
+    }
+
+    pub fn free_blocks(&self) -> u32 {
+        self.state.lock().unwrap().free_blocks
+    }
+
+    /// Number of blocks holding `tokens` tokens
+    pub fn blocks(&self, tokens: u32) -> u32 {
+        tokens.div_ceil(self.block_size)
+    }
+
+    /// Reserve the blocks of `tokens` tokens, if they are free
+    pub fn allocate(&self, tokens: u32) -> Option<BlockReservation> {
+        let blocks = self.blocks(tokens);
+        self.take(blocks).then(|| BlockReservation {
+            manager: self.clone(),
+            blocks,
+        })
+    }
+
+    /// Reserve the blocks of `tokens` tokens, preempting running requests if they are not free
+    pub fn allocate_or_preempt(&self, tokens: u32) -> Option<BlockReservation> {
+        if let Some(reservation) = self.allocate(tokens) {
+            return Some(reservation);
+        }
+        let preemption = self.preemption.as_ref()?;
+        let blocks = self.blocks(tokens);
+        if blocks > self.total_blocks {
+            return None;
+        }
+        // The hooks release blocks through this manager, the lock must not be held
+        let missing = blocks.saturating_sub(self.free_blocks());
+        let swapped = preemption.swap_out(missing);
+        let evicted = if swapped < missing {
+            preemption.evict(missing - swapped)
+        } else {
+            0
+        };
+        scheduler_events::emit(SchedulerEvent::Preemption {
+            missing_blocks: missing,
+            swapped_blocks: swapped,
+            evicted_blocks: evicted,
+        });
+        self.allocate(tokens)
+    }
+
+    fn take(&self, blocks: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if blocks > state.free_blocks {
+            return false;
+        }
+        state.free_blocks -= blocks;
+        metrics::gauge!("tgi_kv_cache_free_blocks").set(state.free_blocks as f64);
+        true
+    }
+
+    fn release(&self, blocks: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.free_blocks = (state.free_blocks + blocks).min(self.total_blocks);
+        metrics::gauge!("tgi_kv_cache_free_blocks").set(state.free_blocks as f64);
+    }
+}
+
+/// Blocks reserved for a request, they are freed when it is dropped
+#[derive(Debug)]
+pub struct BlockReservation {
+    manager: BlockManager,
+    blocks: u32,
+}
+
+impl BlockReservation {
+    pub fn blocks(&self) -> u32 {
+        self.blocks
+    }
+
+    /// Extend the reservation to hold `tokens` tokens, returns false if the blocks are not free
+    pub fn grow(&mut self, tokens: u32) -> bool {
+        let blocks = self.manager.blocks(tokens);
+        if blocks <= self.blocks {
+            return true;
+        }
+        if !self.manager.take(blocks - self.blocks) {
+            return false;
+        }
+        self.blocks = blocks;
+        true
+    }
+}
+
+impl Drop for BlockReservation {
+    fn drop(&mut self) {
+        self.manager.release(self.blocks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_manager() {
+        let manager = BlockManager::new(16, 4);
+        let mut first = manager.allocate(17).unwrap();
+        assert_eq!(first.blocks(), 2);
+        assert_eq!(manager.free_blocks(), 2);
+
+        assert!(manager.allocate(33).is_none());
+        assert!(first.grow(48));
+        assert_eq!(manager.free_blocks(), 1);
+        assert!(!first.grow(80));
+
+        drop(first);
+        assert_eq!(manager.free_blocks(), 4);
+    }
+
+    #[test]
+    fn test_block_manager_preemption() {
+        struct Evict(Mutex<Vec<BlockReservation>>);
+
+        impl Preemption for Evict {
+            fn evict(&self, blocks: u32) -> u32 {
+                let mut running = self.0.lock().unwrap();
+                let mut freed = 0;
+                while freed < blocks {
+                    let Some(reservation) = running.pop() else {
+                        break;
+                    };
+                    freed += reservation.blocks();
+                }
+                freed
+            }
+        }
+
+        let manager = BlockManager::new(1, 4);
+        let running = vec![manager.allocate(2).unwrap(), manager.allocate(2).unwrap()];
+        let evict = Arc::new(Evict(Mutex::new(running)));
+        let manager = manager.with_preemption(evict.clone());
+
+        assert!(manager.allocate(2).is_none());
+        let reservation = manager.allocate_or_preempt(2).unwrap();
+        assert_eq!(reservation.blocks(), 2);
+        assert_eq!(evict.0.lock().unwrap().len(), 1);
+        // Larger than the cache
+        assert!(manager.allocate_or_preempt(5).is_none());
+    }
+}
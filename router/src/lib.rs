@@ -1,18 +1,38 @@
 /// Text Generation Inference Webserver
+mod chat_store;
+mod concurrency;
 pub mod config;
+mod generate_batch;
 pub mod infer;
+mod json_body;
 pub mod server;
 pub mod validation;
 
+#[cfg(feature = "grpc")]
+mod grpc;
 #[cfg(feature = "kserve")]
 mod kserve;
+pub mod kv_cache;
 pub mod logging;
+pub mod replay;
+pub mod router_config;
 
+mod prefix_pins;
+mod rag;
+mod requests;
 mod sagemaker;
+pub mod scheduler_events;
+mod stream_resume;
+mod tls;
+mod tokenizer_cache;
+mod tokenizer_formats;
+mod upload;
+mod usage;
 pub mod usage_stats;
 mod vertex;
 
-use crate::infer::{Infer, InferError};
+use crate::infer::{BackendCapabilities, Infer, InferError};
+use crate::router_config::{CompressionStrategy, ModelDefaults};
 use crate::server::prepare_chat_input;
 use pyo3::prelude::*;
 use pyo3::types::IntoPyDict;
@@ -20,7 +40,7 @@ use serde::{Deserialize, Serialize};
 use tokenizers::Encoding;
 use tracing::warn;
 use utoipa::ToSchema;
-use validation::Validation;
+use validation::{ValidParameters, Validation};
 
 #[derive(Clone)]
 pub enum Tokenizer {
@@ -68,7 +88,7 @@ impl TokenizerTrait for tokenizers::Tokenizer {
         query: String,
         add_special_tokens: bool,
     ) -> Result<tokenizers::Encoding, Box<dyn std::error::Error + Send + Sync>> {
-        self.encode(query, add_special_tokens)
+        self.encode_char_offsets(query, add_special_tokens)
     }
 }
 
@@ -202,9 +222,14 @@ pub(crate) enum GrammarType {
     /// JSON Schema is a declarative language that allows to annotate JSON documents
     /// with types and descriptions.
     #[serde(rename = "json")]
-    #[serde(alias = "json_object")]
     #[schema(example = json ! ({"properties": {"location":{"type": "string"}}}))]
     Json(serde_json::Value),
+    /// A JSON object, following the JSON Schema in `value` if set.
+    ///
+    /// The generation stops as soon as the object is closed, without trailing text.
+    #[serde(rename = "json_object")]
+    #[schema(example = json ! ({"properties": {"location":{"type": "string"}}}))]
+    JsonObject(Option<serde_json::Value>),
     #[serde(rename = "regex")]
     Regex(String),
 }
@@ -248,6 +273,88 @@ pub struct Info {
     pub sha: Option<&'static str>,
     #[schema(nullable = true, example = "null")]
     pub docker_label: Option<&'static str>,
+    /// Changes with the model revision and the version of the router and of its backend,
+    /// returned as the `system_fingerprint` of the OpenAI endpoints
+    #[schema(example = "fp_3b1e4c2a9f0d7e65")]
+    pub system_fingerprint: String,
+
+    /// Model shards, in rank order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shards: Vec<ShardInfo>,
+    /// Backend serving the requests
+    #[schema(example = "v3")]
+    pub backend: &'static str,
+    pub backend_metadata: BackendMetadata,
+    /// Features of the backend, the endpoints of the unsupported ones return 501
+    pub capabilities: BackendCapabilities,
+    /// Generation parameters applied when a request omits them, by model or adapter id
+    #[serde(skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub default_parameters: std::collections::HashMap<String, ModelDefaults>,
+}
+
+/// A model shard of a tensor parallel backend
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ShardInfo {
+    #[schema(example = "0")]
+    pub rank: u32,
+    #[schema(example = "cuda")]
+    pub device_type: String,
+    /// Total memory of the device in bytes
+    #[schema(nullable = true, example = "85899345920")]
+    pub device_memory: Option<u64>,
+}
+
+/// Build and configuration of a backend, reported in `/info` for bug reports
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct BackendMetadata {
+    /// Version of the inference engine wrapped by the backend, when it is not built with the
+    /// router, e.g. a llama.cpp commit
+    #[schema(nullable = true, example = "b3962")]
+    pub version: Option<String>,
+    #[schema(nullable = true, example = "awq")]
+    pub quantization: Option<String>,
+    /// Adapters loaded at startup or with `POST /admin/adapters`, selected with the
+    /// `adapter_id` of the requests
+    pub adapters: Vec<String>,
+    /// Limits of the batches after the warmup, for the backends batching the requests
+    #[schema(nullable = true)]
+    pub batching: Option<BatchingLimits>,
+}
+
+/// LoRA adapter loaded on demand, downloaded from the hub
+///
+/// Local directories are not accepted, they can only be set with `--lora-adapters`.
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LoadAdapterRequest {
+    /// Id selecting the adapter in the requests, and its hub repository
+    #[schema(example = "predibase/customer_support")]
+    pub adapter_id: String,
+    /// Hub revision of the adapter
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub revision: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct AdaptersResponse {
+    /// Adapters selectable by the requests, including the one just loaded
+    #[schema(example = json!(["predibase/customer_support"]))]
+    pub adapters: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct BatchingLimits {
+    #[schema(example = "4096")]
+    pub max_batch_prefill_tokens: u32,
+    #[schema(example = "32000")]
+    pub max_batch_total_tokens: u32,
+    #[schema(nullable = true, example = "null")]
+    pub max_batch_size: Option<usize>,
+    #[schema(example = "20")]
+    pub max_waiting_tokens: usize,
+    #[schema(example = "1.2")]
+    pub waiting_served_ratio: f32,
 }
 
 #[derive(Clone, Debug, Deserialize, ToSchema, Default)]
@@ -258,6 +365,11 @@ pub(crate) struct GenerateParameters {
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
     pub best_of: Option<usize>,
 
+    /// How the best_of sequences are ranked, by mean token logprob by default.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub best_of_strategy: Option<BestOfStrategy>,
+
     /// The value used to module the logits distribution.
     #[serde(default)]
     #[schema(
@@ -292,15 +404,15 @@ pub(crate) struct GenerateParameters {
     pub frequency_penalty: Option<f32>,
 
     /// The number of highest probability vocabulary tokens to keep for top-k-filtering.
+    /// Values above the vocabulary size are clamped to it.
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
     pub top_k: Option<i32>,
 
-    /// Top-p value for nucleus sampling.
+    /// Top-p value for nucleus sampling. Values above 1.0 are clamped to 1.0, which disables it.
     #[serde(default)]
     #[schema(
         exclusive_minimum = 0.0,
-        maximum = 1.0,
         nullable = true,
         default = "null",
         example = 0.95
@@ -325,7 +437,7 @@ pub(crate) struct GenerateParameters {
     pub do_sample: bool,
 
     /// Maximum number of tokens to generate.
-    #[serde(default = "default_max_new_tokens")]
+    #[serde(default)]
     #[schema(nullable = true, default = "100", example = "20")]
     pub max_new_tokens: Option<u32>,
 
@@ -379,19 +491,122 @@ pub(crate) struct GenerateParameters {
     #[schema(nullable = true, default = "null", example = "null")]
     pub grammar: Option<GrammarType>,
 
+    /// Tokens the generation is restricted to, as ids or as texts of single tokens of the
+    /// vocabulary. The end of sequence token is only generated if it is in the list.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json!(["positive", "negative"]))]
+    pub allowed_tokens: Option<Vec<VocabToken>>,
+
+    /// Tokens never generated, as ids or as texts of single tokens of the vocabulary.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub banned_tokens: Option<Vec<VocabToken>>,
+
     /// Lora adapter id
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
     pub adapter_id: Option<String>,
+
+    /// Name of a parameter preset defined by the server operator.
+    /// Parameters set on the request take precedence over the preset.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub preset: Option<String>,
+
+    /// Return the raw bytes of every token and leave the token texts untouched.
+    /// By default, characters split across several tokens are only sent once complete.
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub raw_bytes: bool,
+
+    /// Return the tokens generated before a generation error, with an `error` finish reason,
+    /// instead of only the error.
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub partial_on_error: bool,
+
+    /// Stream `queued` events with the position of the request in the queue and its estimated
+    /// wait, while it waits for a batch. Only used when streaming.
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub queue_position: bool,
+
+    /// Stop the generation with a `repetition` finish reason when it is stuck in a loop.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub stop_on_repetition: Option<RepetitionStop>,
+
+    /// Keep the KV cache of the prompt for the next requests of a conversation.
+    /// The response then has a `prefix_id` to reuse it.
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub pin: bool,
+
+    /// Id of a pinned prompt, the inputs must start with it. Its prefill is skipped and its
+    /// lifetime extended.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub prefix_id: Option<String>,
 }
 
 fn default_max_new_tokens() -> Option<u32> {
     Some(100)
 }
 
+/// Ranking of the best_of sequences, the sequence with the highest score is returned
+#[derive(Clone, Copy, Debug, Default, Deserialize, ToSchema, PartialEq)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub(crate) enum BestOfStrategy {
+    /// Mean logprob of the generated tokens.
+    #[default]
+    MeanLogprob,
+    /// Sum of the logprobs of the generated tokens, favors shorter sequences.
+    SumLogprob,
+    /// Sum of the logprobs divided by the number of tokens to the power `value`, as the length
+    /// penalty of beam search. 0 is the sum and 1 the mean of the logprobs.
+    #[schema(example = 1.0)]
+    LengthPenalty(f32),
+    /// Score of the rerank endpoint set by the server operator, given the inputs and the
+    /// generated text.
+    Rerank,
+}
+
+/// Loop detection on the generated tokens
+#[derive(Clone, Copy, Debug, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct RepetitionStop {
+    /// Number of tokens of the repeated sequence.
+    #[serde(default = "default_repetition_window")]
+    #[schema(exclusive_minimum = 0, default = "8", example = 8)]
+    pub window: u32,
+
+    /// Number of times the sequence is generated before the generation is stopped, at least 2.
+    #[serde(default = "default_repetition_repeats")]
+    #[schema(default = "3", example = 3)]
+    pub repeats: u32,
+}
+
+/// Token of the vocabulary, by id or by text
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum VocabToken {
+    #[schema(example = 42)]
+    Id(u32),
+    #[schema(example = "positive")]
+    Text(String),
+}
+
+fn default_repetition_window() -> u32 {
+    8
+}
+
+fn default_repetition_repeats() -> u32 {
+    3
+}
+
 fn default_parameters() -> GenerateParameters {
     GenerateParameters {
         best_of: None,
+        best_of_strategy: None,
         temperature: None,
         repetition_penalty: None,
         frequency_penalty: None,
@@ -409,7 +624,16 @@ fn default_parameters() -> GenerateParameters {
         seed: None,
         top_n_tokens: None,
         grammar: None,
+        allowed_tokens: None,
+        banned_tokens: None,
         adapter_id: None,
+        preset: None,
+        raw_bytes: false,
+        partial_on_error: false,
+        queue_position: false,
+        stop_on_repetition: None,
+        pin: false,
+        prefix_id: None,
     }
 }
 
@@ -446,9 +670,8 @@ impl TryFrom<PromptDeserializer> for Prompt {
 
 #[derive(Clone, Deserialize, Serialize, ToSchema, Debug)]
 pub struct CompletionRequest {
-    /// UNUSED
     #[schema(example = "mistralai/Mistral-7B-Instruct-v0.2")]
-    /// ID of the model to use. See the model endpoint compatibility table for details on which models work with the Chat API.
+    /// ID of the model to use, `base:adapter` selects the LoRA adapter `adapter` of the served model.
     pub model: Option<String>,
 
     /// The prompt to generate completions for.
@@ -478,8 +701,8 @@ pub struct CompletionRequest {
     #[schema(nullable = true, example = 42)]
     pub seed: Option<u64>,
 
-    /// The text to append to the prompt. This is useful for completing sentences or generating a paragraph of text.
-    /// please see the completion_template field in the model's tokenizer_config.json file for completion template.
+    /// The text following the completion, the prompt being the text before it. The prompt is
+    /// built with the fill-in-the-middle tokens of the model.
     #[serde(default)]
     pub suffix: Option<String>,
 
@@ -496,6 +719,12 @@ pub struct CompletionRequest {
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
     pub stop: Option<Vec<String>>,
+
+    /// LoRA adapter generating the completion, takes precedence over the adapter of a
+    /// `base:adapter` model
+    #[serde(default)]
+    #[schema(nullable = true, example = "predibase/customer_support")]
+    pub adapter_id: Option<String>,
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -546,6 +775,10 @@ pub(crate) struct ChatCompletion {
     pub system_fingerprint: String,
     pub choices: Vec<ChatCompletionComplete>,
     pub usage: Usage,
+    /// Compression of the prompt, when it was over the threshold of the router
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub compression: Option<PromptCompression>,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
@@ -634,6 +867,27 @@ pub(crate) struct Usage {
     pub total_tokens: u32,
 }
 
+impl Usage {
+    /// Usage of a chat completion, with the tokens of the summary generated to compress its
+    /// prompt
+    fn chat(
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        compression: Option<&PromptCompression>,
+    ) -> Self {
+        let (summary_prompt_tokens, summary_completion_tokens) = compression.map_or((0, 0), |c| {
+            (c.summary_prompt_tokens, c.summary_completion_tokens)
+        });
+        let prompt_tokens = prompt_tokens + summary_prompt_tokens;
+        let completion_tokens = completion_tokens + summary_completion_tokens;
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
 #[derive(Clone, Serialize, ToSchema)]
 #[serde(tag = "object")]
 enum CompletionType {
@@ -653,7 +907,58 @@ impl ChatCompletion {
         return_logprobs: bool,
         tool_calls: Option<Vec<ToolCall>>,
     ) -> Self {
-        let message = match (output, tool_calls) {
+        Self {
+            id: String::new(),
+            created,
+            model,
+            system_fingerprint,
+            choices: vec![ChatCompletionComplete {
+                index: 0,
+                message: Self::message(output, tool_calls),
+                logprobs: return_logprobs
+                    .then(|| ChatCompletionLogprobs::from((details.tokens, details.top_tokens))),
+                finish_reason: details.finish_reason.format(true),
+            }],
+            usage: Usage::chat(
+                details.prefill.len() as u32,
+                details.generated_tokens,
+                details.compression.as_ref(),
+            ),
+            compression: details.compression,
+        }
+    }
+
+    /// Completion of a streamed generation, without the log probabilities of its tokens
+    pub(crate) fn from_stream(
+        model: String,
+        system_fingerprint: String,
+        output: Option<String>,
+        created: u64,
+        details: &StreamDetails,
+        tool_calls: Option<Vec<ToolCall>>,
+    ) -> Self {
+        Self {
+            id: String::new(),
+            created,
+            model,
+            system_fingerprint,
+            choices: vec![ChatCompletionComplete {
+                index: 0,
+                message: Self::message(output, tool_calls),
+                logprobs: None,
+                finish_reason: details.finish_reason.format(true),
+            }],
+            usage: Usage::chat(
+                details.input_length,
+                details.generated_tokens,
+                details.compression.as_ref(),
+            ),
+            compression: details.compression.clone(),
+        }
+    }
+
+    fn message(output: Option<String>, tool_calls: Option<Vec<ToolCall>>) -> OutputMessage {
+        match (output, tool_calls) {
             (Some(content), None) => OutputMessage::ChatMessage(TextMessage {
                 role: "assistant".into(),
                 content,
@@ -676,24 +981,6 @@ impl ChatCompletion {
                     content: "".to_string(),
                 })
             }
-        };
-        Self {
-            id: String::new(),
-            created,
-            model,
-            system_fingerprint,
-            choices: vec![ChatCompletionComplete {
-                index: 0,
-                message,
-                logprobs: return_logprobs
-                    .then(|| ChatCompletionLogprobs::from((details.tokens, details.top_tokens))),
-                finish_reason: details.finish_reason.format(true),
-            }],
-            usage: Usage {
-                prompt_tokens: details.prefill.len() as u32,
-                completion_tokens: details.generated_tokens,
-                total_tokens: details.prefill.len() as u32 + details.generated_tokens,
-            },
         }
     }
 }
@@ -707,6 +994,11 @@ pub(crate) struct ChatCompletionChunk {
     pub system_fingerprint: String,
     pub choices: Vec<ChatCompletionChoice>,
     pub usage: Option<Usage>,
+    /// Compression of the prompt, on the last chunk when it was over the threshold of the
+    /// router
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub compression: Option<PromptCompression>,
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -721,7 +1013,7 @@ pub(crate) struct ChatCompletionChoice {
 pub struct ToolCallDelta {
     #[schema(example = "assistant")]
     role: String,
-    tool_calls: DeltaToolCall,
+    tool_calls: Vec<DeltaToolCall>,
 }
 
 #[derive(Clone, Debug, Serialize, ToSchema)]
@@ -734,14 +1026,21 @@ enum ChatCompletionDelta {
 #[derive(Clone, Deserialize, Serialize, ToSchema, Debug, PartialEq)]
 pub(crate) struct DeltaToolCall {
     pub index: u32,
-    pub id: String,
-    pub r#type: String,
+    /// Set in the first delta of the call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Set in the first delta of the call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
     pub function: Function,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema, Debug, PartialEq)]
 pub(crate) struct Function {
+    /// Set in the first delta of the call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    /// Part of the JSON arguments of the call
     pub arguments: String,
 }
 
@@ -751,7 +1050,7 @@ impl ChatCompletionChunk {
         model: String,
         system_fingerprint: String,
         delta: Option<String>,
-        tool_calls: Option<Vec<String>>,
+        tool_calls: Option<Vec<DeltaToolCall>>,
         created: u64,
         logprobs: Option<ChatCompletionLogprobs>,
         finish_reason: Option<String>,
@@ -764,15 +1063,7 @@ impl ChatCompletionChunk {
             }),
             (None, Some(tool_calls)) => ChatCompletionDelta::Tool(ToolCallDelta {
                 role: "assistant".to_string(),
-                tool_calls: DeltaToolCall {
-                    index: 0,
-                    id: String::new(),
-                    r#type: "function".to_string(),
-                    function: Function {
-                        name: None,
-                        arguments: tool_calls[0].to_string(),
-                    },
-                },
+                tool_calls,
             }),
             (None, None) => ChatCompletionDelta::Chat(TextMessage {
                 role: "assistant".to_string(),
@@ -791,6 +1082,7 @@ impl ChatCompletionChunk {
                 finish_reason,
             }],
             usage,
+            compression: None,
         }
     }
 }
@@ -799,7 +1091,7 @@ impl ChatCompletionChunk {
 #[cfg_attr(test, derive(Debug, PartialEq, Default))]
 pub(crate) struct ChatRequest {
     #[schema(example = "mistralai/Mistral-7B-Instruct-v0.2")]
-    /// [UNUSED] ID of the model to use. See the model endpoint compatibility table for details on which models work with the Chat API.
+    /// ID of the model to use, `base:adapter` selects the LoRA adapter `adapter` of the served model.
     pub model: Option<String>,
 
     /// A list of messages comprising the conversation so far.
@@ -834,10 +1126,19 @@ pub(crate) struct ChatRequest {
     pub top_logprobs: Option<u32>,
 
     /// The maximum number of tokens that can be generated in the chat completion.
+    ///
+    /// Deprecated alias of `max_completion_tokens`, ignored when `max_completion_tokens` is set.
     #[serde(default)]
     #[schema(example = "32")]
     pub max_tokens: Option<u32>,
 
+    /// The maximum number of tokens that can be generated in the chat completion, takes
+    /// precedence over `max_tokens`. The prompt tokens plus this limit must fit in the context of
+    /// the model, the request fails with `context_length_exceeded` otherwise.
+    #[serde(default)]
+    #[schema(nullable = true, example = "32")]
+    pub max_completion_tokens: Option<u32>,
+
     /// UNUSED
     /// How many chat completion choices to generate for each input message. Note that you will be charged based on the
     /// number of generated tokens across all of the choices. Keep n as 1 to minimize costs.
@@ -895,6 +1196,12 @@ pub(crate) struct ChatRequest {
     #[schema(nullable = true, example = "null")]
     pub tool_choice: ToolChoice,
 
+    /// Allow the model to call several tools in one turn, the calls are returned in order in
+    /// `tool_calls`
+    #[serde(default)]
+    #[schema(default = false, example = true)]
+    pub parallel_tool_calls: bool,
+
     /// Response format constraints for the generation.
     ///
     /// NOTE: A request can use `response_format` OR `tools` but not both.
@@ -911,6 +1218,43 @@ pub(crate) struct ChatRequest {
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
     pub stream_options: Option<StreamOptions>,
+
+    /// Keep the completed conversation, retrievable with `GET /v1/chat/completions/{id}` where
+    /// `id` is the `x-request-id` header of the response. Ignored unless the chat store is
+    /// enabled.
+    #[serde(default)]
+    #[schema(default = false, example = false)]
+    pub store: bool,
+
+    /// Key-value pairs recorded in the logs and traces of the request, and returned with the
+    /// stored conversation
+    #[serde(default)]
+    #[schema(nullable = true, example = json!({"session": "4f2a9c"}))]
+    pub metadata: Option<std::collections::HashMap<String, String>>,
+
+    /// Identifier of the end user, recorded in the logs and traces of the request
+    #[serde(default)]
+    #[schema(nullable = true, example = "user-1234")]
+    pub user: Option<String>,
+
+    /// LoRA adapter generating the answer, takes precedence over the adapter of a
+    /// `base:adapter` model
+    #[serde(default)]
+    #[schema(nullable = true, example = "predibase/customer_support")]
+    pub adapter_id: Option<String>,
+}
+
+/// Adapter selected by an OpenAI request: its `adapter_id`, else the `adapter` of a
+/// `base:adapter` model, else the model itself, which the backends serve with the base model
+/// when it is not one of their adapters
+pub(crate) fn requested_adapter(model: Option<&str>, adapter_id: Option<String>) -> Option<String> {
+    adapter_id.or_else(|| {
+        let model = model.filter(|model| *model != "tgi")?;
+        match model.split_once(':') {
+            Some((_, adapter)) => (!adapter.is_empty()).then(|| adapter.to_string()),
+            None => Some(model.to_string()),
+        }
+    })
 }
 
 impl ChatRequest {
@@ -918,6 +1262,7 @@ impl ChatRequest {
         let ChatRequest {
             model,
             max_tokens,
+            max_completion_tokens,
             messages,
             seed,
             stop,
@@ -925,6 +1270,7 @@ impl ChatRequest {
             tools,
             tool_choice,
             tool_prompt,
+            parallel_tool_calls,
             temperature,
             response_format,
             guideline,
@@ -932,11 +1278,13 @@ impl ChatRequest {
             frequency_penalty,
             top_p,
             top_logprobs,
+            adapter_id,
+            stream_options,
             ..
         } = self;
 
         let repetition_penalty = presence_penalty.map(|x| x + 2.0);
-        let max_new_tokens = max_tokens.or(Some(100));
+        let max_new_tokens = max_completion_tokens.or(max_tokens);
         let tool_prompt = tool_prompt
             .filter(|s| !s.is_empty())
             .unwrap_or_else(default_tool_prompt);
@@ -952,6 +1300,7 @@ impl ChatRequest {
             tools,
             tool_choice,
             &tool_prompt,
+            parallel_tool_calls,
             guideline,
             messages,
         )?;
@@ -960,8 +1309,14 @@ impl ChatRequest {
             GenerateRequest {
                 inputs: inputs.to_string(),
                 add_special_tokens: false,
+                endpoint: Endpoint::Chat,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
                     best_of: None,
+                    best_of_strategy: None,
                     temperature,
                     repetition_penalty,
                     frequency_penalty,
@@ -979,7 +1334,17 @@ impl ChatRequest {
                     seed,
                     top_n_tokens: top_logprobs,
                     grammar,
-                    adapter_id: model.filter(|m| *m != "tgi").map(String::from),
+                    allowed_tokens: None,
+                    banned_tokens: None,
+                    adapter_id: requested_adapter(model.as_deref(), adapter_id),
+                    preset: None,
+                    raw_bytes: false,
+                    partial_on_error: false,
+                    queue_position: stream_options
+                        .is_some_and(|stream_options| stream_options.include_queue_position),
+                    stop_on_repetition: None,
+                    pin: false,
+                    prefix_id: None,
                 },
             },
             using_tools,
@@ -993,6 +1358,11 @@ struct StreamOptions {
     /// If set, an additional chunk will be streamed before the data: [DONE] message. The usage field on this chunk shows the token usage statistics for the entire request, and the choices field will always be an empty array. All other chunks will also include a usage field, but with a null value.
     #[schema(example = "true")]
     include_usage: bool,
+    /// If set, `queued` events with the position of the request in the queue and its estimated
+    /// wait are streamed while it waits for a batch.
+    #[serde(default)]
+    #[schema(default = "false", example = "true")]
+    include_queue_position: bool,
 }
 
 pub fn default_tool_prompt() -> String {
@@ -1059,9 +1429,20 @@ struct FunctionsMap {
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
-struct FunctionRef {
-    #[serde(rename = "$ref")]
-    ref_path: String,
+#[serde(untagged)]
+enum FunctionRef {
+    Ref {
+        #[serde(rename = "$ref")]
+        ref_path: String,
+    },
+    /// List of calls of the functions, for parallel tool calls
+    Array {
+        #[serde(rename = "type")]
+        kind: String,
+        items: serde_json::Value,
+        #[serde(rename = "minItems")]
+        min_items: usize,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -1138,6 +1519,11 @@ pub struct Message {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     #[schema(example = "\"David\"")]
     name: Option<String>,
+    /// Continue this message instead of starting a new one, only the last message can be a
+    /// prefix and it must be from the `assistant`. The prefix is not part of the completion.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[schema(default = false, example = false)]
+    pub prefix: bool,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema, Debug, PartialEq)]
@@ -1162,6 +1548,20 @@ impl MessageContent {
             }
         }
     }
+
+    /// Texts of the message, without its images
+    pub(crate) fn texts_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            MessageContent::SingleText(text) => vec![text],
+            MessageContent::MultipleChunks(chunks) => chunks
+                .iter_mut()
+                .filter_map(|chunk| match chunk {
+                    MessageChunk::Text { text } => Some(text),
+                    MessageChunk::ImageUrl { .. } => None,
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, ToSchema, Serialize, Debug, PartialEq)]
@@ -1207,29 +1607,82 @@ pub(crate) enum OutputMessage {
 
 #[derive(Clone, Debug, Deserialize, ToSchema)]
 pub(crate) struct GenerateRequest {
+    #[serde(default)]
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
 
+    /// Name of a server-side prompt template rendered into `inputs`.
+    /// The template can use `inputs` and the request `variables`.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub template: Option<String>,
+
+    /// Variables used to render `template`
+    #[serde(default)]
+    #[schema(value_type = Option<Object>, nullable = true, default = "null", example = json!({"language": "French"}))]
+    pub variables: Option<std::collections::HashMap<String, serde_json::Value>>,
+
+    /// Pre-tokenized input, used instead of `inputs`.
+    /// The ids must include the special tokens expected by the model.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub inputs_ids: Option<Vec<u32>>,
+
+    /// Text following the generated text, `inputs` being the text before it. The prompt is
+    /// built with the fill-in-the-middle tokens of the model.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub suffix: Option<String>,
+
     /// This is used internally because some requests
     /// already contain the templated input therefore
     /// we shouldn't add the special tokens.
     #[serde(default = "default_true", skip)]
     pub add_special_tokens: bool,
+
+    /// Endpoint of the request, which selects its limit on the input tokens
+    #[serde(skip)]
+    pub endpoint: Endpoint,
 }
 
 fn default_true() -> bool {
     true
 }
 
+/// Endpoints with their own limit on the input tokens, under `input_limits` in the router
+/// configuration
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Endpoint {
+    /// The generate endpoints, and the ones converted to generate requests but the chat ones
+    #[default]
+    Generate,
+    /// Chat completions, whose inputs include the chat template
+    Chat,
+    Embeddings,
+}
+
 #[derive(Clone, Debug, Deserialize, ToSchema)]
 pub(crate) struct CompatGenerateRequest {
+    #[serde(default)]
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
     #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub template: Option<String>,
+    #[serde(default)]
+    #[schema(value_type = Option<Object>, nullable = true, default = "null", example = json!({"language": "French"}))]
+    pub variables: Option<std::collections::HashMap<String, serde_json::Value>>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub inputs_ids: Option<Vec<u32>>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub suffix: Option<String>,
+    #[serde(default)]
     #[schema(default = "false")]
     pub stream: bool,
 }
@@ -1239,7 +1692,12 @@ impl From<CompatGenerateRequest> for GenerateRequest {
         Self {
             inputs: req.inputs,
             add_special_tokens: true,
+            endpoint: Endpoint::Generate,
             parameters: req.parameters,
+            template: req.template,
+            variables: req.variables,
+            inputs_ids: req.inputs_ids,
+            suffix: req.suffix,
         }
     }
 }
@@ -1252,6 +1710,13 @@ pub struct PrefillToken {
     pub text: String,
     #[schema(nullable = true, example = - 0.34)]
     pub logprob: f32,
+    /// Character offsets of the token in the inputs, if the inputs are text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0)]
+    pub start: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 2)]
+    pub stop: Option<usize>,
 }
 
 #[derive(Debug, Serialize, ToSchema, Clone)]
@@ -1264,6 +1729,10 @@ pub struct Token {
     pub logprob: f32,
     #[schema(example = "false")]
     pub special: bool,
+    /// Raw bytes of the token, only returned when `raw_bytes` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json!([226, 130, 172]))]
+    pub bytes: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -1289,6 +1758,10 @@ pub enum FinishReason {
     EndOfSequenceToken,
     #[schema(rename = "stop_sequence")]
     StopSequence,
+    #[schema(rename = "error")]
+    Error,
+    #[schema(rename = "repetition")]
+    Repetition,
 }
 
 impl std::fmt::Display for FinishReason {
@@ -1297,6 +1770,8 @@ impl std::fmt::Display for FinishReason {
             FinishReason::Length => write!(f, "length"),
             FinishReason::EndOfSequenceToken => write!(f, "eos_token"),
             FinishReason::StopSequence => write!(f, "stop_sequence"),
+            FinishReason::Error => write!(f, "error"),
+            FinishReason::Repetition => write!(f, "repetition"),
         }
     }
 }
@@ -1340,6 +1815,93 @@ pub(crate) struct Details {
     pub best_of_sequences: Option<Vec<BestOfSequence>>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub top_tokens: Vec<Vec<Token>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "v3")]
+    pub backend: Option<String>,
+    /// Forward passes of the generation, the prefill included, when the backend counts them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 1)]
+    pub steps: Option<u32>,
+    /// Generated tokens over one per step, accepted from the speculation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0)]
+    pub speculative_tokens_accepted: Option<u32>,
+    /// Compression of the prompt, when it was over the threshold of the router
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub compression: Option<PromptCompression>,
+    pub effective_parameters: EffectiveParameters,
+}
+
+/// Changes of a prompt compressed by the router, as it was over the `prompt_compression`
+/// threshold
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, ToSchema)]
+pub(crate) struct PromptCompression {
+    /// Tokens of the prompt before the compression
+    #[schema(example = 6144)]
+    pub input_tokens: u32,
+    /// Tokens of the prompt after the compression
+    #[schema(example = 3968)]
+    pub compressed_tokens: u32,
+    /// Strategies that changed the prompt, in the order they were applied
+    pub strategies: Vec<CompressionStrategy>,
+    /// Repeated few-shot examples removed from the prompt
+    #[schema(example = 2)]
+    pub removed_examples: u32,
+    /// Older chat messages replaced by their summary
+    #[schema(example = 0)]
+    pub summarized_turns: u32,
+    /// Input tokens of the generation of the summary, included in the usage of the response
+    #[schema(example = 0)]
+    pub summary_prompt_tokens: u32,
+    /// Tokens of the summary, included in the usage of the response
+    #[schema(example = 0)]
+    pub summary_completion_tokens: u32,
+}
+
+/// Sampling parameters the generation ran with, after the defaults and the clamping of the
+/// validation
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct EffectiveParameters {
+    /// The logits are sampled, false for a greedy generation
+    #[schema(example = true)]
+    pub do_sample: bool,
+    /// 1.0 when the logits are not scaled
+    #[schema(example = 0.5)]
+    pub temperature: f32,
+    /// Null when the logits are not restricted to the k most likely tokens
+    #[schema(nullable = true, example = 10)]
+    pub top_k: Option<u32>,
+    /// 1.0 when nucleus sampling is disabled
+    #[schema(example = 0.95)]
+    pub top_p: f32,
+    /// 1.0 when typical decoding is disabled
+    #[schema(example = 1.0)]
+    pub typical_p: f32,
+    #[schema(example = 1.03)]
+    pub repetition_penalty: f32,
+    #[schema(example = 0.0)]
+    pub frequency_penalty: f32,
+}
+
+impl From<&ValidParameters> for EffectiveParameters {
+    fn from(parameters: &ValidParameters) -> Self {
+        // The shards sample as soon as a logits warper is enabled
+        let do_sample = parameters.do_sample
+            || parameters.temperature != 1.0
+            || parameters.top_k != 0
+            || parameters.top_p < 1.0
+            || parameters.typical_p < 1.0;
+        Self {
+            do_sample,
+            temperature: parameters.temperature,
+            top_k: (parameters.top_k != 0).then_some(parameters.top_k),
+            top_p: parameters.top_p,
+            typical_p: parameters.typical_p,
+            repetition_penalty: parameters.repetition_penalty,
+            frequency_penalty: parameters.frequency_penalty,
+        }
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1348,6 +1910,114 @@ pub(crate) struct GenerateResponse {
     pub generated_text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub details: Option<Details>,
+    /// Error that interrupted the generation, only returned with `partial_on_error`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorDetails>,
+    /// Id of the pinned prompt, only returned with `pin`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "pfx_5f0e6c1b2a7d4e98")]
+    pub prefix_id: Option<String>,
+}
+
+#[derive(Clone, Deserialize, ToSchema)]
+pub(crate) struct ChatTemplateRenderRequest {
+    /// A list of messages comprising the conversation so far.
+    #[schema(example = "[{\"role\": \"user\", \"content\": \"What is Deep Learning?\"}]")]
+    pub messages: Vec<Message>,
+    /// Tools rendered by the chat template, as in the chat completions
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub tools: Option<Vec<Tool>>,
+    /// A prompt to be appended before the tools
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub tool_prompt: Option<String>,
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub tool_choice: ToolChoice,
+    /// A guideline to be used in the chat_template
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub guideline: Option<String>,
+    /// End the prompt with the start of the assistant turn, as for the chat completions
+    #[serde(default = "default_true")]
+    #[schema(default = true, example = true)]
+    pub add_generation_prompt: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatTemplateRenderResponse {
+    /// Prompt rendered by the chat template
+    #[schema(example = "<|user|>What is Deep Learning?<|assistant|>")]
+    pub prompt: String,
+    /// Number of tokens of the prompt
+    #[schema(example = 12)]
+    pub tokens: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct ScoreRequest {
+    #[schema(example = "My name is Olivier and I")]
+    pub inputs: String,
+    /// Keep the last `truncate` tokens of the inputs
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub truncate: Option<usize>,
+    #[serde(default = "default_true")]
+    #[schema(default = true, example = true)]
+    pub add_special_tokens: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ScoreResponse {
+    /// Log probability of the inputs, the sum of the log probabilities of their tokens
+    #[schema(example = -12.5)]
+    pub logprob: f32,
+    /// Tokens of the inputs, the first one has no log probability
+    pub tokens: Vec<PrefillToken>,
+}
+
+#[derive(Clone, Deserialize, ToSchema, Debug)]
+pub struct EmbeddingRequest {
+    /// UNUSED
+    #[schema(example = "mistralai/Mistral-7B-Instruct-v0.2")]
+    /// ID of the model to use.
+    pub model: Option<String>,
+    /// The texts to embed.
+    #[schema(example = "What is Deep Learning?")]
+    pub input: Prompt,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbeddingResponse {
+    #[schema(example = "list")]
+    pub object: &'static str,
+    pub data: Vec<Embedding>,
+    #[schema(example = "mistralai/Mistral-7B-Instruct-v0.2")]
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Embedding {
+    #[schema(example = "embedding")]
+    pub object: &'static str,
+    #[schema(example = json!([0.0023, -0.0091]))]
+    pub embedding: Vec<f32>,
+    /// Position of the text in `input`
+    #[schema(example = 0)]
+    pub index: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbeddingUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1370,6 +2040,22 @@ pub(crate) struct StreamDetails {
     pub seed: Option<u64>,
     #[schema(example = 1)]
     pub input_length: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "v3")]
+    pub backend: Option<String>,
+    /// Forward passes of the generation, the prefill included, when the backend counts them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 1)]
+    pub steps: Option<u32>,
+    /// Generated tokens over one per step, accepted from the speculation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0)]
+    pub speculative_tokens_accepted: Option<u32>,
+    /// Compression of the prompt, when it was over the threshold of the router
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub compression: Option<PromptCompression>,
+    pub effective_parameters: EffectiveParameters,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1382,12 +2068,87 @@ pub(crate) struct StreamResponse {
     pub generated_text: Option<String>,
     #[schema(nullable = true, default = "null")]
     pub details: Option<StreamDetails>,
+    /// Generation budget after this token, only returned when `details` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, default = "null")]
+    pub budget: Option<StreamBudget>,
+    /// Seed of the sampling, only returned with the first token of sampled generations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, default = "null", example = 42)]
+    pub seed: Option<u64>,
+    /// Id of the pinned prompt, only returned with the last token of the requests with `pin`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, default = "null", example = "pfx_5f0e6c1b2a7d4e98")]
+    pub prefix_id: Option<String>,
+}
+
+/// Position of a request waiting for a batch, streamed as `queued` events
+#[derive(Serialize, ToSchema)]
+pub(crate) struct QueueUpdate {
+    /// Position in the queue, 1 for the next request batched
+    #[schema(example = 7)]
+    pub position: usize,
+    /// Estimated time before the first token in milliseconds, none until the backend measured
+    /// its latencies
+    #[schema(nullable = true, example = 1200)]
+    pub eta_ms: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct StreamBudget {
+    /// Tokens generated so far
+    #[schema(example = 1)]
+    pub generated_tokens: u32,
+    /// Tokens left before reaching `max_new_tokens`
+    #[schema(example = 19)]
+    pub remaining_tokens: u32,
 }
 
 #[derive(Serialize, ToSchema)]
 pub(crate) struct ErrorResponse {
-    pub error: String,
+    pub error: ErrorDetails,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ErrorDetails {
+    /// Stable machine-readable code of the error
+    #[schema(example = "input_too_long")]
+    pub code: String,
+    /// Category of the error
+    #[serde(rename = "type")]
+    #[schema(example = "validation")]
     pub error_type: String,
+    #[schema(
+        example = "Input validation error: `inputs` must have less than 1024 tokens, the `max_input_tokens` limit. Given: 2048"
+    )]
+    pub message: String,
+    /// Request parameter that failed validation
+    #[schema(nullable = true, default = "null", example = "inputs")]
+    pub param: Option<String>,
+    /// Estimated time to first token in milliseconds, when the request was rejected for
+    /// exceeding the latency objective of the backend
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, default = "null", example = 2500)]
+    pub estimated_ttft_ms: Option<u64>,
+}
+
+impl ErrorResponse {
+    pub(crate) fn new(code: &str, error_type: &str, message: impl Into<String>) -> Self {
+        Self {
+            error: ErrorDetails {
+                code: code.to_string(),
+                error_type: error_type.to_string(),
+                message: message.into(),
+                param: None,
+                estimated_ttft_ms: None,
+            },
+        }
+    }
+
+    pub(crate) fn with_param(mut self, param: Option<&str>) -> Self {
+        self.error.param = param.map(String::from);
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -1430,6 +2191,23 @@ mod tests {
         Tokenizer::Rust(tokenizers::Tokenizer::from_file(filename).unwrap())
     }
 
+    /// Tokenizer of whitespace separated words, `[UNK]` has the id 0 and the words follow
+    pub(crate) fn get_word_level_tokenizer(words: &[&str]) -> Tokenizer {
+        let vocab = std::iter::once("[UNK]")
+            .chain(words.iter().copied())
+            .zip(0..)
+            .map(|(token, id)| (token.to_string(), id))
+            .collect();
+        let model = tokenizers::models::wordlevel::WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(tokenizers::pre_tokenizers::whitespace::Whitespace));
+        Tokenizer::Rust(tokenizer)
+    }
+
     #[test]
     fn test_hub_nested_tokens_tokenizer_config() {
         // this is a subset of the tokenizer.json file
@@ -1519,7 +2297,8 @@ mod tests {
             Message {
                 role: "user".to_string(),
                 content: MessageContent::SingleText("What is Deep Learning?".to_string()),
-                name: None
+                name: None,
+                prefix: false,
             }
         );
     }
@@ -1575,11 +2354,52 @@ mod tests {
                     MessageChunk::Text { text: "Whats in this image?".to_string() },
                     MessageChunk::ImageUrl { image_url: Url { url: "https://huggingface.co/datasets/huggingface/documentation-images/resolve/main/transformers/rabbit.png".to_string() }},
                 ]),
-                name: None
+                name: None,
+                prefix: false,
             }
         );
     }
 
+    #[test]
+    fn test_requested_adapter() {
+        let adapter = |model, adapter_id: Option<&str>| {
+            requested_adapter(model, adapter_id.map(String::from))
+        };
+        assert_eq!(adapter(None, None), None);
+        assert_eq!(adapter(Some("tgi"), None), None);
+        assert_eq!(
+            adapter(Some("meta-llama/Llama-3.1-8B:customer-support"), None),
+            Some("customer-support".to_string())
+        );
+        assert_eq!(adapter(Some("meta-llama/Llama-3.1-8B:"), None), None);
+        assert_eq!(
+            adapter(Some("customer-support"), None),
+            Some("customer-support".to_string())
+        );
+        assert_eq!(
+            adapter(Some("tgi:customer-support"), Some("legal")),
+            Some("legal".to_string())
+        );
+    }
+
+    #[test]
+    fn test_grammar_type() {
+        let grammar =
+            |json: serde_json::Value| serde_json::from_value::<GrammarType>(json).unwrap();
+        assert_eq!(
+            grammar(json!({"type": "json_object"})),
+            GrammarType::JsonObject(None)
+        );
+        assert_eq!(
+            grammar(json!({"type": "json_object", "value": {"type": "object"}})),
+            GrammarType::JsonObject(Some(json!({"type": "object"})))
+        );
+        assert_eq!(
+            grammar(json!({"type": "json", "value": {"type": "object"}})),
+            GrammarType::Json(json!({"type": "object"}))
+        );
+    }
+
     #[test]
     fn text_message_convert() {
         let message = Message{
@@ -1588,7 +2408,8 @@ mod tests {
                     MessageChunk::Text { text: "Whats in this image?".to_string() },
                     MessageChunk::ImageUrl { image_url: Url { url: "https://huggingface.co/datasets/huggingface/documentation-images/resolve/main/transformers/rabbit.png".to_string() } }
                 ]),
-                name: None
+                name: None,
+                prefix: false,
             };
         let textmsg: TextMessage = message.into();
         assert_eq!(textmsg.content, "Whats in this image?![](https://huggingface.co/datasets/huggingface/documentation-images/resolve/main/transformers/rabbit.png)");
@@ -1609,7 +2430,8 @@ mod tests {
         assert!(matches!(
             request.stream_options,
             Some(StreamOptions {
-                include_usage: true
+                include_usage: true,
+                include_queue_position: false,
             })
         ));
     }
@@ -1646,4 +2468,220 @@ mod tests {
             r#"{"role":"assistant","tool_calls":[{"id":"0","type":"function","function":{"description":null,"name":"myfn","arguments":{"format":"csv"}}}]}"#
         );
     }
+
+    fn effective_parameters() -> EffectiveParameters {
+        EffectiveParameters {
+            do_sample: false,
+            temperature: 1.0,
+            top_k: None,
+            top_p: 1.0,
+            typical_p: 1.0,
+            repetition_penalty: 1.0,
+            frequency_penalty: 0.0,
+        }
+    }
+
+    fn details() -> Details {
+        Details {
+            finish_reason: FinishReason::EndOfSequenceToken,
+            generated_tokens: 1,
+            seed: None,
+            prefill: vec![PrefillToken {
+                id: 0,
+                text: "<s>".to_string(),
+                logprob: f32::NAN,
+                start: None,
+                stop: None,
+            }],
+            tokens: vec![Token {
+                id: 1,
+                text: "Hi".to_string(),
+                logprob: f32::NAN,
+                special: false,
+                bytes: None,
+            }],
+            best_of_sequences: None,
+            top_tokens: vec![],
+            backend: None,
+            steps: None,
+            speculative_tokens_accepted: None,
+            compression: None,
+            effective_parameters: effective_parameters(),
+        }
+    }
+
+    /// The types of the Rust client must stay compatible with the ones of the server
+    #[test]
+    fn test_client_types() {
+        use text_generation_router_client as client;
+
+        fn convert<T: serde::de::DeserializeOwned>(value: impl Serialize) -> T {
+            serde_json::from_value(serde_json::to_value(value).unwrap()).unwrap()
+        }
+
+        let mut request = client::GenerateRequest::new("Hello");
+        request.parameters = client::GenerateParameters {
+            best_of: Some(2),
+            temperature: Some(0.5),
+            top_k: Some(10),
+            do_sample: true,
+            max_new_tokens: Some(5),
+            stop: vec!["\n".to_string()],
+            details: true,
+            seed: Some(3),
+            grammar: Some(client::GrammarType::Regex("a+".to_string())),
+            preset: Some("creative".to_string()),
+            ..Default::default()
+        };
+        let parsed: GenerateRequest = convert(&request);
+        assert_eq!(parsed.inputs, "Hello");
+        assert_eq!(parsed.parameters.best_of, Some(2));
+        assert_eq!(parsed.parameters.max_new_tokens, Some(5));
+        assert_eq!(
+            parsed.parameters.grammar,
+            Some(GrammarType::Regex("a+".to_string()))
+        );
+        assert_eq!(parsed.parameters.preset.as_deref(), Some("creative"));
+
+        let request = client::ChatRequest {
+            max_completion_tokens: Some(5),
+            stop: Some(vec!["\n".to_string()]),
+            tools: Some(json!([{"type": "function", "function": {"name": "f", "arguments": {}}}])),
+            store: true,
+            user: Some("user-1".to_string()),
+            ..client::ChatRequest::new(vec![
+                client::Message::new("system", "Be brief."),
+                client::Message {
+                    role: "user".to_string(),
+                    content: client::MessageContent::MultipleChunks(vec![
+                        client::MessageChunk::Text {
+                            text: "What is this?".to_string(),
+                        },
+                        client::MessageChunk::ImageUrl {
+                            image_url: client::Url {
+                                url: "https://example.com/image.png".to_string(),
+                            },
+                        },
+                    ]),
+                    name: None,
+                },
+            ])
+        };
+        let parsed: ChatRequest = convert(&request);
+        assert_eq!(parsed.messages.len(), 2);
+        assert_eq!(parsed.max_completion_tokens, Some(5));
+        assert_eq!(parsed.tools.unwrap()[0].function.name, "f");
+        assert!(parsed.store);
+
+        let response = GenerateResponse {
+            generated_text: "Hi".to_string(),
+            details: Some(details()),
+            error: None,
+            prefix_id: None,
+        };
+        let parsed: client::GenerateResponse = convert(&response);
+        let parsed_details = parsed.details.unwrap();
+        assert_eq!(
+            parsed_details.finish_reason,
+            client::FinishReason::EndOfSequenceToken
+        );
+        assert_eq!(parsed_details.prefill[0].logprob, None);
+        assert_eq!(parsed_details.tokens[0].text, "Hi");
+
+        let response = StreamResponse {
+            index: 1,
+            token: Token {
+                id: 1,
+                text: "Hi".to_string(),
+                logprob: -0.5,
+                special: false,
+                bytes: None,
+            },
+            top_tokens: vec![],
+            generated_text: Some("Hi".to_string()),
+            details: Some(StreamDetails {
+                finish_reason: FinishReason::Length,
+                generated_tokens: 1,
+                seed: Some(1),
+                input_length: 2,
+                backend: None,
+                steps: None,
+                speculative_tokens_accepted: None,
+                compression: None,
+                effective_parameters: effective_parameters(),
+            }),
+            budget: None,
+            seed: None,
+            prefix_id: None,
+        };
+        let parsed: client::StreamResponse = convert(&response);
+        assert_eq!(
+            parsed.details.unwrap().finish_reason,
+            client::FinishReason::Length
+        );
+
+        let response = ChatCompletion::new(
+            "model".to_string(),
+            "fingerprint".to_string(),
+            Some("Hi".to_string()),
+            0,
+            details(),
+            false,
+            None,
+        );
+        let parsed: client::ChatCompletion = convert(&response);
+        assert_eq!(parsed.choices[0].message.content.as_deref(), Some("Hi"));
+        assert_eq!(parsed.usage.completion_tokens, 1);
+
+        let tool_call = ToolCall {
+            id: "0".to_string(),
+            r#type: "function".to_string(),
+            function: FunctionDefinition {
+                description: None,
+                name: "f".to_string(),
+                arguments: json!({"x": 1}),
+            },
+        };
+        let response = ChatCompletion::new(
+            "model".to_string(),
+            "fingerprint".to_string(),
+            None,
+            0,
+            details(),
+            false,
+            Some(vec![tool_call]),
+        );
+        let parsed: client::ChatCompletion = convert(&response);
+        assert_eq!(parsed.choices[0].message.content, None);
+        assert_eq!(parsed.choices[0].message.tool_calls[0].function.name, "f");
+
+        let chunk = CompletionType::ChatCompletionChunk(ChatCompletionChunk::new(
+            "model".to_string(),
+            "fingerprint".to_string(),
+            Some("Hi".to_string()),
+            None,
+            0,
+            None,
+            Some("stop".to_string()),
+            None,
+        ));
+        let parsed: client::ChatCompletionChunk = convert(&chunk);
+        assert_eq!(parsed.choices[0].delta.content.as_deref(), Some("Hi"));
+        assert_eq!(parsed.choices[0].finish_reason.as_deref(), Some("stop"));
+
+        let tokens = vec![SimpleToken {
+            id: 1,
+            text: "Hi".to_string(),
+            start: 0,
+            stop: 2,
+        }];
+        let parsed: Vec<client::SimpleToken> = convert(&tokens);
+        assert_eq!(parsed[0].stop, 2);
+
+        let error = ErrorResponse::new("input_too_long", "validation", "Too long")
+            .with_param(Some("inputs"));
+        let parsed: client::ErrorResponse = convert(&error);
+        assert_eq!(parsed.error.code, "input_too_long");
+        assert_eq!(parsed.error.param.as_deref(), Some("inputs"));
+    }
 }
@@ -1,20 +1,84 @@
+use crate::ErrorResponse;
+use axum::http::StatusCode;
+use axum::Json;
+use clap::ValueEnum;
 use opentelemetry::sdk::propagation::TraceContextPropagator;
 use opentelemetry::sdk::trace;
 use opentelemetry::sdk::trace::Sampler;
 use opentelemetry::sdk::Resource;
 use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use thiserror::Error;
+use tracing_subscriber::filter::ParseError;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{filter::LevelFilter, EnvFilter, Layer};
+use tracing_subscriber::{filter::LevelFilter, reload, EnvFilter, Layer, Registry};
+use utoipa::ToSchema;
+
+/// Filter of the logs, replaced at runtime with `/admin/log_level` or on SIGHUP
+static FILTER: OnceLock<LogFilter> = OnceLock::new();
+
+struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// Filter set at startup with LOG_LEVEL
+    initial: String,
+}
+
+/// Spans recorded for the requests while they are generated
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TraceDetail {
+    /// Spans of the request, its time in the queue and the batches it joins, but none per
+    /// generated token
+    Request,
+    /// Also a span per request for every decode step and every generated token
+    #[default]
+    Token,
+}
+
+/// Format of the logs written on stdout
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// One line per event
+    Text,
+    /// One JSON object per event
+    Json,
+    /// Multiple lines per event, easier to read when debugging
+    Pretty,
+}
+
+impl LogFormat {
+    /// `--json-output` takes precedence over LOG_FORMAT, unknown formats are logged as text
+    fn new(json_output: bool, log_format: Option<&str>) -> Self {
+        if json_output {
+            return Self::Json;
+        }
+        match log_format.map(str::to_lowercase).as_deref() {
+            Some("json") => Self::Json,
+            Some("pretty") => Self::Pretty,
+            _ => Self::Text,
+        }
+    }
+}
 
 /// Init logging using env variables LOG_LEVEL and LOG_FORMAT:
 ///     - otlp_endpoint is an optional URL to an Open Telemetry collector
 ///     - otlp_service_name service name to appear in APM
-///     - LOG_LEVEL may be TRACE, DEBUG, INFO, WARN or ERROR (default to INFO)
-///     - LOG_FORMAT may be TEXT or JSON (default to TEXT)
+///     - otlp_sampling_ratio share of the traces exported, between 0 and 1
+///     - LOG_LEVEL may be TRACE, DEBUG, INFO, WARN or ERROR (default to INFO), or filter
+///       directives per module such as `text_generation_router_v3::queue=debug`
+///     - LOG_FORMAT may be TEXT, JSON or PRETTY (default to TEXT, JSON with json_output)
 ///     - LOG_COLORIZE may be "false" or "true" (default to "true" or ansi supported platforms)
-pub fn init_logging(otlp_endpoint: Option<String>, otlp_service_name: String, json_output: bool) {
+///
+/// The filter can be replaced at runtime with `/admin/log_level`, or on SIGHUP with the
+/// content of the LOG_LEVEL_FILE file, or LOG_LEVEL if not set.
+pub fn init_logging(
+    otlp_endpoint: Option<String>,
+    otlp_service_name: String,
+    otlp_sampling_ratio: f64,
+    json_output: bool,
+) {
     let mut layers = Vec::new();
 
     // STDOUT/STDERR layer
@@ -24,9 +88,11 @@ pub fn init_logging(otlp_endpoint: Option<String>, otlp_service_name: String, js
         .with_ansi(ansi)
         .with_line_number(true);
 
-    let fmt_layer = match json_output {
-        true => fmt_layer.json().flatten_event(true).boxed(),
-        false => fmt_layer.boxed(),
+    let log_format = std::env::var("LOG_FORMAT").ok();
+    let fmt_layer = match LogFormat::new(json_output, log_format.as_deref()) {
+        LogFormat::Json => fmt_layer.json().flatten_event(true).boxed(),
+        LogFormat::Pretty => fmt_layer.pretty().boxed(),
+        LogFormat::Text => fmt_layer.boxed(),
     };
     layers.push(fmt_layer);
 
@@ -47,7 +113,9 @@ pub fn init_logging(otlp_endpoint: Option<String>, otlp_service_name: String, js
                         "service.name",
                         otlp_service_name,
                     )]))
-                    .with_sampler(Sampler::AlwaysOn),
+                    // The decision only depends on the trace id, so that all the services
+                    // sampling with the same ratio keep the same traces
+                    .with_sampler(Sampler::TraceIdRatioBased(otlp_sampling_ratio)),
             )
             .install_batch(opentelemetry::runtime::Tokio);
 
@@ -58,24 +126,185 @@ pub fn init_logging(otlp_endpoint: Option<String>, otlp_service_name: String, js
     }
 
     // Filter events with LOG_LEVEL
-    let varname = "LOG_LEVEL";
-    let env_filter = if let Ok(log_level) = std::env::var(varname) {
-        // Override to avoid simple logs to be spammed with tokio level informations
-        let log_level = match &log_level[..] {
-            "warn" => "text_generation_launcher=warn,text_generation_router=warn",
-            "info" => "text_generation_launcher=info,text_generation_router=info",
-            "debug" => "text_generation_launcher=debug,text_generation_router=debug",
-            log_level => log_level,
-        };
-        EnvFilter::builder()
-            .with_default_directive(LevelFilter::INFO.into())
-            .parse_lossy(log_level)
-    } else {
-        EnvFilter::new("info")
-    };
+    let log_level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
+    let env_filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse_lossy(directives(&log_level));
+    let initial = env_filter.to_string();
+    let (env_filter, handle) = reload::Layer::new(env_filter);
 
     tracing_subscriber::registry()
         .with(env_filter)
         .with(layers)
         .init();
+    let _ = FILTER.set(LogFilter { handle, initial });
+}
+
+/// Directives of a LOG_LEVEL value
+fn directives(log_level: &str) -> &str {
+    // Override to avoid simple logs to be spammed with tokio level informations
+    match log_level {
+        "warn" => "text_generation_launcher=warn,text_generation_router=warn",
+        "info" => "text_generation_launcher=info,text_generation_router=info",
+        "debug" => "text_generation_launcher=debug,text_generation_router=debug",
+        log_level => log_level,
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum LogFilterError {
+    #[error("Invalid log filter: {0}")]
+    Invalid(#[from] ParseError),
+    #[error("Logging was not initialized by the router")]
+    Uninitialized,
+    #[error("Log filter reload failed: {0}")]
+    Reload(#[from] reload::Error),
+}
+
+/// Replace the filter of the logs, returns the directives of the new filter
+pub(crate) fn set_log_filter(log_level: &str) -> Result<String, LogFilterError> {
+    let filter = FILTER.get().ok_or(LogFilterError::Uninitialized)?;
+    // Unlike LOG_LEVEL at startup, invalid directives are rejected instead of ignored
+    let env_filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse(directives(log_level.trim()))?;
+    let directives = env_filter.to_string();
+    filter.handle.reload(env_filter)?;
+    Ok(directives)
+}
+
+/// Set the filter of the logs on SIGHUP, from the content of the LOG_LEVEL_FILE file or back
+/// to LOG_LEVEL
+#[cfg(unix)]
+pub(crate) async fn reload_on_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Some(filter) = FILTER.get() else {
+        return;
+    };
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            tracing::warn!("Could not install the SIGHUP handler: {err}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let log_level = match std::env::var("LOG_LEVEL_FILE") {
+            Ok(path) => match std::fs::read_to_string(&path) {
+                Ok(log_level) => log_level,
+                Err(err) => {
+                    tracing::error!("Could not read the log filter from {path}: {err}");
+                    continue;
+                }
+            },
+            Err(_) => filter.initial.clone(),
+        };
+        match set_log_filter(&log_level) {
+            Ok(directives) => tracing::info!("Log filter set to {directives}"),
+            Err(err) => tracing::error!("{err}"),
+        }
+    }
+}
+
+/// Filter of the logs, as LOG_LEVEL
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct LogLevel {
+    /// A level, or filter directives per module
+    #[schema(example = "text_generation_router=info,text_generation_router_v3::queue=debug")]
+    pub filter: String,
+}
+
+/// Current filter of the logs
+///
+/// Served when `admin.api_key` is set in the router config, to the requests with this key.
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/log_level",
+responses(
+(status = 200, description = "Current log filter", body = LogLevel),
+(status = 401, description = "Missing or invalid admin key"),
+(status = 404, description = "Logging was not initialized by the router"),
+)
+)]
+pub(crate) async fn get_log_level() -> Result<Json<LogLevel>, StatusCode> {
+    let filter = FILTER.get().ok_or(StatusCode::NOT_FOUND)?;
+    let filter = filter
+        .handle
+        .with_current(ToString::to_string)
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    Ok(Json(LogLevel { filter }))
+}
+
+/// Replace the filter of the logs until the next change or restart
+///
+/// Served when `admin.api_key` is set in the router config, to the requests with this key.
+#[utoipa::path(
+put,
+tag = "Text Generation Inference",
+path = "/admin/log_level",
+request_body = LogLevel,
+responses(
+(status = 200, description = "New log filter", body = LogLevel),
+(status = 401, description = "Missing or invalid admin key"),
+(status = 422, description = "Invalid log filter", body = ErrorResponse,
+example = json ! ({"error": {"code": "invalid_log_filter", "type": "validation", "message": "Invalid log filter: invalid filter directive", "param": "filter"}})),
+)
+)]
+pub(crate) async fn set_log_level(
+    Json(request): Json<LogLevel>,
+) -> Result<Json<LogLevel>, (StatusCode, Json<ErrorResponse>)> {
+    match set_log_filter(&request.filter) {
+        Ok(filter) => {
+            tracing::info!("Log filter set to {filter}");
+            Ok(Json(LogLevel { filter }))
+        }
+        Err(err @ LogFilterError::Invalid(_)) => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(
+                ErrorResponse::new("invalid_log_filter", "validation", err.to_string())
+                    .with_param(Some("filter")),
+            ),
+        )),
+        Err(err) => Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(
+                "logging_uninitialized",
+                "not_found",
+                err.to_string(),
+            )),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(LogFormat::new(false, None), LogFormat::Text);
+        assert_eq!(LogFormat::new(false, Some("PRETTY")), LogFormat::Pretty);
+        assert_eq!(LogFormat::new(false, Some("json")), LogFormat::Json);
+        assert_eq!(LogFormat::new(true, Some("pretty")), LogFormat::Json);
+        assert_eq!(LogFormat::new(false, Some("yaml")), LogFormat::Text);
+    }
+
+    #[test]
+    fn test_set_log_filter() {
+        // The tests do not initialize the logging of the router
+        assert!(matches!(
+            set_log_filter("debug"),
+            Err(LogFilterError::Uninitialized)
+        ));
+        assert_eq!(
+            directives("debug"),
+            "text_generation_launcher=debug,text_generation_router=debug"
+        );
+        assert_eq!(
+            directives("text_generation_router_v3::queue=trace"),
+            "text_generation_router_v3::queue=trace"
+        );
+    }
 }
@@ -0,0 +1,370 @@
+/// Prompts kept in the KV cache of the backend for the next requests of a conversation
+use crate::infer::{Backend, Infer, InferError};
+use crate::router_config::PrefixPinningConfig;
+use crate::validation::{ValidGenerateRequest, ValidationError};
+use crate::ErrorResponse;
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+/// Pinned prompts, released by the backend when they are unpinned or expire
+pub(crate) struct PrefixPins {
+    max_pinned_tokens: u64,
+    ttl: Duration,
+    backend: Arc<dyn Backend + Send + Sync>,
+    state: Mutex<PinnedPrefixes>,
+}
+
+#[derive(Default)]
+struct PinnedPrefixes {
+    /// Pinned prompts by the id given to the backend
+    prefixes: HashMap<u64, PinnedPrefix>,
+    /// Tokens of all the pinned prompts
+    tokens: u64,
+}
+
+struct PinnedPrefix {
+    input_ids: Arc<Vec<u32>>,
+    expires_at: Instant,
+}
+
+impl PrefixPins {
+    /// `None` if pinning is disabled or not supported by the backend
+    pub(crate) fn new(
+        config: PrefixPinningConfig,
+        backend: Arc<dyn Backend + Send + Sync>,
+    ) -> Option<Arc<Self>> {
+        if config.max_pinned_tokens == 0 {
+            return None;
+        }
+        if !backend.capabilities().supports_prefix_pinning {
+            tracing::warn!(
+                "Prefix pinning is disabled, backend `{}` does not support it",
+                backend.name()
+            );
+            return None;
+        }
+        let pins = Arc::new(Self {
+            max_pinned_tokens: config.max_pinned_tokens,
+            ttl: Duration::from_secs(config.ttl_secs),
+            backend,
+            state: Mutex::new(PinnedPrefixes::default()),
+        });
+        tokio::spawn(expire_task(Arc::downgrade(&pins)));
+        Some(pins)
+    }
+
+    /// Check that the inputs of `request` start with the prompt of `prefix_id` and extend its
+    /// lifetime, then pin the prompt of `request` if `pin` is set and return its id
+    pub(crate) fn apply(
+        &self,
+        request: &mut ValidGenerateRequest,
+        pin: bool,
+        prefix_id: Option<&str>,
+    ) -> Result<Option<String>, InferError> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        self.expire(&mut state, now);
+
+        if let Some(prefix_id) = prefix_id {
+            let prefix = parse_id(prefix_id)
+                .and_then(|id| state.prefixes.get_mut(&id))
+                .ok_or_else(|| ValidationError::UnknownPrefix(prefix_id.to_string()))?;
+            let input_ids = request.input_ids.as_deref().map_or(&[][..], Vec::as_slice);
+            if !input_ids.starts_with(&prefix.input_ids) {
+                return Err(ValidationError::PrefixMismatch.into());
+            }
+            prefix.expires_at = now + self.ttl;
+        }
+
+        if !pin {
+            return Ok(None);
+        }
+        // The backend skips the prefix cache when the prefill logprobs are returned
+        if request.decoder_input_details {
+            return Err(ValidationError::PinConflict("decoder_input_details").into());
+        }
+        let input_ids = request
+            .input_ids
+            .clone()
+            .ok_or(ValidationError::PinUnsupported)?;
+        let tokens = state.tokens + input_ids.len() as u64;
+        if tokens > self.max_pinned_tokens {
+            return Err(InferError::PinCapacity(self.max_pinned_tokens));
+        }
+        let id = loop {
+            let id = rand::random();
+            if !state.prefixes.contains_key(&id) {
+                break id;
+            }
+        };
+        state.prefixes.insert(
+            id,
+            PinnedPrefix {
+                input_ids,
+                expires_at: now + self.ttl,
+            },
+        );
+        state.tokens = tokens;
+        metrics::gauge!("tgi_prefix_pinned_tokens").set(tokens as f64);
+        request.pin_prefix = Some(id);
+        Ok(Some(format_id(id)))
+    }
+
+    /// Release a pinned prompt, returns false if it is unknown or expired
+    pub(crate) fn unpin(&self, prefix_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some((id, prefix)) =
+            parse_id(prefix_id).and_then(|id| state.prefixes.remove_entry(&id))
+        else {
+            return false;
+        };
+        state.tokens -= prefix.input_ids.len() as u64;
+        metrics::gauge!("tgi_prefix_pinned_tokens").set(state.tokens as f64);
+        self.backend.unpin_prefix(id);
+        true
+    }
+
+    pub(crate) fn usage(&self) -> PrefixPinsResponse {
+        let mut state = self.state.lock().unwrap();
+        self.expire(&mut state, Instant::now());
+        PrefixPinsResponse {
+            pinned_prefixes: state.prefixes.len(),
+            pinned_tokens: state.tokens,
+            max_pinned_tokens: self.max_pinned_tokens,
+        }
+    }
+
+    /// Release the prompts unused for longer than the TTL
+    fn expire(&self, state: &mut PinnedPrefixes, now: Instant) {
+        let PinnedPrefixes { prefixes, tokens } = state;
+        let pinned_tokens = *tokens;
+        prefixes.retain(|&id, prefix| {
+            if prefix.expires_at > now {
+                return true;
+            }
+            *tokens -= prefix.input_ids.len() as u64;
+            self.backend.unpin_prefix(id);
+            false
+        });
+        if *tokens != pinned_tokens {
+            metrics::gauge!("tgi_prefix_pinned_tokens").set(*tokens as f64);
+        }
+    }
+}
+
+/// Release the expired prompts of the servers without traffic
+async fn expire_task(pins: Weak<PrefixPins>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let Some(pins) = pins.upgrade() else {
+            return;
+        };
+        let mut state = pins.state.lock().unwrap();
+        pins.expire(&mut state, Instant::now());
+    }
+}
+
+fn format_id(id: u64) -> String {
+    format!("pfx_{id:016x}")
+}
+
+fn parse_id(prefix_id: &str) -> Option<u64> {
+    u64::from_str_radix(prefix_id.strip_prefix("pfx_")?, 16).ok()
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct PrefixPinsResponse {
+    #[schema(example = 3)]
+    pub pinned_prefixes: usize,
+    /// Tokens of all the pinned prompts
+    #[schema(example = 4096)]
+    pub pinned_tokens: u64,
+    /// Tokens of all the pinned prompts above which `pin` is rejected, 0 if pinning is disabled
+    #[schema(example = 65536)]
+    pub max_pinned_tokens: u64,
+}
+
+/// Pinned prompts and their capacity
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/prefixes",
+responses(
+(status = 200, description = "Pinned prompts", body = PrefixPinsResponse),
+)
+)]
+pub(crate) async fn get_prefixes(Extension(infer): Extension<Infer>) -> Json<PrefixPinsResponse> {
+    Json(infer.prefix_pins_usage())
+}
+
+/// Release the KV cache of a pinned prompt before it expires
+#[utoipa::path(
+delete,
+tag = "Text Generation Inference",
+path = "/v1/prefixes/{id}",
+params(
+("id" = String, Path, description = "`prefix_id` returned with the pinned prompt"),
+),
+responses(
+(status = 204, description = "The prompt was unpinned"),
+(status = 404, description = "The prompt is not pinned", body = ErrorResponse,
+example = json ! ({"error": {"code": "prefix_not_found", "type": "not_found", "message": "No pinned prompt with this id"}})),
+)
+)]
+pub(crate) async fn unpin_prefix(
+    Extension(infer): Extension<Infer>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    if infer.unpin_prefix(&id) {
+        tracing::info!("Unpinned prefix {id}");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(
+                "prefix_not_found",
+                "not_found",
+                "No pinned prompt with this id",
+            )),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::{BackendCapabilities, GenerationStream};
+    use crate::validation::{ValidParameters, ValidStoppingParameters};
+    use async_trait::async_trait;
+
+    #[derive(Default)]
+    struct PinningBackend {
+        unpinned: Mutex<Vec<u64>>,
+    }
+
+    #[async_trait]
+    impl Backend for PinningBackend {
+        fn schedule(&self, _: ValidGenerateRequest) -> Result<GenerationStream, InferError> {
+            unimplemented!()
+        }
+
+        async fn health(&self, _: bool) -> bool {
+            true
+        }
+
+        fn capabilities(&self) -> BackendCapabilities {
+            BackendCapabilities {
+                supports_prefix_pinning: true,
+                ..Default::default()
+            }
+        }
+
+        fn unpin_prefix(&self, id: u64) {
+            self.unpinned.lock().unwrap().push(id);
+        }
+    }
+
+    fn request(input_ids: Vec<u32>) -> ValidGenerateRequest {
+        ValidGenerateRequest {
+            inputs: vec![],
+            input_length: input_ids.len() as u32,
+            input_ids: Some(Arc::new(input_ids)),
+            input_offsets: None,
+            truncate: 0,
+            add_special_tokens: true,
+            pre_tokenized: false,
+            decoder_input_details: false,
+            parameters: ValidParameters {
+                temperature: 1.0,
+                top_k: 0,
+                top_p: 1.0,
+                typical_p: 1.0,
+                do_sample: false,
+                seed: 0,
+                repetition_penalty: 1.0,
+                frequency_penalty: 0.0,
+                watermark: false,
+                grammar: None,
+                allowed_token_ids: vec![],
+                banned_token_ids: vec![],
+            },
+            stopping_parameters: ValidStoppingParameters {
+                max_new_tokens: 1,
+                stop_sequences: vec![],
+                ignore_eos_token: false,
+            },
+            top_n_tokens: 0,
+            adapter_id: None,
+            raw_bytes: false,
+            stop_on_repetition: None,
+            stop_on_json: false,
+            stop_token_ids: vec![],
+            pin_prefix: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefix_pins() {
+        let backend = Arc::new(PinningBackend::default());
+        let config = PrefixPinningConfig {
+            max_pinned_tokens: 6,
+            ttl_secs: 60,
+        };
+        let pins = PrefixPins::new(config, backend.clone()).unwrap();
+
+        let mut first = request(vec![1, 2, 3, 4]);
+        let prefix_id = pins.apply(&mut first, true, None).unwrap().unwrap();
+        let id = first.pin_prefix.unwrap();
+        assert_eq!(parse_id(&prefix_id), Some(id));
+        assert_eq!(pins.usage().pinned_tokens, 4);
+
+        // The next turn must start with the pinned prompt
+        let mut next = request(vec![1, 2, 3, 4, 5]);
+        assert_eq!(
+            pins.apply(&mut next, false, Some(&prefix_id)).unwrap(),
+            None
+        );
+        assert_eq!(next.pin_prefix, None);
+        assert!(matches!(
+            pins.apply(&mut request(vec![1, 2, 5]), false, Some(&prefix_id)),
+            Err(InferError::ValidationError(ValidationError::PrefixMismatch))
+        ));
+        assert!(matches!(
+            pins.apply(&mut request(vec![1]), false, Some("pfx_0")),
+            Err(InferError::ValidationError(ValidationError::UnknownPrefix(
+                _
+            )))
+        ));
+
+        // Pinned prompts are limited to `max_pinned_tokens`
+        assert!(matches!(
+            pins.apply(&mut next, true, Some(&prefix_id)),
+            Err(InferError::PinCapacity(6))
+        ));
+        assert!(pins.unpin(&prefix_id));
+        assert!(!pins.unpin(&prefix_id));
+        assert_eq!(*backend.unpinned.lock().unwrap(), vec![id]);
+        assert_eq!(pins.usage().pinned_tokens, 0);
+        assert!(pins.apply(&mut next, true, None).unwrap().is_some());
+        assert_eq!(pins.usage().pinned_prefixes, 1);
+
+        // Unused pins expire
+        let config = PrefixPinningConfig {
+            max_pinned_tokens: 6,
+            ttl_secs: 0,
+        };
+        let pins = PrefixPins::new(config, backend.clone()).unwrap();
+        let prefix_id = pins.apply(&mut request(vec![1]), true, None).unwrap();
+        assert_eq!(pins.usage().pinned_prefixes, 0);
+        assert!(!pins.unpin(&prefix_id.unwrap()));
+        assert_eq!(backend.unpinned.lock().unwrap().len(), 2);
+    }
+}
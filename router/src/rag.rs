@@ -0,0 +1,285 @@
+/// Answers to a question over documents, with the context assembled by the router
+///
+/// The documents come in ranking order and are placed in the prompt while they fit in the input
+/// tokens of the model. Each one is truncated to `max_document_tokens`, and once the budget runs
+/// out the last one placed is truncated and the following ones are left out.
+use crate::infer::{Infer, InferError};
+use crate::json_body::JsonBody;
+use crate::server::{generate_internal, ComputeType};
+use crate::{
+    default_max_new_tokens, default_parameters, Endpoint, ErrorResponse, GenerateParameters,
+    GenerateRequest, GenerateResponse, Info,
+};
+use axum::extract::Extension;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::instrument;
+use utoipa::ToSchema;
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct RagRequest {
+    #[schema(example = "What is the capital of France?")]
+    pub question: String,
+    /// Documents in ranking order, the most relevant first
+    #[schema(example = json!(["Paris is the capital and most populous city of France."]))]
+    pub documents: Vec<String>,
+    /// Maximum number of tokens of each document in the prompt
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = 512)]
+    pub max_document_tokens: Option<usize>,
+    /// Name of a server-side prompt template assembling the prompt, rendered with the question
+    /// as `inputs` and the placed documents as `documents`. A default prompt is used if not set.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub template: Option<String>,
+    #[serde(default = "default_parameters")]
+    pub parameters: GenerateParameters,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RagResponse {
+    #[serde(flatten)]
+    pub response: GenerateResponse,
+    /// Documents placed in the prompt, the others did not fit
+    pub documents: Vec<RagDocument>,
+}
+
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct RagDocument {
+    /// Position of the document in `documents`
+    #[schema(example = 0)]
+    pub index: usize,
+    /// Number of tokens of the document in the prompt
+    #[schema(example = 12)]
+    pub tokens: usize,
+    /// Whether the end of the document was cut
+    #[schema(example = false)]
+    pub truncated: bool,
+}
+
+/// Generate an answer to a question over documents
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/rag",
+request_body = RagRequest,
+responses(
+(status = 200, description = "Generated Text", body = RagResponse),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": {"code": "template_error", "type": "template_error", "message": "template not found"}})),
+)
+)]
+#[instrument(skip_all, fields(documents = req.documents.len()))]
+pub(crate) async fn rag(
+    Extension(infer): Extension<Infer>,
+    Extension(compute_type): Extension<ComputeType>,
+    Extension(info): Extension<Info>,
+    JsonBody(req): JsonBody<RagRequest>,
+) -> Result<(HeaderMap, Json<RagResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let RagRequest {
+        question,
+        documents,
+        max_document_tokens,
+        template,
+        parameters,
+    } = req;
+
+    // The special tokens are stripped before counting the tokens
+    let mut checked = GenerateRequest {
+        inputs: question,
+        parameters,
+        add_special_tokens: true,
+        endpoint: Endpoint::Generate,
+        template: None,
+        variables: Some(HashMap::from([("documents".to_string(), documents.into())])),
+        inputs_ids: None,
+        suffix: None,
+    };
+    infer.check_special_tokens(&mut checked)?;
+    let documents = checked
+        .variables
+        .and_then(|mut variables| variables.remove("documents"))
+        .and_then(|documents| serde_json::from_value::<Vec<String>>(documents).ok())
+        .unwrap_or_default();
+    let question = checked.inputs;
+    let parameters = checked.parameters;
+
+    // The prompt and the new tokens fit in the total tokens, as validated by the generation
+    let max_new_tokens = parameters
+        .max_new_tokens
+        .or(default_max_new_tokens())
+        .unwrap_or(0) as usize;
+    let budget = info
+        .max_input_tokens
+        .min(info.max_total_tokens.saturating_sub(max_new_tokens));
+    let request = |documents: Vec<String>| {
+        prompt_request(
+            question.clone(),
+            template.clone(),
+            documents,
+            parameters.clone(),
+        )
+    };
+    let mut remaining = budget.saturating_sub(count_tokens(&infer, request(vec![])).await?);
+
+    let mut placed = Vec::new();
+    let mut placed_documents = Vec::new();
+    for (index, document) in documents.into_iter().enumerate() {
+        let cap = max_document_tokens.unwrap_or(usize::MAX).min(remaining);
+        if cap == 0 {
+            break;
+        }
+        let encoding = tokenize(&infer, document.clone(), false).await?;
+        let tokens = encoding.len().min(cap);
+        let truncated = tokens < encoding.len();
+        let document = if truncated {
+            truncate(&document, encoding.get_offsets(), encoding.len(), tokens)
+        } else {
+            document
+        };
+        remaining -= tokens;
+        placed.push(document);
+        placed_documents.push(RagDocument {
+            index,
+            tokens,
+            truncated,
+        });
+    }
+    // The text around the documents is not counted above, the last documents are left out
+    // until the prompt fits
+    while !placed.is_empty() && count_tokens(&infer, request(placed.clone())).await? > budget {
+        placed.pop();
+        placed_documents.pop();
+    }
+    metrics::histogram!("tgi_rag_documents").record(placed_documents.len() as f64);
+
+    let (headers, Json(response)) =
+        generate_internal(Extension(infer), compute_type, Json(request(placed)), span).await?;
+    Ok((
+        headers,
+        Json(RagResponse {
+            response,
+            documents: placed_documents,
+        }),
+    ))
+}
+
+/// Generate request of the prompt with these `documents`, built by the `template` if set
+fn prompt_request(
+    question: String,
+    template: Option<String>,
+    documents: Vec<String>,
+    parameters: GenerateParameters,
+) -> GenerateRequest {
+    let (inputs, variables) = match template {
+        Some(_) => (
+            question,
+            Some(HashMap::from([("documents".to_string(), documents.into())])),
+        ),
+        None => (default_prompt(&question, &documents), None),
+    };
+    GenerateRequest {
+        inputs,
+        parameters,
+        add_special_tokens: true,
+        endpoint: Endpoint::Generate,
+        template,
+        variables,
+        inputs_ids: None,
+        suffix: None,
+    }
+}
+
+fn default_prompt(question: &str, documents: &[String]) -> String {
+    let mut prompt = String::from("Answer the question using the following documents.\n\n");
+    for (index, document) in documents.iter().enumerate() {
+        prompt.push_str(&format!("Document {}:\n{document}\n\n", index + 1));
+    }
+    prompt.push_str(&format!("Question: {question}\nAnswer:"));
+    prompt
+}
+
+/// Number of tokens of the rendered prompt of `request`
+async fn count_tokens(infer: &Infer, mut request: GenerateRequest) -> Result<usize, InferError> {
+    infer.apply_prompt_template(&mut request)?;
+    let encoding = tokenize(infer, request.inputs, true).await?;
+    Ok(encoding.len())
+}
+
+async fn tokenize(
+    infer: &Infer,
+    inputs: String,
+    add_special_tokens: bool,
+) -> Result<tokenizers::Encoding, InferError> {
+    infer
+        .tokenize(GenerateRequest {
+            inputs,
+            parameters: default_parameters(),
+            add_special_tokens,
+            endpoint: Endpoint::Generate,
+            template: None,
+            variables: None,
+            inputs_ids: None,
+            suffix: None,
+        })
+        .await
+}
+
+/// First `keep` of the `tokens` tokens of `document`, cut at the character offsets of the
+/// tokens, or proportionally to its length without offsets
+fn truncate(document: &str, offsets: &[(usize, usize)], tokens: usize, keep: usize) -> String {
+    let characters = if keep == 0 {
+        0
+    } else if offsets.len() == tokens {
+        offsets[keep - 1].1
+    } else {
+        document.chars().count() * keep / tokens
+    };
+    document.chars().take(characters).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        let offsets = [(0, 5), (5, 11), (11, 12)];
+        assert_eq!(truncate("Hello world!", &offsets, 3, 2), "Hello world");
+        assert_eq!(truncate("Hello world!", &offsets, 3, 0), "");
+        // Without offsets
+        assert_eq!(truncate("Hello world!", &[], 4, 2), "Hello ");
+    }
+
+    #[test]
+    fn test_prompt_request() {
+        let documents = vec!["Paris is in France.".to_string()];
+        let request = prompt_request(
+            "Where is Paris?".to_string(),
+            None,
+            documents.clone(),
+            default_parameters(),
+        );
+        assert_eq!(
+            request.inputs,
+            "Answer the question using the following documents.\n\n\
+            Document 1:\nParis is in France.\n\n\
+            Question: Where is Paris?\nAnswer:"
+        );
+
+        let request = prompt_request(
+            "Where is Paris?".to_string(),
+            Some("rag@v1".to_string()),
+            documents,
+            default_parameters(),
+        );
+        assert_eq!(request.inputs, "Where is Paris?");
+        assert_eq!(
+            request.variables.unwrap()["documents"],
+            serde_json::json!(["Paris is in France."])
+        );
+    }
+}
@@ -0,0 +1,176 @@
+/// Re-sending logged requests against a running server, at their original or accelerated pacing
+///
+/// The log is a JSON lines file with one request per line, `{"timestamp": 1718000000.25,
+/// "path": "/generate", "body": {...}}`, where `timestamp` is the Unix time the request was
+/// received in seconds.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::Instant;
+
+#[derive(Debug, Deserialize)]
+struct LoggedRequest {
+    timestamp: f64,
+    path: String,
+    body: serde_json::Value,
+}
+
+/// Outcome of a replay, printed as JSON by the `replay` subcommand
+#[derive(Debug, Default, Serialize)]
+pub struct ReplaySummary {
+    pub requests: usize,
+    /// Responses by status code, `error` for the requests that got no response
+    pub responses: BTreeMap<String, usize>,
+    /// Latencies until the end of the responses, in seconds
+    pub latency_p50: f64,
+    pub latency_p99: f64,
+    pub duration: f64,
+}
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("could not read request log: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid request on line {line} of the request log: {source}")]
+    Json {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("the request log is empty")]
+    Empty,
+}
+
+fn parse_log(content: &str) -> Result<Vec<LoggedRequest>, ReplayError> {
+    let mut requests = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|source| ReplayError::Json {
+                line: i + 1,
+                source,
+            })
+        })
+        .collect::<Result<Vec<LoggedRequest>, _>>()?;
+    if requests.is_empty() {
+        return Err(ReplayError::Empty);
+    }
+    requests.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+    Ok(requests)
+}
+
+/// Delay of each request after the start of the replay, `speed` times faster than the log,
+/// or none if `speed` is 0
+fn schedule(requests: &[LoggedRequest], speed: f64) -> Vec<Duration> {
+    let start = requests[0].timestamp;
+    requests
+        .iter()
+        .map(|request| {
+            if speed > 0.0 {
+                Duration::from_secs_f64((request.timestamp - start) / speed)
+            } else {
+                Duration::ZERO
+            }
+        })
+        .collect()
+}
+
+/// Send the requests of the log at `log_path` to the server at `url`, and wait for all the
+/// responses
+pub async fn replay(
+    log_path: &Path,
+    url: &str,
+    speed: f64,
+    api_key: Option<String>,
+) -> Result<ReplaySummary, ReplayError> {
+    let requests = parse_log(&std::fs::read_to_string(log_path)?)?;
+    let delays = schedule(&requests, speed);
+    let client = reqwest::Client::new();
+    let url = url.trim_end_matches('/');
+
+    let start = Instant::now();
+    let tasks: Vec<_> = requests
+        .into_iter()
+        .zip(delays)
+        .map(|(request, delay)| {
+            let mut builder = client
+                .post(format!("{url}{}", request.path))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(request.body.to_string());
+            if let Some(api_key) = &api_key {
+                builder = builder.bearer_auth(api_key);
+            }
+            tokio::spawn(async move {
+                tokio::time::sleep_until(start + delay).await;
+                let sent_at = Instant::now();
+                // Streamed responses are read to their end
+                let status = match builder.send().await {
+                    Ok(response) => {
+                        let status = response.status();
+                        response.bytes().await.ok().map(|_| status.as_u16())
+                    }
+                    Err(_) => None,
+                };
+                (status, sent_at.elapsed())
+            })
+        })
+        .collect();
+
+    let mut summary = ReplaySummary {
+        requests: tasks.len(),
+        ..Default::default()
+    };
+    let mut latencies = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        let (status, latency) = task.await.expect("replay task panicked");
+        let status = status.map_or_else(|| "error".to_string(), |status| status.to_string());
+        *summary.responses.entry(status).or_default() += 1;
+        latencies.push(latency);
+    }
+    latencies.sort_unstable();
+    let percentile = |p: usize| latencies[(latencies.len() * p).div_ceil(100) - 1].as_secs_f64();
+    summary.latency_p50 = percentile(50);
+    summary.latency_p99 = percentile(99);
+    summary.duration = start.elapsed().as_secs_f64();
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_schedule() {
+        let log = r#"{"timestamp": 12.5, "path": "/generate", "body": {"inputs": "b"}}
+
+{"timestamp": 10.0, "path": "/v1/chat/completions", "body": {"messages": []}}
+{"timestamp": 14.0, "path": "/generate", "body": {"inputs": "c"}}"#;
+        let requests = parse_log(log).unwrap();
+        assert_eq!(requests[0].path, "/v1/chat/completions");
+        assert_eq!(
+            schedule(&requests, 1.0),
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(2500),
+                Duration::from_secs(4)
+            ]
+        );
+        assert_eq!(
+            schedule(&requests, 2.0),
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(1250),
+                Duration::from_secs(2)
+            ]
+        );
+        assert_eq!(schedule(&requests, 0.0), vec![Duration::ZERO; 3]);
+
+        assert!(matches!(
+            parse_log("{\"timestamp\": 1}"),
+            Err(ReplayError::Json { line: 1, .. })
+        ));
+        assert!(matches!(parse_log("\n"), Err(ReplayError::Empty)));
+    }
+}
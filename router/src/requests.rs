@@ -0,0 +1,258 @@
+/// Ids of the requests in flight, to cancel their generations
+use crate::concurrency::CallerLimit;
+use crate::infer::{special_tokens, Infer};
+use crate::usage::{bearer_token, UsageKey};
+use crate::ErrorResponse;
+use axum::extract::{Extension, Path, Request};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+tokio::task_local! {
+    /// Id of the request being handled
+    static REQUEST_ID: RequestId;
+}
+
+/// Id of a request, and the API key of its caller
+///
+/// The ids are chosen by the clients, so they are only unique per caller.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct RequestId {
+    id: Arc<str>,
+    owner: Option<Arc<str>>,
+}
+
+/// Id of the request being handled, if any
+pub(crate) fn request_id() -> Option<Arc<str>> {
+    REQUEST_ID.try_with(|request_id| request_id.id.clone()).ok()
+}
+
+/// API key of the caller of the request being handled, if any
+pub(crate) fn request_owner() -> Option<Arc<str>> {
+    REQUEST_ID
+        .try_with(|request_id| request_id.owner.clone())
+        .ok()
+        .flatten()
+}
+
+/// Task-local context of the request being handled
+///
+/// Streams are polled outside of the request handler, so the context is captured in the
+/// handler and restored around the generations.
+#[derive(Clone)]
+pub(crate) struct RequestScope {
+    id: Option<RequestId>,
+    usage_key: Option<UsageKey>,
+    caller_limit: Option<CallerLimit>,
+    trusted: bool,
+}
+
+impl RequestScope {
+    pub(crate) fn current() -> Self {
+        Self {
+            id: REQUEST_ID.try_with(Clone::clone).ok(),
+            usage_key: UsageKey::current(),
+            caller_limit: CallerLimit::current(),
+            trusted: special_tokens::trusted_caller(),
+        }
+    }
+
+    /// Run `future` in the context of the request
+    pub(crate) async fn run<F: Future>(self, future: F) -> F::Output {
+        let future = special_tokens::scope_trusted(self.trusted, future);
+        let future = UsageKey::scope(self.usage_key, future);
+        let future = CallerLimit::scope(self.caller_limit, future);
+        match self.id {
+            Some(id) => REQUEST_ID.scope(id, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Use the `x-request-id` header of the request as its id, or generate one, and return it in
+/// the `x-request-id` header of the response
+///
+/// The id is bound to the bearer token of the request, to only let the caller cancel it.
+pub(crate) async fn assign_request_id(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty())
+        .map(Arc::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string().into());
+    let header = HeaderValue::from_str(&id).unwrap();
+    let owner = bearer_token(request.headers()).map(Arc::from);
+
+    let mut response = REQUEST_ID
+        .scope(RequestId { id, owner }, next.run(request))
+        .await;
+    response.headers_mut().insert("x-request-id", header);
+    response
+}
+
+/// Cancellation signals of the generations in flight, by request id and caller
+///
+/// A request can have several generations, e.g. with `/generate_batch`, and they are cancelled
+/// together.
+#[derive(Clone, Default)]
+pub(crate) struct Requests {
+    cancellations: Arc<Mutex<HashMap<RequestId, watch::Sender<bool>>>>,
+}
+
+impl Requests {
+    /// Register a generation of the request being handled
+    pub(crate) fn register(&self) -> Cancellation {
+        let Ok(id) = REQUEST_ID.try_with(Clone::clone) else {
+            return Cancellation { registration: None };
+        };
+        let receiver = self
+            .cancellations
+            .lock()
+            .unwrap()
+            .entry(id.clone())
+            .or_insert_with(|| watch::channel(false).0)
+            .subscribe();
+        Cancellation {
+            registration: Some((self.clone(), id, receiver)),
+        }
+    }
+
+    /// Cancel the generations of a request of the caller with the API key `owner`, returns
+    /// false if it has none in flight
+    pub(crate) fn cancel(&self, id: &str, owner: Option<&str>) -> bool {
+        let id = RequestId {
+            id: id.into(),
+            owner: owner.map(Arc::from),
+        };
+        match self.cancellations.lock().unwrap().get(&id) {
+            Some(sender) => {
+                sender.send_replace(true);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Cancellation signal of a generation
+pub(crate) struct Cancellation {
+    registration: Option<(Requests, RequestId, watch::Receiver<bool>)>,
+}
+
+impl Cancellation {
+    /// Resolves once the request is cancelled, never if it has no id
+    pub(crate) async fn cancelled(&mut self) {
+        if let Some((_, _, receiver)) = &mut self.registration {
+            // The sender lives as long as the receivers
+            let _ = receiver.wait_for(|cancelled| *cancelled).await;
+            return;
+        }
+        std::future::pending().await
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.registration
+            .as_ref()
+            .is_some_and(|(_, _, receiver)| *receiver.borrow())
+    }
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        if let Some((requests, id, receiver)) = self.registration.take() {
+            let mut cancellations = requests.cancellations.lock().unwrap();
+            drop(receiver);
+            if cancellations
+                .get(&id)
+                .is_some_and(|sender| sender.receiver_count() == 0)
+            {
+                cancellations.remove(&id);
+            }
+        }
+    }
+}
+
+/// Cancel the generations of a request
+///
+/// The generations are removed from the queue, or from the running batch and their cache is
+/// freed. Their streams end with a `cancelled` error. Only the requests sent with the same
+/// API key can be cancelled.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/requests/{id}/cancel",
+params(
+("id" = String, Path, description = "Value of the `x-request-id` header of the request"),
+),
+responses(
+(status = 204, description = "The generations of the request were cancelled"),
+(status = 404, description = "No generation of the request of the caller is in flight", body = ErrorResponse,
+example = json ! ({"error": {"code": "request_not_found", "type": "not_found", "message": "No generation in flight for this request"}})),
+)
+)]
+pub(crate) async fn cancel_request(
+    Extension(infer): Extension<Infer>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    if infer.cancel(&id, bearer_token(&headers)) {
+        tracing::info!("Cancelled request {id}");
+        metrics::counter!("tgi_request_failure", "err" => "cancelled").increment(1);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse::new(
+                "request_not_found",
+                "not_found",
+                "No generation in flight for this request",
+            )),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancel_request() {
+        let requests = Requests::default();
+        let id = RequestId {
+            id: "request".into(),
+            owner: Some("key".into()),
+        };
+        let (mut first, mut second) = REQUEST_ID
+            .scope(id, async { (requests.register(), requests.register()) })
+            .await;
+        let mut other = requests.register();
+        assert!(!requests.cancel("other", Some("key")));
+        // Another caller cannot cancel the request
+        assert!(!requests.cancel("request", None));
+        assert!(!requests.cancel("request", Some("other")));
+
+        assert!(requests.cancel("request", Some("key")));
+        tokio::time::timeout(Duration::from_secs(1), async {
+            first.cancelled().await;
+            second.cancelled().await;
+        })
+        .await
+        .unwrap();
+        // Generations without a request id cannot be cancelled
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), other.cancelled())
+                .await
+                .is_err()
+        );
+
+        drop((first, second));
+        assert!(requests.cancellations.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,904 @@
+/// Router configuration file
+use crate::{GenerateParameters, GrammarType, RepetitionStop, VocabToken};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Operator settings loaded from the JSON file given with `--router-config`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouterConfig {
+    /// Named generation parameter presets, selected with the `preset` request parameter
+    #[serde(default)]
+    pub presets: HashMap<String, Preset>,
+    /// Default generation parameters of the served model or of its adapters, by model id
+    #[serde(default)]
+    pub default_parameters: HashMap<String, ModelDefaults>,
+    /// Named prompt templates, selected with the `template` field of the generate endpoints
+    #[serde(default)]
+    pub prompt_templates: HashMap<String, String>,
+    /// Limits of the `/generate_batch` endpoint
+    #[serde(default)]
+    pub generate_batch: GenerateBatchConfig,
+    /// Token quotas per API key
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    /// Buffering of the streamed events to resume streams after a disconnect
+    #[serde(default)]
+    pub stream_resume: StreamResumeConfig,
+    /// Handling of the special tokens found in the user inputs
+    #[serde(default)]
+    pub special_tokens: SpecialTokensConfig,
+    /// Ranking of the `best_of` sequences
+    #[serde(default)]
+    pub best_of: BestOfConfig,
+    /// Fill-in-the-middle tokens of the model, detected from the tokenizer if not set
+    pub fim: Option<FimConfig>,
+    /// Tokenizer of the models without a `tokenizer.json`
+    #[serde(default)]
+    pub tokenizer: TokenizerConfig,
+    /// Tokenization cache of the input prefixes ending with a special token
+    #[serde(default)]
+    pub tokenizer_cache: TokenizerCacheConfig,
+    /// Stream of the scheduler decisions on `/admin/events`, served with the `admin` key
+    #[serde(default)]
+    pub scheduler_events: SchedulerEventsConfig,
+    /// System prompts enforced on the chat requests per API key
+    #[serde(default)]
+    pub system_prompt: SystemPromptConfig,
+    /// Concurrent requests per API key or client IP
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+    /// Limit of the concurrent requests adjusted to the time to first token
+    #[serde(default)]
+    pub adaptive_concurrency: AdaptiveConcurrencyConfig,
+    /// KV cache of the prompts pinned with the `pin` request parameter
+    #[serde(default)]
+    pub prefix_pinning: PrefixPinningConfig,
+    /// Completed chat conversations of the requests with `store`, for debugging consoles
+    #[serde(default)]
+    pub chat_store: ChatStoreConfig,
+    /// gRPC API mirroring the generate and chat endpoints, served with the `grpc` feature
+    #[serde(default)]
+    pub grpc: GrpcConfig,
+    /// TLS termination of the HTTP server, served in plain HTTP if not set
+    pub tls: Option<TlsConfig>,
+    /// Multipart uploads of the prompts of `/generate` and `/generate_stream`
+    #[serde(default)]
+    pub uploads: UploadConfig,
+    /// Limits of the expensive validation checks, run on the validation workers
+    #[serde(default)]
+    pub validation: ValidationConfig,
+    /// Input tokens of the requests per endpoint
+    #[serde(default)]
+    pub input_limits: InputLimitsConfig,
+    /// Checks of the arguments of the tool calls against the JSON schemas of their tools
+    #[serde(default)]
+    pub tool_calls: ToolCallsConfig,
+    /// Compression of the prompts over a number of tokens
+    #[serde(default)]
+    pub prompt_compression: PromptCompressionConfig,
+    /// Credentials of the `/admin` endpoints changing the state of the router
+    #[serde(default)]
+    pub admin: AdminConfig,
+}
+
+impl RouterConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, RouterConfigError> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct GenerateBatchConfig {
+    /// Maximum number of prompts in one request
+    pub max_prompts: usize,
+    /// Maximum number of prompts of one request generated at the same time
+    pub max_concurrent: usize,
+}
+
+impl Default for GenerateBatchConfig {
+    fn default() -> Self {
+        Self {
+            max_prompts: 1024,
+            max_concurrent: 32,
+        }
+    }
+}
+
+/// Token quotas per API key, the key being the bearer token of the requests
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct QuotaConfig {
+    /// Duration of the usage windows, aligned on the Unix epoch
+    pub window_secs: u64,
+    /// Quota shared by the requests without a key of `keys`, the router cannot tell their
+    /// callers apart
+    pub default: Option<Quota>,
+    pub keys: HashMap<String, Quota>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 24 * 60 * 60,
+            default: None,
+            keys: HashMap::new(),
+        }
+    }
+}
+
+/// Limits on the prompt and completion tokens of a key in a window
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// Responses carry a `x-quota-warning` header past this number of tokens
+    pub soft_limit: Option<u64>,
+    /// Requests are rejected with a 429 past this number of tokens
+    pub hard_limit: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct StreamResumeConfig {
+    /// Number of events kept per stream, 0 disables resuming streams
+    pub buffer_events: usize,
+    /// How long the events are kept after the stream ended, and how long a generation goes on
+    /// without a client before it is cancelled
+    pub ttl_secs: u64,
+}
+
+impl Default for StreamResumeConfig {
+    fn default() -> Self {
+        Self {
+            buffer_events: 0,
+            ttl_secs: 30,
+        }
+    }
+}
+
+/// Endpoint of the `rerank` strategy of `best_of`, with the API of the `/rerank` route of
+/// Text Embeddings Inference
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct BestOfConfig {
+    /// URL of the rerank endpoint, the `rerank` strategy is rejected if it is not set
+    pub rerank_url: Option<String>,
+    pub rerank_timeout_secs: u64,
+}
+
+impl Default for BestOfConfig {
+    fn default() -> Self {
+        Self {
+            rerank_url: None,
+            rerank_timeout_secs: 10,
+        }
+    }
+}
+
+/// Tokens of the fill-in-the-middle prompts, `{prefix}{inputs}{suffix}{request suffix}{middle}`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FimConfig {
+    pub prefix: String,
+    pub suffix: String,
+    pub middle: String,
+}
+
+/// Tokenizer validating the inputs, loaded from a file of the model in another format than the
+/// `tokenizer.json` of the `tokenizers` library
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct TokenizerConfig {
+    pub format: TokenizerFormat,
+    /// Tokenizer file in the model repository, or a local path, `tokenizer.model` if not set
+    pub file: Option<String>,
+    /// Regular expression splitting the text before the merges of a tiktoken file, the one of
+    /// `cl100k_base` if not set
+    pub pattern: Option<String>,
+    /// Ids of the special tokens of a tiktoken file, which only lists the mergeable tokens
+    pub special_tokens: HashMap<String, u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenizerFormat {
+    /// `tokenizer.json` of the model, or converted from its `transformers` tokenizer
+    #[default]
+    Hf,
+    /// SentencePiece model, unigram or BPE
+    #[serde(rename = "sentencepiece")]
+    SentencePiece,
+    /// BPE ranks of tiktoken, one base64 token and its rank per line
+    Tiktoken,
+}
+
+/// Limits of the tokenization cache of the input prefixes, such as the system prompts rendered
+/// by the chat template
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct TokenizerCacheConfig {
+    /// Maximum number of cached prefixes, 0 disables the cache
+    pub max_entries: usize,
+    /// Maximum number of tokens of the cached prefixes
+    pub max_tokens: usize,
+}
+
+impl Default for TokenizerCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 4096,
+            max_tokens: 1 << 20,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SchedulerEventsConfig {
+    pub enabled: bool,
+    /// Number of events buffered per client, slower clients miss the older events
+    pub capacity: usize,
+}
+
+impl Default for SchedulerEventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            capacity: 1024,
+        }
+    }
+}
+
+/// Endpoints changing the state of the router, `/admin/adapters` and `/admin/log_level`, or
+/// exposing its scheduling, `/admin/events`
+///
+/// They are only served when `api_key` is set, and only to the requests with this key. The
+/// key of `--api-key` does not give access to them.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AdminConfig {
+    /// Bearer token of the admin endpoints, disabled when not set
+    pub api_key: Option<String>,
+}
+
+/// Special tokens of the tokenizer found in the user inputs, such as `<|im_start|>` or the
+/// EOS token, checked before the router applies its chat and prompt templates
+///
+/// Pre-tokenized inputs are not checked, they must contain the special tokens of the model.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SpecialTokensConfig {
+    pub action: SpecialTokenAction,
+    /// Strings handled as special tokens in addition to the ones of the tokenizer
+    pub extra: Vec<String>,
+    /// API keys allowed to send special tokens, the key being the bearer token of the requests
+    pub trusted_keys: HashSet<String>,
+}
+
+/// System prompt policies per API key, the key being the bearer token of the requests
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SystemPromptConfig {
+    /// Policy of the keys missing from `keys`
+    pub default: Option<SystemPromptPolicy>,
+    pub keys: HashMap<String, SystemPromptPolicy>,
+}
+
+impl SystemPromptConfig {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.default.is_none() && self.keys.is_empty()
+    }
+}
+
+/// Changes to the system messages of a chat request, applied in the order of the fields
+/// before the chat template is rendered
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SystemPromptPolicy {
+    /// Maximum number of characters of every system message of the client
+    pub max_length: Option<usize>,
+    /// System prompt replacing the system messages of the client
+    pub replace: Option<String>,
+    /// System prompt put before the system prompt of the request, or added as a system
+    /// message if the request has none
+    pub prepend: Option<String>,
+}
+
+/// Concurrent generations per caller, checked before the global `--max-concurrent-requests`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ConcurrencyConfig {
+    pub by: CallerIdentity,
+    /// Limit of the callers missing from `keys`
+    pub default: Option<usize>,
+    /// Limits by API key, or by IP address when `by` is `ip`
+    pub keys: HashMap<String, usize>,
+}
+
+impl ConcurrencyConfig {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.default.is_none() && self.keys.is_empty()
+    }
+}
+
+/// Additive increase and multiplicative decrease of the limit of the concurrent requests, within
+/// `--max-concurrent-requests`, to keep the time to first token under a target
+///
+/// Requests over the limit are rejected before they are queued. Disabled unless
+/// `target_ttft_ms` is set.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AdaptiveConcurrencyConfig {
+    /// p99 time to first token, in milliseconds, above which the limit is decreased
+    pub target_ttft_ms: Option<u64>,
+    /// Number of times to first token between two adjustments of the limit
+    pub window: usize,
+    /// Factor applied to the limit when the target is exceeded
+    pub backoff: f64,
+    pub min_limit: usize,
+    /// Limit before the first adjustment, `--max-concurrent-requests` if not set
+    pub initial_limit: Option<usize>,
+}
+
+impl Default for AdaptiveConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            target_ttft_ms: None,
+            window: 100,
+            backoff: 0.9,
+            min_limit: 1,
+            initial_limit: None,
+        }
+    }
+}
+
+/// Prompts kept in the KV cache of the backend for the requests that reuse them with a
+/// `prefix_id`. Disabled unless `max_pinned_tokens` is set.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct PrefixPinningConfig {
+    /// Tokens of all the pinned prompts
+    pub max_pinned_tokens: u64,
+    /// Seconds a pinned prompt is kept after its last use
+    pub ttl_secs: u64,
+}
+
+impl Default for PrefixPinningConfig {
+    fn default() -> Self {
+        Self {
+            max_pinned_tokens: 0,
+            ttl_secs: 300,
+        }
+    }
+}
+
+/// Chat completions kept for `GET /v1/chat/completions/{id}`. Disabled unless
+/// `max_completions` is set.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ChatStoreConfig {
+    /// Number of completions kept, the oldest ones are dropped first
+    pub max_completions: usize,
+    /// Seconds a completion is kept after it ended
+    pub ttl_secs: u64,
+}
+
+impl Default for ChatStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_completions: 0,
+            ttl_secs: 3600,
+        }
+    }
+}
+
+/// gRPC API of the router, served on the hostname of the HTTP server. Disabled unless `port`
+/// is set.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct GrpcConfig {
+    pub port: Option<u16>,
+}
+
+/// Limits of the `multipart/form-data` bodies of the generate endpoints
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct UploadConfig {
+    /// Maximum size of a multipart body, in bytes
+    pub max_bytes: usize,
+    /// Size of the uploaded prompt kept in memory, the rest is spooled to a temporary file
+    pub memory_bytes: usize,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 << 20,
+            memory_bytes: 1 << 20,
+        }
+    }
+}
+
+/// Limits of the checks run on the validation workers: the tokenization, with the fetching of
+/// the images, the compilation of the JSON schemas and the decoding of the `inputs_ids`
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ValidationConfig {
+    /// Maximum number of checks waiting for or running on the workers, the requests over it
+    /// are rejected with a 429
+    pub max_pending: usize,
+    /// Time a check can take before its request is rejected with a 503, waiting included
+    pub timeout_ms: u64,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            max_pending: 1024,
+            timeout_ms: 30_000,
+        }
+    }
+}
+
+/// Limits on the input tokens per endpoint, the `--max-input-tokens` of the server applies to
+/// the endpoints without one and bounds the others
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct InputLimitsConfig {
+    /// Generate endpoints, and the other endpoints generating text but the chat completions
+    pub generate: Option<usize>,
+    /// Chat completions, whose inputs include the chat template
+    pub chat: Option<usize>,
+    /// `/v1/embeddings`, embedding models usually having smaller context windows
+    pub embeddings: Option<usize>,
+}
+
+/// Retries of the chat completions whose tool calls have arguments failing the JSON schema of
+/// their tool, the model is prompted again with the validation error
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ToolCallsConfig {
+    /// Generations retried after the first one, the arguments are not checked when 0
+    pub max_retries: u32,
+}
+
+/// Compression of the prompts over a number of input tokens, before their validation
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct PromptCompressionConfig {
+    /// Input tokens over which the prompts are compressed, disabled when not set
+    pub threshold_tokens: Option<usize>,
+    /// Strategies applied in order, until the prompt is under the threshold
+    pub strategies: Vec<CompressionStrategy>,
+    /// Last chat messages kept by `summarize_turns`, the system messages are always kept
+    pub keep_turns: usize,
+    /// Maximum tokens of the summary of the older chat messages
+    pub summary_max_tokens: u32,
+}
+
+impl Default for PromptCompressionConfig {
+    fn default() -> Self {
+        Self {
+            threshold_tokens: None,
+            strategies: vec![
+                CompressionStrategy::Whitespace,
+                CompressionStrategy::DedupExamples,
+            ],
+            keep_turns: 4,
+            summary_max_tokens: 256,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionStrategy {
+    /// Collapse the repeated spaces and blank lines, the indentation is kept
+    Whitespace,
+    /// Remove the blocks of text repeated in the prompt, and the repeated pairs of user and
+    /// assistant messages of the chats, the last block and message are kept
+    DedupExamples,
+    /// Replace the older chat messages by a summary generated by the model
+    SummarizeTurns,
+}
+
+/// Certificate of the HTTP server, and certificate authorities of the clients when they must
+/// authenticate with a certificate
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain of the server
+    pub cert_path: PathBuf,
+    /// PEM file with the private key of the server
+    pub key_path: PathBuf,
+    /// PEM file with the certificate authorities of the clients, connections without a client
+    /// certificate signed by one of them are rejected when set
+    #[serde(default)]
+    pub client_ca_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CallerIdentity {
+    /// The bearer token of the requests, requests without one are not limited
+    #[default]
+    ApiKey,
+    /// The IP address of the peer of the connection
+    Ip,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecialTokenAction {
+    /// Special tokens are left in the inputs
+    #[default]
+    Allow,
+    /// Special tokens are removed from the inputs
+    Strip,
+    /// Requests with special tokens in their inputs are rejected
+    Reject,
+}
+
+impl SpecialTokenAction {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            SpecialTokenAction::Allow => "allow",
+            SpecialTokenAction::Strip => "strip",
+            SpecialTokenAction::Reject => "reject",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RouterConfigError {
+    #[error("could not read router config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid router config: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Generation parameters applied when a request selects the preset
+///
+/// Parameters explicitly set on the request always take precedence over the preset.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    pub temperature: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    /// Enable sampling. A preset cannot disable sampling requested by the client.
+    #[serde(default)]
+    pub do_sample: bool,
+    /// Used when the request does not set any stop sequence
+    #[serde(default)]
+    pub stop: Vec<String>,
+    pub truncate: Option<usize>,
+    /// Enable watermarking. A preset cannot disable watermarking requested by the client.
+    #[serde(default)]
+    pub watermark: bool,
+    pub top_n_tokens: Option<u32>,
+    pub(crate) grammar: Option<GrammarType>,
+    pub adapter_id: Option<String>,
+    pub stop_on_repetition: Option<RepetitionStop>,
+}
+
+impl Preset {
+    /// Fill the parameters that were not set on the request
+    pub(crate) fn merge_into(&self, parameters: &mut GenerateParameters) {
+        let preset = self.clone();
+        parameters.temperature = parameters.temperature.or(preset.temperature);
+        parameters.repetition_penalty = parameters.repetition_penalty.or(preset.repetition_penalty);
+        parameters.frequency_penalty = parameters.frequency_penalty.or(preset.frequency_penalty);
+        parameters.top_k = parameters.top_k.or(preset.top_k);
+        parameters.top_p = parameters.top_p.or(preset.top_p);
+        parameters.typical_p = parameters.typical_p.or(preset.typical_p);
+        parameters.do_sample |= preset.do_sample;
+        if parameters.stop.is_empty() {
+            parameters.stop = preset.stop;
+        }
+        parameters.truncate = parameters.truncate.or(preset.truncate);
+        parameters.watermark |= preset.watermark;
+        parameters.top_n_tokens = parameters.top_n_tokens.or(preset.top_n_tokens);
+        parameters.grammar = parameters.grammar.take().or(preset.grammar);
+        parameters.adapter_id = parameters.adapter_id.take().or(preset.adapter_id);
+        parameters.stop_on_repetition = parameters.stop_on_repetition.or(preset.stop_on_repetition);
+    }
+}
+
+/// Generation parameters of a model applied when a request omits them
+///
+/// Presets and parameters set on the request take precedence over the model defaults.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ModelDefaults {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.7)]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.95)]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 512)]
+    pub max_new_tokens: Option<u32>,
+    /// Used when the request does not set any stop sequence
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["</s>"]))]
+    pub stop: Vec<String>,
+    /// Tokens ending every generation in addition to the end of sequence token of the
+    /// tokenizer, such as the end of turn token of the chat template
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["<|eot_id|>"]))]
+    pub stop_tokens: Vec<VocabToken>,
+}
+
+impl ModelDefaults {
+    /// Fill the parameters that were not set on the request
+    pub(crate) fn merge_into(&self, parameters: &mut GenerateParameters) {
+        parameters.temperature = parameters.temperature.or(self.temperature);
+        parameters.top_p = parameters.top_p.or(self.top_p);
+        parameters.max_new_tokens = parameters.max_new_tokens.or(self.max_new_tokens);
+        if parameters.stop.is_empty() {
+            parameters.stop = self.stop.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_parameters;
+
+    #[test]
+    fn test_router_config_presets() {
+        let config: RouterConfig = serde_json::from_str(
+            r#"{"presets": {"precise": {"temperature": 0.2, "top_k": 10, "stop": ["\n"]}}}"#,
+        )
+        .unwrap();
+        let preset = &config.presets["precise"];
+        assert_eq!(preset.temperature, Some(0.2));
+        assert_eq!(preset.top_k, Some(10));
+
+        let unknown: Result<RouterConfig, _> =
+            serde_json::from_str(r#"{"presets": {"precise": {"temprature": 0.2}}}"#);
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn test_router_config_generate_batch() {
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"generate_batch": {"max_prompts": 10}}"#).unwrap();
+        assert_eq!(config.generate_batch.max_prompts, 10);
+        assert_eq!(config.generate_batch.max_concurrent, 32);
+    }
+
+    #[test]
+    fn test_router_config_quotas() {
+        let config: RouterConfig = serde_json::from_str(
+            r#"{"quotas": {"keys": {"team-a": {"soft_limit": 1000, "hard_limit": 2000}}}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.quotas.window_secs, 86400);
+        assert!(config.quotas.default.is_none());
+        let quota = &config.quotas.keys["team-a"];
+        assert_eq!(
+            (quota.soft_limit, quota.hard_limit),
+            (Some(1000), Some(2000))
+        );
+    }
+
+    #[test]
+    fn test_router_config_stream_resume() {
+        let config = RouterConfig::default();
+        assert_eq!(config.stream_resume.buffer_events, 0);
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"stream_resume": {"buffer_events": 256}}"#).unwrap();
+        assert_eq!(config.stream_resume.buffer_events, 256);
+        assert_eq!(config.stream_resume.ttl_secs, 30);
+    }
+
+    #[test]
+    fn test_router_config_special_tokens() {
+        let config = RouterConfig::default();
+        assert_eq!(config.special_tokens.action, SpecialTokenAction::Allow);
+        let config: RouterConfig = serde_json::from_str(
+            r#"{"special_tokens": {"action": "reject", "trusted_keys": ["internal"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.special_tokens.action, SpecialTokenAction::Reject);
+        assert!(config.special_tokens.trusted_keys.contains("internal"));
+        assert!(config.special_tokens.extra.is_empty());
+    }
+
+    #[test]
+    fn test_router_config_concurrency() {
+        assert!(RouterConfig::default().concurrency.is_empty());
+        let config: RouterConfig = serde_json::from_str(
+            r#"{"concurrency": {"by": "ip", "default": 4, "keys": {"10.0.0.1": 16}}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.concurrency.by, CallerIdentity::Ip);
+        assert_eq!(config.concurrency.default, Some(4));
+        assert_eq!(config.concurrency.keys["10.0.0.1"], 16);
+
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"adaptive_concurrency": {"target_ttft_ms": 500}}"#).unwrap();
+        assert_eq!(config.adaptive_concurrency.target_ttft_ms, Some(500));
+        assert_eq!(config.adaptive_concurrency.window, 100);
+        assert_eq!(config.adaptive_concurrency.backoff, 0.9);
+    }
+
+    #[test]
+    fn test_router_config_prefix_pinning() {
+        assert_eq!(RouterConfig::default().prefix_pinning.max_pinned_tokens, 0);
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"prefix_pinning": {"max_pinned_tokens": 65536}}"#).unwrap();
+        assert_eq!(config.prefix_pinning.max_pinned_tokens, 65536);
+        assert_eq!(config.prefix_pinning.ttl_secs, 300);
+        assert!(serde_json::from_str::<RouterConfig>(r#"{"prefix_pinning": {"ttl": 1}}"#).is_err());
+    }
+
+    #[test]
+    fn test_router_config_chat_store() {
+        assert_eq!(RouterConfig::default().chat_store.max_completions, 0);
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"chat_store": {"max_completions": 1000}}"#).unwrap();
+        assert_eq!(config.chat_store.max_completions, 1000);
+        assert_eq!(config.chat_store.ttl_secs, 3600);
+    }
+
+    #[test]
+    fn test_router_config_grpc() {
+        assert_eq!(RouterConfig::default().grpc.port, None);
+        let config: RouterConfig = serde_json::from_str(r#"{"grpc": {"port": 8033}}"#).unwrap();
+        assert_eq!(config.grpc.port, Some(8033));
+        assert!(serde_json::from_str::<RouterConfig>(r#"{"grpc": {"host": "::"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_router_config_validation() {
+        assert_eq!(RouterConfig::default().validation.max_pending, 1024);
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"validation": {"timeout_ms": 5000}}"#).unwrap();
+        assert_eq!(config.validation.timeout_ms, 5000);
+        assert_eq!(config.validation.max_pending, 1024);
+    }
+
+    #[test]
+    fn test_router_config_input_limits() {
+        assert!(RouterConfig::default().input_limits.chat.is_none());
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"input_limits": {"chat": 3072, "embeddings": 512}}"#).unwrap();
+        assert_eq!(config.input_limits.chat, Some(3072));
+        assert_eq!(config.input_limits.embeddings, Some(512));
+        assert!(config.input_limits.generate.is_none());
+    }
+
+    #[test]
+    fn test_router_config_tool_calls() {
+        assert_eq!(RouterConfig::default().tool_calls.max_retries, 0);
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"tool_calls": {"max_retries": 2}}"#).unwrap();
+        assert_eq!(config.tool_calls.max_retries, 2);
+    }
+
+    #[test]
+    fn test_router_config_prompt_compression() {
+        let config = RouterConfig::default().prompt_compression;
+        assert!(config.threshold_tokens.is_none());
+        assert_eq!(config.strategies.len(), 2);
+        let config: RouterConfig = serde_json::from_str(
+            r#"{"prompt_compression": {"threshold_tokens": 4096, "strategies": ["whitespace", "summarize_turns"], "keep_turns": 2}}"#,
+        )
+        .unwrap();
+        let config = config.prompt_compression;
+        assert_eq!(config.threshold_tokens, Some(4096));
+        assert_eq!(
+            config.strategies,
+            [
+                CompressionStrategy::Whitespace,
+                CompressionStrategy::SummarizeTurns
+            ]
+        );
+        assert_eq!(config.keep_turns, 2);
+        assert_eq!(config.summary_max_tokens, 256);
+        assert!(serde_json::from_str::<RouterConfig>(
+            r#"{"prompt_compression": {"strategies": ["truncate"]}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_router_config_admin() {
+        assert!(RouterConfig::default().admin.api_key.is_none());
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"admin": {"api_key": "secret"}}"#).unwrap();
+        assert_eq!(config.admin.api_key.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_router_config_tls() {
+        assert!(RouterConfig::default().tls.is_none());
+        let config: RouterConfig = serde_json::from_str(
+            r#"{"tls": {"cert_path": "/certs/server.pem", "key_path": "/certs/server.key"}}"#,
+        )
+        .unwrap();
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.cert_path, PathBuf::from("/certs/server.pem"));
+        assert_eq!(tls.client_ca_path, None);
+        // The certificate and its key go together
+        assert!(serde_json::from_str::<RouterConfig>(
+            r#"{"tls": {"cert_path": "/certs/server.pem"}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_router_config_tokenizer() {
+        assert_eq!(
+            RouterConfig::default().tokenizer.format,
+            TokenizerFormat::Hf
+        );
+        let config: RouterConfig = serde_json::from_str(
+            r#"{"tokenizer": {"format": "tiktoken", "special_tokens": {"<|endoftext|>": 100257}}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.tokenizer.format, TokenizerFormat::Tiktoken);
+        assert_eq!(config.tokenizer.special_tokens["<|endoftext|>"], 100257);
+        let config: RouterConfig =
+            serde_json::from_str(r#"{"tokenizer": {"format": "sentencepiece"}}"#).unwrap();
+        assert_eq!(config.tokenizer.format, TokenizerFormat::SentencePiece);
+    }
+
+    #[test]
+    fn test_preset_merge() {
+        let preset = Preset {
+            temperature: Some(0.2),
+            top_k: Some(10),
+            stop: vec!["\n".to_string()],
+            ..Default::default()
+        };
+
+        let mut parameters = GenerateParameters {
+            temperature: Some(0.9),
+            ..default_parameters()
+        };
+        preset.merge_into(&mut parameters);
+        // Explicit parameters win
+        assert_eq!(parameters.temperature, Some(0.9));
+        assert_eq!(parameters.top_k, Some(10));
+        assert_eq!(parameters.stop, vec!["\n".to_string()]);
+    }
+
+    #[test]
+    fn test_model_defaults_merge() {
+        let config: RouterConfig = serde_json::from_str(
+            r#"{"default_parameters": {"my/model": {"temperature": 0.7, "max_new_tokens": 512, "stop_tokens": ["<|eot_id|>", 128001]}}}"#,
+        )
+        .unwrap();
+        let defaults = &config.default_parameters["my/model"];
+        assert_eq!(
+            defaults.stop_tokens,
+            vec![
+                VocabToken::Text("<|eot_id|>".to_string()),
+                VocabToken::Id(128001)
+            ]
+        );
+
+        let mut parameters = GenerateParameters {
+            max_new_tokens: Some(16),
+            ..default_parameters()
+        };
+        defaults.merge_into(&mut parameters);
+        assert_eq!(parameters.temperature, Some(0.7));
+        assert_eq!(parameters.max_new_tokens, Some(16));
+        assert!(parameters.stop.is_empty());
+    }
+}
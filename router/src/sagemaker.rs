@@ -1,5 +1,9 @@
+use crate::chat_store::ChatStore;
 use crate::infer::Infer;
+use crate::json_body::JsonBody;
+use crate::router_config::ToolCallsConfig;
 use crate::server::{chat_completions, compat_generate, completions, ComputeType};
+use crate::stream_resume::StreamBuffers;
 use crate::{
     ChatCompletion, ChatCompletionChunk, ChatRequest, Chunk, CompatGenerateRequest,
     CompletionFinal, CompletionRequest, ErrorResponse, GenerateResponse, Info, StreamResponse,
@@ -21,7 +25,7 @@ pub(crate) enum SagemakerRequest {
 }
 
 // Used for OpenAPI specs
-#[allow(dead_code)]
+#[allow(dead_code, clippy::large_enum_variant)]
 #[derive(Serialize, ToSchema)]
 #[serde(untagged)]
 pub(crate) enum SagemakerResponse {
@@ -52,31 +56,51 @@ content(
 ("application/json" = SagemakerResponse),
 ("text/event-stream" = SagemakerStreamResponse),
 )),
-(status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation", "error_type": "generation"})),
+(status = 502, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": {"code": "generation_failed", "type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+example = json ! ({"error": {"code": "queue_full", "type": "overloaded_server", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error", "error_type": "validation"})),
-(status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation", "error_type": "incomplete_generation"})),
+example = json ! ({"error": {"code": "invalid_max_new_tokens", "type": "validation", "message": "Input validation error: `max_new_tokens` must be strictly positive", "param": "max_new_tokens"}})),
 )
 )]
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip_all)]
 pub(crate) async fn sagemaker_compatibility(
     default_return_full_text: Extension<bool>,
     infer: Extension<Infer>,
     compute_type: Extension<ComputeType>,
     info: Extension<Info>,
-    Json(req): Json<SagemakerRequest>,
+    stream_buffers: Extension<StreamBuffers>,
+    chat_store: Extension<ChatStore>,
+    tool_calls_config: Extension<ToolCallsConfig>,
+    JsonBody(req): JsonBody<SagemakerRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     match req {
         SagemakerRequest::Generate(req) => {
-            compat_generate(default_return_full_text, infer, compute_type, Json(req)).await
+            compat_generate(
+                default_return_full_text,
+                infer,
+                compute_type,
+                stream_buffers,
+                JsonBody(req),
+            )
+            .await
+        }
+        SagemakerRequest::Chat(req) => {
+            chat_completions(
+                infer,
+                compute_type,
+                info,
+                stream_buffers,
+                chat_store,
+                tool_calls_config,
+                JsonBody(req),
+            )
+            .await
         }
-        SagemakerRequest::Chat(req) => chat_completions(infer, compute_type, info, Json(req)).await,
         SagemakerRequest::Completion(req) => {
-            completions(infer, compute_type, info, Json(req)).await
+            completions(infer, compute_type, info, stream_buffers, JsonBody(req)).await
         }
     }
 }
@@ -0,0 +1,131 @@
+/// Debug events of the scheduler, streamed on `/admin/events`
+///
+/// The backends report why queued requests were left out of a batch, why new requests were
+/// added to the running batch, and the preemptions of running requests. The events are only
+/// kept when enabled with `scheduler_events` in the router config, and only for the clients
+/// connected at the time. They are served with the key of the admin endpoints.
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+static SENDER: OnceLock<broadcast::Sender<SchedulerEvent>> = OnceLock::new();
+
+/// Limit of the scheduler that kept a queued request out of the next batch
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The tokens of the whole generation exceed the token budget of the batch
+    TokenBudget,
+    /// The prompt exceeds the prefill token budget of the batch
+    PrefillBudget,
+    /// The KV cache has no free blocks for the generation
+    KvCacheBlocks,
+    /// The batch reached its maximum size
+    BatchSize,
+    /// Fewer requests fit than the minimum size of a batch added to the running batch
+    MinBatchSize,
+    /// The shards are compiling the grammar of the request
+    GrammarCompiling,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SchedulerEvent {
+    /// A queued request was not added to the batch
+    Skipped {
+        /// Id of the request in the queue
+        id: u64,
+        reason: SkipReason,
+        /// The request keeps its place in the queue and the next requests are considered
+        skipped_over: bool,
+    },
+    /// New requests were added to the running batch
+    Concat {
+        /// `backpressure`, `chunking`, `wait_exceeded` or `finished`, as the labels of the
+        /// `tgi_batch_concat` metric
+        reason: &'static str,
+        new_requests: usize,
+        running_requests: u32,
+    },
+    /// Running requests were preempted to free KV cache blocks
+    Preemption {
+        missing_blocks: u32,
+        swapped_blocks: u32,
+        evicted_blocks: u32,
+    },
+}
+
+/// Keep the events for the connected clients, with `capacity` events buffered per client
+pub(crate) fn enable(capacity: usize) {
+    SENDER.get_or_init(|| broadcast::channel(capacity.max(1)).0);
+}
+
+pub(crate) fn enabled() -> bool {
+    SENDER.get().is_some()
+}
+
+/// Send an event to the connected clients, if the events are enabled
+pub fn emit(event: SchedulerEvent) {
+    if let Some(sender) = SENDER.get() {
+        // Fails when no client is connected
+        let _ = sender.send(event);
+    }
+}
+
+/// Stream the decisions of the scheduler
+///
+/// Clients falling behind receive a `lagged` event with the number of events they missed.
+/// Served when `admin.api_key` is set in the router config, to the requests with this key.
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/admin/events",
+responses(
+(status = 200, description = "Decisions of the scheduler", body = String, content_type = "text/event-stream"),
+(status = 401, description = "Missing or invalid admin key"),
+(status = 404, description = "Scheduler events are disabled"),
+)
+)]
+pub(crate) async fn scheduler_events() -> Response {
+    let Some(sender) = SENDER.get() else {
+        return axum::http::StatusCode::NOT_FOUND.into_response();
+    };
+    let mut receiver = sender.subscribe();
+    let events = async_stream::stream! {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => Event::default().json_data(event).unwrap(),
+                Err(RecvError::Lagged(missed)) => {
+                    Event::default().event("lagged").data(missed.to_string())
+                }
+                Err(RecvError::Closed) => break,
+            };
+            yield Ok::<Event, Infallible>(event);
+        }
+    };
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scheduler_event_serialization() {
+        let event = SchedulerEvent::Skipped {
+            id: 1,
+            reason: SkipReason::PrefillBudget,
+            skipped_over: false,
+        };
+        assert_eq!(
+            serde_json::to_value(event).unwrap(),
+            serde_json::json!({"event": "skipped", "id": 1, "reason": "prefill_budget", "skipped_over": false})
+        );
+    }
+}
@@ -1,26 +1,71 @@
+use crate::chat_store::{
+    get_chat_completion, ChatStore, StoredChatCompletion, __path_get_chat_completion,
+};
+use crate::concurrency::{identify_caller_limit, AdaptiveLimit, ConcurrencyLimits};
 /// HTTP Server logic
 use crate::config::Config;
+use crate::generate_batch::{
+    generate_batch, GenerateBatchRequest, GenerateBatchResponse, __path_generate_batch,
+};
+use crate::infer::best_of::Reranker;
+use crate::infer::fim::FimTemplate;
+use crate::infer::prompt_template::PromptTemplates;
+use crate::infer::special_tokens::{identify_trusted_caller, SpecialTokenGuard};
+use crate::infer::system_prompt::{identify_system_prompt, SystemPrompts};
+use crate::infer::tool_calls::{
+    parse_tool_calls, ToolCallRetries, ToolCallStream, ToolStreamDelta,
+};
 use crate::infer::tool_grammar::ToolGrammar;
-use crate::infer::{Backend, Infer, InferError, InferResponse, InferStreamResponse};
+use crate::infer::{
+    Backend, BackendCapabilities, Infer, InferError, InferResponse, InferStreamResponse,
+};
+use crate::json_body::JsonBody;
 #[cfg(feature = "kserve")]
 use crate::kserve::{
     kerve_server_metadata, kserve_health_live, kserve_health_ready, kserve_model_infer,
     kserve_model_metadata, kserve_model_metadata_ready,
 };
+use crate::logging::{
+    get_log_level, set_log_level, LogLevel, __path_get_log_level, __path_set_log_level,
+};
+use crate::prefix_pins::{
+    get_prefixes, unpin_prefix, PrefixPinsResponse, __path_get_prefixes, __path_unpin_prefix,
+};
+use crate::rag::{rag, RagDocument, RagRequest, RagResponse, __path_rag};
+use crate::requests::{assign_request_id, cancel_request, RequestScope, __path_cancel_request};
+use crate::router_config::{
+    CompressionStrategy, ModelDefaults, RouterConfig, RouterConfigError, TokenizerFormat,
+    ToolCallsConfig,
+};
 use crate::sagemaker::{
     sagemaker_compatibility, SagemakerRequest, SagemakerResponse, SagemakerStreamResponse,
     __path_sagemaker_compatibility,
 };
+use crate::scheduler_events::{self, __path_scheduler_events, scheduler_events};
+use crate::stream_resume::{resume_stream, StreamBuffers, __path_resume_stream};
+use crate::tls::{self, TlsError};
+use crate::tokenizer_cache::TokenizerCache;
+use crate::tokenizer_formats::{self, TokenizerFormatError};
+use crate::upload::GenerateBody;
+use crate::usage::{
+    bearer_token, enforce_quota, get_usage, UsageResponse, UsageTracker, __path_get_usage,
+};
 use crate::validation::ValidationError;
 use crate::vertex::vertex_compatibility;
 use crate::ChatTokenizeResponse;
 use crate::{
-    usage_stats, BestOfSequence, Details, ErrorResponse, FinishReason, FunctionName,
-    GenerateParameters, GenerateRequest, GenerateResponse, GrammarType, HubModelInfo,
-    HubProcessorConfig, HubTokenizerConfig, Info, Message, MessageChunk, MessageContent,
-    OutputMessage, PrefillToken, SimpleToken, StreamDetails, StreamOptions, StreamResponse,
+    default_parameters, default_tool_prompt, requested_adapter, ChatTemplateRenderRequest,
+    ChatTemplateRenderResponse,
+};
+use crate::{
+    usage_stats, AdaptersResponse, BackendMetadata, BatchingLimits, BestOfSequence, BestOfStrategy,
+    Details, EffectiveParameters, Endpoint, ErrorDetails, ErrorResponse, FinishReason,
+    FunctionName, GenerateParameters, GenerateRequest, GenerateResponse, GrammarType, HubModelInfo,
+    HubProcessorConfig, HubTokenizerConfig, Info, LoadAdapterRequest, Message, MessageChunk,
+    MessageContent, OutputMessage, PrefillToken, PromptCompression, QueueUpdate, RepetitionStop,
+    ShardInfo, SimpleToken, StreamBudget, StreamDetails, StreamOptions, StreamResponse,
     TextMessage, Token, TokenizeResponse, Tokenizer, ToolCallDelta, ToolCallMessage, Url, Usage,
-    Validation,
+    Validation, VocabToken,
 };
 use crate::{
     ChatCompletion, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionComplete,
@@ -28,14 +73,17 @@ use crate::{
     ChatRequest, Chunk, CompatGenerateRequest, Completion, CompletionComplete, CompletionFinal,
     CompletionRequest, CompletionType, DeltaToolCall, Function, Prompt, Tool,
 };
+use crate::{
+    Embedding, EmbeddingRequest, EmbeddingResponse, EmbeddingUsage, ScoreRequest, ScoreResponse,
+};
 use crate::{FunctionDefinition, HubPreprocessorConfig, ToolCall, ToolChoice, ToolType};
 use crate::{ModelInfo, ModelsInfo};
 use async_stream::__private::AsyncStream;
-use axum::extract::Extension;
+use axum::extract::{Extension, State};
 use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
-use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::sse::Event;
 use axum::response::{IntoResponse, Response};
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
 use axum::{http, Json, Router};
 use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
 use futures::stream::StreamExt;
@@ -48,13 +96,14 @@ use http::header::AUTHORIZATION;
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use pyo3::prelude::*;
 use pyo3::types::IntoPyDict;
-use regex::Regex;
-use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::Infallible;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::BufReader;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::select;
 use tokio::signal;
@@ -112,22 +161,21 @@ content(
 ("application/json" = GenerateResponse),
 ("text/event-stream" = StreamResponse),
 )),
-(status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+(status = 502, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": {"code": "generation_failed", "type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"code": "queue_full", "type": "overloaded_server", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
-(status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"code": "invalid_max_new_tokens", "type": "validation", "message": "Input validation error: `max_new_tokens` must be strictly positive", "param": "max_new_tokens"}})),
 )
 )]
-#[instrument(skip(infer, req))]
+#[instrument(skip(infer, stream_buffers, req))]
 pub(crate) async fn compat_generate(
     Extension(default_return_full_text): Extension<bool>,
     infer: Extension<Infer>,
     compute_type: Extension<ComputeType>,
-    Json(mut req): Json<CompatGenerateRequest>,
+    stream_buffers: Extension<StreamBuffers>,
+    JsonBody(mut req): JsonBody<CompatGenerateRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     // default return_full_text given the pipeline_tag
     if req.parameters.return_full_text.is_none() {
@@ -136,11 +184,16 @@ pub(crate) async fn compat_generate(
 
     // switch on stream
     if req.stream {
-        Ok(generate_stream(infer, compute_type, Json(req.into()))
-            .await
-            .into_response())
+        Ok(generate_stream(
+            infer,
+            compute_type,
+            stream_buffers,
+            GenerateBody(req.into()),
+        )
+        .await)
     } else {
-        let (headers, Json(generation)) = generate(infer, compute_type, Json(req.into())).await?;
+        let (headers, Json(generation)) =
+            generate(infer, compute_type, GenerateBody(req.into())).await?;
         // wrap generation inside a Vec to match api-inference
         Ok((headers, Json(vec![generation])).into_response())
     }
@@ -153,9 +206,54 @@ tag = "Text Generation Inference",
 path = "/info",
 responses((status = 200, description = "Served model info", body = Info))
 )]
-#[instrument]
-async fn get_model_info(info: Extension<Info>) -> Json<Info> {
-    Json(info.0)
+#[instrument(skip(infer))]
+async fn get_model_info(info: Extension<Info>, Extension(infer): Extension<Infer>) -> Json<Info> {
+    // The adapters loaded on demand are added to the metadata of the backend
+    let mut info = info.0;
+    info.backend_metadata = infer.backend_metadata();
+    Json(info)
+}
+
+/// Reject the requests to the admin endpoints without the admin key of the router config
+async fn authorize_admin(
+    State(api_key): State<Arc<str>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Result<Response, StatusCode> {
+    if bearer_token(request.headers()) != Some(&*api_key) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(next.run(request).await)
+}
+
+/// Load a LoRA adapter, selected by the next requests with its `adapter_id`
+///
+/// Served when `admin.api_key` is set in the router config, to the requests with this key.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/admin/adapters",
+request_body = LoadAdapterRequest,
+responses(
+(status = 200, description = "Loaded adapters", body = AdaptersResponse),
+(status = 401, description = "Missing or invalid admin key"),
+(status = 502, description = "The adapter could not be loaded", body = ErrorResponse,
+example = json ! ({"error": {"code": "adapter_load_failed", "type": "adapter_loading", "message": "Adapter loading failed: No adapter weights found for adapter 'predibase/customer_support'", "param": "adapter_id"}})),
+(status = 501, description = "The backend cannot load adapters", body = ErrorResponse,
+example = json ! ({"error": {"code": "not_supported", "type": "not_supported", "message": "The backend does not support adapter loading"}})),
+)
+)]
+#[instrument(skip(infer))]
+async fn load_adapter(
+    Extension(infer): Extension<Infer>,
+    Json(request): Json<LoadAdapterRequest>,
+) -> Result<Json<AdaptersResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let adapter_id = request.adapter_id.clone();
+    infer.load_adapter(request).await?;
+    tracing::info!("Loaded adapter {adapter_id}");
+    Ok(Json(AdaptersResponse {
+        adapters: infer.backend_metadata().adapters,
+    }))
 }
 
 #[utoipa::path(
@@ -190,7 +288,7 @@ async fn openai_get_model_info(info: Extension<Info>) -> Json<ModelsInfo> {
 )]
 async fn get_chat_tokenize(
     Extension(infer): Extension<Infer>,
-    Json(chat): Json<ChatRequest>,
+    JsonBody(chat): JsonBody<ChatRequest>,
 ) -> Result<(HeaderMap, Json<ChatTokenizeResponse>), (StatusCode, Json<ErrorResponse>)> {
     metrics::counter!("tgi_request_count").increment(1);
 
@@ -207,6 +305,61 @@ async fn get_chat_tokenize(
     Ok((HeaderMap::new(), Json(resp)))
 }
 
+/// Render a chat request with the chat template of the model, without generating
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/chat_template/render",
+request_body = ChatTemplateRenderRequest,
+responses(
+(status = 200, description = "Rendered prompt", body = ChatTemplateRenderResponse),
+(status = 422, description = "Template error", body = ErrorResponse,
+example = json ! ({"error": {"code": "template_error", "type": "template_error", "message": "Template error: template not found"}})),
+)
+)]
+#[instrument(skip_all)]
+async fn render_chat_template(
+    Extension(infer): Extension<Infer>,
+    JsonBody(request): JsonBody<ChatTemplateRenderRequest>,
+) -> Result<Json<ChatTemplateRenderResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let ChatTemplateRenderRequest {
+        messages,
+        tools,
+        tool_prompt,
+        tool_choice,
+        guideline,
+        add_generation_prompt,
+    } = request;
+    let tools_and_prompt = match tools {
+        Some(tools) => {
+            let (tools, _) = ToolGrammar::apply(tools, tool_choice, false)?;
+            let tool_prompt = tool_prompt
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(default_tool_prompt);
+            Some((tools, tool_prompt))
+        }
+        None => None,
+    };
+    let prompt =
+        infer.render_chat_template(guideline, messages, tools_and_prompt, add_generation_prompt)?;
+    let encoding = infer
+        .tokenize(GenerateRequest {
+            inputs: prompt.clone(),
+            parameters: default_parameters(),
+            template: None,
+            variables: None,
+            inputs_ids: None,
+            suffix: None,
+            add_special_tokens: false,
+            endpoint: Endpoint::Generate,
+        })
+        .await?;
+    Ok(Json(ChatTemplateRenderResponse {
+        prompt,
+        tokens: encoding.len(),
+    }))
+}
+
 #[utoipa::path(
 get,
 tag = "Text Generation Inference",
@@ -214,7 +367,7 @@ path = "/health",
 responses(
 (status = 200, description = "Everything is working fine"),
 (status = 503, description = "Text generation inference is down", body = ErrorResponse,
-example = json ! ({"error": "unhealthy", "error_type": "healthcheck"})),
+example = json ! ({"error": {"code": "unhealthy", "type": "healthcheck", "message": "unhealthy"}})),
 )
 )]
 #[instrument(skip(infer))]
@@ -224,10 +377,7 @@ async fn health(infer: Extension<Infer>) -> Result<(), (StatusCode, Json<ErrorRe
         true => Ok(()),
         false => Err((
             StatusCode::SERVICE_UNAVAILABLE,
-            Json(ErrorResponse {
-                error: "unhealthy".to_string(),
-                error_type: "healthcheck".to_string(),
-            }),
+            Json(ErrorResponse::new("unhealthy", "healthcheck", "unhealthy")),
         )),
     }
 }
@@ -240,14 +390,12 @@ path = "/generate",
 request_body = GenerateRequest,
 responses(
 (status = 200, description = "Generated Text", body = GenerateResponse),
-(status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+(status = 502, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": {"code": "generation_failed", "type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"code": "queue_full", "type": "overloaded_server", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
-(status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"code": "invalid_max_new_tokens", "type": "validation", "message": "Input validation error: `max_new_tokens` must be strictly positive", "param": "max_new_tokens"}})),
 )
 )]
 #[instrument(
@@ -265,7 +413,7 @@ seed,
 async fn generate(
     infer: Extension<Infer>,
     Extension(ComputeType(compute_type)): Extension<ComputeType>,
-    Json(req): Json<GenerateRequest>,
+    GenerateBody(req): GenerateBody,
 ) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     generate_internal(infer, ComputeType(compute_type), Json(req), span).await
@@ -274,12 +422,16 @@ async fn generate(
 pub(crate) async fn generate_internal(
     infer: Extension<Infer>,
     ComputeType(compute_type): ComputeType,
-    Json(req): Json<GenerateRequest>,
+    Json(mut req): Json<GenerateRequest>,
     span: tracing::Span,
 ) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
     let start_time = Instant::now();
     metrics::counter!("tgi_request_count").increment(1);
 
+    infer.check_special_tokens(&mut req)?;
+    infer.apply_prompt_template(&mut req)?;
+    let compression = infer.compress_prompt(&mut req).await?;
+
     // Do not long ultra long inputs, like image payloads.
     tracing::debug!(
         "Input: {}",
@@ -305,6 +457,7 @@ pub(crate) async fn generate_internal(
 
     // Token details
     let input_length = response._input_length;
+    let error = response.error.map(|err| ErrorResponse::from(err).error);
     let details = match details {
         true => {
             // convert best_of_responses
@@ -331,6 +484,7 @@ pub(crate) async fn generate_internal(
                     .collect()
             });
 
+            let speculative_tokens_accepted = response.generated_text.speculative_tokens_accepted();
             Some(Details {
                 finish_reason: response.generated_text.finish_reason,
                 generated_tokens: response.generated_text.generated_tokens,
@@ -339,6 +493,11 @@ pub(crate) async fn generate_internal(
                 seed: response.generated_text.seed,
                 best_of_sequences,
                 top_tokens: response.top_tokens,
+                backend: response.generated_text.backend.map(String::from),
+                steps: response.generated_text.steps,
+                speculative_tokens_accepted,
+                effective_parameters: response.parameters,
+                compression,
             })
         }
         false => None,
@@ -397,7 +556,10 @@ pub(crate) async fn generate_internal(
     );
 
     // Metrics
-    metrics::counter!("tgi_request_success").increment(1);
+    // A partial generation was already counted as a failure by the backend
+    if error.is_none() {
+        metrics::counter!("tgi_request_success").increment(1);
+    }
     metrics::histogram!("tgi_request_duration").record(total_time.as_secs_f64());
     metrics::histogram!("tgi_request_validation_duration").record(validation_time.as_secs_f64());
     metrics::histogram!("tgi_request_queue_duration").record(queue_time.as_secs_f64());
@@ -414,11 +576,16 @@ pub(crate) async fn generate_internal(
     }
 
     tracing::debug!("Output: {}", output_text);
-    tracing::info!("Success");
+    match &error {
+        Some(error) => tracing::info!("Partial success: {}", error.message),
+        None => tracing::info!("Success"),
+    }
 
     let response = GenerateResponse {
         generated_text: output_text,
         details,
+        error,
+        prefix_id: response.prefix_id,
     };
     Ok((headers, Json(response)))
 }
@@ -432,17 +599,14 @@ request_body = GenerateRequest,
 responses(
 (status = 200, description = "Generated Text", body = StreamResponse,
 content_type = "text/event-stream"),
-(status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"}),
+(status = 502, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": {"code": "generation_failed", "type": "generation", "message": "Request failed during generation"}}),
 content_type = "text/event-stream"),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"}),
+example = json ! ({"error": {"code": "queue_full", "type": "overloaded_server", "message": "Model is overloaded"}}),
 content_type = "text/event-stream"),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"}),
-content_type = "text/event-stream"),
-(status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"}),
+example = json ! ({"error": {"code": "invalid_max_new_tokens", "type": "validation", "message": "Input validation error: `max_new_tokens` must be strictly positive", "param": "max_new_tokens"}}),
 content_type = "text/event-stream"),
 )
 )]
@@ -461,11 +625,9 @@ seed,
 async fn generate_stream(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
-    Json(req): Json<GenerateRequest>,
-) -> (
-    HeaderMap,
-    Sse<impl Stream<Item = Result<Event, Infallible>>>,
-) {
+    Extension(stream_buffers): Extension<StreamBuffers>,
+    GenerateBody(req): GenerateBody,
+) -> Response {
     let span = tracing::Span::current();
     let (headers, response_stream) =
         generate_stream_internal(infer, compute_type, Json(req), span).await;
@@ -473,30 +635,46 @@ async fn generate_stream(
     let response_stream = async_stream::stream! {
         let mut response_stream = Box::pin(response_stream);
         while let Some(raw_event) = response_stream.next().await {
-            yield Ok(raw_event.map_or_else(Event::from, |token| {
-                Event::default()
-                    .json_data(token)
-                    .unwrap_or_else(|e| InferError::StreamSerializationError(e.to_string()).into())
+            yield Ok(raw_event.map_or_else(Event::from, |event| {
+                match event {
+                    StreamEvent::Token(token) => Event::default().json_data(token),
+                    StreamEvent::Queued(update) => Event::default().event("queued").json_data(update),
+                }
+                .unwrap_or_else(|e| InferError::StreamSerializationError(e.to_string()).into())
             }));
         }
     };
 
-    let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
-    (headers, sse)
+    stream_buffers.sse(headers, response_stream)
 }
 
-async fn generate_stream_internal(
+/// Event of the stream of [`generate_stream_internal`]
+// Tokens are the common case, boxing them would allocate on every token
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum StreamEvent {
+    Token(StreamResponse),
+    /// Position in the queue, only streamed with `queue_position`
+    Queued(QueueUpdate),
+}
+
+pub(crate) async fn generate_stream_internal(
     infer: Infer,
     ComputeType(compute_type): ComputeType,
-    Json(req): Json<GenerateRequest>,
+    Json(mut req): Json<GenerateRequest>,
     span: tracing::Span,
 ) -> (
     HeaderMap,
-    impl Stream<Item = Result<StreamResponse, InferError>>,
+    impl Stream<Item = Result<StreamEvent, InferError>>,
 ) {
     let start_time = Instant::now();
     metrics::counter!("tgi_request_count").increment(1);
 
+    // Reported as the first event of the stream
+    let input_error = infer
+        .check_special_tokens(&mut req)
+        .and_then(|()| infer.apply_prompt_template(&mut req))
+        .err();
+
     tracing::debug!("Input: {}", req.inputs);
 
     let compute_characters = req.inputs.chars().count();
@@ -509,19 +687,33 @@ async fn generate_stream_internal(
     );
     headers.insert("X-Accel-Buffering", "no".parse().unwrap());
 
+    // The stream is polled outside of the request handler
+    let scope = RequestScope::current();
     let stream = async_stream::stream! {
         // Inference
         let mut end_reached = false;
         let mut error = false;
 
+        // The compression tokenizes the prompt, it is awaited with the stream
+        let (input_error, mut compression) = match input_error {
+            Some(err) => (Some(err), None),
+            None => match infer.compress_prompt(&mut req).await {
+                Ok(compression) => (None, compression),
+                Err(err) => (Some(err), None),
+            },
+        };
+
         let mut add_prompt = None;
         if req.parameters.return_full_text.unwrap_or(false) {
             add_prompt = Some(req.inputs.clone());
         }
         let details = req.parameters.details;
+        let queue_position = req.parameters.queue_position;
 
         let best_of = req.parameters.best_of.unwrap_or(1);
-        if best_of != 1 {
+        if let Some(err) = input_error {
+            yield Err(err);
+        } else if best_of != 1 {
             let err = InferError::from(ValidationError::BestOfStream);
             metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
             tracing::error!("{err}");
@@ -532,19 +724,37 @@ async fn generate_stream_internal(
             tracing::error!("{err}");
             yield Err(err);
         } else {
-            match infer.generate_stream(req).instrument(info_span!(parent: &span, "async_stream")).await {
+            let generation = scope.run(infer.generate_stream(req));
+            match generation.instrument(info_span!(parent: &span, "async_stream")).await {
                 // Keep permit as long as generate_stream lives
-                Ok((_permit, input_length, response_stream)) => {
+                Ok((_permit, input_length, max_new_tokens, mut seed, parameters, prefix_id, response_stream)) => {
                     let mut index = 0;
+                    let mut generated_tokens = 0;
                     let mut response_stream = Box::pin(response_stream);
                     // Server-Sent Event stream
                     while let Some(response) = response_stream.next().await {
+                        if let Ok(InferStreamResponse::Queued { position, eta }) = response {
+                            if queue_position {
+                                yield Ok(StreamEvent::Queued(QueueUpdate {
+                                    position,
+                                    eta_ms: eta.map(|eta| eta.as_millis() as u64),
+                                }));
+                            }
+                            continue;
+                        }
                         index += 1;
+                        if let Ok(InferStreamResponse::Intermediate { .. } | InferStreamResponse::End { .. }) = &response {
+                            generated_tokens += 1;
+                        }
+                        let budget = details.then(|| StreamBudget {
+                            generated_tokens,
+                            remaining_tokens: max_new_tokens.saturating_sub(generated_tokens),
+                        });
                         match response {
                             Ok(response) => {
                                 match response {
-                                    // Prefill is ignored
-                                    InferStreamResponse::Prefill(_) => {}
+                                    // Prefill is ignored, positions in the queue were handled above
+                                    InferStreamResponse::Queued { .. } | InferStreamResponse::Prefill(_) => {}
                                     // Yield event for every new token
                                     InferStreamResponse::Intermediate{
                                         token,
@@ -559,8 +769,11 @@ async fn generate_stream_internal(
                                             top_tokens,
                                             generated_text: None,
                                             details: None,
+                                            budget,
+                                            seed: seed.take(),
+                                            prefix_id: None,
                                         };
-                                        yield Ok(stream_token);
+                                        yield Ok(StreamEvent::Token(stream_token));
                                     }
                                     // Yield event for last token and compute timings
                                     InferStreamResponse::End {
@@ -571,12 +784,18 @@ async fn generate_stream_internal(
                                         top_tokens,
                                     } => {
                                         // Token details
+                                        let speculative_tokens_accepted = generated_text.speculative_tokens_accepted();
                                         let details = match details {
                                             true => Some(StreamDetails {
                                                 finish_reason: generated_text.finish_reason,
                                                 generated_tokens: generated_text.generated_tokens,
                                                 seed: generated_text.seed,
                                                 input_length,
+                                                backend: generated_text.backend.map(String::from),
+                                                steps: generated_text.steps,
+                                                speculative_tokens_accepted,
+                                                effective_parameters: parameters.clone(),
+                                                compression: compression.take(),
                                             }),
                                             false => None,
                                         };
@@ -621,10 +840,13 @@ async fn generate_stream_internal(
                                             token,
                                             top_tokens,
                                             generated_text: Some(output_text),
-                                            details
+                                            details,
+                                            budget,
+                                            seed: seed.take(),
+                                            prefix_id,
                                         };
 
-                                        yield Ok(stream_token);
+                                        yield Ok(StreamEvent::Token(stream_token));
                                         break;
                                     }
                                 }
@@ -670,14 +892,12 @@ content(
 ("application/json" = CompletionFinal),
 ("text/event-stream" = Chunk),
 )),
-(status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+(status = 502, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": {"code": "generation_failed", "type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"code": "queue_full", "type": "overloaded_server", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
-(status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"code": "invalid_max_new_tokens", "type": "validation", "message": "Input validation error: `max_new_tokens` must be strictly positive", "param": "max_new_tokens"}})),
 )
 )]
 #[instrument(
@@ -696,7 +916,8 @@ pub(crate) async fn completions(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
     Extension(info): Extension<Info>,
-    Json(req): Json<CompletionRequest>,
+    Extension(stream_buffers): Extension<StreamBuffers>,
+    JsonBody(req): JsonBody<CompletionRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     metrics::counter!("tgi_request_count").increment(1);
@@ -708,10 +929,12 @@ pub(crate) async fn completions(
         stop,
         stream,
         temperature,
+        adapter_id,
         ..
     } = req;
+    let adapter_id = requested_adapter(model.as_deref(), adapter_id);
 
-    let max_new_tokens = max_tokens.or(Some(100));
+    let max_new_tokens = max_tokens;
     let stop = stop.unwrap_or_default();
     // enable greedy only when temperature is 0
     let (do_sample, temperature) = match temperature {
@@ -719,30 +942,21 @@ pub(crate) async fn completions(
         other => (true, other),
     };
 
-    // if suffix is present throw an error
-    if req.suffix.is_some() {
-        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
-        return Err((
-            StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: "Suffix is not supported and can be achieved by preprocessing the prompt."
-                    .to_string(),
-                error_type: "suffix not supported".to_string(),
-            }),
-        ));
-    }
-
     if req.prompt.0.len() > info.max_client_batch_size {
         metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: format!(
-                    "Number of prompts exceeds the maximum allowed batch size of {}",
-                    info.max_client_batch_size
-                ),
-                error_type: "batch size exceeded".to_string(),
-            }),
+            Json(
+                ErrorResponse::new(
+                    "too_many_prompts",
+                    "validation",
+                    format!(
+                        "Number of prompts exceeds the maximum allowed batch size of {}",
+                        info.max_client_batch_size
+                    ),
+                )
+                .with_param(Some("prompt")),
+            ),
         ));
     }
 
@@ -753,8 +967,14 @@ pub(crate) async fn completions(
         .map(|prompt| GenerateRequest {
             inputs: prompt.to_string(),
             add_special_tokens: true,
+            endpoint: Endpoint::Generate,
+            template: None,
+            variables: None,
+            inputs_ids: None,
+            suffix: req.suffix.clone(),
             parameters: GenerateParameters {
                 best_of: None,
+                best_of_strategy: None,
                 temperature,
                 repetition_penalty: req.repetition_penalty,
                 frequency_penalty: req.frequency_penalty,
@@ -772,7 +992,16 @@ pub(crate) async fn completions(
                 seed,
                 top_n_tokens: None,
                 grammar: None,
-                adapter_id: model.as_ref().filter(|m| *m != "tgi").map(String::from),
+                allowed_tokens: None,
+                banned_tokens: None,
+                adapter_id: adapter_id.clone(),
+                preset: None,
+                raw_bytes: false,
+                partial_on_error: false,
+                queue_position: false,
+                stop_on_repetition: None,
+                pin: false,
+                prefix_id: None,
             },
         })
         .collect();
@@ -785,18 +1014,18 @@ pub(crate) async fn completions(
         let mut response_streams = FuturesOrdered::new();
         for (index, generate_request) in generate_requests.into_iter().enumerate() {
             let model_id = info.model_id.clone();
-            let system_fingerprint =
-                format!("{}-{}", info.version, info.docker_label.unwrap_or("native"));
+            let system_fingerprint = info.system_fingerprint.clone();
             let infer_clone = infer.clone();
             let compute_type_clone = compute_type.clone();
             let span_clone = span.clone();
+            let scope = RequestScope::current();
 
             // Create a future for each generate_stream_internal call.
             let generate_future = async move {
                 let (header_tx, header_rx) = oneshot::channel();
                 let (sse_tx, sse_rx) = tokio::sync::mpsc::unbounded_channel();
 
-                tokio::spawn(async move {
+                tokio::spawn(scope.run(async move {
                     let (headers, response_stream) = generate_stream_internal(
                         infer_clone.clone(),
                         compute_type_clone.clone(),
@@ -810,7 +1039,9 @@ pub(crate) async fn completions(
 
                         while let Some(stream_token) = response_stream.next().await {
                             match stream_token {
-                                Ok(stream_token) => {
+                                // Completions do not request the positions in the queue
+                                Ok(StreamEvent::Queued(_)) => {}
+                                Ok(StreamEvent::Token(stream_token)) => {
                                     let event = Event::default();
 
                                     let current_time = std::time::SystemTime::now()
@@ -878,7 +1109,7 @@ pub(crate) async fn completions(
                             break;
                         }
                     }
-                });
+                }));
 
                 (header_rx, sse_rx)
             };
@@ -895,10 +1126,11 @@ pub(crate) async fn completions(
                 tracing::error!("Failed to get headers: {:?}", e);
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: "Failed to get headers".to_string(),
-                        error_type: "headers".to_string(),
-                    }),
+                    Json(ErrorResponse::new(
+                        "missing_headers",
+                        "internal",
+                        "Failed to get headers",
+                    )),
                 )
             })?;
             if x_compute_type.is_none() {
@@ -956,8 +1188,7 @@ pub(crate) async fn completions(
             Ok(Event::default().data("[DONE]"))
         }));
 
-        let sse = Sse::new(stream).keep_alive(KeepAlive::default());
-        Ok((headers, sse).into_response())
+        Ok(stream_buffers.sse(headers, stream))
     } else {
         let current_time = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -1002,10 +1233,11 @@ pub(crate) async fn completions(
                 let details = generation.details.ok_or((
                     // this should never happen but handle if details are missing unexpectedly
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: "No details in generation".to_string(),
-                        error_type: "no details".to_string(),
-                    }),
+                    Json(ErrorResponse::new(
+                        "missing_details",
+                        "internal",
+                        "No details in generation",
+                    )),
                 ))?;
 
                 if x_compute_type.is_none() {
@@ -1071,11 +1303,7 @@ pub(crate) async fn completions(
             id: "".to_string(),
             created: current_time,
             model: info.model_id.clone(),
-            system_fingerprint: format!(
-                "{}-{}",
-                info.version,
-                info.docker_label.unwrap_or("native")
-            ),
+            system_fingerprint: info.system_fingerprint.clone(),
             choices,
             usage: Usage {
                 prompt_tokens,
@@ -1104,18 +1332,15 @@ pub(crate) async fn completions(
     }
 }
 
-enum StreamState {
-    Buffering,
-    BufferTrailing,
-    Content { skip_close_quote: bool },
-}
-
 /// Convert a StreamResponse into an Event to be sent over SSE
+///
+/// `tool_delta` is the part of the response parsed from the token when using tools, `None`
+/// otherwise
 fn create_event_from_stream_token(
     stream_token: &StreamResponse,
     logprobs: bool,
     stream_options: Option<StreamOptions>,
-    inner_using_tools: bool,
+    tool_delta: Option<Option<ToolStreamDelta>>,
     system_fingerprint: String,
     model_id: String,
 ) -> Event {
@@ -1129,17 +1354,20 @@ fn create_event_from_stream_token(
         ChatCompletionLogprobs::from((stream_token.token.clone(), stream_token.top_tokens.clone()))
     });
 
-    // replace the content with the tool calls if grammar is present
-    let (content, tool_calls) = if inner_using_tools {
-        (None, Some(vec![stream_token.token.text.clone()]))
-    } else {
-        let content = if !stream_token.token.special {
-            Some(stream_token.token.text.clone())
-        } else {
-            None
-        };
+    // replace the content with the part of the response parsed from the tool grammar
+    let (content, tool_calls) = match tool_delta {
+        Some(Some(ToolStreamDelta::Content(content))) => (Some(content), None),
+        Some(Some(ToolStreamDelta::ToolCalls(tool_calls))) => (None, Some(tool_calls)),
+        Some(None) => (None, None),
+        None => {
+            let content = if !stream_token.token.special {
+                Some(stream_token.token.text.clone())
+            } else {
+                None
+            };
 
-        (content, None)
+            (content, None)
+        }
     };
 
     let (usage, finish_reason) = match &stream_token.details {
@@ -1149,14 +1377,11 @@ fn create_event_from_stream_token(
                 .map(|s| s.include_usage)
                 .unwrap_or(false)
             {
-                let completion_tokens = details.generated_tokens;
-                let prompt_tokens = details.input_length;
-                let total_tokens = prompt_tokens + completion_tokens;
-                Some(Usage {
-                    completion_tokens,
-                    prompt_tokens,
-                    total_tokens,
-                })
+                Some(Usage::chat(
+                    details.input_length,
+                    details.generated_tokens,
+                    details.compression.as_ref(),
+                ))
             } else {
                 None
             };
@@ -1165,7 +1390,7 @@ fn create_event_from_stream_token(
         None => (None, None),
     };
 
-    let chat_complete = CompletionType::ChatCompletionChunk(ChatCompletionChunk::new(
+    let mut chunk = ChatCompletionChunk::new(
         model_id.clone(),
         system_fingerprint.clone(),
         content,
@@ -1174,7 +1399,12 @@ fn create_event_from_stream_token(
         logprobs,
         finish_reason,
         usage,
-    ));
+    );
+    chunk.compression = stream_token
+        .details
+        .as_ref()
+        .and_then(|details| details.compression.clone());
+    let chat_complete = CompletionType::ChatCompletionChunk(chunk);
 
     event.json_data(chat_complete).unwrap_or_else(|e| {
         println!("Failed to serialize ChatCompletionChunk: {:?}", e);
@@ -1194,14 +1424,12 @@ content(
 ("application/json" = ChatCompletion),
 ("text/event-stream" = ChatCompletionChunk),
 )),
-(status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+(status = 502, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": {"code": "generation_failed", "type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"code": "queue_full", "type": "overloaded_server", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
-(status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"code": "invalid_max_new_tokens", "type": "validation", "message": "Input validation error: `max_new_tokens` must be strictly positive", "param": "max_new_tokens"}})),
 )
 )]
 #[instrument(
@@ -1214,13 +1442,18 @@ queue_time,
 inference_time,
 time_per_token,
 seed,
+user,
+metadata,
 )
 )]
 pub(crate) async fn chat_completions(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
     Extension(info): Extension<Info>,
-    Json(chat): Json<ChatRequest>,
+    Extension(stream_buffers): Extension<StreamBuffers>,
+    Extension(chat_store): Extension<ChatStore>,
+    Extension(tool_calls_config): Extension<ToolCallsConfig>,
+    JsonBody(mut chat): JsonBody<ChatRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     metrics::counter!("tgi_request_count").increment(1);
@@ -1228,8 +1461,20 @@ pub(crate) async fn chat_completions(
         stream,
         stream_options,
         logprobs,
+        messages,
+        store,
+        metadata,
+        user,
         ..
     } = chat.clone();
+    span.record("user", user.as_deref());
+    span.record("metadata", metadata.as_ref().map(tracing::field::debug));
+    let pending = chat_store.pending(store, messages, metadata, user);
+    // The stored messages are the ones of the request, before their compression
+    let compression = infer.compress_chat(&mut chat).await?;
+    // The streamed tokens cannot be taken back, only the other requests are retried
+    let mut retries = (!stream && tool_calls_config.max_retries > 0)
+        .then(|| ToolCallRetries::new(chat.clone(), tool_calls_config.max_retries));
     let (generate_request, using_tools): (GenerateRequest, bool) =
         chat.try_into_generate(&infer)?;
 
@@ -1237,212 +1482,120 @@ pub(crate) async fn chat_completions(
 
     // static values that will be returned in all cases
     let model_id = info.model_id.clone();
-    let system_fingerprint = format!("{}-{}", info.version, info.docker_label.unwrap_or("native"));
+    let system_fingerprint = info.system_fingerprint.clone();
     // switch on stream
     if stream {
         let (headers, response_stream) =
             generate_stream_internal(infer, compute_type, Json(generate_request), span).await;
 
-        // regex to match any function name
-        let function_regex = match Regex::new(r#"\{"function":\{"_name":"([^"]+)""#) {
-            Ok(regex) => regex,
-            Err(e) => {
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: format!("Failed to compile regex: {}", e),
-                        error_type: "regex".to_string(),
-                    }),
-                ))
-            }
-        };
-
         let response_stream = async_stream::stream! {
             let mut response_stream = Box::pin(response_stream);
-            let mut buffer = Vec::new();
-            let mut json_buffer = String::new();
-            let mut state = if using_tools {
-                StreamState::Buffering
-            } else {
-                StreamState::Content {
-                    skip_close_quote: false,
-                }
-            };
-            let mut response_as_tool = using_tools;
+            let mut tool_stream = using_tools.then(ToolCallStream::default);
+            let mut pending = pending;
+            let mut compression = compression;
             while let Some(result) = response_stream.next().await {
-                if let Ok(stream_token) = result {
-                    let token_text = &stream_token.token.text.clone();
-                    match state {
-                        StreamState::Buffering => {
-                            json_buffer.push_str(&token_text.replace(" ", ""));
-                            buffer.push(stream_token);
-                            if let Some(captures) = function_regex.captures(&json_buffer) {
-                                let function_name = captures[1].to_string();
-                                if function_name == "no_tool" {
-                                    state = StreamState::BufferTrailing;
-                                    response_as_tool = false;
-                                    buffer.clear();
-                                    json_buffer.clear();
-                                } else {
-                                    state = StreamState::Content {
-                                        skip_close_quote: false,
-                                    };
-                                    // send all the buffered messages
-                                    for stream_token in &buffer {
-                                        let event = create_event_from_stream_token(
-                                            stream_token,
-                                            logprobs,
-                                            stream_options.clone(),
-                                            response_as_tool,
-                                            system_fingerprint.clone(),
-                                            model_id.clone(),
-                                        );
-                                        yield Ok::<Event, Infallible>(event);
-                                    }
-                                }
-                            }
-                        }
-                        // if we skipped sending the buffer we need to avoid sending the following json key and quotes
-                        StreamState::BufferTrailing => {
-                            let infix_text = "\"content\":\"";
-                            json_buffer.push_str(&token_text.replace(" ", ""));
-                            // keep capturing until we find the infix text
-                            match json_buffer.find(infix_text) {
-                                Some(content_key_index) => {
-                                    json_buffer =
-                                        json_buffer[content_key_index + infix_text.len()..].to_string();
-                                }
-                                None => {
-                                    continue;
-                                }
-                            }
-                            // if there is leftover text after removing the infix text, we need to send it
-                            if !json_buffer.is_empty() {
-                                let event = Event::default();
-                                let current_time = std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .unwrap_or_else(|_| std::time::Duration::from_secs(0))
-                                    .as_secs();
-                                let chat_complete =
-                                    CompletionType::ChatCompletionChunk(ChatCompletionChunk::new(
-                                        model_id.clone(),
-                                        system_fingerprint.clone(),
-                                        Some(json_buffer.clone()),
-                                        None,
-                                        current_time,
-                                        None,
-                                        None,
-                                        None,
-                                    ));
-                                yield Ok(event.json_data(chat_complete).unwrap_or_else(|e| {
-                                    InferError::StreamSerializationError(e.to_string()).into()
-                                }));
-                            }
-                            // cleanup the buffers
-                            buffer.clear();
-                            json_buffer.clear();
-                            state = StreamState::Content {
-                                skip_close_quote: true,
-                            };
-                        }
-                        StreamState::Content { skip_close_quote } => {
-                            if skip_close_quote && token_text.contains('"') {
-                                break;
-                            }
-
-                            // send the content
-                            let event = create_event_from_stream_token(
-                                &stream_token,
-                                logprobs,
-                                stream_options.clone(),
-                                response_as_tool,
-                                system_fingerprint.clone(),
-                                model_id.clone(),
-                            );
-
-                            yield Ok::<Event, Infallible>(event);
-                        }
+                if let Ok(StreamEvent::Queued(update)) = result {
+                    let event = Event::default()
+                        .event("queued")
+                        .json_data(update)
+                        .unwrap_or_else(|e| InferError::StreamSerializationError(e.to_string()).into());
+                    yield Ok::<Event, Infallible>(event);
+                } else if let Ok(StreamEvent::Token(mut stream_token)) = result {
+                    // The messages were compressed before the generation, reported at its end
+                    if let Some(details) = &mut stream_token.details {
+                        details.compression = compression.take();
+                    }
+                    if let (Some(details), Some(pending)) = (&stream_token.details, pending.take()) {
+                        let text = stream_token.generated_text.clone().unwrap_or_default();
+                        let (tool_calls, output) = if using_tools {
+                            parse_tool_calls(&text).unwrap_or((None, Some(text)))
+                        } else {
+                            (None, Some(text))
+                        };
+                        let current_time = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+                            .as_secs();
+                        pending.complete(ChatCompletion::from_stream(
+                            model_id.clone(),
+                            system_fingerprint.clone(),
+                            output,
+                            current_time,
+                            details,
+                            tool_calls,
+                        ));
                     }
+                    let tool_delta = tool_stream
+                        .as_mut()
+                        .map(|tool_stream| tool_stream.push(&stream_token.token.text));
+                    // the tokens of the JSON structure are not sent, except the last one
+                    if matches!(tool_delta, Some(None)) && stream_token.details.is_none() {
+                        continue;
+                    }
+                    let event = create_event_from_stream_token(
+                        &stream_token,
+                        logprobs,
+                        stream_options.clone(),
+                        tool_delta,
+                        system_fingerprint.clone(),
+                        model_id.clone(),
+                    );
+                    yield Ok::<Event, Infallible>(event);
                 }
             }
             yield Ok::<Event, Infallible>(Event::default().data("[DONE]"));
         };
 
-        let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
-        Ok((headers, sse).into_response())
+        Ok(stream_buffers.sse(headers, response_stream))
     } else {
-        let (headers, Json(generation)) =
-            generate_internal(Extension(infer), compute_type, Json(generate_request), span).await?;
+        let mut generate_request = generate_request;
+        let (headers, mut details, tool_calls, output) = loop {
+            let (headers, Json(generation)) = generate_internal(
+                Extension(infer.clone()),
+                compute_type.clone(),
+                Json(generate_request),
+                span.clone(),
+            )
+            .await?;
+            if !using_tools {
+                break (
+                    headers,
+                    generation.details,
+                    None,
+                    Some(generation.generated_text),
+                );
+            }
+            let (tool_calls, output) = parse_tool_calls(&generation.generated_text)?;
+            let (Some(retries), Some(calls)) = (&mut retries, &tool_calls) else {
+                break (headers, generation.details, tool_calls, output);
+            };
+            let Some(request) = retries.next(&generation.generated_text, calls) else {
+                break (headers, generation.details, tool_calls, output);
+            };
+            (generate_request, _) = request.try_into_generate(&infer)?;
+        };
 
         let current_time = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap_or_else(|_| std::time::Duration::from_secs(0))
             .as_secs();
-
-        let (tool_calls, output) = if using_tools {
-            let gen_text_value: Value =
-                serde_json::from_str(&generation.generated_text).map_err(|e| {
-                    InferError::ToolError(format!(
-                        "Failed to parse generated text: {} {:?}",
-                        e, generation.generated_text
-                    ))
-                })?;
-            let function = gen_text_value.get("function").ok_or(InferError::ToolError(
-                "No function found in generated text".to_string(),
-            ))?;
-
-            let name = function
-                .get("_name")
-                .and_then(Value::as_str)
-                .ok_or(InferError::ToolError(
-                    "No _name found in generated text".to_string(),
-                ))?
-                .to_string();
-
-            let mut arguments = function.clone();
-            if let Value::Object(ref mut props) = arguments {
-                props.remove("_name");
-            }
-            match name.as_str() {
-                "no_tool" => {
-                    // parse the content message
-                    let content_message = arguments
-                        .get("content")
-                        .and_then(Value::as_str)
-                        .ok_or_else(|| {
-                            InferError::ToolError(
-                                "No `content` found in generated text".to_string(),
-                            )
-                        })?
-                        .to_string();
-                    (None, Some(content_message))
-                }
-                _ => {
-                    let tool_calls = vec![ToolCall {
-                        id: "0".to_string(),
-                        r#type: "function".to_string(),
-                        function: FunctionDefinition {
-                            description: None,
-                            name,
-                            arguments,
-                        },
-                    }];
-                    (Some(tool_calls), None)
-                }
-            }
-        } else {
-            (None, Some(generation.generated_text))
-        };
+        if let Some(details) = &mut details {
+            details.compression = compression;
+        }
         // build the complete response object with the full text
-        let response = CompletionType::ChatCompletion(ChatCompletion::new(
+        let completion = ChatCompletion::new(
             model_id,
             system_fingerprint,
             output,
             current_time,
-            generation.details.unwrap(),
+            details.unwrap(),
             logprobs,
             tool_calls,
-        ));
+        );
+        if let Some(pending) = pending {
+            pending.complete(completion.clone());
+        }
+        let response = CompletionType::ChatCompletion(completion);
 
         // wrap generation inside a Vec to match api-inference
         Ok((headers, Json(response)).into_response())
@@ -1458,13 +1611,13 @@ request_body = GenerateRequest,
 responses(
 (status = 200, description = "Tokenized ids", body = TokenizeResponse),
 (status = 404, description = "No tokenizer found", body = ErrorResponse,
-example = json ! ({"error": "No fast tokenizer available"})),
+example = json ! ({"error": {"code": "tokenizer_error", "type": "validation", "message": "No fast tokenizer available"}})),
 )
 )]
 #[instrument(skip_all)]
 async fn tokenize(
     Extension(infer): Extension<Infer>,
-    Json(req): Json<GenerateRequest>,
+    JsonBody(req): JsonBody<GenerateRequest>,
 ) -> Result<Json<TokenizeResponse>, (StatusCode, Json<ErrorResponse>)> {
     let input = req.inputs.clone();
     let encoding = infer.tokenize(req).await?;
@@ -1472,6 +1625,133 @@ async fn tokenize(
     Ok(Json(TokenizeResponse(tokens)))
 }
 
+/// Request served by a single forward of the inputs, without generation
+fn prefill_request(
+    inputs: String,
+    truncate: Option<usize>,
+    add_special_tokens: bool,
+) -> GenerateRequest {
+    GenerateRequest {
+        inputs,
+        parameters: GenerateParameters {
+            do_sample: false,
+            max_new_tokens: Some(1),
+            truncate,
+            details: true,
+            decoder_input_details: true,
+            ..default_parameters()
+        },
+        template: None,
+        variables: None,
+        inputs_ids: None,
+        suffix: None,
+        add_special_tokens,
+        endpoint: Endpoint::Generate,
+    }
+}
+
+/// Log probabilities of the tokens of the inputs, without generating
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/score",
+request_body = ScoreRequest,
+responses(
+(status = 200, description = "Log probabilities of the inputs", body = ScoreResponse),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": {"code": "input_too_long", "type": "validation", "message": "Input validation error: `inputs` must have less than 1024 tokens, the `max_input_tokens` limit. Given: 2048", "param": "inputs"}})),
+(status = 501, description = "Not supported by the backend", body = ErrorResponse,
+example = json ! ({"error": {"code": "not_supported", "type": "not_supported", "message": "The backend does not support scoring"}})),
+)
+)]
+#[instrument(skip_all)]
+async fn score(
+    Extension(infer): Extension<Infer>,
+    JsonBody(request): JsonBody<ScoreRequest>,
+) -> Result<Json<ScoreResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let ScoreRequest {
+        inputs,
+        truncate,
+        add_special_tokens,
+    } = request;
+    let tokens = infer
+        .score(prefill_request(inputs, truncate, add_special_tokens))
+        .await?;
+    let logprob = tokens
+        .iter()
+        .map(|token| token.logprob)
+        .filter(|logprob| !logprob.is_nan())
+        .sum();
+    Ok(Json(ScoreResponse { logprob, tokens }))
+}
+
+/// OpenAI compatible embeddings of texts
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/embeddings",
+request_body = EmbeddingRequest,
+responses(
+(status = 200, description = "Embeddings of the texts", body = EmbeddingResponse),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": {"code": "too_many_prompts", "type": "validation", "message": "Number of inputs exceeds the maximum allowed batch size of 4", "param": "input"}})),
+(status = 501, description = "Not supported by the backend", body = ErrorResponse,
+example = json ! ({"error": {"code": "not_supported", "type": "not_supported", "message": "The backend does not support embeddings"}})),
+)
+)]
+#[instrument(skip_all)]
+async fn embeddings(
+    Extension(infer): Extension<Infer>,
+    Extension(info): Extension<Info>,
+    JsonBody(request): JsonBody<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, (StatusCode, Json<ErrorResponse>)> {
+    if request.input.0.len() > info.max_client_batch_size {
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(
+                ErrorResponse::new(
+                    "too_many_prompts",
+                    "validation",
+                    format!(
+                        "Number of inputs exceeds the maximum allowed batch size of {}",
+                        info.max_client_batch_size
+                    ),
+                )
+                .with_param(Some("input")),
+            ),
+        ));
+    }
+
+    let embeddings = futures::future::try_join_all(
+        request
+            .input
+            .0
+            .into_iter()
+            .map(|input| infer.embed(prefill_request(input, None, true))),
+    )
+    .await?;
+    let prompt_tokens = embeddings.iter().map(|(_, tokens)| tokens).sum();
+    let data = embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, (embedding, _))| Embedding {
+            object: "embedding",
+            embedding,
+            index,
+        })
+        .collect();
+    Ok(Json(EmbeddingResponse {
+        object: "list",
+        data,
+        model: info.model_id.clone(),
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    }))
+}
+
 /// Prometheus metrics scrape endpoint
 #[utoipa::path(
     get,
@@ -1483,6 +1763,15 @@ async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
     prom_handle.render()
 }
 
+/// Fingerprint of the model revision and of the router and backend versions
+fn system_fingerprint(model_id: &str, model_sha: Option<&str>, backend: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    (model_id, model_sha, backend).hash(&mut hasher);
+    (env!("CARGO_PKG_VERSION"), option_env!("VERGEN_GIT_SHA")).hash(&mut hasher);
+    option_env!("DOCKER_LABEL").hash(&mut hasher);
+    format!("fp_{:016x}", hasher.finish())
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct ComputeType(String);
 
@@ -1495,9 +1784,24 @@ get_model_info,
 compat_generate,
 generate,
 generate_stream,
+generate_batch,
+rag,
+get_usage,
+resume_stream,
+scheduler_events,
+get_log_level,
+set_log_level,
+load_adapter,
+cancel_request,
+get_prefixes,
+unpin_prefix,
 chat_completions,
+get_chat_completion,
 completions,
 tokenize,
+render_chat_template,
+score,
+embeddings,
 metrics,
 openai_get_model_info,
 sagemaker_compatibility,
@@ -1505,10 +1809,20 @@ sagemaker_compatibility,
 components(
 schemas(
 Info,
+ShardInfo,
+BackendMetadata,
+BatchingLimits,
+LoadAdapterRequest,
+AdaptersResponse,
+LogLevel,
+ModelDefaults,
 CompatGenerateRequest,
 SagemakerRequest,
 GenerateRequest,
 GrammarType,
+RepetitionStop,
+VocabToken,
+BestOfStrategy,
 ChatRequest,
 Message,
 MessageContent,
@@ -1540,13 +1854,36 @@ PrefillToken,
 Token,
 GenerateResponse,
 TokenizeResponse,
+ChatTemplateRenderRequest,
+ChatTemplateRenderResponse,
+ScoreRequest,
+ScoreResponse,
+EmbeddingRequest,
+EmbeddingResponse,
+Embedding,
+EmbeddingUsage,
+BackendCapabilities,
 SimpleToken,
 BestOfSequence,
 Details,
 FinishReason,
 StreamResponse,
 StreamDetails,
+StreamBudget,
+QueueUpdate,
+EffectiveParameters,
+PromptCompression,
+CompressionStrategy,
+GenerateBatchRequest,
+GenerateBatchResponse,
+RagRequest,
+RagResponse,
+RagDocument,
+UsageResponse,
+PrefixPinsResponse,
+StoredChatCompletion,
 ErrorResponse,
+ErrorDetails,
 GrammarType,
 Usage,
 StreamOptions,
@@ -1656,6 +1993,7 @@ pub async fn run(
     api_key: Option<String>,
     tokenizer_name: String,
     tokenizer_config_path: Option<String>,
+    router_config_path: Option<String>,
     revision: Option<String>,
     trust_remote_code: bool,
     hostname: String,
@@ -1740,7 +2078,7 @@ pub async fn run(
         preprocessor_config_filename,
         processor_config_filename,
         model_info,
-    ) = match api {
+    ) = match api.clone() {
         Type::None => (
             Some(local_path.join("config.json")),
             Some(local_path.join("tokenizer_config.json")),
@@ -1802,7 +2140,46 @@ pub async fn run(
         HubTokenizerConfig::default()
     });
 
-    let tokenizer: Tokenizer = {
+    let router_config = match router_config_path {
+        Some(path) => RouterConfig::from_file(path)?,
+        None => RouterConfig::default(),
+    };
+
+    let tokenizer: Tokenizer = if router_config.tokenizer.format != TokenizerFormat::Hf {
+        let config = &router_config.tokenizer;
+        let file = config.file.as_deref().unwrap_or("tokenizer.model");
+        let filename = if Path::new(file).is_file() {
+            Some(PathBuf::from(file))
+        } else {
+            match api {
+                Type::None => Some(local_path.join(file)),
+                Type::Api(api) => api
+                    .repo(Repo::with_revision(
+                        tokenizer_name.to_string(),
+                        RepoType::Model,
+                        revision.clone().unwrap_or_else(|| "main".to_string()),
+                    ))
+                    .get(file)
+                    .await
+                    .ok(),
+                Type::Cache(cache) => cache
+                    .repo(Repo::with_revision(
+                        tokenizer_name.to_string(),
+                        RepoType::Model,
+                        revision.clone().unwrap_or_else(|| "main".to_string()),
+                    ))
+                    .get(file),
+            }
+        };
+        let filename = filename.ok_or_else(|| TokenizerFormatError::NotFound(file.to_string()))?;
+        let tokenizer = tokenizer_formats::load(&filename, config, &tokenizer_config)?;
+        tracing::info!(
+            "Loaded a {:?} tokenizer from {}",
+            config.format,
+            filename.display()
+        );
+        Tokenizer::Rust(tokenizer)
+    } else {
         use pyo3::prelude::*;
         pyo3::Python::with_gil(|py| -> PyResult<()> {
             py_resolve_tokenizer(py, &tokenizer_name, revision.as_deref(), trust_remote_code)?;
@@ -1910,6 +2287,7 @@ pub async fn run(
         config,
         (tokenizer, tokenizer_config),
         (preprocessor_config, processor_config),
+        router_config,
         hostname,
         port,
         ngrok,
@@ -1955,6 +2333,61 @@ pub async fn run(
     }
 }
 
+/// Serving method of the backends without model files, with a tokenizer built by the caller
+///
+/// Nothing is resolved from the hub: `model_id` is only reported in `/info` and the metrics.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_with_tokenizer(
+    backend: impl Backend + Send + Sync + 'static,
+    max_concurrent_requests: usize,
+    max_best_of: usize,
+    max_stop_sequences: usize,
+    max_top_n_tokens: u32,
+    max_input_tokens: usize,
+    max_total_tokens: usize,
+    validation_workers: usize,
+    api_key: Option<String>,
+    model_id: String,
+    (tokenizer, tokenizer_config): (tokenizers::Tokenizer, HubTokenizerConfig),
+    router_config: RouterConfig,
+    hostname: String,
+    port: u16,
+    max_client_batch_size: usize,
+) -> Result<(), WebServerError> {
+    let disable_grammar_support = !backend.capabilities().supports_grammar;
+    let model_info = HubModelInfo {
+        model_id,
+        sha: None,
+        pipeline_tag: Some("text-generation".to_string()),
+    };
+    start(
+        backend,
+        max_concurrent_requests,
+        max_best_of,
+        max_stop_sequences,
+        max_top_n_tokens,
+        max_input_tokens,
+        max_total_tokens,
+        validation_workers,
+        api_key,
+        None,
+        (Tokenizer::Rust(tokenizer), tokenizer_config),
+        (None, HubProcessorConfig::default()),
+        router_config,
+        hostname,
+        port,
+        false,
+        None,
+        None,
+        disable_grammar_support,
+        max_client_batch_size,
+        model_info,
+        true,
+        None,
+    )
+    .await
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn start(
     backend: impl Backend + Send + Sync + 'static,
@@ -1969,6 +2402,7 @@ async fn start(
     config: Option<Config>,
     (tokenizer, tokenizer_config): (Tokenizer, HubTokenizerConfig),
     (preprocessor_config, processor_config): (Option<HubPreprocessorConfig>, HubProcessorConfig),
+    router_config: RouterConfig,
     hostname: String,
     port: u16,
     ngrok: bool,
@@ -1998,6 +2432,41 @@ async fn start(
     };
 
     // Create state
+    let detokenizer = match &tokenizer {
+        Tokenizer::Rust(tokenizer) => Some(tokenizer.clone()),
+        Tokenizer::Python { .. } => None,
+    };
+    // The defaults of the served model apply to the requests without adapter
+    let default_parameters = router_config.default_parameters;
+    let model_defaults = default_parameters
+        .iter()
+        .map(|(model_id, defaults)| {
+            let adapter_id = (*model_id != model_info.model_id).then(|| model_id.clone());
+            (adapter_id, defaults.clone())
+        })
+        .collect();
+    if router_config.scheduler_events.enabled {
+        // Only streamed on the admin endpoints
+        if router_config.admin.api_key.is_some() {
+            scheduler_events::enable(router_config.scheduler_events.capacity);
+        } else {
+            tracing::warn!(
+                "Scheduler events are disabled, they are only served with `admin.api_key`"
+            );
+        }
+    }
+    #[cfg(unix)]
+    tokio::spawn(crate::logging::reload_on_hangup());
+    let tokenizer_cache = TokenizerCache::new(
+        router_config.tokenizer_cache,
+        model_info.sha.clone(),
+        detokenizer.as_ref(),
+    );
+    if max_best_of > max_concurrent_requests {
+        tracing::warn!(
+            "`max_best_of` ({max_best_of}) is above `max_concurrent_requests` ({max_concurrent_requests}), each `best_of` sequence is a concurrent request so larger values are rejected"
+        );
+    }
     let validation = Validation::new(
         validation_workers,
         tokenizer,
@@ -2009,14 +2478,49 @@ async fn start(
         max_input_tokens,
         max_total_tokens,
         disable_grammar_support,
-    );
-
+        router_config.presets,
+        model_defaults,
+        tokenizer_cache,
+    )
+    .with_limits(router_config.validation)
+    .with_input_limits(router_config.input_limits);
+
+    let prompt_templates = PromptTemplates::new(router_config.prompt_templates)?;
+    let fim_template = FimTemplate::new(router_config.fim, detokenizer.as_ref());
+    let special_tokens = SpecialTokenGuard::new(router_config.special_tokens, detokenizer.as_ref());
+    let generate_batch_config = router_config.generate_batch;
+    let tool_calls_config = router_config.tool_calls;
+    let upload_config = router_config.uploads;
+    let usage_tracker = UsageTracker::new(router_config.quotas);
+    let system_prompts = SystemPrompts::new(router_config.system_prompt);
+    let concurrency_limits = ConcurrencyLimits::new(router_config.concurrency);
+    let adaptive_limit =
+        AdaptiveLimit::new(router_config.adaptive_concurrency, max_concurrent_requests);
+    let stream_buffers = StreamBuffers::new(router_config.stream_resume);
+    let chat_store = ChatStore::new(router_config.chat_store);
+    let tls_config = router_config
+        .tls
+        .as_ref()
+        .map(tls::server_config)
+        .transpose()?;
+    let shards = backend.shards();
+    let backend_metadata = backend.metadata();
+    let capabilities = backend.capabilities();
+    let backend_name = backend.name();
     let infer = Infer::new(
         backend,
         validation,
         max_concurrent_requests,
         tokenizer_config,
         processor_config,
+        prompt_templates,
+        fim_template,
+        special_tokens.clone(),
+        detokenizer,
+        Reranker::new(router_config.best_of),
+        adaptive_limit,
+        router_config.prefix_pinning,
+        router_config.prompt_compression,
     );
 
     // Duration buckets
@@ -2075,6 +2579,21 @@ async fn start(
 
     // Metrics descriptions
     metrics::describe_counter!("tgi_request_success", "Number of successful requests");
+    metrics::describe_counter!(
+        "tgi_adapter_request_count",
+        metrics::Unit::Count,
+        "Requests per adapter, `base` for the requests served by the base model"
+    );
+    metrics::describe_counter!(
+        "tgi_adapter_input_tokens",
+        metrics::Unit::Count,
+        "Prompt tokens of the requests per adapter"
+    );
+    metrics::describe_counter!(
+        "tgi_adapter_generated_tokens",
+        metrics::Unit::Count,
+        "Generated tokens of the finished requests per adapter"
+    );
     metrics::describe_histogram!(
         "tgi_request_duration",
         metrics::Unit::Seconds,
@@ -2120,12 +2639,42 @@ async fn start(
         metrics::Unit::Count,
         "Number of successful inference calls per method (prefill or decode)"
     );
+    metrics::describe_counter!(
+        "tgi_batch_orphaned",
+        metrics::Unit::Count,
+        "Batches cleared from the shards cache by the reconciliation"
+    );
     metrics::describe_gauge!(
         "tgi_batch_current_size",
         metrics::Unit::Count,
         "Current batch size"
     );
     metrics::describe_gauge!("tgi_queue_size", metrics::Unit::Count, "Current queue size");
+    metrics::describe_gauge!(
+        "tgi_kv_cache_free_blocks",
+        metrics::Unit::Count,
+        "Free blocks of the KV cache"
+    );
+    metrics::describe_gauge!(
+        "tgi_kv_cache_used_blocks",
+        metrics::Unit::Count,
+        "KV cache blocks used by the running batches, reported by the shards"
+    );
+    metrics::describe_gauge!(
+        "tgi_kv_cache_total_blocks",
+        metrics::Unit::Count,
+        "KV cache blocks allocated by the shards"
+    );
+    metrics::describe_gauge!(
+        "tgi_kv_cache_used_bytes",
+        metrics::Unit::Bytes,
+        "Device memory of the KV cache blocks used by the running batches"
+    );
+    metrics::describe_gauge!(
+        "tgi_kv_cache_total_bytes",
+        metrics::Unit::Bytes,
+        "Device memory of the KV cache of the shards"
+    );
     metrics::describe_gauge!(
         "tgi_batch_current_max_tokens",
         metrics::Unit::Count,
@@ -2151,6 +2700,11 @@ async fn start(
         metrics::Unit::Seconds,
         "Batch forward duration per method (prefill or decode)"
     );
+    metrics::describe_histogram!(
+        "tgi_shard_forward_duration",
+        metrics::Unit::Seconds,
+        "Batch forward duration per shard rank and method (prefill or decode)"
+    );
     metrics::describe_histogram!(
         "tgi_request_skipped_tokens",
         metrics::Unit::Count,
@@ -2186,11 +2740,56 @@ async fn start(
         metrics::Unit::Count,
         "Input token length per request"
     );
+    metrics::describe_histogram!(
+        "tgi_validation_check_duration",
+        metrics::Unit::Seconds,
+        "Time spent by the validation workers per check (tokenize, grammar or decode)"
+    );
+    metrics::describe_counter!(
+        "tgi_validation_overloaded",
+        metrics::Unit::Count,
+        "Number of requests rejected as the validation workers had too many pending checks"
+    );
+    metrics::describe_counter!(
+        "tgi_validation_timeout",
+        metrics::Unit::Count,
+        "Number of validation checks over their timeout per check (tokenize, grammar or decode)"
+    );
+    metrics::describe_counter!(
+        "tgi_tool_call_retries",
+        metrics::Unit::Count,
+        "Number of chat completions generated again as their tool calls failed the schemas"
+    );
+    metrics::describe_counter!(
+        "tgi_request_prompt_compression",
+        metrics::Unit::Count,
+        "Number of prompts changed by a compression strategy per strategy"
+    );
+    metrics::describe_counter!(
+        "tgi_tokenizer_cache_hit",
+        metrics::Unit::Count,
+        "Number of inputs tokenized from a cached prefix"
+    );
+    metrics::describe_counter!(
+        "tgi_tokenizer_cache_miss",
+        metrics::Unit::Count,
+        "Number of inputs with special tokens and no cached prefix"
+    );
+    metrics::describe_gauge!(
+        "tgi_tokenizer_cache_tokens",
+        metrics::Unit::Count,
+        "Tokens of the prefixes in the tokenizer cache"
+    );
     metrics::describe_histogram!(
         "tgi_batch_next_size",
         metrics::Unit::Count,
         "Batch size of the next batch"
     );
+    metrics::describe_histogram!(
+        "tgi_rag_documents",
+        metrics::Unit::Count,
+        "Documents placed in the prompt of a `/rag` request"
+    );
 
     // CORS layer
     let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
@@ -2200,6 +2799,11 @@ async fn start(
         .allow_origin(allow_origin);
 
     // Endpoint info
+    let system_fingerprint = system_fingerprint(
+        &model_info.model_id,
+        model_info.sha.as_deref(),
+        backend_name,
+    );
     let info = Info {
         model_id: model_info.model_id,
         model_sha: model_info.sha,
@@ -2221,6 +2825,12 @@ async fn start(
         version: env!("CARGO_PKG_VERSION"),
         sha: option_env!("VERGEN_GIT_SHA"),
         docker_label: option_env!("DOCKER_LABEL"),
+        system_fingerprint,
+        shards,
+        backend: backend_name,
+        backend_metadata,
+        capabilities,
+        default_parameters,
     };
 
     #[allow(unused_mut)] // mut is needed for conditional compilation
@@ -2285,11 +2895,89 @@ async fn start(
         .route("/", post(compat_generate))
         .route("/generate", post(generate))
         .route("/generate_stream", post(generate_stream))
+        .route("/generate_batch", post(generate_batch))
+        .route("/rag", post(rag))
         .route("/v1/chat/completions", post(chat_completions))
         .route("/v1/completions", post(completions))
         .route("/vertex", post(vertex_compatibility))
         .route("/invocations", post(sagemaker_compatibility))
-        .route("/tokenize", post(tokenize));
+        .route("/tokenize", post(tokenize))
+        .route("/chat_template/render", post(render_chat_template))
+        .route("/score", post(score))
+        .route("/v1/embeddings", post(embeddings));
+
+    if usage_tracker.enabled() {
+        base_routes = base_routes.layer(axum::middleware::from_fn_with_state(
+            usage_tracker.clone(),
+            enforce_quota,
+        ));
+    }
+    if special_tokens.has_trusted_keys() {
+        base_routes = base_routes.layer(axum::middleware::from_fn_with_state(
+            special_tokens,
+            identify_trusted_caller,
+        ));
+    }
+    if let Some(system_prompts) = system_prompts {
+        base_routes = base_routes.layer(axum::middleware::from_fn_with_state(
+            system_prompts,
+            identify_system_prompt,
+        ));
+    }
+    if let Some(concurrency_limits) = concurrency_limits {
+        base_routes = base_routes.layer(axum::middleware::from_fn_with_state(
+            concurrency_limits,
+            identify_caller_limit,
+        ));
+    }
+    base_routes = base_routes.layer(axum::middleware::from_fn(assign_request_id));
+    // Added after the quota layer, so that keys over their quota can still query their usage
+    base_routes = base_routes
+        .route("/v1/usage", get(get_usage))
+        .route("/v1/streams/:id", get(resume_stream))
+        .route("/v1/chat/completions/:id", get(get_chat_completion))
+        .route("/v1/requests/:id/cancel", post(cancel_request))
+        .route("/v1/prefixes", get(get_prefixes))
+        .route("/v1/prefixes/:id", delete(unpin_prefix));
+    // Served with their own key, the key of `--api-key` does not give access to them
+    let admin_routes = router_config.admin.api_key.map(|api_key| {
+        let mut admin_routes = Router::new()
+            .route("/admin/adapters", post(load_adapter))
+            .route("/admin/log_level", get(get_log_level).put(set_log_level));
+        if scheduler_events::enabled() {
+            admin_routes = admin_routes.route("/admin/events", get(scheduler_events));
+        }
+        admin_routes.layer(axum::middleware::from_fn_with_state(
+            Arc::<str>::from(api_key),
+            authorize_admin,
+        ))
+    });
+
+    let compute_type =
+        ComputeType(std::env::var("COMPUTE_TYPE").unwrap_or("gpu+optimized".to_string()));
+
+    if let Some(grpc_port) = router_config.grpc.port {
+        #[cfg(feature = "grpc")]
+        {
+            let grpc_addr = SocketAddr::new(addr.ip(), grpc_port);
+            let grpc_server = crate::grpc::serve(
+                grpc_addr,
+                infer.clone(),
+                compute_type.clone(),
+                api_key.clone(),
+                shutdown_signal(),
+            );
+            tokio::spawn(async move {
+                if let Err(err) = grpc_server.await {
+                    tracing::error!("gRPC server failed: {err}");
+                }
+            });
+        }
+        #[cfg(not(feature = "grpc"))]
+        tracing::warn!(
+            "`grpc.port` is set to {grpc_port} but `text-generation-router` was compiled without the `grpc` feature"
+        );
+    }
 
     if let Some(api_key) = api_key {
         let mut prefix = "Bearer ".to_string();
@@ -2324,14 +3012,14 @@ async fn start(
         .route("/metrics", get(metrics))
         .route("/v1/models", get(openai_get_model_info));
 
-    let compute_type =
-        ComputeType(std::env::var("COMPUTE_TYPE").unwrap_or("gpu+optimized".to_string()));
-
     // Combine routes and layers
     let mut app = Router::new()
         .merge(swagger_ui)
         .merge(base_routes)
         .merge(info_routes);
+    if let Some(admin_routes) = admin_routes {
+        app = app.merge(admin_routes);
+    }
 
     #[cfg(feature = "google")]
     {
@@ -2374,6 +3062,12 @@ async fn start(
         .layer(Extension(compat_return_full_text))
         .layer(Extension(infer))
         .layer(Extension(compute_type))
+        .layer(Extension(generate_batch_config))
+        .layer(Extension(tool_calls_config))
+        .layer(Extension(upload_config))
+        .layer(Extension(usage_tracker))
+        .layer(Extension(stream_buffers))
+        .layer(Extension(chat_store))
         .layer(Extension(prom_handle.clone()))
         .layer(OtelAxumLayer::default())
         .layer(cors_layer);
@@ -2400,10 +3094,22 @@ async fn start(
         // Run server
 
         let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-        axum::serve(listener, app)
-            .with_graceful_shutdown(shutdown_signal())
-            .await
-            .map_err(|err| WebServerError::Axum(Box::new(err)))?;
+        match tls_config {
+            Some(tls_config) => {
+                tracing::info!("Serving HTTPS on {addr}");
+                tls::serve(listener, app, tls_config, shutdown_signal()).await
+            }
+            None => {
+                // The peer addresses identify the callers of the concurrency limits by IP
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .map_err(|err| WebServerError::Axum(Box::new(err)))?;
+            }
+        }
     }
     Ok(())
 }
@@ -2478,34 +3184,52 @@ async fn shutdown_signal() {
 impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
     fn from(err: InferError) -> Self {
         let status_code = match err {
-            InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
+            InferError::GenerationError(_) => StatusCode::BAD_GATEWAY,
+            InferError::GenerationTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
             InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::CallerOverloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::LoadShed(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::TtftSlo(_, _) => StatusCode::TOO_MANY_REQUESTS,
+            // The workers of the validation are the limit, not the request
+            InferError::ValidationError(ValidationError::Overloaded(_)) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            InferError::ValidationError(ValidationError::CheckTimeout(..)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
-            InferError::IncompleteGenerationStream => StatusCode::INTERNAL_SERVER_ERROR,
+            InferError::IncompleteGeneration => StatusCode::BAD_GATEWAY,
+            InferError::IncompleteGenerationStream => StatusCode::BAD_GATEWAY,
             InferError::TemplateError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             InferError::MissingTemplateVariable(_) => StatusCode::UNPROCESSABLE_ENTITY,
             InferError::ToolError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            InferError::InvalidToolArguments(_, _) => StatusCode::UNPROCESSABLE_ENTITY,
             InferError::StreamSerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            // Client Closed Request, as the request was cancelled by a client
+            InferError::Cancelled => StatusCode::from_u16(499).unwrap(),
+            InferError::RerankError(_) => StatusCode::BAD_GATEWAY,
+            InferError::PinCapacity(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+            InferError::AdapterLoading(_) => StatusCode::BAD_GATEWAY,
         };
 
-        (
-            status_code,
-            Json(ErrorResponse {
-                error: err.to_string(),
-                error_type: err.error_type().to_string(),
-            }),
-        )
+        (status_code, Json(ErrorResponse::from(err)))
+    }
+}
+
+impl From<InferError> for ErrorResponse {
+    fn from(err: InferError) -> Self {
+        let mut response = ErrorResponse::new(err.code(), err.error_type(), err.to_string())
+            .with_param(err.param());
+        response.error.estimated_ttft_ms = err.estimated_ttft_ms();
+        response
     }
 }
 
 impl From<InferError> for Event {
     fn from(err: InferError) -> Self {
         Event::default()
-            .json_data(ErrorResponse {
-                error: err.to_string(),
-                error_type: err.error_type().to_string(),
-            })
+            .json_data(ErrorResponse::from(err))
             .unwrap()
     }
 }
@@ -2514,16 +3238,26 @@ impl From<InferError> for Event {
 pub enum WebServerError {
     #[error("Axum error: {0}")]
     Axum(#[from] axum::BoxError),
+    #[error(transparent)]
+    RouterConfig(#[from] RouterConfigError),
+    #[error("Invalid prompt template: {0}")]
+    PromptTemplate(#[from] minijinja::Error),
+    #[error("Invalid TLS configuration: {0}")]
+    Tls(#[from] TlsError),
+    #[error("Invalid tokenizer: {0}")]
+    Tokenizer(#[from] TokenizerFormatError),
 }
 
 type PreparedInput = (String, Option<GrammarType>, bool);
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn prepare_chat_input(
     infer: &Infer,
     response_format: Option<GrammarType>,
     tools: Option<Vec<Tool>>,
     tool_choice: ToolChoice,
     tool_prompt: &str,
+    parallel_tool_calls: bool,
     guideline: Option<String>,
     messages: Vec<Message>,
 ) -> Result<PreparedInput, InferError> {
@@ -2542,7 +3276,8 @@ pub(crate) fn prepare_chat_input(
     // when no response_format is set and tools are included, apply the chat template with the tools
     // to generate inputs
     if let Some(tools) = tools {
-        let (updated_tools, tool_schema) = ToolGrammar::apply(tools, tool_choice)?;
+        let (updated_tools, tool_schema) =
+            ToolGrammar::apply(tools, tool_choice, parallel_tool_calls)?;
 
         let grammar = tool_schema
             .as_ref()
@@ -2569,8 +3304,10 @@ mod tests {
     use crate::TokenizerConfigToken;
     use crate::Tool;
 
+    use crate::router_config::{PrefixPinningConfig, PromptCompressionConfig};
     use crate::tests::get_tokenizer;
     use serde_json::json;
+    use std::collections::HashMap;
 
     #[tokio::test]
     async fn test_prepare_chat_input() {
@@ -2581,12 +3318,7 @@ mod tests {
             fn schedule(
                 &self,
                 _request: crate::validation::ValidGenerateRequest,
-            ) -> Result<
-                tokio_stream::wrappers::UnboundedReceiverStream<
-                    Result<InferStreamResponse, InferError>,
-                >,
-                InferError,
-            > {
+            ) -> Result<crate::infer::GenerationStream, InferError> {
                 unimplemented!("Never called in this test");
             }
             fn health<'a, 'async_trait>(
@@ -2618,10 +3350,32 @@ mod tests {
 
         let infer = Infer::new(
             backend,
-            Validation::new(1, tokenizer, None, None, 1, 1, 1, 1, 1, false),
+            Validation::new(
+                1,
+                tokenizer,
+                None,
+                None,
+                1,
+                1,
+                1,
+                1,
+                1,
+                false,
+                HashMap::new(),
+                HashMap::new(),
+                None,
+            ),
             1,
             tokenizer_config,
             HubProcessorConfig::default(),
+            PromptTemplates::default(),
+            None,
+            SpecialTokenGuard::default(),
+            None,
+            None,
+            None,
+            PrefixPinningConfig::default(),
+            PromptCompressionConfig::default(),
         );
         let response_format = None;
         let tools = Some(vec![Tool {
@@ -2654,6 +3408,7 @@ mod tests {
             content: MessageContent::SingleText(
                 "What is the weather like in New York?".to_string(),
             ),
+            prefix: false,
         }];
 
         let result = prepare_chat_input(
@@ -2662,6 +3417,7 @@ mod tests {
             tools,
             ToolChoice(None),
             tool_prompt,
+            false,
             guideline,
             messages,
         );
@@ -2671,4 +3427,69 @@ mod tests {
         assert_eq!(using_tools, true);
         assert_eq!(inputs, "<s>[AVAILABLE_TOOLS] [{\"type\": \"function\", \"function\": {\"arguments\": {\"properties\":{\"format\":{\"description\":\"The temperature unit to use. Infer this from the users location.\",\"enum\":[\"celsius\",\"fahrenheit\"],\"type\":\"string\"},\"location\":{\"description\":\"The city and state, e.g. San Francisco, CA\",\"type\":\"string\"}},\"required\":[\"location\",\"format\"],\"type\":\"object\"}, \"description\": \"Get the current weather\", \"name\": \"get_current_weather\"}}, {\"type\": \"function\", \"function\": {\"arguments\": {\"properties\":{\"content\":{\"description\":\"The response content\",\"type\":\"string\"}},\"required\":[\"content\"],\"type\":\"object\"}, \"description\": \"Open ened response with no specific tool selected\", \"name\": \"no_tool\"}}][/AVAILABLE_TOOLS][INST] What is the weather like in New York?\n---\nGiven the functions available, please respond with a JSON for a function call with its proper arguments that best answers the given prompt. Respond in the format {name: function name, parameters: dictionary of argument name and its value}.Do not use variables.[/INST]".to_string());
     }
+
+    #[test]
+    fn test_error_response() {
+        let err = InferError::ValidationError(ValidationError::InputLength(
+            1024,
+            2048,
+            "max_input_tokens",
+        ));
+        let (status, Json(response)) = <(StatusCode, Json<ErrorResponse>)>::from(err);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            json!({"error": {
+                "code": "input_too_long",
+                "type": "validation",
+                "message": "Input validation error: `inputs` must have less than 1024 tokens, the `max_input_tokens` limit. Given: 2048",
+                "param": "inputs",
+            }})
+        );
+
+        let (_, Json(response)) =
+            <(StatusCode, Json<ErrorResponse>)>::from(InferError::TtftSlo(2500, 1000));
+        let body = serde_json::to_value(response).unwrap();
+        assert_eq!(body["error"]["code"], "ttft_slo_exceeded");
+        assert_eq!(body["error"]["estimated_ttft_ms"], 2500);
+    }
+
+    #[test]
+    fn test_error_status() {
+        let status = |err: InferError| <(StatusCode, Json<ErrorResponse>)>::from(err).0;
+        assert_eq!(
+            status(InferError::ValidationError(ValidationError::EmptyInput)),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            status(InferError::Overloaded(
+                tokio::sync::TryAcquireError::NoPermits
+            )),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            status(InferError::CallerOverloaded(2)),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            status(InferError::TtftSlo(2500, 1000)),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            status(InferError::GenerationError("CUDA OOM".to_string())),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            status(InferError::GenerationTimeout("no answer".to_string())),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            status(InferError::IncompleteGeneration),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            status(InferError::StreamSerializationError("invalid".to_string())),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
 }
@@ -0,0 +1,365 @@
+/// Resuming generation streams after a transient disconnect
+///
+/// When enabled, the generation stream of a request is driven by a detached task which keeps
+/// the last events in a buffer. The client receives the events from the buffer, with a
+/// stream id generated by the router in the `x-stream-id` header and an id on every event,
+/// and can reconnect to `/v1/streams/{id}` with the `Last-Event-ID` header to receive the
+/// events it missed. The stream id is random so that only the client that started the
+/// stream can resume it, whatever the `x-request-id` it sent.
+use crate::router_config::StreamResumeConfig;
+use crate::ErrorResponse;
+use axum::extract::{Extension, Path};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::{Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+#[derive(Default)]
+struct BufferedEvents {
+    /// Id of the first event of `events`
+    first_id: u64,
+    events: VecDeque<Event>,
+    /// The generation stream ended
+    done: bool,
+    subscribers: usize,
+    /// When the last client disconnected
+    unsubscribed_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Buffer {
+    events: Mutex<BufferedEvents>,
+    /// Notified when an event is buffered or the stream ends
+    notify: Notify,
+}
+
+/// A connected client, reading the events of a buffer
+struct Subscription(Arc<Buffer>);
+
+impl Subscription {
+    fn new(buffer: Arc<Buffer>) -> Self {
+        buffer.events.lock().unwrap().subscribers += 1;
+        Self(buffer)
+    }
+
+    /// Buffered events from `next_id`, then the new events until the stream ends
+    ///
+    /// Ends early if the client falls behind the buffer.
+    fn events(self, mut next_id: u64) -> impl Stream<Item = Result<Event, Infallible>> {
+        async_stream::stream! {
+            loop {
+                let mut notified = pin!(self.0.notify.notified());
+                let events: Vec<Event> = {
+                    let buffered = self.0.events.lock().unwrap();
+                    if next_id < buffered.first_id {
+                        tracing::warn!("Stream client fell behind the resume buffer");
+                        break;
+                    }
+                    let events: Vec<_> = buffered
+                        .events
+                        .iter()
+                        .skip((next_id - buffered.first_id) as usize)
+                        .cloned()
+                        .collect();
+                    if events.is_empty() && buffered.done {
+                        break;
+                    }
+                    // Register before releasing the lock to not miss the next event
+                    notified.as_mut().enable();
+                    events
+                };
+                if events.is_empty() {
+                    notified.await;
+                    continue;
+                }
+                next_id += events.len() as u64;
+                for event in events {
+                    yield Ok(event);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut buffered = self.0.events.lock().unwrap();
+        buffered.subscribers -= 1;
+        if buffered.subscribers == 0 {
+            buffered.unsubscribed_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Event buffers of the streams in flight, and of the ones that ended less than `ttl_secs` ago
+#[derive(Clone)]
+pub(crate) struct StreamBuffers {
+    config: StreamResumeConfig,
+    buffers: Arc<Mutex<HashMap<String, Arc<Buffer>>>>,
+}
+
+impl StreamBuffers {
+    pub(crate) fn new(config: StreamResumeConfig) -> Self {
+        Self {
+            config,
+            buffers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.config.ttl_secs)
+    }
+
+    /// SSE response of a generation stream, resumable if enabled
+    pub(crate) fn sse<S>(&self, mut headers: HeaderMap, stream: S) -> Response
+    where
+        S: Stream<Item = Result<Event, Infallible>> + Send + 'static,
+    {
+        if self.config.buffer_events == 0 {
+            return (headers, Sse::new(stream).keep_alive(KeepAlive::default())).into_response();
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let buffer = Arc::new(Buffer::default());
+        self.buffers
+            .lock()
+            .unwrap()
+            .insert(id.clone(), buffer.clone());
+        // Subscribe before the generation starts so that it is not cancelled right away
+        let subscription = Subscription::new(buffer.clone());
+        tokio::spawn(self.clone().buffer(id.clone(), buffer, stream));
+
+        headers.insert("x-stream-id", id.parse().unwrap());
+        let sse = Sse::new(subscription.events(0)).keep_alive(KeepAlive::default());
+        (headers, sse).into_response()
+    }
+
+    /// Drive the generation stream into the buffer
+    async fn buffer<S>(self, id: String, buffer: Arc<Buffer>, stream: S)
+    where
+        S: Stream<Item = Result<Event, Infallible>>,
+    {
+        let mut stream = Box::pin(stream);
+        while let Some(Ok(event)) = stream.next().await {
+            {
+                let mut buffered = buffer.events.lock().unwrap();
+                if buffered.subscribers == 0
+                    && buffered
+                        .unsubscribed_at
+                        .is_some_and(|at| at.elapsed() > self.ttl())
+                {
+                    // Dropping the stream cancels the generation
+                    tracing::debug!("No client resumed stream {id}, cancelling the generation");
+                    break;
+                }
+                let event_id = buffered.first_id + buffered.events.len() as u64;
+                buffered.events.push_back(event.id(event_id.to_string()));
+                if buffered.events.len() > self.config.buffer_events {
+                    buffered.events.pop_front();
+                    buffered.first_id += 1;
+                }
+            }
+            buffer.notify.notify_waiters();
+        }
+        drop(stream);
+
+        buffer.events.lock().unwrap().done = true;
+        buffer.notify.notify_waiters();
+        tokio::time::sleep(self.ttl()).await;
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.get(&id).is_some_and(|b| Arc::ptr_eq(b, &buffer)) {
+            buffers.remove(&id);
+        }
+    }
+}
+
+fn error(
+    status: StatusCode,
+    code: &str,
+    error_type: &str,
+    message: &str,
+) -> (StatusCode, Json<ErrorResponse>) {
+    (status, Json(ErrorResponse::new(code, error_type, message)))
+}
+
+/// Resume a generation stream after a disconnect
+///
+/// Streams the events following the `Last-Event-ID` header, or all the events if it is not
+/// set, then the new events.
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/streams/{id}",
+params(
+("id" = String, Path, description = "Value of the `x-stream-id` header of the stream"),
+("Last-Event-ID" = Option<u64>, Header, description = "Id of the last event received"),
+),
+responses(
+(status = 200, description = "Events of the stream", content_type = "text/event-stream"),
+(status = 404, description = "Unknown or expired stream", body = ErrorResponse,
+example = json ! ({"error": {"code": "stream_not_found", "type": "not_found", "message": "Unknown stream"}})),
+(status = 410, description = "The missed events are no longer buffered", body = ErrorResponse,
+example = json ! ({"error": {"code": "events_expired", "type": "gone", "message": "The missed events are no longer buffered"}})),
+(status = 422, description = "Invalid Last-Event-ID", body = ErrorResponse,
+example = json ! ({"error": {"code": "invalid_last_event_id", "type": "validation", "message": "Invalid Last-Event-ID"}})),
+)
+)]
+pub(crate) async fn resume_stream(
+    Extension(buffers): Extension<StreamBuffers>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let next_id = match headers.get("last-event-id") {
+        Some(last_event_id) => last_event_id
+            .to_str()
+            .ok()
+            .and_then(|id| id.parse::<u64>().ok())
+            .ok_or_else(|| {
+                error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_last_event_id",
+                    "validation",
+                    "Invalid Last-Event-ID",
+                )
+            })?
+            .saturating_add(1),
+        None => 0,
+    };
+
+    let buffer = buffers.buffers.lock().unwrap().get(&id).cloned();
+    let buffer = buffer.ok_or_else(|| {
+        error(
+            StatusCode::NOT_FOUND,
+            "stream_not_found",
+            "not_found",
+            "Unknown stream",
+        )
+    })?;
+    let subscription = Subscription::new(buffer);
+    if next_id < subscription.0.events.lock().unwrap().first_id {
+        return Err(error(
+            StatusCode::GONE,
+            "events_expired",
+            "gone",
+            "The missed events are no longer buffered",
+        ));
+    }
+
+    let sse = Sse::new(subscription.events(next_id)).keep_alive(KeepAlive::default());
+    Ok(sse.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    fn event_ids(events: &[Event]) -> Vec<u64> {
+        events
+            .iter()
+            .map(|event| {
+                let event = format!("{event:?}");
+                let (_, id) = event.split_once("id: ").unwrap();
+                id.split(|c: char| !c.is_ascii_digit())
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    async fn read(
+        events: impl Stream<Item = Result<Event, Infallible>>,
+        count: usize,
+    ) -> Vec<Event> {
+        events.take(count).map(Result::unwrap).collect().await
+    }
+
+    #[tokio::test]
+    async fn test_stream_resume() {
+        let buffers = StreamBuffers::new(StreamResumeConfig {
+            buffer_events: 4,
+            ttl_secs: 30,
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let buffer = Arc::new(Buffer::default());
+        let subscription = Subscription::new(buffer.clone());
+        tokio::spawn(buffers.clone().buffer(
+            "id".to_string(),
+            buffer.clone(),
+            UnboundedReceiverStream::new(receiver),
+        ));
+
+        for i in 0..3 {
+            sender
+                .send(Ok(Event::default().data(i.to_string())))
+                .unwrap();
+        }
+        // The client disconnects after the first two events
+        let events = read(subscription.events(0), 2).await;
+        assert_eq!(event_ids(&events), vec![0, 1]);
+
+        for i in 3..6 {
+            sender
+                .send(Ok(Event::default().data(i.to_string())))
+                .unwrap();
+        }
+        drop(sender);
+        let events = read(Subscription::new(buffer.clone()).events(2), usize::MAX).await;
+        assert_eq!(event_ids(&events), vec![2, 3, 4, 5]);
+
+        // Only the last 4 events are kept
+        assert_eq!(buffer.events.lock().unwrap().first_id, 2);
+        assert!(read(Subscription::new(buffer).events(1), usize::MAX)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stream_resume_ids() {
+        let buffers = StreamBuffers::new(StreamResumeConfig {
+            buffer_events: 4,
+            ttl_secs: 0,
+        });
+        let stream_id = |response: &Response| {
+            response.headers()["x-stream-id"]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "reused".parse().unwrap());
+        let first = buffers.sse(headers.clone(), futures::stream::pending());
+        let second = buffers.sse(headers, futures::stream::pending());
+        let (first, second) = (stream_id(&first), stream_id(&second));
+        assert_ne!(first, second);
+        assert_eq!(buffers.buffers.lock().unwrap().len(), 2);
+
+        // An expired buffer does not remove the newer buffer registered under its id
+        let newer = Arc::new(Buffer::default());
+        buffers
+            .buffers
+            .lock()
+            .unwrap()
+            .insert("id".to_string(), newer.clone());
+        buffers
+            .clone()
+            .buffer(
+                "id".to_string(),
+                Arc::new(Buffer::default()),
+                futures::stream::empty(),
+            )
+            .await;
+        let buffered = buffers.buffers.lock().unwrap().get("id").cloned();
+        assert!(buffered.is_some_and(|b| Arc::ptr_eq(&b, &newer)));
+    }
+}
@@ -0,0 +1,176 @@
+/// TLS termination of the HTTP server
+///
+/// Connections are accepted and decrypted by the router itself, then served by the same
+/// axum application as the plain HTTP connections.
+use crate::router_config::TlsConfig;
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::rustls::crypto::aws_lc_rs;
+use tokio_rustls::rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
+use tokio_rustls::rustls::{self, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Unable to read `{0}`: {1}")]
+    Read(String, std::io::Error),
+    #[error("No certificate found in `{0}`")]
+    NoCertificate(String),
+    #[error("No private key found in `{0}`")]
+    NoPrivateKey(String),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error("Invalid client certificate authorities: {0}")]
+    ClientVerifier(#[from] VerifierBuilderError),
+}
+
+/// Server side TLS configuration, loading the certificates and keys of `config`
+pub(crate) fn server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>, TlsError> {
+    let provider = Arc::new(aws_lc_rs::default_provider());
+    let certs = read_certs(&config.cert_path)?;
+    let key = rustls_pemfile::private_key(&mut read(&config.key_path)?.as_slice())
+        .map_err(|err| TlsError::Read(display(&config.key_path), err))?
+        .ok_or_else(|| TlsError::NoPrivateKey(display(&config.key_path)))?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca_path)? {
+                roots.add(cert)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|err| TlsError::Read(display(path), err))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut read(path)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| TlsError::Read(display(path), err))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(display(path)));
+    }
+    Ok(certs)
+}
+
+fn display(path: &Path) -> String {
+    path.display().to_string()
+}
+
+/// Serve `app` over TLS on `listener` until `shutdown` completes, then wait for the open
+/// connections to finish their requests
+pub(crate) async fn serve(
+    listener: TcpListener,
+    app: Router,
+    config: Arc<ServerConfig>,
+    shutdown: impl Future<Output = ()>,
+) {
+    let acceptor = TlsAcceptor::from(config);
+    // Asks the connections to close, each one holding a receiver until it is closed
+    let (close_tx, close_rx) = watch::channel(());
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!("Unable to accept a connection: {err}");
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let mut close_rx = close_rx.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::debug!("TLS handshake with {peer} failed: {err}");
+                    return;
+                }
+            };
+            // The peer addresses identify the callers of the concurrency limits by IP
+            let service =
+                hyper::service::service_fn(move |mut request: hyper::Request<Incoming>| {
+                    request.extensions_mut().insert(ConnectInfo(peer));
+                    app.clone().oneshot(request)
+                });
+            let builder = Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = close_rx.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(err) = result {
+                tracing::debug!("Connection with {peer} failed: {err}");
+            }
+        });
+    }
+
+    drop(listener);
+    drop(close_rx);
+    close_tx.send_replace(());
+    close_tx.closed().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_tls_server_config_errors() {
+        let dir = std::env::temp_dir().join(format!("tgi-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+
+        let config = TlsConfig {
+            cert_path: dir.join("missing.pem"),
+            key_path: empty.clone(),
+            client_ca_path: None,
+        };
+        assert!(matches!(server_config(&config), Err(TlsError::Read(..))));
+
+        let config = TlsConfig {
+            cert_path: empty.clone(),
+            key_path: empty.clone(),
+            client_ca_path: Some(PathBuf::from("/nonexistent/ca.pem")),
+        };
+        assert!(matches!(
+            server_config(&config),
+            Err(TlsError::NoCertificate(path)) if path == empty.display().to_string()
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+/// Tokenization cache of the input prefixes shared by many requests, such as system prompts
+/// and few-shot examples
+///
+/// Tokenizers split their inputs on the special tokens before normalizing and pre-tokenizing
+/// them, so the text up to a special token is tokenized independently of the text after it.
+/// The cache keeps the ids of the prefixes ending with a special token, and only the text
+/// after the longest cached prefix is tokenized. The composition is checked on every miss, the
+/// cache disables itself if the tokenizer breaks it, e.g. by appending an EOS token.
+use crate::router_config::TokenizerCacheConfig;
+use crate::TokenizerTrait;
+use regex::Regex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokenizers::Encoding;
+
+/// Tokens of a cached prefix, the prefixes of the same inputs share their ids and offsets
+#[derive(Clone)]
+struct Prefix {
+    ids: Arc<Vec<u32>>,
+    offsets: Arc<Vec<(usize, usize)>>,
+    /// Number of tokens of the prefix
+    len: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    prefixes: HashMap<u64, Prefix>,
+    /// Sum of the number of tokens of the prefixes
+    tokens: usize,
+    clock: u64,
+}
+
+struct Shared {
+    config: TokenizerCacheConfig,
+    /// Model revision, part of the keys of the prefixes
+    revision: Option<String>,
+    special_tokens: Regex,
+    entries: Mutex<Entries>,
+    enabled: AtomicBool,
+}
+
+/// Prefix cache shared by the validation workers
+#[derive(Clone)]
+pub(crate) struct TokenizerCache(Arc<Shared>);
+
+impl TokenizerCache {
+    pub(crate) fn new(
+        config: TokenizerCacheConfig,
+        revision: Option<String>,
+        tokenizer: Option<&tokenizers::Tokenizer>,
+    ) -> Option<Self> {
+        if config.max_entries == 0 || config.max_tokens == 0 {
+            return None;
+        }
+        let special_tokens: Vec<String> = tokenizer?
+            .get_added_tokens_decoder()
+            .into_values()
+            .filter(|token| token.special)
+            .map(|token| regex::escape(&token.content))
+            .collect();
+        if special_tokens.is_empty() {
+            return None;
+        }
+        let special_tokens = Regex::new(&special_tokens.join("|")).ok()?;
+        Some(Self(Arc::new(Shared {
+            config,
+            revision,
+            special_tokens,
+            entries: Mutex::new(Entries::default()),
+            enabled: AtomicBool::new(true),
+        })))
+    }
+
+    /// Encode `query` with character offsets, reusing the tokens of its longest cached prefix
+    pub(crate) fn encode(
+        &self,
+        tokenizer: &tokenizers::Tokenizer,
+        query: String,
+        add_special_tokens: bool,
+    ) -> tokenizers::Result<Encoding> {
+        if !self.0.enabled.load(Ordering::Relaxed) {
+            return tokenizer.encode_char_offsets(query, add_special_tokens);
+        }
+        // Byte offsets of the ends of the prefixes
+        let ends: Vec<usize> = self
+            .0
+            .special_tokens
+            .find_iter(&query)
+            .map(|special_token| special_token.end())
+            .collect();
+        if ends.is_empty() {
+            return tokenizer.encode_char_offsets(query, add_special_tokens);
+        }
+
+        for &end in ends.iter().rev() {
+            let Some(prefix) = self.get(&query[..end], add_special_tokens) else {
+                continue;
+            };
+            metrics::counter!("tgi_tokenizer_cache_hit").increment(1);
+            let shift = query[..end].chars().count();
+            let rest = tokenizer.encode_char_offsets(&query[end..], false)?;
+            let ids = prefix.ids[..prefix.len].iter().chain(rest.get_ids());
+            let offsets = prefix.offsets[..prefix.len].iter().copied().chain(
+                rest.get_offsets()
+                    .iter()
+                    .map(|&(start, stop)| (start + shift, stop + shift)),
+            );
+            return Ok(encoding(ids.copied().collect(), offsets.collect()));
+        }
+
+        metrics::counter!("tgi_tokenizer_cache_miss").increment(1);
+        let encoding = tokenizer.encode_char_offsets(query.as_str(), add_special_tokens)?;
+        self.insert(tokenizer, &query, &ends, &encoding, add_special_tokens)?;
+        Ok(encoding)
+    }
+
+    fn get(&self, prefix: &str, add_special_tokens: bool) -> Option<Prefix> {
+        let key = self.key(prefix, add_special_tokens);
+        let mut entries = self.0.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let prefix = entries.prefixes.get_mut(&key)?;
+        prefix.last_used = clock;
+        Some(prefix.clone())
+    }
+
+    /// Cache the prefixes of a tokenized query ending at `ends`
+    fn insert(
+        &self,
+        tokenizer: &tokenizers::Tokenizer,
+        query: &str,
+        ends: &[usize],
+        encoding: &Encoding,
+        add_special_tokens: bool,
+    ) -> tokenizers::Result<()> {
+        let offsets = encoding.get_offsets();
+        if offsets.len() != encoding.len() {
+            return Ok(());
+        }
+        // Number of tokens of every prefix, the tokens ending before the end of its text
+        let lengths: Vec<usize> = ends
+            .iter()
+            .map(|&end| {
+                let chars = query[..end].chars().count();
+                offsets
+                    .iter()
+                    .take_while(|&&(_, stop)| stop <= chars)
+                    .count()
+            })
+            .collect();
+
+        // The text after the last prefix must be tokenized the same on its own
+        let last = ends.len() - 1;
+        let rest = tokenizer.encode_char_offsets(&query[ends[last]..], false)?;
+        if rest.get_ids() != &encoding.get_ids()[lengths[last]..] {
+            tracing::warn!("Disabling the tokenizer cache, inputs are not split on special tokens");
+            self.0.enabled.store(false, Ordering::Relaxed);
+            *self.0.entries.lock().unwrap() = Entries::default();
+            metrics::gauge!("tgi_tokenizer_cache_tokens").set(0.0);
+            return Ok(());
+        }
+
+        let ids = Arc::new(encoding.get_ids().to_vec());
+        let offsets = Arc::new(offsets.to_vec());
+        let mut entries = self.0.entries.lock().unwrap();
+        for (&end, &len) in ends.iter().zip(&lengths) {
+            if len > self.0.config.max_tokens {
+                break;
+            }
+            let key = self.key(&query[..end], add_special_tokens);
+            entries.clock += 1;
+            let prefix = Prefix {
+                ids: ids.clone(),
+                offsets: offsets.clone(),
+                len,
+                last_used: entries.clock,
+            };
+            if let Some(previous) = entries.prefixes.insert(key, prefix) {
+                entries.tokens -= previous.len;
+            }
+            entries.tokens += len;
+            self.evict(&mut entries);
+        }
+        metrics::gauge!("tgi_tokenizer_cache_tokens").set(entries.tokens as f64);
+        Ok(())
+    }
+
+    /// Remove the least recently used prefixes until the cache is within its limits
+    fn evict(&self, entries: &mut Entries) {
+        while entries.prefixes.len() > self.0.config.max_entries
+            || entries.tokens > self.0.config.max_tokens
+        {
+            let Some(key) = entries
+                .prefixes
+                .iter()
+                .min_by_key(|(_, prefix)| prefix.last_used)
+                .map(|(&key, _)| key)
+            else {
+                break;
+            };
+            if let Some(prefix) = entries.prefixes.remove(&key) {
+                entries.tokens -= prefix.len;
+            }
+        }
+    }
+
+    fn key(&self, prefix: &str, add_special_tokens: bool) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.0.revision.hash(&mut hasher);
+        add_special_tokens.hash(&mut hasher);
+        prefix.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+fn encoding(ids: Vec<u32>, offsets: Vec<(usize, usize)>) -> Encoding {
+    Encoding::new(
+        ids,
+        vec![],                           // type ids
+        vec![],                           // tokens (strings)
+        vec![],                           // words
+        offsets,                          // offsets
+        vec![],                           // special_tokens_mask
+        vec![],                           // attention_mask
+        vec![],                           // overflowing
+        std::collections::HashMap::new(), //sequence_ranges
+    )
+}
+
+/// Fast tokenizer of a validation worker
+pub(crate) struct CachedTokenizer {
+    pub(crate) tokenizer: tokenizers::Tokenizer,
+    pub(crate) cache: Option<TokenizerCache>,
+}
+
+impl TokenizerTrait for CachedTokenizer {
+    fn encode_trait(
+        &self,
+        query: String,
+        add_special_tokens: bool,
+    ) -> Result<Encoding, Box<dyn std::error::Error + Send + Sync>> {
+        match &self.cache {
+            Some(cache) => cache.encode(&self.tokenizer, query, add_special_tokens),
+            None => self.tokenizer.encode_trait(query, add_special_tokens),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+    use tokenizers::AddedToken;
+
+    #[test]
+    fn test_tokenizer_cache() {
+        let vocab = [
+            ("<s>", 0),
+            ("[UNK]", 1),
+            ("system", 2),
+            ("hello", 3),
+            ("world", 4),
+        ]
+        .into_iter()
+        .map(|(token, id)| (token.to_string(), id))
+        .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.add_special_tokens(&[AddedToken::from("<s>", true)]);
+
+        let config = TokenizerCacheConfig {
+            max_entries: 2,
+            max_tokens: 16,
+        };
+        let cache = TokenizerCache::new(config, None, Some(&tokenizer)).unwrap();
+        let encode = |query: &str| cache.encode(&tokenizer, query.to_string(), false).unwrap();
+
+        encode("<s>system<s>hello");
+        assert_eq!(cache.0.entries.lock().unwrap().prefixes.len(), 2);
+        // Hit on the `<s>system<s>` prefix
+        let query = "<s>system<s>héllo world";
+        let cached = encode(query);
+        let expected = tokenizer.encode_char_offsets(query, false).unwrap();
+        assert_eq!(cached.get_ids(), expected.get_ids());
+        assert_eq!(cached.get_offsets(), expected.get_offsets());
+        assert_eq!(cached.get_ids(), [0, 2, 0, 1, 4]);
+        assert_eq!(cached.get_offsets()[4], (18, 23));
+
+        // The least recently used prefixes are evicted
+        encode("hello<s>world<s>");
+        let entries = cache.0.entries.lock().unwrap();
+        assert_eq!(entries.prefixes.len(), 2);
+        assert_eq!(entries.tokens, 6);
+    }
+}
@@ -0,0 +1,480 @@
+/// Tokenizers of the models shipping a SentencePiece model or tiktoken BPE ranks instead of a
+/// `tokenizer.json`
+///
+/// Both are converted into a `tokenizers` tokenizer, as `transformers` converts its slow
+/// tokenizers, so that the validation counts the tokens and their offsets with the same
+/// `TokenizerTrait` implementation whatever the format.
+use crate::router_config::{TokenizerConfig, TokenizerFormat};
+use crate::HubTokenizerConfig;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::collections::HashMap;
+use std::path::Path;
+use thiserror::Error;
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::byte_level::ByteLevel;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip as DecoderStrip;
+use tokenizers::models::bpe::BPE;
+use tokenizers::models::unigram::Unigram;
+use tokenizers::normalizers::replace::ReplacePattern;
+use tokenizers::normalizers::{Precompiled, Prepend, Replace, Sequence, Strip};
+use tokenizers::pre_tokenizers::sequence::Sequence as PreTokenizerSequence;
+use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{
+    AddedToken, DecoderWrapper, ModelWrapper, NormalizerWrapper, PreTokenizerWrapper,
+    SplitDelimiterBehavior, Tokenizer,
+};
+
+/// Split pattern of the `cl100k_base` encoding
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Whitespace of the SentencePiece pieces
+const SPACE: &str = "\u{2581}";
+
+#[derive(Debug, Error)]
+pub enum TokenizerFormatError {
+    #[error("could not read the tokenizer file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid SentencePiece model: {0}")]
+    SentencePiece(#[from] prost::DecodeError),
+    #[error("invalid tiktoken file at line {0}")]
+    Tiktoken(usize),
+    #[error("unsupported SentencePiece model type {0}")]
+    ModelType(i32),
+    #[error("tokenizer file {0} not found")]
+    NotFound(String),
+    #[error(transparent)]
+    Tokenizer(#[from] tokenizers::Error),
+}
+
+/// Tokenizer of the file at `path`, in the `format` of the config
+pub(crate) fn load(
+    path: &Path,
+    config: &TokenizerConfig,
+    tokenizer_config: &HubTokenizerConfig,
+) -> Result<Tokenizer, TokenizerFormatError> {
+    let mut tokenizer = match config.format {
+        TokenizerFormat::Hf => Tokenizer::from_file(path)?,
+        TokenizerFormat::SentencePiece => sentencepiece(&std::fs::read(path)?)?,
+        TokenizerFormat::Tiktoken => tiktoken(
+            &std::fs::read_to_string(path)?,
+            config.pattern.as_deref().unwrap_or(CL100K_PATTERN),
+            &config.special_tokens,
+        )?,
+    };
+    if config.format != TokenizerFormat::Hf {
+        add_post_processor(&mut tokenizer, tokenizer_config)?;
+    }
+    Ok(tokenizer)
+}
+
+/// Messages of `sentencepiece_model.proto` read by the conversion
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ModelProto {
+        #[prost(message, repeated, tag = "1")]
+        pub pieces: Vec<SentencePiece>,
+        #[prost(message, optional, tag = "2")]
+        pub trainer_spec: Option<TrainerSpec>,
+        #[prost(message, optional, tag = "3")]
+        pub normalizer_spec: Option<NormalizerSpec>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SentencePiece {
+        #[prost(string, optional, tag = "1")]
+        pub piece: Option<String>,
+        #[prost(float, optional, tag = "2")]
+        pub score: Option<f32>,
+        #[prost(enumeration = "PieceType", optional, tag = "3", default = "Normal")]
+        pub r#type: Option<i32>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+    pub enum PieceType {
+        Normal = 1,
+        Unknown = 2,
+        Control = 3,
+        UserDefined = 4,
+        Unused = 5,
+        Byte = 6,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TrainerSpec {
+        #[prost(enumeration = "ModelType", optional, tag = "3", default = "Unigram")]
+        pub model_type: Option<i32>,
+        #[prost(bool, optional, tag = "35", default = "false")]
+        pub byte_fallback: Option<bool>,
+        #[prost(int32, optional, tag = "40", default = "0")]
+        pub unk_id: Option<i32>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+    pub enum ModelType {
+        Unigram = 1,
+        Bpe = 2,
+        Word = 3,
+        Char = 4,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct NormalizerSpec {
+        #[prost(bytes = "vec", optional, tag = "2")]
+        pub precompiled_charsmap: Option<Vec<u8>>,
+        #[prost(bool, optional, tag = "3", default = "true")]
+        pub add_dummy_prefix: Option<bool>,
+        #[prost(bool, optional, tag = "4", default = "true")]
+        pub remove_extra_whitespaces: Option<bool>,
+        #[prost(bool, optional, tag = "5", default = "true")]
+        pub escape_whitespaces: Option<bool>,
+    }
+}
+
+/// Tokenizer of a serialized SentencePiece `ModelProto`
+fn sentencepiece(model: &[u8]) -> Result<Tokenizer, TokenizerFormatError> {
+    use proto::{ModelType, PieceType};
+    let model: proto::ModelProto = prost::Message::decode(model)?;
+    let trainer_spec = model.trainer_spec.unwrap_or_default();
+    let normalizer_spec = model.normalizer_spec.unwrap_or_default();
+    let byte_fallback = trainer_spec.byte_fallback();
+
+    let pieces: Vec<(String, f64, PieceType)> = model
+        .pieces
+        .iter()
+        .map(|piece| {
+            (
+                piece.piece().to_string(),
+                piece.score() as f64,
+                piece.r#type(),
+            )
+        })
+        .collect();
+    let model: ModelWrapper = match trainer_spec.model_type() {
+        ModelType::Unigram => {
+            let vocab = pieces
+                .iter()
+                .map(|(piece, score, _)| (piece.clone(), *score))
+                .collect();
+            let unk_id = usize::try_from(trainer_spec.unk_id()).ok();
+            Unigram::from(vocab, unk_id, byte_fallback)?.into()
+        }
+        ModelType::Bpe => {
+            let vocab: HashMap<String, u32> = pieces
+                .iter()
+                .enumerate()
+                .map(|(id, (piece, _, _))| (piece.clone(), id as u32))
+                .collect();
+            // The pairs of pieces merged into another piece, in the order of the merged pieces
+            let mut merges = Vec::new();
+            for (piece, id) in &vocab {
+                for (split, _) in piece.char_indices().skip(1) {
+                    let (left, right) = piece.split_at(split);
+                    if let (Some(left_id), Some(right_id)) = (vocab.get(left), vocab.get(right)) {
+                        merges.push(((*id, *left_id, *right_id), left, right));
+                    }
+                }
+            }
+            merges.sort_unstable_by_key(|(key, _, _)| *key);
+            let merges = merges
+                .into_iter()
+                .map(|(_, left, right)| (left.to_string(), right.to_string()))
+                .collect();
+            let mut builder = BPE::builder()
+                .vocab_and_merges(vocab.clone(), merges)
+                .byte_fallback(byte_fallback)
+                .fuse_unk(true);
+            if let Some((unk, _, _)) = pieces.iter().find(|(_, _, t)| *t == PieceType::Unknown) {
+                builder = builder.unk_token(unk.clone());
+            }
+            builder.build()?.into()
+        }
+        model_type => return Err(TokenizerFormatError::ModelType(model_type as i32)),
+    };
+
+    let mut normalizers: Vec<NormalizerWrapper> = Vec::new();
+    match normalizer_spec.precompiled_charsmap() {
+        [] => {}
+        charsmap => normalizers.push(
+            Precompiled::from(charsmap)
+                .map_err(|err| TokenizerFormatError::Tokenizer(err.into()))?
+                .into(),
+        ),
+    }
+    if normalizer_spec.remove_extra_whitespaces() {
+        normalizers.push(Strip::new(true, true).into());
+        normalizers.push(Replace::new(ReplacePattern::Regex(" {2,}".to_string()), " ")?.into());
+    }
+    if normalizer_spec.escape_whitespaces() {
+        normalizers.push(Replace::new(" ", SPACE)?.into());
+    }
+    let add_dummy_prefix = normalizer_spec.add_dummy_prefix();
+    if add_dummy_prefix {
+        normalizers.push(Prepend::new(SPACE.to_string()).into());
+    }
+
+    let mut decoders: Vec<DecoderWrapper> = vec![Replace::new(SPACE, " ")?.into()];
+    if byte_fallback {
+        decoders.push(ByteFallback::new().into());
+    }
+    decoders.push(Fuse::new().into());
+    if add_dummy_prefix {
+        decoders.push(DecoderStrip::new(' ', 1, 0).into());
+    }
+
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer
+        .with_normalizer(Some(Sequence::new(normalizers)))
+        .with_decoder(Some(DecoderSequence::new(decoders)));
+    let added_tokens: Vec<AddedToken> = pieces
+        .iter()
+        .filter_map(|(piece, _, piece_type)| match piece_type {
+            PieceType::Control => Some(AddedToken::from(piece.clone(), true)),
+            PieceType::UserDefined => Some(AddedToken::from(piece.clone(), false)),
+            _ => None,
+        })
+        .collect();
+    tokenizer.add_special_tokens(&added_tokens);
+    Ok(tokenizer)
+}
+
+/// Tokenizer of the BPE `ranks` of a tiktoken file, splitting the text with `pattern`
+fn tiktoken(
+    ranks: &str,
+    pattern: &str,
+    special_tokens: &HashMap<String, u32>,
+) -> Result<Tokenizer, TokenizerFormatError> {
+    let mut mergeable = HashMap::new();
+    for (index, line) in ranks.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = line.split_once(' ').and_then(|(token, rank)| {
+            Some((
+                STANDARD.decode(token).ok()?,
+                rank.trim().parse::<u32>().ok()?,
+            ))
+        });
+        let (token, rank) = parsed.ok_or(TokenizerFormatError::Tiktoken(index + 1))?;
+        mergeable.insert(token, rank);
+    }
+
+    // The merges of the pairs of tokens making up a token, in the order of its rank
+    let mut merges = Vec::new();
+    for (token, rank) in &mergeable {
+        let mut pairs: Vec<_> = (1..token.len())
+            .filter_map(|split| {
+                let (left, right) = token.split_at(split);
+                Some((
+                    *rank,
+                    mergeable.get(left)?,
+                    mergeable.get(right)?,
+                    left,
+                    right,
+                ))
+            })
+            .collect();
+        merges.append(&mut pairs);
+    }
+    merges.sort_unstable_by_key(|(rank, left, right, _, _)| (*rank, **left, **right));
+
+    let bytes_char = bytes_char();
+    let to_string = |token: &[u8]| {
+        token
+            .iter()
+            .map(|byte| bytes_char[*byte as usize])
+            .collect()
+    };
+    let merges = merges
+        .into_iter()
+        .map(|(_, _, _, left, right)| (to_string(left), to_string(right)))
+        .collect();
+    let mut vocab: HashMap<String, u32> = mergeable
+        .iter()
+        .map(|(token, rank)| (to_string(token), *rank))
+        .collect();
+    vocab.extend(
+        special_tokens
+            .iter()
+            .map(|(token, id)| (token.clone(), *id)),
+    );
+    let model = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .ignore_merges(true)
+        .build()?;
+
+    let split = Split::new(
+        SplitPattern::Regex(pattern.to_string()),
+        SplitDelimiterBehavior::Isolated,
+        false,
+    )?;
+    let pre_tokenizers: Vec<PreTokenizerWrapper> =
+        vec![split.into(), ByteLevel::new(false, false, false).into()];
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer
+        .with_pre_tokenizer(Some(PreTokenizerSequence::new(pre_tokenizers)))
+        .with_decoder(Some(ByteLevel::default()));
+    let added_tokens: Vec<AddedToken> = special_tokens
+        .keys()
+        .map(|token| AddedToken::from(token.clone(), true))
+        .collect();
+    tokenizer.add_special_tokens(&added_tokens);
+    Ok(tokenizer)
+}
+
+/// Printable characters standing for the bytes in the byte-level BPE vocabularies
+fn bytes_char() -> Vec<char> {
+    let printable = |byte: u8| matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    let mut shifted = 0;
+    (0..=255u8)
+        .map(|byte| {
+            if printable(byte) {
+                byte as char
+            } else {
+                shifted += 1;
+                char::from_u32(255 + shifted).unwrap()
+            }
+        })
+        .collect()
+}
+
+/// Add the BOS and EOS tokens as set by `add_bos_token` and `add_eos_token` in the tokenizer
+/// config, which the converted files do not tell
+fn add_post_processor(
+    tokenizer: &mut Tokenizer,
+    tokenizer_config: &HubTokenizerConfig,
+) -> Result<(), TokenizerFormatError> {
+    let token = |add: Option<bool>, token: &Option<crate::TokenizerConfigToken>| {
+        let token = token.as_ref().filter(|_| add.unwrap_or(false))?.as_str();
+        Some((token.to_string(), tokenizer.token_to_id(token)?))
+    };
+    let bos = token(tokenizer_config.add_bos_token, &tokenizer_config.bos_token);
+    let eos = token(tokenizer_config.add_eos_token, &tokenizer_config.eos_token);
+    if bos.is_none() && eos.is_none() {
+        return Ok(());
+    }
+    let template = |sequence: &str| {
+        let bos = bos.iter().map(|(token, _)| token.as_str());
+        let eos = eos.iter().map(|(token, _)| token.as_str());
+        bos.chain([sequence])
+            .chain(eos)
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    let single = template("$A");
+    let pair = [template("$A"), template("$B:1")].concat();
+    let invalid = |err: String| TokenizerFormatError::Tokenizer(err.into());
+    let processor = TemplateProcessing::builder()
+        .try_single(single)
+        .map_err(invalid)?
+        .try_pair(pair)
+        .map_err(invalid)?
+        .special_tokens(bos.into_iter().chain(eos).collect::<Vec<_>>())
+        .build()
+        .map_err(|err| TokenizerFormatError::Tokenizer(err.into()))?;
+    tokenizer.with_post_processor(Some(processor));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    fn piece(piece: &str, score: f32, piece_type: proto::PieceType) -> proto::SentencePiece {
+        proto::SentencePiece {
+            piece: Some(piece.to_string()),
+            score: Some(score),
+            r#type: Some(piece_type as i32),
+        }
+    }
+
+    fn ids(tokenizer: &Tokenizer, inputs: &str, add_special_tokens: bool) -> Vec<u32> {
+        tokenizer
+            .encode(inputs, add_special_tokens)
+            .unwrap()
+            .get_ids()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_sentencepiece() {
+        use proto::PieceType::*;
+        let pieces = vec![
+            piece("<unk>", 0.0, Unknown),
+            piece("<s>", 0.0, Control),
+            piece("</s>", 0.0, Control),
+            piece("\u{2581}", -2.0, Normal),
+            piece("h", -3.0, Normal),
+            piece("i", -3.0, Normal),
+            piece("hi", -1.0, Normal),
+            piece("\u{2581}hi", -1.5, Normal),
+        ];
+        let model = |model_type: proto::ModelType| {
+            proto::ModelProto {
+                pieces: pieces.clone(),
+                trainer_spec: Some(proto::TrainerSpec {
+                    model_type: Some(model_type as i32),
+                    ..Default::default()
+                }),
+                normalizer_spec: None,
+            }
+            .encode_to_vec()
+        };
+
+        let tokenizer = sentencepiece(&model(proto::ModelType::Unigram)).unwrap();
+        assert_eq!(ids(&tokenizer, "hi  hi", false), [7, 7]);
+        assert_eq!(ids(&tokenizer, "hi</s>", false), [7, 2]);
+        assert_eq!(tokenizer.decode(&[7, 7], true).unwrap(), "hi hi");
+
+        // The pieces are merged in the order of the merged pieces
+        let tokenizer = sentencepiece(&model(proto::ModelType::Bpe)).unwrap();
+        assert_eq!(ids(&tokenizer, "hi hi", false), [7, 7]);
+
+        let tokenizer_config = HubTokenizerConfig {
+            bos_token: Some(crate::TokenizerConfigToken::String("<s>".to_string())),
+            add_bos_token: Some(true),
+            ..Default::default()
+        };
+        let config = TokenizerConfig {
+            format: TokenizerFormat::SentencePiece,
+            ..Default::default()
+        };
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, &model(proto::ModelType::Unigram)).unwrap();
+        let tokenizer = load(file.path(), &config, &tokenizer_config).unwrap();
+        assert_eq!(ids(&tokenizer, "hi", true), [1, 7]);
+        assert_eq!(ids(&tokenizer, "hi", false), [7]);
+
+        let word = model(proto::ModelType::Word);
+        assert!(matches!(
+            sentencepiece(&word),
+            Err(TokenizerFormatError::ModelType(3))
+        ));
+    }
+
+    #[test]
+    fn test_tiktoken() {
+        let ranks = [" ", "a", "b", "c", "ab", "abc", " a"]
+            .iter()
+            .enumerate()
+            .map(|(rank, token)| format!("{} {rank}", STANDARD.encode(token)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let special_tokens = HashMap::from([("<|endoftext|>".to_string(), 100)]);
+        let tokenizer = tiktoken(&ranks, CL100K_PATTERN, &special_tokens).unwrap();
+        assert_eq!(ids(&tokenizer, "abc", false), [5]);
+        assert_eq!(ids(&tokenizer, "abcab a", false), [5, 4, 6]);
+        assert_eq!(ids(&tokenizer, "ab<|endoftext|>", false), [4, 100]);
+        let encoding = tokenizer.encode_char_offsets("ab c", false).unwrap();
+        assert_eq!(encoding.get_offsets(), [(0, 2), (2, 3), (3, 4)]);
+        assert_eq!(tokenizer.decode(&[4, 0, 3], false).unwrap(), "ab c");
+
+        assert!(matches!(
+            tiktoken("YQ== 0\nYg==", CL100K_PATTERN, &special_tokens),
+            Err(TokenizerFormatError::Tiktoken(2))
+        ));
+    }
+}
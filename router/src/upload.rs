@@ -0,0 +1,449 @@
+/// Multipart uploads of the prompts of the generate endpoints
+///
+/// A large document is sent as a `multipart/form-data` body instead of a string inside the JSON
+/// body. The `inputs` part carries the prompt as text or as a file, and the optional `request`
+/// part the JSON of the rest of the request. The body is read as it arrives: the prompt is held
+/// in memory up to `memory_bytes` and spooled to a temporary file beyond, and the body is
+/// rejected once over `max_bytes`.
+use crate::json_body::{parse, rejection, JsonBody};
+use crate::router_config::UploadConfig;
+use crate::{ErrorResponse, GenerateRequest};
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Request};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::Json;
+use futures::{Stream, StreamExt};
+use std::io::SeekFrom;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Maximum size of the headers of a part
+const MAX_HEADERS_BYTES: usize = 8 << 10;
+/// Maximum size of the `request` part, the default limit of the JSON bodies
+const MAX_REQUEST_BYTES: usize = 2 << 20;
+
+type Rejection = (StatusCode, Json<ErrorResponse>);
+
+/// Generate request sent as JSON, or as a multipart upload
+pub(crate) struct GenerateBody(pub GenerateRequest);
+
+#[async_trait]
+impl<S> FromRequest<S> for GenerateBody
+where
+    S: Send + Sync,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Some(boundary) = boundary(request.headers()) else {
+            let JsonBody(request) = JsonBody::from_request(request, state).await?;
+            return Ok(Self(request));
+        };
+        let config = request
+            .extensions()
+            .get::<UploadConfig>()
+            .cloned()
+            .unwrap_or_default();
+        let body = request.into_body().into_data_stream();
+        read_upload(body, &boundary, &config).await.map(Self)
+    }
+}
+
+/// Boundary of a `multipart/form-data` body
+fn boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let mut parameters = content_type.split(';');
+    let mime = parameters.next()?.trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    parameters.find_map(|parameter| {
+        let (name, value) = parameter.trim().split_once('=')?;
+        name.eq_ignore_ascii_case("boundary")
+            .then(|| value.trim_matches('"').to_string())
+            .filter(|boundary| !boundary.is_empty())
+    })
+}
+
+async fn read_upload<E: std::fmt::Display>(
+    mut body: impl Stream<Item = Result<Bytes, E>> + Unpin,
+    boundary: &str,
+    config: &UploadConfig,
+) -> Result<GenerateRequest, Rejection> {
+    let mut parser = Parser::new(boundary);
+    let mut inputs: Option<Spool> = None;
+    let mut request: Option<Vec<u8>> = None;
+    let mut part = None;
+    let mut received = 0;
+
+    while !parser.ended() {
+        let Some(chunk) = body.next().await else {
+            return Err(invalid(
+                "The multipart body ended before its closing boundary",
+            ));
+        };
+        let chunk = chunk.map_err(|err| {
+            rejection(
+                StatusCode::BAD_REQUEST,
+                "invalid_body",
+                err.to_string(),
+                None,
+            )
+        })?;
+        received += chunk.len();
+        if received > config.max_bytes {
+            return Err(rejection(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                format!("The multipart body is over {} bytes", config.max_bytes),
+                None,
+            ));
+        }
+        parser.push(&chunk);
+
+        while let Some(event) = parser.next()? {
+            match event {
+                Event::Part(name) => {
+                    let duplicated = match name.as_str() {
+                        "inputs" => inputs.replace(Spool::new(config.memory_bytes)).is_some(),
+                        "request" => request.replace(Vec::new()).is_some(),
+                        _ => {
+                            return Err(unprocessable(format!(
+                                "Unknown part `{name}`, expected `inputs` or `request`"
+                            )))
+                        }
+                    };
+                    if duplicated {
+                        return Err(unprocessable(format!("The `{name}` part is sent twice")));
+                    }
+                    part = Some(name);
+                }
+                Event::Data(data) => match part.as_deref() {
+                    Some("inputs") => {
+                        let spool = inputs.as_mut().expect("inputs part started");
+                        spool.write(&data).await.map_err(spool_error)?;
+                    }
+                    Some(_) => {
+                        let request = request.as_mut().expect("request part started");
+                        if request.len() + data.len() > MAX_REQUEST_BYTES {
+                            return Err(rejection(
+                                StatusCode::PAYLOAD_TOO_LARGE,
+                                "payload_too_large",
+                                format!("The `request` part is over {MAX_REQUEST_BYTES} bytes"),
+                                None,
+                            ));
+                        }
+                        request.extend_from_slice(&data);
+                    }
+                    None => unreachable!("data is only returned inside a part"),
+                },
+            }
+        }
+    }
+
+    let mut generate_request: GenerateRequest = parse(request.as_deref().unwrap_or(b"{}"))?;
+    if let Some(inputs) = inputs {
+        if !generate_request.inputs.is_empty() {
+            return Err(unprocessable(
+                "`inputs` is set in both the `request` and the `inputs` parts".to_string(),
+            ));
+        }
+        let inputs = inputs.read().await.map_err(spool_error)?;
+        generate_request.inputs = String::from_utf8(inputs)
+            .map_err(|_| unprocessable("The `inputs` part is not valid UTF-8".to_string()))?;
+    }
+    Ok(generate_request)
+}
+
+fn invalid(message: &str) -> Rejection {
+    rejection(
+        StatusCode::BAD_REQUEST,
+        "invalid_multipart",
+        message.to_string(),
+        None,
+    )
+}
+
+fn unprocessable(message: String) -> Rejection {
+    rejection(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "invalid_body",
+        message,
+        None,
+    )
+}
+
+fn spool_error(err: std::io::Error) -> Rejection {
+    tracing::error!("Unable to spool the uploaded inputs: {err}");
+    let error = ErrorResponse::new("upload_failed", "upload", "Unable to store the upload");
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(error))
+}
+
+/// Bytes of a part, in memory up to `memory_bytes` and in a temporary file beyond
+struct Spool {
+    memory: Vec<u8>,
+    memory_bytes: usize,
+    file: Option<File>,
+}
+
+impl Spool {
+    fn new(memory_bytes: usize) -> Self {
+        Self {
+            memory: Vec::new(),
+            memory_bytes,
+            file: None,
+        }
+    }
+
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if self.file.is_none() && self.memory.len() + data.len() > self.memory_bytes {
+            let mut file = File::from_std(tempfile::tempfile()?);
+            file.write_all(&self.memory).await?;
+            self.memory = Vec::new();
+            self.file = Some(file);
+        }
+        match &mut self.file {
+            Some(file) => file.write_all(data).await,
+            None => {
+                self.memory.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    async fn read(self) -> std::io::Result<Vec<u8>> {
+        let Some(mut file) = self.file else {
+            return Ok(self.memory);
+        };
+        let mut bytes = Vec::new();
+        file.flush().await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        file.read_to_end(&mut bytes).await?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    /// Start of the part with this name
+    Part(String),
+    /// Bytes of the current part
+    Data(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Preamble,
+    /// After a boundary, the end of the body or the headers of a part follow
+    Boundary,
+    Headers,
+    Body,
+    End,
+}
+
+/// Incremental parser of a multipart body (RFC 7578)
+struct Parser {
+    /// Boundary delimiter, preceded by the line break ending the previous part
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    state: State,
+}
+
+impl Parser {
+    fn new(boundary: &str) -> Self {
+        Self {
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // The first boundary may start the body, without a line break before it
+            buffer: b"\r\n".to_vec(),
+            state: State::Preamble,
+        }
+    }
+
+    fn ended(&self) -> bool {
+        self.state == State::End
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        // The epilogue is ignored
+        if !self.ended() {
+            self.buffer.extend_from_slice(chunk);
+        }
+    }
+
+    /// Next event of the bytes pushed so far, `None` when more bytes are needed
+    fn next(&mut self) -> Result<Option<Event>, Rejection> {
+        loop {
+            match self.state {
+                State::Preamble => {
+                    let Some(position) = find(&self.buffer, &self.delimiter) else {
+                        // Keep the bytes that may start the delimiter
+                        let keep = self.buffer.len().min(self.delimiter.len() - 1);
+                        self.buffer.drain(..self.buffer.len() - keep);
+                        return Ok(None);
+                    };
+                    self.buffer.drain(..position + self.delimiter.len());
+                    self.state = State::Boundary;
+                }
+                State::Boundary => {
+                    if self.buffer.len() < 2 {
+                        return Ok(None);
+                    }
+                    if self.buffer.starts_with(b"--") {
+                        self.buffer.clear();
+                        self.state = State::End;
+                        return Ok(None);
+                    }
+                    // Whitespace may pad the boundary line
+                    let Some(position) = find(&self.buffer, b"\r\n") else {
+                        if self.buffer.len() > MAX_HEADERS_BYTES {
+                            return Err(invalid("Invalid multipart boundary line"));
+                        }
+                        return Ok(None);
+                    };
+                    if self.buffer[..position]
+                        .iter()
+                        .any(|byte| !matches!(byte, b' ' | b'\t'))
+                    {
+                        return Err(invalid("Invalid multipart boundary line"));
+                    }
+                    self.buffer.drain(..position + 2);
+                    self.state = State::Headers;
+                }
+                State::Headers => {
+                    let Some(position) = find(&self.buffer, b"\r\n\r\n") else {
+                        if self.buffer.len() > MAX_HEADERS_BYTES {
+                            return Err(invalid("The headers of a part are too large"));
+                        }
+                        return Ok(None);
+                    };
+                    let headers: Vec<u8> = self.buffer.drain(..position + 4).collect();
+                    let name = part_name(&headers[..position])
+                        .ok_or_else(|| invalid("A part has no `Content-Disposition` name"))?;
+                    self.state = State::Body;
+                    return Ok(Some(Event::Part(name)));
+                }
+                State::Body => {
+                    let (end, next) = match find(&self.buffer, &self.delimiter) {
+                        Some(position) => (position, Some(position + self.delimiter.len())),
+                        // Keep the bytes that may start the delimiter
+                        None => (
+                            self.buffer.len().saturating_sub(self.delimiter.len() - 1),
+                            None,
+                        ),
+                    };
+                    let data: Vec<u8> = self.buffer.drain(..end).collect();
+                    if let Some(next) = next {
+                        self.buffer.drain(..next - end);
+                        self.state = State::Boundary;
+                    }
+                    if !data.is_empty() {
+                        return Ok(Some(Event::Data(data)));
+                    }
+                    if next.is_none() {
+                        return Ok(None);
+                    }
+                }
+                State::End => return Ok(None),
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Name of the `Content-Disposition` header of the part `headers`
+fn part_name(headers: &[u8]) -> Option<String> {
+    let headers = std::str::from_utf8(headers).ok()?;
+    headers.split("\r\n").find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.trim().eq_ignore_ascii_case("content-disposition") {
+            return None;
+        }
+        value.split(';').find_map(|parameter| {
+            let (key, value) = parameter.trim().split_once('=')?;
+            (key.trim() == "name").then(|| value.trim().trim_matches('"').to_string())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = "preamble\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"request\"\r\n\
+        Content-Type: application/json\r\n\r\n\
+        {\"parameters\": {\"max_new_tokens\": 4}}\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"inputs\"; filename=\"doc.txt\"\r\n\r\n\
+        A long document\r\n--xyz--\r\n";
+
+    async fn upload(
+        chunks: Vec<&[u8]>,
+        config: &UploadConfig,
+    ) -> Result<GenerateRequest, Rejection> {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = chunks
+            .into_iter()
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        read_upload(futures::stream::iter(chunks), "xyz", config).await
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let config = UploadConfig::default();
+        let Ok(request) = upload(vec![BODY.as_bytes()], &config).await else {
+            panic!("Invalid upload");
+        };
+        assert_eq!(request.inputs, "A long document");
+        assert_eq!(request.parameters.max_new_tokens, Some(4));
+
+        // The boundaries are split across the chunks, and the inputs spooled to a file
+        let config = UploadConfig {
+            memory_bytes: 4,
+            ..Default::default()
+        };
+        let chunks = BODY.as_bytes().chunks(3).collect();
+        let Ok(request) = upload(chunks, &config).await else {
+            panic!("Invalid upload");
+        };
+        assert_eq!(request.inputs, "A long document");
+        assert_eq!(request.parameters.max_new_tokens, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_upload_errors() {
+        let config = UploadConfig {
+            max_bytes: 64,
+            ..Default::default()
+        };
+        let (status_code, _) = upload(vec![BODY.as_bytes()], &config).await.unwrap_err();
+        assert_eq!(status_code, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let config = UploadConfig::default();
+        let truncated = &BODY.as_bytes()[..BODY.len() - 8];
+        let (status_code, _) = upload(vec![truncated], &config).await.unwrap_err();
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let body = BODY.replace("name=\"request\"", "name=\"prompt\"");
+        let (status_code, Json(error)) = upload(vec![body.as_bytes()], &config).await.unwrap_err();
+        assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(error.error.message.contains("`prompt`"));
+    }
+
+    #[test]
+    fn test_boundary() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "multipart/form-data; boundary=\"xyz\"".parse().unwrap(),
+        );
+        assert_eq!(boundary(&headers).as_deref(), Some("xyz"));
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        assert_eq!(boundary(&headers), None);
+    }
+}
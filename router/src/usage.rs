@@ -0,0 +1,341 @@
+/// Token usage accounting and quotas per API key
+use crate::router_config::{Quota, QuotaConfig};
+use crate::ErrorResponse;
+use axum::extract::{Extension, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+tokio::task_local! {
+    /// Key of the request being handled, so that generations are accounted to it
+    static USAGE_KEY: UsageKey;
+}
+
+/// Account the tokens of a request are accounted to
+#[derive(Clone)]
+pub(crate) struct UsageKey {
+    tracker: UsageTracker,
+    account: Account,
+}
+
+impl UsageKey {
+    /// Key of the request being handled, if it has a quota
+    ///
+    /// Must be called from the request handler, streams are polled outside of it.
+    pub(crate) fn current() -> Option<Self> {
+        USAGE_KEY.try_with(Clone::clone).ok()
+    }
+
+    /// Account the tokens of a finished generation
+    pub(crate) fn record(&self, prompt_tokens: u32, completion_tokens: u32) {
+        self.tracker.record(
+            &self.account,
+            prompt_tokens as u64,
+            completion_tokens as u64,
+            now(),
+        )
+    }
+
+    /// Run `future` with its generations accounted to `key`
+    pub(crate) async fn scope<F: Future>(key: Option<Self>, future: F) -> F::Output {
+        match key {
+            Some(key) => USAGE_KEY.scope(key, future).await,
+            None => future.await,
+        }
+    }
+}
+
+/// Usage account of a request
+///
+/// Only the keys of the quota config are accounted separately. The other bearer tokens are
+/// not authenticated by the router, accounting them separately would let a caller reset its
+/// usage by changing its token, and grow the usage map with every token it makes up.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Account {
+    Key(Arc<str>),
+    /// The requests without a key of the config, under the `default` quota
+    Default,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct KeyUsage {
+    /// Fixed window the counts belong to
+    window: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+impl KeyUsage {
+    fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Token counts of every account with a quota, over fixed windows of `window_secs`
+///
+/// There is at most one entry per key of the config, and one for the `default` quota.
+#[derive(Clone)]
+pub(crate) struct UsageTracker {
+    config: Arc<QuotaConfig>,
+    usage: Arc<Mutex<HashMap<Account, KeyUsage>>>,
+}
+
+impl UsageTracker {
+    pub(crate) fn new(config: QuotaConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.config.default.is_some() || !self.config.keys.is_empty()
+    }
+
+    /// Account of the requests with the bearer token `key`, and its quota
+    fn account(&self, key: Option<&str>) -> Option<(Account, &Quota)> {
+        match key.and_then(|key| self.config.keys.get_key_value(key)) {
+            Some((key, quota)) => Some((Account::Key(key.as_str().into()), quota)),
+            None => Some((Account::Default, self.config.default.as_ref()?)),
+        }
+    }
+
+    fn window(&self, now: u64) -> u64 {
+        now / self.config.window_secs.max(1)
+    }
+
+    /// Usage of `account` in the current window
+    fn usage(&self, account: &Account, now: u64) -> KeyUsage {
+        let window = self.window(now);
+        match self.usage.lock().unwrap().get(account) {
+            Some(usage) if usage.window == window => *usage,
+            _ => KeyUsage {
+                window,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn record(&self, account: &Account, prompt_tokens: u64, completion_tokens: u64, now: u64) {
+        let window = self.window(now);
+        let mut usage = self.usage.lock().unwrap();
+        if usage
+            .get(account)
+            .is_some_and(|usage| usage.window != window)
+        {
+            // Forget the accounts without usage in the current window
+            usage.retain(|_, usage| usage.window == window);
+        }
+        let entry = usage.entry(account.clone()).or_insert(KeyUsage {
+            window,
+            ..Default::default()
+        });
+        entry.prompt_tokens += prompt_tokens;
+        entry.completion_tokens += completion_tokens;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Reject the requests of accounts over their hard quota and warn the ones over their soft
+/// quota
+///
+/// Hard quotas are checked when a request is received, so requests in flight can complete
+/// past the quota. Soft quotas are checked once the response is ready, with the usage of the
+/// request when it is not streamed.
+pub(crate) async fn enforce_quota(
+    State(tracker): State<UsageTracker>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some((account, quota)) = tracker
+        .account(bearer_token(request.headers()))
+        .map(|(account, quota)| (account, quota.clone()))
+    else {
+        return next.run(request).await;
+    };
+
+    let used = tracker.usage(&account, now()).total_tokens();
+    if let Some(hard_limit) = quota.hard_limit {
+        if used >= hard_limit {
+            metrics::counter!("tgi_request_failure", "err" => "quota").increment(1);
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(ErrorResponse::new(
+                    "token_quota_exceeded",
+                    "quota_exceeded",
+                    format!("Token quota of {hard_limit} tokens exceeded"),
+                )),
+            )
+                .into_response();
+        }
+    }
+
+    let usage_key = UsageKey {
+        tracker: tracker.clone(),
+        account: account.clone(),
+    };
+    let mut response = USAGE_KEY.scope(usage_key, next.run(request)).await;
+    if let Some(soft_limit) = quota.soft_limit {
+        if tracker.usage(&account, now()).total_tokens() >= soft_limit {
+            response.headers_mut().insert(
+                "x-quota-warning",
+                HeaderValue::from_str(&format!("soft quota of {soft_limit} tokens exceeded"))
+                    .unwrap(),
+            );
+        }
+    }
+    response
+}
+
+/// Token usage of an API key in the current window
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct UsageResponse {
+    /// Start of the window, in seconds since the Unix epoch
+    #[schema(example = 1700000000)]
+    pub window_start: u64,
+    /// End of the window, in seconds since the Unix epoch
+    #[schema(example = 1700086400)]
+    pub window_end: u64,
+    #[schema(example = 1200)]
+    pub prompt_tokens: u64,
+    #[schema(example = 300)]
+    pub completion_tokens: u64,
+    #[schema(example = 1500)]
+    pub total_tokens: u64,
+    /// Total tokens after which responses carry a `x-quota-warning` header
+    #[schema(nullable = true, example = 100000)]
+    pub soft_limit: Option<u64>,
+    /// Total tokens after which requests are rejected
+    #[schema(nullable = true, example = 200000)]
+    pub hard_limit: Option<u64>,
+}
+
+/// Token usage of the API key of the request
+///
+/// The keys missing from the quota config share the usage of the `default` quota.
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/usage",
+responses(
+(status = 200, description = "Token usage in the current window", body = UsageResponse),
+(status = 401, description = "No API key", body = ErrorResponse,
+example = json ! ({"error": {"code": "missing_api_key", "type": "unauthorized", "message": "An API key is required to query the usage"}})),
+)
+)]
+pub(crate) async fn get_usage(
+    Extension(tracker): Extension<UsageTracker>,
+    headers: HeaderMap,
+) -> Result<Json<UsageResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let key = bearer_token(&headers).ok_or_else(|| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(ErrorResponse::new(
+                "missing_api_key",
+                "unauthorized",
+                "An API key is required to query the usage",
+            )),
+        )
+    })?;
+    let now = now();
+    let (account, quota) = tracker.account(Some(key)).unzip();
+    let usage = tracker.usage(&account.unwrap_or(Account::Default), now);
+    let window_secs = tracker.config.window_secs.max(1);
+    Ok(Json(UsageResponse {
+        window_start: usage.window * window_secs,
+        window_end: (usage.window + 1) * window_secs,
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+        total_tokens: usage.total_tokens(),
+        soft_limit: quota.and_then(|quota| quota.soft_limit),
+        hard_limit: quota.and_then(|quota| quota.hard_limit),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> UsageTracker {
+        UsageTracker::new(QuotaConfig {
+            window_secs: 60,
+            default: None,
+            keys: HashMap::from([(
+                "key".to_string(),
+                Quota {
+                    soft_limit: Some(10),
+                    hard_limit: Some(20),
+                },
+            )]),
+        })
+    }
+
+    #[test]
+    fn test_usage_window() {
+        let tracker = tracker();
+        let key = Account::Key("key".into());
+        tracker.record(&key, 5, 3, 0);
+        tracker.record(&key, 1, 1, 59);
+        let usage = tracker.usage(&key, 59);
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (6, 4));
+
+        // The counts start over in the next window
+        assert_eq!(tracker.usage(&key, 60).total_tokens(), 0);
+        tracker.record(&key, 2, 0, 61);
+        assert_eq!(tracker.usage(&key, 61).total_tokens(), 2);
+    }
+
+    #[test]
+    fn test_usage_quota() {
+        let tracker = tracker();
+        assert!(tracker.enabled());
+        let (account, quota) = tracker.account(Some("key")).unwrap();
+        assert_eq!(account, Account::Key("key".into()));
+        assert_eq!(quota.hard_limit, Some(20));
+        assert!(tracker.account(Some("other")).is_none());
+        assert!(tracker.account(None).is_none());
+        assert!(!UsageTracker::new(QuotaConfig::default()).enabled());
+    }
+
+    #[test]
+    fn test_usage_default_account() {
+        let tracker = UsageTracker::new(QuotaConfig {
+            default: Some(Quota {
+                soft_limit: None,
+                hard_limit: Some(10),
+            }),
+            ..tracker().config.as_ref().clone()
+        });
+        // Unknown tokens and requests without a token share one account
+        for key in [Some("other"), Some("another"), None] {
+            let (account, quota) = tracker.account(key).unwrap();
+            assert_eq!(account, Account::Default);
+            assert_eq!(quota.hard_limit, Some(10));
+            tracker.record(&account, 2, 2, 0);
+        }
+        assert_eq!(tracker.usage(&Account::Default, 0).total_tokens(), 12);
+        assert_eq!(tracker.usage.lock().unwrap().len(), 1);
+        assert_eq!(tracker.account(Some("key")).unwrap().1.hard_limit, Some(20));
+    }
+}
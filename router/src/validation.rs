@@ -1,9 +1,12 @@
 /// Payload validation logic
 use crate::config::Config;
+use crate::router_config::{InputLimitsConfig, ModelDefaults, Preset, ValidationConfig};
+use crate::tokenizer_cache::{CachedTokenizer, TokenizerCache};
 use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
 use crate::{
-    GenerateParameters, GenerateRequest, GrammarType, HubPreprocessorConfig, Idefics2Preprocessor,
-    TokenizerTrait,
+    default_max_new_tokens, BestOfStrategy, Endpoint, GenerateParameters, GenerateRequest,
+    GrammarType, HubPreprocessorConfig, Idefics2Preprocessor, RepetitionStop, TokenizerTrait,
+    VocabToken,
 };
 use crate::{PyTokenizer, Tokenizer};
 use base64::{engine::general_purpose::STANDARD, Engine};
@@ -11,16 +14,24 @@ use image::{ImageFormat, ImageReader};
 use jsonschema::{Draft, JSONSchema};
 use rand::{thread_rng, Rng};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
 use std::io::Cursor;
 use std::iter;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
 use tracing::{instrument, Span};
 use {once_cell::sync::Lazy, regex::Regex};
 
 /// Validation
+///
+/// The parameters of a request are checked inline, then its inputs are tokenized and its grammar
+/// compiled on the validation workers, within the limits of pending checks and time.
 #[derive(Debug, Clone)]
 pub struct Validation {
     /// Validation parameters
@@ -29,9 +40,24 @@ pub struct Validation {
     max_top_n_tokens: u32,
     max_input_length: usize,
     max_total_tokens: usize,
+    /// Input tokens per endpoint, at most `max_input_length`
+    input_limits: InputLimitsConfig,
     disable_grammar_support: bool,
+    /// Named parameter presets
+    presets: Arc<HashMap<String, Preset>>,
+    /// Default parameters of the served model, with no adapter id, and of its adapters
+    model_defaults: Arc<HashMap<Option<String>, ModelDefaults>>,
+    /// Ids of the stop tokens of the served model, with no adapter id, and of its adapters
+    stop_token_ids: Arc<HashMap<Option<String>, Vec<u32>>>,
+    /// Fast tokenizer, used to decode pre-tokenized inputs
+    tokenizer: Option<Arc<tokenizers::Tokenizer>>,
     /// Channel to communicate with the background tokenization task
     sender: mpsc::UnboundedSender<TokenizerRequest>,
+    /// Checks waiting for or running on the workers, up to `max_pending`
+    pending: Arc<Semaphore>,
+    max_pending: usize,
+    /// Time a check can take, waiting for a worker included
+    check_timeout: Duration,
 }
 
 impl Validation {
@@ -47,12 +73,38 @@ impl Validation {
         max_input_length: usize,
         max_total_tokens: usize,
         disable_grammar_support: bool,
+        presets: HashMap<String, Preset>,
+        model_defaults: HashMap<Option<String>, ModelDefaults>,
+        tokenizer_cache: Option<TokenizerCache>,
     ) -> Self {
         let workers = if let Tokenizer::Python { .. } = &tokenizer {
             1
         } else {
             workers
         };
+        let fast_tokenizer = match &tokenizer {
+            Tokenizer::Rust(tokenizer) => Some(Arc::new(tokenizer.clone())),
+            Tokenizer::Python { .. } => None,
+        };
+        // The stop tokens of the configuration are resolved once, the unknown ones are skipped
+        let stop_token_ids = model_defaults
+            .iter()
+            .map(|(adapter_id, defaults)| {
+                let mut ids: Vec<u32> = defaults
+                    .stop_tokens
+                    .iter()
+                    .filter_map(|token| {
+                        token_id(fast_tokenizer.as_deref(), "stop_tokens", token.clone())
+                            .inspect_err(|err| tracing::warn!("Ignoring stop token: {err}"))
+                            .ok()
+                    })
+                    .collect();
+                ids.sort_unstable();
+                ids.dedup();
+                (adapter_id.clone(), ids)
+            })
+            .collect();
+        let limits = ValidationConfig::default();
         // If we have a fast tokenizer
         let sender = {
             // Create round robin channel
@@ -64,6 +116,7 @@ impl Validation {
                 let tokenizer_clone = tokenizer.clone();
                 let config_clone = config.clone();
                 let preprocessor_config_clone = preprocessor_config.clone();
+                let tokenizer_cache_clone = tokenizer_cache.clone();
                 let (tokenizer_sender, tokenizer_receiver) = mpsc::unbounded_channel();
                 senders.push(tokenizer_sender);
 
@@ -73,6 +126,7 @@ impl Validation {
                         tokenizer_clone,
                         config_clone,
                         preprocessor_config_clone,
+                        tokenizer_cache_clone,
                         tokenizer_receiver,
                     )
                 });
@@ -91,8 +145,87 @@ impl Validation {
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            input_limits: InputLimitsConfig::default(),
             disable_grammar_support,
+            presets: Arc::new(presets),
+            model_defaults: Arc::new(model_defaults),
+            stop_token_ids: Arc::new(stop_token_ids),
+            tokenizer: fast_tokenizer,
+            pending: Arc::new(Semaphore::new(limits.max_pending)),
+            max_pending: limits.max_pending,
+            check_timeout: Duration::from_millis(limits.timeout_ms),
+        }
+    }
+
+    /// Limit the checks run on the workers
+    pub(crate) fn with_limits(mut self, config: ValidationConfig) -> Self {
+        self.pending = Arc::new(Semaphore::new(config.max_pending));
+        self.max_pending = config.max_pending;
+        self.check_timeout = Duration::from_millis(config.timeout_ms);
+        self
+    }
+
+    /// Limit the input tokens per endpoint, the limits over `max_input_length` are lowered to it
+    pub(crate) fn with_input_limits(mut self, mut input_limits: InputLimitsConfig) -> Self {
+        for (endpoint, limit) in [
+            ("generate", &mut input_limits.generate),
+            ("chat", &mut input_limits.chat),
+            ("embeddings", &mut input_limits.embeddings),
+        ] {
+            if let Some(max_input_length) = limit.filter(|limit| *limit > self.max_input_length) {
+                tracing::warn!(
+                    "Lowering the {endpoint} input limit of {max_input_length} tokens to `max_input_tokens`: {}",
+                    self.max_input_length
+                );
+                *limit = Some(self.max_input_length);
+            }
         }
+        self.input_limits = input_limits;
+        self
+    }
+
+    /// Maximum input tokens of the requests of `endpoint`, with the name of the limit
+    pub(crate) fn max_input_length(&self, endpoint: Endpoint) -> (usize, &'static str) {
+        let limit = match endpoint {
+            Endpoint::Generate => (self.input_limits.generate, "input_limits.generate"),
+            Endpoint::Chat => (self.input_limits.chat, "input_limits.chat"),
+            Endpoint::Embeddings => (self.input_limits.embeddings, "input_limits.embeddings"),
+        };
+        match limit {
+            (Some(max_input_length), name) => (max_input_length, name),
+            (None, _) => (self.max_input_length, "max_input_tokens"),
+        }
+    }
+
+    /// Maximum input and generated tokens of a request
+    pub(crate) fn max_total_tokens(&self) -> usize {
+        self.max_total_tokens
+    }
+
+    /// Run an expensive check, which holds its permit until it completes on the workers
+    ///
+    /// The request is rejected when `max_pending` checks are already pending, or when its check
+    /// does not complete in time.
+    async fn run_check<T, F, Fut>(&self, check: &'static str, job: F) -> Result<T, ValidationError>
+    where
+        F: FnOnce(OwnedSemaphorePermit) -> Fut,
+        Fut: Future<Output = Result<T, ValidationError>>,
+    {
+        let permit = self.pending.clone().try_acquire_owned().map_err(|_| {
+            metrics::counter!("tgi_validation_overloaded", "check" => check).increment(1);
+            ValidationError::Overloaded(self.max_pending)
+        })?;
+        let start = Instant::now();
+        let result = tokio::time::timeout(self.check_timeout, job(permit)).await;
+        metrics::histogram!("tgi_validation_check_duration", "check" => check)
+            .record(start.elapsed().as_secs_f64());
+        result.unwrap_or_else(|_| {
+            metrics::counter!("tgi_validation_timeout", "check" => check).increment(1);
+            Err(ValidationError::CheckTimeout(
+                check,
+                self.check_timeout.as_millis() as u64,
+            ))
+        })
     }
 
     #[instrument(skip(self, inputs))]
@@ -102,27 +235,28 @@ impl Validation {
         add_special_tokens: bool,
         truncate: Option<usize>,
     ) -> Result<(tokenizers::Encoding, Vec<Chunk>), ValidationError> {
-        // If we have a fast tokenizer
-        // Create response channel
-        let (response_sender, response_receiver) = oneshot::channel();
-        // Send request to the background validation task
-        // Unwrap is safe here
-        let _ = &self
-            .sender
-            .send((
-                (inputs, add_special_tokens, truncate),
-                response_sender,
-                Span::current(),
-            ))
-            .unwrap();
-
-        // Await on response channel
-        // Unwrap is safe here
-        let encoding = response_receiver.await.unwrap()?;
-        Ok(encoding)
+        self.run_check("tokenize", |permit| async move {
+            // Create response channel
+            let (response_sender, response_receiver) = oneshot::channel();
+            // Send request to the background validation task
+            // Unwrap is safe here
+            let _ = &self
+                .sender
+                .send((
+                    (inputs, add_special_tokens, truncate),
+                    response_sender,
+                    Span::current(),
+                    permit,
+                ))
+                .unwrap();
+
+            // Await on response channel
+            // Unwrap is safe here
+            response_receiver.await.unwrap()
+        })
+        .await
     }
 
-    #[allow(clippy::type_complexity)]
     #[instrument(skip(self, inputs))]
     async fn validate_input(
         &self,
@@ -130,9 +264,9 @@ impl Validation {
         add_special_tokens: bool,
         truncate: Option<usize>,
         max_new_tokens: Option<u32>,
-    ) -> Result<(Vec<Chunk>, Option<Vec<u32>>, usize, u32), ValidationError> {
+    ) -> Result<ValidInput, ValidationError> {
         // If we have a fast tokenizer
-        let (encoding, inputs) = self
+        let (encoding, chunks) = self
             .tokenize(inputs.clone(), add_special_tokens, truncate)
             .await?;
         // Create response channel
@@ -141,7 +275,93 @@ impl Validation {
         } else {
             encoding.len()
         };
+        let max_new_tokens = self.validate_length(input_length, max_new_tokens)?;
+
+        let ids = encoding.get_ids();
+        let input_ids = ids[ids.len().saturating_sub(input_length)..].to_owned();
+        // The offsets of multimodal inputs point into the query with the image tokens
+        let offsets = encoding.get_offsets();
+        let input_offsets = (offsets.len() == ids.len()
+            && matches!(&chunks[..], [Chunk::Text(text)] if *text == inputs))
+        .then(|| offsets[offsets.len() - input_ids.len()..].to_owned());
+
+        metrics::histogram!("tgi_request_input_length").record(input_length as f64);
+        Ok((
+            chunks,
+            Some(input_ids),
+            input_offsets,
+            input_length,
+            max_new_tokens,
+        ))
+    }
+
+    /// Validate pre-tokenized inputs and decode them for the backends that need the text
+    #[instrument(skip_all)]
+    async fn validate_input_ids(
+        &self,
+        mut input_ids: Vec<u32>,
+        truncate: Option<usize>,
+        max_new_tokens: Option<u32>,
+    ) -> Result<ValidInput, ValidationError> {
+        let tokenizer = self
+            .tokenizer
+            .clone()
+            .ok_or(ValidationError::UnsupportedInputIds)?;
+        let vocab_size = tokenizer.get_vocab_size(true);
+        if let Some(&id) = input_ids.iter().find(|&&id| id as usize >= vocab_size) {
+            return Err(ValidationError::InputIdOutOfVocab(id, vocab_size));
+        }
+
+        if let Some(truncate) = truncate {
+            input_ids.drain(..input_ids.len().saturating_sub(truncate));
+        }
+        let input_length = input_ids.len();
+        let max_new_tokens = self.validate_length(input_length, max_new_tokens)?;
+
+        let (input_ids, text) = self
+            .run_check("decode", |permit| async move {
+                tokio::task::spawn_blocking(move || {
+                    let _permit = permit;
+                    let text = tokenizer.decode(&input_ids, false);
+                    (input_ids, text)
+                })
+                .await
+                .map_err(|err| ValidationError::Tokenizer(err.to_string()))
+            })
+            .await?;
+        let text = text.map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
 
+        metrics::histogram!("tgi_request_input_length").record(input_length as f64);
+        Ok((
+            vec![Chunk::Text(text)],
+            Some(input_ids),
+            None,
+            input_length,
+            max_new_tokens,
+        ))
+    }
+
+    /// Sorted ids of the `tokens` of the `param` list, the texts must be single tokens
+    fn validate_tokens(
+        &self,
+        param: &'static str,
+        tokens: Vec<VocabToken>,
+    ) -> Result<Vec<u32>, ValidationError> {
+        let mut ids = tokens
+            .into_iter()
+            .map(|token| token_id(self.tokenizer.as_deref(), param, token))
+            .collect::<Result<Vec<_>, _>>()?;
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    /// Validate the total number of tokens and get `max_new_tokens`
+    fn validate_length(
+        &self,
+        input_length: usize,
+        max_new_tokens: Option<u32>,
+    ) -> Result<u32, ValidationError> {
         // Get total tokens
         let max_new_tokens: u32 = if let Some(max_new_tokens) = max_new_tokens {
             max_new_tokens
@@ -164,22 +384,44 @@ impl Validation {
             return Err(ValidationError::InputLength(
                 self.max_input_length,
                 input_length,
+                "max_input_tokens",
             ));
         }
-
-        let ids = encoding.get_ids();
-        let input_ids = ids[ids.len().saturating_sub(input_length)..].to_owned();
-
-        metrics::histogram!("tgi_request_input_length").record(input_length as f64);
-        Ok((inputs, Some(input_ids), input_length, max_new_tokens))
+        Ok(max_new_tokens)
     }
 
     /// Validate a payload and get the number of tokens in the input
+    ///
+    /// The parameters are checked first, so that the invalid requests are rejected without
+    /// waiting for the workers.
     #[instrument(skip_all)]
     pub(crate) async fn validate(
         &self,
         request: GenerateRequest,
     ) -> Result<ValidGenerateRequest, ValidationError> {
+        let request = self.check_parameters(request)?;
+        self.check_inputs(request).await
+    }
+
+    /// Syntactic checks of the parameters of a request, inline as they are cheap
+    fn check_parameters(
+        &self,
+        request: GenerateRequest,
+    ) -> Result<CheckedRequest, ValidationError> {
+        let mut parameters = request.parameters;
+        if let Some(name) = parameters.preset.take() {
+            let preset = self
+                .presets
+                .get(&name)
+                .ok_or(ValidationError::UnknownPreset(name))?;
+            preset.merge_into(&mut parameters);
+        }
+        if let Some(defaults) = self.model_defaults.get(&parameters.adapter_id) {
+            defaults.merge_into(&mut parameters);
+        }
+        // The model defaults take precedence over the default of the requests
+        parameters.max_new_tokens = parameters.max_new_tokens.or(default_max_new_tokens());
+
         let GenerateParameters {
             best_of,
             temperature,
@@ -197,9 +439,14 @@ impl Validation {
             decoder_input_details,
             top_n_tokens,
             grammar,
+            allowed_tokens,
+            banned_tokens,
             adapter_id,
+            raw_bytes,
+            stop_on_repetition,
+            best_of_strategy,
             ..
-        } = request.parameters;
+        } = parameters;
 
         // sampling must be true when best_of > 1
         let best_of = best_of.unwrap_or(1);
@@ -228,14 +475,13 @@ impl Validation {
             return Err(ValidationError::FrequencyPenalty);
         }
 
-        // Different because the proto default value is not a valid value
-        // for the user
+        // A value above 1.0 keeps every token, as 1.0 which disables nucleus sampling
         let top_p = top_p
             .map(|value| {
-                if value <= 0.0 || value >= 1.0 {
+                if value.is_nan() || value <= 0.0 {
                     return Err(ValidationError::TopP);
                 }
-                Ok(value)
+                Ok(value.min(1.0))
             })
             .unwrap_or(Ok(1.0))?;
 
@@ -248,12 +494,18 @@ impl Validation {
             })
             .unwrap_or(Ok(1.0))?;
 
+        // The k most likely tokens cannot be more than the tokens of the vocabulary
+        let vocab_size = self
+            .tokenizer
+            .as_ref()
+            .map(|tokenizer| tokenizer.get_vocab_size(true) as u32);
         let top_k: u32 = top_k
             .map(|value| {
                 if value <= 0 {
                     return Err(ValidationError::TopK);
                 }
-                Ok(value as u32)
+                let value = value as u32;
+                Ok(vocab_size.map_or(value, |vocab_size| value.min(vocab_size)))
             })
             .unwrap_or(Ok(0))?;
 
@@ -268,6 +520,16 @@ impl Validation {
             ));
         }
 
+        if let Some(BestOfStrategy::LengthPenalty(alpha)) = best_of_strategy {
+            if !alpha.is_finite() {
+                return Err(ValidationError::LengthPenalty);
+            }
+        }
+
+        if stop_on_repetition.is_some_and(|stop| stop.window == 0 || stop.repeats < 2) {
+            return Err(ValidationError::StopOnRepetition);
+        }
+
         // If seed is None, assign a random one
         let seed = match seed {
             None => thread_rng().gen(),
@@ -281,6 +543,9 @@ impl Validation {
 
         let top_n_tokens = top_n_tokens
             .map(|value| {
+                if self.max_top_n_tokens == 0 && value > 0 {
+                    return Err(ValidationError::TopNTokensDisabled);
+                }
                 if value > self.max_top_n_tokens {
                     return Err(ValidationError::TopNTokens(self.max_top_n_tokens, value));
                 }
@@ -289,81 +554,52 @@ impl Validation {
             .unwrap_or(Ok(0))?;
 
         // Check if inputs is empty
-        if request.inputs.is_empty() {
-            return Err(EmptyInput);
+        match &request.inputs_ids {
+            Some(_) if !request.inputs.is_empty() => {
+                return Err(ValidationError::InputsAndInputIds)
+            }
+            Some(input_ids) if input_ids.is_empty() => return Err(EmptyInput),
+            None if request.inputs.is_empty() => return Err(EmptyInput),
+            _ => {}
         }
 
-        // Check if truncate is strictly positive and less than max_input_length
+        // Check if truncate is strictly positive and less than the input limit of the endpoint
+        let (max_input_length, _) = self.max_input_length(request.endpoint);
         let truncate = truncate
             .map(|value| {
-                if value == 0 || value > self.max_input_length {
-                    return Err(ValidationError::Truncate(self.max_input_length, value));
+                if value == 0 || value > max_input_length {
+                    return Err(ValidationError::Truncate(max_input_length, value));
                 }
                 Ok(Some(value))
             })
             .unwrap_or(Ok(None))?;
 
-        // Validate inputs
-        let (inputs, input_ids, input_length, max_new_tokens) = self
-            .validate_input(
-                request.inputs,
-                request.add_special_tokens,
-                truncate,
-                max_new_tokens,
-            )
-            .await?;
+        // Pre-tokenized inputs already contain the special tokens
+        let add_special_tokens = request.add_special_tokens && request.inputs_ids.is_none();
 
-        // TODO: we should build the FSM here and pass the compiled FSM instead of the grammar
-        // NOTE: this is currently difficult because we need the tokenizer in Python to build
-        // the FSM and we'd have to load a copy of the tokenizer into our Pyo3 instance which
-        // may be slow and memory intensive. Best case is to have a Rust implementation of the FSM
-        // compiler and use that to build the FSM here.
+        // Ensure that grammar is not set if it's not supported
+        if grammar.is_some() && self.disable_grammar_support {
+            return Err(ValidationError::Grammar);
+        }
 
-        // Validate grammar and unpack the grammar and type for the proto message
-        let grammar = match grammar {
-            Some(grammar) => {
-                // Ensure that grammar is not set if it's not supported
-                if self.disable_grammar_support {
-                    return Err(ValidationError::Grammar);
+        // Banned tokens take precedence over the allowed ones
+        let banned_token_ids = match banned_tokens {
+            Some(tokens) => self.validate_tokens("banned_tokens", tokens)?,
+            None => vec![],
+        };
+        let allowed_token_ids = match allowed_tokens {
+            Some(tokens) => {
+                let mut ids = self.validate_tokens("allowed_tokens", tokens)?;
+                ids.retain(|id| banned_token_ids.binary_search(id).is_err());
+                if ids.is_empty() {
+                    return Err(ValidationError::EmptyAllowedTokens);
                 }
-                let valid_grammar = match grammar {
-                    GrammarType::Json(json) => {
-                        let json = match json {
-                            // if value is a string, we need to parse it again to make sure its
-                            // a valid json
-                            Value::String(s) => serde_json::from_str(&s)
-                                .map_err(|e| ValidationError::InvalidGrammar(e.to_string())),
-                            Value::Object(_) => Ok(json),
-                            _ => Err(ValidationError::Grammar),
-                        }?;
-
-                        // Check if the json is a valid JSONSchema
-                        JSONSchema::options()
-                            .with_draft(Draft::Draft202012)
-                            .compile(&json)
-                            .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
-
-                        // The schema can be valid but lack properties.
-                        // We need properties for the grammar to be successfully parsed in Python.
-                        // Therefore, we must check and throw an error if properties are missing.
-                        json.get("properties")
-                            .ok_or(ValidationError::InvalidGrammar(
-                                "Grammar must have a 'properties' field".to_string(),
-                            ))?;
-
-                        // Serialize json to string
-                        ValidGrammar::Json(
-                            serde_json::to_string(&json)
-                                .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?,
-                        )
-                    }
-                    GrammarType::Regex(regex) => ValidGrammar::Regex(regex),
-                };
-                Some(valid_grammar)
+                ids
             }
-            None => None,
+            None => vec![],
         };
 
+        // The grammar is set once compiled
         let parameters = ValidParameters {
             temperature,
             repetition_penalty,
@@ -374,8 +610,112 @@ impl Validation {
             do_sample,
             seed,
             watermark,
+            grammar: None,
+            allowed_token_ids,
+            banned_token_ids,
+        };
+
+        Ok(CheckedRequest {
+            inputs: request.inputs,
+            inputs_ids: request.inputs_ids,
+            add_special_tokens,
+            endpoint: request.endpoint,
+            truncate,
+            max_new_tokens,
+            grammar,
+            parameters,
+            stop_sequences,
+            decoder_input_details,
+            top_n_tokens,
+            adapter_id,
+            raw_bytes,
+            stop_on_repetition,
+        })
+    }
+
+    /// Semantic checks of the inputs and of the grammar of a request, run on the workers
+    async fn check_inputs(
+        &self,
+        request: CheckedRequest,
+    ) -> Result<ValidGenerateRequest, ValidationError> {
+        let CheckedRequest {
+            inputs,
+            inputs_ids,
+            add_special_tokens,
+            endpoint,
+            truncate,
+            max_new_tokens,
             grammar,
+            mut parameters,
+            stop_sequences,
+            decoder_input_details,
+            top_n_tokens,
+            adapter_id,
+            raw_bytes,
+            stop_on_repetition,
+        } = request;
+
+        // Validate inputs
+        let pre_tokenized = inputs_ids.is_some();
+        let inputs = async {
+            match inputs_ids {
+                Some(input_ids) => {
+                    self.validate_input_ids(input_ids, truncate, max_new_tokens)
+                        .await
+                }
+                None => {
+                    self.validate_input(inputs, add_special_tokens, truncate, max_new_tokens)
+                        .await
+                }
+            }
         };
+
+        // TODO: we should build the FSM here and pass the compiled FSM instead of the grammar
+        // NOTE: this is currently difficult because we need the tokenizer in Python to build
+        // the FSM and we'd have to load a copy of the tokenizer into our Pyo3 instance which
+        // may be slow and memory intensive. Best case is to have a Rust implementation of the FSM
+        // compiler and use that to build the FSM here.
+
+        // Validate grammar and unpack the grammar and type for the proto message
+        let stop_on_json = matches!(grammar, Some(GrammarType::JsonObject(_)));
+        let grammar = async {
+            let valid_grammar = match grammar {
+                Some(GrammarType::Json(json) | GrammarType::JsonObject(Some(json))) => {
+                    self.run_check("grammar", |permit| async move {
+                        tokio::task::spawn_blocking(move || {
+                            let _permit = permit;
+                            json_grammar(json)
+                        })
+                        .await
+                        .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?
+                    })
+                    .await?
+                }
+                // Without a schema, the grammar allows any object
+                Some(GrammarType::JsonObject(None)) => {
+                    ValidGrammar::Json(r#"{"type":"object"}"#.to_string())
+                }
+                Some(GrammarType::Regex(regex)) => ValidGrammar::Regex(regex),
+                None => return Ok(None),
+            };
+            Ok(Some(valid_grammar))
+        };
+
+        // The inputs are tokenized while the grammar compiles
+        let ((inputs, input_ids, input_offsets, input_length, max_new_tokens), grammar) =
+            tokio::try_join!(inputs, grammar)?;
+        parameters.grammar = grammar;
+
+        // The endpoints can have a lower limit than the server
+        let (max_input_length, limit) = self.max_input_length(endpoint);
+        if input_length > max_input_length {
+            return Err(ValidationError::InputLength(
+                max_input_length,
+                input_length,
+                limit,
+            ));
+        }
+
         let stopping_parameters = ValidStoppingParameters {
             max_new_tokens,
             stop_sequences,
@@ -384,10 +724,18 @@ impl Validation {
 
         metrics::histogram!("tgi_request_max_new_tokens").record(max_new_tokens as f64);
 
+        let stop_token_ids = self
+            .stop_token_ids
+            .get(&adapter_id)
+            .cloned()
+            .unwrap_or_default();
+
         Ok(ValidGenerateRequest {
             inputs,
             input_ids: input_ids.map(Arc::new),
-            add_special_tokens: request.add_special_tokens,
+            input_offsets: input_offsets.map(Arc::new),
+            add_special_tokens,
+            pre_tokenized,
             decoder_input_details,
             input_length: input_length as u32,
             truncate: truncate.unwrap_or(self.max_input_length) as u32,
@@ -395,6 +743,11 @@ impl Validation {
             stopping_parameters,
             top_n_tokens,
             adapter_id,
+            raw_bytes,
+            stop_on_repetition,
+            stop_on_json,
+            stop_token_ids,
+            pin_prefix: None,
         })
     }
 
@@ -428,11 +781,73 @@ async fn round_robin_task(
     }
 }
 
+/// Id of a token of the `param` list, its text must be a single token
+fn token_id(
+    tokenizer: Option<&tokenizers::Tokenizer>,
+    param: &'static str,
+    token: VocabToken,
+) -> Result<u32, ValidationError> {
+    match (token, tokenizer) {
+        (VocabToken::Id(id), Some(tokenizer)) => {
+            let vocab_size = tokenizer.get_vocab_size(true);
+            if id as usize >= vocab_size {
+                return Err(ValidationError::TokenOutOfVocab(param, id, vocab_size));
+            }
+            Ok(id)
+        }
+        // The vocabulary of the Python tokenizers is not known
+        (VocabToken::Id(id), None) => Ok(id),
+        (VocabToken::Text(text), Some(tokenizer)) => tokenizer
+            .token_to_id(&text)
+            .or_else(
+                || match tokenizer.encode(text.as_str(), false).ok()?.get_ids() {
+                    [id] => Some(*id),
+                    _ => None,
+                },
+            )
+            .ok_or(ValidationError::UnknownToken(param, text)),
+        (VocabToken::Text(text), None) => Err(ValidationError::UnknownToken(param, text)),
+    }
+}
+
+/// Validate a JSON Schema grammar
+fn json_grammar(json: Value) -> Result<ValidGrammar, ValidationError> {
+    let json = match json {
+        // if value is a string, we need to parse it again to make sure its
+        // a valid json
+        Value::String(s) => {
+            serde_json::from_str(&s).map_err(|e| ValidationError::InvalidGrammar(e.to_string()))
+        }
+        Value::Object(_) => Ok(json),
+        _ => Err(ValidationError::Grammar),
+    }?;
+
+    // Check if the json is a valid JSONSchema
+    JSONSchema::options()
+        .with_draft(Draft::Draft202012)
+        .compile(&json)
+        .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
+
+    // The schema can be valid but lack properties.
+    // We need properties for the grammar to be successfully parsed in Python.
+    // Therefore, we must check and throw an error if properties are missing.
+    json.get("properties")
+        .ok_or(ValidationError::InvalidGrammar(
+            "Grammar must have a 'properties' field".to_string(),
+        ))?;
+
+    // Serialize json to string
+    Ok(ValidGrammar::Json(serde_json::to_string(&json).map_err(
+        |e| ValidationError::InvalidGrammar(e.to_string()),
+    )?))
+}
+
 /// Start tokenization workers
 fn tokenizer_worker(
     tokenizer: Tokenizer,
     config: Option<Config>,
     preprocessor_config: Option<HubPreprocessorConfig>,
+    tokenizer_cache: Option<TokenizerCache>,
     mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>,
 ) {
     match tokenizer {
@@ -443,8 +858,12 @@ fn tokenizer_worker(
             pyo3::Python::with_gil(|py| -> pyo3::PyResult<()> {
                 let tokenizer = PyTokenizer::from_py(py, tokenizer_name, revision)?;
                 // Loop over requests
-                while let Some(((inputs, add_special_tokens, truncate), response_tx, parent_span)) =
-                    receiver.blocking_recv()
+                while let Some((
+                    (inputs, add_special_tokens, truncate),
+                    response_tx,
+                    parent_span,
+                    _permit,
+                )) = receiver.blocking_recv()
                 {
                     parent_span.in_scope(|| {
                         response_tx
@@ -464,8 +883,16 @@ fn tokenizer_worker(
             .expect("Failure in python tokenizer worker");
         }
         Tokenizer::Rust(tokenizer) => {
-            while let Some(((inputs, add_special_tokens, truncate), response_tx, parent_span)) =
-                receiver.blocking_recv()
+            let tokenizer = CachedTokenizer {
+                tokenizer,
+                cache: tokenizer_cache,
+            };
+            while let Some((
+                (inputs, add_special_tokens, truncate),
+                response_tx,
+                parent_span,
+                _permit,
+            )) = receiver.blocking_recv()
             {
                 parent_span.in_scope(|| {
                     response_tx
@@ -662,10 +1089,40 @@ fn prepare_input<T: TokenizerTrait>(
     Ok((encoding, input_chunks))
 }
 
+/// Chunks, ids, character offsets of the ids, length and `max_new_tokens` of validated inputs
+type ValidInput = (
+    Vec<Chunk>,
+    Option<Vec<u32>>,
+    Option<Vec<(usize, usize)>>,
+    usize,
+    u32,
+);
+
+/// Request whose parameters passed the syntactic checks
+struct CheckedRequest {
+    inputs: String,
+    inputs_ids: Option<Vec<u32>>,
+    add_special_tokens: bool,
+    endpoint: Endpoint,
+    truncate: Option<usize>,
+    max_new_tokens: Option<u32>,
+    grammar: Option<GrammarType>,
+    /// Parameters without the grammar, which is compiled by the semantic checks
+    parameters: ValidParameters,
+    stop_sequences: Vec<String>,
+    decoder_input_details: bool,
+    top_n_tokens: u32,
+    adapter_id: Option<String>,
+    raw_bytes: bool,
+    stop_on_repetition: Option<RepetitionStop>,
+}
+
+/// Inputs to tokenize, with the permit of their check, released once they are tokenized
 type TokenizerRequest = (
     (String, bool, Option<usize>),
     oneshot::Sender<Result<(tokenizers::Encoding, Vec<Chunk>), ValidationError>>,
     Span,
+    OwnedSemaphorePermit,
 );
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -729,6 +1186,10 @@ pub struct ValidParameters {
     pub watermark: bool,
     /// / grammar (applied if not empty)
     pub grammar: Option<ValidGrammar>,
+    /// / tokens the generation is restricted to, sorted, all of them if empty
+    pub allowed_token_ids: Vec<u32>,
+    /// / tokens never generated, sorted
+    pub banned_token_ids: Vec<u32>,
 }
 
 #[derive(Debug, Clone)]
@@ -746,14 +1207,30 @@ pub struct ValidStoppingParameters {
 pub struct ValidGenerateRequest {
     pub inputs: Vec<Chunk>,
     pub input_ids: Option<Arc<Vec<u32>>>,
+    /// Character offsets of `input_ids` in the text inputs
+    pub input_offsets: Option<Arc<Vec<(usize, usize)>>>,
     pub input_length: u32,
     pub truncate: u32,
     pub add_special_tokens: bool,
+    /// `input_ids` were sent by the client, backends should use them instead of tokenizing
+    /// `inputs`, which is their decoded text
+    pub pre_tokenized: bool,
     pub decoder_input_details: bool,
     pub parameters: ValidParameters,
     pub stopping_parameters: ValidStoppingParameters,
     pub top_n_tokens: u32,
     pub adapter_id: Option<String>,
+    /// Return the raw token bytes instead of buffering incomplete characters
+    pub raw_bytes: bool,
+    /// Stop the generation in the router when it is stuck in a loop
+    pub stop_on_repetition: Option<RepetitionStop>,
+    /// Stop the generation in the router once the JSON value it generates is closed
+    pub stop_on_json: bool,
+    /// Stop the generation in the router on these tokens, in addition to the end of sequence
+    /// token, sorted
+    pub stop_token_ids: Vec<u32>,
+    /// Id of the pin of the KV cache of the prompt, kept by the backend until it is unpinned
+    pub pin_prefix: Option<u64>,
 }
 
 #[derive(Error, Debug)]
@@ -768,7 +1245,9 @@ pub enum ValidationError {
     BestOfSeed,
     #[error("`best_of` != 1 is not supported when streaming tokens")]
     BestOfStream,
-    #[error("`top_n_tokens` must be >= 0 and <= {0}. Given: {1}")]
+    #[error("`best_of` uses one of the {0} concurrent requests allowed per sequence, it must be <= {0}. Given: {1}")]
+    BestOfConcurrency(usize, usize),
+    #[error("`top_n_tokens` must be >= 0 and <= {0}, the server `max_top_n_tokens`. Given: {1}")]
     TopNTokens(u32, u32),
     #[error("`top_n_tokens` != 0 is not allowed for this endpoint")]
     TopNTokensDisabled,
@@ -780,7 +1259,7 @@ pub enum ValidationError {
     RepetitionPenalty,
     #[error("`frequency_penalty` must be >= -2.0 and <= 2.0")]
     FrequencyPenalty,
-    #[error("`top_p` must be > 0.0 and < 1.0")]
+    #[error("`top_p` must be > 0.0")]
     TopP,
     #[error("`top_k` must be strictly positive")]
     TopK,
@@ -796,10 +1275,16 @@ pub enum ValidationError {
     MaxNewTokens(usize, u32),
     #[error("`inputs` tokens + `max_new_tokens` must be <= {0}. Given: {1} `inputs` tokens and {2} `max_new_tokens`")]
     MaxTotalTokens(usize, usize, u32),
-    #[error("`inputs` must have less than {0} tokens. Given: {1}")]
-    InputLength(usize, usize),
+    #[error("`inputs` must have less than {0} tokens, the `{2}` limit. Given: {1}")]
+    InputLength(usize, usize, &'static str),
     #[error("`inputs` cannot be empty")]
     EmptyInput,
+    #[error("`inputs` and `inputs_ids` are mutually exclusive")]
+    InputsAndInputIds,
+    #[error("`inputs_ids` is not supported with this tokenizer")]
+    UnsupportedInputIds,
+    #[error("`inputs_ids` must be < {1}, the vocabulary size. Given: {0}")]
+    InputIdOutOfVocab(u32, usize),
     #[error("`stop` supports up to {0} stop sequences. Given: {1}")]
     StopSequence(usize, usize),
     #[error("tokenizer error {0}")]
@@ -808,6 +1293,16 @@ pub enum ValidationError {
     Grammar,
     #[error("grammar is not valid: {0}")]
     InvalidGrammar(String),
+    #[error("`{0}` contains `{1}`, which is not a single token of the vocabulary")]
+    UnknownToken(&'static str, String),
+    #[error("`{0}` must be < {2}, the vocabulary size. Given: {1}")]
+    TokenOutOfVocab(&'static str, u32, usize),
+    #[error("`allowed_tokens` must have tokens that are not in `banned_tokens`")]
+    EmptyAllowedTokens,
+    #[error("`{0}` is not supported by this backend")]
+    TokenListUnsupported(&'static str),
+    #[error("`{0}` is not supported by this backend")]
+    SamplingUnsupported(&'static str),
     #[error("base64 encoding is invalid: {0}")]
     InvalidBase64(#[from] base64::DecodeError),
     #[error("invalid image: {0}")]
@@ -820,14 +1315,161 @@ pub enum ValidationError {
     FailedFetchImage(#[from] reqwest::Error),
     #[error("{0} modality is not supported")]
     UnsupportedModality(&'static str),
+    #[error("unknown preset `{0}`")]
+    UnknownPreset(String),
+    #[error("input contains the special token `{0}`")]
+    SpecialToken(String),
+    #[error("`stop_on_repetition` must have a `window` > 0 and `repeats` >= 2")]
+    StopOnRepetition,
+    #[error("the `length_penalty` of `best_of_strategy` must be a finite number")]
+    LengthPenalty,
+    #[error("the `rerank` strategy of `best_of` is not enabled on this server")]
+    RerankDisabled,
+    #[error("`suffix` is not supported, the model has no known fill-in-the-middle tokens")]
+    UnsupportedSuffix,
+    #[error("only the last message can be a `prefix`, and it must be from the `assistant`")]
+    PrefixMessage,
+    #[error("`pin` is not supported on this server")]
+    PinUnsupported,
+    #[error("`pin` cannot be used with `{0}`")]
+    PinConflict(&'static str),
+    #[error("`prefix_id` `{0}` is unknown or expired")]
+    UnknownPrefix(String),
+    #[error("`inputs` do not start with the prompt pinned as `prefix_id`")]
+    PrefixMismatch,
+    #[error("validation is overloaded, {0} checks are already pending")]
+    Overloaded(usize),
+    #[error("the `{0}` check did not complete within {1}ms")]
+    CheckTimeout(&'static str, u64),
+}
+
+impl ValidationError {
+    /// Stable code of the error, returned to the clients
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            ValidationError::BestOf(..) => "invalid_best_of",
+            ValidationError::BestOfDisabled => "best_of_disabled",
+            ValidationError::BestOfSampling => "best_of_requires_sampling",
+            ValidationError::BestOfSeed => "best_of_with_seed",
+            ValidationError::BestOfConcurrency(..) => "best_of_exceeds_concurrency",
+            ValidationError::BestOfStream => "best_of_stream",
+            ValidationError::TopNTokens(..) => "invalid_top_n_tokens",
+            ValidationError::TopNTokensDisabled => "top_n_tokens_disabled",
+            ValidationError::PrefillDetailsStream => "decoder_input_details_stream",
+            ValidationError::Temperature => "invalid_temperature",
+            ValidationError::RepetitionPenalty => "invalid_repetition_penalty",
+            ValidationError::FrequencyPenalty => "invalid_frequency_penalty",
+            ValidationError::TopP => "invalid_top_p",
+            ValidationError::TopK => "invalid_top_k",
+            ValidationError::Truncate(..) => "invalid_truncate",
+            ValidationError::TypicalP => "invalid_typical_p",
+            ValidationError::UnsetMaxNewTokens => "max_new_tokens_required",
+            ValidationError::NegativeMaxNewTokens => "invalid_max_new_tokens",
+            ValidationError::MaxNewTokens(..) => "max_new_tokens_too_large",
+            ValidationError::MaxTotalTokens(..) => "context_length_exceeded",
+            ValidationError::InputLength(..) => "input_too_long",
+            ValidationError::EmptyInput => "empty_input",
+            ValidationError::InputsAndInputIds => "inputs_and_inputs_ids",
+            ValidationError::UnsupportedInputIds => "inputs_ids_not_supported",
+            ValidationError::InputIdOutOfVocab(..) => "input_id_out_of_vocab",
+            ValidationError::StopSequence(..) => "too_many_stop_sequences",
+            ValidationError::Tokenizer(_) => "tokenizer_error",
+            ValidationError::Grammar => "grammar_not_supported",
+            ValidationError::InvalidGrammar(_) => "invalid_grammar",
+            ValidationError::UnknownToken(..) => "unknown_token",
+            ValidationError::TokenOutOfVocab(..) => "token_out_of_vocab",
+            ValidationError::EmptyAllowedTokens => "empty_allowed_tokens",
+            ValidationError::TokenListUnsupported(_) => "token_list_not_supported",
+            ValidationError::SamplingUnsupported(_) => "sampling_parameter_not_supported",
+            ValidationError::InvalidBase64(_) => "invalid_base64",
+            ValidationError::InvalidImage(_) => "invalid_image",
+            ValidationError::InvalidInt(_) => "invalid_integer",
+            ValidationError::InvalidImageContent(_) => "invalid_image_content",
+            ValidationError::FailedFetchImage(_) => "image_fetch_failed",
+            ValidationError::UnsupportedModality(_) => "modality_not_supported",
+            ValidationError::UnknownPreset(_) => "unknown_preset",
+            ValidationError::StopOnRepetition => "invalid_stop_on_repetition",
+            ValidationError::LengthPenalty => "invalid_length_penalty",
+            ValidationError::RerankDisabled => "rerank_not_supported",
+            ValidationError::UnsupportedSuffix => "suffix_not_supported",
+            ValidationError::PrefixMessage => "invalid_prefix_message",
+            ValidationError::PinUnsupported => "pin_not_supported",
+            ValidationError::PinConflict(_) => "pin_conflict",
+            ValidationError::UnknownPrefix(_) => "unknown_prefix_id",
+            ValidationError::PrefixMismatch => "prefix_mismatch",
+            ValidationError::SpecialToken(_) => "special_token",
+            ValidationError::Overloaded(_) => "validation_overloaded",
+            ValidationError::CheckTimeout(..) => "validation_timeout",
+        }
+    }
+
+    /// Name of the `/generate` parameter that failed validation, if the error is due to one
+    pub(crate) fn param(&self) -> Option<&'static str> {
+        match self {
+            ValidationError::BestOf(..)
+            | ValidationError::BestOfDisabled
+            | ValidationError::BestOfSampling
+            | ValidationError::BestOfStream
+            | ValidationError::BestOfConcurrency(..) => Some("best_of"),
+            ValidationError::BestOfSeed => Some("seed"),
+            ValidationError::TopNTokens(..) | ValidationError::TopNTokensDisabled => {
+                Some("top_n_tokens")
+            }
+            ValidationError::PrefillDetailsStream => Some("decoder_input_details"),
+            ValidationError::Temperature => Some("temperature"),
+            ValidationError::RepetitionPenalty => Some("repetition_penalty"),
+            ValidationError::FrequencyPenalty => Some("frequency_penalty"),
+            ValidationError::TopP => Some("top_p"),
+            ValidationError::TopK => Some("top_k"),
+            ValidationError::Truncate(..) => Some("truncate"),
+            ValidationError::TypicalP => Some("typical_p"),
+            ValidationError::UnsetMaxNewTokens
+            | ValidationError::NegativeMaxNewTokens
+            | ValidationError::MaxNewTokens(..)
+            | ValidationError::MaxTotalTokens(..) => Some("max_new_tokens"),
+            ValidationError::InputLength(..)
+            | ValidationError::EmptyInput
+            | ValidationError::InvalidBase64(_)
+            | ValidationError::InvalidImage(_)
+            | ValidationError::InvalidImageContent(_)
+            | ValidationError::FailedFetchImage(_)
+            | ValidationError::UnsupportedModality(_)
+            | ValidationError::SpecialToken(_) => Some("inputs"),
+            ValidationError::InputsAndInputIds
+            | ValidationError::UnsupportedInputIds
+            | ValidationError::InputIdOutOfVocab(..) => Some("inputs_ids"),
+            ValidationError::StopSequence(..) => Some("stop"),
+            ValidationError::Grammar | ValidationError::InvalidGrammar(_) => Some("grammar"),
+            ValidationError::UnknownToken(param, _)
+            | ValidationError::TokenOutOfVocab(param, ..)
+            | ValidationError::TokenListUnsupported(param)
+            | ValidationError::SamplingUnsupported(param) => Some(param),
+            ValidationError::EmptyAllowedTokens => Some("allowed_tokens"),
+            ValidationError::UnknownPreset(_) => Some("preset"),
+            ValidationError::StopOnRepetition => Some("stop_on_repetition"),
+            ValidationError::UnsupportedSuffix => Some("suffix"),
+            ValidationError::PrefixMessage => Some("messages"),
+            ValidationError::PinUnsupported | ValidationError::PinConflict(_) => Some("pin"),
+            ValidationError::UnknownPrefix(_) | ValidationError::PrefixMismatch => {
+                Some("prefix_id")
+            }
+            ValidationError::LengthPenalty | ValidationError::RerankDisabled => {
+                Some("best_of_strategy")
+            }
+            ValidationError::Tokenizer(_)
+            | ValidationError::InvalidInt(_)
+            | ValidationError::Overloaded(_)
+            | ValidationError::CheckTimeout(..) => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::{Idefics2, PaliTextConfig, Paligemma};
-    use crate::default_parameters;
-    use crate::tests::get_tokenizer;
+    use crate::tests::{get_tokenizer, get_word_level_tokenizer};
+    use crate::{default_parameters, EffectiveParameters};
 
     #[tokio::test]
     async fn test_validation_max_new_tokens() {
@@ -851,6 +1493,9 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            HashMap::new(),
+            HashMap::new(),
+            None,
         );
 
         let max_new_tokens = 10;
@@ -886,6 +1531,9 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            HashMap::new(),
+            HashMap::new(),
+            None,
         );
 
         let max_new_tokens = 10;
@@ -898,6 +1546,71 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_validation_input_offsets() {
+        let tokenizer = get_word_level_tokenizer(&["Héllo", "world"]);
+        let validation = Validation::new(
+            1,
+            tokenizer,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            6,
+            true,
+            HashMap::new(),
+            HashMap::new(),
+            None,
+        );
+
+        // The offsets of the truncated inputs are kept, in characters
+        let (_, input_ids, input_offsets, _, _) = validation
+            .validate_input("Héllo world".to_string(), false, Some(1), Some(1))
+            .await
+            .unwrap();
+        assert_eq!(input_ids, Some(vec![2]));
+        assert_eq!(input_offsets, Some(vec![(6, 11)]));
+    }
+
+    #[tokio::test]
+    async fn test_validation_input_ids() {
+        let tokenizer = get_word_level_tokenizer(&["Hello", "world"]);
+        let validation = Validation::new(
+            1,
+            tokenizer,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            6,
+            true,
+            HashMap::new(),
+            HashMap::new(),
+            None,
+        );
+
+        let (inputs, input_ids, input_offsets, input_length, _) = validation
+            .validate_input_ids(vec![1, 2], None, Some(1))
+            .await
+            .unwrap();
+        assert_eq!(inputs, vec![Chunk::Text("Hello world".to_string())]);
+        assert_eq!(input_ids, Some(vec![1, 2]));
+        assert_eq!(input_offsets, None);
+        assert_eq!(input_length, 2);
+
+        match validation
+            .validate_input_ids(vec![1, 3], None, Some(1))
+            .await
+        {
+            Err(ValidationError::InputIdOutOfVocab(3, 3)) => (),
+            _ => panic!("Unexpected out of vocabulary id"),
+        }
+    }
+
     #[tokio::test]
     async fn test_validation_best_of_sampling() {
         let tokenizer = get_tokenizer();
@@ -920,11 +1633,19 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            HashMap::new(),
+            HashMap::new(),
+            None,
         );
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
                     best_of: Some(2),
                     do_sample: false,
@@ -960,13 +1681,21 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            HashMap::new(),
+            HashMap::new(),
+            None,
         );
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
-                    top_p: Some(1.0),
+                    top_p: Some(0.0),
                     max_new_tokens: Some(5),
                     ..default_parameters()
                 },
@@ -977,10 +1706,34 @@ mod tests {
             _ => panic!("Unexpected top_p"),
         }
 
+        let valid_request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
+                parameters: GenerateParameters {
+                    top_p: Some(1.0001),
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.top_p, 1.0);
+
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
                     top_p: Some(0.99),
                     max_new_tokens: Some(5),
@@ -997,6 +1750,11 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
                     top_p: None,
                     max_new_tokens: Some(5),
@@ -1005,10 +1763,285 @@ mod tests {
             })
             .await
             .unwrap();
-        // top_p == 1.0 is invalid for users to ask for but it's the default resolved value.
         assert_eq!(valid_request.parameters.top_p, 1.0);
     }
 
+    #[tokio::test]
+    async fn test_validation_top_k() {
+        let tokenizer = get_word_level_tokenizer(&["Hello", "world"]);
+        let validation = Validation::new(
+            1,
+            tokenizer,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            HashMap::new(),
+            HashMap::new(),
+            None,
+        );
+        let valid_request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
+                parameters: GenerateParameters {
+                    top_k: Some(i32::MAX),
+                    temperature: Some(1.0),
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        let vocab_size = validation.tokenizer.as_ref().unwrap().get_vocab_size(true) as u32;
+        assert_eq!(valid_request.parameters.top_k, vocab_size);
+
+        let effective = EffectiveParameters::from(&valid_request.parameters);
+        assert!(effective.do_sample);
+        assert_eq!(effective.top_k, Some(vocab_size));
+        assert_eq!(effective.temperature, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_validation_token_lists() {
+        let vocab = [("[UNK]", 0), ("yes", 1), ("no", 2)]
+            .into_iter()
+            .map(|(token, id)| (token.to_string(), id))
+            .collect();
+        let model = tokenizers::models::wordlevel::WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(tokenizers::pre_tokenizers::whitespace::Whitespace));
+        let validation = Validation::new(
+            1,
+            Tokenizer::Rust(tokenizer),
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            6,
+            true,
+            HashMap::new(),
+            HashMap::new(),
+            None,
+        );
+        let validate = |allowed_tokens, banned_tokens| {
+            validation.validate(GenerateRequest {
+                inputs: "yes".to_string(),
+                add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    allowed_tokens,
+                    banned_tokens,
+                    ..default_parameters()
+                },
+            })
+        };
+
+        let request = validate(
+            Some(vec![
+                VocabToken::Text("no".to_string()),
+                VocabToken::Id(1),
+                VocabToken::Text("yes".to_string()),
+            ]),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.parameters.allowed_token_ids, [1, 2]);
+        assert!(request.parameters.banned_token_ids.is_empty());
+
+        match validate(None, Some(vec![VocabToken::Text("yes no".to_string())])).await {
+            Err(ValidationError::UnknownToken("banned_tokens", _)) => (),
+            _ => panic!("Unexpected text of several tokens"),
+        }
+        match validate(Some(vec![VocabToken::Id(3)]), None).await {
+            Err(ValidationError::TokenOutOfVocab("allowed_tokens", 3, 3)) => (),
+            _ => panic!("Unexpected out of vocabulary id"),
+        }
+        // Banned tokens take precedence
+        match validate(Some(vec![VocabToken::Id(1)]), Some(vec![VocabToken::Id(1)])).await {
+            Err(ValidationError::EmptyAllowedTokens) => (),
+            _ => panic!("Unexpected allowed tokens that are all banned"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_limits() {
+        let vocab = [("[UNK]".to_string(), 0)].into_iter().collect();
+        let model = tokenizers::models::wordlevel::WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let validation = Validation::new(
+            1,
+            Tokenizer::Rust(tokenizers::Tokenizer::new(model)),
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            6,
+            true,
+            HashMap::new(),
+            HashMap::new(),
+            None,
+        )
+        .with_limits(ValidationConfig {
+            max_pending: 0,
+            timeout_ms: 1000,
+        });
+        let validate = |temperature| {
+            validation.validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    temperature,
+                    ..default_parameters()
+                },
+            })
+        };
+
+        // The parameters are checked before waiting for the workers
+        match validate(Some(0.0)).await {
+            Err(ValidationError::Temperature) => (),
+            _ => panic!("Unexpected valid temperature"),
+        }
+        match validate(None).await {
+            Err(ValidationError::Overloaded(0)) => (),
+            _ => panic!("Unexpected tokenization over the pending checks"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_model_default_max_new_tokens() {
+        let model_defaults = HashMap::from([(
+            Some("my/adapter".to_string()),
+            ModelDefaults {
+                max_new_tokens: Some(512),
+                ..Default::default()
+            },
+        )]);
+        let validation = Validation::new(
+            1,
+            get_word_level_tokenizer(&["Hello"]),
+            None,
+            None,
+            2,
+            3,
+            4,
+            1000,
+            1024,
+            true,
+            HashMap::new(),
+            model_defaults,
+            None,
+        );
+        let validate = |body: &str| {
+            let request: GenerateRequest = serde_json::from_str(body).unwrap();
+            validation.validate(request)
+        };
+
+        let request =
+            validate(r#"{"inputs": "Hello", "parameters": {"adapter_id": "my/adapter"}}"#)
+                .await
+                .unwrap();
+        assert_eq!(request.stopping_parameters.max_new_tokens, 512);
+        let request = validate(r#"{"inputs": "Hello"}"#).await.unwrap();
+        assert_eq!(request.stopping_parameters.max_new_tokens, 100);
+        let request = validate(
+            r#"{"inputs": "Hello", "parameters": {"adapter_id": "my/adapter", "max_new_tokens": 16}}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.stopping_parameters.max_new_tokens, 16);
+    }
+
+    #[tokio::test]
+    async fn test_validation_input_limits() {
+        let vocab = [("[UNK]".to_string(), 0)].into_iter().collect();
+        let model = tokenizers::models::wordlevel::WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(tokenizers::pre_tokenizers::whitespace::Whitespace));
+        let validation = Validation::new(
+            1,
+            Tokenizer::Rust(tokenizer),
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            6,
+            true,
+            HashMap::new(),
+            HashMap::new(),
+            None,
+        )
+        .with_input_limits(InputLimitsConfig {
+            generate: None,
+            chat: Some(2),
+            embeddings: Some(8),
+        });
+        let validate = |endpoint| {
+            validation.validate(GenerateRequest {
+                inputs: "Hello dear world".to_string(),
+                add_special_tokens: true,
+                endpoint,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+            })
+        };
+
+        assert_eq!(validate(Endpoint::Generate).await.unwrap().input_length, 3);
+        match validate(Endpoint::Chat).await {
+            Err(ValidationError::InputLength(2, 3, "input_limits.chat")) => (),
+            _ => panic!("Unexpected inputs over the chat limit"),
+        }
+        // The limits over the one of the server are lowered to it
+        assert_eq!(
+            validation.max_input_length(Endpoint::Embeddings),
+            (5, "input_limits.embeddings")
+        );
+    }
+
     #[tokio::test]
     async fn test_validation_top_n_tokens() {
         let tokenizer = get_tokenizer();
@@ -1031,11 +2064,19 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            HashMap::new(),
+            HashMap::new(),
+            None,
         );
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
                     top_n_tokens: Some(5),
                     max_new_tokens: Some(5),
@@ -1052,6 +2093,11 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
                     top_n_tokens: Some(4),
                     max_new_tokens: Some(5),
@@ -1065,6 +2111,11 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
                     top_n_tokens: Some(0),
                     max_new_tokens: Some(5),
@@ -1078,6 +2129,11 @@ mod tests {
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
                 add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
                     top_n_tokens: None,
                     max_new_tokens: Some(5),
@@ -1121,6 +2177,9 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            HashMap::new(),
+            HashMap::new(),
+            None,
         );
 
         let chunks = match validation
@@ -1178,6 +2237,9 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            HashMap::new(),
+            HashMap::new(),
+            None,
         );
 
         let (encoding, chunks) = match validation
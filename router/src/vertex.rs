@@ -1,6 +1,7 @@
 use crate::infer::Infer;
+use crate::json_body::JsonBody;
 use crate::server::{generate_internal, ComputeType};
-use crate::{ChatRequest, ErrorResponse, GenerateParameters, GenerateRequest};
+use crate::{ChatRequest, Endpoint, ErrorResponse, GenerateParameters, GenerateRequest};
 use axum::extract::Extension;
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
@@ -46,14 +47,12 @@ path = "/vertex",
 request_body = VertexRequest,
 responses(
 (status = 200, description = "Generated Text", body = VertexResponse),
-(status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
+(status = 502, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": {"code": "generation_failed", "type": "generation", "message": "Request failed during generation"}})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
+example = json ! ({"error": {"code": "queue_full", "type": "overloaded_server", "message": "Model is overloaded"}})),
 (status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
-(status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+example = json ! ({"error": {"code": "invalid_max_new_tokens", "type": "validation", "message": "Input validation error: `max_new_tokens` must be strictly positive", "param": "max_new_tokens"}})),
 )
 )]
 #[instrument(
@@ -70,7 +69,7 @@ example = json ! ({"error": "Incomplete generation"})),
 pub(crate) async fn vertex_compatibility(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
-    Json(req): Json<VertexRequest>,
+    JsonBody(req): JsonBody<VertexRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     metrics::counter!("tgi_request_count").increment(1);
@@ -79,10 +78,14 @@ pub(crate) async fn vertex_compatibility(
     if req.instances.is_empty() {
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: "Input validation error".to_string(),
-                error_type: "Input validation error".to_string(),
-            }),
+            Json(
+                ErrorResponse::new(
+                    "empty_instances",
+                    "validation",
+                    "Input validation error: `instances` cannot be empty",
+                )
+                .with_param(Some("instances")),
+            ),
         ));
     }
 
@@ -94,6 +97,11 @@ pub(crate) async fn vertex_compatibility(
             VertexInstance::Generate(instance) => GenerateRequest {
                 inputs: instance.inputs.clone(),
                 add_special_tokens: true,
+                endpoint: Endpoint::Generate,
+                template: None,
+                variables: None,
+                inputs_ids: None,
+                suffix: None,
                 parameters: GenerateParameters {
                     do_sample: true,
                     max_new_tokens: instance.parameters.as_ref().and_then(|p| p.max_new_tokens),
@@ -123,15 +131,6 @@ pub(crate) async fn vertex_compatibility(
             )
             .await
             .map(|(_, Json(generation))| generation.generated_text)
-            .map_err(|_| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: "Incomplete generation".into(),
-                        error_type: "Incomplete generation".into(),
-                    }),
-                )
-            })
         });
     }
 
@@ -172,6 +171,7 @@ mod tests {
                         role: "user".to_string(),
                         content: MessageContent::SingleText("What's Deep Learning?".to_string()),
                         name: None,
+                        prefix: false,
                     },],
                     max_tokens: Some(128),
                     top_p: Some(0.95),
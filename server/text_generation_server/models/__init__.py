@@ -1371,84 +1371,92 @@ def get_model_with_lora_adapters(
         target_to_layer = build_layer_weight_lookup(model.model)
 
         for index, adapter in enumerate(lora_adapters):
-            # The AdapterParameters object allows for merging multiple adapters into a single adapter.
-            # At the moment, we only support loading a single adapter into the model, but we keep the
-            # AdapterParameters object for easier extension in the future.
-            adapter_parameters = AdapterParameters(
-                adapter_info=[adapter],
-                # when merging multiple adapters we can weight them differently
-                # if this is not set, all adapters will be weighted equally
-                # see: text_generation_server.utils.merges.strategies for impl
-                weights=None,
-                merge_strategy=0,
-                density=1.0,
-                majority_sign_method=0,
-            )
-
             adapter_index = index + 1
             adapter_to_index[adapter.id] = adapter_index
+            load_lora_adapter(model, adapter, adapter_index, target_to_layer)
 
-            logger.info(
-                f"Loading adapter weights into model: {','.join([adapter.id for adapter in adapter_parameters.adapter_info])}"
-            )
-            weight_names = tuple([v[0] for v in target_to_layer.values()])
-            (
-                module_map,
-                adapter_config,
-                adapter_weight_names,
-                adapter_tokenizer,
-            ) = load_and_merge_adapters(
-                model.model_id,
-                adapter_parameters,
-                adapter_index,
-                weight_names,
-                False,
-            )
+    return model
 
-            unused_weight_names = adapter_weight_names.copy()
-
-            adapter_layers = [
-                "q_proj",
-                "k_proj",
-                "v_proj",
-                "o_proj",
-                "gate_proj",
-                "up_proj",
-                "down_proj",
-                "qkv_proj",
-            ]
 
-            for layer_name in adapter_layers:
-                nlayers = (
-                    1 if layer_name == "lm_head" else len(model.model.model.layers)
-                )
-                adapter_weights = LoraWeights.prepare_weights(
-                    config=adapter_config,
-                    module_map=module_map,
-                    layer_type=layer_name,
-                    unused_weight_names=unused_weight_names,
-                    nlayers=nlayers,
-                    dtype=model.dtype,
-                    world_size=model.world_size,
-                    process_group=model.process_group,
-                    target_to_layer=target_to_layer,
-                )
+def load_lora_adapter(
+    model: Model,
+    adapter: AdapterInfo,
+    adapter_index: int,
+    target_to_layer: Optional[Dict] = None,
+):
+    """Load the weights of a LoRA adapter, selected by the requests with `adapter_index`"""
+    if target_to_layer is None:
+        target_to_layer = build_layer_weight_lookup(model.model)
 
-                if adapter_weights is None:
-                    continue
+    # The AdapterParameters object allows for merging multiple adapters into a single adapter.
+    # At the moment, we only support loading a single adapter into the model, but we keep the
+    # AdapterParameters object for easier extension in the future.
+    adapter_parameters = AdapterParameters(
+        adapter_info=[adapter],
+        # when merging multiple adapters we can weight them differently
+        # if this is not set, all adapters will be weighted equally
+        # see: text_generation_server.utils.merges.strategies for impl
+        weights=None,
+        merge_strategy=0,
+        density=1.0,
+        majority_sign_method=0,
+    )
 
-                model.layer_to_adapter_weights[layer_name].add_adapter(
-                    adapter_index, adapter_weights
-                )
+    logger.info(
+        f"Loading adapter weights into model: {','.join([adapter.id for adapter in adapter_parameters.adapter_info])}"
+    )
+    weight_names = tuple([v[0] for v in target_to_layer.values()])
+    (
+        module_map,
+        adapter_config,
+        adapter_weight_names,
+        adapter_tokenizer,
+    ) = load_and_merge_adapters(
+        model.model_id,
+        adapter_parameters,
+        adapter_index,
+        weight_names,
+        False,
+    )
 
-            if len(unused_weight_names) > 0:
-                logger.warning(
-                    f"{','.join([a.id for a in lora_adapters])} unused adapter weights: {unused_weight_names}"
-                )
+    unused_weight_names = adapter_weight_names.copy()
+
+    adapter_layers = [
+        "q_proj",
+        "k_proj",
+        "v_proj",
+        "o_proj",
+        "gate_proj",
+        "up_proj",
+        "down_proj",
+        "qkv_proj",
+    ]
+
+    for layer_name in adapter_layers:
+        nlayers = 1 if layer_name == "lm_head" else len(model.model.model.layers)
+        adapter_weights = LoraWeights.prepare_weights(
+            config=adapter_config,
+            module_map=module_map,
+            layer_type=layer_name,
+            unused_weight_names=unused_weight_names,
+            nlayers=nlayers,
+            dtype=model.dtype,
+            world_size=model.world_size,
+            process_group=model.process_group,
+            target_to_layer=target_to_layer,
+        )
 
-            if adapter_tokenizer is not None:
-                model.tokenizers.add_tokenizer(adapter_index, adapter_tokenizer)
+        if adapter_weights is None:
+            continue
 
-            model.loaded_adapters.add(adapter_index)
+        model.layer_to_adapter_weights[layer_name].add_adapter(
+            adapter_index, adapter_weights
+        )
 
-    return model
+    if len(unused_weight_names) > 0:
+        logger.warning(f"{adapter.id} unused adapter weights: {unused_weight_names}")
+
+    if adapter_tokenizer is not None:
+        model.tokenizers.add_tokenizer(adapter_index, adapter_tokenizer)
+
+    model.loaded_adapters.add(adapter_index)
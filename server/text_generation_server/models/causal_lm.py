@@ -18,7 +18,7 @@ from text_generation_server.utils import (
     Weights,
 )
 from text_generation_server.models import Model
-from text_generation_server.utils.chunks import concat_text_chunks
+from text_generation_server.utils.chunks import concat_text_chunks, tokenize_padded
 from text_generation_server.utils.import_utils import SYSTEM
 from text_generation_server.utils.quantization import get_loader
 from text_generation_server.utils.tokens import batch_top_tokens
@@ -117,13 +117,8 @@ class CausalLMBatch(Batch):
                 padding_right_offset, stopping_criteria.max_new_tokens
             )
 
-        tokenized_inputs = tokenizer(
-            inputs,
-            return_tensors="pt",
-            padding=True,
-            return_token_type_ids=False,
-            truncation=True,
-            max_length=max_truncation,
+        tokenized_inputs = tokenize_padded(
+            tokenizer, pb.requests, inputs, max_truncation
         ).to(device)
         for _ in pb.requests:
             input_len = tokenized_inputs["input_ids"].shape[1]
@@ -241,13 +241,17 @@ class FlashCausalLMBatch(Batch):
         batch_size = 0
         for r in requests:
             batch_size += 1
-            inputs = concat_text_chunks(r.input_chunks.chunks)
-            input_ids = tokenizer(
-                inputs,
-                truncation=True,
-                max_length=r.truncate,
-                add_special_tokens=r.add_special_tokens,
-            )["input_ids"]
+            if r.input_ids:
+                # Pre-tokenized by the client and truncated by the router
+                input_ids = list(r.input_ids)
+            else:
+                inputs = concat_text_chunks(r.input_chunks.chunks)
+                input_ids = tokenizer(
+                    inputs,
+                    truncation=True,
+                    max_length=r.truncate,
+                    add_special_tokens=r.add_special_tokens,
+                )["input_ids"]
             max_length = max(max_length, len(input_ids))
             all_input_ids.append(input_ids)
         return all_input_ids
@@ -12,7 +12,7 @@ from text_generation_server.utils import (
     NextTokenChooser,
     StoppingCriteria,
 )
-from text_generation_server.utils.chunks import concat_text_chunks
+from text_generation_server.utils.chunks import concat_text_chunks, tokenize_padded
 
 # CREDIT: Papers with code => https://github.com/paperswithcode/galai/blob/main/galai/utils.py
 
@@ -101,13 +101,8 @@ class GalacticaCausalLMBatch(CausalLMBatch):
                 padding_right_offset, stopping_criteria.max_new_tokens
             )
 
-        tokenized_inputs = tokenizer(
-            inputs,
-            return_tensors="pt",
-            padding=True,
-            return_token_type_ids=False,
-            truncation=True,
-            max_length=max_truncation,
+        tokenized_inputs = tokenize_padded(
+            tokenizer, pb.requests, inputs, max_truncation
         ).to(device)
         for _ in pb.requests:
             input_len = tokenized_inputs["input_ids"].shape[1]
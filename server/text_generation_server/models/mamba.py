@@ -26,7 +26,7 @@ from text_generation_server.models.types import (
     Generation,
     GeneratedText,
 )
-from text_generation_server.utils.chunks import concat_text_chunks
+from text_generation_server.utils.chunks import concat_text_chunks, tokenize_padded
 from text_generation_server.utils.quantization import get_loader
 from text_generation_server.utils.tokens import batch_top_tokens, Sampling
 from dataclasses import dataclass
@@ -156,13 +156,8 @@ class MambaBatch(Batch):
                 padding_right_offset, stopping_criteria.max_new_tokens
             )
 
-        tokenized_inputs = tokenizer(
-            inputs,
-            return_tensors="pt",
-            padding=True,
-            return_token_type_ids=False,
-            truncation=True,
-            max_length=max_truncation,
+        tokenized_inputs = tokenize_padded(
+            tokenizer, pb.requests, inputs, max_truncation
         ).to(device)
         for _ in pb.requests:
             input_len = tokenized_inputs["input_ids"].shape[1]
@@ -118,12 +118,16 @@ class MllamaCausalLMBatch(VlmCausalLMBatch):
                 image_inputs.append(curr_image)
                 image_indices.append(curr_i)
 
-            input_ids = tokenizer(
-                curr_text,
-                truncation=True,
-                max_length=r.truncate,
-                add_special_tokens=r.add_special_tokens,
-            )["input_ids"]
+            if r.input_ids:
+                # Pre-tokenized by the client and truncated by the router
+                input_ids = list(r.input_ids)
+            else:
+                input_ids = tokenizer(
+                    curr_text,
+                    truncation=True,
+                    max_length=r.truncate,
+                    add_special_tokens=r.add_special_tokens,
+                )["input_ids"]
             batch_tokenized_inputs.append(input_ids)
         if image_inputs:
             image_input = image_inputs[0]
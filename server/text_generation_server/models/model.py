@@ -115,8 +115,18 @@ class Model(ABC):
             use_prefix_caching=PREFIX_CACHING,
             attention_impl=ATTENTION,
             block_size=BLOCK_SIZE,
+            rank=self.rank,
+            device_memory=self.device_memory,
         )
 
+    @property
+    def device_memory(self) -> Optional[int]:
+        if self.device.type == "cuda":
+            return torch.cuda.get_device_properties(self.device).total_memory
+        if self.device.type == "xpu":
+            return torch.xpu.get_device_properties(self.device).total_memory
+        return None
+
     @property
     @abstractmethod
     def batch_type(self) -> Type[B]:
@@ -50,6 +50,11 @@ class PaliGemmaBatch(VlmCausalLMBatch):
             max_length=max_truncation,
             add_special_tokens=False,
         )["input_ids"]
+        # Pre-tokenized requests keep the ids sent by the client, truncated by the router
+        batch_tokenized_inputs = [
+            list(r.input_ids) if r.input_ids else input_ids
+            for r, input_ids in zip(requests, batch_tokenized_inputs)
+        ]
         if image_inputs:
             image_input = image_inputs[0]
             new_image_inputs = {
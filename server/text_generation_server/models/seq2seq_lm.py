@@ -16,7 +16,7 @@ from text_generation_server.utils import (
     weight_files,
     Weights,
 )
-from text_generation_server.utils.chunks import concat_text_chunks
+from text_generation_server.utils.chunks import concat_text_chunks, tokenize_padded
 from text_generation_server.utils.quantization import get_loader
 from text_generation_server.utils.tokens import batch_top_tokens
 from text_generation_server.models import Model
@@ -124,13 +124,8 @@ class Seq2SeqLMBatch(Batch):
             )
 
         # Tokenize batch
-        tokenized_inputs = tokenizer(
-            inputs,
-            return_tensors="pt",
-            padding=True,
-            return_token_type_ids=False,
-            truncation=True,
-            max_length=max_truncation,
+        tokenized_inputs = tokenize_padded(
+            tokenizer, pb.requests, inputs, max_truncation
         ).to(device)
 
         input_lengths = tokenized_inputs["attention_mask"].sum(1)
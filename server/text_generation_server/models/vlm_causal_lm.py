@@ -223,6 +223,11 @@ class VlmCausalLMBatch(FlashCausalLMBatch):
             max_length=max_truncation,
             add_special_tokens=not config.model_type == "paligemma",
         )["input_ids"]
+        # Pre-tokenized requests keep the ids sent by the client, truncated by the router
+        batch_tokenized_inputs = [
+            list(r.input_ids) if r.input_ids else input_ids
+            for r, input_ids in zip(requests, batch_tokenized_inputs)
+        ]
 
         return batch_tokenized_inputs, image_inputs
 
@@ -13,8 +13,14 @@ from typing import List, Optional
 
 from text_generation_server.cache import Cache
 from text_generation_server.interceptor import ExceptionInterceptor
-from text_generation_server.models import Model, get_model_with_lora_adapters
+from text_generation_server.models import (
+    Model,
+    get_model_with_lora_adapters,
+    load_lora_adapter,
+)
+from text_generation_server.utils import hub
 from text_generation_server.utils.adapter import AdapterInfo
+from text_generation_server.utils.logits_process import GrammarLogitProcessor
 from text_generation_server.utils.prefill_chunking import set_max_prefill_tokens
 
 try:
@@ -37,7 +43,10 @@ except (ImportError, NotImplementedError):
 
 from text_generation_server.pb import generate_pb2_grpc, generate_pb2
 from text_generation_server.tracing import UDSOpenTelemetryAioServerInterceptor
-from text_generation_server.models.globals import set_adapter_to_index
+from text_generation_server.models.globals import (
+    get_adapter_to_index,
+    set_adapter_to_index,
+)
 
 
 class SignalHandler:
@@ -150,6 +159,79 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
             max_total_tokens=max_total_tokens,
         )
 
+    async def CompileGrammar(self, request, context):
+        # Compiled in a thread, the batches keep running meanwhile
+        await asyncio.get_running_loop().run_in_executor(
+            None,
+            GrammarLogitProcessor.compile_fsm,
+            self.model.tokenizer,
+            request.grammar,
+            request.grammar_type,
+        )
+        return generate_pb2.CompileGrammarResponse()
+
+    async def CacheStats(self, request, context):
+        # Only the paged attention models have a KV cache of blocks
+        kv_cache = getattr(self.model, "kv_cache", [])
+        batch_ids = list(self.cache.cache.keys())
+        if not kv_cache:
+            return generate_pb2.CacheStatsResponse(batch_ids=batch_ids)
+        total_blocks = kv_cache[0].kv_cache[0].shape[0]
+        total_bytes = sum(
+            tensor.numel() * tensor.element_size()
+            for layer in kv_cache
+            for tensor in layer.kv_cache
+        )
+        # Blocks of the prompt prefixes shared by several requests are counted once
+        used_blocks = len(
+            {
+                block
+                for batch in self.cache.cache.values()
+                for block_table in getattr(batch, "block_tables", [])
+                for block in block_table
+            }
+        )
+        return generate_pb2.CacheStatsResponse(
+            used_blocks=used_blocks,
+            total_blocks=total_blocks,
+            used_bytes=total_bytes * used_blocks // total_blocks,
+            total_bytes=total_bytes,
+            batch_ids=batch_ids,
+        )
+
+    async def LoadAdapter(self, request, context):
+        adapter_to_index = get_adapter_to_index()
+        # The model only runs the adapter layers when started with adapters
+        if not adapter_to_index:
+            raise ValueError(
+                "Adapters can only be loaded when the model is started with `--lora-adapters`"
+            )
+        if request.id in adapter_to_index:
+            return generate_pb2.LoadAdapterResponse()
+
+        adapter = AdapterInfo(
+            id=request.id,
+            path=None,
+            revision=request.revision if request.HasField("revision") else None,
+        )
+
+        # Downloaded in a thread, the batches keep running meanwhile
+        def download():
+            filenames = hub.weight_hub_files(adapter.id, adapter.revision)
+            hub.download_weights(filenames, adapter.id, adapter.revision)
+
+        await asyncio.get_running_loop().run_in_executor(None, download)
+
+        # Loaded on the event loop, between two Prefill or Decode calls, since the weights of
+        # the batches are read from the same dicts. There is no await until the adapter is
+        # registered, so concurrent calls cannot pick the same index.
+        if request.id in adapter_to_index:
+            return generate_pb2.LoadAdapterResponse()
+        adapter_index = max(adapter_to_index.values()) + 1
+        load_lora_adapter(self.model, adapter, adapter_index)
+        adapter_to_index[adapter.id] = adapter_index
+        return generate_pb2.LoadAdapterResponse()
+
     async def Prefill(self, request, context):
         start = time.time_ns()
         if (
@@ -1,6 +1,7 @@
-from typing import Iterable
+from typing import Iterable, List, Sequence
 
 from loguru import logger
+from transformers import BatchEncoding, PreTrainedTokenizerBase
 
 from text_generation_server.pb import generate_pb2
 
@@ -25,3 +26,38 @@ def concat_text_chunks(chunks: Iterable[generate_pb2.InputChunk]) -> str:
         raise NotImplementedError("Request without a text chunk")
 
     return text
+
+
+def tokenize_padded(
+    tokenizer: PreTrainedTokenizerBase,
+    requests: Sequence[generate_pb2.Request],
+    inputs: List[str],
+    max_truncation: int,
+) -> BatchEncoding:
+    """
+    Tokenize the `inputs` of `requests` as a padded batch. Pre-tokenized requests keep their
+    `input_ids`, already truncated by the router.
+    """
+    if not any(r.input_ids for r in requests):
+        return tokenizer(
+            inputs,
+            return_tensors="pt",
+            padding=True,
+            return_token_type_ids=False,
+            truncation=True,
+            max_length=max_truncation,
+        )
+
+    encoded_inputs = [
+        {
+            "input_ids": (
+                list(r.input_ids)
+                if r.input_ids
+                else tokenizer(text, truncation=True, max_length=max_truncation)[
+                    "input_ids"
+                ]
+            )
+        }
+        for r, text in zip(requests, inputs)
+    ]
+    return tokenizer.pad(encoded_inputs, return_tensors="pt")
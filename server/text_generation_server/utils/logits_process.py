@@ -188,6 +188,89 @@ class HeterogeneousFrequencyPenaltyLogitsProcessor(LogitsProcessor):
         return None
 
 
+class TokenListLogitsProcessor(LogitsProcessor):
+    r"""
+    Restricts the generation to the allowed tokens and never generates the banned ones.
+
+    Args:
+        allowed_token_ids (`List[int]`):
+            The only tokens that can be generated. Empty means no restriction.
+        banned_token_ids (`List[int]`):
+            The tokens that are never generated.
+    """
+
+    def __init__(self, allowed_token_ids: List[int], banned_token_ids: List[int]):
+        self.allowed_token_ids = allowed_token_ids
+        self.banned_token_ids = banned_token_ids
+
+    def __call__(
+        self, input_ids: torch.LongTensor, scores: torch.FloatTensor
+    ) -> torch.FloatTensor:
+        vocab_size = scores.size(-1)
+        if self.allowed_token_ids:
+            allowed = [i for i in self.allowed_token_ids if i < vocab_size]
+            mask = torch.full_like(scores, -math.inf)
+            mask[:, allowed] = 0
+            scores = scores + mask
+        banned = [i for i in self.banned_token_ids if i < vocab_size]
+        if banned:
+            scores[:, banned] = -math.inf
+        return scores
+
+
+class HeterogeneousTokenListLogitsProcessor(LogitsProcessor):
+    r"""
+    Restricts the generation to the allowed tokens and never generates the banned ones.
+    This version allows for a separate list for each sample.
+
+    Args:
+        allowed_token_ids (`List[List[int]]`):
+            The only tokens that can be generated for each sample. Empty means no restriction.
+        banned_token_ids (`List[List[int]]`):
+            The tokens that are never generated for each sample.
+    """
+
+    def __init__(
+        self,
+        allowed_token_ids: List[List[int]],
+        banned_token_ids: List[List[int]],
+        device: torch.device,
+    ):
+        self.allowed_token_ids = allowed_token_ids
+        self.banned_token_ids = banned_token_ids
+        self.device = device
+        self.mask = None
+
+    def _build_mask(self, scores: torch.Tensor) -> torch.Tensor:
+        vocab_size = scores.size(-1)
+        mask = torch.zeros_like(scores)
+        for i, (allowed, banned) in enumerate(
+            zip(self.allowed_token_ids, self.banned_token_ids)
+        ):
+            if allowed:
+                mask[i] = -math.inf
+                mask[i, [t for t in allowed if t < vocab_size]] = 0
+            banned = [t for t in banned if t < vocab_size]
+            if banned:
+                mask[i, banned] = -math.inf
+        return mask
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        if self.mask is None or self.mask.shape != scores.shape:
+            self.mask = self._build_mask(scores)
+        scores += self.mask
+        return scores
+
+    def filter(self, indices):
+        self.allowed_token_ids = [self.allowed_token_ids[i] for i in indices]
+        self.banned_token_ids = [self.banned_token_ids[i] for i in indices]
+        if any(self.allowed_token_ids) or any(self.banned_token_ids):
+            if self.mask is not None:
+                self.mask = self.mask[indices]
+            return self
+        return None
+
+
 class HeterogeneousTemperatureLogitsWarper:
     r"""
     [`LogitsWarper`] for temperature (exponential scaling output probability distribution).
@@ -515,6 +598,15 @@ class GrammarLogitProcessor(LogitsProcessor):
             return fsm_grammar_state
         return fsm.next_state(fsm_grammar_state, next_token_id)
 
+    @staticmethod
+    def compile_fsm(tokenizer, grammar, grammar_type):
+        """Compile the FSM of a grammar ahead of the batches using it.
+
+        The router keeps track of the last 32 grammars, the size of the FSM cache.
+        """
+        tokenizer = GrammarLogitProcessor._cached_adapt_tokenizer(tokenizer)
+        GrammarLogitProcessor._cached_compile_fsm(grammar_type, grammar, tokenizer)
+
     # TODO: move grammar compilation into the router
     @staticmethod
     @lru_cache(maxsize=32, typed=True)
@@ -15,6 +15,8 @@ from text_generation_server.utils.logits_process import (
     HeterogeneousTopPLogitsWarper,
     HeterogeneousTypicalLogitsWarper,
     HeterogeneousGrammarLogitProcessor,
+    HeterogeneousTokenListLogitsProcessor,
+    TokenListLogitsProcessor,
     static_warper,
 )
 from text_generation_server.utils.watermark import WatermarkLogitsProcessor
@@ -38,6 +40,8 @@ class NextTokenChooser:
         grammar: str = "",
         grammar_type: GrammarType = GrammarType.GRAMMAR_TYPE_NONE,
         fsm_grammar_state: int = 0,
+        allowed_token_ids: Optional[List[int]] = None,
+        banned_token_ids: Optional[List[int]] = None,
     ):
         self.watermark_processor = (
             WatermarkLogitsProcessor(device=device) if watermark else None
@@ -57,6 +61,11 @@ class NextTokenChooser:
             if grammar != ""
             else None
         )
+        self.token_list_processor = (
+            TokenListLogitsProcessor(allowed_token_ids or [], banned_token_ids or [])
+            if allowed_token_ids or banned_token_ids
+            else None
+        )
         self.tokenizer = tokenizer
 
         has_warpers = (
@@ -87,6 +96,8 @@ class NextTokenChooser:
             scores = self.frequency_processor(input_ids, scores)
         if self.grammar_processor is not None:
             scores = self.grammar_processor(scores, self.fsm_grammar_state)
+        if self.token_list_processor is not None:
+            scores = self.token_list_processor(input_ids, scores)
 
         if self.static_warper is None:
             next_logprob = torch.log_softmax(scores, -1)
@@ -125,6 +136,8 @@ class NextTokenChooser:
             tokenizer=tokenizer,
             grammar=pb.grammar,
             grammar_type=pb.grammar_type,
+            allowed_token_ids=list(pb.allowed_token_ids),
+            banned_token_ids=list(pb.banned_token_ids),
         )
 
 
@@ -248,6 +261,8 @@ class HeterogeneousNextTokenChooser:
         grammars: List[str],
         grammar_types: List[int],
         fsm_grammar_states=List[int],
+        allowed_token_ids: Optional[List[List[int]]] = None,
+        banned_token_ids: Optional[List[List[int]]] = None,
     ):
         warpers = []
 
@@ -287,6 +302,16 @@ class HeterogeneousNextTokenChooser:
             else None
         )
 
+        allowed_token_ids = allowed_token_ids or [[] for _ in temperature]
+        banned_token_ids = banned_token_ids or [[] for _ in temperature]
+        self.token_list_processor = (
+            HeterogeneousTokenListLogitsProcessor(
+                allowed_token_ids, banned_token_ids, device
+            )
+            if any(allowed_token_ids) or any(banned_token_ids)
+            else None
+        )
+
         if any(x != 1.0 for x in temperature):
             do_sample = [
                 sample or x != 1.0 for x, sample in zip(temperature, do_sample)
@@ -353,6 +378,8 @@ class HeterogeneousNextTokenChooser:
                 _scores = self.frequency_processor(input_ids, _scores)
             if self.grammar_processor is not None:
                 _scores = self.grammar_processor(_scores, self.fsm_grammar_states)
+            if self.token_list_processor is not None:
+                _scores = self.token_list_processor(input_ids, _scores)
             for warper in self.warpers:
                 _scores = warper(input_ids, _scores)
             _next_ids = self.choice(_scores)
@@ -444,6 +471,9 @@ class HeterogeneousNextTokenChooser:
         if self.grammar_processor is not None:
             self.grammar_processor = self.grammar_processor.filter(indices)
 
+        if self.token_list_processor is not None:
+            self.token_list_processor = self.token_list_processor.filter(indices)
+
         filtered_warpers = []
         for warper in self.warpers:
             filtered_warper = warper.filter(indices)
@@ -500,6 +530,8 @@ class HeterogeneousNextTokenChooser:
             fsm_grammar_states=(
                 fsm_grammar_states if fsm_grammar_states else [0] * len(pb)
             ),
+            allowed_token_ids=[list(pb_.allowed_token_ids) for pb_ in pb],
+            banned_token_ids=[list(pb_.banned_token_ids) for pb_ in pb],
         )
 
 